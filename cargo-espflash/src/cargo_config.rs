@@ -3,7 +3,7 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use miette::{Result, WrapErr};
+use miette::{IntoDiagnostic, Result, WrapErr};
 use serde::Deserialize;
 
 use crate::error::TomlError;
@@ -44,6 +44,37 @@ impl CargoConfig {
         }
     }
 
+    /// Apply the `--config` overrides passed to Cargo, each of which is either
+    /// a `KEY=VALUE` pair in TOML syntax or the path of a configuration file
+    ///
+    /// Like Cargo, the overrides take precedence over the configuration files.
+    pub fn apply_overrides(&mut self, overrides: &[String]) -> Result<()> {
+        for value in overrides {
+            let path = Path::new(value);
+            let (content, source) = if path.is_file() {
+                let content = fs::read_to_string(path)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+                (content, path.to_string_lossy().into_owned())
+            } else {
+                (value.clone(), format!("--config {value}"))
+            };
+
+            let config: CargoConfig = toml::from_str(&content)
+                .map_err(move |e| TomlError::new(e, content))
+                .wrap_err_with(|| format!("Failed to parse {source}"))?;
+
+            if config.build.target.is_some() {
+                self.build.target = config.build.target;
+            }
+            if !config.unstable.build_std.is_empty() {
+                self.unstable.build_std = config.unstable.build_std;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn has_build_std(&self) -> bool {
         !self.unstable.build_std.is_empty()
     }
@@ -95,3 +126,32 @@ fn config_path(path: &Path) -> Option<PathBuf> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_overrides() {
+        let mut config: CargoConfig = toml::from_str(
+            r#"
+            [build]
+            target = "xtensa-esp32-none-elf"
+            "#,
+        )
+        .unwrap();
+        assert!(!config.has_build_std());
+
+        config
+            .apply_overrides(&[
+                r#"build.target="riscv32imc-unknown-none-elf""#.into(),
+                r#"unstable.build-std=["core"]"#.into(),
+                "profile.release.opt-level=3".into(),
+            ])
+            .unwrap();
+        assert_eq!(config.target(), Some("riscv32imc-unknown-none-elf"));
+        assert!(config.has_build_std());
+
+        assert!(config.apply_overrides(&["build.target=".into()]).is_err());
+    }
+}
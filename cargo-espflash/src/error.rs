@@ -1,6 +1,7 @@
 use std::{
     fmt::{Display, Formatter},
     iter::once,
+    path::PathBuf,
 };
 
 use espflash::targets::Chip;
@@ -10,6 +11,13 @@ use thiserror::Error;
 #[derive(Debug, Diagnostic, Error)]
 #[non_exhaustive]
 pub enum Error {
+    #[error("The artifact `{}` could not be found", .0.display())]
+    #[diagnostic(
+        code(cargo_espflash::artifact_not_found),
+        help("Ensure that the path given to `--artifact` is the ELF file produced by your build")
+    )]
+    ArtifactNotFound(PathBuf),
+
     #[error("The current workspace is invalid, and could not be loaded")]
     #[diagnostic(
         code(cargo_espflash::invalid_workspace),
@@ -1,26 +1,40 @@
 use std::{
-    fs,
-    path::PathBuf,
+    env,
+    path::{Path, PathBuf},
     process::{exit, Command, ExitStatus, Stdio},
 };
 
 use cargo_metadata::{Message, MetadataCommand};
-use clap::{Args, CommandFactory, Parser, Subcommand};
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand};
 use espflash::{
+    build_flash_plan,
     cli::{
-        self, board_info, checksum_md5, completions, config::Config, connect, erase_flash,
-        erase_partitions, erase_region, flash_elf_image, make_flash_data, monitor::monitor,
-        partition_table, print_board_info, read_flash, save_elf_as_image, serial_monitor,
-        ChecksumMd5Args, CompletionsArgs, ConnectArgs, EraseFlashArgs, EraseRegionArgs,
-        EspflashProgress, FlashConfigArgs, MonitorArgs, PartitionTableArgs, ReadFlashArgs,
+        self,
+        artifacts::{save_artifacts, save_web_flasher},
+        board_info, checksum_md5, complete_from_env, completions,
+        config::{self, Config, ConfigArgs},
+        connect, diagnostics, dump_mem,
+        efuse::{efuse, EfuseArgs},
+        erase_flash, erase_partitions, erase_region, flash_elf_image, list_ports, make_flash_data,
+        make_log_file, make_recorder, map_file,
+        monitor::{monitor, parser::channels::ChannelRoutes, rules::Rule},
+        partition_table, preflight_checks, print_board_info, read_flash, read_mem,
+        report::{OutputFormat, Report},
+        save_elf_as_image, serial_monitor,
+        simulate::simulate_flash,
+        spi_command, write_mem, BoardInfoArgs, ChecksumMd5Args, CompletionsArgs, ConnectArgs,
+        DumpMemArgs, EraseFlashArgs, EraseRegionArgs, EspflashProgress, FlashConfigArgs,
+        ListPortsArgs, MonitorArgs, PartitionTableArgs, ReadFlashArgs, ReadMemArgs, SpiCommandArgs,
+        WriteMemArgs,
     },
-    error::Error as EspflashError,
+    elf::ElfFirmwareImage,
     flasher::parse_partition_table,
-    logging::initialize_logger,
+    image_format::metadata::Metadata,
+    logging::{initialize_logger, log_level, print_warning_summary},
     targets::{Chip, XtalFrequency},
     update::check_for_update,
 };
-use log::{debug, info, LevelFilter};
+use log::{debug, info};
 use miette::{IntoDiagnostic, Result, WrapErr};
 
 use crate::{
@@ -53,8 +67,19 @@ enum CargoSubcommand {
         subcommand: Commands,
 
         /// Do not check for updates
-        #[clap(short, long, global = true, action)]
+        #[clap(long, global = true, action)]
         skip_update_check: bool,
+
+        /// Log more details, `-v` for debug and `-vv` for trace output
+        #[clap(short, long, global = true, action = ArgAction::Count)]
+        verbose: u8,
+
+        /// Format of the results printed to stdout, e.g. 'json' for scripts
+        ///
+        /// With 'json', the human-readable output is printed to stderr
+        /// instead, along with the logs.
+        #[clap(long, global = true, value_enum, default_value = "text")]
+        output_format: OutputFormat,
     },
 }
 
@@ -64,14 +89,31 @@ enum Commands {
     ///
     /// Automatically detects and prints the chip type, crystal frequency, flash
     /// size, chip features, and MAC address of a connected target device.
-    BoardInfo(ConnectArgs),
+    BoardInfo(BoardInfoArgs),
     /// Generate completions for the given shell
     ///
     /// The completions are printed to stdout, and can be redirected as needed.
     /// The directory in which completion scripts are stored differs
     /// depending on which shell is being used; consult your shell's
     /// documentation to determine the appropriate path.
+    ///
+    /// To also complete values like the available serial ports, register the
+    /// dynamic completions instead by sourcing the output of `cargo-espflash` with the
+    /// `COMPLETE` environment variable set to the shell, e.g.
+    /// `source <(COMPLETE=bash cargo-espflash)`.
     Completions(CompletionsArgs),
+    /// Validate, print and edit the configuration
+    ///
+    /// Unknown keys in the configuration file are rejected, so a typo does
+    /// not silently go unnoticed.
+    Config(ConfigArgs),
+    /// Save a region of memory, e.g. IRAM or DRAM, to a file
+    ///
+    /// The flasher stub is not loaded, so the RAM it would occupy is read as
+    /// the application left it.
+    DumpMem(DumpMemArgs),
+    /// Burn eFuses, only BLOCK0 of the ESP32-C3 so far
+    Efuse(EfuseArgs),
     /// Erase Flash entirely
     EraseFlash(EraseFlashArgs),
     /// Erase specified partitions
@@ -89,9 +131,11 @@ enum Commands {
     /// binary image format:
     ///
     /// https://docs.espressif.com/projects/esp-idf/en/latest/esp32/api-reference/system/app_image_format.html
-    Flash(FlashArgs),
+    Flash(Box<FlashArgs>),
     /// Hold the target device in reset
     HoldInReset(ConnectArgs),
+    /// List the serial ports to which a device may be connected
+    ListPorts(ListPortsArgs),
     /// Open the serial monitor without flashing the connected target device
     Monitor(MonitorArgs),
     /// Convert partition tables between CSV and binary format
@@ -103,10 +147,15 @@ enum Commands {
     ///
     /// Allows for conversion between formats via the '--to-csv' and
     /// '--to-binary' options, plus the ability to print a partition table
-    /// in tabular format.
+    /// in tabular format. Partitions can be added, removed and resized with
+    /// the 'add', 'remove' and 'resize' subcommands, and '--validate' checks
+    /// a partition table, e.g. against '--flash-size'. With '--from-device',
+    /// the partition table is read from the connected device.
     PartitionTable(PartitionTableArgs),
     /// Read SPI flash content
     ReadFlash(ReadFlashArgs),
+    /// Read a word of memory, e.g. a peripheral register
+    ReadMem(ReadMemArgs),
     /// Reset the target device
     Reset(ConnectArgs),
     /// Generate a binary application image and save it to a local disk
@@ -116,6 +165,18 @@ enum Commands {
     /// Otherwise, each segment will be saved as individual binaries, prefixed
     /// with their intended addresses in flash.
     SaveImage(SaveImageArgs),
+    /// Run a command on the SPI flash chip of a target device
+    ///
+    /// Sends the opcode and data, and prints the response if any bits of it are
+    /// read, e.g. 'spi-cmd 0x05 --read-bits 8' reads status register 1. Meant
+    /// for scripting advanced flash operations; commands which modify the
+    /// flash can render the device unbootable.
+    SpiCmd(SpiCommandArgs),
+    /// Write a word of memory, e.g. a peripheral register
+    ///
+    /// With '--mask', only the bits set in the mask are changed, e.g.
+    /// 'write-mem 0x60004004 0x10 --mask 0x10' sets a single bit.
+    WriteMem(WriteMemArgs),
     /// Calculate the MD5 checksum of the given region
     ChecksumMd5(ChecksumMd5Args),
 }
@@ -123,6 +184,18 @@ enum Commands {
 #[derive(Debug, Args)]
 #[non_exhaustive]
 struct BuildArgs {
+    /// ELF file built by another build system to use instead of building
+    /// with Cargo
+    ///
+    /// The bootloader and partition table of an ESP-IDF build directory are
+    /// used if they are found next to the file, in 'bootloader/' and
+    /// 'partition_table/'.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["bin", "example", "features", "no_default_features", "release"]
+    )]
+    pub artifact: Option<PathBuf>,
     /// Binary to build and flash
     #[arg(long)]
     pub bin: Option<String>,
@@ -156,6 +229,16 @@ struct BuildArgs {
     /// Unstable (nightly-only) flags to Cargo, see 'cargo -Z help' for details
     #[arg(short = 'Z')]
     pub unstable: Option<Vec<String>>,
+    /// Override a Cargo configuration value, passed on to Cargo
+    ///
+    /// Either a `KEY=VALUE` pair in TOML syntax, e.g.
+    /// `build.rustflags=["-C", "force-frame-pointers"]`, or the path of an
+    /// additional configuration file. May be given multiple times.
+    #[arg(long = "config", value_name = "KEY=VALUE")]
+    pub cargo_config: Vec<String>,
+    /// Run without accessing the network
+    #[arg(long)]
+    pub offline: bool,
 
     #[clap(flatten)]
     pub flash_config_args: FlashConfigArgs,
@@ -190,6 +273,13 @@ struct FlashArgs {
     connect_args: ConnectArgs,
     #[clap(flatten)]
     flash_args: cli::FlashArgs,
+    /// Directory to save copies of the images written to flash to
+    ///
+    /// The bootloader, partition table and application image are saved exactly
+    /// as they are written, along with a manifest.json listing their offsets and
+    /// SHA-256 digests.
+    #[arg(long, value_name = "DIR", conflicts_with = "ram")]
+    artifact_dir: Option<PathBuf>,
 }
 
 #[derive(Debug, Args)]
@@ -203,7 +293,7 @@ struct SaveImageArgs {
 
 fn main() -> Result<()> {
     miette::set_panic_hook();
-    initialize_logger(LevelFilter::Info);
+    complete_from_env(Cli::command);
 
     // Attempt to parse any provided comand-line arguments, or print the help
     // message and terminate if the invocation is not correct.
@@ -211,7 +301,11 @@ fn main() -> Result<()> {
     let CargoSubcommand::Espflash {
         subcommand: args,
         skip_update_check,
+        verbose,
+        output_format,
     } = cli.subcommand;
+    initialize_logger(log_level(verbose));
+    let mut report = Report::new(output_format);
     debug!("{:#?}, {:#?}", args, skip_update_check);
 
     // Only check for updates once the command-line arguments have been processed,
@@ -226,21 +320,32 @@ fn main() -> Result<()> {
 
     // Execute the correct action based on the provided subcommand and its
     // associated arguments.
-    match args {
-        Commands::BoardInfo(args) => board_info(&args, &config),
+    let result = match args {
+        Commands::BoardInfo(args) => board_info(&args, &config, &mut report),
         Commands::Completions(args) => completions(&args, &mut Cli::command(), "cargo"),
+        Commands::Config(args) => config::config(args, &config),
+        Commands::DumpMem(args) => dump_mem(args, &config),
+        Commands::Efuse(args) => efuse(args, &config, &mut report),
         Commands::EraseFlash(args) => erase_flash(args, &config),
         Commands::EraseParts(args) => erase_parts(args, &config),
         Commands::EraseRegion(args) => erase_region(args, &config),
-        Commands::Flash(args) => flash(args, &config),
+        Commands::Flash(args) => flash(*args, &config, &mut report),
         Commands::HoldInReset(args) => hold_in_reset(args, &config),
+        Commands::ListPorts(args) => list_ports(&args, &config, &report),
         Commands::Monitor(args) => serial_monitor(args, &config),
-        Commands::PartitionTable(args) => partition_table(args),
-        Commands::ReadFlash(args) => read_flash(args, &config),
+        Commands::PartitionTable(args) => partition_table(args, &config),
+        Commands::ReadFlash(args) => read_flash(args, &config, &mut report),
+        Commands::ReadMem(args) => read_mem(args, &config),
         Commands::Reset(args) => reset(args, &config),
-        Commands::SaveImage(args) => save_image(args, &config),
-        Commands::ChecksumMd5(args) => checksum_md5(&args, &config),
-    }
+        Commands::SaveImage(args) => save_image(args, &config, &report),
+        Commands::SpiCmd(args) => spi_command(&args, &config),
+        Commands::WriteMem(args) => write_mem(args, &config),
+        Commands::ChecksumMd5(args) => checksum_md5(&args, &config, &report),
+    };
+
+    print_warning_summary();
+
+    result.map_err(diagnostics::advise)
 }
 
 #[derive(Debug, Clone)]
@@ -251,10 +356,6 @@ struct BuildContext {
 }
 
 pub fn erase_parts(args: ErasePartsArgs, config: &Config) -> Result<()> {
-    if args.connect_args.no_stub {
-        return Err(EspflashError::StubRequired).into_diagnostic();
-    }
-
     let partition_table = args
         .partition_table
         .as_deref()
@@ -293,9 +394,59 @@ fn hold_in_reset(args: ConnectArgs, config: &Config) -> Result<()> {
     Ok(())
 }
 
-fn flash(args: FlashArgs, config: &Config) -> Result<()> {
+fn flash(args: FlashArgs, config: &Config, report: &mut Report) -> Result<()> {
+    let monitor_rules = Rule::parse_all(&args.flash_args.monitor_rules)?;
+    let channels = ChannelRoutes::parse_all(
+        config
+            .monitor
+            .channels
+            .iter()
+            .chain(&args.flash_args.channels),
+    )?;
     let metadata = PackageMetadata::load(&args.build_args.package)?;
-    let cargo_config = CargoConfig::load(&metadata.workspace_root, &metadata.package_root);
+    let mut cargo_config = CargoConfig::load(&metadata.workspace_root, &metadata.package_root);
+    cargo_config.apply_overrides(&args.build_args.cargo_config)?;
+
+    if let Some(path) = &args.flash_args.simulate {
+        let build_ctx = build(
+            &args.build_args,
+            &cargo_config,
+            args.connect_args.chip(),
+            report.format(),
+        )
+        .wrap_err("Failed to build project")?;
+        let elf_data = map_file(&build_ctx.artifact_path)?;
+        let chip = Metadata::from_bytes(&elf_data)?.select_chip(args.connect_args.chip())?;
+
+        let segment_filter = args.flash_args.segment_filter();
+        let mut flash_data = make_flash_data(
+            args.flash_args.image,
+            &args.build_args.flash_config_args,
+            config,
+            build_ctx.bootloader_path.as_deref(),
+            build_ctx.partition_table_path.as_deref(),
+            report.format(),
+        )?;
+        flash_data.app_only = args.flash_args.app_only;
+        flash_data.segment_filter = segment_filter;
+
+        let xtal_freq = XtalFrequency::default(chip);
+        if let Some(dir) = &args.artifact_dir {
+            let plan = build_flash_plan(&elf_data, chip, flash_data.clone(), xtal_freq)?;
+            save_artifacts(dir, chip, &flash_data, &plan)?;
+        }
+
+        return simulate_flash(
+            path,
+            chip,
+            &ElfFirmwareImage::try_from(&elf_data[..])?
+                .with_segment_filter(flash_data.segment_filter.clone())?,
+            flash_data,
+            xtal_freq,
+            args.flash_args.erase_parts,
+            args.flash_args.erase_data_parts,
+        );
+    }
 
     let mut flasher = connect(
         &args.connect_args,
@@ -304,6 +455,10 @@ fn flash(args: FlashArgs, config: &Config) -> Result<()> {
         args.flash_args.no_skip,
     )?;
     flasher.verify_minimum_revision(args.flash_args.image.min_chip_rev)?;
+    flasher.set_encryption(args.flash_args.encryption.encryption()?);
+    flasher
+        .connection()
+        .set_write_retries(args.flash_args.max_retries);
 
     // If the user has provided a flash size via a command-line argument or config, we'll
     // override the detected (or default) value with this.
@@ -319,24 +474,30 @@ fn flash(args: FlashArgs, config: &Config) -> Result<()> {
 
     flasher.disable_watchdog()?;
 
-    let build_ctx =
-        build(&args.build_args, &cargo_config, chip).wrap_err("Failed to build project")?;
+    let build_ctx = build(&args.build_args, &cargo_config, Some(chip), report.format())
+        .wrap_err("Failed to build project")?;
 
     // Read the ELF data from the build path and load it to the target.
-    let elf_data = fs::read(build_ctx.artifact_path.clone()).into_diagnostic()?;
+    let elf_data = map_file(&build_ctx.artifact_path)?;
 
-    print_board_info(&mut flasher)?;
+    print_board_info(&mut flasher, report)?;
 
     if args.flash_args.ram {
-        flasher.load_elf_to_ram(&elf_data, Some(&mut EspflashProgress::default()))?;
+        flasher.load_elf_to_ram(&elf_data, Some(&mut EspflashProgress::for_device(&flasher)))?;
     } else {
-        let flash_data = make_flash_data(
+        let segment_filter = args.flash_args.segment_filter();
+        let mut flash_data = make_flash_data(
             args.flash_args.image,
             &args.build_args.flash_config_args,
             config,
             build_ctx.bootloader_path.as_deref(),
             build_ctx.partition_table_path.as_deref(),
+            report.format(),
         )?;
+        flash_data.app_only = args.flash_args.app_only;
+        flash_data.segment_filter = segment_filter;
+
+        preflight_checks(&mut flasher, args.flash_args.preflight, report)?;
 
         if args.flash_args.erase_parts.is_some() || args.flash_args.erase_data_parts.is_some() {
             erase_partitions(
@@ -347,9 +508,22 @@ fn flash(args: FlashArgs, config: &Config) -> Result<()> {
             )?;
         }
 
-        flash_elf_image(&mut flasher, &elf_data, flash_data, target_xtal_freq)?;
+        if let Some(dir) = &args.artifact_dir {
+            let plan = build_flash_plan(&elf_data, chip, flash_data.clone(), target_xtal_freq)?;
+            save_artifacts(dir, chip, &flash_data, &plan)?;
+        }
+
+        flash_elf_image(
+            &mut flasher,
+            &elf_data,
+            flash_data,
+            target_xtal_freq,
+            report,
+        )?;
     }
 
+    report.print_flash(flasher.flash_stats())?;
+
     if args.flash_args.monitor {
         let pid = flasher.get_usb_pid()?;
 
@@ -361,33 +535,81 @@ fn flash(args: FlashArgs, config: &Config) -> Result<()> {
             115_200
         };
 
+        // The monitor gets a copy of the ELF file rather than the mapping, as
+        // the project may be rebuilt during the session
+        let monitor_elf = elf_data.to_vec();
+        drop(elf_data);
+
+        let baud = args.flash_args.monitor_baud.unwrap_or(default_baud);
+        let recorder = make_recorder(
+            args.flash_args.record.as_deref(),
+            chip,
+            baud,
+            Some(&monitor_elf),
+        )?;
+
+        let log_file = make_log_file(
+            args.flash_args.log_file.as_deref(),
+            args.flash_args.log_file_max_size,
+            args.flash_args.log_file_count,
+        )?;
+
         monitor(
             flasher.into_serial(),
-            Some(&elf_data),
+            Some(&monitor_elf),
             pid,
-            args.flash_args.monitor_baud.unwrap_or(default_baud),
+            baud,
             args.flash_args.log_format,
+            &channels,
+            args.flash_args.encoding,
             true,
             args.flash_args.processors,
             Some(build_ctx.artifact_path),
+            monitor_rules,
+            recorder,
+            log_file,
+            args.flash_args.timestamps,
         )
     } else {
         Ok(())
     }
 }
 
+/// Options which are passed on to both `cargo metadata` and `cargo build`
+fn cargo_options(build_options: &BuildArgs) -> Vec<String> {
+    let mut options = Vec::new();
+
+    for value in &build_options.cargo_config {
+        options.push("--config".to_string());
+        options.push(value.to_string());
+    }
+
+    if build_options.offline {
+        options.push("--offline".to_string());
+    }
+
+    options
+}
+
+/// Build the application, for the target of `chip` if it is known
 fn build(
     build_options: &BuildArgs,
     cargo_config: &CargoConfig,
-    chip: Chip,
+    chip: Option<Chip>,
+    format: OutputFormat,
 ) -> Result<BuildContext> {
+    if let Some(artifact_path) = &build_options.artifact {
+        return prebuilt(artifact_path);
+    }
+
     let target = build_options
         .target
         .as_deref()
         .or_else(|| cargo_config.target())
-        .ok_or_else(|| NoTargetError::new(Some(chip)))?;
+        .ok_or_else(|| NoTargetError::new(chip))?;
 
     let mut metadata_cmd = MetadataCommand::new();
+    metadata_cmd.other_options(cargo_options(build_options));
     if build_options.no_default_features {
         metadata_cmd.features(cargo_metadata::CargoOpt::NoDefaultFeatures);
     }
@@ -396,8 +618,10 @@ fn build(
     }
     let metadata = metadata_cmd.exec().into_diagnostic()?;
 
-    if !chip.into_target().supports_build_target(target) {
-        return Err(UnsupportedTargetError::new(target, chip).into());
+    if let Some(chip) = chip {
+        if !chip.into_target().supports_build_target(target) {
+            return Err(UnsupportedTargetError::new(target, chip).into());
+        }
     }
 
     // The 'build-std' unstable cargo feature is required to enable
@@ -438,6 +662,8 @@ fn build(
         args.push("--frozen".to_string());
     }
 
+    args.extend(cargo_options(build_options));
+
     if let Some(example) = &build_options.example {
         args.push("--example".to_string());
         args.push(example.to_string());
@@ -469,8 +695,13 @@ fn build(
         }
     }
 
-    // Invoke the 'cargo build' command, passing our list of arguments.
-    let output = Command::new("cargo")
+    // Invoke the 'cargo build' command, passing our list of arguments. The
+    // environment is inherited, and when running as `cargo espflash` Cargo
+    // points `CARGO` at itself, so that the build uses the same toolchain (e.g.
+    // with `cargo +nightly espflash`) and produces the same binary as running
+    // `cargo build` would.
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let output = Command::new(cargo)
         .arg("build")
         .args(args)
         .args(["--message-format", "json-diagnostic-rendered-ansi"])
@@ -507,20 +738,12 @@ fn build(
                 // If the `esp-idf-sys` package is being used, attempt to use the bootloader and
                 // partition table compiled by `embuild` instead.
                 let build_path = PathBuf::from(script.out_dir).join("build");
+                let (bl_path, pt_path) = idf_build_outputs(&build_path);
 
-                let bl_path = build_path.join("bootloader").join("bootloader.bin");
-                let pt_path = build_path
-                    .join("partition_table")
-                    .join("partition-table.bin");
-
-                if bl_path.exists() && bl_path.is_file() {
-                    bootloader_path = Some(bl_path);
-                }
-
-                if pt_path.exists() && pt_path.is_file() {
-                    partition_table_path = Some(pt_path);
-                }
+                bootloader_path = bl_path.or(bootloader_path);
+                partition_table_path = pt_path.or(partition_table_path);
             }
+            #[allow(clippy::collapsible_match)]
             Message::CompilerArtifact(artifact) => {
                 if artifact.executable.is_some() {
                     if target_artifact.is_some() {
@@ -532,7 +755,12 @@ fn build(
             }
             Message::CompilerMessage(message) => {
                 if let Some(rendered) = message.message.rendered {
-                    print!("{}", rendered);
+                    // Keep stdout for the JSON document of the results
+                    if format.is_json() {
+                        eprint!("{rendered}");
+                    } else {
+                        print!("{rendered}");
+                    }
                 }
             }
             // Ignore all other messages.
@@ -560,16 +788,53 @@ fn build(
     Ok(build_ctx)
 }
 
-fn save_image(args: SaveImageArgs, config: &Config) -> Result<()> {
+/// The build context of an ELF file built outside of Cargo, e.g. by Bazel, Make
+/// or ESP-IDF
+fn prebuilt(artifact_path: &Path) -> Result<BuildContext> {
+    if !artifact_path.is_file() {
+        return Err(Error::ArtifactNotFound(artifact_path.to_path_buf()).into());
+    }
+
+    let (bootloader_path, partition_table_path) =
+        idf_build_outputs(artifact_path.parent().unwrap_or(Path::new(".")));
+
+    Ok(BuildContext {
+        artifact_path: artifact_path.to_path_buf(),
+        bootloader_path,
+        partition_table_path,
+    })
+}
+
+/// The bootloader and partition table in an ESP-IDF build directory, if any
+fn idf_build_outputs(build_path: &Path) -> (Option<PathBuf>, Option<PathBuf>) {
+    let bl_path = build_path.join("bootloader").join("bootloader.bin");
+    let pt_path = build_path
+        .join("partition_table")
+        .join("partition-table.bin");
+
+    (
+        bl_path.is_file().then_some(bl_path),
+        pt_path.is_file().then_some(pt_path),
+    )
+}
+
+fn save_image(args: SaveImageArgs, config: &Config, report: &Report) -> Result<()> {
     let metadata = PackageMetadata::load(&args.build_args.package)?;
-    let cargo_config = CargoConfig::load(&metadata.workspace_root, &metadata.package_root);
+    let mut cargo_config = CargoConfig::load(&metadata.workspace_root, &metadata.package_root);
+    cargo_config.apply_overrides(&args.build_args.cargo_config)?;
 
-    let build_ctx = build(&args.build_args, &cargo_config, args.save_image_args.chip)?;
-    let elf_data = fs::read(build_ctx.artifact_path).into_diagnostic()?;
+    let build_ctx = build(
+        &args.build_args,
+        &cargo_config,
+        args.save_image_args.chip,
+        report.format(),
+    )?;
+    let elf_data = map_file(&build_ctx.artifact_path)?;
+    let chip = Metadata::from_bytes(&elf_data)?.select_chip(args.save_image_args.chip)?;
 
     // Since we have no `Flasher` instance and as such cannot print the board
     // information, we will print whatever information we _do_ have.
-    println!("Chip type:         {}", args.save_image_args.chip);
+    println!("Chip type:         {chip}");
     println!("Merge:             {}", args.save_image_args.merge);
     println!("Skip padding:      {}", args.save_image_args.skip_padding);
 
@@ -579,22 +844,42 @@ fn save_image(args: SaveImageArgs, config: &Config) -> Result<()> {
         config,
         build_ctx.bootloader_path.as_deref(),
         build_ctx.partition_table_path.as_deref(),
+        report.format(),
     )?;
 
     let xtal_freq = args
         .save_image_args
         .xtal_freq
-        .unwrap_or(XtalFrequency::default(args.save_image_args.chip));
-
-    save_elf_as_image(
-        &elf_data,
-        args.save_image_args.chip,
-        args.save_image_args.file,
-        flash_data,
-        args.save_image_args.merge,
-        args.save_image_args.skip_padding,
-        xtal_freq,
-    )?;
+        .unwrap_or(XtalFrequency::default(chip));
+
+    if args.save_image_args.web_flasher {
+        let name = build_ctx
+            .artifact_path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let plan = build_flash_plan(&elf_data, chip, flash_data, xtal_freq)?;
+
+        save_web_flasher(
+            &args.save_image_args.file,
+            chip,
+            &name,
+            &plan,
+            args.save_image_args.merge,
+        )?;
+    } else {
+        save_elf_as_image(
+            &elf_data,
+            chip,
+            args.save_image_args.file,
+            flash_data,
+            args.save_image_args.merge,
+            args.save_image_args.skip_padding,
+            xtal_freq,
+            args.save_image_args.format,
+            report.format(),
+        )?;
+    }
 
     Ok(())
 }
@@ -613,3 +898,17 @@ fn exit_with_process_status(status: ExitStatus) -> ! {
 
     exit(code)
 }
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::Cli;
+
+    #[test]
+    fn cli_is_consistent() {
+        // Dynamic completions build the whole command, so an inconsistency in
+        // any subcommand breaks them
+        Cli::command().debug_assert();
+    }
+}
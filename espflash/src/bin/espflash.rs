@@ -1,26 +1,51 @@
-use std::{
-    fs::{self, File},
-    io::Read,
-    path::PathBuf,
-};
+use std::path::{Path, PathBuf};
 
-use clap::{Args, CommandFactory, Parser, Subcommand};
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand};
 use espflash::{
+    build_flash_plan,
     cli::{
-        self, board_info, checksum_md5, completions, config::Config, connect, erase_flash,
-        erase_partitions, erase_region, flash_elf_image, make_flash_data, monitor::monitor,
-        parse_uint32, partition_table, print_board_info, read_flash, save_elf_as_image,
-        serial_monitor, ChecksumMd5Args, CompletionsArgs, ConnectArgs, EraseFlashArgs,
-        EraseRegionArgs, EspflashProgress, FlashConfigArgs, MonitorArgs, PartitionTableArgs,
-        ReadFlashArgs,
+        self,
+        artifacts::save_web_flasher,
+        benchmark::{benchmark, BenchmarkArgs},
+        board_info, checksum_md5, complete_from_env, completions,
+        config::{self, Config, ConfigArgs},
+        connect, diagnostics, dump_mem,
+        efuse::{efuse, EfuseArgs},
+        erase_flash, erase_partitions, erase_region, flash_app_image, flash_elf_image,
+        flash_map::{flash_map, FlashMapArgs},
+        image_info::{image_info, ImageInfoArgs},
+        list_ports, make_flash_data, make_log_file, make_recorder, map_file,
+        merge::{merge_bin, MergeBinArgs},
+        metadata::{metadata, MetadataArgs},
+        monitor::{monitor, parser::channels::ChannelRoutes, rules::Rule},
+        nvs::{write_nvs, WriteNvsArgs},
+        ota::{ota, OtaArgs},
+        parse_uint32, partition_table, preflight_checks, print_board_info, read_flash, read_mem,
+        report::{OutputFormat, Report},
+        save_elf_as_image, serial_monitor,
+        simulate::simulate_flash,
+        spi_command,
+        targets::{chips, targets, ChipsArgs, TargetsArgs},
+        verify::{verify_flash, VerifyFlashArgs},
+        write_mem, BoardInfoArgs, ChecksumMd5Args, CompletionsArgs, ConnectArgs, DumpMemArgs,
+        EncryptionArgs, EraseFlashArgs, EraseRegionArgs, EspflashProgress, FlashConfigArgs,
+        ListPortsArgs, MonitorArgs, PartitionTableArgs, ReadFlashArgs, ReadMemArgs, SpiCommandArgs,
+        WriteMemArgs,
     },
+    elf::{ElfFirmwareImage, FirmwareImage, RomSegment},
     error::Error,
-    flasher::parse_partition_table,
-    logging::initialize_logger,
+    flasher::{parse_partition_table, FlashData, Flasher},
+    image_format::{
+        check_image,
+        ihex::{is_ihex, parse_ihex},
+        metadata::Metadata,
+        AppImage, ESP_MAGIC,
+    },
+    logging::{initialize_logger, log_level, print_warning_summary},
     targets::{Chip, XtalFrequency},
     update::check_for_update,
 };
-use log::{debug, info, LevelFilter};
+use log::{debug, info, warn};
 use miette::{IntoDiagnostic, Result, WrapErr};
 
 #[derive(Debug, Parser)]
@@ -32,22 +57,63 @@ pub struct Cli {
     /// Do not check for updates
     #[clap(short = 'S', long, global = true, action)]
     skip_update_check: bool,
+
+    /// Log more details, `-v` for debug and `-vv` for trace output
+    #[clap(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Format of the results printed to stdout, e.g. 'json' for scripts
+    ///
+    /// With 'json', the human-readable output is printed to stderr instead,
+    /// along with the logs.
+    #[clap(long, global = true, value_enum, default_value = "text")]
+    output_format: OutputFormat,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
+    /// Measure the performance of the connection to a target device
+    ///
+    /// Measures the time it takes to connect and load the flasher stub, and the
+    /// throughput of writing and reading flash at several baud rates and block
+    /// sizes. The given flash region is overwritten.
+    Benchmark(BenchmarkArgs),
     /// Print information about a connected target device
     ///
     /// Automatically detects and prints the chip type, crystal frequency, flash
     /// size, chip features, and MAC address of a connected target device.
-    BoardInfo(ConnectArgs),
+    BoardInfo(BoardInfoArgs),
+    /// List the supported chips and their properties
+    ///
+    /// Prints the chip IDs, flash layout, supported flash frequencies and
+    /// sizes, build targets and features of each chip from the data built into
+    /// espflash, without a device attached, e.g. for scripts validating their
+    /// configuration with 'espflash chips --chip esp32c6 --output-format json'.
+    Chips(ChipsArgs),
     /// Generate completions for the given shell
     ///
     /// The completions are printed to stdout, and can be redirected as needed.
     /// The directory in which completion scripts are stored differs
     /// depending on which shell is being used; consult your shell's
     /// documentation to determine the appropriate path.
+    ///
+    /// To also complete values like the available serial ports, register the
+    /// dynamic completions instead by sourcing the output of `espflash` with the
+    /// `COMPLETE` environment variable set to the shell, e.g.
+    /// `source <(COMPLETE=bash espflash)`.
     Completions(CompletionsArgs),
+    /// Validate, print and edit the configuration
+    ///
+    /// Unknown keys in the configuration file are rejected, so a typo does
+    /// not silently go unnoticed.
+    Config(ConfigArgs),
+    /// Save a region of memory, e.g. IRAM or DRAM, to a file
+    ///
+    /// The flasher stub is not loaded, so the RAM it would occupy is read as
+    /// the application left it.
+    DumpMem(DumpMemArgs),
+    /// Burn eFuses, only BLOCK0 of the ESP32-C3 so far
+    Efuse(EfuseArgs),
     /// Erase Flash entirely
     EraseFlash(EraseFlashArgs),
     /// Erase specified partitions
@@ -65,11 +131,46 @@ enum Commands {
     /// binary image format:
     ///
     /// https://docs.espressif.com/projects/esp-idf/en/latest/esp32/api-reference/system/app_image_format.html
-    Flash(FlashArgs),
+    Flash(Box<FlashArgs>),
+    /// Show the flash layout as a bar chart
+    ///
+    /// Shows the bootloader, the partition table and each partition, the free
+    /// space between them, and how much of the app partitions is used. Given an
+    /// ELF image, the layout it would be flashed with is shown, otherwise the
+    /// layout of the connected device.
+    FlashMap(FlashMapArgs),
     /// Hold the target device in reset
     HoldInReset(ConnectArgs),
+    /// Print the header, segments and integrity of a binary image
+    ///
+    /// Describes an application or bootloader image, or each image in a merged
+    /// image, like 'esptool.py image_info --version 2', e.g. with 'espflash
+    /// image-info app.bin'. Fails if a checksum or the appended SHA-256 digest
+    /// does not match.
+    ImageInfo(ImageInfoArgs),
+    /// List the serial ports to which a device may be connected
+    ListPorts(ListPortsArgs),
+    /// Merge binaries into a single image
+    ///
+    /// The files are given as pairs of the address to place them at and their
+    /// path, e.g. 'merge-bin --chip esp32s3 0x0 bootloader.bin 0x8000
+    /// partitions.bin 0x10000 app.bin -o merged.bin'. Raw images start at the
+    /// lowest address, and can be written there with 'write-bin'.
+    MergeBin(MergeBinArgs),
+    /// Print the metadata of an ELF file
+    ///
+    /// Reads the chip, log format and build information embedded by esp-hal
+    /// and ESP-IDF, without a device. With '--chip', fails if the image was
+    /// built for another chip, to validate artifacts in build pipelines.
+    Metadata(MetadataArgs),
     /// Open the serial monitor without flashing the connected target device
     Monitor(MonitorArgs),
+    /// Inspect and change the OTA app partition which is booted
+    ///
+    /// Reads the OTA data partition of the connected device, e.g. with
+    /// 'espflash ota status', and rewrites it to boot another app partition,
+    /// e.g. with 'espflash ota set-boot ota_0' to roll back an update.
+    Ota(OtaArgs),
     /// Convert partition tables between CSV and binary format
     ///
     /// Uses the ESP-IDF format for partition tables; please refer to the
@@ -79,10 +180,15 @@ enum Commands {
     ///
     /// Allows for conversion between formats via the '--to-csv' and
     /// '--to-binary' options, plus the ability to print a partition table
-    /// in tabular format.
+    /// in tabular format. Partitions can be added, removed and resized with
+    /// the 'add', 'remove' and 'resize' subcommands, and '--validate' checks
+    /// a partition table, e.g. against '--flash-size'. With '--from-device',
+    /// the partition table is read from the connected device.
     PartitionTable(PartitionTableArgs),
     /// Read SPI flash content
     ReadFlash(ReadFlashArgs),
+    /// Read a word of memory, e.g. a peripheral register
+    ReadMem(ReadMemArgs),
     /// Reset the target device
     Reset(ConnectArgs),
     /// Generate a binary application image and save it to a local disk
@@ -92,8 +198,45 @@ enum Commands {
     /// Otherwise, each segment will be saved as individual binaries, prefixed
     /// with their intended addresses in flash.
     SaveImage(SaveImageArgs),
+    /// Run a command on the SPI flash chip of a target device
+    ///
+    /// Sends the opcode and data, and prints the response if any bits of it are
+    /// read, e.g. 'spi-cmd 0x05 --read-bits 8' reads status register 1. Meant
+    /// for scripting advanced flash operations; commands which modify the
+    /// flash can render the device unbootable.
+    SpiCmd(SpiCommandArgs),
+    /// Print information about the supported target devices
+    ///
+    /// Allows other tools to consume the same register addresses and memory
+    /// layouts which espflash uses, e.g. with
+    /// 'espflash targets dump --chip esp32s3 --format json'.
+    Targets(TargetsArgs),
+    /// Compare the contents of flash with a file, or with an application
+    ///
+    /// Regions are compared by their MD5 digests, and the first differing
+    /// address of each region is reported without reading it back, e.g. with
+    /// 'espflash verify-flash 0x10000 app.bin', or with 'espflash verify-flash
+    /// --elf app' to check everything 'flash' writes for an application.
+    VerifyFlash(VerifyFlashArgs),
     /// Write a binary file to a specific address in a target device's flash
+    ///
+    /// Intel HEX files contain the addresses of their data, so each of their
+    /// contiguous regions is written to its own address, e.g. with
+    /// 'espflash write-bin firmware.hex'.
+    #[command(allow_missing_positional = true)]
     WriteBin(WriteBinArgs),
+    /// Write a word of memory, e.g. a peripheral register
+    ///
+    /// With '--mask', only the bits set in the mask are changed, e.g.
+    /// 'write-mem 0x60004004 0x10 --mask 0x10' sets a single bit.
+    WriteMem(WriteMemArgs),
+    /// Write an NVS partition with NVS encryption, along with its keys
+    ///
+    /// Encrypts an NVS partition image generated by 'nvs_partition_gen.py' with
+    /// the NVS keys, and writes the keys to the 'nvs_keys' partition with flash
+    /// encryption, e.g. 'write-nvs nvs.bin --encrypt --save-keys keys.bin'.
+    /// The keys are generated unless given with '--keys'.
+    WriteNvs(WriteNvsArgs),
     /// Calculate the MD5 checksum of the given region
     ChecksumMd5(ChecksumMd5Args),
 }
@@ -126,7 +269,22 @@ struct FlashArgs {
     #[clap(flatten)]
     flash_args: cli::FlashArgs,
     /// ELF image to flash
-    image: PathBuf,
+    ///
+    /// An Intel HEX file is written as it is instead, to the addresses of its
+    /// records.
+    #[arg(required_unless_present = "app_bin")]
+    image: Option<PathBuf>,
+    /// Application image to flash instead of an ELF image, e.g. one built by
+    /// ESP-IDF
+    ///
+    /// The bootloader and partition table are written along with it, as when
+    /// flashing an ELF image.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["image", "ram", "only_segments", "skip_segments"]
+    )]
+    app_bin: Option<PathBuf>,
 }
 
 #[derive(Debug, Args)]
@@ -146,23 +304,30 @@ struct SaveImageArgs {
 #[derive(Debug, Args)]
 #[non_exhaustive]
 struct WriteBinArgs {
-    /// Address at which to write the binary file
+    /// Address at which to write the binary file, which is omitted for Intel
+    /// HEX files
     #[arg(value_parser = parse_uint32)]
-    pub addr: u32,
-    /// File containing the binary data to write
-    pub bin_file: String,
+    pub addr: Option<u32>,
+    /// File containing the binary data to write, either raw or in the Intel
+    /// HEX format
+    pub bin_file: PathBuf,
     /// Connection configuration
     #[clap(flatten)]
     connect_args: ConnectArgs,
+    /// Flash encryption configuration
+    #[clap(flatten)]
+    encryption: EncryptionArgs,
 }
 
 fn main() -> Result<()> {
     miette::set_panic_hook();
-    initialize_logger(LevelFilter::Info);
+    complete_from_env(Cli::command);
 
     // Attempt to parse any provided comand-line arguments, or print the help
     // message and terminate if the invocation is not correct.
     let cli = Cli::parse();
+    initialize_logger(log_level(cli.verbose));
+    let mut report = Report::new(cli.output_format);
     let args = cli.subcommand;
     debug!("{:#?}, {:#?}", args, cli.skip_update_check);
 
@@ -178,29 +343,46 @@ fn main() -> Result<()> {
 
     // Execute the correct action based on the provided subcommand and its
     // associated arguments.
-    match args {
-        Commands::BoardInfo(args) => board_info(&args, &config),
+    let result = match args {
+        Commands::Benchmark(args) => benchmark(args, &config),
+        Commands::BoardInfo(args) => board_info(&args, &config, &mut report),
+        Commands::Chips(args) => chips(args, &report),
         Commands::Completions(args) => completions(&args, &mut Cli::command(), "espflash"),
+        Commands::Config(args) => config::config(args, &config),
+        Commands::DumpMem(args) => dump_mem(args, &config),
+        Commands::Efuse(args) => efuse(args, &config, &mut report),
         Commands::EraseFlash(args) => erase_flash(args, &config),
         Commands::EraseParts(args) => erase_parts(args, &config),
         Commands::EraseRegion(args) => erase_region(args, &config),
-        Commands::Flash(args) => flash(args, &config),
+        Commands::Flash(args) => flash(*args, &config, &mut report),
+        Commands::FlashMap(args) => flash_map(args, &config, &report),
         Commands::HoldInReset(args) => hold_in_reset(args, &config),
+        Commands::ImageInfo(args) => image_info(args, &report),
+        Commands::ListPorts(args) => list_ports(&args, &config, &report),
+        Commands::MergeBin(args) => merge_bin(args),
+        Commands::Metadata(args) => metadata(args),
         Commands::Monitor(args) => serial_monitor(args, &config),
-        Commands::PartitionTable(args) => partition_table(args),
-        Commands::ReadFlash(args) => read_flash(args, &config),
+        Commands::Ota(args) => ota(args, &config, &mut report),
+        Commands::PartitionTable(args) => partition_table(args, &config),
+        Commands::ReadFlash(args) => read_flash(args, &config, &mut report),
+        Commands::ReadMem(args) => read_mem(args, &config),
         Commands::Reset(args) => reset(args, &config),
-        Commands::SaveImage(args) => save_image(args, &config),
-        Commands::WriteBin(args) => write_bin(args, &config),
-        Commands::ChecksumMd5(args) => checksum_md5(&args, &config),
-    }
+        Commands::SaveImage(args) => save_image(args, &config, &report),
+        Commands::SpiCmd(args) => spi_command(&args, &config),
+        Commands::Targets(args) => targets(args),
+        Commands::VerifyFlash(args) => verify_flash(args, &config, &mut report),
+        Commands::WriteBin(args) => write_bin(args, &config, &mut report),
+        Commands::WriteMem(args) => write_mem(args, &config),
+        Commands::WriteNvs(args) => write_nvs(args, &config, &mut report),
+        Commands::ChecksumMd5(args) => checksum_md5(&args, &config, &report),
+    };
+
+    print_warning_summary();
+
+    result.map_err(diagnostics::advise)
 }
 
 pub fn erase_parts(args: ErasePartsArgs, config: &Config) -> Result<()> {
-    if args.connect_args.no_stub {
-        return Err(Error::StubRequired.into());
-    }
-
     let mut flasher = connect(&args.connect_args, config, false, false)?;
     let partition_table = match args.partition_table {
         Some(path) => Some(parse_partition_table(&path)?),
@@ -236,7 +418,46 @@ fn hold_in_reset(args: ConnectArgs, config: &Config) -> Result<()> {
     Ok(())
 }
 
-fn flash(args: FlashArgs, config: &Config) -> Result<()> {
+fn flash(args: FlashArgs, config: &Config, report: &mut Report) -> Result<()> {
+    let monitor_rules = Rule::parse_all(&args.flash_args.monitor_rules)?;
+    let channels = ChannelRoutes::parse_all(
+        config
+            .monitor
+            .channels
+            .iter()
+            .chain(&args.flash_args.channels),
+    )?;
+
+    // Exactly one of the two is present, which clap ensures
+    let image_path = args.app_bin.as_ref().or(args.image.as_ref()).unwrap();
+
+    if let Some(path) = &args.flash_args.simulate {
+        let image_data = map_file(image_path)?;
+        let chip = if args.app_bin.is_some() {
+            args.connect_args.chip().ok_or(Error::ChipNotProvided)?
+        } else {
+            Metadata::from_bytes(&image_data)?.select_chip(args.connect_args.chip())?
+        };
+        let flash_data = app_flash_data(&args, config, report.format())?;
+
+        let image: &dyn FirmwareImage = if args.app_bin.is_some() {
+            &AppImage::parse(&image_data)?
+        } else {
+            &ElfFirmwareImage::try_from(&image_data[..])?
+                .with_segment_filter(flash_data.segment_filter.clone())?
+        };
+
+        return simulate_flash(
+            path,
+            chip,
+            image,
+            flash_data,
+            XtalFrequency::default(chip),
+            args.flash_args.erase_parts,
+            args.flash_args.erase_data_parts,
+        );
+    }
+
     let mut flasher = connect(
         &args.connect_args,
         config,
@@ -244,6 +465,10 @@ fn flash(args: FlashArgs, config: &Config) -> Result<()> {
         args.flash_args.no_skip,
     )?;
     flasher.verify_minimum_revision(args.flash_args.image.min_chip_rev)?;
+    flasher.set_encryption(args.flash_args.encryption.encryption()?);
+    flasher
+        .connection()
+        .set_write_retries(args.flash_args.max_retries);
 
     // If the user has provided a flash size via a command-line argument, we'll
     // override the detected (or default) value with this.
@@ -253,25 +478,29 @@ fn flash(args: FlashArgs, config: &Config) -> Result<()> {
         flasher.set_flash_size(flash_size);
     }
 
-    print_board_info(&mut flasher)?;
+    print_board_info(&mut flasher, report)?;
 
     let chip = flasher.chip();
     let target = chip.into_target();
     let target_xtal_freq = target.crystal_freq(flasher.connection())?;
 
-    // Read the ELF data from the build path and load it to the target.
-    let elf_data = fs::read(&args.image).into_diagnostic()?;
+    // Read the image data from the given path and load it to the target.
+    let image_data = map_file(image_path)?;
 
-    if args.flash_args.ram {
-        flasher.load_elf_to_ram(&elf_data, Some(&mut EspflashProgress::default()))?;
-    } else {
-        let flash_data = make_flash_data(
-            args.flash_args.image,
-            &args.flash_config_args,
-            config,
-            None,
-            None,
+    let is_hex = args.image.is_some() && is_ihex(&image_data);
+
+    if is_hex {
+        preflight_checks(&mut flasher, args.flash_args.preflight, report)?;
+        write_segments(&mut flasher, &read_ihex(image_path, &image_data)?)?;
+    } else if args.flash_args.ram {
+        flasher.load_elf_to_ram(
+            &image_data,
+            Some(&mut EspflashProgress::for_device(&flasher)),
         )?;
+    } else {
+        let flash_data = app_flash_data(&args, config, report.format())?;
+
+        preflight_checks(&mut flasher, args.flash_args.preflight, report)?;
 
         if args.flash_args.erase_parts.is_some() || args.flash_args.erase_data_parts.is_some() {
             erase_partitions(
@@ -282,9 +511,27 @@ fn flash(args: FlashArgs, config: &Config) -> Result<()> {
             )?;
         }
 
-        flash_elf_image(&mut flasher, &elf_data, flash_data, target_xtal_freq)?;
+        if args.app_bin.is_some() {
+            flash_app_image(
+                &mut flasher,
+                &image_data,
+                flash_data,
+                target_xtal_freq,
+                report,
+            )?;
+        } else {
+            flash_elf_image(
+                &mut flasher,
+                &image_data,
+                flash_data,
+                target_xtal_freq,
+                report,
+            )?;
+        }
     }
 
+    report.print_flash(flasher.flash_stats())?;
+
     if args.flash_args.monitor {
         let pid = flasher.get_usb_pid()?;
 
@@ -296,29 +543,66 @@ fn flash(args: FlashArgs, config: &Config) -> Result<()> {
             115_200
         };
 
+        // A prebuilt application image has no symbols to resolve addresses with.
+        // The monitor gets a copy of the ELF file rather than the mapping, as
+        // it may be rebuilt during the session.
+        let elf_data = (args.image.is_some() && !is_hex).then(|| image_data.to_vec());
+        drop(image_data);
+        let elf_data = elf_data.as_deref();
+        let baud = args.flash_args.monitor_baud.unwrap_or(default_baud);
+        let recorder = make_recorder(args.flash_args.record.as_deref(), chip, baud, elf_data)?;
+
+        let log_file = make_log_file(
+            args.flash_args.log_file.as_deref(),
+            args.flash_args.log_file_max_size,
+            args.flash_args.log_file_count,
+        )?;
+
         monitor(
             flasher.into_serial(),
-            Some(&elf_data),
+            elf_data,
             pid,
-            args.flash_args.monitor_baud.unwrap_or(default_baud),
+            baud,
             args.flash_args.log_format,
+            &channels,
+            args.flash_args.encoding,
             true,
             args.flash_args.processors,
-            Some(args.image),
+            args.image.filter(|_| !is_hex),
+            monitor_rules,
+            recorder,
+            log_file,
+            args.flash_args.timestamps,
         )
     } else {
         Ok(())
     }
 }
 
-fn save_image(args: SaveImageArgs, config: &Config) -> Result<()> {
-    let elf_data = fs::read(&args.image)
-        .into_diagnostic()
+/// The flash settings for the `flash` command
+fn app_flash_data(args: &FlashArgs, config: &Config, format: OutputFormat) -> Result<FlashData> {
+    let mut flash_data = make_flash_data(
+        args.flash_args.image.clone(),
+        &args.flash_config_args,
+        config,
+        None,
+        None,
+        format,
+    )?;
+    flash_data.app_only = args.flash_args.app_only;
+    flash_data.segment_filter = args.flash_args.segment_filter();
+
+    Ok(flash_data)
+}
+
+fn save_image(args: SaveImageArgs, config: &Config, report: &Report) -> Result<()> {
+    let elf_data = map_file(&args.image)
         .wrap_err_with(|| format!("Failed to open image {}", args.image.display()))?;
+    let chip = Metadata::from_bytes(&elf_data)?.select_chip(args.save_image_args.chip)?;
 
     // Since we have no `Flasher` instance and as such cannot print the board
     // information, we will print whatever information we _do_ have.
-    println!("Chip type:         {}", args.save_image_args.chip);
+    println!("Chip type:         {chip}");
     println!("Merge:             {}", args.save_image_args.merge);
     println!("Skip padding:      {}", args.save_image_args.skip_padding);
 
@@ -328,36 +612,124 @@ fn save_image(args: SaveImageArgs, config: &Config) -> Result<()> {
         config,
         None,
         None,
+        report.format(),
     )?;
 
     let xtal_freq = args
         .save_image_args
         .xtal_freq
-        .unwrap_or(XtalFrequency::default(args.save_image_args.chip));
-
-    save_elf_as_image(
-        &elf_data,
-        args.save_image_args.chip,
-        args.save_image_args.file,
-        flash_data,
-        args.save_image_args.merge,
-        args.save_image_args.skip_padding,
-        xtal_freq,
-    )?;
+        .unwrap_or(XtalFrequency::default(chip));
+
+    if args.save_image_args.web_flasher {
+        let name = args.image.file_stem().unwrap_or_default().to_string_lossy();
+        let plan = build_flash_plan(&elf_data, chip, flash_data, xtal_freq)?;
+
+        save_web_flasher(
+            &args.save_image_args.file,
+            chip,
+            &name,
+            &plan,
+            args.save_image_args.merge,
+        )?;
+    } else {
+        save_elf_as_image(
+            &elf_data,
+            chip,
+            args.save_image_args.file,
+            flash_data,
+            args.save_image_args.merge,
+            args.save_image_args.skip_padding,
+            xtal_freq,
+            args.save_image_args.format,
+            report.format(),
+        )?;
+    }
 
     Ok(())
 }
 
-fn write_bin(args: WriteBinArgs, config: &Config) -> Result<()> {
+fn write_bin(args: WriteBinArgs, config: &Config, report: &mut Report) -> Result<()> {
+    let data = map_file(&args.bin_file)?;
+
+    // Intel HEX files are checked before connecting, as are the arguments for
+    // raw binaries
+    let hex_segments = if is_ihex(&data) {
+        if let Some(addr) = args.addr {
+            warn!("Intel HEX files contain their addresses, ignoring the address {addr:#x}");
+        }
+        Some(read_ihex(&args.bin_file, &data)?)
+    } else if args.addr.is_none() {
+        return Err(Error::MissingAddress(args.bin_file.display().to_string()).into());
+    } else {
+        None
+    };
+
     let mut flasher = connect(&args.connect_args, config, false, false)?;
-    print_board_info(&mut flasher)?;
+    flasher.set_encryption(args.encryption.encryption()?);
+    print_board_info(&mut flasher, report)?;
+
+    if let Some(segments) = hex_segments {
+        return write_segments(&mut flasher, &segments);
+    }
 
-    let mut f = File::open(&args.bin_file).into_diagnostic()?;
-    let size = f.metadata().into_diagnostic()?.len();
-    let mut buffer = Vec::with_capacity(size.try_into().into_diagnostic()?);
-    f.read_to_end(&mut buffer).into_diagnostic()?;
+    // Images are checked by the ROM and the bootloader before booting them, so
+    // point out corrupted ones before writing them
+    if data.first() == Some(&ESP_MAGIC) {
+        if let Err(e) = check_image(&data) {
+            warn!("{} looks like an image, but: {e}", args.bin_file.display());
+        }
+    }
 
-    flasher.write_bin_to_flash(args.addr, &buffer, Some(&mut EspflashProgress::default()))?;
+    let result = flasher.write_bin_to_flash(
+        args.addr.unwrap(),
+        &data,
+        Some(&mut EspflashProgress::for_device(&flasher)),
+    );
+    #[cfg(feature = "metrics")]
+    espflash::metrics::report_flash(&mut flasher, result.is_ok());
+    result?;
 
     Ok(())
 }
+
+/// Parse the contiguous regions of an Intel HEX file
+fn read_ihex(path: &Path, data: &[u8]) -> Result<Vec<RomSegment<'static>>> {
+    let text = std::str::from_utf8(data)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+
+    Ok(parse_ihex(text)?)
+}
+
+/// Write each region of an Intel HEX file to its address
+fn write_segments(flasher: &mut Flasher, segments: &[RomSegment<'_>]) -> Result<()> {
+    for segment in segments {
+        info!(
+            "Writing {} bytes at {:#x}",
+            segment.data.len(),
+            segment.addr
+        );
+    }
+
+    let result =
+        flasher.write_bins_to_flash(segments, Some(&mut EspflashProgress::for_device(flasher)));
+    #[cfg(feature = "metrics")]
+    espflash::metrics::report_flash(flasher, result.is_ok());
+    result?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::Cli;
+
+    #[test]
+    fn cli_is_consistent() {
+        // Dynamic completions build the whole command, so an inconsistency in
+        // any subcommand breaks them
+        Cli::command().debug_assert();
+    }
+}
@@ -0,0 +1,457 @@
+//! Adapters running [Connection] and [Flasher] on tokio's pool for blocking
+//! tasks
+//!
+//! [BlockingPoolConnection] and [BlockingPoolFlasher] offer the same commands
+//! as [Connection] and [Flasher], and return futures which can be awaited from
+//! a tokio runtime. This allows applications built on tokio, e.g. GUI
+//! provisioning tools, to flash devices without stalling their executor.
+//!
+//! This is not async I/O: the serial port is still read and written with
+//! blocking calls, on a thread of tokio's pool for blocking tasks, which holds
+//! the lock of the device for as long as the command runs. This has
+//! consequences for cancellation:
+//!
+//! - Dropping the future of a command, e.g. when it loses a `select!` or a
+//!   `timeout` elapses, does not stop the command. It keeps running to
+//!   completion on its thread, only its result is discarded.
+//! - The device stays locked until then, so the next command on any clone of
+//!   the handle waits for the cancelled one to finish, and
+//!   [BlockingPoolFlasher::into_inner] returns `None` meanwhile.
+//! - Each running command occupies a thread of the blocking pool.
+//!
+//! Commands are therefore bounded by the timeouts of the serial protocol, not
+//! by those of the application. In exchange, the device is never left in the
+//! middle of an operation.
+//!
+//! ```no_run
+//! # async fn flash(flasher: espflash::flasher::Flasher) -> Result<(), espflash::error::Error> {
+//! use espflash::blocking_pool::BlockingPoolFlasher;
+//!
+//! let flasher = BlockingPoolFlasher::new(flasher);
+//! flasher.erase_region(0x10000, 0x1000).await?;
+//! flasher.write_bin_to_flash(0x10000, vec![0; 0x1000], None).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    panic,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use esp_idf_part::PartitionTable;
+use serialport::UsbPortInfo;
+use tokio::task;
+
+use crate::{
+    command::Command,
+    connection::{
+        reset::{ResetAfterOperation, ResetBeforeOperation},
+        CommandResponseValue, Connection, Port,
+    },
+    error::Error,
+    flasher::{ConnectOptions, DeviceInfo, FlashData, FlashSize, Flasher, ProgressCallbacks},
+    targets::{Chip, XtalFrequency},
+};
+
+/// Progress callbacks which can be moved to the thread running a command
+pub type SendProgressCallbacks = Box<dyn ProgressCallbacks + Send>;
+
+/// Run `f` with exclusive access to `inner` on the pool for blocking tasks
+async fn run_blocking<I, T, F>(inner: &Arc<Mutex<I>>, f: F) -> Result<T, Error>
+where
+    I: Send + 'static,
+    T: Send + 'static,
+    F: FnOnce(&mut I) -> Result<T, Error> + Send + 'static,
+{
+    let inner = inner.clone();
+    task::spawn_blocking(move || {
+        // A command which panicked may have left the device in any state, but
+        // the connection itself is still usable
+        let mut inner = inner.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut inner)
+    })
+    .await
+    .unwrap_or_else(|e| panic::resume_unwind(e.into_panic()))
+}
+
+/// An established connection with a target device, whose commands run on
+/// tokio's pool for blocking tasks
+///
+/// Dropping the future of a command does not stop it, see the
+/// [module documentation](self) for how commands are cancelled.
+#[derive(Clone)]
+pub struct BlockingPoolConnection {
+    inner: Arc<Mutex<Connection>>,
+}
+
+impl BlockingPoolConnection {
+    /// Create a connection to the device on `serial`, see [Connection::new]
+    pub fn new(
+        serial: Port,
+        port_info: UsbPortInfo,
+        after_operation: ResetAfterOperation,
+        before_operation: ResetBeforeOperation,
+    ) -> Self {
+        Self::from(Connection::new(
+            serial,
+            port_info,
+            after_operation,
+            before_operation,
+        ))
+    }
+
+    /// Run `f` with the blocking connection, for commands which have no adapter
+    /// variant
+    pub async fn run<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+    {
+        run_blocking(&self.inner, f).await
+    }
+
+    /// Initialize a connection with a device
+    pub async fn begin(&self) -> Result<(), Error> {
+        self.run(Connection::begin).await
+    }
+
+    /// Reset the device
+    pub async fn reset(&self) -> Result<(), Error> {
+        self.run(Connection::reset).await
+    }
+
+    /// Reset the device taking into account the reset after argument
+    pub async fn reset_after(&self, is_stub: bool) -> Result<(), Error> {
+        self.run(move |connection| connection.reset_after(is_stub))
+            .await
+    }
+
+    /// Set the baud rate for the serial port
+    pub async fn set_baud(&self, speed: u32) -> Result<(), Error> {
+        self.run(move |connection| connection.set_baud(speed)).await
+    }
+
+    /// Write a command to the serial port and return its response
+    pub async fn command(&self, command: Command<'static>) -> Result<CommandResponseValue, Error> {
+        self.run(move |connection| connection.command(command))
+            .await
+    }
+
+    /// Read a register command with a timeout duration
+    pub async fn read_reg(&self, reg: u32) -> Result<u32, Error> {
+        self.run(move |connection| connection.read_reg(reg)).await
+    }
+
+    /// Write a register command with a timeout duration
+    pub async fn write_reg(&self, addr: u32, value: u32, mask: Option<u32>) -> Result<(), Error> {
+        self.run(move |connection| connection.write_reg(addr, value, mask))
+            .await
+    }
+
+    /// Replace the bits of `mask` in a register, see [Connection::update_reg]
+    pub async fn update_reg(&self, addr: u32, mask: u32, value: u32) -> Result<u32, Error> {
+        self.run(move |connection| connection.update_reg(addr, mask, value))
+            .await
+    }
+
+    /// Read `size` bytes of flash at `offset`, see
+    /// [Connection::read_flash_region]
+    pub async fn read_flash_region(
+        &self,
+        offset: u32,
+        size: u32,
+        block_size: u32,
+        max_in_flight: u32,
+    ) -> Result<Vec<u8>, Error> {
+        self.run(move |connection| {
+            connection.read_flash_region(offset, size, block_size, max_in_flight)
+        })
+        .await
+    }
+
+    /// Flush the serial port
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.run(Connection::flush).await
+    }
+
+    /// Return the blocking connection
+    ///
+    /// Returns `None` if the connection is still shared by clones of this handle,
+    /// or by commands which are still running.
+    pub fn into_inner(self) -> Option<Connection> {
+        Arc::into_inner(self.inner)
+            .map(|inner| inner.into_inner().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl From<Connection> for BlockingPoolConnection {
+    fn from(connection: Connection) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(connection)),
+        }
+    }
+}
+
+/// Connect to and flash a target device, running its operations on tokio's
+/// pool for blocking tasks
+///
+/// Dropping the future of an operation does not stop it, see the
+/// [module documentation](self) for how operations are cancelled.
+#[derive(Clone)]
+pub struct BlockingPoolFlasher {
+    inner: Arc<Mutex<Flasher>>,
+}
+
+impl BlockingPoolFlasher {
+    /// Wrap a connected [Flasher]
+    pub fn new(flasher: Flasher) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(flasher)),
+        }
+    }
+
+    /// Connect to the device on `serial`, see [Flasher::connect]
+    ///
+    /// Connecting keeps going when the future is dropped, and the connected
+    /// flasher is dropped afterwards.
+    pub async fn connect(
+        serial: Port,
+        port_info: UsbPortInfo,
+        options: ConnectOptions,
+    ) -> Result<Self, Error> {
+        let flasher = task::spawn_blocking(move || Flasher::connect(serial, port_info, options))
+            .await
+            .unwrap_or_else(|e| panic::resume_unwind(e.into_panic()))?;
+
+        Ok(Self::new(flasher))
+    }
+
+    /// Run `f` with the blocking flasher, for commands which have no adapter
+    /// variant
+    pub async fn run<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Flasher) -> Result<T, Error> + Send + 'static,
+    {
+        run_blocking(&self.inner, f).await
+    }
+
+    /// The chip the flasher is connected to
+    pub async fn chip(&self) -> Chip {
+        self.run(|flasher| Ok(flasher.chip()))
+            .await
+            .expect("reading the chip cannot fail")
+    }
+
+    /// Set the flash size of the device
+    pub async fn set_flash_size(&self, flash_size: FlashSize) {
+        self.run(move |flasher| {
+            flasher.set_flash_size(flash_size);
+            Ok(())
+        })
+        .await
+        .expect("setting the flash size cannot fail")
+    }
+
+    /// Get information about the connected device
+    pub async fn device_info(&self) -> Result<DeviceInfo, Error> {
+        self.run(Flasher::device_info).await
+    }
+
+    /// Load an ELF image to flash and execute it
+    pub async fn load_elf_to_flash(
+        &self,
+        elf_data: Vec<u8>,
+        flash_data: FlashData,
+        mut progress: Option<SendProgressCallbacks>,
+        xtal_freq: XtalFrequency,
+    ) -> Result<(), Error> {
+        self.run(move |flasher| {
+            flasher.load_elf_to_flash(
+                &elf_data,
+                flash_data,
+                progress.as_deref_mut().map(|p| p as _),
+                xtal_freq,
+            )
+        })
+        .await
+    }
+
+    /// Load a binary image to flash at a given address
+    pub async fn write_bin_to_flash(
+        &self,
+        addr: u32,
+        data: Vec<u8>,
+        mut progress: Option<SendProgressCallbacks>,
+    ) -> Result<(), Error> {
+        self.run(move |flasher| {
+            flasher.write_bin_to_flash(addr, &data, progress.as_deref_mut().map(|p| p as _))
+        })
+        .await
+    }
+
+    /// Get the MD5 digest of a region of flash
+    pub async fn checksum_md5(&self, addr: u32, length: u32) -> Result<u128, Error> {
+        self.run(move |flasher| flasher.checksum_md5(addr, length))
+            .await
+    }
+
+    /// Change the baud rate of the connection
+    pub async fn change_baud(&self, speed: u32) -> Result<(), Error> {
+        self.run(move |flasher| flasher.change_baud(speed)).await
+    }
+
+    /// Erase `size` bytes of flash at `offset`
+    pub async fn erase_region(&self, offset: u32, size: u32) -> Result<(), Error> {
+        self.run(move |flasher| flasher.erase_region(offset, size))
+            .await
+    }
+
+    /// Erase the entire flash
+    pub async fn erase_flash(&self) -> Result<(), Error> {
+        self.run(Flasher::erase_flash).await
+    }
+
+    /// Erase the entire flash region by region, see
+    /// [Flasher::erase_flash_with_progress]
+    pub async fn erase_flash_with_progress(
+        &self,
+        mut progress: Option<SendProgressCallbacks>,
+    ) -> Result<(), Error> {
+        self.run(move |flasher| {
+            flasher.erase_flash_with_progress(progress.as_deref_mut().map(|p| p as _))
+        })
+        .await
+    }
+
+    /// Read `size` bytes of flash at `offset` to the file at `file_path`, see
+    /// [Flasher::read_flash]
+    pub async fn read_flash(
+        &self,
+        offset: u32,
+        size: u32,
+        block_size: u32,
+        max_in_flight: u32,
+        file_path: PathBuf,
+    ) -> Result<(), Error> {
+        self.run(move |flasher| {
+            flasher.read_flash(offset, size, block_size, max_in_flight, file_path)
+        })
+        .await
+    }
+
+    /// Read `size` bytes of flash at `offset`
+    pub async fn read_flash_region(
+        &self,
+        offset: u32,
+        size: u32,
+        block_size: u32,
+        max_in_flight: u32,
+    ) -> Result<Vec<u8>, Error> {
+        self.run(move |flasher| flasher.read_flash_region(offset, size, block_size, max_in_flight))
+            .await
+    }
+
+    /// Read the partition table at `offset`
+    pub async fn read_partition_table(&self, offset: u32) -> Result<PartitionTable, Error> {
+        self.run(move |flasher| flasher.read_partition_table(offset))
+            .await
+    }
+
+    /// Search flash for the partition table, see
+    /// [Flasher::find_partition_table]
+    pub async fn find_partition_table(&self) -> Result<(u32, PartitionTable), Error> {
+        self.run(Flasher::find_partition_table).await
+    }
+
+    /// Check that the chip revision is at least `minimum`
+    pub async fn verify_minimum_revision(&self, minimum: u16) -> Result<(), Error> {
+        self.run(move |flasher| flasher.verify_minimum_revision(minimum))
+            .await
+    }
+
+    /// Return the blocking flasher
+    ///
+    /// Returns `None` if the flasher is still shared by clones of this handle,
+    /// or by operations which are still running.
+    pub fn into_inner(self) -> Option<Flasher> {
+        Arc::into_inner(self.inner)
+            .map(|inner| inner.into_inner().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl From<Flasher> for BlockingPoolFlasher {
+    fn from(flasher: Flasher) -> Self {
+        Self::new(flasher)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread, time::Duration};
+
+    use tokio::runtime::Builder;
+
+    use super::*;
+
+    #[test]
+    fn commands_run_in_order() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let inner = Arc::new(Mutex::new(Vec::new()));
+
+        runtime.block_on(async {
+            for i in 0..3 {
+                run_blocking(&inner, move |log| {
+                    log.push(i);
+                    Ok(())
+                })
+                .await
+                .unwrap();
+            }
+
+            let result: Result<(), _> =
+                run_blocking(&inner, |_| Err(Error::InvalidRecording("test".into()))).await;
+            assert!(result.is_err());
+        });
+
+        assert_eq!(*inner.lock().unwrap(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn cancelled_commands_run_to_completion() {
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let inner = Arc::new(Mutex::new(Vec::new()));
+        let (started, wait_started) = mpsc::channel();
+
+        runtime.block_on(async {
+            let cancelled = tokio::spawn({
+                let inner = inner.clone();
+                async move {
+                    run_blocking(&inner, move |log| {
+                        started.send(()).unwrap();
+                        thread::sleep(Duration::from_millis(50));
+                        log.push(1);
+                        Ok(())
+                    })
+                    .await
+                }
+            });
+            task::spawn_blocking(move || wait_started.recv().unwrap())
+                .await
+                .unwrap();
+            cancelled.abort();
+
+            // The next command waits for the cancelled one
+            run_blocking(&inner, |log| {
+                log.push(2);
+                Ok(())
+            })
+            .await
+            .unwrap();
+        });
+
+        assert_eq!(*inner.lock().unwrap(), [1, 2]);
+    }
+}
@@ -0,0 +1,302 @@
+//! Archiving of the images written to a device
+//!
+//! Saves the bootloader, partition table and application image exactly as they
+//! are written to flash, together with a `manifest.json` listing the offset,
+//! size and SHA-256 digest of each image. The images can also be saved along
+//! with a manifest for [ESP Web Tools], to flash them from a browser.
+//!
+//! [ESP Web Tools]: https://esphome.github.io/esp-web-tools/
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use log::info;
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    flasher::{FlashData, FlashSettings},
+    image_format::metadata::AppDescriptor,
+    output::{self, OutputFile},
+    targets::Chip,
+};
+
+/// Name of the manifest file in an artifact directory
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Description of the images in an artifact directory
+#[derive(Debug, Clone, Serialize)]
+#[non_exhaustive]
+pub struct Manifest {
+    pub chip: String,
+    pub flash: FlashSettings,
+    pub images: Vec<ManifestImage>,
+}
+
+/// An image in an artifact directory, and where it is written to flash
+#[derive(Debug, Clone, Serialize)]
+#[non_exhaustive]
+pub struct ManifestImage {
+    pub name: String,
+    pub file: String,
+    pub offset: u32,
+    pub size: usize,
+    pub sha256: String,
+}
+
+impl Manifest {
+    /// Describe the segments of a flash plan, as returned by
+    /// [build_flash_plan](crate::build_flash_plan)
+    ///
+    /// The plan consists of the bootloader, the partition table and then one or
+    /// more copies of the application image, or only the copies of the
+    /// application image if `app_only` is set.
+    pub fn new(chip: Chip, flash: FlashSettings, plan: &[(u32, Vec<u8>)], app_only: bool) -> Self {
+        let first_app = if app_only { 0 } else { 2 };
+        let images = plan
+            .iter()
+            .enumerate()
+            .map(|(index, (offset, data))| {
+                let name = match index {
+                    _ if index >= first_app => "app",
+                    0 => "bootloader",
+                    _ => "partition-table",
+                };
+
+                ManifestImage {
+                    name: name.into(),
+                    file: format!("{name}.bin"),
+                    offset: *offset,
+                    size: data.len(),
+                    sha256: hex::encode(Sha256::digest(data)),
+                }
+            })
+            .collect();
+
+        Self {
+            chip: chip.to_string(),
+            flash,
+            images,
+        }
+    }
+}
+
+/// Save the segments of a flash plan and their manifest to `dir`
+pub fn save_artifacts(
+    dir: &Path,
+    chip: Chip,
+    flash_data: &FlashData,
+    plan: &[(u32, Vec<u8>)],
+) -> Result<()> {
+    fs::create_dir_all(dir)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+
+    let manifest = Manifest::new(chip, flash_data.flash_settings, plan, flash_data.app_only);
+    write_images(dir, &manifest, plan)?;
+
+    let json = serde_json::to_string_pretty(&manifest).into_diagnostic()?;
+    output::write(dir.join(MANIFEST_FILE), json)?;
+
+    info!("Artifacts saved to {}", dir.display());
+
+    Ok(())
+}
+
+/// Write the images of a flash plan to the files named in its manifest
+fn write_images(dir: &Path, manifest: &Manifest, plan: &[(u32, Vec<u8>)]) -> Result<()> {
+    // Copies of the application share a file, as they are identical
+    for (image, (_, data)) in manifest.images.iter().zip(plan) {
+        let path = dir.join(&image.file);
+        output::write(&path, data)
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Offset of the application description, following the image header and the
+/// header of the first segment
+const APP_DESC_OFFSET: usize = 0x20;
+
+/// Manifest consumed by ESP Web Tools
+#[derive(Debug, Clone, Serialize)]
+#[non_exhaustive]
+pub struct WebFlasherManifest {
+    pub name: String,
+    pub version: String,
+    pub new_install_prompt_erase: bool,
+    pub builds: Vec<WebFlasherBuild>,
+}
+
+/// Images to flash on one chip family
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[non_exhaustive]
+pub struct WebFlasherBuild {
+    pub chip_family: String,
+    pub parts: Vec<WebFlasherPart>,
+}
+
+/// An image and the offset it is written to
+#[derive(Debug, Clone, Serialize)]
+#[non_exhaustive]
+pub struct WebFlasherPart {
+    pub path: String,
+    pub offset: u32,
+}
+
+/// Save the segments of a flash plan to `dir`, with a `manifest.json` for ESP
+/// Web Tools
+///
+/// The name and version in the manifest are taken from the application
+/// description in the image when there is one, with `name` as fallback. If
+/// `merge` is set, the segments are combined into a single `merged.bin`.
+pub fn save_web_flasher(
+    dir: &Path,
+    chip: Chip,
+    name: &str,
+    plan: &[(u32, Vec<u8>)],
+    merge: bool,
+) -> Result<()> {
+    fs::create_dir_all(dir)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+
+    let app_desc = plan.get(2).and_then(|(_, app)| app_description(app));
+    let (name, version) = match app_desc {
+        Some((project, version)) if !project.is_empty() => (project, version),
+        _ => (name.to_string(), String::from("unknown")),
+    };
+
+    let parts = if merge {
+        let mut file = OutputFile::create(dir.join("merged.bin"))?;
+
+        let mut segments = plan.iter().collect::<Vec<_>>();
+        segments.sort_by_key(|(offset, _)| *offset);
+
+        let mut position = 0;
+        for (offset, data) in segments {
+            let padding = (*offset as u64).saturating_sub(position);
+            io::copy(&mut io::repeat(0xff).take(padding), &mut file).into_diagnostic()?;
+            file.write_all(data).into_diagnostic()?;
+            position = *offset as u64 + data.len() as u64;
+        }
+        file.persist()?;
+
+        vec![WebFlasherPart {
+            path: "merged.bin".into(),
+            offset: 0,
+        }]
+    } else {
+        let manifest = Manifest::new(chip, Default::default(), plan, false);
+        write_images(dir, &manifest, plan)?;
+
+        manifest
+            .images
+            .into_iter()
+            .map(|image| WebFlasherPart {
+                path: image.file,
+                offset: image.offset,
+            })
+            .collect()
+    };
+
+    let manifest = WebFlasherManifest {
+        name,
+        version,
+        new_install_prompt_erase: true,
+        builds: vec![WebFlasherBuild {
+            chip_family: chip_family(chip),
+            parts,
+        }],
+    };
+
+    let json = serde_json::to_string_pretty(&manifest).into_diagnostic()?;
+    output::write(dir.join(MANIFEST_FILE), json)?;
+
+    info!("Web flasher files saved to {}", dir.display());
+
+    Ok(())
+}
+
+/// Name of the chip family as used by ESP Web Tools, e.g. `ESP32-C3`
+fn chip_family(chip: Chip) -> String {
+    let name = chip.to_string().to_uppercase();
+
+    match name.strip_prefix("ESP32") {
+        Some(variant) if !variant.is_empty() => format!("ESP32-{variant}"),
+        _ => name,
+    }
+}
+
+/// Read the project name and version from the application description of an
+/// application image
+fn app_description(app: &[u8]) -> Option<(String, String)> {
+    let desc = AppDescriptor::from_bytes(app.get(APP_DESC_OFFSET..)?)?;
+
+    Some((desc.project_name, desc.version))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{app_description, chip_family, Manifest};
+    use crate::{flasher::FlashSettings, targets::Chip};
+
+    #[test]
+    fn manifest_lists_every_segment() {
+        let plan = vec![
+            (0x0, vec![0xe9; 4]),
+            (0x8000, vec![0xaa; 4]),
+            (0x10000, vec![0xe9; 8]),
+            (0x110000, vec![0xe9; 8]),
+        ];
+        let manifest = Manifest::new(Chip::Esp32c3, FlashSettings::default(), &plan, false);
+
+        let files = manifest
+            .images
+            .iter()
+            .map(|image| (image.file.as_str(), image.offset))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            [
+                ("bootloader.bin", 0x0),
+                ("partition-table.bin", 0x8000),
+                ("app.bin", 0x10000),
+                ("app.bin", 0x110000),
+            ]
+        );
+        assert_eq!(manifest.images[2].sha256, manifest.images[3].sha256);
+        assert_eq!(manifest.images[0].sha256.len(), 64);
+
+        let manifest = Manifest::new(Chip::Esp32c3, FlashSettings::default(), &plan[2..], true);
+        assert!(manifest.images.iter().all(|image| image.file == "app.bin"));
+    }
+
+    #[test]
+    fn web_flasher_chip_family() {
+        assert_eq!(chip_family(Chip::Esp32), "ESP32");
+        assert_eq!(chip_family(Chip::Esp32c3), "ESP32-C3");
+        assert_eq!(chip_family(Chip::Esp32s3), "ESP32-S3");
+    }
+
+    #[test]
+    fn read_app_description() {
+        let mut app = vec![0u8; 0x100];
+        app[0x20..0x24].copy_from_slice(&0xabcd_5432u32.to_le_bytes());
+        app[0x30..0x35].copy_from_slice(b"1.2.3");
+        app[0x50..0x55].copy_from_slice(b"blink");
+
+        assert_eq!(
+            app_description(&app),
+            Some(("blink".into(), "1.2.3".into()))
+        );
+        assert_eq!(app_description(&app[..0x40]), None);
+        assert_eq!(app_description(&[0u8; 0x100]), None);
+    }
+}
@@ -0,0 +1,214 @@
+//! Benchmark of the connection to a target device
+
+use std::{
+    borrow::Cow,
+    time::{Duration, Instant},
+};
+
+use clap::Args;
+use comfy_table::{modifiers, presets::UTF8_FULL, Attribute, Cell, Color, Table};
+use log::{info, warn};
+use miette::Result;
+
+use crate::{
+    cli::{config::Config, connect, parse_uint32, ConnectArgs},
+    elf::RomSegment,
+    error::Error,
+    flasher::Flasher,
+};
+
+/// Measure connection, write and read performance
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct BenchmarkArgs {
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+    /// Offset of a flash region to use for the benchmark
+    ///
+    /// The content of this region will be overwritten!
+    #[arg(value_name = "OFFSET", value_parser = parse_uint32)]
+    pub addr: u32,
+    /// Size of the data to write and read
+    #[arg(long, default_value = "0x40000", value_parser = parse_uint32)]
+    pub size: u32,
+    /// Baud rates to measure the throughput at
+    #[arg(long, value_delimiter = ',', default_value = "115200,460800,921600")]
+    pub baud_rates: Vec<u32>,
+    /// Block sizes to read flash with
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "0x400,0x1000",
+        value_parser = parse_uint32
+    )]
+    pub read_block_sizes: Vec<u32>,
+}
+
+/// A single measurement of the benchmark
+struct Measurement {
+    name: &'static str,
+    baud: Option<u32>,
+    block_size: Option<u32>,
+    duration: Duration,
+    bytes: Option<u32>,
+}
+
+/// Run the benchmark, printing a report of the results
+pub fn benchmark(args: BenchmarkArgs, config: &Config) -> Result<()> {
+    if args.connect_args.no_stub {
+        return Err(Error::StubRequired.into());
+    }
+
+    let mut measurements = Vec::new();
+
+    let start = Instant::now();
+    let mut flasher = connect(&args.connect_args, config, true, true)?;
+    measurements.push(Measurement {
+        name: "Connect",
+        baud: None,
+        block_size: None,
+        duration: start.elapsed(),
+        bytes: None,
+    });
+
+    if let Some(duration) = flasher.stub_load_time() {
+        measurements.push(Measurement {
+            name: "Load stub",
+            baud: None,
+            block_size: None,
+            duration,
+            bytes: None,
+        });
+    }
+
+    let data = benchmark_data(args.size as usize);
+
+    for &baud in &args.baud_rates {
+        info!("Measuring at {baud} baud...");
+        if let Err(e) = flasher.change_baud(baud) {
+            warn!("Skipping {baud} baud, changing the baud rate failed: {e}");
+            continue;
+        }
+
+        measurements.push(measure_write(&mut flasher, args.addr, &data, baud)?);
+
+        for &block_size in &args.read_block_sizes {
+            let start = Instant::now();
+            flasher.read_flash_region(args.addr, args.size, block_size, 64)?;
+            measurements.push(Measurement {
+                name: "Read flash",
+                baud: Some(baud),
+                block_size: Some(block_size),
+                duration: start.elapsed(),
+                bytes: Some(args.size),
+            });
+        }
+    }
+
+    print_report(&measurements);
+
+    Ok(())
+}
+
+fn measure_write(flasher: &mut Flasher, addr: u32, data: &[u8], baud: u32) -> Result<Measurement> {
+    let segment = RomSegment {
+        addr,
+        data: Cow::Borrowed(data),
+    };
+
+    let start = Instant::now();
+    flasher.write_bins_to_flash(&[segment], None)?;
+
+    Ok(Measurement {
+        name: "Write flash",
+        baud: Some(baud),
+        block_size: None,
+        duration: start.elapsed(),
+        bytes: Some(data.len() as u32),
+    })
+}
+
+/// Generate data which does not compress well, so that writes measure the
+/// throughput of the connection rather than that of the compression
+fn benchmark_data(size: usize) -> Vec<u8> {
+    // xorshift32
+    let mut state = 0x2545_f491_u32;
+
+    (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+fn print_report(measurements: &[Measurement]) {
+    let mut pretty = Table::new();
+
+    pretty
+        .load_preset(UTF8_FULL)
+        .apply_modifier(modifiers::UTF8_ROUND_CORNERS)
+        .set_header(vec![
+            Cell::new("Benchmark")
+                .fg(Color::Green)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Baud rate")
+                .fg(Color::Cyan)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Block size")
+                .fg(Color::Magenta)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Time")
+                .fg(Color::Red)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Throughput")
+                .fg(Color::Yellow)
+                .add_attribute(Attribute::Bold),
+        ]);
+
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "-".into());
+
+    for measurement in measurements {
+        let throughput = measurement.bytes.map(|bytes| {
+            format!(
+                "{:.1} KiB/s",
+                bytes as f64 / 1024.0 / measurement.duration.as_secs_f64()
+            )
+        });
+
+        pretty.add_row(vec![
+            Cell::new(measurement.name),
+            Cell::new(optional(measurement.baud.map(|baud| baud.to_string()))),
+            Cell::new(optional(
+                measurement.block_size.map(|size| format!("{size:#x}")),
+            )),
+            Cell::new(format!("{:.2}s", measurement.duration.as_secs_f64())),
+            Cell::new(optional(throughput)),
+        ]);
+    }
+
+    println!("{pretty}");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::ZlibEncoder, Compression};
+
+    use super::benchmark_data;
+
+    #[test]
+    fn benchmark_data_does_not_compress() {
+        let data = benchmark_data(0x10000);
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        assert!(compressed.len() > data.len() * 9 / 10);
+    }
+}
@@ -2,35 +2,82 @@
 //!
 //! Both [cargo-espflash] and [espflash] allow for the use of configuration
 //! files; the [Config] type handles the loading and saving of this
-//! configuration file.
+//! configuration file. The `config` subcommand allows for validating and
+//! editing it.
 //!
 //! [cargo-espflash]: https://crates.io/crates/cargo-espflash
 //! [espflash]: https://crates.io/crates/espflash
 
 use std::{
     ffi::OsStr,
-    fs::{create_dir_all, read_to_string, write},
-    path::PathBuf,
+    fs::{create_dir_all, read_to_string},
+    path::{Path, PathBuf},
 };
 
+use clap::{Args, Subcommand};
 use directories::ProjectDirs;
-use log::debug;
+use log::{debug, info};
 use miette::{IntoDiagnostic, Result, WrapErr};
 use serde::{Deserialize, Serialize};
 use serialport::UsbPortInfo;
+use toml::{Table, Value};
 
+use crate::cli::monitor::parser::channels::ChannelRoutes;
+use crate::connection::reset::{CustomReset, ResetHook, ResetStep};
 use crate::error::Error;
 use crate::flasher::FlashSettings;
+use crate::identity::DeviceAliases;
+use crate::output;
 
 /// A configured, known serial connection
 #[derive(Debug, Deserialize, Serialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Connection {
     /// Name of the serial port used for communication
     pub serial: Option<String>,
+    /// Serial ports which are always offered, even if they are not USB
+    /// devices, e.g. `/dev/ttyS5` for a native or PCI UART
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_ports: Vec<String>,
+    /// Number of attempts to reset and connect to the target device
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_attempts: Option<usize>,
+    /// Reset strategies to attempt in order when connecting, e.g.
+    /// `["usb-jtag-serial", "classic:500"]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reset_sequence: Option<Vec<ResetStep>>,
+    /// Changes of the DTR and RTS lines and pauses to reset the target device
+    /// with instead, e.g. `D0|R1|W0.1|D1|R0|W0.05|D0`, unless reset strategies
+    /// are given on the command line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub custom_reset_sequence: Option<CustomReset>,
+    /// External command which resets the target device, for boards whose
+    /// reset and boot pins are not driven by DTR and RTS, e.g.
+    /// `["./reset-board.sh", "--expander", "0x20"]`; it is run with `download`
+    /// or `run` appended, and the serial port in `ESPFLASH_PORT`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reset_hook: Option<ResetHook>,
+}
+
+/// Settings of the serial monitor
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Monitor {
+    /// Routes of the channels with `--log-format channels`, given as
+    /// `CHANNEL=ROUTE`, e.g. `["1=file:trace.bin"]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub channels: Vec<String>,
+}
+
+impl Monitor {
+    fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
 }
 
 /// A configured, known USB device
 #[derive(Debug, Deserialize, Serialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct UsbDevice {
     /// USB Vendor ID
     #[serde(serialize_with = "parse_u16_hex", deserialize_with = "parse_hex_u16")]
@@ -74,6 +121,7 @@ impl UsbDevice {
 
 /// Deserialized contents of a configuration file
 #[derive(Debug, Deserialize, Serialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     /// Baudrate
     #[serde(default)]
@@ -96,6 +144,13 @@ pub struct Config {
     /// Flash settings
     #[serde(default)]
     pub flash: FlashSettings,
+    /// Serial monitor settings
+    #[serde(default, skip_serializing_if = "Monitor::is_empty")]
+    pub monitor: Monitor,
+    /// Aliases of devices by their MAC address, prefixed to the output of
+    /// operations on them
+    #[serde(default, skip_serializing_if = "DeviceAliases::is_empty")]
+    pub device_aliases: DeviceAliases,
     /// Path of the file to save the configuration to
     #[serde(skip)]
     save_path: PathBuf,
@@ -125,11 +180,23 @@ impl Config {
         let file = Self::get_config_path()?;
 
         let mut config = if let Ok(data) = read_to_string(&file) {
-            toml::from_str(&data).into_diagnostic()?
+            Self::parse(&file, Value::Table(parse_table(&file, &data)?))?
         } else {
             Self::default()
         };
 
+        config.save_path = file;
+        debug!("Config: {:#?}", &config);
+        Ok(config)
+    }
+
+    /// Deserialize and validate the configuration read from `path`
+    fn parse(path: &Path, value: Value) -> Result<Self> {
+        let config: Self = value.try_into().map_err(|e| Error::InvalidConfig {
+            path: path.display().to_string(),
+            reason: e.to_string(),
+        })?;
+
         if let Some(table) = &config.partition_table {
             match table.extension() {
                 Some(ext) if ext == "bin" || ext == "csv" => {}
@@ -137,17 +204,48 @@ impl Config {
             }
         }
 
+        ChannelRoutes::parse_all(&config.monitor.channels).map_err(|e| Error::InvalidConfig {
+            path: path.display().to_string(),
+            reason: e.to_string(),
+        })?;
+
         if let Some(bootloader) = &config.bootloader {
             if bootloader.extension() != Some(OsStr::new("bin")) {
                 return Err(Error::InvalidBootloaderPath.into());
             }
         }
 
-        config.save_path = file;
-        debug!("Config: {:#?}", &config);
         Ok(config)
     }
 
+    /// Set the value of a (dotted) key in the configuration file, keeping the
+    /// other keys as they are
+    ///
+    /// The value is parsed as a TOML value if possible, and as a string
+    /// otherwise. The resulting configuration is validated before it is saved.
+    pub fn set(&self, key: &str, value: &str) -> Result<()> {
+        let mut table = match read_to_string(&self.save_path) {
+            Ok(data) => parse_table(&self.save_path, &data)?,
+            Err(_) => Table::new(),
+        };
+
+        set_key(&mut table, key, parse_value(value)).map_err(|reason| Error::InvalidConfig {
+            path: self.save_path.display().to_string(),
+            reason,
+        })?;
+        Self::parse(&self.save_path, Value::Table(table.clone()))?;
+
+        let serialized = toml::to_string(&table)
+            .into_diagnostic()
+            .wrap_err("Failed to serialize config")?;
+        self.write(serialized)
+    }
+
+    /// Path of the file the configuration is saved to
+    pub fn save_path(&self) -> &Path {
+        &self.save_path
+    }
+
     /// Save configuration to the configuration file
     pub fn save_with<F: Fn(&mut Self)>(&self, modify_fn: F) -> Result<()> {
         let mut copy = self.clone();
@@ -156,18 +254,140 @@ impl Config {
         let serialized = toml::to_string(&copy)
             .into_diagnostic()
             .wrap_err("Failed to serialize config")?;
+        self.write(serialized)
+    }
+
+    fn write(&self, serialized: String) -> Result<()> {
         create_dir_all(self.save_path.parent().unwrap())
             .into_diagnostic()
             .wrap_err("Failed to create config directory")?;
-        write(&self.save_path, serialized)
-            .into_diagnostic()
+        output::write(&self.save_path, serialized)
             .wrap_err_with(|| format!("Failed to write config to {}", self.save_path.display()))
     }
 }
 
+fn parse_table(path: &Path, data: &str) -> Result<Table> {
+    toml::from_str(data).map_err(|e| {
+        Error::InvalidConfig {
+            path: path.display().to_string(),
+            reason: e.to_string(),
+        }
+        .into()
+    })
+}
+
+/// Parse a value given on the command-line, falling back to a string
+fn parse_value(value: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {value}"))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(value.to_string()))
+}
+
+fn set_key(table: &mut Table, key: &str, value: Value) -> Result<(), String> {
+    let invalid_key = || format!("'{key}' is not a valid key");
+
+    let mut parts = key.split('.').collect::<Vec<_>>();
+    let last = parts
+        .pop()
+        .filter(|last| !last.is_empty())
+        .ok_or_else(invalid_key)?;
+
+    let mut table = table;
+    for part in parts {
+        table = table
+            .entry(part)
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or_else(invalid_key)?;
+    }
+    table.insert(last.to_string(), value);
+
+    Ok(())
+}
+
+/// Validate and edit the configuration file
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    pub command: ConfigCommand,
+}
+
+#[derive(Debug, Subcommand)]
+#[non_exhaustive]
+pub enum ConfigCommand {
+    /// Validate the configuration and print the effective configuration
+    ///
+    /// The effective configuration is the one from the configuration file, with
+    /// the serial port and baud rate overridden by the environment or the
+    /// command-line.
+    Check(ConfigCheckArgs),
+    /// Set a key in the configuration file, e.g. `flash.mode dio`
+    Set(ConfigSetArgs),
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct ConfigCheckArgs {
+    /// Baud rate at which to communicate with target device
+    #[arg(short = 'B', long, env = "ESPFLASH_BAUD")]
+    pub baud: Option<u32>,
+    /// Serial port connected to target device
+    #[arg(short = 'p', long, env = "ESPFLASH_PORT")]
+    pub port: Option<String>,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct ConfigSetArgs {
+    /// Key to set, nested keys are separated by dots
+    pub key: String,
+    /// Value to set the key to
+    pub value: String,
+}
+
+/// Execute a config subcommand
+pub fn config(args: ConfigArgs, config: &Config) -> Result<()> {
+    match args.command {
+        ConfigCommand::Check(args) => {
+            if config.save_path.exists() {
+                info!("Configuration file: {}", config.save_path.display());
+            } else {
+                info!(
+                    "No configuration file found, defaulting to {}",
+                    config.save_path.display()
+                );
+            }
+
+            let mut effective = config.clone();
+            if let Some(baud) = args.baud {
+                effective.baudrate = Some(baud);
+            }
+            if let Some(port) = args.port {
+                effective.connection.serial = Some(port);
+            }
+
+            let serialized = toml::to_string(&effective)
+                .into_diagnostic()
+                .wrap_err("Failed to serialize config")?;
+            println!("{serialized}");
+
+            Ok(())
+        }
+        ConfigCommand::Set(args) => {
+            config.set(&args.key, &args.value)?;
+            info!("Set '{}' in {}", args.key, config.save_path.display());
+
+            Ok(())
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::flasher::FlashMode;
     use serde::Deserialize;
 
     #[derive(Debug, Deserialize, Serialize)]
@@ -229,4 +449,36 @@ mod tests {
         let result: Result<TestData, _> = toml::from_str(r#"value = "xyz""#);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        let path = Path::new("espflash.toml");
+
+        let table = parse_table(path, "baudrate = 460800\n[flash]\nmode = \"dio\"").unwrap();
+        assert!(Config::parse(path, Value::Table(table)).is_ok());
+
+        let table = parse_table(path, "baudrat = 460800").unwrap();
+        assert!(Config::parse(path, Value::Table(table)).is_err());
+
+        let table = parse_table(path, "[flash]\nfreq = \"40MHz\"").unwrap();
+        assert!(Config::parse(path, Value::Table(table)).is_err());
+    }
+
+    #[test]
+    fn test_set_key() {
+        let mut table = Table::new();
+
+        set_key(&mut table, "flash.mode", parse_value("dio")).unwrap();
+        set_key(&mut table, "partition_table_offset", parse_value("0x9000")).unwrap();
+        set_key(&mut table, "connection.serial", parse_value("/dev/ttyUSB0")).unwrap();
+
+        let config =
+            Config::parse(Path::new("espflash.toml"), Value::Table(table.clone())).unwrap();
+        assert!(matches!(config.flash.mode, Some(FlashMode::Dio)));
+        assert_eq!(config.partition_table_offset, Some(0x9000));
+        assert_eq!(config.connection.serial.as_deref(), Some("/dev/ttyUSB0"));
+
+        assert!(set_key(&mut table, "flash.mode.x", parse_value("1")).is_err());
+        assert!(set_key(&mut table, "flash.", parse_value("1")).is_err());
+    }
 }
@@ -0,0 +1,319 @@
+//! Guided next steps for common failures
+//!
+//! Errors are matched against a table of known signatures, and the steps which
+//! apply to the connected chip and port are added to the help text of the
+//! error. The context is recorded while connecting, as most failures happen
+//! while or after connecting to a device.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    sync::Mutex,
+};
+
+use miette::{Diagnostic, Report};
+
+use crate::{
+    command::CommandType,
+    connection::{ESPRESSIF_USB_VID, USB_OTG_PID, USB_SERIAL_JTAG_PID},
+    error::{ConnectionError, Error},
+    targets::Chip,
+};
+
+/// What is known about the device an error occurred with
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// The chip of the device, if it was given or detected
+    pub chip: Option<Chip>,
+    /// The USB vendor and product IDs of the port
+    pub usb_ids: Option<(u16, u16)>,
+    /// Whether the flasher stub was used
+    pub stub: bool,
+}
+
+static CONTEXT: Mutex<ErrorContext> = Mutex::new(ErrorContext {
+    chip: None,
+    usb_ids: None,
+    stub: false,
+});
+
+/// Record the context later errors are advised in
+pub fn set_context(context: ErrorContext) {
+    *CONTEXT.lock().unwrap() = context;
+}
+
+/// Failures which have known remedies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Signature {
+    InvalidStubHandshake,
+    NoSyncReply,
+    ReadRegTimeout,
+    VerifyFailed,
+}
+
+impl Signature {
+    fn of(error: &Report) -> Option<Self> {
+        let connection_error = match error.downcast_ref::<Error>() {
+            Some(Error::VerifyFailed) => return Some(Signature::VerifyFailed),
+            Some(Error::Connection(error) | Error::Flashing(error)) => error,
+            _ => error.downcast_ref::<ConnectionError>()?,
+        };
+
+        match connection_error {
+            ConnectionError::InvalidStubHandshake => Some(Signature::InvalidStubHandshake),
+            ConnectionError::NoSyncReply => Some(Signature::NoSyncReply),
+            ConnectionError::Timeout(command)
+                if matches!(command.command(), Some(CommandType::ReadReg)) =>
+            {
+                Some(Signature::ReadRegTimeout)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Circumstances in which a step applies
+#[derive(Debug, Clone, Copy)]
+enum Condition {
+    Always,
+    /// The port is the USB-Serial-JTAG peripheral of the chip
+    UsbSerialJtag,
+    /// The port is the USB OTG peripheral of the chip
+    UsbOtg,
+    /// The port is a USB to UART bridge, or not a USB device at all
+    Uart,
+    /// The flasher stub was used
+    Stub,
+    /// The chip is one of these
+    Chip(&'static [Chip]),
+}
+
+impl Condition {
+    fn applies(self, context: &ErrorContext) -> bool {
+        let espressif_pid = match context.usb_ids {
+            Some((ESPRESSIF_USB_VID, pid)) => Some(pid),
+            _ => None,
+        };
+
+        match self {
+            Condition::Always => true,
+            Condition::UsbSerialJtag => espressif_pid == Some(USB_SERIAL_JTAG_PID),
+            Condition::UsbOtg => espressif_pid == Some(USB_OTG_PID),
+            Condition::Uart => !matches!(espressif_pid, Some(USB_SERIAL_JTAG_PID | USB_OTG_PID)),
+            Condition::Stub => context.stub,
+            Condition::Chip(chips) => context.chip.is_some_and(|chip| chips.contains(&chip)),
+        }
+    }
+}
+
+/// Remedies of the known failures, in the order they are suggested
+const STEPS: &[(Signature, Condition, &str)] = &[
+    (
+        Signature::InvalidStubHandshake,
+        Condition::Always,
+        "Use `--no-stub` to flash with the ROM loader of the chip instead of the flasher stub",
+    ),
+    (
+        Signature::InvalidStubHandshake,
+        Condition::UsbSerialJtag,
+        "Use `--stub-settle` to wait longer for the USB-Serial-JTAG port after the stub started",
+    ),
+    (
+        Signature::InvalidStubHandshake,
+        Condition::Always,
+        "If a stub was given with `--stub`, ensure that it was built for this chip",
+    ),
+    (
+        Signature::NoSyncReply,
+        Condition::Uart,
+        "Check the connection of the TX line of the serial adapter to the RX pin (U0RXD) of the chip",
+    ),
+    (
+        Signature::NoSyncReply,
+        Condition::Always,
+        "Close other programs which use the serial port, such as serial monitors",
+    ),
+    (
+        Signature::NoSyncReply,
+        Condition::Chip(&[Chip::Esp32]),
+        "Hold the BOOT button (GPIO0) while connecting, the reset circuit of some ESP32 boards is too fast for the automatic reset",
+    ),
+    (
+        Signature::ReadRegTimeout,
+        Condition::UsbSerialJtag,
+        "The application may have disabled or reconfigured the USB-Serial-JTAG peripheral. Hold the BOOT button while resetting the device, then connect with `--before no-reset`",
+    ),
+    (
+        Signature::ReadRegTimeout,
+        Condition::UsbOtg,
+        "The device may have left download mode. Hold the BOOT button while resetting the device, then connect with `--before no-reset`",
+    ),
+    (
+        Signature::ReadRegTimeout,
+        Condition::Uart,
+        "Lower the baud rate, e.g. with `--baud 115200`",
+    ),
+    (
+        Signature::VerifyFailed,
+        Condition::Always,
+        "Lower the baud rate, e.g. with `--baud 115200`, to rule out transmission errors",
+    ),
+    (
+        Signature::VerifyFailed,
+        Condition::Always,
+        "Check for brownouts with `--preflight`, a weak power supply corrupts writes to flash",
+    ),
+    (
+        Signature::VerifyFailed,
+        Condition::Stub,
+        "Slow down the SPI flash clock with `--flash-clock-div`, for flash chips with marginal timing",
+    ),
+    (
+        Signature::VerifyFailed,
+        Condition::Chip(&[Chip::Esp32]),
+        "If the flash is connected to other pins than the default ones, give them with `--spi-connection`",
+    ),
+];
+
+/// The steps which apply to `signature` in `context`
+fn steps(signature: Signature, context: &ErrorContext) -> Vec<&'static str> {
+    STEPS
+        .iter()
+        .filter(|(s, condition, _)| *s == signature && condition.applies(context))
+        .map(|(_, _, step)| *step)
+        .collect()
+}
+
+/// Add the next steps for known failures to the help text of `error`
+pub fn advise(error: Report) -> Report {
+    let Some(signature) = Signature::of(&error) else {
+        return error;
+    };
+
+    let context = *CONTEXT.lock().unwrap();
+    let steps = steps(signature, &context);
+    if steps.is_empty() {
+        return error;
+    }
+
+    Report::new(Advised { error, steps })
+}
+
+/// An error with the steps which are likely to resolve it
+struct Advised {
+    error: Report,
+    steps: Vec<&'static str>,
+}
+
+impl fmt::Debug for Advised {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Advised")
+            .field("error", &self.error.to_string())
+            .field("steps", &self.steps)
+            .finish()
+    }
+}
+
+impl Display for Advised {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for Advised {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+impl Diagnostic for Advised {
+    fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        self.error.code()
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        let mut help = self
+            .error
+            .help()
+            .map(|help| format!("{help}\n\n"))
+            .unwrap_or_default();
+        help.push_str("Try the following:");
+        for (i, step) in self.steps.iter().enumerate() {
+            help.push_str(&format!("\n  {}. {step}", i + 1));
+        }
+
+        Some(Box::new(help))
+    }
+
+    fn url<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
+        self.error.url()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::TimedOutCommand;
+
+    const JTAG: ErrorContext = ErrorContext {
+        chip: Some(Chip::Esp32c3),
+        usb_ids: Some((ESPRESSIF_USB_VID, USB_SERIAL_JTAG_PID)),
+        stub: true,
+    };
+    const UART: ErrorContext = ErrorContext {
+        chip: Some(Chip::Esp32),
+        usb_ids: Some((0x10c4, 0xea60)),
+        stub: false,
+    };
+
+    #[test]
+    fn errors_are_matched_to_signatures() {
+        let timeout = |command: TimedOutCommand| {
+            Report::new(Error::Connection(ConnectionError::Timeout(command)))
+        };
+
+        assert_eq!(
+            Signature::of(&timeout(CommandType::ReadReg.into())),
+            Some(Signature::ReadRegTimeout)
+        );
+        assert_eq!(Signature::of(&timeout(CommandType::FlashData.into())), None);
+        assert_eq!(
+            Signature::of(&Report::new(ConnectionError::NoSyncReply)),
+            Some(Signature::NoSyncReply)
+        );
+        assert_eq!(
+            Signature::of(&Report::new(Error::VerifyFailed)),
+            Some(Signature::VerifyFailed)
+        );
+        assert_eq!(Signature::of(&Report::new(Error::InternalError)), None);
+    }
+
+    #[test]
+    fn steps_depend_on_the_port_and_chip() {
+        let jtag = steps(Signature::ReadRegTimeout, &JTAG);
+        assert_eq!(jtag.len(), 1);
+        assert!(jtag[0].contains("USB-Serial-JTAG"));
+
+        let uart = steps(Signature::ReadRegTimeout, &UART);
+        assert_eq!(uart, ["Lower the baud rate, e.g. with `--baud 115200`"]);
+
+        assert!(steps(Signature::NoSyncReply, &UART)
+            .iter()
+            .any(|step| step.contains("BOOT")));
+        assert!(!steps(Signature::NoSyncReply, &JTAG)
+            .iter()
+            .any(|step| step.contains("BOOT") || step.contains("U0RXD")));
+    }
+
+    #[test]
+    fn advice_is_added_to_the_help() {
+        let advised = advise(Report::new(ConnectionError::NoSyncReply));
+        let help = advised.help().unwrap().to_string();
+
+        assert!(help.starts_with("The serial TX path seems to be down\n\nTry the following:"));
+        assert!(help.contains("\n  1. "));
+        assert_eq!(
+            advised.code().map(|code| code.to_string()).as_deref(),
+            Some("espflash::no_sync_reply")
+        );
+    }
+}
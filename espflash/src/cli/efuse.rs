@@ -0,0 +1,193 @@
+//! eFuse subcommands
+
+use std::path::PathBuf;
+
+use clap::{Args, Subcommand};
+use comfy_table::{modifiers, presets::UTF8_FULL, Attribute, Cell, Color, Table};
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use log::info;
+use miette::Result;
+
+use crate::{
+    cli::{config::Config, connect, print_board_info, report::Report, ConnectArgs},
+    efuse::{burn_efuse_bits, read_efuse_word, EfuseAction, EfusePlan, EfuseSpec, EfuseState},
+    error::Error,
+};
+
+/// Burn eFuses, only BLOCK0 of the ESP32-C3 so far
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct EfuseArgs {
+    #[command(subcommand)]
+    pub command: EfuseCommand,
+}
+
+#[derive(Debug, Subcommand)]
+#[non_exhaustive]
+pub enum EfuseCommand {
+    /// Burn the eFuse fields described by a TOML or JSON spec
+    ///
+    /// Only fields in BLOCK0 of the ESP32-C3 are supported so far.
+    ///
+    /// All fields are checked against the current eFuse values before anything
+    /// is burned. Fields are then burned in order, stopping at the first
+    /// failure. Completed fields are recorded in a state file, so that an
+    /// interrupted run can be resumed.
+    Apply(EfuseApplyArgs),
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct EfuseApplyArgs {
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+    /// TOML or JSON file describing the fields to burn
+    #[arg(value_name = "SPEC")]
+    pub spec: PathBuf,
+    /// Only print the plan, without burning anything
+    #[arg(long)]
+    pub dry_run: bool,
+    /// File recording the progress, defaults to the spec with a `.state.json`
+    /// extension
+    #[arg(long, value_name = "FILE")]
+    pub state: Option<PathBuf>,
+    /// Do not ask for confirmation before burning
+    #[arg(long)]
+    pub yes: bool,
+}
+
+/// Execute an eFuse subcommand
+pub fn efuse(args: EfuseArgs, config: &Config, report: &mut Report) -> Result<()> {
+    match args.command {
+        EfuseCommand::Apply(args) => apply(args, config, report),
+    }
+}
+
+fn apply(args: EfuseApplyArgs, config: &Config, report: &mut Report) -> Result<()> {
+    let spec = EfuseSpec::load(&args.spec)?;
+    let state_path = args
+        .state
+        .unwrap_or_else(|| args.spec.with_extension("state.json"));
+    let mut state = EfuseState::load(&state_path)?;
+
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    print_board_info(&mut flasher, report)?;
+
+    let chip = flasher.chip();
+    let plan = EfusePlan::new(&spec, chip, |block, word| {
+        read_efuse_word(flasher.connection(), chip, block, word)
+    })?;
+
+    print_plan(&plan, &state);
+
+    let pending = plan
+        .steps
+        .iter()
+        .filter(|step| !state.completed.contains(&step.burn.name))
+        .filter(|step| step.action != EfuseAction::AlreadyBurned || step.write_protect)
+        .collect::<Vec<_>>();
+
+    if args.dry_run || pending.is_empty() {
+        info!("Nothing was burned");
+        return Ok(());
+    }
+
+    if !args.yes
+        && !Confirm::with_theme(&ColorfulTheme::default())
+            .with_prompt("Burning eFuses is irreversible. Continue?")
+            .interact_opt()
+            .map_err(Error::from)?
+            .unwrap_or_default()
+    {
+        return Err(Error::Cancelled.into());
+    }
+
+    for step in pending {
+        let burn = &step.burn;
+        info!("Burning {}...", burn.name);
+
+        let connection = flasher.connection();
+        if let EfuseAction::Burn(bits) = step.action {
+            burn_efuse_bits(connection, chip, burn.word, bits)?;
+
+            let word = read_efuse_word(connection, chip, burn.block, burn.word)?;
+            if word & bits != bits {
+                return Err(Error::EfuseBurnFailed(format!(
+                    "'{}' does not read back as burned (word is {word:#010x})",
+                    burn.name
+                ))
+                .into());
+            }
+        }
+
+        if let (true, Some(bit)) = (step.write_protect, burn.wr_dis_bit) {
+            burn_efuse_bits(connection, chip, 0, 1 << bit)?;
+        }
+
+        state.completed.push(burn.name.clone());
+        state.save(&state_path)?;
+    }
+
+    info!("All eFuse fields were burned successfully");
+
+    Ok(())
+}
+
+fn print_plan(plan: &EfusePlan, state: &EfuseState) {
+    let mut pretty = Table::new();
+
+    pretty
+        .load_preset(UTF8_FULL)
+        .apply_modifier(modifiers::UTF8_ROUND_CORNERS)
+        .set_header(vec![
+            Cell::new("Field")
+                .fg(Color::Green)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Location")
+                .fg(Color::Cyan)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Current")
+                .fg(Color::Magenta)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Requested")
+                .fg(Color::Red)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Action")
+                .fg(Color::Yellow)
+                .add_attribute(Attribute::Bold),
+        ]);
+
+    for step in &plan.steps {
+        let burn = &step.burn;
+        let action = if state.completed.contains(&burn.name) {
+            "done (state file)".to_string()
+        } else {
+            let action = match step.action {
+                EfuseAction::Burn(_) => "burn",
+                EfuseAction::AlreadyBurned => "already burned",
+            };
+            if step.write_protect {
+                format!("{action}, write-protect")
+            } else {
+                action.to_string()
+            }
+        };
+
+        pretty.add_row(vec![
+            Cell::new(&burn.name),
+            Cell::new(format!(
+                "BLOCK{} word {} bits {}..{}",
+                burn.block,
+                burn.word,
+                burn.bit,
+                burn.bit + burn.width
+            )),
+            Cell::new(format!("{:#x}", step.current)),
+            Cell::new(format!("{:#x}", burn.value)),
+            Cell::new(action),
+        ]);
+    }
+
+    println!("{pretty}");
+}
@@ -0,0 +1,348 @@
+//! Map of the flash layout of an application or a device
+//!
+//! The map shows the bootloader, the partition table and each partition with
+//! the space between them, along with how much of them the images use. This
+//! explains at a glance why an application does not fit into its partition.
+
+use std::fmt::Write as _;
+
+use clap::Args;
+use esp_idf_part::{PartitionTable, Type};
+use indicatif::HumanBytes;
+use log::debug;
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use crate::{
+    cli::{
+        config::Config,
+        connect, make_flash_data, map_file, parse_partition_table,
+        report::{OutputFormat, Report},
+        ConnectArgs, FlashConfigArgs, ImageArgs,
+    },
+    elf::ElfFirmwareImage,
+    error::Error,
+    flasher::FLASH_SECTOR_SIZE,
+    image_format::read_image_len,
+    targets::XtalFrequency,
+};
+
+/// Width of the bars showing how much of a region is used
+const BAR_WIDTH: usize = 24;
+
+/// Show the flash layout of an application, or of the connected device
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct FlashMapArgs {
+    /// ELF image to show the layout of, instead of the connected device
+    #[arg(value_name = "IMAGE", requires = "chip")]
+    pub image: Option<std::path::PathBuf>,
+    /// Draw the map with ASCII characters only
+    #[arg(long)]
+    pub ascii: bool,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+    /// Flashing configuration
+    #[clap(flatten)]
+    pub flash_config_args: FlashConfigArgs,
+    /// Image arguments, e.g. the partition table to show instead of the one on
+    /// the device
+    #[clap(flatten)]
+    pub image_args: ImageArgs,
+}
+
+/// A region of flash in the map
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Region {
+    name: String,
+    offset: u32,
+    size: u32,
+    /// Bytes of the region used by an image, if it holds one
+    used: Option<u32>,
+}
+
+impl Region {
+    fn new(name: impl Into<String>, offset: u32, size: u32, used: Option<u32>) -> Self {
+        Self {
+            name: name.into(),
+            offset,
+            size,
+            used,
+        }
+    }
+}
+
+/// Print the flash layout of an application or the connected device
+pub fn flash_map(args: FlashMapArgs, config: &Config, report: &Report) -> Result<()> {
+    let (regions, flash_size) = match &args.image {
+        Some(path) => image_layout(&args, path, config, report.format())?,
+        None => device_layout(&args, config)?,
+    };
+
+    print!("{}", render(&regions, flash_size, args.ascii));
+
+    Ok(())
+}
+
+/// The layout the application would be flashed with
+fn image_layout(
+    args: &FlashMapArgs,
+    path: &std::path::Path,
+    config: &Config,
+    format: OutputFormat,
+) -> Result<(Vec<Region>, u32)> {
+    let chip = args.connect_args.chip().ok_or(Error::ChipNotProvided)?;
+    let elf_data =
+        map_file(path).wrap_err_with(|| format!("Failed to open image {}", path.display()))?;
+
+    let flash_data = make_flash_data(
+        args.image_args.clone(),
+        &args.flash_config_args,
+        config,
+        None,
+        None,
+        format,
+    )?;
+    let flash_size = flash_data.flash_settings.size.unwrap_or_default().size();
+    let params = chip.into_target().params();
+
+    let partition_table = flash_data
+        .partition_table
+        .clone()
+        .unwrap_or_else(|| params.default_partition_table(Some(flash_size)));
+    let partition_table_offset = flash_data
+        .partition_table_offset
+        .unwrap_or(params.partition_addr);
+    let bootloader_len = flash_data
+        .bootloader
+        .as_ref()
+        .map_or(params.default_bootloader.len(), Vec::len) as u32;
+
+    let boot_addr = flash_data.bootloader_offset.unwrap_or(params.boot_addr);
+    let app_offset = flash_data.app_offset;
+
+    // The size is reported even if the application does not fit
+    let target_app_partition = flash_data.target_app_partition.clone();
+    let image = ElfFirmwareImage::try_from(&elf_data[..])?;
+    let app_size = match chip.into_target().get_flash_image(
+        &image,
+        flash_data,
+        None,
+        XtalFrequency::default(chip),
+    ) {
+        Ok(image) => image.app_size(),
+        Err(Error::ElfTooBig(app_size, _)) => app_size,
+        Err(e) => return Err(e.into()),
+    };
+
+    let app_partition = match (app_offset, &target_app_partition) {
+        (Some(offset), _) => partition_table
+            .partitions()
+            .iter()
+            .find(|partition| partition.offset() == offset),
+        (None, Some(label)) => partition_table.find(label),
+        (None, None) => partition_table
+            .find("factory")
+            .or_else(|| partition_table.find_by_type(Type::App)),
+    }
+    .map(|partition| partition.name());
+    if app_offset.is_none() && app_partition.is_none() {
+        return Err(Error::AppPartitionNotFound.into());
+    }
+
+    let mut regions = vec![
+        Region::new(
+            "bootloader",
+            boot_addr,
+            partition_table_offset.saturating_sub(boot_addr),
+            Some(bootloader_len),
+        ),
+        partition_table_region(&partition_table, partition_table_offset)?,
+    ];
+    regions.extend(partition_table.partitions().iter().map(|partition| {
+        let used = (Some(partition.name()) == app_partition).then_some(app_size);
+        Region::new(partition.name(), partition.offset(), partition.size(), used)
+    }));
+    // An application written outside of the app partitions
+    if let (Some(offset), None) = (app_offset, &app_partition) {
+        regions.push(Region::new("app", offset, app_size, Some(app_size)));
+    }
+
+    Ok((regions, flash_size))
+}
+
+/// The layout of the connected device, with the size of the images on it
+fn device_layout(args: &FlashMapArgs, config: &Config) -> Result<(Vec<Region>, u32)> {
+    let mut flasher = connect(&args.connect_args, config, true, true)?;
+    if let Some(flash_size) = args.flash_config_args.flash_size.or(config.flash.size) {
+        flasher.set_flash_size(flash_size);
+    }
+
+    let flash_size = flasher.device_info()?.flash_size.size();
+    let boot_addr = flasher.chip().into_target().params().boot_addr;
+
+    let partition_table = args
+        .image_args
+        .partition_table
+        .as_deref()
+        .or(config.partition_table.as_deref());
+    let (partition_table_offset, partition_table) = match partition_table {
+        Some(path) => {
+            let offset = args
+                .image_args
+                .partition_table_offset
+                .or(config.partition_table_offset)
+                .unwrap_or(flasher.chip().into_target().params().partition_addr);
+            (offset, parse_partition_table(path)?)
+        }
+        None => flasher.find_partition_table()?,
+    };
+
+    let mut image_len = |offset: u32| {
+        let len = read_image_len(|pos, len| {
+            flasher.read_flash_region(offset + pos, len, FLASH_SECTOR_SIZE as u32, 64)
+        });
+        if let Err(e) = &len {
+            debug!("No image found at {offset:#x}: {e}");
+        }
+
+        len.ok()
+    };
+
+    let mut regions = vec![
+        Region::new(
+            "bootloader",
+            boot_addr,
+            partition_table_offset.saturating_sub(boot_addr),
+            image_len(boot_addr),
+        ),
+        partition_table_region(&partition_table, partition_table_offset)?,
+    ];
+    for partition in partition_table.partitions() {
+        let used = match partition.ty() {
+            Type::App => Some(image_len(partition.offset()).unwrap_or(0)),
+            _ => None,
+        };
+        regions.push(Region::new(
+            partition.name(),
+            partition.offset(),
+            partition.size(),
+            used,
+        ));
+    }
+
+    Ok((regions, flash_size))
+}
+
+/// The partition table, which takes up the sector at its offset
+fn partition_table_region(partition_table: &PartitionTable, offset: u32) -> Result<Region> {
+    let len = partition_table.to_bin().into_diagnostic()?.len() as u32;
+
+    Ok(Region::new(
+        "partition table",
+        offset,
+        len.next_multiple_of(FLASH_SECTOR_SIZE as u32),
+        None,
+    ))
+}
+
+/// Render the map of the regions, with the free space between them, as a table
+/// of bars showing how much of each region is used
+fn render(regions: &[Region], flash_size: u32, ascii: bool) -> String {
+    let (used_char, free_char, data_char) = if ascii {
+        ('#', '.', '=')
+    } else {
+        ('█', '░', '▒')
+    };
+
+    let mut regions = regions.to_vec();
+    regions.sort_by_key(|region| region.offset);
+
+    // Fill the space between the regions, and up to the end of the flash
+    let mut rows = Vec::new();
+    let mut end = regions.first().map_or(0, |region| region.offset);
+    for region in regions {
+        if region.offset > end {
+            rows.push(Region::new("(free)", end, region.offset - end, None));
+        }
+        end = end.max(region.offset + region.size);
+        rows.push(region);
+    }
+    if end < flash_size {
+        rows.push(Region::new("(free)", end, flash_size - end, None));
+    }
+
+    let name_width = rows.iter().map(|row| row.name.len()).max().unwrap_or(0);
+    let mut out = String::new();
+    writeln!(out, "Flash size: {}", HumanBytes(flash_size as u64)).ok();
+
+    for row in rows {
+        let (bar, usage) = match row.used {
+            _ if row.name == "(free)" => (" ".repeat(BAR_WIDTH), String::new()),
+            Some(used) => {
+                let filled = if used >= row.size {
+                    BAR_WIDTH
+                } else {
+                    // Any used space shows up as at least one character
+                    (used as u64 * BAR_WIDTH as u64)
+                        .div_ceil(row.size.max(1) as u64)
+                        .min(BAR_WIDTH as u64) as usize
+                };
+                let bar = format!(
+                    "{}{}",
+                    used_char.to_string().repeat(filled),
+                    free_char.to_string().repeat(BAR_WIDTH - filled)
+                );
+
+                let percent = used as f64 / row.size.max(1) as f64 * 100.0;
+                let mut usage = format!("{} used ({percent:.0}%)", HumanBytes(used as u64));
+                if used > row.size {
+                    write!(
+                        usage,
+                        ", exceeds the region by {}",
+                        HumanBytes((used - row.size) as u64)
+                    )
+                    .ok();
+                }
+
+                (bar, usage)
+            }
+            None => (data_char.to_string().repeat(BAR_WIDTH), String::new()),
+        };
+
+        let line = format!(
+            "{:#010x}  {:>10}  {:<name_width$}  |{bar}|  {usage}",
+            row.offset,
+            HumanBytes(row.size as u64).to_string(),
+            row.name,
+        );
+        writeln!(out, "{}", line.trim_end()).ok();
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_shows_free_space_and_usage() {
+        let regions = [
+            Region::new("bootloader", 0x0, 0x8000, Some(0x4000)),
+            Region::new("partition table", 0x8000, 0x1000, None),
+            Region::new("factory", 0x10000, 0x10000, Some(0x18000)),
+        ];
+
+        let map = render(&regions, 0x40000, true);
+        let lines: Vec<_> = map.lines().collect();
+
+        assert_eq!(lines[0], "Flash size: 256.00 KiB");
+        assert!(lines[1].starts_with("0x00000000   32.00 KiB  bootloader       |############............|  16.00 KiB used (50%)"));
+        assert!(lines[2].ends_with("partition table  |========================|"));
+        assert!(lines[3].starts_with("0x00009000   28.00 KiB  (free)"));
+        assert!(lines[4].ends_with("96.00 KiB used (150%), exceeds the region by 32.00 KiB"));
+        assert!(lines[5].starts_with("0x00020000  128.00 KiB  (free)"));
+        assert_eq!(lines.len(), 6);
+    }
+}
@@ -0,0 +1,328 @@
+//! Printing and checking the header, segments and integrity of binary images
+//!
+//! Application and bootloader images are described on their own. In merged
+//! images, the partition table is searched for, and the bootloader and each
+//! application found in the app partitions are described.
+
+use std::path::PathBuf;
+
+use clap::Args;
+use comfy_table::{modifiers, presets::UTF8_FULL, Attribute, Cell, Color, Table};
+use esp_idf_part::{PartitionTable, Type};
+use miette::{Result, WrapErr};
+use serde::Serialize;
+
+use crate::{
+    cli::{map_file, parse_uint32, report::Report},
+    flasher::{MAX_PARTITION_TABLE_SIZE, PARTITION_ENTRY_MAGIC, PARTITION_TABLE_OFFSETS},
+    image_format::{check_image, metadata::AppDescriptor, ImageInfo, ESP_MAGIC},
+};
+
+/// Offsets the bootloader is placed at, depending on the chip
+const BOOTLOADER_OFFSETS: [u32; 3] = [0x0, 0x1000, 0x2000];
+
+/// Print the header, segments and integrity of a binary image
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct ImageInfoArgs {
+    /// Application, bootloader or merged image
+    #[arg(value_name = "FILE")]
+    pub image: PathBuf,
+    /// Flash address the image starts at, for merged images which do not
+    /// start at 0x0
+    #[arg(long, value_name = "ADDRESS", value_parser = parse_uint32, default_value = "0")]
+    pub offset: u32,
+}
+
+/// An image as printed with `--output-format json`
+#[derive(Debug, Serialize)]
+struct ImageReport {
+    /// Flash address of the image, within a merged image
+    address: u32,
+    /// `bootloader` or the label of the app partition, within a merged image
+    label: Option<String>,
+    chip: Option<String>,
+    chip_id: u16,
+    entry: u32,
+    flash_mode: Option<String>,
+    flash_size: Option<String>,
+    flash_frequency: Option<String>,
+    min_chip_rev: u16,
+    max_chip_rev: u16,
+    segments: Vec<SegmentReport>,
+    checksum: u8,
+    checksum_valid: bool,
+    sha256: Option<String>,
+    sha256_valid: Option<bool>,
+    app_descriptor: Option<AppDescriptor>,
+}
+
+#[derive(Debug, Serialize)]
+struct SegmentReport {
+    address: u32,
+    length: u32,
+    offset: usize,
+    /// Whether the segment is mapped from flash, if the chip is known
+    flash: Option<bool>,
+}
+
+/// Print the header, segments and integrity of the image given in `args`,
+/// failing if the checksum or digest of any image does not match
+pub fn image_info(args: ImageInfoArgs, report: &Report) -> Result<()> {
+    let data = map_file(&args.image)
+        .wrap_err_with(|| format!("Failed to open image {}", args.image.display()))?;
+
+    let images = match find_partition_table(&data, args.offset) {
+        Some(table) => merged_images(&data, args.offset, &table),
+        None => vec![(args.offset, None)],
+    };
+
+    let mut reports = Vec::with_capacity(images.len());
+    for (address, label) in images {
+        let image = &data[(address - args.offset) as usize..];
+        let info = ImageInfo::parse(image)?;
+
+        match &label {
+            Some(label) => outputln!(report, "{label} at {address:#x}"),
+            None => outputln!(report, "{}", args.image.display()),
+        }
+        print_info(&info, report);
+        outputln!(report);
+
+        reports.push(image_report(address, label, &info));
+    }
+
+    report.print(&reports)?;
+
+    for image in &reports {
+        check_image(&data[(image.address - args.offset) as usize..])?;
+    }
+
+    Ok(())
+}
+
+/// The partition table of a merged image starting at the flash address
+/// `offset`, if there is one
+fn find_partition_table(data: &[u8], offset: u32) -> Option<PartitionTable> {
+    PARTITION_TABLE_OFFSETS
+        .iter()
+        .filter_map(|address| address.checked_sub(offset))
+        .map(|start| start as usize)
+        .filter(|&start| {
+            data.get(start..start + PARTITION_ENTRY_MAGIC.len()) == Some(&PARTITION_ENTRY_MAGIC)
+        })
+        .find_map(|start| {
+            let end = data.len().min(start + MAX_PARTITION_TABLE_SIZE as usize);
+            PartitionTable::try_from_bytes(data[start..end].to_vec()).ok()
+        })
+}
+
+/// Flash addresses and labels of the bootloader and of the applications in
+/// the app partitions of a merged image
+fn merged_images(data: &[u8], offset: u32, table: &PartitionTable) -> Vec<(u32, Option<String>)> {
+    let starts_image = |address: u32| {
+        address >= offset && data.get((address - offset) as usize) == Some(&ESP_MAGIC)
+    };
+
+    let bootloader = BOOTLOADER_OFFSETS
+        .into_iter()
+        .find(|&address| starts_image(address))
+        .map(|address| (address, Some("bootloader".to_string())));
+    let apps = table
+        .partitions()
+        .iter()
+        .filter(|partition| partition.ty() == Type::App && starts_image(partition.offset()))
+        .map(|partition| (partition.offset(), Some(partition.name())));
+
+    bootloader.into_iter().chain(apps).collect()
+}
+
+fn print_info(info: &ImageInfo, report: &Report) {
+    let unknown = || "unknown".to_string();
+    let revision = |rev: u16| format!("v{}.{}", rev / 100, rev % 100);
+
+    outputln!(
+        report,
+        "Chip:              {} (ID {})",
+        info.chip.map_or_else(unknown, |chip| chip.to_string()),
+        info.chip_id
+    );
+    outputln!(report, "Entry point:       {:#010x}", info.entry);
+    outputln!(
+        report,
+        "Flash mode:        {}",
+        info.flash_mode.map_or_else(unknown, name)
+    );
+    outputln!(
+        report,
+        "Flash size:        {}",
+        info.flash_size.map_or_else(unknown, name)
+    );
+    outputln!(
+        report,
+        "Flash frequency:   {}",
+        info.flash_frequency.map_or_else(unknown, name)
+    );
+    outputln!(
+        report,
+        "Chip revision:     {} - {}",
+        revision(info.min_chip_rev),
+        revision(info.max_chip_rev)
+    );
+    outputln!(
+        report,
+        "Checksum:          {:#04x} ({})",
+        info.checksum,
+        if info.checksum_valid() {
+            "valid".to_string()
+        } else {
+            format!("invalid, calculated {:#04x}", info.calculated_checksum)
+        }
+    );
+    match (info.digest, info.digest_valid()) {
+        (Some(digest), Some(true)) => {
+            outputln!(report, "SHA-256:           {} (valid)", hex(&digest))
+        }
+        (Some(digest), _) => outputln!(
+            report,
+            "SHA-256:           {} (invalid, calculated {})",
+            hex(&digest),
+            hex(&info.calculated_digest)
+        ),
+        (None, _) => outputln!(report, "SHA-256:           not appended"),
+    }
+
+    if let Some(desc) = &info.app_descriptor {
+        outputln!(report, "Project name:      {}", desc.project_name);
+        outputln!(report, "Version:           {}", desc.version);
+        outputln!(report, "Secure version:    {}", desc.secure_version);
+        outputln!(report, "Build time:        {} {}", desc.date, desc.time);
+        outputln!(report, "IDF version:       {}", desc.idf_version);
+        outputln!(report, "ELF SHA-256:       {}", hex(&desc.elf_sha256));
+    }
+
+    let mut pretty = Table::new();
+    pretty
+        .load_preset(UTF8_FULL)
+        .apply_modifier(modifiers::UTF8_ROUND_CORNERS)
+        .set_header(vec![
+            Cell::new("Segment")
+                .fg(Color::Green)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Address")
+                .fg(Color::Cyan)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Length")
+                .fg(Color::Magenta)
+                .add_attribute(Attribute::Bold),
+            Cell::new("File offset")
+                .fg(Color::Red)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Memory")
+                .fg(Color::Yellow)
+                .add_attribute(Attribute::Bold),
+        ]);
+
+    for (index, segment) in info.segments.iter().enumerate() {
+        let memory = match segment_in_flash(info, segment.addr) {
+            Some(true) => "flash",
+            Some(false) => "RAM",
+            None => "unknown",
+        };
+
+        pretty.add_row(vec![
+            Cell::new(index).fg(Color::Green),
+            Cell::new(format!("{:#010x}", segment.addr)).fg(Color::Cyan),
+            Cell::new(format!("{:#x}", segment.len)).fg(Color::Magenta),
+            Cell::new(format!("{:#x}", segment.offset)).fg(Color::Red),
+            Cell::new(memory).fg(Color::Yellow),
+        ]);
+    }
+
+    outputln!(report, "{pretty}");
+}
+
+fn image_report(address: u32, label: Option<String>, info: &ImageInfo) -> ImageReport {
+    ImageReport {
+        address,
+        label,
+        chip: info.chip.map(|chip| chip.to_string()),
+        chip_id: info.chip_id,
+        entry: info.entry,
+        flash_mode: info.flash_mode.map(name),
+        flash_size: info.flash_size.map(name),
+        flash_frequency: info.flash_frequency.map(name),
+        min_chip_rev: info.min_chip_rev,
+        max_chip_rev: info.max_chip_rev,
+        segments: info
+            .segments
+            .iter()
+            .map(|segment| SegmentReport {
+                address: segment.addr,
+                length: segment.len,
+                offset: segment.offset,
+                flash: segment_in_flash(info, segment.addr),
+            })
+            .collect(),
+        checksum: info.checksum,
+        checksum_valid: info.checksum_valid(),
+        sha256: info.digest.map(|digest| hex(&digest)),
+        sha256_valid: info.digest_valid(),
+        app_descriptor: info.app_descriptor.clone(),
+    }
+}
+
+/// Whether the address is mapped from flash, if the chip is known
+fn segment_in_flash(info: &ImageInfo, addr: u32) -> Option<bool> {
+    info.chip.map(|chip| chip.into_target().addr_is_flash(addr))
+}
+
+/// The name of a flash setting, as used in the configuration
+fn name(value: impl Serialize) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        other => format!("{other:?}"),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        flasher::{FlashData, FlashSettings},
+        image_format::build_flash_plan,
+        targets::{Chip, XtalFrequency},
+    };
+
+    #[test]
+    fn merged_images_are_found() {
+        let elf = include_bytes!("../../tests/resources/esp32_hal_blinky").to_vec();
+        let flash_data = FlashData::new(None, None, None, None, FlashSettings::default(), 0, None);
+        let plan = build_flash_plan(&elf, Chip::Esp32, flash_data, XtalFrequency::_40Mhz).unwrap();
+
+        let mut merged = Vec::new();
+        for (addr, data) in &plan {
+            merged.resize(*addr as usize, 0xff);
+            merged.extend_from_slice(data);
+        }
+
+        let table = find_partition_table(&merged, 0).unwrap();
+        assert_eq!(
+            merged_images(&merged, 0, &table),
+            [
+                (0x1000, Some("bootloader".to_string())),
+                (0x10000, Some("factory".to_string()))
+            ]
+        );
+
+        // Merged images starting at the bootloader
+        let table = find_partition_table(&merged[0x1000..], 0x1000).unwrap();
+        assert_eq!(merged_images(&merged[0x1000..], 0x1000, &table).len(), 2);
+
+        assert!(find_partition_table(&plan[2].1, 0).is_none());
+    }
+}
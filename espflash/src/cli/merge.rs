@@ -0,0 +1,189 @@
+//! Merging binaries into a single image, e.g. a factory image containing the
+//! bootloader, partition table, application and data partitions
+
+use std::{borrow::Cow, fs, io::Write, path::PathBuf};
+
+use clap::{Args, ValueEnum};
+use log::{info, warn};
+use miette::{IntoDiagnostic, Result};
+
+use crate::{
+    cli::{parse_uint32, write_padding_with},
+    elf::RomSegment,
+    error::Error,
+    flasher::FlashSize,
+    image_format::{ihex::write_ihex, uf2::write_uf2},
+    output::OutputFile,
+    targets::Chip,
+};
+
+/// Format of the merged image
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[non_exhaustive]
+pub enum MergeFormat {
+    /// Raw binary, the contents of flash starting at the first file
+    #[default]
+    Raw,
+    /// Intel HEX
+    Hex,
+    /// UF2, e.g. for the TinyUF2 bootloader
+    Uf2,
+}
+
+/// Merge binaries into a single image
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct MergeBinArgs {
+    /// Chip the image is for
+    #[arg(short = 'c', long)]
+    pub chip: Chip,
+    /// File to write the merged image to
+    #[arg(short = 'o', long, value_name = "FILE")]
+    pub output: PathBuf,
+    /// Format of the merged image
+    #[arg(long, value_enum, default_value_t)]
+    pub format: MergeFormat,
+    /// Byte the gaps between the files are filled with in raw images
+    #[arg(long, default_value = "0xFF", value_parser = parse_int::parse::<u8>)]
+    pub fill_byte: u8,
+    /// Pad a raw image to the size of the flash, so that it overwrites all
+    /// of it
+    #[arg(long, value_name = "SIZE", value_enum)]
+    pub pad_to_size: Option<FlashSize>,
+    /// Files to merge, as pairs of the address to place the file at and its
+    /// path
+    #[arg(value_names = ["ADDRESS", "FILE"], num_args = 2.., required = true)]
+    pub files: Vec<String>,
+}
+
+/// Merge the files given in `args` into a single image
+pub fn merge_bin(args: MergeBinArgs) -> Result<()> {
+    let segments = read_segments(&args.files)?;
+
+    let mut file = OutputFile::create(&args.output)?;
+    match args.format {
+        MergeFormat::Raw => {
+            let flash_size = args.pad_to_size.map(|size| size.size());
+            write_raw(&mut file, &segments, args.fill_byte, flash_size)?;
+        }
+        MergeFormat::Hex => write_ihex(&mut file, &segments).into_diagnostic()?,
+        MergeFormat::Uf2 => write_uf2(&mut file, &segments, args.chip).into_diagnostic()?,
+    }
+    file.persist()?;
+
+    if args.format != MergeFormat::Raw && args.pad_to_size.is_some() {
+        warn!("Only raw images are padded to the flash size, `--pad-to-size` was ignored");
+    }
+
+    info!(
+        "Merged {} files into {}",
+        segments.len(),
+        args.output.display()
+    );
+
+    Ok(())
+}
+
+/// Read the files given as pairs of an address and a path, ordered by their
+/// address
+fn read_segments(files: &[String]) -> Result<Vec<RomSegment<'static>>> {
+    if files.len() % 2 != 0 {
+        return Err(Error::InvalidMergeArgs(format!(
+            "no file given for the address {}",
+            files[files.len() - 1]
+        ))
+        .into());
+    }
+
+    let mut segments = files
+        .chunks_exact(2)
+        .map(|pair| {
+            let addr = parse_uint32(&pair[0]).map_err(|_| {
+                Error::InvalidMergeArgs(format!("'{}' is not a valid address", pair[0]))
+            })?;
+            let data = fs::read(&pair[1]).map_err(|e| Error::FileOpenError(pair[1].clone(), e))?;
+
+            Ok(RomSegment {
+                addr,
+                data: Cow::Owned(data),
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    segments.sort_by_key(|segment| segment.addr);
+    check_overlaps(&segments)?;
+
+    Ok(segments)
+}
+
+fn check_overlaps(segments: &[RomSegment<'_>]) -> Result<(), Error> {
+    for pair in segments.windows(2) {
+        let end = pair[0].addr as u64 + pair[0].data.len() as u64;
+        if end > pair[1].addr as u64 {
+            return Err(Error::InvalidMergeArgs(format!(
+                "the file at {:#x} ends at {end:#x}, after the start of the file at {:#x}",
+                pair[0].addr, pair[1].addr
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the segments as a raw image, which starts at the first segment
+fn write_raw(
+    writer: &mut impl Write,
+    segments: &[RomSegment<'_>],
+    fill_byte: u8,
+    flash_size: Option<u32>,
+) -> Result<()> {
+    let start = segments.first().map_or(0, |segment| segment.addr as u64);
+    let mut position = start;
+
+    for segment in segments {
+        write_padding_with(writer, segment.addr as u64 - position, fill_byte)?;
+        writer.write_all(&segment.data).into_diagnostic()?;
+        position = segment.addr as u64 + segment.data.len() as u64;
+    }
+
+    if let Some(flash_size) = flash_size {
+        write_padding_with(
+            writer,
+            (flash_size as u64).saturating_sub(position),
+            fill_byte,
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(addr: u32, data: &[u8]) -> RomSegment<'_> {
+        RomSegment {
+            addr,
+            data: Cow::Borrowed(data),
+        }
+    }
+
+    #[test]
+    fn raw_images_fill_gaps() {
+        let segments = [segment(0x1000, &[1, 2]), segment(0x1004, &[3])];
+
+        let mut image = Vec::new();
+        write_raw(&mut image, &segments, 0x00, None).unwrap();
+        assert_eq!(image, [1, 2, 0, 0, 3]);
+
+        let mut image = Vec::new();
+        write_raw(&mut image, &segments, 0xff, Some(0x1008)).unwrap();
+        assert_eq!(image, [1, 2, 0xff, 0xff, 3, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn overlapping_files_are_rejected() {
+        assert!(check_overlaps(&[segment(0x0, &[0; 0x10]), segment(0x10, &[0])]).is_ok());
+        assert!(check_overlaps(&[segment(0x0, &[0; 0x11]), segment(0x10, &[0])]).is_err());
+    }
+}
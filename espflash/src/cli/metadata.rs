@@ -0,0 +1,74 @@
+//! Printing and checking the metadata of an ELF file
+
+use std::path::PathBuf;
+
+use clap::Args;
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use crate::{cli::map_file, image_format::metadata::Metadata, targets::Chip};
+
+/// Print the metadata of an ELF file
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct MetadataArgs {
+    /// ELF image to read the metadata of
+    pub image: PathBuf,
+    /// Fail if the image was not built for this chip
+    #[arg(short = 'c', long)]
+    pub chip: Option<Chip>,
+    /// Print the metadata as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Print the metadata of the ELF file given in `args`
+pub fn metadata(args: MetadataArgs) -> Result<()> {
+    let elf_data = map_file(&args.image)
+        .wrap_err_with(|| format!("Failed to open image {}", args.image.display()))?;
+    let metadata = Metadata::from_bytes(&elf_data)?;
+
+    if args.json {
+        let json = serde_json::to_string_pretty(&metadata).into_diagnostic()?;
+        println!("{json}");
+    } else {
+        print_metadata(&metadata);
+    }
+
+    if let Some(chip) = args.chip {
+        metadata.check_chip(chip)?;
+    }
+
+    Ok(())
+}
+
+fn print_metadata(metadata: &Metadata) {
+    let unknown = "unknown";
+    println!(
+        "Chip:              {}",
+        metadata.chip_name().unwrap_or(unknown)
+    );
+    println!(
+        "Log format:        {}",
+        metadata.log_format().unwrap_or(unknown)
+    );
+    println!("defmt:             {}", metadata.has_defmt());
+
+    if let Some(desc) = metadata.app_descriptor() {
+        println!("Project name:      {}", desc.project_name);
+        println!("Version:           {}", desc.version);
+        println!("Secure version:    {}", desc.secure_version);
+        println!("Build time:        {} {}", desc.date, desc.time);
+        println!("IDF version:       {}", desc.idf_version);
+    }
+
+    let notes = metadata.notes().collect::<Vec<_>>();
+    if !notes.is_empty() {
+        println!("Notes:");
+        for (key, value) in notes {
+            match std::str::from_utf8(value) {
+                Ok(value) => println!("  {key} = {value}"),
+                Err(_) => println!("  {key} = {value:02x?}"),
+            }
+        }
+    }
+}
@@ -13,38 +13,107 @@
 use std::{
     collections::HashMap,
     fs,
-    io::Write,
+    io::{self, Read, Write},
     num::ParseIntError,
+    ops::Range,
     path::{Path, PathBuf},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
 };
 
-use clap::Args;
-use clap_complete::Shell;
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    Args,
+};
+use clap_complete::{engine::ArgValueCandidates, CompleteEnv, Shell};
 use comfy_table::{modifiers, presets::UTF8_FULL, Attribute, Cell, Color, Table};
+use encoding_rs::Encoding;
 use esp_idf_part::{DataType, Partition, PartitionTable};
-use indicatif::{style::ProgressStyle, HumanCount, ProgressBar};
+use indicatif::{style::ProgressStyle, HumanBytes, HumanCount, ProgressBar};
 use log::{debug, info, warn};
+use memmap2::Mmap;
 use miette::{IntoDiagnostic, Result, WrapErr};
-use serialport::{FlowControl, SerialPortType, UsbPortInfo};
+use serialport::{SerialPortType, UsbPortInfo};
+use strum::VariantNames;
 
 use self::{
     config::Config,
-    monitor::{monitor, LogFormat},
-    serial::get_serial_port_info,
+    diagnostics::ErrorContext,
+    monitor::{
+        monitor, parse_encoding,
+        parser::channels::ChannelRoutes,
+        replay,
+        rules::Rule,
+        session::{Recorder, SessionMetadata},
+        sinks::LogFile,
+        LogFormat,
+    },
+    partitions::{edit_partition_table, validate_partition_table, PartitionTableCommand},
+    report::{print_json, OutputFormat, Report},
+    serial::{detect_serial_ports, get_serial_port_info},
 };
 use crate::{
-    connection::reset::{ResetAfterOperation, ResetBeforeOperation},
-    elf::ElfFirmwareImage,
+    connection::{
+        reset::{DownloadModeEntry, ResetAfterOperation, ResetBeforeOperation, ResetStep},
+        ConnectStrategy, Port, PortKind, StubSettle, DEFAULT_WRITE_RETRIES,
+    },
+    digest::DigestAlgorithm,
+    elf::{ElfFirmwareImage, RomSegment, SegmentFilter},
     error::{Error, MissingPartition, MissingPartitionTable},
     flasher::{
-        parse_partition_table, FlashData, FlashFrequency, FlashMode, FlashSettings, FlashSize,
-        Flasher, ProgressCallbacks,
+        deflate::{BlockSize, CompressionLevel},
+        encryption::{FlashEncryption, FlashEncryptionKey},
+        parse_partition_table,
+        sfdp::Sfdp,
+        stubs::FlashStub,
+        ConnectOptions, ExtraAppPartitions, FlashData, FlashFrequency, FlashMode, FlashSettings,
+        FlashSize, Flasher, ProgressCallbacks, SegmentProgress, SpiAttachParams, TransferRate,
+        FLASH_SECTOR_SIZE,
+    },
+    identity::MacAddress,
+    image_format::{
+        metadata::Metadata, signing::SigningKey, uf2::write_uf2, AppImage, ImageFormatKind,
     },
+    output::{self, OutputFile},
     targets::{Chip, XtalFrequency},
 };
 
+/// Print a line of human-readable output, which goes to stderr when the
+/// results are printed as JSON, so that stdout only holds the JSON document
+///
+/// The first argument is the [Report](report::Report) of the command, or its
+/// [OutputFormat].
+macro_rules! outputln {
+    ($report:expr) => {
+        outputln!($report, "")
+    };
+    ($report:expr, $($arg:tt)*) => {
+        if $report.is_json() {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+pub mod artifacts;
+pub mod benchmark;
 pub mod config;
+pub mod diagnostics;
+pub mod efuse;
+pub mod flash_map;
+pub mod image_info;
+pub mod merge;
+pub mod metadata;
 pub mod monitor;
+pub mod nvs;
+pub mod ota;
+pub mod partitions;
+pub mod report;
+pub mod simulate;
+pub mod targets;
+pub mod verify;
 
 mod serial;
 
@@ -61,9 +130,17 @@ pub struct ConnectArgs {
     /// Reset operation to perform before connecting to the target
     #[arg(short = 'b', long, default_value = "default-reset")]
     pub before: ResetBeforeOperation,
-    /// Target device
-    #[arg(short = 'c', long)]
-    pub chip: Option<Chip>,
+    /// How to put the target device into download mode
+    ///
+    /// `usb-touch` opens the port at 1200 baud, which applications using the
+    /// USB OTG peripheral of the ESP32-S2/S3 as a CDC port handle by
+    /// restarting into download mode, so no BOOT button needs to be pressed.
+    #[arg(long, default_value = "reset", value_name = "METHOD")]
+    pub enter_download_mode: DownloadModeEntry,
+    /// Target device, or `none` when flashing a simulated device with
+    /// `--simulate`
+    #[arg(short = 'c', long, value_parser = chip_arg_parser())]
+    pub chip: Option<ChipArg>,
     /// Require confirmation before auto-connecting to a recognized device.
     #[arg(short = 'C', long)]
     pub confirm_port: bool,
@@ -73,9 +150,108 @@ pub struct ConnectArgs {
     /// Do not use the RAM stub for loading
     #[arg(long)]
     pub no_stub: bool,
-    /// Serial port connected to target device
-    #[arg(short = 'p', long, env = "ESPFLASH_PORT")]
+    /// Serial port connected to target device, or `auto:<VID>:<PID>[:<SERIAL>]`
+    /// to select a USB device by its identifiers
+    ///
+    /// Serial ports exposed over the network are given as
+    /// `socket://HOST:PORT` for raw TCP servers, or `rfc2217://HOST:PORT` for
+    /// RFC 2217 servers, which also reset the device.
+    #[arg(
+        short = 'p',
+        long,
+        env = "ESPFLASH_PORT",
+        add = ArgValueCandidates::new(serial::port_candidates)
+    )]
     pub port: Option<String>,
+    /// Divider of the SPI flash clock, for flashing marginal hardware
+    ///
+    /// By default, the clock is lowered automatically when written data fails
+    /// verification. Setting a divider disables this.
+    #[arg(long, value_name = "DIVIDER")]
+    pub flash_clock_div: Option<u32>,
+    /// Pins the SPI flash is connected to: `SPI`, `HSPI`, or its GPIOs as
+    /// `CLK,Q,D,HD,CS`
+    ///
+    /// Only needed for flash on non-default pins; it is an error to use this
+    /// with flash embedded in the chip package.
+    #[arg(long, value_name = "CONNECTION")]
+    pub spi_connection: Option<SpiAttachParams>,
+    /// Number of data blocks to send ahead of their acknowledgement when
+    /// writing flash
+    ///
+    /// Values above 1 improve the throughput on links with a high latency, e.g.
+    /// network serial ports or busy USB hubs. Only applies when using the
+    /// flasher stub; if writing fails, the block is retried one at a time.
+    #[arg(long, value_name = "BLOCKS")]
+    pub data_window: Option<usize>,
+    /// Compression level of the data written to flash: `auto`, or from 0
+    /// (none) to 9 (best)
+    ///
+    /// `auto` compresses with the best level, unless the flasher stub writes
+    /// over native USB or a serial link faster than 460800 baud, where
+    /// compressing less saves more time than sending the larger data costs.
+    #[arg(long, value_name = "LEVEL")]
+    pub compression_level: Option<CompressionLevel>,
+    /// Size of the blocks the data is written to flash in: `auto`, or a number
+    /// of bytes up to 0x4000
+    ///
+    /// `auto` sends larger blocks the faster the link is when using the
+    /// flasher stub, and blocks of the chip's default size to the ROM loader,
+    /// which may reject larger ones.
+    #[arg(long, value_name = "BYTES")]
+    pub flash_block_size: Option<BlockSize>,
+    /// Digest comparing the contents of flash with the data written, when
+    /// skipping unchanged segments and verifying them
+    ///
+    /// MD5 is computed on the device. Other digests are computed from the data
+    /// read back, which is slower but works where the loader lacks the MD5
+    /// command.
+    #[arg(long, value_name = "ALGORITHM", value_enum)]
+    pub verify_digest: Option<DigestAlgorithm>,
+    /// How to let the connection settle once the flasher stub has started:
+    /// `off`, or the delay in milliseconds optionally followed by the number of
+    /// retries, e.g. `100,5`
+    ///
+    /// Defaults to `50,3` on USB-Serial-JTAG ports, whose first commands after
+    /// starting the stub tend to time out, and `off` otherwise.
+    #[arg(long, value_name = "SETTLE")]
+    pub stub_settle: Option<StubSettle>,
+    /// Path to a custom flasher stub (`esptool.py` .json, .toml or stub .elf)
+    #[arg(long, value_name = "FILE", conflicts_with = "no_stub")]
+    pub stub_path: Option<PathBuf>,
+    /// Name of the built-in flasher stub to use, rather than the one selected
+    /// for the chip and its revision
+    #[arg(
+        long,
+        value_name = "NAME",
+        value_parser = stub_name_parser(),
+        conflicts_with_all = ["no_stub", "stub_path"]
+    )]
+    pub stub: Option<String>,
+    /// Number of attempts to reset and connect to the target device
+    #[arg(long, value_name = "ATTEMPTS")]
+    pub connect_attempts: Option<usize>,
+    /// Reset strategies to attempt in order when connecting, e.g.
+    /// `usb-jtag-serial,classic:500`
+    ///
+    /// Each is one of `classic`, `unix-tight`, `usb-jtag-serial` or
+    /// `usb-bridge`, optionally followed by the time in milliseconds to hold
+    /// the boot pin after the reset. Defaults to strategies suiting the serial
+    /// port and operating system.
+    #[arg(long, value_name = "STRATEGIES", value_delimiter = ',')]
+    pub reset_sequence: Option<Vec<ResetStep>>,
+}
+
+/// Print information about a connected target device
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct BoardInfoArgs {
+    /// Also print the parameters the flash chip reports about itself (SFDP)
+    #[arg(long)]
+    pub extended: bool,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
 }
 
 /// Generate completions for the given shell
@@ -136,9 +312,26 @@ pub struct FlashArgs {
     /// Erase specified data partitions
     #[arg(long, value_name = "PARTS", value_enum, value_delimiter = ',')]
     pub erase_data_parts: Option<Vec<DataType>>,
-    /// Logging format.
-    #[arg(long, short = 'L', default_value = "serial", requires = "monitor")]
+    /// Logging format, by default defmt output is detected automatically
+    #[arg(long, short = 'L', default_value = "auto", requires = "monitor")]
     pub log_format: LogFormat,
+    /// Route a channel of the output with '--log-format channels'
+    ///
+    /// Given as CHANNEL=ROUTE, where the route is one of 'auto', 'defmt',
+    /// 'serial', 'file:PATH' to write the data as it is, or 'drop'. May be
+    /// given multiple times, and replaces the route of the channel in the
+    /// configuration file.
+    #[arg(long = "channel", value_name = "CHANNEL=ROUTE", requires = "monitor")]
+    pub channels: Vec<String>,
+    /// Character encoding of the text output by the device, e.g. 'gb2312' or
+    /// 'latin1'
+    #[arg(
+        long,
+        default_value = "utf-8",
+        value_parser = parse_encoding,
+        requires = "monitor"
+    )]
+    pub encoding: &'static Encoding,
     /// Open a serial monitor after flashing
     #[arg(short = 'M', long)]
     pub monitor: bool,
@@ -148,35 +341,198 @@ pub struct FlashArgs {
     /// Load the application to RAM instead of Flash
     #[arg(long)]
     pub ram: bool,
+    /// Only write the application image, never the bootloader or partition
+    /// table
+    ///
+    /// The partition table is still used to find the app partition, but is not
+    /// written, even when one is found alongside the build artifacts.
+    #[arg(long, conflicts_with = "ram")]
+    pub app_only: bool,
+    /// Fail instead of warning when the pre-flight checks find a problem with
+    /// the device, e.g. an unstable power supply
+    #[arg(long, conflicts_with = "ram")]
+    pub preflight: bool,
+    /// Only write the ELF segments built from the given sections, e.g.
+    /// `.text,.rodata`
+    ///
+    /// The application image is still built from all segments, but the flash
+    /// sectors holding only the other segments are not written, leaving their
+    /// previous contents. This speeds up flashing when iterating on code, as
+    /// long as the segments which were left out did not change.
+    #[arg(
+        long,
+        value_name = "SECTIONS",
+        value_delimiter = ',',
+        conflicts_with_all = ["skip_segments", "ram"]
+    )]
+    pub only_segments: Option<Vec<String>>,
+    /// Don't write the ELF segments built from the given sections, e.g.
+    /// `.rtc.data`
+    #[arg(
+        long,
+        value_name = "SECTIONS",
+        value_delimiter = ',',
+        conflicts_with = "ram"
+    )]
+    pub skip_segments: Option<Vec<String>>,
+    /// Flash a simulated device, whose flash contents are kept in FILE,
+    /// instead of a connected one
+    ///
+    /// No device is needed; the image is built for the chip given with
+    /// `--chip`, by default the one the ELF image was built for, as named by
+    /// its metadata. `--chip none` states that no device is connected. An
+    /// existing FILE is used as the initial contents of flash.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["ram", "monitor"]
+    )]
+    pub simulate: Option<PathBuf>,
     /// Don't verify the flash contents after flashing
     #[arg(long)]
     pub no_verify: bool,
     /// Don't skip flashing of parts with matching checksum
     #[arg(long)]
     pub no_skip: bool,
+    /// Number of times writing flash is resumed after a transient error, e.g.
+    /// a timeout, before giving up
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_WRITE_RETRIES)]
+    pub max_retries: u32,
     #[clap(flatten)]
     pub image: ImageArgs,
+    #[clap(flatten)]
+    pub encryption: EncryptionArgs,
     /// External log processors to use (comma separated executables)
     #[arg(long, requires = "monitor")]
     pub processors: Option<String>,
+    /// Take an action when a line of output matches a pattern
+    ///
+    /// The action is one of 'run:COMMAND' to run a command on the host (the
+    /// matching line is passed in the ESPFLASH_MATCH environment variable),
+    /// 'mark' to insert a marker line, or 'reset' to reset the device. May be
+    /// given multiple times.
+    #[arg(
+        long = "on",
+        num_args = 2,
+        value_names = ["PATTERN", "ACTION"],
+        requires = "monitor"
+    )]
+    pub monitor_rules: Vec<String>,
+    /// Record the output of the device to FILE, to replay it with `espflash
+    /// monitor --replay`
+    #[arg(long, value_name = "FILE", requires = "monitor")]
+    pub record: Option<PathBuf>,
+    /// Write the output of the device to FILE as well, without colors
+    #[arg(long, value_name = "FILE", requires = "monitor")]
+    pub log_file: Option<PathBuf>,
+    /// Start a new log file once it reaches BYTES, keeping the previous ones as
+    /// FILE.1, FILE.2 and so on
+    #[arg(long, value_name = "BYTES", requires = "log_file", value_parser = parse_u32)]
+    pub log_file_max_size: Option<u32>,
+    /// Number of previous log files to keep with `--log-file-max-size`
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = 5,
+        requires = "log_file_max_size"
+    )]
+    pub log_file_count: usize,
+    /// Start each line of output with the time it was received at
+    #[arg(long, requires = "monitor")]
+    pub timestamps: bool,
+}
+
+/// Flash encryption arguments, for devices with flash encryption enabled
+#[derive(Debug, Args)]
+#[non_exhaustive]
+#[group(skip)]
+pub struct EncryptionArgs {
+    /// Have the device encrypt the data with its flash encryption key as it
+    /// is written
+    ///
+    /// The flash contents can then not be verified, and unchanged regions are
+    /// not skipped.
+    #[arg(long)]
+    pub encrypt: bool,
+    /// Encrypt the data on the host with the flash encryption key in FILE
+    /// before writing it
+    ///
+    /// The key is the raw binary key burned to the eFuses of the device, as
+    /// generated by `espsecure.py generate_flash_encryption_key`. Not
+    /// supported for the ESP32.
+    #[arg(long, value_name = "FILE", conflicts_with = "encrypt")]
+    pub encryption_key: Option<PathBuf>,
+}
+
+impl EncryptionArgs {
+    /// The encryption of the data written to flash
+    pub fn encryption(&self) -> Result<Option<FlashEncryption>> {
+        if let Some(path) = &self.encryption_key {
+            let key = FlashEncryptionKey::load(path)?;
+            return Ok(Some(FlashEncryption::Host(key)));
+        }
+
+        Ok(self.encrypt.then_some(FlashEncryption::Device))
+    }
+}
+
+impl FlashArgs {
+    /// The segments of the ELF file selected with `--only-segments` or
+    /// `--skip-segments`
+    pub fn segment_filter(&self) -> SegmentFilter {
+        match (&self.only_segments, &self.skip_segments) {
+            (Some(names), _) => SegmentFilter::Only(names.clone()),
+            (None, Some(names)) => SegmentFilter::Skip(names.clone()),
+            (None, None) => SegmentFilter::All,
+        }
+    }
 }
 
 /// Operations for partitions tables
 #[derive(Debug, Args)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 #[non_exhaustive]
 pub struct PartitionTableArgs {
+    /// Edit the partition table instead
+    #[command(subcommand)]
+    command: Option<PartitionTableCommand>,
     /// Optional output file name, if unset will output to stdout
     #[arg(short = 'o', long, value_name = "FILE")]
     output: Option<PathBuf>,
     /// Input partition table
-    #[arg(value_name = "FILE")]
-    partition_table: PathBuf,
+    #[arg(value_name = "FILE", required_unless_present = "from_device")]
+    partition_table: Option<PathBuf>,
+    /// Read the partition table from the connected device instead
+    #[arg(long, conflicts_with = "partition_table")]
+    from_device: bool,
+    /// Offset of the partition table on the device
+    ///
+    /// By default, the commonly used offsets and the sector following the
+    /// bootloader are searched for the partition table.
+    #[arg(
+        long,
+        value_name = "OFFSET",
+        value_parser = parse_uint32,
+        conflicts_with = "partition_table"
+    )]
+    partition_table_offset: Option<u32>,
+    /// Connection configuration, used with `--from-device`
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
     /// Convert CSV partition table to binary representation
-    #[arg(long, conflicts_with = "to_csv")]
+    #[arg(long, conflicts_with_all = ["to_csv", "validate"])]
     to_binary: bool,
     /// Convert binary partition table to CSV representation
-    #[arg(long, conflicts_with = "to_binary")]
+    #[arg(long, conflicts_with_all = ["to_binary", "validate"])]
     to_csv: bool,
+    /// Only check the partition table for overlapping, misaligned and
+    /// duplicate partitions, and that it fits into the flash with
+    /// `--flash-size`
+    #[arg(long)]
+    validate: bool,
+    /// Size of the flash which the partition table has to fit into
+    #[arg(long, value_name = "SIZE", value_enum)]
+    flash_size: Option<FlashSize>,
 }
 
 /// Reads the content of flash memory and saves it to a file
@@ -184,8 +540,12 @@ pub struct PartitionTableArgs {
 #[non_exhaustive]
 pub struct ReadFlashArgs {
     /// Offset to start reading from
-    #[arg(value_name = "OFFSET", value_parser = parse_uint32)]
-    pub addr: u32,
+    #[arg(
+        value_name = "OFFSET",
+        value_parser = parse_uint32,
+        required_unless_present = "partition"
+    )]
+    pub addr: Option<u32>,
     /// Size of each individual packet of data
     ///
     /// Defaults to 0x1000 (FLASH_SECTOR_SIZE)
@@ -195,14 +555,49 @@ pub struct ReadFlashArgs {
     #[clap(flatten)]
     connect_args: ConnectArgs,
     /// Size of the region to read
-    #[arg(value_name = "SIZE", value_parser = parse_uint32)]
-    pub size: u32,
+    #[arg(
+        value_name = "SIZE",
+        value_parser = parse_uint32,
+        required_unless_present = "partition"
+    )]
+    pub size: Option<u32>,
     /// Name of binary dump
-    #[arg(value_name = "FILE")]
-    pub file: PathBuf,
+    #[arg(
+        value_name = "FILE",
+        required_unless_present = "follow",
+        conflicts_with = "follow"
+    )]
+    pub file: Option<PathBuf>,
     /// Maximum number of un-acked packets
     #[arg(long, default_value = "64", value_parser = parse_uint32)]
     pub max_in_flight: u32,
+    /// Keep polling the region and print new content as it is written, like
+    /// `tail -f`
+    #[arg(long)]
+    pub follow: bool,
+    /// Label of the partition to follow, instead of an offset and size
+    #[arg(long, value_name = "LABEL", requires = "follow", conflicts_with_all = ["addr", "size"])]
+    pub partition: Option<String>,
+    /// Offset of the partition table on the device
+    ///
+    /// By default, the commonly used offsets and the sector following the
+    /// bootloader are searched for the partition table.
+    #[arg(long, value_name = "OFFSET", value_parser = parse_uint32, requires = "partition")]
+    pub partition_table_offset: Option<u32>,
+    /// Interval between polls of the flash region, in milliseconds
+    #[arg(long, value_name = "MS", default_value = "1000", requires = "follow")]
+    pub poll_interval: u64,
+}
+
+/// Format of an image saved to disk
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[non_exhaustive]
+pub enum SaveImageFormat {
+    /// Raw binary images
+    #[default]
+    Bin,
+    /// UF2, for dropping onto the USB drive of the TinyUF2 bootloader
+    Uf2,
 }
 
 /// Save the image to disk instead of flashing to device
@@ -210,9 +605,10 @@ pub struct ReadFlashArgs {
 #[non_exhaustive]
 #[group(skip)]
 pub struct SaveImageArgs {
-    /// Chip to create an image for
+    /// Chip to create an image for, by default the one the ELF image was
+    /// built for, as named by its metadata
     #[arg(long, value_enum)]
-    pub chip: Chip,
+    pub chip: Option<Chip>,
     /// File name to save the generated image to
     pub file: PathBuf,
     /// Boolean flag to merge binaries into single binary
@@ -224,29 +620,98 @@ pub struct SaveImageArgs {
     /// Cristal frequency of the target
     #[arg(long, short = 'x')]
     pub xtal_freq: Option<XtalFrequency>,
+    /// Format of the saved image
+    ///
+    /// UF2 files contain only the application image, addressed relative to
+    /// the app partition, as expected by the TinyUF2 bootloader.
+    #[arg(long, value_enum, default_value_t, conflicts_with_all = ["merge", "web_flasher"])]
+    pub format: SaveImageFormat,
+    /// Save the images to the directory FILE, with a manifest.json for ESP Web
+    /// Tools
+    ///
+    /// Combined with `--merge`, a single merged image is saved instead.
+    /// Merged images are never padded to the flash size.
+    #[arg(long)]
+    pub web_flasher: bool,
     #[clap(flatten)]
     pub image: ImageArgs,
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Clone, Args)]
 #[non_exhaustive]
 #[group(skip)]
 pub struct ImageArgs {
     /// Path to a binary (.bin) bootloader file
     #[arg(long, value_name = "FILE")]
     pub bootloader: Option<PathBuf>,
+    /// Write the bootloader even if it was built for a different chip or flash
+    /// mode
+    #[arg(long)]
+    pub force: bool,
     /// Path to a CSV file containing partition table
     #[arg(long, short = 'T', value_name = "FILE")]
     pub partition_table: Option<PathBuf>,
     /// Partition table offset
     #[arg(long, value_name = "OFFSET", value_parser = parse_uint32)]
     pub partition_table_offset: Option<u32>,
+    /// Offset to write the bootloader at, instead of the default of the chip
+    #[arg(long, value_name = "OFFSET", value_parser = parse_uint32)]
+    pub bootloader_offset: Option<u32>,
+    /// Offset to write the application at, instead of the offset of the target
+    /// app partition
+    ///
+    /// E.g. for a custom bootloader which boots the application from a fixed
+    /// offset. The offset must be within an app partition or outside of all
+    /// partitions.
+    #[arg(
+        long,
+        value_name = "OFFSET",
+        value_parser = parse_uint32,
+        conflicts_with = "target_app_partition"
+    )]
+    pub app_offset: Option<u32>,
     /// Label of target app partition
     #[arg(long, value_name = "LABEL")]
     pub target_app_partition: Option<String>,
+    /// Labels of further app partitions to write a copy of the application to
+    ///
+    /// E.g. writing to both 'factory' and 'ota_0' provides a fallback image
+    /// before the first over-the-air update.
+    #[arg(
+        long,
+        value_name = "LABELS",
+        value_delimiter = ',',
+        conflicts_with = "all_app_partitions"
+    )]
+    pub extra_app_partitions: Vec<String>,
+    /// Write a copy of the application to every app partition
+    #[arg(long)]
+    pub all_app_partitions: bool,
     /// Minimum chip revision supported by image, in format: major.minor
     #[arg(long, default_value = "0.0", value_parser = parse_chip_rev)]
     pub min_chip_rev: u16,
+    /// MMU page size to align the application image to (e.g. 0x8000)
+    ///
+    /// Only configurable on chips which support multiple page sizes, defaults
+    /// to 64KB.
+    #[arg(long, value_name = "SIZE", value_parser = parse_uint32)]
+    pub mmu_page_size: Option<u32>,
+    /// Sign the application image for Secure Boot V2 with the RSA 3072 or
+    /// ECDSA P-256 private key in the PEM file FILE
+    #[arg(long, value_name = "FILE")]
+    pub signing_key: Option<PathBuf>,
+    /// Format of the image
+    ///
+    /// 'direct-boot' writes the image without a bootloader or partition
+    /// table, to be booted directly by the ROM of RISC-V chips which support
+    /// it, e.g. the ESP32-C3.
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        conflicts_with_all = ["bootloader", "partition_table", "signing_key"]
+    )]
+    pub image_format: ImageFormatKind,
 }
 
 /// Open the serial monitor without flashing
@@ -262,19 +727,135 @@ pub struct MonitorArgs {
     /// Avoids asking the user for interactions like resetting the device
     #[arg(long)]
     non_interactive: bool,
-    /// Logging format.
-    #[arg(long, short = 'L', default_value = "serial", requires = "elf")]
+    /// Logging format, by default defmt output is detected automatically
+    ///
+    /// Decoding defmt output requires the ELF file.
+    #[arg(long, short = 'L', default_value = "auto")]
     pub log_format: LogFormat,
+    /// Route a channel of the output with '--log-format channels'
+    ///
+    /// Given as CHANNEL=ROUTE, where the route is one of 'auto', 'defmt',
+    /// 'serial', 'file:PATH' to write the data as it is, or 'drop'. May be
+    /// given multiple times, and replaces the route of the channel in the
+    /// configuration file.
+    #[arg(long = "channel", value_name = "CHANNEL=ROUTE")]
+    pub channels: Vec<String>,
+    /// Character encoding of the text output by the device, e.g. 'gb2312' or
+    /// 'latin1'
+    #[arg(long, default_value = "utf-8", value_parser = parse_encoding)]
+    pub encoding: &'static Encoding,
     /// External log processors to use (comma separated executables)
     #[arg(long)]
     processors: Option<String>,
+    /// Take an action when a line of output matches a pattern
+    ///
+    /// The action is one of 'run:COMMAND' to run a command on the host (the
+    /// matching line is passed in the ESPFLASH_MATCH environment variable),
+    /// 'mark' to insert a marker line, or 'reset' to reset the device. May be
+    /// given multiple times.
+    #[arg(long = "on", num_args = 2, value_names = ["PATTERN", "ACTION"])]
+    monitor_rules: Vec<String>,
+    /// Record the output of the device to FILE, to replay it with `--replay`
+    #[arg(long, value_name = "FILE", conflicts_with = "replay")]
+    record: Option<PathBuf>,
+    /// Write the output of the device to FILE as well, without colors
+    #[arg(long, value_name = "FILE", conflicts_with = "replay")]
+    log_file: Option<PathBuf>,
+    /// Start a new log file once it reaches BYTES, keeping the previous ones as
+    /// FILE.1, FILE.2 and so on
+    #[arg(long, value_name = "BYTES", requires = "log_file", value_parser = parse_u32)]
+    log_file_max_size: Option<u32>,
+    /// Number of previous log files to keep with `--log-file-max-size`
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = 5,
+        requires = "log_file_max_size"
+    )]
+    log_file_count: usize,
+    /// Start each line of output with the time it was received at
+    #[arg(long, conflicts_with = "replay")]
+    timestamps: bool,
+    /// Replay a session recorded with `--record` instead of connecting to a
+    /// device
+    ///
+    /// The output is decoded as by the live monitor, so e.g. a different ELF
+    /// file or log format can be used.
+    #[arg(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+    /// Speed of the replay as a multiple of the original speed, at least 0.01,
+    /// or 0 to replay without delays
+    #[arg(
+        long,
+        value_name = "FACTOR",
+        default_value = "1",
+        requires = "replay",
+        value_parser = parse_replay_speed
+    )]
+    replay_speed: f64,
+}
+
+/// List the serial ports to which a device may be connected
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct ListPortsArgs {
+    /// List all available ports.
+    #[arg(long)]
+    pub list_all_ports: bool,
+}
+
+/// Read a word of memory, e.g. a peripheral register
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct ReadMemArgs {
+    /// Address of the word to read
+    #[arg(value_parser = parse_uint32)]
+    addr: u32,
+    /// Connection configuration
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+}
+
+/// Write a word of memory, e.g. a peripheral register
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct WriteMemArgs {
+    /// Address of the word to write
+    #[arg(value_parser = parse_uint32)]
+    addr: u32,
+    /// Value to write
+    #[arg(value_parser = parse_uint32)]
+    value: u32,
+    /// Only write the bits set in the mask, leaving the others as they are
+    #[arg(long, value_parser = parse_uint32)]
+    mask: Option<u32>,
+    /// Connection configuration
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+}
+
+/// Save a region of memory, e.g. IRAM or DRAM, to a file
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct DumpMemArgs {
+    /// Address to start reading from
+    #[arg(value_parser = parse_uint32)]
+    addr: u32,
+    /// Number of bytes to read
+    #[arg(value_parser = parse_uint32)]
+    size: u32,
+    /// File to write the memory contents to
+    file: PathBuf,
+    /// Connection configuration
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
 }
 
 #[derive(Debug, Args)]
 #[non_exhaustive]
 pub struct ChecksumMd5Args {
     /// Start address
-    #[clap(short, long, value_parser=parse_u32)]
+    #[clap(long, value_parser=parse_u32)]
     address: u32,
     /// Length
     #[clap(short, long, value_parser=parse_u32)]
@@ -284,6 +865,92 @@ pub struct ChecksumMd5Args {
     connect_args: ConnectArgs,
 }
 
+/// Run a command on the SPI flash chip of a target device
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct SpiCommandArgs {
+    /// Opcode of the command, e.g. `0x9f`
+    #[arg(value_parser = parse_int::parse::<u8>)]
+    opcode: u8,
+    /// Data to send after the opcode as hexadecimal bytes, e.g. `000100` for a
+    /// 24-bit address of 0x100
+    #[arg(short, long, value_name = "HEX")]
+    data: Option<String>,
+    /// Number of bits of the response to read, at most 32
+    #[arg(short, long, value_name = "BITS", default_value_t = 0)]
+    read_bits: u32,
+    /// Send a write enable command first, as commands which modify the flash
+    /// or its status registers require
+    #[arg(long)]
+    write_enable: bool,
+    /// Wait for the flash chip to finish writing or erasing afterwards
+    #[arg(long)]
+    wait: bool,
+    /// Connection configuration
+    #[clap(flatten)]
+    connect_args: ConnectArgs,
+}
+
+impl ConnectArgs {
+    /// The chip selected with `--chip`, unless it is `none`
+    pub fn chip(&self) -> Option<Chip> {
+        self.chip.and_then(ChipArg::chip)
+    }
+}
+
+/// Value of the `--chip` option
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChipArg {
+    /// No device is connected, which is only valid with `--simulate`
+    None,
+    /// The chip of the target device
+    Chip(Chip),
+}
+
+impl ChipArg {
+    /// The selected chip, if there is one
+    pub fn chip(self) -> Option<Chip> {
+        match self {
+            ChipArg::None => None,
+            ChipArg::Chip(chip) => Some(chip),
+        }
+    }
+}
+
+impl FromStr for ChipArg {
+    type Err = strum::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ChipArg::None),
+            chip => chip.parse().map(ChipArg::Chip),
+        }
+    }
+}
+
+/// Parser of the `--chip` option, which accepts the names of the chips and
+/// `none`
+fn chip_arg_parser() -> impl TypedValueParser<Value = ChipArg> {
+    PossibleValuesParser::new(Chip::VARIANTS.iter().copied().chain(["none"]))
+        .map(|name| name.parse().unwrap())
+}
+
+fn stub_name_parser() -> impl TypedValueParser<Value = String> {
+    PossibleValuesParser::new(FlashStub::names())
+}
+
+/// Slowest speed a session can be replayed at, as a multiple of the original
+const MIN_REPLAY_SPEED: f64 = 0.01;
+
+fn parse_replay_speed(input: &str) -> Result<f64, String> {
+    let speed: f64 = input.parse().map_err(|e| format!("{e}"))?;
+    if speed == 0.0 || (speed.is_finite() && speed >= MIN_REPLAY_SPEED) {
+        Ok(speed)
+    } else {
+        Err(format!("must be 0, or at least {MIN_REPLAY_SPEED}"))
+    }
+}
+
 pub fn parse_u32(input: &str) -> Result<u32, ParseIntError> {
     parse_int::parse(input)
 }
@@ -295,6 +962,10 @@ pub fn connect(
     no_verify: bool,
     no_skip: bool,
 ) -> Result<Flasher> {
+    if args.chip == Some(ChipArg::None) {
+        return Err(Error::NoChip.into());
+    }
+
     if args.before == ResetBeforeOperation::NoReset
         || args.before == ResetBeforeOperation::NoResetNoSync
     {
@@ -304,24 +975,28 @@ pub fn connect(
         );
     }
 
-    let port_info = get_serial_port_info(args, config)?;
+    let mut port_info = get_serial_port_info(args, config)?;
+    let mut before = args.before;
+    if args.enter_download_mode == DownloadModeEntry::UsbTouch {
+        port_info = serial::usb_touch_port(port_info)?;
+        // The device is already waiting in the ROM bootloader
+        before = ResetBeforeOperation::NoReset;
+    }
 
     // Attempt to open the serial port and set its initial baud rate.
     info!("Serial port: '{}'", port_info.port_name);
     info!("Connecting...");
 
-    let serial_port = serialport::new(&port_info.port_name, 115_200)
-        .flow_control(FlowControl::None)
-        .open_native()
-        .map_err(Error::from)
+    let serial_port = Port::open(&port_info.port_name, 115_200)
         .wrap_err_with(|| format!("Failed to open serial port {}", port_info.port_name))?;
 
-    // NOTE: since `get_serial_port_info` filters out all PCI Port and Bluetooth
-    //       serial ports, we can just pretend these types don't exist here.
+    // Ports which are not USB devices, e.g. native or PCI UARTs and Bluetooth
+    // serial ports, cannot use any USB-specific reset logic.
+    let port_name = port_info.port_name;
     let port_info = match port_info.port_type {
         SerialPortType::UsbPort(info) => info,
-        SerialPortType::PciPort | SerialPortType::Unknown => {
-            debug!("Matched `SerialPortType::PciPort or ::Unknown`");
+        port_type => {
+            debug!("Port is not a USB device: {port_type:?}");
             UsbPortInfo {
                 vid: 0,
                 pid: 0,
@@ -330,36 +1005,281 @@ pub fn connect(
                 product: None,
             }
         }
-        _ => unreachable!(),
     };
+    let before = if before == ResetBeforeOperation::UsbReset && port_info.vid == 0 {
+        warn!(
+            "{} is not a USB device, using the default reset instead of the USB reset",
+            port_name
+        );
+        ResetBeforeOperation::DefaultReset
+    } else {
+        before
+    };
+
+    let mut context = ErrorContext {
+        chip: args.chip(),
+        usb_ids: (port_info.vid != 0).then_some((port_info.vid, port_info.pid)),
+        stub: !args.no_stub,
+    };
+    diagnostics::set_context(context);
 
-    Ok(Flasher::connect(
-        *Box::new(serial_port),
-        port_info,
-        args.baud.or(config.baudrate),
-        !args.no_stub,
-        !no_verify,
-        !no_skip,
-        args.chip,
-        args.after,
-        args.before,
-    )?)
+    let stub = match (&args.stub_path, &args.stub) {
+        (Some(path), _) => Some(FlashStub::load(path)?),
+        (None, Some(name)) => Some(FlashStub::named(name)?),
+        (None, None) => None,
+    };
+
+    let mut connect_strategy = ConnectStrategy::default();
+    if let Some(attempts) = args.connect_attempts.or(config.connection.connect_attempts) {
+        connect_strategy = connect_strategy.with_attempts(attempts);
+    }
+    if let Some(steps) = args
+        .reset_sequence
+        .clone()
+        .or_else(|| config.connection.reset_sequence.clone())
+    {
+        connect_strategy = connect_strategy.with_reset_sequence(steps);
+    }
+    if let (None, Some(custom)) = (
+        &args.reset_sequence,
+        &config.connection.custom_reset_sequence,
+    ) {
+        connect_strategy = connect_strategy.with_custom_reset(custom.clone());
+    }
+    if let Some(hook) = &config.connection.reset_hook {
+        connect_strategy = connect_strategy.with_reset_hook(hook.clone());
+    }
+
+    let options = ConnectOptions {
+        speed: args.baud.or(config.baudrate),
+        use_stub: !args.no_stub,
+        stub,
+        verify: !no_verify,
+        skip: !no_skip,
+        chip: args.chip(),
+        target: None,
+        after_operation: args.after,
+        before_operation: before,
+        stub_settle: args.stub_settle,
+        connect_strategy,
+    };
+    let mut flasher = Flasher::connect(serial_port, port_info, options)?;
+
+    context.chip = Some(flasher.chip());
+    diagnostics::set_context(context);
+
+    if !config.device_aliases.is_empty() {
+        let mac = flasher
+            .chip()
+            .into_target()
+            .mac_address(flasher.connection())?;
+        let name = config.device_aliases.name(&MacAddress::from_str(&mac)?);
+        info!("Device {mac} is '{name}'");
+        flasher.set_device_name(Some(name));
+    }
+
+    if let Some(digest) = args.verify_digest {
+        flasher.set_digest(digest);
+    }
+
+    if let Some(spi_params) = args.spi_connection {
+        flasher.set_spi_connection(spi_params)?;
+    }
+
+    if let Some(divider) = args.flash_clock_div {
+        flasher.set_spi_clock_divider(divider)?;
+    }
+
+    if let Some(window) = args.data_window {
+        flasher.connection().set_data_window(window);
+    }
+
+    if let Some(level) = args.compression_level {
+        flasher.connection().set_compression_level(level);
+    }
+
+    if let Some(size) = args.flash_block_size {
+        flasher.connection().set_block_size(size);
+    }
+
+    Ok(flasher)
 }
 
 /// Connect to a target device and print information about its chip
-pub fn board_info(args: &ConnectArgs, config: &Config) -> Result<()> {
-    let mut flasher = connect(args, config, true, true)?;
-    print_board_info(&mut flasher)?;
+pub fn board_info(args: &BoardInfoArgs, config: &Config, report: &mut Report) -> Result<()> {
+    let mut flasher = connect(&args.connect_args, config, true, true)?;
+    print_board_info(&mut flasher, report)?;
+
+    if args.extended {
+        print_sfdp(flasher.sfdp(), report);
+    }
+    report.print_device()?;
+
+    Ok(())
+}
+
+/// List the serial ports which would be offered to connect to
+pub fn list_ports(args: &ListPortsArgs, config: &Config, report: &Report) -> Result<()> {
+    let ports = detect_serial_ports(args.list_all_ports, config);
+
+    if report.is_json() {
+        let ports = ports
+            .iter()
+            .map(|port| match &port.port_type {
+                SerialPortType::UsbPort(info) => serde_json::json!({
+                    "port": port.port_name,
+                    "type": "usb",
+                    "kind": PortKind::from_usb_ids(info.vid, info.pid).to_string(),
+                    "jtag": PortKind::from_usb_ids(info.vid, info.pid).has_jtag(),
+                    "vid": format!("{:04x}", info.vid),
+                    "pid": format!("{:04x}", info.pid),
+                    "serial_number": info.serial_number,
+                    "manufacturer": info.manufacturer,
+                    "product": info.product,
+                }),
+                other => serde_json::json!({
+                    "port": port.port_name,
+                    "type": match other {
+                        SerialPortType::PciPort => "pci",
+                        SerialPortType::BluetoothPort => "bluetooth",
+                        _ => "unknown",
+                    },
+                }),
+            })
+            .collect::<Vec<_>>();
+
+        return print_json(&ports);
+    }
+
+    for port in ports {
+        match port.port_type {
+            SerialPortType::UsbPort(info) => println!(
+                "{}  {:04x}:{:04x}  {:<15}  {}",
+                port.port_name,
+                info.vid,
+                info.pid,
+                PortKind::from_usb_ids(info.vid, info.pid).to_string(),
+                [info.manufacturer, info.product, info.serial_number]
+                    .into_iter()
+                    .flatten()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            _ => println!("{}", port.port_name),
+        }
+    }
 
     Ok(())
 }
 
 /// Connect to a target device and calculate the checksum of the given region
-pub fn checksum_md5(args: &ChecksumMd5Args, config: &Config) -> Result<()> {
+pub fn checksum_md5(args: &ChecksumMd5Args, config: &Config, report: &Report) -> Result<()> {
     let mut flasher = connect(&args.connect_args, config, true, true)?;
 
     let checksum = flasher.checksum_md5(args.address, args.length)?;
-    println!("0x{:x}", checksum);
+    if report.is_json() {
+        print_json(&serde_json::json!({
+            "address": args.address,
+            "length": args.length,
+            "md5": format!("{checksum:032x}"),
+        }))?;
+    } else {
+        println!("0x{:x}", checksum);
+    }
+
+    Ok(())
+}
+
+/// Connect to a target device and print a word of its memory
+pub fn read_mem(args: ReadMemArgs, config: &Config) -> Result<()> {
+    let mut flasher = connect_without_stub(args.connect_args, config)?;
+
+    let value = flasher.connection().read_reg(args.addr)?;
+    println!("{:#010x} = {value:#010x}", args.addr);
+
+    Ok(())
+}
+
+/// Connect to a target device and write a word of its memory
+pub fn write_mem(args: WriteMemArgs, config: &Config) -> Result<()> {
+    let mut flasher = connect_without_stub(args.connect_args, config)?;
+    let connection = flasher.connection();
+
+    match args.mask {
+        Some(mask) => {
+            let value = connection.update_reg(args.addr, mask, args.value)?;
+            println!(
+                "Wrote {:#010x} with mask {mask:#010x} to {:#010x}, now {value:#010x}",
+                args.value, args.addr
+            );
+        }
+        None => {
+            connection.write_reg(args.addr, args.value, None)?;
+            println!("Wrote {:#010x} to {:#010x}", args.value, args.addr);
+        }
+    }
+
+    Ok(())
+}
+
+/// Connect to a target device and save a region of its memory to a file
+pub fn dump_mem(args: DumpMemArgs, config: &Config) -> Result<()> {
+    let mut flasher = connect_without_stub(args.connect_args, config)?;
+
+    let data = flasher.read_mem(
+        args.addr,
+        args.size,
+        Some(&mut EspflashProgress::for_device(&flasher)),
+    )?;
+
+    let mut file = OutputFile::create(&args.file)?;
+    file.write_all(&data).into_diagnostic()?;
+    file.persist()?;
+
+    info!(
+        "Saved {} bytes from {:#x} to {}",
+        data.len(),
+        args.addr,
+        args.file.display()
+    );
+
+    Ok(())
+}
+
+/// Connect without loading the flasher stub, which would overwrite parts of
+/// the RAM being inspected
+fn connect_without_stub(mut args: ConnectArgs, config: &Config) -> Result<Flasher> {
+    args.no_stub = true;
+    connect(&args, config, true, true)
+}
+
+/// Connect to a target device and run a command on its SPI flash chip, printing
+/// the response
+pub fn spi_command(args: &SpiCommandArgs, config: &Config) -> Result<()> {
+    let data = match &args.data {
+        Some(data) => hex::decode(data.trim_start_matches("0x"))
+            .into_diagnostic()
+            .wrap_err("Invalid command data")?,
+        None => Vec::new(),
+    };
+
+    let mut flasher = connect(&args.connect_args, config, true, true)?;
+
+    const WRITE_ENABLE: u8 = 0x06;
+    if args.write_enable {
+        flasher.spi_command(WRITE_ENABLE, &[], 0)?;
+    }
+
+    let response = flasher.spi_command(args.opcode, &data, args.read_bits)?;
+
+    if args.wait {
+        flasher.wait_flash_idle()?;
+    }
+
+    if args.read_bits > 0 {
+        let width = args.read_bits.div_ceil(4) as usize;
+        println!("0x{response:0width$x}");
+    }
 
     Ok(())
 }
@@ -371,6 +1291,15 @@ pub fn completions(args: &CompletionsArgs, app: &mut clap::Command, bin_name: &s
     Ok(())
 }
 
+/// Complete the command line for the shell given by the `COMPLETE` environment
+/// variable and exit, if it is set
+///
+/// Unlike [completions], this completes values which depend on the host, like
+/// the available serial ports.
+pub fn complete_from_env(command: fn() -> clap::Command) {
+    CompleteEnv::with_factory(command).complete();
+}
+
 /// Parses chip revision from string to major * 100 + minor format
 pub fn parse_chip_rev(chip_rev: &str) -> Result<u16> {
     let mut split = chip_rev.split('.');
@@ -402,37 +1331,138 @@ pub fn parse_chip_rev(chip_rev: &str) -> Result<u16> {
 }
 
 /// Print information about a chip
-pub fn print_board_info(flasher: &mut Flasher) -> Result<()> {
+pub fn print_board_info(flasher: &mut Flasher, report: &mut Report) -> Result<()> {
     let info = flasher.device_info()?;
+    report.record_device(&info);
+
+    let revision = info
+        .revision
+        .map(|(major, minor)| format!(" (revision v{major}.{minor})"))
+        .unwrap_or_default();
+    outputln!(report, "Chip type:         {}{revision}", info.chip);
+    outputln!(report, "Crystal frequency: {}", info.crystal_frequency);
+    let location = match info.embedded_flash {
+        Some(true) => " (embedded)",
+        Some(false) => " (external)",
+        None => "",
+    };
+    outputln!(report, "Flash size:        {}{location}", info.flash_size);
+    if let Some(flash_chip) = &info.flash_chip {
+        outputln!(report, "Flash chip:        {flash_chip}");
+    }
+    outputln!(report, "Features:          {}", info.features.join(", "));
+    outputln!(report, "MAC address:       {}", info.mac_address);
 
-    print!("Chip type:         {}", info.chip);
-    if let Some((major, minor)) = info.revision {
-        println!(" (revision v{major}.{minor})");
+    Ok(())
+}
+
+/// Check the device for conditions which make flashing likely to fail
+///
+/// Problems are errors if `mandatory` is set, and warnings otherwise.
+pub fn preflight_checks(flasher: &mut Flasher, mandatory: bool, report: &Report) -> Result<()> {
+    let preflight = match flasher.preflight() {
+        Ok(preflight) => preflight,
+        Err(e) if !mandatory => {
+            debug!("Failed to run the pre-flight checks: {e}");
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let problems = preflight.problems();
+    let unknown = preflight.unknown();
+    if !mandatory {
+        for problem in problems {
+            warn!("{problem}");
+        }
+        if !unknown.is_empty() {
+            debug!("Cannot read the {} of the device", unknown.join(" or the "));
+        }
+    } else if problems.is_empty() && unknown.is_empty() {
+        outputln!(report, "Pre-flight checks: passed");
+    } else if problems.is_empty() {
+        outputln!(
+            report,
+            "Pre-flight checks: unknown, the {} cannot be read on this chip",
+            unknown.join(" and the ")
+        );
     } else {
-        println!();
+        return Err(Error::PreflightFailed(problems.join("; ")).into());
     }
-    println!("Crystal frequency: {}", info.crystal_frequency);
-    println!("Flash size:        {}", info.flash_size);
-    println!("Features:          {}", info.features.join(", "));
-    println!("MAC address:       {}", info.mac_address);
 
     Ok(())
 }
 
+/// Print the Serial Flash Discoverable Parameters of the flash chip
+fn print_sfdp(sfdp: Option<&Sfdp>, report: &Report) {
+    let Some(sfdp) = sfdp else {
+        outputln!(report, "SFDP:              not supported by the flash chip");
+        return;
+    };
+
+    let (major, minor) = sfdp.revision;
+    outputln!(report, "SFDP revision:     v{major}.{minor}");
+    outputln!(report, "Flash density:     {}", HumanBytes(sfdp.basic.size));
+    outputln!(report, "Addressing:        {}", sfdp.basic.address_bytes);
+    if let Some(page_size) = sfdp.basic.page_size {
+        outputln!(
+            report,
+            "Page size:         {}",
+            HumanBytes(page_size.into())
+        );
+    }
+    let erase_types = sfdp
+        .basic
+        .erase_types
+        .iter()
+        .map(|erase| format!("{} (0x{:02x})", HumanBytes(erase.size.into()), erase.opcode))
+        .collect::<Vec<_>>();
+    outputln!(report, "Erase commands:    {}", erase_types.join(", "));
+
+    let tables = sfdp
+        .parameter_headers
+        .iter()
+        .map(|header| {
+            let (major, minor) = header.revision;
+            format!(
+                "0x{:04x} v{major}.{minor} ({} DWORDs at 0x{:x})",
+                header.id, header.length, header.pointer
+            )
+        })
+        .collect::<Vec<_>>();
+    outputln!(report, "Parameter tables:  {}", tables.join(", "));
+}
+
 /// Open a serial monitor
 pub fn serial_monitor(args: MonitorArgs, config: &Config) -> Result<()> {
-    let mut flasher = connect(&args.connect_args, config, true, true)?;
-    let pid = flasher.get_usb_pid()?;
+    let rules = Rule::parse_all(&args.monitor_rules)?;
+    let channels = ChannelRoutes::parse_all(config.monitor.channels.iter().chain(&args.channels))?;
 
     let elf = if let Some(elf_path) = args.elf.clone() {
         let path = fs::canonicalize(elf_path).into_diagnostic()?;
-        let data = fs::read(path).into_diagnostic()?;
 
-        Some(data)
+        Some(fs::read(&path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?)
     } else {
         None
     };
 
+    if let Some(path) = &args.replay {
+        return replay(
+            path,
+            elf.as_deref(),
+            args.log_format,
+            &channels,
+            args.encoding,
+            args.processors,
+            args.elf,
+            rules,
+            args.replay_speed,
+        );
+    }
+
+    let mut flasher = connect(&args.connect_args, config, true, true)?;
+    let pid = flasher.get_usb_pid()?;
+
     let chip = flasher.chip();
     let target = chip.into_target();
 
@@ -446,19 +1476,69 @@ pub fn serial_monitor(args: MonitorArgs, config: &Config) -> Result<()> {
         115_200
     };
 
+    let baud = args.connect_args.baud.unwrap_or(default_baud);
+    let recorder = make_recorder(args.record.as_deref(), chip, baud, elf.as_deref())?;
+    let log_file = make_log_file(
+        args.log_file.as_deref(),
+        args.log_file_max_size,
+        args.log_file_count,
+    )?;
+
     monitor(
         flasher.into_serial(),
         elf.as_deref(),
         pid,
-        args.connect_args.baud.unwrap_or(default_baud),
+        baud,
         args.log_format,
+        &channels,
+        args.encoding,
         !args.non_interactive,
         args.processors,
         args.elf,
+        rules,
+        recorder,
+        log_file,
+        args.timestamps,
     )
 }
 
+/// Create the recorder for a monitor session, if it is to be recorded to
+/// `path`
+pub fn make_recorder(
+    path: Option<&Path>,
+    chip: Chip,
+    baud: u32,
+    elf: Option<&[u8]>,
+) -> Result<Option<Recorder>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+
+    let recorder = Recorder::create(path, &SessionMetadata::new(chip, baud, elf))?;
+    info!("Recording the session to {}", path.display());
+
+    Ok(Some(recorder))
+}
+
+/// Create the log file of a monitor session, if the output is to be written to
+/// `path`
+pub fn make_log_file(
+    path: Option<&Path>,
+    max_size: Option<u32>,
+    keep: usize,
+) -> Result<Option<LogFile>> {
+    let Some(path) = path else {
+        return Ok(None);
+    };
+
+    let log_file = LogFile::create(path, max_size.map(u64::from), keep)?;
+    info!("Writing the output to {}", path.display());
+
+    Ok(Some(log_file))
+}
+
 /// Convert the provided firmware image from ELF to binary
+#[allow(clippy::too_many_arguments)]
 pub fn save_elf_as_image(
     elf_data: &[u8],
     chip: Chip,
@@ -467,59 +1547,77 @@ pub fn save_elf_as_image(
     merge: bool,
     skip_padding: bool,
     xtal_freq: XtalFrequency,
+    format: SaveImageFormat,
+    output_format: OutputFormat,
 ) -> Result<()> {
-    let image = ElfFirmwareImage::try_from(elf_data)?;
+    Metadata::from_bytes(elf_data)?.check_chip(chip)?;
+
+    let image = ElfFirmwareImage::try_from(elf_data)?
+        .with_segment_filter(flash_data.segment_filter.clone())?;
+
+    if format == SaveImageFormat::Uf2 {
+        let image = chip
+            .into_target()
+            .get_flash_image(&image, flash_data, None, xtal_freq)?;
 
-    if merge {
+        display_image_size(output_format, image.app_size(), image.part_size());
+
+        // TinyUF2 writes the blocks to the app partition, at their address
+        // relative to its start
+        let app = image
+            .ota_segments()
+            .next()
+            .map(|segment| RomSegment {
+                addr: 0,
+                data: segment.data,
+            })
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        let mut file = OutputFile::create(image_path)?;
+        write_uf2(&mut file, &app, chip).into_diagnostic()?;
+        file.persist()?;
+    } else if merge {
         // To get a chip revision, the connection is needed
         // For simplicity, the revision None is used
         let image =
             chip.into_target()
                 .get_flash_image(&image, flash_data.clone(), None, xtal_freq)?;
 
-        display_image_size(image.app_size(), image.part_size());
+        display_image_size(output_format, image.app_size(), image.part_size());
 
-        let mut file = fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(image_path)
-            .into_diagnostic()?;
+        // Padding is streamed to the file rather than allocated, as merged images
+        // can be as large as the flash.
+        let mut file = OutputFile::create(image_path)?;
+        let mut position = 0;
 
         for segment in image.flash_segments() {
-            let padding_bytes = vec![
-                0xffu8;
-                segment.addr as usize
-                    - file.metadata().into_diagnostic()?.len() as usize
-            ];
-            file.write_all(&padding_bytes).into_diagnostic()?;
+            write_padding(&mut file, segment.addr as u64 - position)?;
             file.write_all(&segment.data).into_diagnostic()?;
+            position = segment.addr as u64 + segment.data.len() as u64;
         }
 
         if !skip_padding {
             // Take flash_size as input parameter, if None, use default value of 4Mb
-            let padding_bytes = vec![
-                0xffu8;
-                flash_data.flash_settings.size.unwrap_or_default().size()
-                    as usize
-                    - file.metadata().into_diagnostic()?.len() as usize
-            ];
-            file.write_all(&padding_bytes).into_diagnostic()?;
+            let flash_size = flash_data.flash_settings.size.unwrap_or_default().size();
+            write_padding(&mut file, (flash_size as u64).saturating_sub(position))?;
         }
+
+        file.persist()?;
     } else {
         let image = chip
             .into_target()
             .get_flash_image(&image, flash_data, None, xtal_freq)?;
 
-        display_image_size(image.app_size(), image.part_size());
+        display_image_size(output_format, image.app_size(), image.part_size());
 
         let parts = image.ota_segments().collect::<Vec<_>>();
         match parts.as_slice() {
-            [single] => fs::write(&image_path, &single.data).into_diagnostic()?,
+            [single] => output::write(&image_path, &single.data)?,
             parts => {
                 for part in parts {
                     let part_path = format!("{:#x}_{}", part.addr, image_path.display());
-                    fs::write(part_path, &part.data).into_diagnostic()?
+                    output::write(part_path, &part.data)?
                 }
             }
         }
@@ -530,46 +1628,134 @@ pub fn save_elf_as_image(
     Ok(())
 }
 
+/// Write `len` bytes of erased flash (`0xFF`)
+fn write_padding(writer: &mut impl Write, len: u64) -> Result<()> {
+    write_padding_with(writer, len, 0xff)
+}
+
+/// Write `len` bytes of `fill_byte`
+fn write_padding_with(writer: &mut impl Write, len: u64, fill_byte: u8) -> Result<()> {
+    io::copy(&mut io::repeat(fill_byte).take(len), writer).into_diagnostic()?;
+
+    Ok(())
+}
+
+/// Map a file into memory
+///
+/// ELF files and binaries can be large, so they are mapped rather than read
+/// into memory, leaving it to the OS to page in the data as it is needed.
+/// Only files which are used briefly are mapped: the monitor reads the ELF
+/// file instead, as the project may be rebuilt while it runs.
+pub fn map_file(path: &Path) -> Result<Mmap> {
+    let file =
+        fs::File::open(path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+    // SAFETY: if another process truncates the file while it is mapped, reading
+    // the truncated part raises SIGBUS, and modifying it changes memory which
+    // is assumed to be immutable. Both are undefined behaviour, so mappings
+    // are only kept while a command reads its inputs, never for a long-running
+    // session.
+    let map = unsafe { Mmap::map(&file) }
+        .map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+    Ok(map)
+}
+
 /// Displays the image or app size
-pub(crate) fn display_image_size(app_size: u32, part_size: Option<u32>) {
+pub(crate) fn display_image_size(format: OutputFormat, app_size: u32, part_size: Option<u32>) {
     if let Some(part_size) = part_size {
         let percent = app_size as f32 / part_size as f32 * 100.0;
-        println!(
+        outputln!(
+            format,
             "App/part. size:    {}/{} bytes, {:.2}%",
             HumanCount(app_size as u64),
             HumanCount(part_size as u64),
             percent
         );
     } else {
-        println!("App size:          {} bytes", HumanCount(app_size as u64));
+        outputln!(
+            format,
+            "App size:          {} bytes",
+            HumanCount(app_size as u64)
+        );
     }
 }
 
 /// Progress callback implementations for use in `cargo-espflash` and `espflash`
+///
+/// The progress bar counts blocks, and switches to bytes for writes, which
+/// report their progress in bytes as well.
 #[derive(Default)]
 pub struct EspflashProgress {
     pb: Option<ProgressBar>,
+    addr: u32,
+    /// Whether the progress bar counts bytes rather than blocks
+    bytes: bool,
+    device_name: Option<String>,
+    /// Segments which were completely written
+    segments: Vec<SegmentProgress>,
+}
+
+impl EspflashProgress {
+    /// Progress bars prefixed with the name of the device `flasher` is
+    /// connected to, if it was named with [Flasher::set_device_name]
+    pub fn for_device(flasher: &Flasher) -> Self {
+        Self {
+            device_name: flasher.device_name().map(str::to_string),
+            ..Self::default()
+        }
+    }
+
+    /// Prefix the progress bars with the name of the device being written
+    pub fn with_device_name(mut self, name: impl Into<String>) -> Self {
+        self.device_name = Some(name.into());
+        self
+    }
+
+    /// The segments which were completely written so far
+    pub fn finished_segments(&self) -> &[SegmentProgress] {
+        &self.segments
+    }
+}
+
+/// Style of the progress bars, counting bytes or blocks
+fn progress_style(prefixed: bool, bytes: bool) -> ProgressStyle {
+    let prefix = if prefixed { "[{prefix}] " } else { "" };
+    let position = if bytes {
+        "{bytes:>10}/{total_bytes:10}"
+    } else {
+        "{pos:>7}/{len:7}"
+    };
+
+    ProgressStyle::default_bar()
+        .template(&format!(
+            "{prefix}[{{elapsed_precise}}] [{{bar:40}}] {position} {{msg}}"
+        ))
+        .unwrap()
+        .progress_chars("=> ")
 }
 
 impl ProgressCallbacks for EspflashProgress {
     /// Initialize the progress bar
     fn init(&mut self, addr: u32, len: usize) {
+        self.addr = addr;
+        self.bytes = false;
+
+        let prefix = self.device_name.clone().unwrap_or_default();
         let pb = ProgressBar::new(len as u64)
-            .with_message(format!("{addr:#X}"))
-            .with_style(
-                ProgressStyle::default_bar()
-                    .template("[{elapsed_precise}] [{bar:40}] {pos:>7}/{len:7} {msg}")
-                    .unwrap()
-                    .progress_chars("=> "),
-            );
+            .with_style(progress_style(!prefix.is_empty(), false))
+            .with_prefix(prefix)
+            .with_message(format!("{addr:#X}"));
 
         self.pb = Some(pb);
     }
 
-    /// Update the progress bar
+    /// Update the progress bar, unless it counts bytes
     fn update(&mut self, current: usize) {
         if let Some(ref pb) = self.pb {
-            pb.set_position(current as u64);
+            if !self.bytes {
+                pb.set_position(current as u64);
+            }
         }
     }
 
@@ -579,17 +1765,44 @@ impl ProgressCallbacks for EspflashProgress {
             pb.finish();
         }
     }
-}
 
-pub fn erase_flash(args: EraseFlashArgs, config: &Config) -> Result<()> {
-    if args.connect_args.no_stub {
-        return Err(Error::StubRequired.into());
+    /// Show the throughput and remaining time in the progress bar
+    fn rate(&mut self, rate: TransferRate) {
+        if let Some(ref pb) = self.pb {
+            pb.set_message(format!(
+                "{:#X} {}/s, ETA {}s (overall {}/s, ETA {}s)",
+                self.addr,
+                HumanBytes(rate.segment_bytes_per_sec as u64),
+                rate.segment_eta.as_secs(),
+                HumanBytes(rate.overall_bytes_per_sec as u64),
+                rate.overall_eta.as_secs(),
+            ));
+        }
     }
 
+    /// Count the bytes of the segment in the progress bar
+    fn bytes(&mut self, progress: SegmentProgress) {
+        if let Some(ref pb) = self.pb {
+            if !self.bytes {
+                self.bytes = true;
+                pb.set_style(progress_style(!pb.prefix().is_empty(), true));
+                pb.set_length(progress.size as u64);
+            }
+            pb.set_position(progress.written as u64);
+        }
+    }
+
+    /// Record the segment for the results of the command
+    fn segment_finished(&mut self, progress: SegmentProgress) {
+        self.segments.push(progress);
+    }
+}
+
+pub fn erase_flash(args: EraseFlashArgs, config: &Config) -> Result<()> {
     let mut flasher = connect(&args.connect_args, config, true, true)?;
     info!("Erasing Flash...");
 
-    flasher.erase_flash()?;
+    flasher.erase_flash_with_progress(Some(&mut EspflashProgress::for_device(&flasher)))?;
     flasher
         .connection()
         .reset_after(!args.connect_args.no_stub)?;
@@ -600,10 +1813,6 @@ pub fn erase_flash(args: EraseFlashArgs, config: &Config) -> Result<()> {
 }
 
 pub fn erase_region(args: EraseRegionArgs, config: &Config) -> Result<()> {
-    if args.connect_args.no_stub {
-        return Err(Error::StubRequired).into_diagnostic();
-    }
-
     let mut flasher = connect(&args.connect_args, config, true, true)?;
 
     info!(
@@ -625,16 +1834,42 @@ pub fn flash_elf_image(
     elf_data: &[u8],
     flash_data: FlashData,
     xtal_freq: XtalFrequency,
+    report: &mut Report,
 ) -> Result<()> {
+    Metadata::from_bytes(elf_data)?.check_chip(flasher.chip())?;
+
     // Load the ELF data, optionally using the provider bootloader/partition
     // table/image format, to the device's flash memory.
-    flasher.load_elf_to_flash(
-        elf_data,
-        flash_data,
-        Some(&mut EspflashProgress::default()),
-        xtal_freq,
-    )?;
+    let mut progress = EspflashProgress::for_device(flasher);
+    let result = flasher.load_elf_to_flash(elf_data, flash_data, Some(&mut progress), xtal_freq);
+    report.record_segments(progress.finished_segments());
+    #[cfg(feature = "metrics")]
+    crate::metrics::report_flash(flasher, result.is_ok());
+    result?;
+    info!("Flashing has completed!");
+    debug!("Connection statistics: {:?}", flasher.connection().stats());
+
+    Ok(())
+}
+
+/// Write a prebuilt application image to flash, along with the bootloader and
+/// partition table
+pub fn flash_app_image(
+    flasher: &mut Flasher,
+    app_data: &[u8],
+    flash_data: FlashData,
+    xtal_freq: XtalFrequency,
+    report: &mut Report,
+) -> Result<()> {
+    let image = AppImage::parse(app_data)?;
+    let mut progress = EspflashProgress::for_device(flasher);
+    let result = flasher.load_image_to_flash(&image, flash_data, Some(&mut progress), xtal_freq);
+    report.record_segments(progress.finished_segments());
+    #[cfg(feature = "metrics")]
+    crate::metrics::report_flash(flasher, result.is_ok());
+    result?;
     info!("Flashing has completed!");
+    debug!("Connection statistics: {:?}", flasher.connection().stats());
 
     Ok(())
 }
@@ -651,8 +1886,21 @@ pub fn erase_partitions(
         None => return Err(MissingPartitionTable.into()),
     };
 
+    partitions_to_erase(partition_table, erase_parts, erase_data_parts)?
+        .into_iter()
+        .try_for_each(|p| erase_partition(flasher, p))?;
+
+    Ok(())
+}
+
+/// Find the partitions selected by their labels or data subtypes
+pub(crate) fn partitions_to_erase(
+    partition_table: &PartitionTable,
+    erase_parts: Option<Vec<String>>,
+    erase_data_parts: Option<Vec<DataType>>,
+) -> Result<Vec<&Partition>> {
     // Using a hashmap to deduplicate entries
-    let mut parts_to_erase = None;
+    let mut parts_to_erase = HashMap::new();
 
     // Look for any partitions with specific labels
     if let Some(part_labels) = erase_parts {
@@ -661,9 +1909,7 @@ pub fn erase_partitions(
                 .find(label.as_str())
                 .ok_or_else(|| MissingPartition::from(label))?;
 
-            parts_to_erase
-                .get_or_insert(HashMap::new())
-                .insert(part.offset(), part);
+            parts_to_erase.insert(part.offset(), part);
         }
     }
 
@@ -676,21 +1922,13 @@ pub fn erase_partitions(
                 if part.ty() == esp_idf_part::Type::Data
                     && part.subtype() == esp_idf_part::SubType::Data(ty)
                 {
-                    parts_to_erase
-                        .get_or_insert(HashMap::new())
-                        .insert(part.offset(), part);
+                    parts_to_erase.insert(part.offset(), part);
                 }
             }
         }
     }
 
-    if let Some(parts) = parts_to_erase {
-        parts
-            .iter()
-            .try_for_each(|(_, p)| erase_partition(flasher, p))?;
-    }
-
-    Ok(())
+    Ok(parts_to_erase.into_values().collect())
 }
 
 /// Erase a single partition
@@ -704,57 +1942,208 @@ fn erase_partition(flasher: &mut Flasher, part: &Partition) -> Result<()> {
 }
 
 /// Read flash content and write it to a file
-pub fn read_flash(args: ReadFlashArgs, config: &Config) -> Result<()> {
-    if args.connect_args.no_stub {
-        return Err(Error::StubRequired.into());
+pub fn read_flash(args: ReadFlashArgs, config: &Config, report: &mut Report) -> Result<()> {
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    print_board_info(&mut flasher, report)?;
+
+    let (addr, size) = if let Some(label) = &args.partition {
+        let table = match args
+            .partition_table_offset
+            .or(config.partition_table_offset)
+        {
+            Some(offset) => flasher.read_partition_table(offset)?,
+            None => flasher.find_partition_table()?.1,
+        };
+        let part = table
+            .find(label)
+            .ok_or_else(|| Error::PartitionNotFound(label.clone()))?;
+
+        (part.offset(), part.size())
+    } else {
+        // Both are required by clap when no partition is given
+        (args.addr.unwrap(), args.size.unwrap())
+    };
+
+    if args.follow {
+        return follow_flash(
+            &mut flasher,
+            addr,
+            size,
+            args.block_size,
+            args.max_in_flight,
+            Duration::from_millis(args.poll_interval),
+        );
     }
 
-    let mut flasher = connect(&args.connect_args, config, false, false)?;
-    print_board_info(&mut flasher)?;
+    let file = args.file.unwrap();
+    let start = Instant::now();
     flasher.read_flash(
-        args.addr,
-        args.size,
+        addr,
+        size,
         args.block_size,
         args.max_in_flight,
-        args.file,
+        file.clone(),
     )?;
 
+    if report.is_json() {
+        print_json(&serde_json::json!({
+            "device": report.device(),
+            "address": addr,
+            "size": size,
+            "file": file,
+            "duration_ms": start.elapsed().as_millis(),
+        }))?;
+    }
+
     Ok(())
 }
 
-/// Convert and display CSV and binary partition tables
-pub fn partition_table(args: PartitionTableArgs) -> Result<()> {
+/// Poll a flash region and print any content written to it, until interrupted
+///
+/// To avoid reading the whole region on every poll, the MD5 digest of each
+/// sector is compared against the previous poll, and only sectors which
+/// changed are read back. Erased bytes (`0xFF`) are not printed, so data
+/// written to a circular log shows up as it is appended.
+fn follow_flash(
+    flasher: &mut Flasher,
+    addr: u32,
+    size: u32,
+    block_size: u32,
+    max_in_flight: u32,
+    interval: Duration,
+) -> Result<()> {
+    let sectors = sectors(addr, size)?;
+
+    let mut contents = Vec::with_capacity(sectors.len());
+    let mut digests = Vec::with_capacity(sectors.len());
+    for &(offset, len) in &sectors {
+        contents.push(flasher.read_flash_region(offset, len, block_size, max_in_flight)?);
+        digests.push(flasher.checksum_md5(offset, len)?);
+    }
+
+    info!(
+        "Following 0x{size:x}B at 0x{addr:08x}, polling every {}ms (Ctrl-C to stop)",
+        interval.as_millis()
+    );
+
+    let mut stdout = std::io::stdout();
+    loop {
+        thread::sleep(interval);
+
+        for (i, &(offset, len)) in sectors.iter().enumerate() {
+            let digest = flasher.checksum_md5(offset, len)?;
+            if digest == digests[i] {
+                continue;
+            }
+
+            let current = flasher.read_flash_region(offset, len, block_size, max_in_flight)?;
+            for range in changed_ranges(&contents[i], &current) {
+                let data = current[range]
+                    .iter()
+                    .copied()
+                    .filter(|&b| b != 0xFF)
+                    .collect::<Vec<_>>();
+                stdout.write_all(&data).into_diagnostic()?;
+            }
+            stdout.flush().into_diagnostic()?;
+
+            contents[i] = current;
+            digests[i] = digest;
+        }
+    }
+}
+
+/// The offset and length of each sector of a flash region
+fn sectors(addr: u32, size: u32) -> Result<Vec<(u32, u32)>, Error> {
+    let end = addr
+        .checked_add(size)
+        .ok_or(Error::InvalidFlashRegion { offset: addr, size })?;
+
+    let sector_size = FLASH_SECTOR_SIZE as u32;
+    Ok((addr..end)
+        .step_by(sector_size as usize)
+        .map(|offset| (offset, sector_size.min(end - offset)))
+        .collect())
+}
+
+/// Ranges of bytes which differ between two snapshots of the same region
+fn changed_ranges(previous: &[u8], current: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+
+    for (i, (old, new)) in previous.iter().zip(current).enumerate() {
+        if old == new {
+            continue;
+        }
+
+        match ranges.last_mut() {
+            Some(range) if range.end == i => range.end += 1,
+            _ => ranges.push(i..i + 1),
+        }
+    }
+
+    ranges
+}
+
+/// Convert, display, validate and edit CSV and binary partition tables
+pub fn partition_table(args: PartitionTableArgs, config: &Config) -> Result<()> {
+    if let Some(command) = args.command {
+        return edit_partition_table(command);
+    }
+
+    let table = if args.from_device {
+        let mut flasher = connect(&args.connect_args, config, false, false)?;
+        match args
+            .partition_table_offset
+            .or(config.partition_table_offset)
+        {
+            Some(offset) => flasher.read_partition_table(offset)?,
+            None => {
+                let (offset, table) = flasher.find_partition_table()?;
+                info!("Found the partition table at {offset:#x}");
+                table
+            }
+        }
+    } else {
+        // Required by clap without a subcommand or `--from-device`
+        let path = args.partition_table.unwrap();
+        let input =
+            fs::read(&path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+        if args.to_csv {
+            PartitionTable::try_from_bytes(input).into_diagnostic()?
+        } else {
+            PartitionTable::try_from(input).into_diagnostic()?
+        }
+    };
+
+    if args.validate || args.flash_size.is_some() {
+        validate_partition_table(&table, args.flash_size)?;
+
+        if args.validate {
+            info!("The partition table is valid");
+            return Ok(());
+        }
+    }
+
     if args.to_binary {
-        let table = parse_partition_table(&args.partition_table)?;
+        let data = table.to_bin().into_diagnostic()?;
 
         // Use either stdout or a file if provided for the output.
-        let mut writer: Box<dyn Write> = if let Some(output) = args.output {
-            Box::new(fs::File::create(output).into_diagnostic()?)
+        if let Some(path) = args.output {
+            output::write(path, data)?;
         } else {
-            Box::new(std::io::stdout())
-        };
-
-        writer
-            .write_all(&table.to_bin().into_diagnostic()?)
-            .into_diagnostic()?;
+            io::stdout().write_all(&data).into_diagnostic()?;
+        }
     } else if args.to_csv {
-        let input = fs::read(&args.partition_table).into_diagnostic()?;
-        let table = PartitionTable::try_from_bytes(input).into_diagnostic()?;
+        let data = table.to_csv().into_diagnostic()?;
 
         // Use either stdout or a file if provided for the output.
-        let mut writer: Box<dyn Write> = if let Some(output) = args.output {
-            Box::new(fs::File::create(output).into_diagnostic()?)
+        if let Some(path) = args.output {
+            output::write(path, data)?;
         } else {
-            Box::new(std::io::stdout())
-        };
-
-        writer
-            .write_all(table.to_csv().into_diagnostic()?.as_bytes())
-            .into_diagnostic()?;
+            io::stdout().write_all(data.as_bytes()).into_diagnostic()?;
+        }
     } else {
-        let input = fs::read(&args.partition_table).into_diagnostic()?;
-        let table = PartitionTable::try_from(input).into_diagnostic()?;
-
         pretty_print(table);
     }
 
@@ -822,6 +2211,7 @@ pub fn make_flash_data(
     config: &Config,
     default_bootloader: Option<&Path>,
     default_partition_table: Option<&Path>,
+    format: OutputFormat,
 ) -> Result<FlashData, Error> {
     let bootloader = image_args
         .bootloader
@@ -839,21 +2229,56 @@ pub fn make_flash_data(
         .or(config.partition_table_offset);
 
     if let Some(path) = &bootloader {
-        println!("Bootloader:        {}", path.display());
+        outputln!(format, "Bootloader:        {}", path.display());
     }
     if let Some(path) = &partition_table {
-        println!("Partition table:   {}", path.display());
+        outputln!(format, "Partition table:   {}", path.display());
     }
 
+    let bootloader = bootloader
+        .map(|path| {
+            fs::canonicalize(path)
+                .and_then(fs::read)
+                .map_err(|e| Error::FileOpenError(path.display().to_string(), e))
+        })
+        .transpose()?;
+    let partition_table = partition_table.map(parse_partition_table).transpose()?;
+
     let flash_settings = make_flash_settings(flash_config_args, config);
-    FlashData::new(
+    let mut flash_data = FlashData::new(
         bootloader,
         partition_table,
         partition_table_offset,
         image_args.target_app_partition,
         flash_settings,
         image_args.min_chip_rev,
-    )
+        image_args.mmu_page_size,
+    );
+
+    flash_data.bootloader_offset = image_args.bootloader_offset;
+    flash_data.app_offset = image_args.app_offset;
+    flash_data.force_bootloader = image_args.force;
+    flash_data.image_format = image_args.image_format;
+    flash_data.extra_app_partitions = if image_args.all_app_partitions {
+        ExtraAppPartitions::All
+    } else if !image_args.extra_app_partitions.is_empty() {
+        ExtraAppPartitions::Labels(image_args.extra_app_partitions)
+    } else {
+        ExtraAppPartitions::None
+    };
+
+    if let Some(path) = &image_args.signing_key {
+        let key = SigningKey::load(path)?;
+        outputln!(
+            format,
+            "Signing key:       {} ({})",
+            path.display(),
+            key.scheme()
+        );
+        flash_data.signing_key = Some(key);
+    }
+
+    Ok(flash_data)
 }
 
 mod test {
@@ -873,4 +2298,41 @@ mod test {
         let parser = TestParser::parse_from(iter);
         assert_eq!(parser.args.image.partition_table_offset, Some(0x8000));
     }
+
+    #[test]
+    fn test_changed_ranges() {
+        let previous = [0xFF; 8];
+        let current = [b'a', b'b', 0xFF, 0xFF, b'c', 0xFF, 0xFF, 0xFF];
+
+        assert_eq!(super::changed_ranges(&previous, &current), [0..2, 4..5]);
+        assert!(super::changed_ranges(&current, &current).is_empty());
+    }
+
+    #[test]
+    fn test_sectors() {
+        assert_eq!(
+            super::sectors(0x1000, 0x1800).unwrap(),
+            [(0x1000, 0x1000), (0x2000, 0x800)]
+        );
+        assert!(matches!(
+            super::sectors(0xFFFF_F000, 0x2000),
+            Err(crate::error::Error::InvalidFlashRegion { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_spi_connection() {
+        use crate::flasher::SpiAttachParams;
+
+        let parse = |value: &str| value.parse::<SpiAttachParams>();
+
+        assert_eq!(parse("spi").unwrap(), SpiAttachParams::default());
+        assert_eq!(parse("HSPI").unwrap(), SpiAttachParams::hspi());
+        assert_eq!(
+            parse("6,17,8,11,16").unwrap(),
+            SpiAttachParams::esp32_pico_d4()
+        );
+        assert!(parse("6,17,8,11").is_err());
+        assert!(parse("6,17,8,11,64").is_err());
+    }
 }
@@ -0,0 +1,491 @@
+//! Tables summarizing the diagnostic dumps printed by ESP-IDF
+//!
+//! The task watchdog report, `heap_caps_print_heap_info` and `heap_trace_dump`
+//! print their findings as a wall of text. The lines of these dumps are still
+//! shown as they arrive, and once a dump is complete it is summarized in a
+//! table, with the addresses of backtraces and allocation callers resolved
+//! when an ELF file is available.
+
+use comfy_table::{modifiers, presets::UTF8_FULL, Attribute, Cell, Color, Table};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::{cli::monitor::rules::RE_ANSI_ESCAPE, monitor::symbols::Symbols};
+
+lazy_static! {
+    static ref RE_WDT_START: Regex =
+        Regex::new(r"task_wdt: Task watchdog got triggered").unwrap();
+    static ref RE_WDT_STALLED: Regex =
+        Regex::new(r"task_wdt:\s+- (\S+) \(CPU ([\d/]+)\)").unwrap();
+    static ref RE_WDT_RUNNING: Regex = Regex::new(r"task_wdt: CPU (\d+): (\S+)").unwrap();
+    static ref RE_BACKTRACE: Regex = Regex::new(r"^Backtrace:").unwrap();
+    static ref RE_BACKTRACE_FRAME: Regex =
+        Regex::new(r"(0x[[:xdigit:]]{8}):0x[[:xdigit:]]{8}").unwrap();
+    static ref RE_HEAP_INFO_START: Regex =
+        Regex::new(r"^Heap summary for capabilities (0x[[:xdigit:]]+):").unwrap();
+    static ref RE_HEAP_REGION: Regex = Regex::new(
+        r"^\s*At (0x[[:xdigit:]]+) len (\d+) free (\d+) allocated (\d+) min_free (\d+)"
+    )
+    .unwrap();
+    static ref RE_HEAP_BLOCKS: Regex = Regex::new(
+        r"^\s*largest_free_block (\d+) alloc_blocks (\d+) free_blocks (\d+) total_blocks (\d+)"
+    )
+    .unwrap();
+    static ref RE_HEAP_TOTALS: Regex = Regex::new(
+        r"^\s*free (\d+) allocated (\d+) min_free (\d+) largest_free_block (\d+)"
+    )
+    .unwrap();
+    static ref RE_HEAP_TRACE_START: Regex =
+        Regex::new(r"^=+ Heap Trace: (\d+) records").unwrap();
+    static ref RE_HEAP_TRACE_RECORD: Regex = Regex::new(
+        r"^\s*(\d+) bytes \(@ (0x[[:xdigit:]]+)(?:, (\w+))?\) allocated CPU (\d+) ccount 0x[[:xdigit:]]+ caller ((?:0x[[:xdigit:]]+:?)+)"
+    )
+    .unwrap();
+    static ref RE_HEAP_TRACE_END: Regex = Regex::new(r"^=+ Heap Trace Summary =+").unwrap();
+}
+
+/// A task named in a task watchdog report
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct WdtTask {
+    name: String,
+    cpu: String,
+    /// Whether the task failed to reset the watchdog, rather than running
+    stalled: bool,
+}
+
+/// A region listed by `heap_caps_print_heap_info`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct HeapRegion {
+    address: String,
+    size: u64,
+    free: u64,
+    allocated: u64,
+    min_free: u64,
+    largest_free_block: u64,
+    alloc_blocks: u64,
+    free_blocks: u64,
+}
+
+/// An allocation listed by `heap_trace_dump`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HeapRecord {
+    size: u64,
+    address: String,
+    caps: Option<String>,
+    cpu: String,
+    callers: Vec<u64>,
+}
+
+/// A complete dump
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Dump {
+    TaskWatchdog {
+        tasks: Vec<WdtTask>,
+        backtrace: Vec<u64>,
+    },
+    HeapInfo {
+        caps: String,
+        regions: Vec<HeapRegion>,
+        totals: Option<HeapRegion>,
+    },
+    HeapTrace {
+        records: Vec<HeapRecord>,
+    },
+}
+
+/// State of the dump being collected
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Idle,
+    TaskWatchdog {
+        tasks: Vec<WdtTask>,
+    },
+    HeapInfo {
+        caps: String,
+        regions: Vec<HeapRegion>,
+        in_totals: bool,
+    },
+    HeapTrace {
+        records: Vec<HeapRecord>,
+    },
+}
+
+/// Collects the lines of dumps, summarizing each complete dump in a table
+#[derive(Debug, Default)]
+pub(crate) struct Dumps {
+    state: State,
+}
+
+impl Dumps {
+    /// Process a complete line of output, returning the table summarizing a
+    /// dump if the line completed it
+    pub fn feed(&mut self, line: &str, symbols: Option<&Symbols>) -> Option<String> {
+        self.feed_line(line)
+            .map(|dump| render(&dump, symbols).replace('\n', "\r\n") + "\r\n")
+    }
+
+    fn feed_line(&mut self, line: &str) -> Option<Dump> {
+        let line = RE_ANSI_ESCAPE.replace_all(line.trim_end(), "");
+
+        match &mut self.state {
+            State::Idle => {
+                self.start(&line);
+                None
+            }
+            State::TaskWatchdog { tasks } => {
+                if let Some(captures) = RE_WDT_STALLED.captures(&line) {
+                    tasks.push(WdtTask {
+                        name: captures[1].into(),
+                        cpu: captures[2].into(),
+                        stalled: true,
+                    });
+                } else if let Some(captures) = RE_WDT_RUNNING.captures(&line) {
+                    tasks.push(WdtTask {
+                        name: captures[2].into(),
+                        cpu: captures[1].into(),
+                        stalled: false,
+                    });
+                } else if RE_BACKTRACE.is_match(&line) {
+                    let backtrace = RE_BACKTRACE_FRAME
+                        .captures_iter(&line)
+                        .filter_map(|captures| parse_int::parse(&captures[1]).ok())
+                        .collect();
+                    let tasks = std::mem::take(tasks);
+                    self.state = State::Idle;
+
+                    return Some(Dump::TaskWatchdog { tasks, backtrace });
+                } else if !line.is_empty() && !line.contains("task_wdt:") {
+                    // Not every architecture prints a backtrace, so any other line
+                    // ends the report
+                    let tasks = std::mem::take(tasks);
+                    self.start(&line);
+
+                    return Some(Dump::TaskWatchdog {
+                        tasks,
+                        backtrace: Vec::new(),
+                    });
+                }
+
+                None
+            }
+            State::HeapInfo {
+                caps,
+                regions,
+                in_totals,
+            } => {
+                if let Some(captures) = RE_HEAP_REGION.captures(&line) {
+                    regions.push(HeapRegion {
+                        address: captures[1].into(),
+                        size: captures[2].parse().unwrap_or_default(),
+                        free: captures[3].parse().unwrap_or_default(),
+                        allocated: captures[4].parse().unwrap_or_default(),
+                        min_free: captures[5].parse().unwrap_or_default(),
+                        ..Default::default()
+                    });
+                } else if let Some(captures) = RE_HEAP_BLOCKS.captures(&line) {
+                    if let Some(region) = regions.last_mut() {
+                        region.largest_free_block = captures[1].parse().unwrap_or_default();
+                        region.alloc_blocks = captures[2].parse().unwrap_or_default();
+                        region.free_blocks = captures[3].parse().unwrap_or_default();
+                    }
+                } else if line.trim() == "Totals:" {
+                    *in_totals = true;
+                } else {
+                    let totals =
+                        RE_HEAP_TOTALS
+                            .captures(&line)
+                            .filter(|_| *in_totals)
+                            .map(|captures| HeapRegion {
+                                address: "Totals".into(),
+                                size: regions.iter().map(|region| region.size).sum(),
+                                free: captures[1].parse().unwrap_or_default(),
+                                allocated: captures[2].parse().unwrap_or_default(),
+                                min_free: captures[3].parse().unwrap_or_default(),
+                                largest_free_block: captures[4].parse().unwrap_or_default(),
+                                alloc_blocks: regions
+                                    .iter()
+                                    .map(|region| region.alloc_blocks)
+                                    .sum(),
+                                free_blocks: regions.iter().map(|region| region.free_blocks).sum(),
+                            });
+                    let dump = Dump::HeapInfo {
+                        caps: std::mem::take(caps),
+                        regions: std::mem::take(regions),
+                        totals: totals.clone(),
+                    };
+
+                    self.state = State::Idle;
+                    if totals.is_none() {
+                        self.start(&line);
+                    }
+
+                    return Some(dump);
+                }
+
+                None
+            }
+            State::HeapTrace { records } => {
+                if let Some(captures) = RE_HEAP_TRACE_RECORD.captures(&line) {
+                    records.push(HeapRecord {
+                        size: captures[1].parse().unwrap_or_default(),
+                        address: captures[2].into(),
+                        caps: captures.get(3).map(|caps| caps.as_str().into()),
+                        cpu: captures[4].into(),
+                        callers: captures[5]
+                            .split(':')
+                            .filter_map(|caller| parse_int::parse(caller).ok())
+                            .collect(),
+                    });
+                } else if RE_HEAP_TRACE_END.is_match(&line) {
+                    let records = std::mem::take(records);
+                    self.state = State::Idle;
+
+                    return Some(Dump::HeapTrace { records });
+                }
+
+                None
+            }
+        }
+    }
+
+    /// Start collecting a dump if the line begins one
+    fn start(&mut self, line: &str) {
+        self.state = if RE_WDT_START.is_match(line) {
+            State::TaskWatchdog { tasks: Vec::new() }
+        } else if let Some(captures) = RE_HEAP_INFO_START.captures(line) {
+            State::HeapInfo {
+                caps: captures[1].into(),
+                regions: Vec::new(),
+                in_totals: false,
+            }
+        } else if RE_HEAP_TRACE_START.is_match(line) {
+            State::HeapTrace {
+                records: Vec::new(),
+            }
+        } else {
+            State::Idle
+        };
+    }
+}
+
+fn table(header: &[&str]) -> Table {
+    let mut table = Table::new();
+    table
+        .load_preset(UTF8_FULL)
+        .apply_modifier(modifiers::UTF8_ROUND_CORNERS)
+        .set_header(header.iter().map(|title| {
+            Cell::new(title)
+                .fg(Color::Cyan)
+                .add_attribute(Attribute::Bold)
+        }));
+
+    table
+}
+
+/// Name and location of the function at `addr`, as far as it can be resolved
+fn function(symbols: Option<&Symbols>, addr: u64) -> (String, String) {
+    let Some(symbols) = symbols else {
+        return (String::from("??"), String::from("??:??"));
+    };
+
+    let name = symbols.get_name(addr).unwrap_or_else(|| "??".into());
+    let location = symbols
+        .get_location(addr)
+        .map(|(file, line)| format!("{file}:{line}"))
+        .unwrap_or_else(|| "??:??".into());
+
+    (name, location)
+}
+
+fn render(dump: &Dump, symbols: Option<&Symbols>) -> String {
+    match dump {
+        Dump::TaskWatchdog { tasks, backtrace } => {
+            let mut summary = table(&["Task", "CPU", "State"]);
+            for task in tasks {
+                let state = if task.stalled {
+                    Cell::new("did not reset the watchdog").fg(Color::Red)
+                } else {
+                    Cell::new("running")
+                };
+                summary.add_row(vec![Cell::new(&task.name), Cell::new(&task.cpu), state]);
+            }
+
+            if backtrace.is_empty() {
+                return summary.to_string();
+            }
+
+            let mut frames = table(&["#", "PC", "Function", "Location"]);
+            for (index, addr) in backtrace.iter().enumerate() {
+                let (name, location) = function(symbols, *addr);
+                frames.add_row(vec![
+                    index.to_string(),
+                    format!("{addr:#010x}"),
+                    name,
+                    location,
+                ]);
+            }
+
+            format!("{summary}\n{frames}")
+        }
+        Dump::HeapInfo {
+            caps,
+            regions,
+            totals,
+        } => {
+            let mut summary = table(&[
+                "Region",
+                "Size",
+                "Free",
+                "Allocated",
+                "Min free",
+                "Largest free block",
+                "Blocks (alloc/free)",
+            ]);
+            for region in regions.iter().chain(totals) {
+                summary.add_row(vec![
+                    region.address.clone(),
+                    region.size.to_string(),
+                    region.free.to_string(),
+                    region.allocated.to_string(),
+                    region.min_free.to_string(),
+                    region.largest_free_block.to_string(),
+                    format!("{}/{}", region.alloc_blocks, region.free_blocks),
+                ]);
+            }
+
+            format!("Heap with capabilities {caps}:\n{summary}")
+        }
+        Dump::HeapTrace { records } => {
+            let mut summary = table(&["Size", "Address", "Caps", "CPU", "Caller", "Location"]);
+            for record in records {
+                let caller = record.callers.first().copied();
+                let (name, location) = caller
+                    .map(|addr| function(symbols, addr))
+                    .unwrap_or_default();
+
+                summary.add_row(vec![
+                    record.size.to_string(),
+                    record.address.clone(),
+                    record.caps.clone().unwrap_or_default(),
+                    record.cpu.clone(),
+                    name,
+                    location,
+                ]);
+            }
+
+            summary.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dump, Dumps, HeapRegion, WdtTask};
+
+    fn feed_all(dumps: &mut Dumps, text: &str) -> Vec<Dump> {
+        text.lines()
+            .filter_map(|line| dumps.feed_line(line))
+            .collect()
+    }
+
+    #[test]
+    fn task_watchdog() {
+        let mut dumps = Dumps::default();
+        let found = feed_all(
+            &mut dumps,
+            "\x1b[0;31mE (10286) task_wdt: Task watchdog got triggered. The following tasks/users did not reset the watchdog in time:\x1b[0m
+E (10286) task_wdt:  - IDLE0 (CPU 0)
+E (10286) task_wdt: Tasks currently running:
+E (10286) task_wdt: CPU 0: main
+E (10286) task_wdt: CPU 1: IDLE1
+E (10286) task_wdt: Print CPU 0 (current core) backtrace
+
+
+Backtrace: 0x400d5f2a:0x3ffb4c50 0x400d61b8:0x3ffb4c70 |<-CORRUPTED
+",
+        );
+
+        assert_eq!(
+            found,
+            [Dump::TaskWatchdog {
+                tasks: vec![
+                    WdtTask {
+                        name: "IDLE0".into(),
+                        cpu: "0".into(),
+                        stalled: true
+                    },
+                    WdtTask {
+                        name: "main".into(),
+                        cpu: "0".into(),
+                        stalled: false
+                    },
+                    WdtTask {
+                        name: "IDLE1".into(),
+                        cpu: "1".into(),
+                        stalled: false
+                    },
+                ],
+                backtrace: vec![0x400d5f2a, 0x400d61b8],
+            }]
+        );
+
+        let table = super::render(&found[0], None);
+        assert!(table.contains("IDLE0") && table.contains("0x400d61b8"));
+    }
+
+    #[test]
+    fn heap_info() {
+        let mut dumps = Dumps::default();
+        let found = feed_all(
+            &mut dumps,
+            "Heap summary for capabilities 0x00001800:
+  At 0x3ffae6e0 len 6432 free 0 allocated 6296 min_free 0
+    largest_free_block 0 alloc_blocks 34 free_blocks 0 total_blocks 34
+  At 0x3ffb7ca0 len 164704 free 126744 allocated 36288 min_free 126744
+    largest_free_block 110592 alloc_blocks 75 free_blocks 3 total_blocks 78
+  Totals:
+    free 126744 allocated 42584 min_free 126744 largest_free_block 110592
+I (42) main: done
+",
+        );
+
+        let [Dump::HeapInfo {
+            caps,
+            regions,
+            totals,
+        }] = found.as_slice()
+        else {
+            panic!("expected a heap summary, found {found:?}");
+        };
+        assert_eq!(caps, "0x00001800");
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[1].largest_free_block, 110592);
+        assert_eq!(
+            totals.as_ref().map(|totals| (totals.size, totals.free)),
+            Some((171136, 126744))
+        );
+        assert_ne!(regions[0], HeapRegion::default());
+    }
+
+    #[test]
+    fn heap_trace() {
+        let mut dumps = Dumps::default();
+        let found = feed_all(
+            &mut dumps,
+            "====== Heap Trace: 2 records (100 capacity) ======
+    6 bytes (@ 0x3ffb8f24, Internal) allocated CPU 0 ccount 0x3c3c3c3c caller 0x400d1234:0x400d5678
+    40 bytes (@ 0x3ffb8f50) allocated CPU 1 ccount 0x12345678 caller 0x400d9abc:
+====== Heap Trace Summary ======
+",
+        );
+
+        let [Dump::HeapTrace { records }] = found.as_slice() else {
+            panic!("expected a heap trace, found {found:?}");
+        };
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].callers, [0x400d1234, 0x400d5678]);
+        assert_eq!(records[1].caps, None);
+        assert_eq!(records[1].cpu, "1");
+    }
+}
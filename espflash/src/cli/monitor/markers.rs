@@ -0,0 +1,77 @@
+//! Marker lines injected into the output of the monitor
+//!
+//! A marker is a numbered line with the current time, e.g.
+//! `----- MARK #3 12:34:56 UTC -----`, inserted with CTRL+T or by sending
+//! `SIGUSR2` to the process (on Unix). Markers are written to the log file as
+//! well, which makes it easy to correlate the output of the device with the
+//! steps of an external test.
+//!
+//! The time is in UTC, like the timestamps added with `--timestamps`.
+
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::SystemTime,
+};
+
+/// Number of `SIGUSR2` signals received which were not turned into markers yet
+static SIGNALLED: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(unix)]
+extern "C" fn on_signal(_: libc::c_int) {
+    SIGNALLED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Numbers the markers of a monitor session
+pub(crate) struct Markers {
+    count: usize,
+}
+
+impl Markers {
+    /// Start numbering markers, inserting one whenever `SIGUSR2` is received
+    pub fn new() -> Self {
+        #[cfg(unix)]
+        // SAFETY: the handler only increments an atomic counter, which is
+        // async-signal-safe
+        unsafe {
+            libc::signal(
+                libc::SIGUSR2,
+                on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+
+        Self { count: 0 }
+    }
+
+    /// The next marker line, without a line ending
+    pub fn next(&mut self) -> String {
+        self.count += 1;
+        marker(self.count, SystemTime::now())
+    }
+
+    /// Marker lines for the signals received since the last call
+    pub fn signalled(&mut self) -> Vec<String> {
+        let signalled = SIGNALLED.swap(0, Ordering::Relaxed);
+        (0..signalled).map(|_| self.next()).collect()
+    }
+}
+
+fn marker(number: usize, time: SystemTime) -> String {
+    // "2024-01-01T12:34:56Z"
+    let timestamp = humantime::format_rfc3339_seconds(time).to_string();
+    let time = timestamp.get(11..19).unwrap_or(&timestamp);
+
+    format!("----- MARK #{number} {time} UTC -----")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    #[test]
+    fn markers_show_number_and_time() {
+        let time = UNIX_EPOCH + Duration::from_secs(45_296);
+        assert_eq!(marker(3, time), "----- MARK #3 12:34:56 UTC -----");
+    }
+}
@@ -3,7 +3,10 @@
 //! While simple, this serial monitor does provide some nice features such as:
 //!
 //! - Keyboard shortcut for resetting the device (Ctrl-R)
+//! - Marker lines to correlate the output with external events (Ctrl-T)
 //! - Decoding of function addresses in serial output
+//! - Running actions when the output matches a pattern, see [rules]
+//! - Recording sessions and replaying them later, see [session]
 //!
 //! While some serial monitors buffer output until a newline is encountered,
 //! that is not the case here. With other monitors the output of a `print!()`
@@ -11,9 +14,10 @@
 //! in our monitor the output is displayed immediately upon reading.
 
 use std::{
-    io::{stdout, ErrorKind, Read, Write},
-    path::PathBuf,
-    time::Duration,
+    io::{stdout, Write},
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::{Duration, Instant},
 };
 
 use crossterm::event::KeyEventKind;
@@ -21,35 +25,73 @@ use crossterm::{
     event::{poll, read, Event, KeyCode, KeyEvent, KeyModifiers},
     terminal::{disable_raw_mode, enable_raw_mode},
 };
+use encoding_rs::Encoding;
 use external_processors::ExternalProcessors;
-use log::error;
+use log::{error, warn};
 use miette::{IntoDiagnostic, Result};
 #[cfg(feature = "serialport")]
 use serialport::SerialPort;
 use strum::{Display, EnumIter, EnumString, VariantNames};
 
 use crate::{
-    cli::monitor::parser::{InputParser, ResolvingPrinter},
+    cli::monitor::{
+        markers::Markers,
+        parser::{channels::ChannelRoutes, InputParser, ResolvingPrinter},
+        reader::SerialReader,
+        rules::{Rule, RuleAction, Rules},
+        session::{elf_digest, Recorder, Session},
+        sinks::{LogFile, Tee, Timestamped},
+    },
     connection::{reset::reset_after_flash, Port},
+    error::{is_disconnected, Error},
 };
 
 pub mod external_processors;
 pub mod parser;
+pub mod rules;
+pub mod session;
+pub mod sinks;
 
+mod dumps;
 mod line_endings;
-mod symbols;
+mod markers;
+mod reader;
+
+/// How long to wait for a disconnected serial port to reappear
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Interval of the attempts to open a disconnected serial port again
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(250);
 
 #[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumIter, EnumString, VariantNames)]
 #[non_exhaustive]
 #[strum(serialize_all = "lowercase")]
 pub enum LogFormat {
+    /// Detect defmt output, showing everything else as text
+    Auto,
+    /// Frames of several channels, each decoded as routed with `--channel`
+    Channels,
     /// defmt
     Defmt,
     /// serial
     Serial,
 }
 
+/// Parse the label of a character encoding, e.g. `utf-8`, `gb2312` or
+/// `latin1`, as accepted by web browsers
+pub fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    match Encoding::for_label(label.as_bytes()) {
+        // Encodings of more than one byte per character, like UTF-16, are not
+        // decoded as text by browsers either
+        Some(encoding) if encoding.output_encoding() == encoding => Ok(encoding),
+        Some(encoding) => Err(format!(
+            "{} is not supported for the monitor output",
+            encoding.name()
+        )),
+        None => Err(format!("unknown encoding '{label}'")),
+    }
+}
+
 /// Type that ensures that raw mode is disabled when dropped.
 struct RawModeGuard;
 
@@ -76,13 +118,20 @@ pub fn monitor(
     pid: u16,
     baud: u32,
     log_format: LogFormat,
+    channels: &ChannelRoutes,
+    encoding: &'static Encoding,
     interactive_mode: bool,
     processors: Option<String>,
     elf_file: Option<PathBuf>,
+    rules: Vec<Rule>,
+    mut recorder: Option<Recorder>,
+    log_file: Option<LogFile>,
+    timestamps: bool,
 ) -> miette::Result<()> {
     if interactive_mode {
         println!("Commands:");
         println!("    CTRL+R    Reset chip");
+        println!("    CTRL+T    Insert a marker");
         println!("    CTRL+C    Exit");
         println!();
     } else {
@@ -100,26 +149,67 @@ pub fn monitor(
     let _raw_mode = RawModeGuard::new();
 
     let stdout = stdout();
-    let mut stdout = ResolvingPrinter::new(elf, stdout.lock());
+    let output = Timestamped::new(Tee::new(stdout.lock(), log_file), timestamps);
+    let mut stdout = ResolvingPrinter::new(elf, output).with_encoding(encoding);
 
-    let mut parser: Box<dyn InputParser> = match log_format {
-        LogFormat::Defmt => Box::new(parser::esp_defmt::EspDefmt::new(elf)?),
-        LogFormat::Serial => Box::new(parser::serial::Serial),
-    };
+    let mut parser = input_parser(log_format, channels, elf)?;
 
     let mut external_processors = ExternalProcessors::new(processors, elf_file)?;
+    let mut rules = Rules::new(rules);
+    let mut markers = Markers::new();
 
-    let mut buff = [0; 1024];
+    let port_name = serial.name().unwrap_or_default();
+    let mut reader =
+        SerialReader::spawn(serial.try_clone_native().into_diagnostic()?).into_diagnostic()?;
+    let mut total_dropped = 0;
+
+    let mut buff = Vec::new();
     loop {
-        let read_count = match serial.read(&mut buff) {
-            Ok(count) => Ok(count),
-            Err(e) if e.kind() == ErrorKind::TimedOut => Ok(0),
-            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
-            err => err.into_diagnostic(),
-        }?;
+        let dropped = match reader.read(&mut buff, Duration::from_millis(5)) {
+            Ok(dropped) => dropped,
+            Err(e) if is_disconnected(&e) && !port_name.is_empty() => {
+                // Stop reading the stale port before opening it again
+                drop(reader);
+
+                let Some(reopened) = reopen(&port_name, baud, interactive_mode, &mut stdout)?
+                else {
+                    break;
+                };
+                serial = reopened;
+                reader = SerialReader::spawn(serial.try_clone_native().into_diagnostic()?)
+                    .into_diagnostic()?;
+                continue;
+            }
+            Err(e) => return Err(e).into_diagnostic(),
+        };
+
+        if dropped > 0 {
+            total_dropped += dropped;
+            write!(
+                stdout,
+                "\r\n[{dropped} bytes of output dropped, the monitor could not keep up]\r\n"
+            )
+            .ok();
+        }
 
-        let processed = external_processors.process(&buff[0..read_count]);
-        parser.feed(&processed, &mut stdout);
+        if let Some(recorder) = &mut recorder {
+            if !buff.is_empty() {
+                recorder.record(&buff)?;
+            }
+        }
+
+        let processed = external_processors.process(&buff);
+        parser.feed(&processed, &mut rules.matching(&mut stdout));
+
+        for action in rules.run_triggered(&mut stdout) {
+            if action == RuleAction::Reset {
+                reset_after_flash(&mut serial, pid).into_diagnostic()?;
+            }
+        }
+
+        for marker in markers.signalled() {
+            write!(stdout, "\r\n{marker}\r\n").ok();
+        }
 
         // Don't forget to flush the writer!
         stdout.flush().ok();
@@ -134,22 +224,157 @@ pub fn monitor(
                                 reset_after_flash(&mut serial, pid).into_diagnostic()?;
                                 continue;
                             }
+                            KeyCode::Char('t') => {
+                                write!(stdout, "\r\n{}\r\n", markers.next()).ok();
+                                stdout.flush().ok();
+                                continue;
+                            }
                             _ => {}
                         }
                     }
 
                     if let Some(bytes) = handle_key_event(key) {
-                        serial.write_all(&bytes).into_diagnostic()?;
-                        serial.flush().into_diagnostic()?;
+                        match serial.write_all(&bytes).and_then(|_| serial.flush()) {
+                            // Reading fails as well, which reopens the port
+                            Err(e) if is_disconnected(&e) => {}
+                            result => result.into_diagnostic()?,
+                        }
                     }
                 }
             }
         }
     }
 
+    if total_dropped > 0 {
+        write!(
+            stdout,
+            "\r\n[{total_dropped} bytes of output were dropped in total]\r\n"
+        )
+        .ok();
+        stdout.flush().ok();
+    }
+
     Ok(())
 }
 
+/// Replay a recorded session through the same decoding as the live monitor
+///
+/// The output is replayed with its original timing, sped up by `speed`, or
+/// without any delays if `speed` is zero.
+#[allow(clippy::too_many_arguments)]
+pub fn replay(
+    path: &Path,
+    elf: Option<&[u8]>,
+    log_format: LogFormat,
+    channels: &ChannelRoutes,
+    encoding: &'static Encoding,
+    processors: Option<String>,
+    elf_file: Option<PathBuf>,
+    rules: Vec<Rule>,
+    speed: f64,
+) -> miette::Result<()> {
+    let mut session = Session::open(path)?;
+    let metadata = session.metadata().clone();
+
+    println!(
+        "Replaying a session of {} at {} baud",
+        metadata.chip, metadata.baud
+    );
+    match (&metadata.elf_sha256, elf) {
+        (Some(recorded), Some(elf)) if *recorded != elf_digest(elf) => {
+            warn!("The ELF file differs from the one used during the recorded session")
+        }
+        (None, Some(_)) => warn!("The recorded session does not identify its ELF file"),
+        _ => {}
+    }
+
+    let stdout = stdout();
+    let mut stdout = ResolvingPrinter::new(elf, stdout.lock()).with_encoding(encoding);
+    let mut parser = input_parser(log_format, channels, elf)?;
+    let mut external_processors = ExternalProcessors::new(processors, elf_file)?;
+    let mut rules = Rules::new(rules);
+
+    let start = Instant::now();
+    while let Some((timestamp, data)) = session.next_record()? {
+        if speed > 0.0 {
+            let due =
+                Duration::try_from_secs_f64(timestamp.as_secs_f64() / speed).into_diagnostic()?;
+            if let Some(delay) = due.checked_sub(start.elapsed()) {
+                sleep(delay);
+            }
+        }
+
+        let processed = external_processors.process(&data);
+        parser.feed(&processed, &mut rules.matching(&mut stdout));
+        // There is no device to reset, other actions are taken as usual
+        rules.run_triggered(&mut stdout);
+
+        stdout.flush().ok();
+    }
+
+    Ok(())
+}
+
+/// The parser for the given log format
+fn input_parser(
+    log_format: LogFormat,
+    channels: &ChannelRoutes,
+    elf: Option<&[u8]>,
+) -> Result<Box<dyn InputParser>> {
+    Ok(match log_format {
+        LogFormat::Auto => Box::new(parser::auto::Auto::new(elf)),
+        LogFormat::Channels => Box::new(parser::channels::Channels::new(channels, elf)?),
+        LogFormat::Defmt => Box::new(parser::esp_defmt::EspDefmt::new(elf)?),
+        LogFormat::Serial => Box::new(parser::serial::Serial),
+    })
+}
+
+/// Wait for a disconnected serial port to reappear at the same path, e.g.
+/// after the host resumed from a suspend, and open it again
+///
+/// Returns `None` if the user exits while waiting.
+fn reopen(
+    port_name: &str,
+    baud: u32,
+    interactive_mode: bool,
+    out: &mut dyn Write,
+) -> Result<Option<Port>> {
+    write!(
+        out,
+        "\r\n[{port_name} was disconnected, waiting for it to reappear...]\r\n"
+    )
+    .ok();
+    out.flush().ok();
+
+    let start = Instant::now();
+    while start.elapsed() < RECONNECT_TIMEOUT {
+        if let Ok(mut serial) = Port::open(port_name, baud) {
+            serial
+                .set_timeout(Duration::from_millis(5))
+                .into_diagnostic()?;
+            write!(out, "[reconnected to {port_name}]\r\n").ok();
+            out.flush().ok();
+
+            return Ok(Some(serial));
+        }
+
+        if interactive_mode && poll(RECONNECT_INTERVAL).into_diagnostic()? {
+            if let Event::Key(key) = read().into_diagnostic()? {
+                if key.kind == KeyEventKind::Press
+                    && key.modifiers.contains(KeyModifiers::CONTROL)
+                    && key.code == KeyCode::Char('c')
+                {
+                    return Ok(None);
+                }
+            }
+        } else if !interactive_mode {
+            sleep(RECONNECT_INTERVAL);
+        }
+    }
+
+    Err(Error::SerialPortDisconnected(port_name.into())).into_diagnostic()
+}
+
 // Converts key events from crossterm into appropriate character/escape
 // sequences which are then sent over the serial connection.
 //
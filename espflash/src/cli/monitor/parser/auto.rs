@@ -0,0 +1,143 @@
+use std::io::Write;
+
+use crate::{
+    cli::monitor::parser::{esp_defmt::EspDefmt, InputParser},
+    monitor::FRAME_START,
+};
+
+/// Passes output through as text until it contains a defmt frame, and decodes
+/// defmt frames from then on
+///
+/// Text is still passed through after switching, as esp-println interleaves
+/// defmt frames with plain text output.
+pub struct Auto {
+    /// Decoder for the defmt table of the ELF file, if it has one
+    defmt: Option<EspDefmt>,
+    has_elf: bool,
+    detected: bool,
+    /// Whether the last byte fed was the first byte of a frame start marker
+    pending: bool,
+}
+
+impl Auto {
+    pub fn new(elf: Option<&[u8]>) -> Self {
+        Self {
+            defmt: EspDefmt::new(elf).ok(),
+            has_elf: elf.is_some(),
+            detected: false,
+            pending: false,
+        }
+    }
+
+    /// Position of the first frame start marker, including one split across
+    /// the previous and the current call
+    fn find_frame_start(&self, bytes: &[u8]) -> Option<usize> {
+        if self.pending && bytes.first() == Some(&FRAME_START[1]) {
+            return Some(0);
+        }
+
+        bytes
+            .windows(FRAME_START.len())
+            .position(|window| window == FRAME_START)
+    }
+}
+
+impl InputParser for Auto {
+    fn feed(&mut self, bytes: &[u8], out: &mut dyn Write) {
+        if self.detected {
+            if let Some(defmt) = &mut self.defmt {
+                defmt.feed(bytes, out);
+            } else {
+                out.write_all(bytes).unwrap();
+            }
+
+            return;
+        }
+
+        let Some(start) = self.find_frame_start(bytes) else {
+            // Hold back a trailing 0xFF, it might start a frame
+            let split = bytes.len() - usize::from(bytes.ends_with(&FRAME_START[..1]));
+            if self.pending {
+                out.write_all(&FRAME_START[..1]).unwrap();
+            }
+            out.write_all(&bytes[..split]).unwrap();
+            self.pending = split < bytes.len();
+
+            return;
+        };
+
+        // The marker may have started with the byte held back from the last call
+        let split_marker = self.pending && bytes.first() == Some(&FRAME_START[1]);
+        if self.pending && !split_marker {
+            out.write_all(&FRAME_START[..1]).unwrap();
+        }
+
+        let (text, frames) = bytes.split_at(start);
+        out.write_all(text).unwrap();
+        self.detected = true;
+
+        match &mut self.defmt {
+            Some(defmt) => {
+                out.write_all(
+                    b"\r\n[defmt output detected, decoding it; use `-L serial` to show it as is]\r\n",
+                )
+                .unwrap();
+
+                if split_marker {
+                    defmt.feed(&FRAME_START[..1], out);
+                }
+                defmt.feed(frames, out);
+            }
+            None => {
+                let notice: &[u8] = if self.has_elf {
+                    b"\r\n[the output looks like defmt, but the ELF file has no defmt data to decode it]\r\n"
+                } else {
+                    b"\r\n[the output looks like defmt, pass the ELF file to decode it]\r\n"
+                };
+                out.write_all(notice).unwrap();
+
+                if split_marker {
+                    out.write_all(&FRAME_START[..1]).unwrap();
+                }
+                out.write_all(frames).unwrap();
+            }
+        }
+        self.pending = false;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn feed_all(parser: &mut Auto, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in chunks {
+            parser.feed(chunk, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn text_is_passed_through() {
+        let mut parser = Auto::new(None);
+
+        let out = feed_all(&mut parser, &[b"hello\xFF", b" world\r\n"]);
+        assert_eq!(out, b"hello\xFF world\r\n");
+        assert!(!parser.detected);
+    }
+
+    #[test]
+    fn frame_start_is_detected_across_chunks() {
+        let mut parser = Auto::new(None);
+
+        let out = feed_all(&mut parser, &[b"boot\xFF", b"\x00frame\x00"]);
+        assert!(parser.detected);
+        assert!(out.starts_with(b"boot\r\n[the output looks like defmt, pass the ELF"));
+        assert!(out.ends_with(b"\xFF\x00frame\x00"));
+
+        let mut parser = Auto::new(None);
+        let out = feed_all(&mut parser, &[b"a\xFF", b"b\xFF\x00"]);
+        assert!(out.starts_with(b"a\xFFb\r\n"));
+    }
+}
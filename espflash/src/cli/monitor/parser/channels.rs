@@ -0,0 +1,280 @@
+//! Output which is split into channels, each decoded on its own
+//!
+//! Firmware can send several streams over the same serial port, e.g. defmt
+//! logs along with plain text, by wrapping the data of each stream in frames
+//! of the byte `0xFE`, the number of the channel, the length of the data and
+//! up to 255 bytes of data. `0xFE` does not occur in UTF-8 text, so output
+//! outside of frames, e.g. of the bootloader, is passed on as channel 0.
+//!
+//! Each channel is routed to a decoder, whose output is shown on the terminal,
+//! or written to a file as it is. Routes are given as `CHANNEL=ROUTE`, where
+//! `ROUTE` is one of:
+//!
+//! - `auto`, `defmt` or `serial`: decode the channel like the log format of the
+//!   same name
+//! - `file:PATH`: write the data of the channel to the file at `PATH`
+//! - `drop`: discard the data of the channel
+//!
+//! Channel 0 is decoded as `auto` unless it is routed, all other channels as
+//! `serial`.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use log::warn;
+use miette::{IntoDiagnostic, Result};
+
+use crate::{
+    cli::monitor::parser::{auto::Auto, esp_defmt::EspDefmt, serial::Serial, InputParser},
+    error::Error,
+};
+
+/// First byte of a frame
+pub const FRAME_MARKER: u8 = 0xFE;
+
+/// Where the data of a channel goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Route {
+    /// Detect defmt frames, showing everything else as text
+    Auto,
+    /// Decode defmt frames
+    Defmt,
+    /// Show the data as text
+    Serial,
+    /// Write the data to a file as it is
+    File(PathBuf),
+    /// Discard the data
+    Drop,
+}
+
+impl FromStr for Route {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "defmt" => Ok(Self::Defmt),
+            "serial" => Ok(Self::Serial),
+            "drop" => Ok(Self::Drop),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(Self::File(path.into())),
+                _ => Err(Error::InvalidChannelRoute(format!(
+                    "unknown route '{s}', expected 'auto', 'defmt', 'serial', 'file:PATH' or 'drop'"
+                ))),
+            },
+        }
+    }
+}
+
+/// The routes of the channels, by channel number
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelRoutes(BTreeMap<u8, Route>);
+
+impl ChannelRoutes {
+    /// Parse routes given as `CHANNEL=ROUTE`, later routes of a channel
+    /// replacing earlier ones
+    pub fn parse_all(values: impl IntoIterator<Item = impl AsRef<str>>) -> Result<Self, Error> {
+        let mut routes = BTreeMap::new();
+
+        for value in values {
+            let value = value.as_ref();
+            let Some((channel, route)) = value.split_once('=') else {
+                return Err(Error::InvalidChannelRoute(format!(
+                    "expected CHANNEL=ROUTE, found '{value}'"
+                )));
+            };
+            let channel = channel.trim().parse().map_err(|_| {
+                Error::InvalidChannelRoute(format!("invalid channel number '{channel}'"))
+            })?;
+
+            routes.insert(channel, route.trim().parse()?);
+        }
+
+        Ok(Self(routes))
+    }
+
+    /// The route of `channel`, if it was given
+    pub fn get(&self, channel: u8) -> Option<&Route> {
+        self.0.get(&channel)
+    }
+}
+
+/// A channel being decoded
+enum Sink {
+    Parser(Box<dyn InputParser>),
+    File(BufWriter<File>, PathBuf),
+    Drop,
+}
+
+/// Position in the framing of the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Outside of a frame
+    Text,
+    /// After the marker of a frame, expecting the channel number
+    Channel,
+    /// Expecting the length of the data
+    Length(u8),
+    /// Within the data of a frame
+    Data { channel: u8, remaining: usize },
+}
+
+/// Splits the output into its channels, and passes the data of each channel on
+/// to its route
+pub struct Channels {
+    sinks: BTreeMap<u8, Sink>,
+    state: State,
+}
+
+impl Channels {
+    pub fn new(routes: &ChannelRoutes, elf: Option<&[u8]>) -> Result<Self> {
+        let mut sinks = BTreeMap::new();
+        for (&channel, route) in &routes.0 {
+            let sink = match route {
+                Route::Auto => Sink::Parser(Box::new(Auto::new(elf))),
+                Route::Defmt => Sink::Parser(Box::new(EspDefmt::new(elf)?)),
+                Route::Serial => Sink::Parser(Box::new(Serial)),
+                Route::File(path) => Sink::File(
+                    BufWriter::new(File::create(path).into_diagnostic()?),
+                    path.clone(),
+                ),
+                Route::Drop => Sink::Drop,
+            };
+            sinks.insert(channel, sink);
+        }
+
+        sinks
+            .entry(0)
+            .or_insert_with(|| Sink::Parser(Box::new(Auto::new(elf))));
+
+        Ok(Self {
+            sinks,
+            state: State::Text,
+        })
+    }
+
+    fn route(&mut self, channel: u8, data: &[u8], out: &mut dyn Write) {
+        if data.is_empty() {
+            return;
+        }
+
+        match self.sinks.get_mut(&channel) {
+            Some(Sink::Parser(parser)) => parser.feed(data, out),
+            Some(Sink::File(file, path)) => {
+                if let Err(e) = file.write_all(data).and_then(|_| file.flush()) {
+                    warn!(
+                        "Failed to write channel {channel} to {}: {e}",
+                        path.display()
+                    );
+                }
+            }
+            Some(Sink::Drop) => {}
+            None => out.write_all(data).unwrap(),
+        }
+    }
+}
+
+impl InputParser for Channels {
+    fn feed(&mut self, bytes: &[u8], out: &mut dyn Write) {
+        let mut rest = bytes;
+
+        while let Some(&byte) = rest.first() {
+            match self.state {
+                State::Text => {
+                    let end = rest
+                        .iter()
+                        .position(|&b| b == FRAME_MARKER)
+                        .unwrap_or(rest.len());
+                    self.route(0, &rest[..end], out);
+
+                    if end < rest.len() {
+                        self.state = State::Channel;
+                        rest = &rest[end + 1..];
+                    } else {
+                        rest = &[];
+                    }
+                }
+                State::Channel => {
+                    self.state = State::Length(byte);
+                    rest = &rest[1..];
+                }
+                State::Length(channel) => {
+                    self.state = match byte {
+                        0 => State::Text,
+                        len => State::Data {
+                            channel,
+                            remaining: len as usize,
+                        },
+                    };
+                    rest = &rest[1..];
+                }
+                State::Data { channel, remaining } => {
+                    let len = remaining.min(rest.len());
+                    self.route(channel, &rest[..len], out);
+
+                    self.state = if len == remaining {
+                        State::Text
+                    } else {
+                        State::Data {
+                            channel,
+                            remaining: remaining - len,
+                        }
+                    };
+                    rest = &rest[len..];
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_routed_by_channel() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = dir.path().join("raw.bin");
+
+        let routes = ChannelRoutes::parse_all([
+            "0=serial".to_string(),
+            format!("1=file:{}", raw.display()),
+            "2=drop".into(),
+        ])
+        .unwrap();
+        let mut channels = Channels::new(&routes, None).unwrap();
+
+        let mut out = Vec::new();
+        let mut input = b"boot\n".to_vec();
+        input.extend([FRAME_MARKER, 1, 3, 0xFF, 0x00, 0x01]);
+        input.extend([FRAME_MARKER, 2, 4]);
+        input.extend(b"gone");
+        input.extend([FRAME_MARKER, 3, 6]);
+        input.extend(b"three\n");
+        input.extend(b"text");
+
+        // Split the input, so that frames span several reads
+        for chunk in input.chunks(4) {
+            channels.feed(chunk, &mut out);
+        }
+        drop(channels);
+
+        assert_eq!(out, b"boot\nthree\ntext");
+        assert_eq!(std::fs::read(&raw).unwrap(), [0xFF, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn invalid_routes_are_rejected() {
+        assert!(ChannelRoutes::parse_all(["1=defmt", "1=file:out.bin"]).is_ok());
+        assert!(ChannelRoutes::parse_all(["1"]).is_err());
+        assert!(ChannelRoutes::parse_all(["256=serial"]).is_err());
+        assert!(ChannelRoutes::parse_all(["1=file:"]).is_err());
+        assert!(ChannelRoutes::parse_all(["1=rtt"]).is_err());
+    }
+}
@@ -1,54 +1,41 @@
+pub mod auto;
+pub mod channels;
 pub mod esp_defmt;
 pub mod serial;
 
-use std::{borrow::Cow, io::Write};
+use std::io::Write;
 
 use crossterm::{
     style::{Color, Print, PrintStyledContent, Stylize},
     QueueableCommand,
 };
-use lazy_static::lazy_static;
-use regex::Regex;
+use encoding_rs::{Decoder, Encoding, UTF_8};
 
-use crate::cli::monitor::{line_endings::normalized, symbols::Symbols};
+use crate::{
+    cli::monitor::{dumps::Dumps, line_endings::normalized},
+    monitor::{addresses, symbols::Symbols, LineBuffer},
+};
 
 pub trait InputParser {
     fn feed(&mut self, bytes: &[u8], out: &mut dyn Write);
 }
 
-// Pattern to much a function address in serial output.
-lazy_static! {
-    static ref RE_FN_ADDR: Regex = Regex::new(r"0x[[:xdigit:]]{8}").unwrap();
-}
-
-fn resolve_addresses(
-    symbols: &Symbols<'_>,
-    line: &str,
-    out: &mut dyn Write,
-) -> std::io::Result<()> {
+fn resolve_addresses(symbols: &Symbols, line: &str, out: &mut dyn Write) -> std::io::Result<()> {
     // Check the previous line for function addresses. For each address found,
     // attempt to look up the associated function's name and location and write both
     // to the terminal.
-    for matched in RE_FN_ADDR.find_iter(line).map(|m| m.as_str()) {
-        // Since our regular expression already confirms that this is a correctly
-        // formatted hex literal, we can (fairly) safely assume that it will parse
-        // successfully into an integer.
-        let addr = parse_int::parse::<u64>(matched).unwrap();
-
-        let name = symbols.get_name(addr);
-        let location = symbols.get_location(addr);
+    for (matched, addr) in addresses(line) {
+        if let Some(resolved) = symbols.lookup(addr) {
+            let name = resolved.function;
+            let location = match resolved.location {
+                Some((file, line_num)) => format!("{file}:{line_num}"),
+                None => String::from("??:??"),
+            };
 
-        if let Some(name) = name {
             let output = if line.trim() == format!("0x{:x}", addr) {
-                if let Some((file, line_num)) = location {
-                    format!("{name}\r\n    at {file}:{line_num}\r\n")
-                } else {
-                    format!("{name}\r\n    at ??:??\r\n")
-                }
-            } else if let Some((file, line_num)) = location {
-                format!("{matched} - {name}\r\n    at {file}:{line_num}\r\n")
+                format!("{name}\r\n    at {location}\r\n")
             } else {
-                format!("{matched} - {name}\r\n    at ??:??\r\n")
+                format!("{matched} - {name}\r\n    at {location}\r\n")
             };
 
             out.queue(PrintStyledContent(output.with(Color::Yellow)))?;
@@ -104,53 +91,84 @@ impl Utf8Merger {
     }
 }
 
-pub struct ResolvingPrinter<'ctx, W: Write> {
+/// Decodes the text output of a device, keeping characters which are split
+/// across reads until they are complete
+enum TextDecoder {
+    Utf8(Utf8Merger),
+    /// Any other encoding, e.g. GB2312 or latin-1
+    Other(Decoder),
+}
+
+impl TextDecoder {
+    fn new(encoding: &'static Encoding) -> Self {
+        if encoding == UTF_8 {
+            Self::Utf8(Utf8Merger::new())
+        } else {
+            Self::Other(encoding.new_decoder_without_bom_handling())
+        }
+    }
+
+    fn decode(&mut self, buff: &[u8]) -> String {
+        match self {
+            Self::Utf8(merger) => merger.process_utf8(buff),
+            Self::Other(decoder) => {
+                let input = normalized(buff.iter().copied()).collect::<Vec<_>>();
+                let capacity = decoder
+                    .max_utf8_buffer_length(input.len())
+                    .unwrap_or(input.len() * 3);
+                let mut text = String::with_capacity(capacity);
+                let _ = decoder.decode_to_string(&input, &mut text, false);
+
+                text
+            }
+        }
+    }
+}
+
+pub struct ResolvingPrinter<W: Write> {
     writer: W,
-    symbols: Option<Symbols<'ctx>>,
-    merger: Utf8Merger,
-    line_fragment: String,
+    symbols: Option<Symbols>,
+    decoder: TextDecoder,
+    lines: LineBuffer,
+    dumps: Dumps,
 }
 
-impl<'ctx, W: Write> ResolvingPrinter<'ctx, W> {
-    pub fn new(elf: Option<&'ctx [u8]>, writer: W) -> Self {
+impl<W: Write> ResolvingPrinter<W> {
+    pub fn new(elf: Option<&[u8]>, writer: W) -> Self {
         Self {
             writer,
             symbols: elf.and_then(|elf| Symbols::try_from(elf).ok()),
-            merger: Utf8Merger::new(),
-            line_fragment: String::new(),
+            decoder: TextDecoder::new(UTF_8),
+            lines: LineBuffer::default(),
+            dumps: Dumps::default(),
         }
     }
+
+    /// Decode the output of the device as `encoding` instead of UTF-8
+    pub fn with_encoding(mut self, encoding: &'static Encoding) -> Self {
+        self.decoder = TextDecoder::new(encoding);
+        self
+    }
 }
 
-impl<W: Write> Write for ResolvingPrinter<'_, W> {
+impl<W: Write> Write for ResolvingPrinter<W> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        let text = self.merger.process_utf8(buf);
-
-        // Split the text into lines, storing the last of which separately if it is
-        // incomplete (ie. does not end with '\n') because these need special handling.
-        let mut lines = text.lines().collect::<Vec<_>>();
-        let incomplete = if text.ends_with('\n') {
-            None
-        } else {
-            lines.pop()
-        };
-
-        // Iterate through all *complete* lines (ie. those ending with '\n') ...
-        for line in lines {
-            // ... and print the line.
-            self.writer.queue(Print(line))?;
-
-            // If there is a previous line fragment, that means that the current line must
-            // be appended to it in order to form the complete line. Since we want to look
-            // for function addresses in the *entire* previous line we combine these prior
-            // to performing the symbol lookup(s).
-            let fragment = std::mem::take(&mut self.line_fragment);
-            let line = if fragment.is_empty() {
-                Cow::from(line)
-            } else {
-                // The previous fragment has been completed (by this current line).
-                Cow::from(format!("{fragment}{line}"))
+        let text = self.decoder.decode(buf);
+
+        // Split the text into lines, joining the incomplete line of the previous
+        // call with the first line of this one.
+        for part in self.lines.split(text.as_bytes()) {
+            // Print every part as soon as possible, splitting at line endings keeps
+            // the text valid UTF-8.
+            self.writer
+                .queue(Print(String::from_utf8_lossy(part.text)))?;
+
+            // Incomplete lines are not terminated, nor searched for function
+            // addresses until they are complete.
+            let Some(line) = part.line else {
+                continue;
             };
+            let line = String::from_utf8_lossy(&line);
 
             // Remember to begin a new line after we have printed this one!
             self.writer.queue(Print("\r\n"))?;
@@ -160,15 +178,11 @@ impl<W: Write> Write for ResolvingPrinter<'_, W> {
                 // Try to print the names of addresses in the current line.
                 resolve_addresses(symbols, &line, &mut self.writer)?;
             }
-        }
-
-        // If there is an incomplete line we will still print it. However, we will not
-        // perform function name lookups or terminate it with a newline.
-        if let Some(line) = incomplete {
-            self.writer.queue(Print(line))?;
 
-            let fragment = std::mem::take(&mut self.line_fragment);
-            self.line_fragment = format!("{fragment}{line}");
+            // Summarize any diagnostic dump completed by this line.
+            if let Some(table) = self.dumps.feed(&line, self.symbols.as_ref()) {
+                self.writer.queue(Print(table))?;
+            }
         }
 
         Ok(buf.len())
@@ -181,7 +195,7 @@ impl<W: Write> Write for ResolvingPrinter<'_, W> {
 
 #[cfg(test)]
 mod test {
-    use super::Utf8Merger;
+    use super::{TextDecoder, Utf8Merger};
 
     #[test]
     fn returns_valid_strings_immediately() {
@@ -251,4 +265,18 @@ mod test {
 
         assert_eq!(result, "Hello world! with UTF: wysyłam\r\n");
     }
+
+    #[test]
+    fn decodes_other_encodings_across_reads() {
+        let mut decoder = TextDecoder::new(encoding_rs::GBK);
+        let mut result = String::new();
+
+        // "你好" in GB2312, split within the second character
+        result.push_str(&decoder.decode(&[0xC4, 0xE3, 0xBA]));
+        result.push_str(&decoder.decode(&[0xC3, b'\n']));
+        assert_eq!(result, "你好\r\n");
+
+        let mut decoder = TextDecoder::new(encoding_rs::WINDOWS_1252);
+        assert_eq!(decoder.decode(b"caf\xE9"), "café");
+    }
 }
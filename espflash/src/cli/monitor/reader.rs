@@ -0,0 +1,152 @@
+//! Background reading of the serial port
+//!
+//! Decoding and printing the output of the device can temporarily fall behind
+//! (e.g. while resolving addresses, or when the terminal is slow). To avoid
+//! losing data in the (small) OS serial buffers in the meantime, the serial
+//! port is read on a dedicated thread into a large ring buffer. Should the
+//! ring buffer overflow anyway, the oldest data is discarded and the number of
+//! lost bytes is reported to the monitor.
+
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use serialport::SerialPort;
+
+use crate::connection::Port;
+
+/// Size of the ring buffer holding data which has not been processed yet
+const RING_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Default)]
+struct State {
+    buffer: VecDeque<u8>,
+    dropped: usize,
+    error: Option<io::Error>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    available: Condvar,
+    stop: AtomicBool,
+}
+
+/// Reads a serial port on a background thread
+pub(crate) struct SerialReader {
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl SerialReader {
+    /// Start reading from the given serial port
+    pub fn spawn(mut serial: Port) -> io::Result<Self> {
+        serial.set_timeout(Duration::from_millis(5))?;
+
+        let shared = Arc::new(Shared::default());
+        let handle = thread::Builder::new().name("serial-reader".into()).spawn({
+            let shared = shared.clone();
+            move || read_loop(&mut serial, &shared)
+        })?;
+
+        Ok(Self {
+            shared,
+            handle: Some(handle),
+        })
+    }
+
+    /// Wait up to `timeout` for data, and move all available data into `out`
+    ///
+    /// Returns the number of bytes that were dropped due to the ring buffer
+    /// overflowing since the last call.
+    pub fn read(&self, out: &mut Vec<u8>, timeout: Duration) -> io::Result<usize> {
+        let state = self.shared.state.lock().unwrap();
+        let (mut state, _) = self
+            .shared
+            .available
+            .wait_timeout_while(state, timeout, |state| {
+                state.buffer.is_empty() && state.dropped == 0 && state.error.is_none()
+            })
+            .unwrap();
+
+        out.clear();
+        out.extend(state.buffer.drain(..));
+
+        if out.is_empty() {
+            if let Some(error) = state.error.take() {
+                return Err(error);
+            }
+        }
+
+        Ok(std::mem::take(&mut state.dropped))
+    }
+}
+
+impl Drop for SerialReader {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.join().ok();
+        }
+    }
+}
+
+fn read_loop(serial: &mut Port, shared: &Shared) {
+    let mut buff = [0; 4096];
+
+    while !shared.stop.load(Ordering::Relaxed) {
+        let read_count = match serial.read(&mut buff) {
+            Ok(count) => count,
+            Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                shared.state.lock().unwrap().error = Some(e);
+                shared.available.notify_one();
+                return;
+            }
+        };
+
+        let mut state = shared.state.lock().unwrap();
+        push_bounded(&mut state, &buff[..read_count], RING_BUFFER_SIZE);
+        shared.available.notify_one();
+    }
+}
+
+/// Append data to the ring buffer, discarding the oldest data if the capacity
+/// is exceeded
+fn push_bounded(state: &mut State, data: &[u8], capacity: usize) {
+    state.buffer.extend(data);
+
+    let overflow = state.buffer.len().saturating_sub(capacity);
+    if overflow > 0 {
+        state.buffer.drain(..overflow);
+        state.dropped += overflow;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{push_bounded, State};
+
+    #[test]
+    fn ring_buffer_drops_oldest_data() {
+        let mut state = State::default();
+
+        push_bounded(&mut state, b"hello", 8);
+        assert_eq!(state.dropped, 0);
+
+        push_bounded(&mut state, b" world", 8);
+        assert_eq!(state.dropped, 3);
+        assert_eq!(
+            state.buffer.iter().copied().collect::<Vec<_>>(),
+            b"lo world"
+        );
+    }
+}
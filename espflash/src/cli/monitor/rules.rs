@@ -0,0 +1,262 @@
+//! Actions triggered by patterns in the monitor output
+//!
+//! Rules are given on the command line as `--on PATTERN ACTION`, where
+//! `PATTERN` is a regular expression matched against each line of output and
+//! `ACTION` is one of:
+//!
+//! - `run:COMMAND`: run a command on the host, with the matching line in the
+//!   `ESPFLASH_MATCH` environment variable
+//! - `mark`: insert a marker line into the output
+//! - `reset`: reset the device
+//!
+//! Lines are matched as they are displayed, i.e. after decoding `defmt` frames
+//! and with any terminal escape sequences removed.
+
+use std::{
+    io::{self, Write},
+    process::Command,
+    str::FromStr,
+    thread,
+};
+
+use lazy_static::lazy_static;
+use log::warn;
+use regex::Regex;
+
+use crate::error::Error;
+
+/// Longest line which is buffered for matching, longer lines are truncated
+const MAX_LINE_LENGTH: usize = 4096;
+
+lazy_static! {
+    pub(crate) static ref RE_ANSI_ESCAPE: Regex = Regex::new(r"\x1b\[[0-9;?]*[ -/]*[@-~]").unwrap();
+}
+
+/// Action to take when a rule matches
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RuleAction {
+    /// Run a command on the host
+    Run(String),
+    /// Insert a marker line into the output
+    Mark,
+    /// Reset the device
+    Reset,
+}
+
+impl FromStr for RuleAction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mark" => Ok(Self::Mark),
+            "reset" => Ok(Self::Reset),
+            _ => match s.strip_prefix("run:") {
+                Some(command) if !command.trim().is_empty() => Ok(Self::Run(command.into())),
+                _ => Err(Error::InvalidMonitorRule(format!(
+                    "unknown action '{s}', expected 'run:COMMAND', 'mark' or 'reset'"
+                ))),
+            },
+        }
+    }
+}
+
+/// A pattern and the action to take when a line of output matches it
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub pattern: Regex,
+    pub action: RuleAction,
+}
+
+impl Rule {
+    /// Parse a rule from its pattern and action
+    pub fn new(pattern: &str, action: &str) -> Result<Self, Error> {
+        let pattern = Regex::new(pattern)
+            .map_err(|e| Error::InvalidMonitorRule(format!("invalid pattern '{pattern}': {e}")))?;
+
+        Ok(Self {
+            pattern,
+            action: action.parse()?,
+        })
+    }
+
+    /// Parse rules from the values of the `--on PATTERN ACTION` option
+    pub fn parse_all(values: &[String]) -> Result<Vec<Self>, Error> {
+        values
+            .chunks(2)
+            .map(|rule| match rule {
+                [pattern, action] => Self::new(pattern, action),
+                _ => Err(Error::InvalidMonitorRule(
+                    "expected a pattern and an action".into(),
+                )),
+            })
+            .collect()
+    }
+}
+
+/// Matches the monitor output against a set of rules, line by line
+#[derive(Debug, Default)]
+pub(crate) struct Rules {
+    rules: Vec<Rule>,
+    line: Vec<u8>,
+    triggered: Vec<(usize, String)>,
+    marks: usize,
+}
+
+impl Rules {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self {
+            rules,
+            ..Default::default()
+        }
+    }
+
+    /// Wrap a writer, so that everything written to it is matched against the
+    /// rules
+    pub fn matching<'a, W: Write + ?Sized>(&'a mut self, inner: &'a mut W) -> RulesWriter<'a, W> {
+        RulesWriter { inner, rules: self }
+    }
+
+    fn scan(&mut self, data: &[u8]) {
+        if self.rules.is_empty() {
+            return;
+        }
+
+        for &byte in data {
+            if byte == b'\n' {
+                let line = String::from_utf8_lossy(&self.line);
+                let line = RE_ANSI_ESCAPE.replace_all(line.trim_end_matches('\r'), "");
+
+                for (index, rule) in self.rules.iter().enumerate() {
+                    if rule.pattern.is_match(&line) {
+                        self.triggered.push((index, line.to_string()));
+                    }
+                }
+
+                self.line.clear();
+            } else if self.line.len() < MAX_LINE_LENGTH {
+                self.line.push(byte);
+            }
+        }
+    }
+
+    /// Take the actions of the rules which matched since the last call,
+    /// together with the matching lines
+    ///
+    /// Markers are written to `out` directly, and commands are run in the
+    /// background, so only the actions which need the serial port (i.e.
+    /// resetting the device) are returned.
+    pub fn run_triggered(&mut self, out: &mut impl Write) -> Vec<RuleAction> {
+        let mut pending = Vec::new();
+
+        for (index, line) in std::mem::take(&mut self.triggered) {
+            let rule = &self.rules[index];
+            match &rule.action {
+                RuleAction::Run(command) => run_command(command, &line),
+                RuleAction::Mark => {
+                    self.marks += 1;
+                    write!(
+                        out,
+                        "\r\n[espflash] ==== MARK {} ({}) ====\r\n",
+                        self.marks, rule.pattern
+                    )
+                    .ok();
+                }
+                action => pending.push(action.clone()),
+            }
+        }
+
+        pending
+    }
+}
+
+/// Writer which forwards everything to the wrapped writer, matching it against
+/// the rules on the way
+pub(crate) struct RulesWriter<'a, W: Write + ?Sized> {
+    inner: &'a mut W,
+    rules: &'a mut Rules,
+}
+
+impl<W: Write + ?Sized> Write for RulesWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.rules.scan(&buf[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn run_command(command: &str, line: &str) {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+
+    match shell.arg(command).env("ESPFLASH_MATCH", line).spawn() {
+        // Don't block the monitor while the command runs, but do reap it
+        Ok(mut child) => {
+            thread::spawn(move || child.wait());
+        }
+        Err(e) => warn!("Failed to run '{command}': {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::{Rule, RuleAction, Rules};
+
+    #[test]
+    fn parse_rules() {
+        let values = [
+            "PANIC:.*",
+            "run:./collect.sh",
+            "READY",
+            "mark",
+            "boot",
+            "reset",
+        ]
+        .map(String::from);
+        let rules = Rule::parse_all(&values).unwrap();
+
+        assert_eq!(rules[0].action, RuleAction::Run("./collect.sh".into()));
+        assert_eq!(rules[1].action, RuleAction::Mark);
+        assert_eq!(rules[2].action, RuleAction::Reset);
+
+        assert!(Rule::new("READY", "run:").is_err());
+        assert!(Rule::new("READY", "explode").is_err());
+        assert!(Rule::new("(", "mark").is_err());
+    }
+
+    #[test]
+    fn rules_match_complete_lines() {
+        let mut rules = Rules::new(vec![
+            Rule::new("^READY$", "mark").unwrap(),
+            Rule::new("PANIC", "reset").unwrap(),
+        ]);
+
+        let mut out = Vec::new();
+        let mut writer = rules.matching(&mut out);
+        writer.write_all(b"booting\r\nREA").unwrap();
+        writer.write_all(b"DY\r\n\x1b[31mPANIC").unwrap();
+        let mut marks = Vec::new();
+        assert_eq!(rules.run_triggered(&mut marks), []);
+        assert!(String::from_utf8(marks).unwrap().contains("MARK 1"));
+
+        let mut writer = rules.matching(&mut out);
+        writer.write_all(b" at 0x42000000\x1b[0m\r\n").unwrap();
+        assert_eq!(rules.run_triggered(&mut Vec::new()), [RuleAction::Reset]);
+
+        assert!(out.starts_with(b"booting\r\nREADY\r\n"));
+    }
+}
@@ -0,0 +1,222 @@
+//! Recording and replaying monitor sessions
+//!
+//! A recording holds the raw output of the device with the time it was read at,
+//! so that replaying it runs the output through the same decoding (defmt,
+//! address resolution, rules) as the live monitor did. This allows analysing a
+//! session after the fact, and attaching an exact reproduction to bug reports.
+//!
+//! The file starts with the line `ESPREC1`, followed by a line of JSON metadata
+//! (see [SessionMetadata]). The rest of the file are records of a little endian
+//! `u64` timestamp in microseconds since the start of the session, a little
+//! endian `u32` length, and that many bytes of output.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{error::Error, targets::Chip};
+
+/// First line of a recording
+const MAGIC: &str = "ESPREC1";
+
+/// Information about the recorded session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct SessionMetadata {
+    /// The chip the output was read from
+    pub chip: String,
+    /// Baud rate of the serial port
+    pub baud: u32,
+    /// SHA-256 digest of the ELF file of the application, if it was known
+    pub elf_sha256: Option<String>,
+}
+
+impl SessionMetadata {
+    pub fn new(chip: Chip, baud: u32, elf: Option<&[u8]>) -> Self {
+        Self {
+            chip: chip.to_string(),
+            baud,
+            elf_sha256: elf.map(elf_digest),
+        }
+    }
+}
+
+/// SHA-256 digest of an ELF file, as stored in the metadata
+pub fn elf_digest(elf: &[u8]) -> String {
+    hex::encode(Sha256::digest(elf))
+}
+
+/// Writes the output of the device to a recording
+pub struct Recorder<W: Write = BufWriter<File>> {
+    writer: W,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Create a recording at `path`
+    pub fn create(path: &Path, metadata: &SessionMetadata) -> Result<Self, Error> {
+        let file =
+            File::create(path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+        Self::new(BufWriter::new(file), metadata)
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    /// Start a recording, writing the header with the given metadata
+    pub fn new(mut writer: W, metadata: &SessionMetadata) -> Result<Self, Error> {
+        let metadata =
+            serde_json::to_string(metadata).map_err(|e| Error::InvalidRecording(e.to_string()))?;
+        writeln!(writer, "{MAGIC}\n{metadata}")?;
+
+        Ok(Self {
+            writer,
+            start: Instant::now(),
+        })
+    }
+
+    /// Record output which was just read from the device
+    pub fn record(&mut self, data: &[u8]) -> Result<(), Error> {
+        let timestamp = self.start.elapsed().as_micros() as u64;
+
+        self.writer.write_all(&timestamp.to_le_bytes())?;
+        self.writer.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.writer.write_all(data)?;
+        // Flush every record, so that the recording is complete up to the point
+        // where the monitor was exited
+        self.writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Reads the output of the device back from a recording
+pub struct Session<R: Read = BufReader<File>> {
+    reader: R,
+    metadata: SessionMetadata,
+}
+
+impl Session {
+    /// Open the recording at `path`
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let file =
+            File::open(path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: BufRead> Session<R> {
+    /// Read the header of a recording
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let invalid = |reason: &str| Error::InvalidRecording(reason.into());
+
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if line.trim_end() != MAGIC {
+            return Err(invalid("the file is not a recorded monitor session"));
+        }
+
+        line.clear();
+        reader.read_line(&mut line)?;
+        let metadata =
+            serde_json::from_str(&line).map_err(|e| Error::InvalidRecording(e.to_string()))?;
+
+        Ok(Self { reader, metadata })
+    }
+
+    /// Metadata of the recorded session
+    pub fn metadata(&self) -> &SessionMetadata {
+        &self.metadata
+    }
+
+    /// Read the next record, returning the time since the start of the session
+    /// and the output, or `None` at the end of the recording
+    pub fn next_record(&mut self) -> Result<Option<(Duration, Vec<u8>)>, Error> {
+        let mut timestamp = [0; 8];
+        match self.reader.read_exact(&mut timestamp) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        // The length is only trusted as far as the recording has data, so a
+        // corrupt one cannot make it allocate up to 4 GB
+        let mut len = [0; 4];
+        let mut data = Vec::new();
+        self.reader
+            .read_exact(&mut len)
+            .and_then(|_| {
+                let len = u32::from_le_bytes(len) as u64;
+                (&mut self.reader).take(len).read_to_end(&mut data)?;
+                if data.len() as u64 == len {
+                    Ok(())
+                } else {
+                    Err(ErrorKind::UnexpectedEof.into())
+                }
+            })
+            .map_err(|_| Error::InvalidRecording("the last record is truncated".into()))?;
+
+        Ok(Some((
+            Duration::from_micros(u64::from_le_bytes(timestamp)),
+            data,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_round_trip() {
+        let metadata = SessionMetadata::new(Chip::Esp32c3, 115_200, Some(b"elf"));
+
+        let mut recording = Vec::new();
+        let mut recorder = Recorder::new(&mut recording, &metadata).unwrap();
+        recorder.record(b"hello").unwrap();
+        recorder.record(b" world\r\n").unwrap();
+
+        let mut session = Session::new(&recording[..]).unwrap();
+        assert_eq!(session.metadata(), &metadata);
+
+        let (first, data) = session.next_record().unwrap().unwrap();
+        assert_eq!(data, b"hello");
+        let (second, data) = session.next_record().unwrap().unwrap();
+        assert_eq!(data, b" world\r\n");
+        assert!(first <= second);
+        assert!(session.next_record().unwrap().is_none());
+
+        recording.truncate(recording.len() - 1);
+        let mut session = Session::new(&recording[..]).unwrap();
+        session.next_record().unwrap();
+        assert!(matches!(
+            session.next_record(),
+            Err(Error::InvalidRecording(_))
+        ));
+
+        assert!(Session::new(&b"not a recording\n"[..]).is_err());
+    }
+
+    #[test]
+    fn record_length_is_not_trusted() {
+        let metadata = SessionMetadata::new(Chip::Esp32c3, 115_200, None);
+        let mut recording = Vec::new();
+        Recorder::new(&mut recording, &metadata).unwrap();
+        recording.extend_from_slice(&0u64.to_le_bytes());
+        recording.extend_from_slice(&u32::MAX.to_le_bytes());
+        recording.extend_from_slice(b"short");
+
+        let mut session = Session::new(&recording[..]).unwrap();
+        assert!(matches!(
+            session.next_record(),
+            Err(Error::InvalidRecording(_))
+        ));
+    }
+}
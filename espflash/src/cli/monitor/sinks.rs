@@ -0,0 +1,236 @@
+//! Where the output of the monitor is written to
+//!
+//! The decoded output of the device is always shown on the terminal, and can be
+//! written to a [LogFile] as well, e.g. to keep the output of long-running
+//! tests. With [Timestamped], each line starts with the time it was received
+//! at on the host.
+
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::error::Error;
+
+/// Writes each line with the time of the host prepended
+pub struct Timestamped<W: Write> {
+    inner: W,
+    enabled: bool,
+    line_start: bool,
+}
+
+impl<W: Write> Timestamped<W> {
+    /// Prepend timestamps to the lines written to `inner`, if `enabled`
+    pub fn new(inner: W, enabled: bool) -> Self {
+        Self {
+            inner,
+            enabled,
+            line_start: true,
+        }
+    }
+}
+
+impl<W: Write> Write for Timestamped<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !self.enabled {
+            return self.inner.write(buf);
+        }
+
+        for line in buf.split_inclusive(|&b| b == b'\n') {
+            if self.line_start {
+                let timestamp = humantime::format_rfc3339_millis(SystemTime::now());
+                write!(self.inner, "[{timestamp}] ")?;
+            }
+            self.inner.write_all(line)?;
+            self.line_start = line.ends_with(b"\n");
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes the output to the terminal and to a log file, if there is one
+pub struct Tee<W: Write> {
+    terminal: W,
+    log_file: Option<LogFile>,
+}
+
+impl<W: Write> Tee<W> {
+    pub fn new(terminal: W, log_file: Option<LogFile>) -> Self {
+        Self { terminal, log_file }
+    }
+}
+
+impl<W: Write> Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.terminal.write_all(buf)?;
+        if let Some(log_file) = &mut self.log_file {
+            log_file.write_all(buf)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.terminal.flush()?;
+        if let Some(log_file) = &mut self.log_file {
+            log_file.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A log file of the output, without the escape sequences coloring it
+///
+/// Once the file reaches its maximum size, it is renamed to `FILE.1` (moving
+/// older files on to `FILE.2` and so on) at the end of the line, and a new file
+/// is started.
+pub struct LogFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+    max_size: Option<u64>,
+    keep: usize,
+    escape: Escape,
+}
+
+/// Progress through an ANSI escape sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Start,
+    Csi,
+}
+
+impl LogFile {
+    /// Create the log file at `path`, starting a new file every `max_size`
+    /// bytes and keeping `keep` of the previous files
+    pub fn create(path: &Path, max_size: Option<u64>, keep: usize) -> Result<Self, Error> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer: Self::open(path)?,
+            size: 0,
+            max_size,
+            keep,
+            escape: Escape::None,
+        })
+    }
+
+    fn open(path: &Path) -> Result<BufWriter<File>, Error> {
+        let file =
+            File::create(path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+        Ok(BufWriter::new(file))
+    }
+
+    /// Path of the `n`th previous file
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.writer.flush()?;
+
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.writer = Self::open(&self.path).map_err(std::io::Error::other)?;
+        self.size = 0;
+
+        Ok(())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for &byte in buf {
+            self.escape = match (self.escape, byte) {
+                (Escape::None, 0x1b) => Escape::Start,
+                (Escape::Start, b'[') => Escape::Csi,
+                // Sequences end with a byte in the range 0x40..=0x7e
+                (Escape::Start, _) | (Escape::Csi, 0x40..=0x7e) => Escape::None,
+                (Escape::Csi, _) => Escape::Csi,
+                // The terminal is in raw mode, so lines end with "\r\n"
+                (Escape::None, b'\r') => Escape::None,
+                (Escape::None, _) => {
+                    self.writer.write_all(&[byte])?;
+                    self.size += 1;
+
+                    if byte == b'\n' && self.max_size.is_some_and(|max| self.size >= max) {
+                        self.rotate()?;
+                    }
+
+                    Escape::None
+                }
+            };
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_files_are_plain_text_and_rotated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("monitor.log");
+
+        let mut log_file = LogFile::create(&path, Some(6), 2).unwrap();
+        for line in [
+            "\x1b[31mfirst\x1b[0m\r\n",
+            "second\r\n",
+            "third\r\n",
+            "fourth",
+        ] {
+            log_file.write_all(line.as_bytes()).unwrap();
+        }
+        log_file.flush().unwrap();
+
+        let read = |n| match n {
+            0 => fs::read_to_string(&path).unwrap(),
+            n => fs::read_to_string(log_file.rotated_path(n)).unwrap(),
+        };
+        assert_eq!(read(0), "fourth");
+        assert_eq!(read(1), "third\n");
+        assert_eq!(read(2), "second\n");
+        assert!(!log_file.rotated_path(3).exists());
+    }
+
+    #[test]
+    fn timestamps_start_each_line() {
+        let mut out = Timestamped::new(Vec::new(), true);
+        out.write_all(b"one\ntw").unwrap();
+        out.write_all(b"o\n").unwrap();
+
+        let out = String::from_utf8(out.inner).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| line.starts_with('[')));
+        assert!(lines[0].ends_with("] one") && lines[1].ends_with("] two"));
+    }
+}
@@ -0,0 +1,175 @@
+//! Writing NVS partitions with NVS encryption
+//!
+//! The NVS image is encrypted with the NVS keys, which are written to the
+//! `nvs_keys` partition with flash encryption. The keys are generated unless
+//! they are given, and can be saved to encrypt further images for the same
+//! device.
+
+use std::{fs, path::PathBuf};
+
+use clap::Args;
+use esp_idf_part::{DataType, PartitionTable, SubType, Type};
+use log::{info, warn};
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use crate::{
+    cli::{
+        config::Config, connect, map_file, print_board_info, report::Report, ConnectArgs,
+        EncryptionArgs, EspflashProgress,
+    },
+    error::Error,
+    flasher::{encryption::FlashEncryption, nvs::NvsKeys, parse_partition_table, Flasher},
+};
+
+/// Write an NVS partition encrypted with NVS keys, along with the keys
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct WriteNvsArgs {
+    /// NVS partition image, as generated by `nvs_partition_gen.py` without
+    /// encryption
+    #[arg(value_name = "NVS_BIN")]
+    pub nvs_bin: PathBuf,
+    /// Label of the NVS partition to write
+    #[arg(long, value_name = "LABEL", default_value = "nvs")]
+    pub partition: String,
+    /// `nvs_keys` partition image with the keys to encrypt with, instead of
+    /// generating new keys
+    #[arg(long, value_name = "FILE")]
+    pub keys: Option<PathBuf>,
+    /// Save the generated keys to FILE as an `nvs_keys` partition image
+    #[arg(long, value_name = "FILE", conflicts_with = "keys")]
+    pub save_keys: Option<PathBuf>,
+    /// Only write the NVS partition, as the device already holds the keys
+    #[arg(long, requires = "keys")]
+    pub skip_keys: bool,
+    /// Partition table to find the partitions in, instead of the one on the
+    /// device
+    #[arg(long, value_name = "FILE")]
+    pub partition_table: Option<PathBuf>,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+    /// Flash encryption of the keys partition
+    #[clap(flatten)]
+    pub encryption: EncryptionArgs,
+}
+
+/// Encrypt an NVS partition image, and write it along with the keys
+pub fn write_nvs(args: WriteNvsArgs, config: &Config, report: &mut Report) -> Result<()> {
+    let nvs = map_file(&args.nvs_bin)?;
+    let encryption = args.encryption.encryption()?;
+    if !args.skip_keys && encryption.is_none() {
+        return Err(Error::NvsKeysNotEncrypted.into());
+    }
+
+    let keys = match &args.keys {
+        Some(path) => NvsKeys::load(path)?,
+        None => NvsKeys::generate()?,
+    };
+    let encrypted = keys.encrypt(&nvs)?;
+
+    if let Some(path) = &args.save_keys {
+        fs::write(path, keys.to_partition())
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+    } else if args.keys.is_none() {
+        warn!("The generated NVS keys are not saved, use `--save-keys` to encrypt further images for this device");
+    }
+
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    print_board_info(&mut flasher, report)?;
+
+    let partition_table = match &args.partition_table {
+        Some(path) => parse_partition_table(path)?,
+        None => flasher.find_partition_table()?.1,
+    };
+    let (nvs_offset, keys_offset) = nvs_partitions(&partition_table, &args.partition, &encrypted)?;
+
+    if !args.skip_keys {
+        info!("Writing the NVS keys at {keys_offset:#x}");
+        write(&mut flasher, keys_offset, &keys.to_partition(), encryption)?;
+    }
+
+    // The NVS partition is protected by NVS encryption instead of flash
+    // encryption
+    info!("Writing the encrypted NVS partition at {nvs_offset:#x}");
+    write(&mut flasher, nvs_offset, &encrypted, None)?;
+
+    Ok(())
+}
+
+/// Offsets of the NVS partition with the given label, which must fit the
+/// image, and of the keys partition
+fn nvs_partitions(
+    partition_table: &PartitionTable,
+    label: &str,
+    image: &[u8],
+) -> Result<(u32, u32), Error> {
+    let nvs = partition_table
+        .find(label)
+        .ok_or_else(|| Error::PartitionNotFound(label.into()))?;
+    if nvs.subtype() != SubType::Data(DataType::Nvs) {
+        return Err(Error::InvalidNvsImage(format!(
+            "the partition '{label}' is not an NVS partition"
+        )));
+    }
+    if image.len() > nvs.size() as usize {
+        return Err(Error::InvalidNvsImage(format!(
+            "the image is {} bytes long, larger than the partition '{label}' of {} bytes",
+            image.len(),
+            nvs.size()
+        )));
+    }
+
+    let keys = partition_table
+        .find_by_subtype(Type::Data, SubType::Data(DataType::NvsKeys))
+        .ok_or_else(|| Error::InvalidNvsKeysPartition("the partition table has none".into()))?;
+    if !keys.encrypted() {
+        return Err(Error::InvalidNvsKeysPartition(format!(
+            "the partition '{}' is not marked as encrypted",
+            keys.name()
+        )));
+    }
+
+    Ok((nvs.offset(), keys.offset()))
+}
+
+fn write(
+    flasher: &mut Flasher,
+    addr: u32,
+    data: &[u8],
+    encryption: Option<FlashEncryption>,
+) -> Result<()> {
+    flasher.set_encryption(encryption);
+    flasher.write_bin_to_flash(addr, data, Some(&mut EspflashProgress::for_device(flasher)))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partitions_are_checked() {
+        let table = |keys: &str| {
+            PartitionTable::try_from_str(format!(
+                "nvs,data,nvs,0x9000,0x4000,\n{keys}factory,app,factory,0x10000,0x100000,"
+            ))
+            .unwrap()
+        };
+        let image = vec![0xff; 0x3000];
+
+        let encrypted = table("nvs_keys,data,nvs_keys,0xd000,0x1000,encrypted\n");
+        assert_eq!(
+            nvs_partitions(&encrypted, "nvs", &image).unwrap(),
+            (0x9000, 0xd000)
+        );
+        assert!(nvs_partitions(&encrypted, "factory", &image).is_err());
+        assert!(nvs_partitions(&encrypted, "nvs", &[0xff; 0x5000]).is_err());
+
+        let plain = table("nvs_keys,data,nvs_keys,0xd000,0x1000,\n");
+        assert!(nvs_partitions(&plain, "nvs", &image).is_err());
+        assert!(nvs_partitions(&table(""), "nvs", &image).is_err());
+    }
+}
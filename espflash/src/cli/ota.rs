@@ -0,0 +1,292 @@
+//! OTA data subcommands
+//!
+//! The OTA data partition of the device is read to show which app partition
+//! the bootloader boots, and rewritten to boot another one, e.g. to roll back
+//! an update.
+
+use std::path::PathBuf;
+
+use clap::{Args, Subcommand};
+use comfy_table::{modifiers, presets::UTF8_FULL, Attribute, Cell, Color, Table};
+use esp_idf_part::{AppType, PartitionTable, SubType, Type};
+use log::info;
+use miette::Result;
+use serde::Serialize;
+
+use crate::{
+    cli::{
+        config::Config, connect, print_board_info, report::Report, ConnectArgs, EspflashProgress,
+    },
+    error::Error,
+    flasher::{
+        ota::{ota_app_partitions, ota_data_partition, OtaData, OtaEntry, OTA_DATA_PARTITION_SIZE},
+        parse_partition_table, Flasher, FLASH_SECTOR_SIZE,
+    },
+};
+
+/// Inspect and change the OTA app partition which is booted
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct OtaArgs {
+    #[command(subcommand)]
+    pub command: OtaCommand,
+}
+
+#[derive(Debug, Subcommand)]
+#[non_exhaustive]
+pub enum OtaCommand {
+    /// Print the entries of the OTA data and the app partition they boot
+    Status(OtaStatusArgs),
+    /// Select the app partition to boot
+    ///
+    /// The slot is given as the index or the label of an OTA app partition,
+    /// e.g. '1' or 'ota_1', or as 'factory' to erase the OTA data and boot
+    /// the factory app.
+    SetBoot(OtaSetBootArgs),
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct OtaStatusArgs {
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+    /// Partition table to find the partitions in, instead of the one on the
+    /// device
+    #[arg(long, value_name = "FILE")]
+    pub partition_table: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct OtaSetBootArgs {
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+    /// Index or label of the app partition to boot, or 'factory'
+    #[arg(value_name = "SLOT")]
+    pub slot: String,
+    /// Partition table to find the partitions in, instead of the one on the
+    /// device
+    #[arg(long, value_name = "FILE")]
+    pub partition_table: Option<PathBuf>,
+}
+
+/// The app partition which is booted, as printed with `--output-format json`
+#[derive(Debug, Serialize)]
+struct OtaStatusReport {
+    /// Label of the booted app partition, if there is one
+    boot_partition: Option<String>,
+    /// Index of the booted OTA app partition, if the factory app is not
+    /// booted
+    boot_slot: Option<usize>,
+    /// The entries stored in both sectors of the OTA data
+    entries: [OtaEntry; 2],
+}
+
+/// Execute an OTA subcommand
+pub fn ota(args: OtaArgs, config: &Config, report: &mut Report) -> Result<()> {
+    match args.command {
+        OtaCommand::Status(args) => status(args, config, report),
+        OtaCommand::SetBoot(args) => set_boot(args, config, report),
+    }
+}
+
+fn status(args: OtaStatusArgs, config: &Config, report: &mut Report) -> Result<()> {
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    print_board_info(&mut flasher, report)?;
+
+    let table = read_partition_table(&mut flasher, args.partition_table.as_ref())?;
+    let (_, ota_data) = read_ota_data(&mut flasher, &table)?;
+    let slots = ota_app_partitions(&table);
+
+    let boot_slot = ota_data.active_slot(slots.len());
+    let boot_partition = match boot_slot {
+        Some(slot) => Some(slots[slot].name()),
+        None => table
+            .find_by_subtype(Type::App, SubType::App(AppType::Factory))
+            .map(|partition| partition.name()),
+    };
+    let entries = ota_data.entries();
+
+    print_entries(&entries, ota_data.active_sector(), slots.len(), report);
+    match &boot_partition {
+        Some(label) => outputln!(report, "Booting '{label}'"),
+        None => outputln!(report, "No app partition is booted"),
+    }
+
+    report.print(&OtaStatusReport {
+        boot_partition,
+        boot_slot,
+        entries,
+    })?;
+
+    Ok(())
+}
+
+fn set_boot(args: OtaSetBootArgs, config: &Config, report: &mut Report) -> Result<()> {
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    print_board_info(&mut flasher, report)?;
+
+    let table = read_partition_table(&mut flasher, args.partition_table.as_ref())?;
+    let (offset, mut ota_data) = read_ota_data(&mut flasher, &table)?;
+    let slots = ota_app_partitions(&table);
+
+    match parse_slot(&args.slot, &table)? {
+        Some(slot) => {
+            ota_data.set_boot_slot(slot, slots.len())?;
+            info!(
+                "Booting '{}' at {:#x}",
+                slots[slot].name(),
+                slots[slot].offset()
+            );
+        }
+        None => {
+            ota_data.set_boot_factory();
+            info!("Booting the factory app");
+        }
+    }
+
+    flasher.write_bin_to_flash(
+        offset,
+        ota_data.to_partition(),
+        Some(&mut EspflashProgress::for_device(&flasher)),
+    )?;
+
+    Ok(())
+}
+
+/// Index of the OTA app partition given as an index or label, or `None` for
+/// the factory app
+fn parse_slot(slot: &str, table: &PartitionTable) -> Result<Option<usize>, Error> {
+    let slots = ota_app_partitions(table);
+    if slots.is_empty() {
+        return Err(Error::InvalidOtaSlot(
+            "the partition table has no OTA app partitions".into(),
+        ));
+    }
+
+    if slot == "factory" {
+        return match table.find_by_subtype(Type::App, SubType::App(AppType::Factory)) {
+            Some(_) => Ok(None),
+            None => Err(Error::InvalidOtaSlot(
+                "the partition table has no factory app partition".into(),
+            )),
+        };
+    }
+
+    if let Ok(index) = slot.parse::<usize>() {
+        if index >= slots.len() {
+            return Err(Error::InvalidOtaSlot(format!(
+                "slot {index} does not exist, there are {} OTA app partitions",
+                slots.len()
+            )));
+        }
+
+        return Ok(Some(index));
+    }
+
+    slots
+        .iter()
+        .position(|partition| partition.name() == slot)
+        .map(Some)
+        .ok_or_else(|| Error::InvalidOtaSlot(format!("'{slot}' is not an OTA app partition")))
+}
+
+fn read_partition_table(flasher: &mut Flasher, path: Option<&PathBuf>) -> Result<PartitionTable> {
+    Ok(match path {
+        Some(path) => parse_partition_table(path)?,
+        None => flasher.find_partition_table()?.1,
+    })
+}
+
+/// The offset and the contents of the OTA data partition
+fn read_ota_data(flasher: &mut Flasher, table: &PartitionTable) -> Result<(u32, OtaData)> {
+    let offset = ota_data_partition(table)?.offset();
+    let data = flasher.read_flash_region(
+        offset,
+        OTA_DATA_PARTITION_SIZE as u32,
+        FLASH_SECTOR_SIZE as u32,
+        64,
+    )?;
+
+    Ok((offset, OtaData::from_partition(&data)?))
+}
+
+fn print_entries(entries: &[OtaEntry], active: Option<usize>, slot_count: usize, report: &Report) {
+    let mut pretty = Table::new();
+
+    pretty
+        .load_preset(UTF8_FULL)
+        .apply_modifier(modifiers::UTF8_ROUND_CORNERS)
+        .set_header(vec![
+            Cell::new("Sector")
+                .fg(Color::Green)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Sequence")
+                .fg(Color::Cyan)
+                .add_attribute(Attribute::Bold),
+            Cell::new("Slot")
+                .fg(Color::Magenta)
+                .add_attribute(Attribute::Bold),
+            Cell::new("State")
+                .fg(Color::Red)
+                .add_attribute(Attribute::Bold),
+            Cell::new("CRC")
+                .fg(Color::Yellow)
+                .add_attribute(Attribute::Bold),
+        ]);
+
+    for (sector, entry) in entries.iter().enumerate() {
+        let (seq, slot, state, crc) = if entry.is_empty() {
+            ("empty".into(), "-".into(), "-".into(), "-")
+        } else {
+            (
+                entry.seq.to_string(),
+                match slot_count {
+                    0 => "-".into(),
+                    count => entry.slot(count).to_string(),
+                },
+                entry.state.to_string(),
+                if entry.crc_valid { "valid" } else { "invalid" },
+            )
+        };
+        let sector = if active == Some(sector) {
+            format!("{sector} (active)")
+        } else {
+            sector.to_string()
+        };
+
+        pretty.add_row(vec![
+            Cell::new(sector).fg(Color::Green),
+            Cell::new(seq).fg(Color::Cyan),
+            Cell::new(slot).fg(Color::Magenta),
+            Cell::new(state).fg(Color::Red),
+            Cell::new(crc).fg(Color::Yellow),
+        ]);
+    }
+
+    outputln!(report, "{pretty}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_parsed() {
+        let table = PartitionTable::try_from_str(
+            "otadata,data,ota,0xd000,0x2000,\n\
+             factory,app,factory,0x10000,0x100000,\n\
+             ota_0,app,ota_0,0x110000,0x100000,\n\
+             ota_1,app,ota_1,0x210000,0x100000,",
+        )
+        .unwrap();
+
+        assert_eq!(parse_slot("1", &table).unwrap(), Some(1));
+        assert_eq!(parse_slot("ota_0", &table).unwrap(), Some(0));
+        assert_eq!(parse_slot("factory", &table).unwrap(), None);
+        assert!(parse_slot("2", &table).is_err());
+        assert!(parse_slot("nvs", &table).is_err());
+    }
+}
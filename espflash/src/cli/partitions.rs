@@ -0,0 +1,412 @@
+//! Editing and validating partition tables
+//!
+//! Partitions are added, removed and resized by their label. The edited table
+//! is validated like ESP-IDF does, and optionally checked to fit into the
+//! flash, before it is written back in the format it was read in, unless the
+//! extension of the output file asks for the other one.
+
+use std::{fs, path::PathBuf, str::FromStr};
+
+use clap::{Args, Subcommand};
+use esp_idf_part::{AppType, DataType, Partition, PartitionTable, SubType, Type};
+use log::info;
+use miette::{IntoDiagnostic, Result};
+
+use crate::{
+    cli::parse_uint32, error::Error, flasher::FlashSize, image_format::check_partition_table_fits,
+    output,
+};
+
+/// Alignment of the offsets of app partitions
+const APP_ALIGNMENT: u32 = 0x10000;
+/// Alignment of the offsets of all other partitions
+const DATA_ALIGNMENT: u32 = 0x1000;
+/// Offset of the first partition, following the partition table at its
+/// default offset
+const FIRST_PARTITION_OFFSET: u32 = 0x9000;
+/// Longest label of a partition
+const MAX_LABEL_LEN: usize = 16;
+
+/// Edit a partition table
+#[derive(Debug, Subcommand)]
+#[non_exhaustive]
+pub enum PartitionTableCommand {
+    /// Add a partition, after the last one unless an offset is given
+    Add(AddPartitionArgs),
+    /// Remove a partition
+    Remove(RemovePartitionArgs),
+    /// Change the size of a partition, which keeps its offset
+    Resize(ResizePartitionArgs),
+}
+
+/// The partition table and partition to edit
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct EditArgs {
+    /// Partition table to edit, in CSV or binary format
+    #[arg(value_name = "FILE")]
+    pub partition_table: PathBuf,
+    /// Label of the partition
+    #[arg(value_name = "LABEL")]
+    pub label: String,
+    /// File to write the edited partition table to instead of overwriting the
+    /// input, in the format of its `.bin` or `.csv` extension or else in that
+    /// of the input
+    #[arg(short = 'o', long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+    /// Size of the flash which the partition table has to fit into
+    #[arg(long, value_name = "SIZE", value_enum)]
+    pub flash_size: Option<FlashSize>,
+}
+
+/// Add a partition
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct AddPartitionArgs {
+    #[clap(flatten)]
+    pub edit: EditArgs,
+    /// Type of the partition: `app`, `data` or a custom type from 0x40 to
+    /// 0xfe
+    #[arg(long = "type", value_name = "TYPE")]
+    pub ty: String,
+    /// Subtype of the partition, e.g. `ota_0`, `nvs` or `spiffs`
+    #[arg(long, value_name = "SUBTYPE")]
+    pub subtype: String,
+    /// Offset of the partition, defaults to right after the last partition
+    #[arg(long, value_name = "OFFSET", value_parser = parse_uint32)]
+    pub offset: Option<u32>,
+    /// Size of the partition in bytes, which may be given with a `K` or `M`
+    /// suffix, e.g. `1M`
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub size: u32,
+    /// Mark the partition as encrypted
+    #[arg(long)]
+    pub encrypted: bool,
+}
+
+/// Remove a partition
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct RemovePartitionArgs {
+    #[clap(flatten)]
+    pub edit: EditArgs,
+}
+
+/// Change the size of a partition
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct ResizePartitionArgs {
+    #[clap(flatten)]
+    pub edit: EditArgs,
+    /// New size of the partition in bytes, which may be given with a `K` or
+    /// `M` suffix, e.g. `1M`
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub size: u32,
+}
+
+/// Parse a size in bytes, which may be given in hexadecimal with a `0x`
+/// prefix, or with a `K` or `M` suffix as in ESP-IDF's partition tables
+pub fn parse_size(input: &str) -> Result<u32, Error> {
+    let s = input.trim();
+    let (number, multiplier) = if let Some(number) = s.strip_suffix(['K', 'k']) {
+        (number, 1024)
+    } else if let Some(number) = s.strip_suffix(['M', 'm']) {
+        (number, 1024 * 1024)
+    } else {
+        (s, 1)
+    };
+
+    parse_int::parse::<u32>(number.trim())
+        .ok()
+        .and_then(|size| size.checked_mul(multiplier))
+        .filter(|size| *size > 0)
+        .ok_or_else(|| Error::InvalidPartitionSize(input.into()))
+}
+
+/// Apply a partition table edit, and write the table back
+pub fn edit_partition_table(command: PartitionTableCommand) -> Result<()> {
+    let edit = match &command {
+        PartitionTableCommand::Add(args) => &args.edit,
+        PartitionTableCommand::Remove(args) => &args.edit,
+        PartitionTableCommand::Resize(args) => &args.edit,
+    };
+
+    let input = fs::read(&edit.partition_table)
+        .map_err(|e| Error::FileOpenError(edit.partition_table.display().to_string(), e))?;
+    let binary = PartitionTable::try_from_bytes(&*input).is_ok();
+    let table = PartitionTable::try_from(input).map_err(Error::from)?;
+
+    let table = match &command {
+        PartitionTableCommand::Add(args) => {
+            let ty = parse_type(&args.ty)?;
+            let subtype = parse_subtype(ty, &args.subtype)?;
+            add_partition(
+                &table,
+                &args.edit.label,
+                ty,
+                subtype,
+                args.offset,
+                args.size,
+                args.encrypted,
+            )?
+        }
+        PartitionTableCommand::Remove(args) => remove_partition(&table, &args.edit.label)?,
+        PartitionTableCommand::Resize(args) => {
+            resize_partition(&table, &args.edit.label, args.size)?
+        }
+    };
+    validate_partition_table(&table, edit.flash_size)?;
+
+    // The output is in the format of its extension, or else in that of the input
+    let path = edit.output.as_ref().unwrap_or(&edit.partition_table);
+    let binary = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("bin") => true,
+        Some("csv") => false,
+        _ => binary,
+    };
+    let data = if binary {
+        table.to_bin().into_diagnostic()?
+    } else {
+        table.to_csv().into_diagnostic()?.into_bytes()
+    };
+    output::write(path, data)?;
+
+    info!("Partition table written to {}", path.display());
+
+    Ok(())
+}
+
+/// Check a partition table for overlapping, misaligned and duplicate
+/// partitions, and that it fits into `flash_size` if given
+pub fn validate_partition_table(
+    table: &PartitionTable,
+    flash_size: Option<FlashSize>,
+) -> Result<(), Error> {
+    table.validate()?;
+
+    if let Some(flash_size) = flash_size {
+        check_partition_table_fits(table, flash_size)?;
+    }
+
+    Ok(())
+}
+
+fn parse_type(ty: &str) -> Result<Type, Error> {
+    match ty.trim() {
+        "app" => Ok(Type::App),
+        "data" => Ok(Type::Data),
+        ty => match parse_int::parse::<u8>(ty) {
+            Ok(ty @ 0x00..=0xfe) => Ok(Type::from(ty)),
+            _ => Err(Error::InvalidPartitionType(ty.into())),
+        },
+    }
+}
+
+fn parse_subtype(ty: Type, subtype: &str) -> Result<SubType, Error> {
+    let subtype = subtype.trim();
+    let number = parse_int::parse::<u8>(subtype).ok();
+
+    let parsed = match ty {
+        Type::App => AppType::from_str(subtype)
+            .ok()
+            .or_else(|| AppType::from_repr(number? as usize))
+            .map(SubType::App),
+        Type::Data => DataType::from_str(subtype)
+            .ok()
+            .or_else(|| DataType::from_repr(number? as usize))
+            .map(SubType::Data),
+        Type::Custom(_) => number.map(SubType::Custom),
+    };
+
+    parsed.ok_or_else(|| Error::InvalidPartitionSubtype(subtype.into(), ty.subtype_hint()))
+}
+
+/// The offset right after the last partition, aligned for a partition of type
+/// `ty`
+fn next_offset(table: &PartitionTable, ty: Type) -> u32 {
+    let end = table
+        .partitions()
+        .iter()
+        .map(|p| p.offset() + p.size())
+        .max()
+        .unwrap_or(FIRST_PARTITION_OFFSET);
+    let alignment = if ty == Type::App {
+        APP_ALIGNMENT
+    } else {
+        DATA_ALIGNMENT
+    };
+
+    end.next_multiple_of(alignment)
+}
+
+fn add_partition(
+    table: &PartitionTable,
+    label: &str,
+    ty: Type,
+    subtype: SubType,
+    offset: Option<u32>,
+    size: u32,
+    encrypted: bool,
+) -> Result<PartitionTable, Error> {
+    if label.is_empty() || label.len() > MAX_LABEL_LEN {
+        return Err(Error::InvalidPartitionLabel(label.into()));
+    }
+    if table.find(label).is_some() {
+        return Err(Error::PartitionExists(label.into()));
+    }
+
+    let offset = offset.unwrap_or_else(|| next_offset(table, ty));
+    let partition = Partition::new(label, ty, subtype, offset, size, encrypted);
+
+    // Keep the partitions ordered by their offsets
+    let mut partitions = table.partitions().clone();
+    let index = partitions.partition_point(|p| p.offset() < offset);
+    partitions.insert(index, partition);
+
+    Ok(PartitionTable::new(partitions))
+}
+
+fn remove_partition(table: &PartitionTable, label: &str) -> Result<PartitionTable, Error> {
+    table
+        .find(label)
+        .ok_or_else(|| Error::PartitionNotFound(label.into()))?;
+
+    let partitions = table
+        .partitions()
+        .iter()
+        .filter(|p| p.name() != label)
+        .cloned()
+        .collect();
+
+    Ok(PartitionTable::new(partitions))
+}
+
+fn resize_partition(
+    table: &PartitionTable,
+    label: &str,
+    size: u32,
+) -> Result<PartitionTable, Error> {
+    table
+        .find(label)
+        .ok_or_else(|| Error::PartitionNotFound(label.into()))?;
+
+    let partitions = table
+        .partitions()
+        .iter()
+        .map(|p| match p.name() == label {
+            true => Partition::new(
+                p.name(),
+                p.ty(),
+                p.subtype(),
+                p.offset(),
+                size,
+                p.encrypted(),
+            ),
+            false => p.clone(),
+        })
+        .collect();
+
+    Ok(PartitionTable::new(partitions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &str = "nvs,data,nvs,0x9000,0x6000,
+phy_init,data,phy,0xf000,0x1000,
+factory,app,factory,0x10000,0x100000,";
+
+    #[test]
+    fn sizes_are_parsed() {
+        assert_eq!(parse_size("4096").unwrap(), 0x1000);
+        assert_eq!(parse_size("0x1000").unwrap(), 0x1000);
+        assert_eq!(parse_size("64K").unwrap(), 0x10000);
+        assert_eq!(parse_size("1M").unwrap(), 0x100000);
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("5G").is_err());
+        assert!(parse_size("8192M").is_err());
+    }
+
+    #[test]
+    fn types_are_parsed() {
+        assert_eq!(parse_type("app").unwrap(), Type::App);
+        assert_eq!(parse_type("0x40").unwrap(), Type::Custom(0x40));
+        assert!(parse_type("code").is_err());
+
+        assert_eq!(
+            parse_subtype(Type::App, "ota_1").unwrap(),
+            SubType::App(AppType::Ota_1)
+        );
+        assert_eq!(
+            parse_subtype(Type::Data, "0x82").unwrap(),
+            SubType::Data(DataType::Spiffs)
+        );
+        assert_eq!(
+            parse_subtype(Type::Custom(0x40), "0x01").unwrap(),
+            SubType::Custom(0x01)
+        );
+        assert!(parse_subtype(Type::App, "nvs").is_err());
+    }
+
+    #[test]
+    fn partitions_are_edited() {
+        let table = PartitionTable::try_from_str(TABLE).unwrap();
+
+        let added = add_partition(
+            &table,
+            "storage",
+            Type::Data,
+            SubType::Data(DataType::Spiffs),
+            None,
+            0x10000,
+            false,
+        )
+        .unwrap();
+        let storage = added.find("storage").unwrap();
+        assert_eq!(storage.offset(), 0x110000);
+        assert!(validate_partition_table(&added, Some(FlashSize::_2Mb)).is_ok());
+        assert!(matches!(
+            validate_partition_table(&added, Some(FlashSize::_1Mb)),
+            Err(Error::PartitionTableExceedsFlashSize { .. })
+        ));
+
+        // Inserted by offset, and checked for overlaps
+        let early = add_partition(
+            &table,
+            "early",
+            Type::Data,
+            SubType::Data(DataType::Undefined),
+            Some(0x8000),
+            0x2000,
+            false,
+        )
+        .unwrap();
+        assert_eq!(early.partitions()[0].name(), "early");
+        assert!(validate_partition_table(&early, None).is_err());
+        assert!(matches!(
+            add_partition(
+                &table,
+                "nvs",
+                Type::Data,
+                SubType::Data(DataType::Nvs),
+                None,
+                0x1000,
+                false
+            ),
+            Err(Error::PartitionExists(_))
+        ));
+
+        let removed = remove_partition(&table, "phy_init").unwrap();
+        assert_eq!(removed.partitions().len(), 2);
+        assert!(matches!(
+            remove_partition(&table, "ota_0"),
+            Err(Error::PartitionNotFound(_))
+        ));
+
+        let resized = resize_partition(&table, "factory", 0x200000).unwrap();
+        assert_eq!(resized.find("factory").unwrap().size(), 0x200000);
+        let overlapping = resize_partition(&table, "nvs", 0x7000).unwrap();
+        assert!(validate_partition_table(&overlapping, None).is_err());
+    }
+}
@@ -0,0 +1,253 @@
+//! Machine-readable results of the commands
+//!
+//! With `--output-format json`, commands print a single JSON document with
+//! their results to stdout, while the human-readable output and the logs go to
+//! stderr.
+
+use clap::ValueEnum;
+use miette::{IntoDiagnostic, Result};
+use serde::Serialize;
+
+use crate::flasher::{DeviceInfo, FlashStats, SegmentProgress};
+
+/// Format of the results printed to stdout
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[non_exhaustive]
+pub enum OutputFormat {
+    /// Human-readable text
+    #[default]
+    Text,
+    /// A JSON document, with the human-readable output moved to stderr
+    Json,
+}
+
+impl OutputFormat {
+    /// Whether the results are printed as JSON, and the human-readable output
+    /// goes to stderr
+    pub fn is_json(self) -> bool {
+        self == OutputFormat::Json
+    }
+}
+
+/// Information about the connected device
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceReport {
+    pub chip: String,
+    pub revision: Option<String>,
+    pub crystal_frequency: String,
+    pub flash_size: String,
+    pub embedded_flash: Option<bool>,
+    pub flash_chip: Option<String>,
+    pub features: Vec<String>,
+    pub mac_address: String,
+}
+
+impl From<&DeviceInfo> for DeviceReport {
+    fn from(info: &DeviceInfo) -> Self {
+        Self {
+            chip: info.chip.to_string(),
+            revision: info
+                .revision
+                .map(|(major, minor)| format!("v{major}.{minor}")),
+            crystal_frequency: info.crystal_frequency.to_string(),
+            flash_size: info.flash_size.to_string(),
+            embedded_flash: info.embedded_flash,
+            flash_chip: info.flash_chip.map(|chip| chip.to_string()),
+            features: info.features.clone(),
+            mac_address: info.mac_address.clone(),
+        }
+    }
+}
+
+/// A segment written to flash
+#[derive(Debug, Clone, Serialize)]
+pub struct SegmentReport {
+    pub address: u32,
+    pub size: usize,
+    /// Size of the data sent after compression, if it was compressed
+    pub compressed_size: Option<usize>,
+    pub duration_ms: u128,
+}
+
+/// Results of a command, collected while it runs and printed as a JSON
+/// document at its end when requested
+#[derive(Debug, Default)]
+pub struct Report {
+    format: OutputFormat,
+    /// The device the command connected to
+    device: Option<DeviceReport>,
+    /// The segments written by the command
+    segments: Vec<SegmentReport>,
+}
+
+/// Results of writing flash
+#[derive(Debug, Serialize)]
+struct FlashReport<'a> {
+    device: Option<&'a DeviceReport>,
+    segments: &'a [SegmentReport],
+    bytes: u64,
+    duration_ms: u128,
+    verify_failures: u64,
+}
+
+impl Report {
+    pub fn new(format: OutputFormat) -> Self {
+        Self {
+            format,
+            ..Self::default()
+        }
+    }
+
+    /// The format of the results
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    /// Whether the results are printed as JSON, and the human-readable output
+    /// goes to stderr
+    pub fn is_json(&self) -> bool {
+        self.format.is_json()
+    }
+
+    /// Remember the device the command connected to
+    pub fn record_device(&mut self, info: &DeviceInfo) {
+        self.device = Some(info.into());
+    }
+
+    /// Remember the segments which were written
+    pub fn record_segments(&mut self, segments: &[SegmentProgress]) {
+        self.segments
+            .extend(segments.iter().map(|segment| SegmentReport {
+                address: segment.addr,
+                size: segment.size,
+                compressed_size: segment.compressed_size,
+                duration_ms: segment.elapsed.as_millis(),
+            }));
+    }
+
+    /// The device the command connected to, if any
+    pub fn device(&self) -> Option<&DeviceReport> {
+        self.device.as_ref()
+    }
+
+    /// Print `value` as the JSON document of the results, if they are printed
+    /// as JSON
+    pub fn print(&self, value: &impl Serialize) -> Result<()> {
+        if self.is_json() {
+            print_json(value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Print the device the command connected to, if the results are printed
+    /// as JSON
+    pub fn print_device(&self) -> Result<()> {
+        self.print(&self.device)
+    }
+
+    /// Print the device and the segments written to it, if the results are
+    /// printed as JSON
+    pub fn print_flash(&self, stats: FlashStats) -> Result<()> {
+        self.print(&self.flash_report(stats))
+    }
+
+    fn flash_report(&self, stats: FlashStats) -> FlashReport<'_> {
+        FlashReport {
+            device: self.device.as_ref(),
+            segments: &self.segments,
+            bytes: stats.bytes,
+            duration_ms: stats.duration.as_millis(),
+            verify_failures: stats.verify_failures,
+        }
+    }
+}
+
+/// Print `value` as the JSON document of the results
+pub fn print_json(value: &impl Serialize) -> Result<()> {
+    let json = serde_json::to_string_pretty(value).into_diagnostic()?;
+    println!("{json}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        flasher::FlashSize,
+        targets::{Chip, XtalFrequency},
+    };
+
+    fn device_info() -> DeviceInfo {
+        DeviceInfo {
+            chip: Chip::Esp32c3,
+            revision: Some((0, 4)),
+            crystal_frequency: XtalFrequency::_40Mhz,
+            flash_size: FlashSize::_4Mb,
+            embedded_flash: Some(false),
+            flash_chip: None,
+            features: vec!["WiFi".into(), "BLE".into()],
+            mac_address: "60:55:f9:c0:0e:08".into(),
+        }
+    }
+
+    #[test]
+    fn device_report() {
+        let mut report = Report::new(OutputFormat::Json);
+        assert_eq!(serde_json::to_value(report.device()).unwrap(), json!(null));
+
+        report.record_device(&device_info());
+        assert_eq!(
+            serde_json::to_value(report.device()).unwrap(),
+            json!({
+                "chip": "esp32c3",
+                "revision": "v0.4",
+                "crystal_frequency": "40 MHz",
+                "flash_size": "4MB",
+                "embedded_flash": false,
+                "flash_chip": null,
+                "features": ["WiFi", "BLE"],
+                "mac_address": "60:55:f9:c0:0e:08",
+            })
+        );
+    }
+
+    #[test]
+    fn flash_report() {
+        let mut report = Report::new(OutputFormat::Json);
+        report.record_device(&device_info());
+        report.record_segments(&[SegmentProgress {
+            addr: 0x10000,
+            size: 0x2000,
+            written: 0x2000,
+            compressed_size: Some(0x800),
+            elapsed: Duration::from_millis(120),
+        }]);
+
+        let stats = FlashStats {
+            bytes: 0x2000,
+            duration: Duration::from_millis(150),
+            verify_failures: 0,
+        };
+        let value = serde_json::to_value(report.flash_report(stats)).unwrap();
+
+        assert_eq!(value["device"]["chip"], "esp32c3");
+        assert_eq!(
+            value["segments"],
+            json!([{
+                "address": 0x10000,
+                "size": 0x2000,
+                "compressed_size": 0x800,
+                "duration_ms": 120,
+            }])
+        );
+        assert_eq!(value["bytes"], 0x2000);
+        assert_eq!(value["duration_ms"], 150);
+        assert_eq!(value["verify_failures"], 0);
+    }
+}
@@ -1,14 +1,24 @@
 #[cfg(not(target_os = "windows"))]
 use std::fs;
+use std::{
+    str::FromStr,
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
+use clap_complete::CompletionCandidate;
 use crossterm::style::Stylize;
 use dialoguer::{theme::ColorfulTheme, Confirm, Select};
 use log::{error, info};
 use miette::{IntoDiagnostic, Result};
-use serialport::{available_ports, SerialPortInfo, SerialPortType};
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
 
 use crate::{
     cli::{config::UsbDevice, Config, ConnectArgs},
+    connection::{
+        network::NetworkPort, reset::usb_touch, ESPRESSIF_USB_VID, USB_OTG_PID, USB_SERIAL_JTAG_PID,
+    },
+    discovery::{available_ports, candidate_ports},
     error::Error,
 };
 
@@ -33,14 +43,33 @@ pub fn get_serial_port_info(
     // doesn't work (on Windows) with "dummy" device paths like `COM4`. That's
     // the reason we need to handle Windows/Posix differently.
 
-    if let Some(serial) = &matches.port {
-        let ports = detect_usb_serial_ports(true).unwrap_or_default();
+    // Ports may also be given as `auto:<VID>:<PID>[:<SERIAL>]`, selecting the
+    // device by its USB identifiers rather than by a path which might change
+    // whenever the device is plugged in again.
+
+    // Ports which are not USB devices, such as native or PCI UARTs and RS-232
+    // adapters, are only offered with `--list-all-ports` unless they are trusted
+    // in the configuration file.
+
+    if let Some(serial) = matches
+        .port
+        .as_ref()
+        .or(config.connection.serial.as_ref())
+        .filter(|serial| NetworkPort::is_url(serial))
+    {
+        // Network ports are not enumerated, they are only known by their URL
+        Ok(SerialPortInfo {
+            port_name: serial.clone(),
+            port_type: SerialPortType::Unknown,
+        })
+    } else if let Some(serial) = &matches.port {
+        let ports = detect_serial_ports(true, config);
         find_serial_port(&ports, serial)
     } else if let Some(serial) = &config.connection.serial {
-        let ports = detect_usb_serial_ports(true).unwrap_or_default();
+        let ports = detect_serial_ports(true, config);
         find_serial_port(&ports, serial)
     } else {
-        let ports = detect_usb_serial_ports(matches.list_all_ports).unwrap_or_default();
+        let ports = detect_serial_ports(matches.list_all_ports, config);
         let (port, matches) = select_serial_port(ports, config, matches.confirm_port)?;
 
         match &port.port_type {
@@ -63,6 +92,24 @@ pub fn get_serial_port_info(
                     }
                 }
             }
+            SerialPortType::UsbPort(_) => {}
+            _ if !matches => {
+                let trust = Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt(format!(
+                        "Always allow {}, without `--list-all-ports`?",
+                        port.port_name
+                    ))
+                    .interact_opt()?
+                    .unwrap_or_default();
+
+                if trust {
+                    if let Err(e) = config.save_with(|config| {
+                        config.connection.trusted_ports.push(port.port_name.clone())
+                    }) {
+                        error!("Failed to save config {:#}", e);
+                    }
+                }
+            }
             _ => {}
         }
 
@@ -73,126 +120,248 @@ pub fn get_serial_port_info(
 /// Given a vector of `SerialPortInfo` structs, attempt to find and return one
 /// whose `port_name` field matches the provided `name` argument.
 fn find_serial_port(ports: &[SerialPortInfo], name: &str) -> Result<SerialPortInfo, Error> {
+    if let Some(selector) = name.strip_prefix("auto:") {
+        return find_serial_port_by_usb_id(ports, &selector.parse()?);
+    }
+
     #[cfg(not(target_os = "windows"))]
     let name = fs::canonicalize(name)?;
     #[cfg(not(target_os = "windows"))]
     let name = name.to_string_lossy();
 
+    let port_info = ports
+        .iter()
+        .find(|port| is_same_port(&port.port_name, &name));
+
+    if let Some(port) = port_info {
+        Ok(port.to_owned())
+    } else if cfg!(not(target_os = "windows")) {
+        // The device exists, as its path could be canonicalized, but it is not
+        // enumerated as a serial port. This is the case for some native UARTs.
+        Ok(SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::Unknown,
+        })
+    } else {
+        Err(Error::SerialNotFound(name.to_string()))
+    }
+}
+
+/// Do both names refer to the same serial port?
+fn is_same_port(a: &str, b: &str) -> bool {
     // The case in device paths matters in BSD!
-    #[cfg(any(
+    if cfg!(any(
         target_os = "freebsd",
         target_os = "dragonfly",
         target_os = "openbsd",
         target_os = "netbsd"
-    ))]
-    let port_info = ports.iter().find(|port| port.port_name == name);
+    )) {
+        a == b
+    } else {
+        // On Windows and other *nix systems, the case is not important.
+        a.eq_ignore_ascii_case(b)
+    }
+}
 
-    // On Windows and other *nix systems, the case is not important.
-    #[cfg(not(any(
-        target_os = "freebsd",
-        target_os = "dragonfly",
-        target_os = "openbsd",
-        target_os = "netbsd"
-    )))]
-    let port_info = ports
+/// Is the port trusted in the configuration file?
+fn is_trusted_port(config: &Config, port_name: &str) -> bool {
+    config
+        .connection
+        .trusted_ports
         .iter()
-        .find(|port| port.port_name.eq_ignore_ascii_case(name.as_ref()));
+        .any(|trusted| is_same_port(trusted, port_name))
+}
 
-    if let Some(port) = port_info {
-        Ok(port.to_owned())
-    } else {
-        Err(Error::SerialNotFound(name.to_string()))
+/// Detect the serial ports to choose from, including the trusted ports from
+/// the configuration file whether or not they are USB devices
+pub(super) fn detect_serial_ports(list_all_ports: bool, config: &Config) -> Vec<SerialPortInfo> {
+    let mut ports = detect_usb_serial_ports(list_all_ports).unwrap_or_default();
+    let available = available_ports().unwrap_or_default();
+
+    for name in &config.connection.trusted_ports {
+        if ports.iter().any(|port| is_same_port(&port.port_name, name)) {
+            continue;
+        }
+
+        let port = available
+            .iter()
+            .find(|port| is_same_port(&port.port_name, name))
+            .cloned()
+            .or_else(|| {
+                // Native UARTs are not always enumerated
+                (cfg!(not(target_os = "windows")) && std::path::Path::new(name).exists()).then(
+                    || SerialPortInfo {
+                        port_name: name.clone(),
+                        port_type: SerialPortType::Unknown,
+                    },
+                )
+            });
+        ports.extend(port);
     }
+
+    ports
 }
 
-/// Serialport's auto-detect doesn't provide any port information when using MUSL
-/// Linux we can do some manual parsing of sysfs to get the relevant bits
-/// without udev
-#[cfg(all(target_os = "linux", target_env = "musl"))]
-fn detect_usb_serial_ports(_list_all_ports: bool) -> Result<Vec<SerialPortInfo>> {
-    use std::{
-        fs::{read_link, read_to_string},
-        path::{Path, PathBuf},
+/// How long to wait for a device to restart after a 1200 baud touch
+const USB_TOUCH_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often to look for the restarted device
+const USB_TOUCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Enter download mode with a 1200 baud touch of `port`, and find the port of
+/// the ROM bootloader once the device restarted
+pub fn usb_touch_port(port: SerialPortInfo) -> Result<SerialPortInfo, Error> {
+    info!("Requesting download mode with a 1200 baud touch...");
+    usb_touch(&port.port_name)?;
+
+    let present = |ports: &[SerialPortInfo]| {
+        ports
+            .iter()
+            .any(|p| is_same_port(&p.port_name, &port.port_name))
     };
 
-    use serialport::UsbPortInfo;
+    // Wait for the device to disconnect, and then for the ROM bootloader to
+    // enumerate
+    let start = Instant::now();
+    let mut disconnected = false;
+    while start.elapsed() < USB_TOUCH_TIMEOUT {
+        sleep(USB_TOUCH_POLL_INTERVAL);
+        let ports = available_ports().unwrap_or_default();
+        if !disconnected {
+            disconnected = !present(&ports);
+            continue;
+        }
 
-    let ports = available_ports().into_diagnostic()?;
-    let ports = ports
-        .into_iter()
-        .filter_map(|port_info| {
-            // With musl, the paths we get are `/sys/class/tty/*` or `/dev/*`
-            // In case of `/dev/*` we transform them into `/sys/class/tty/*`
-            let path = match AsRef::<Path>::as_ref(&port_info.port_name).strip_prefix("/dev/") {
-                Ok(rem) => PathBuf::from("/sys/class/tty/").join(rem),
-                Err(_) => PathBuf::from(&port_info.port_name),
-            };
+        let mut candidates = ports.into_iter().filter(|p| {
+            matches!(
+                &p.port_type,
+                SerialPortType::UsbPort(info)
+                    if info.vid == ESPRESSIF_USB_VID
+                        && (info.pid == USB_OTG_PID || info.pid == USB_SERIAL_JTAG_PID)
+            )
+        });
+        // Prefer the port the device had before, if it has the same name
+        if let Some(found) = candidates
+            .clone()
+            .find(|p| is_same_port(&p.port_name, &port.port_name))
+            .or_else(|| candidates.next())
+        {
+            info!(
+                "Device restarted into download mode on '{}'",
+                found.port_name
+            );
+            return Ok(found);
+        }
+    }
+
+    Err(Error::UsbTouchFailed(port.port_name))
+}
+
+/// Selects a USB serial port by its identifiers, parsed from
+/// `<VID>:<PID>[:<SERIAL>]`
+#[derive(Debug, PartialEq, Eq)]
+struct UsbPortSelector {
+    vid: u16,
+    pid: u16,
+    /// Substring of the serial number of the device
+    serial: Option<String>,
+}
+
+impl FromStr for UsbPortSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidPortSelector(s.to_string());
+        let parse_id = |id: &str| {
+            let id = id
+                .strip_prefix("0x")
+                .or_else(|| id.strip_prefix("0X"))
+                .unwrap_or(id);
+            u16::from_str_radix(id, 16).map_err(|_| invalid())
+        };
+
+        let mut parts = s.splitn(3, ':');
+        let vid = parse_id(parts.next().ok_or_else(invalid)?)?;
+        let pid = parse_id(parts.next().ok_or_else(invalid)?)?;
+        let serial = parts
+            .next()
+            .filter(|serial| !serial.is_empty())
+            .map(String::from);
+
+        Ok(Self { vid, pid, serial })
+    }
+}
 
-            // This will give something like:
-            // `/sys/devices/pci0000:00/0000:00:07.1/0000:0c:00.3/usb5/5-3/5-3.1/5-3.1:1.0/ttyUSB0/tty/ttyUSB0`
-            let mut parent_dev = path.canonicalize().ok()?;
-
-            // Walk up 3 dirs to get to the device hosting the tty:
-            // `/sys/devices/pci0000:00/0000:00:07.1/0000:0c:00.3/usb5/5-3/5-3.1/5-3.1:1.0`
-            parent_dev.pop();
-            parent_dev.pop();
-            parent_dev.pop();
-
-            // Check that the device is using the usb subsystem
-            read_link(parent_dev.join("subsystem"))
-                .ok()
-                .filter(|subsystem| subsystem.ends_with("usb"))?;
-
-            let interface = read_to_string(parent_dev.join("interface"))
-                .ok()
-                .map(|s| s.trim().to_string());
-
-            // /sys/devices/pci0000:00/0000:00:07.1/0000:0c:00.3/usb5/5-3/5-3.1
-            parent_dev.pop();
-
-            let vid = read_to_string(parent_dev.join("idVendor")).ok()?;
-            let pid = read_to_string(parent_dev.join("idProduct")).ok()?;
-
-            Some(SerialPortInfo {
-                port_type: SerialPortType::UsbPort(UsbPortInfo {
-                    vid: u16::from_str_radix(vid.trim(), 16).ok()?,
-                    pid: u16::from_str_radix(pid.trim(), 16).ok()?,
-                    product: interface,
-                    serial_number: None,
-                    manufacturer: None,
-                }),
-                port_name: format!("/dev/{}", path.file_name()?.to_str()?),
+impl UsbPortSelector {
+    fn matches(&self, info: &UsbPortInfo) -> bool {
+        info.vid == self.vid
+            && info.pid == self.pid
+            && self.serial.as_ref().is_none_or(|serial| {
+                info.serial_number
+                    .as_ref()
+                    .is_some_and(|number| number.contains(serial.as_str()))
             })
+    }
+}
+
+/// Find the single USB serial port matching the given selector
+fn find_serial_port_by_usb_id(
+    ports: &[SerialPortInfo],
+    selector: &UsbPortSelector,
+) -> Result<SerialPortInfo, Error> {
+    let matching = ports
+        .iter()
+        .filter(|port| match &port.port_type {
+            SerialPortType::UsbPort(info) => selector.matches(info),
+            _ => false,
         })
         .collect::<Vec<_>>();
 
-    Ok(ports)
+    match matching.as_slice() {
+        [port] => Ok((*port).to_owned()),
+        [] => Err(Error::SerialNotFound(format!(
+            "auto:{:04x}:{:04x}{}",
+            selector.vid,
+            selector.pid,
+            selector
+                .serial
+                .as_ref()
+                .map(|serial| format!(":{serial}"))
+                .unwrap_or_default()
+        ))),
+        ports => Err(Error::AmbiguousSerialPort(
+            ports
+                .iter()
+                .map(|port| port.port_name.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        )),
+    }
 }
 
-/// Returns a vector with available USB serial ports.
-#[cfg(not(all(target_os = "linux", target_env = "musl")))]
-fn detect_usb_serial_ports(list_all_ports: bool) -> Result<Vec<SerialPortInfo>> {
-    let ports = available_ports().into_diagnostic()?;
-    let ports = ports
+/// The available serial ports, for dynamic shell completions of `--port`
+pub(super) fn port_candidates() -> Vec<CompletionCandidate> {
+    available_ports()
+        .unwrap_or_default()
         .into_iter()
-        .filter(|port_info| {
-            if list_all_ports {
-                matches!(
-                    &port_info.port_type,
-                    SerialPortType::UsbPort(..) |
-                    // Allow PciPort. The user may want to use it.
-                    // The port might have been misdetected by the system as PCI.
-                    SerialPortType::PciPort |
-                    // Good luck.
-                    SerialPortType::Unknown
-                )
-            } else {
-                matches!(&port_info.port_type, SerialPortType::UsbPort(..))
-            }
+        .map(|port| {
+            let help = match port.port_type {
+                SerialPortType::UsbPort(info) => Some(format!(
+                    "{} ({:04x}:{:04x})",
+                    info.product.as_deref().unwrap_or("USB serial port"),
+                    info.vid,
+                    info.pid
+                )),
+                _ => None,
+            };
+
+            CompletionCandidate::new(port.port_name).help(help.map(Into::into))
         })
-        .collect::<Vec<_>>();
+        .collect()
+}
 
-    Ok(ports)
+/// Returns a vector with available USB serial ports.
+fn detect_usb_serial_ports(list_all_ports: bool) -> Result<Vec<SerialPortInfo>> {
+    candidate_ports(list_all_ports).into_diagnostic()
 }
 
 /// USB UART adapters which are known to be on common development boards
@@ -205,6 +374,10 @@ const KNOWN_DEVICES: &[UsbDevice] = &[
         vid: 0x1a86,
         pid: 0x7523,
     }, // QinHeng Electronics CH340 serial converter
+    UsbDevice {
+        vid: 0x303a,
+        pid: 0x1002,
+    }, // Espressif ESP USB Bridge
 ];
 
 /// Ask the user to select a serial port from a list of detected serial ports.
@@ -220,7 +393,7 @@ fn select_serial_port(
             .iter()
             .chain(KNOWN_DEVICES.iter())
             .any(|dev| dev.matches(info)),
-        _ => false,
+        _ => is_trusted_port(config, &port.port_name),
     };
 
     if let [port] = ports
@@ -251,7 +424,7 @@ fn select_serial_port(
                 } else {
                     port_info.port_name.as_str().reset()
                 };
-                match &port_info.port_type {
+                let name = match &port_info.port_type {
                     SerialPortType::UsbPort(info) => {
                         if let Some(product) = &info.product {
                             format!("{} - {}", formatted, product)
@@ -260,6 +433,11 @@ fn select_serial_port(
                         }
                     }
                     _ => formatted.to_string(),
+                };
+                if is_usbip_port(&port_info.port_name) {
+                    format!("{name} (usbipd)")
+                } else {
+                    name
                 }
             })
             .collect::<Vec<_>>();
@@ -295,12 +473,53 @@ fn select_serial_port(
         } else {
             Err(Error::SerialNotFound(port_name))
         }
+    } else if is_wsl() {
+        // No serial ports detected, USB devices have to be attached to WSL
+        // explicitly
+        Err(Error::NoSerialWsl)
     } else {
         // No serial ports detected
         Err(Error::NoSerial)
     }
 }
 
+/// Is this process running under the Windows Subsystem for Linux?
+#[cfg(target_os = "linux")]
+fn is_wsl() -> bool {
+    fs::read_to_string("/proc/sys/kernel/osrelease").is_ok_and(|release| is_wsl_release(&release))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_wsl() -> bool {
+    false
+}
+
+/// WSL kernels identify themselves in their release string, e.g.
+/// `5.15.153.1-microsoft-standard-WSL2`
+#[cfg(any(target_os = "linux", test))]
+fn is_wsl_release(release: &str) -> bool {
+    let release = release.to_lowercase();
+    release.contains("microsoft") || release.contains("wsl")
+}
+
+/// Is the port provided by a USB device attached over USB/IP, as done by
+/// `usbipd` to forward devices from a Windows host to WSL?
+#[cfg(target_os = "linux")]
+fn is_usbip_port(port_name: &str) -> bool {
+    let Some(name) = port_name.strip_prefix("/dev/") else {
+        return false;
+    };
+
+    // USB/IP devices are attached to the virtual host controller
+    fs::canonicalize(format!("/sys/class/tty/{name}/device"))
+        .is_ok_and(|path| path.to_string_lossy().contains("vhci_hcd"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_usbip_port(_port_name: &str) -> bool {
+    false
+}
+
 /// Ask the user to confirm the use of a serial port.
 fn confirm_port(port_name: &str, product: Option<&String>) -> Result<bool, Error> {
     Confirm::with_theme(&ColorfulTheme::default())
@@ -314,3 +533,76 @@ fn confirm_port(port_name: &str, product: Option<&String>) -> Result<bool, Error
         .interact_opt()?
         .ok_or(Error::Cancelled)
 }
+
+#[cfg(test)]
+mod tests {
+    use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+
+    use super::{find_serial_port_by_usb_id, is_trusted_port, is_wsl_release, UsbPortSelector};
+    use crate::{cli::Config, error::Error};
+
+    fn usb_port(name: &str, serial_number: &str) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: 0x303a,
+                pid: 0x1001,
+                serial_number: Some(serial_number.to_string()),
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn parse_usb_port_selector() {
+        let selector: UsbPortSelector = "303a:0x1001:F4:12".parse().unwrap();
+        assert_eq!(
+            selector,
+            UsbPortSelector {
+                vid: 0x303a,
+                pid: 0x1001,
+                serial: Some("F4:12".to_string())
+            }
+        );
+        assert!("303a".parse::<UsbPortSelector>().is_err());
+        assert!("303a:zzzz".parse::<UsbPortSelector>().is_err());
+    }
+
+    #[test]
+    fn select_usb_port_by_id() {
+        let ports = [
+            usb_port("/dev/ttyACM0", "AAAA"),
+            usb_port("/dev/ttyACM1", "BBBB"),
+        ];
+
+        let selector = "303a:1001:BB".parse().unwrap();
+        let port = find_serial_port_by_usb_id(&ports, &selector).unwrap();
+        assert_eq!(port.port_name, "/dev/ttyACM1");
+
+        let selector = "303a:1001".parse().unwrap();
+        assert!(matches!(
+            find_serial_port_by_usb_id(&ports, &selector),
+            Err(Error::AmbiguousSerialPort(_))
+        ));
+    }
+
+    #[test]
+    fn detect_wsl_kernel() {
+        assert!(is_wsl_release("5.15.153.1-microsoft-standard-WSL2"));
+        assert!(is_wsl_release("4.4.0-19041-Microsoft"));
+        assert!(!is_wsl_release("6.8.0-45-generic"));
+    }
+
+    #[test]
+    fn trusted_ports() {
+        let mut config = Config::default();
+        config
+            .connection
+            .trusted_ports
+            .push("/dev/ttyS5".to_string());
+
+        assert!(is_trusted_port(&config, "/dev/ttyS5"));
+        assert!(!is_trusted_port(&config, "/dev/ttyS4"));
+    }
+}
@@ -0,0 +1,231 @@
+//! Flashing a virtual device
+//!
+//! Flashes a simulated device, a [MockPort], with the same commands as a
+//! connected device, and saves the contents of its flash to an image of the
+//! whole flash on the host. This allows previewing the contents of flash after
+//! flashing, and testing the command line applications without hardware.
+//!
+//! If the image file already exists it is used as the initial contents of
+//! flash, so that repeated runs behave like flashing the same device again.
+
+use std::{fs, io::ErrorKind, path::Path};
+
+use esp_idf_part::DataType;
+use log::info;
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use crate::{
+    cli::partitions_to_erase,
+    connection::{
+        mock::MockPort,
+        reset::{ResetAfterOperation, ResetBeforeOperation},
+    },
+    elf::FirmwareImage,
+    error::MissingPartitionTable,
+    flasher::{ConnectOptions, FlashData, FlashSize, Flasher},
+    output,
+    targets::{Chip, XtalFrequency},
+};
+
+/// Value of erased flash
+const ERASED: u8 = 0xff;
+
+/// The contents of a simulated flash chip
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulatedFlash {
+    size: FlashSize,
+    contents: Vec<u8>,
+}
+
+impl SimulatedFlash {
+    /// Create a flash chip of the given size, which is entirely erased
+    pub fn new(size: FlashSize) -> Self {
+        Self {
+            size,
+            contents: vec![ERASED; size.size() as usize],
+        }
+    }
+
+    /// Load the contents of flash from the image at `path`, or create an
+    /// erased flash chip if there is no such file
+    ///
+    /// The image is truncated or padded with erased flash to `size`.
+    pub fn load(path: &Path, size: FlashSize) -> Result<Self> {
+        let mut flash = Self::new(size);
+
+        match fs::read(path) {
+            Ok(contents) => {
+                let len = contents.len().min(flash.contents.len());
+                flash.contents[..len].copy_from_slice(&contents[..len]);
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Failed to read {}", path.display()))
+            }
+        }
+
+        Ok(flash)
+    }
+
+    /// The size of the flash chip
+    pub fn size(&self) -> FlashSize {
+        self.size
+    }
+
+    /// The contents of the flash chip
+    pub fn contents(&self) -> &[u8] {
+        &self.contents
+    }
+
+    /// Save the contents of the flash chip to `path`
+    pub fn save(&self, path: &Path) -> Result<()> {
+        output::write(path, &self.contents)
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+/// Flash a firmware image to the simulated device whose flash is saved at
+/// `path`
+///
+/// The flash size is taken from the flash settings, defaulting to
+/// [FlashSize::default]. Partitions are erased before writing, as when
+/// flashing a device.
+pub fn simulate_flash<'a>(
+    path: &Path,
+    chip: Chip,
+    image: &'a dyn FirmwareImage<'a>,
+    flash_data: FlashData,
+    xtal_freq: XtalFrequency,
+    erase_parts: Option<Vec<String>>,
+    erase_data_parts: Option<Vec<DataType>>,
+) -> Result<()> {
+    let size = flash_data.flash_settings.size.unwrap_or_default();
+    let flash = SimulatedFlash::load(path, size)?;
+
+    println!("Chip type:         {chip} (simulated)");
+    println!("Flash size:        {size}");
+
+    let flash = flash_simulated(
+        flash,
+        chip,
+        image,
+        flash_data,
+        xtal_freq,
+        erase_parts,
+        erase_data_parts,
+    )?;
+
+    flash.save(path)?;
+    info!("Simulated flash saved to {}", path.display());
+
+    Ok(())
+}
+
+/// Flash a firmware image to a simulated device whose flash holds `flash`,
+/// returning the contents of flash afterwards
+fn flash_simulated<'a>(
+    flash: SimulatedFlash,
+    chip: Chip,
+    image: &'a dyn FirmwareImage<'a>,
+    flash_data: FlashData,
+    xtal_freq: XtalFrequency,
+    erase_parts: Option<Vec<String>>,
+    erase_data_parts: Option<Vec<DataType>>,
+) -> Result<SimulatedFlash> {
+    let SimulatedFlash { size, contents } = flash;
+    let port = MockPort::new(chip, contents);
+
+    // The simulated device is waiting in download mode, and only runs the ROM
+    // loader
+    let options = ConnectOptions::default()
+        .with_chip(chip)
+        .with_use_stub(false)
+        .with_before_operation(ResetBeforeOperation::NoReset)
+        .with_after_operation(ResetAfterOperation::NoReset);
+    let mut flasher = Flasher::connect(port.clone().into(), MockPort::port_info(), options)
+        .wrap_err("Failed to connect to the simulated device")?;
+
+    if erase_parts.is_some() || erase_data_parts.is_some() {
+        let partition_table = flash_data
+            .partition_table
+            .as_ref()
+            .ok_or(MissingPartitionTable)?;
+
+        for part in partitions_to_erase(partition_table, erase_parts, erase_data_parts)? {
+            info!("Erasing {} ({:?})...", part.name(), part.subtype());
+            flasher.erase_region(part.offset(), part.size())?;
+        }
+    }
+
+    flasher.load_image_to_flash(image, flash_data, None, xtal_freq)?;
+
+    Ok(SimulatedFlash {
+        size,
+        contents: port.flash(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{flash_simulated, SimulatedFlash};
+    use crate::{
+        build_flash_plan,
+        elf::ElfFirmwareImage,
+        flasher::{FlashData, FlashSettings, FlashSize},
+        targets::{Chip, XtalFrequency},
+    };
+
+    #[test]
+    fn flash_plan_is_applied() {
+        let elf_data = std::fs::read("resources/apps/esp32c3").unwrap();
+        let flash_data =
+            || FlashData::new(None, None, None, None, FlashSettings::default(), 0, None);
+        let plan = build_flash_plan(
+            &elf_data,
+            Chip::Esp32c3,
+            flash_data(),
+            XtalFrequency::_40Mhz,
+        )
+        .unwrap();
+
+        let image = ElfFirmwareImage::try_from(&elf_data[..]).unwrap();
+        let flash = flash_simulated(
+            SimulatedFlash::new(FlashSize::_4Mb),
+            Chip::Esp32c3,
+            &image,
+            flash_data(),
+            XtalFrequency::_40Mhz,
+            None,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(flash.size(), FlashSize::_4Mb);
+        for (offset, data) in &plan {
+            let offset = *offset as usize;
+            assert_eq!(&flash.contents()[offset..offset + data.len()], data);
+        }
+        // Bootloader image magic
+        assert_eq!(flash.contents()[0], 0xe9);
+    }
+
+    #[test]
+    fn image_must_fit_in_flash() {
+        let elf_data = std::fs::read("resources/apps/esp32c3").unwrap();
+        let flash_data = FlashData::new(None, None, None, None, FlashSettings::default(), 0, None);
+
+        let image = ElfFirmwareImage::try_from(&elf_data[..]).unwrap();
+        assert!(flash_simulated(
+            SimulatedFlash::new(FlashSize::_256Kb),
+            Chip::Esp32c3,
+            &image,
+            flash_data,
+            XtalFrequency::_40Mhz,
+            None,
+            None,
+        )
+        .is_err());
+    }
+}
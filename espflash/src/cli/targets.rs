@@ -0,0 +1,151 @@
+//! Information about the supported target devices
+
+use std::collections::BTreeMap;
+
+use clap::{Args, Subcommand, ValueEnum};
+use miette::{IntoDiagnostic, Result};
+use serde::Serialize;
+use strum::IntoEnumIterator;
+
+use crate::{
+    cli::report::Report,
+    targets::{Chip, ChipMetadata},
+};
+
+/// List the supported chips and their properties, without a device
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct ChipsArgs {
+    /// Chip to list, all chips if omitted
+    #[arg(long, value_enum)]
+    pub chip: Option<Chip>,
+}
+
+/// Print information about the supported target devices
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct TargetsArgs {
+    #[command(subcommand)]
+    pub command: TargetsCommand,
+}
+
+#[derive(Debug, Subcommand)]
+#[non_exhaustive]
+pub enum TargetsCommand {
+    /// Dump the constants espflash uses for a chip
+    ///
+    /// This includes the eFuse and SPI register addresses, the address ranges
+    /// mapped to flash, and the default flash layout.
+    Dump(TargetsDumpArgs),
+}
+
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct TargetsDumpArgs {
+    /// Chip to dump the constants of, all chips if omitted
+    #[arg(long, value_enum)]
+    pub chip: Option<Chip>,
+    /// Output format
+    #[arg(long, value_enum, default_value_t = DumpFormat::Json)]
+    pub format: DumpFormat,
+}
+
+/// Output formats of the dumped constants
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[non_exhaustive]
+pub enum DumpFormat {
+    Json,
+    Toml,
+}
+
+/// Execute a targets subcommand
+pub fn targets(args: TargetsArgs) -> Result<()> {
+    match args.command {
+        TargetsCommand::Dump(args) => {
+            let output = match args.chip {
+                Some(chip) => serialize(&chip.metadata(), args.format)?,
+                None => {
+                    let metadata = Chip::iter()
+                        .map(|chip| (chip.to_string(), chip.metadata()))
+                        .collect::<BTreeMap<_, _>>();
+                    serialize(&metadata, args.format)?
+                }
+            };
+
+            println!("{output}");
+
+            Ok(())
+        }
+    }
+}
+
+fn serialize(value: &impl Serialize, format: DumpFormat) -> Result<String> {
+    match format {
+        DumpFormat::Json => serde_json::to_string_pretty(value).into_diagnostic(),
+        DumpFormat::Toml => toml::to_string(value).into_diagnostic(),
+    }
+}
+
+/// List the supported chips, as text or, with `--output-format json`, as an
+/// array of their metadata
+pub fn chips(args: ChipsArgs, report: &Report) -> Result<()> {
+    let chips = match args.chip {
+        Some(chip) => vec![chip.metadata()],
+        None => Chip::iter().map(Chip::metadata).collect(),
+    };
+
+    if report.is_json() {
+        return report.print(&chips);
+    }
+
+    for (i, metadata) in chips.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print_chip(metadata)?;
+    }
+
+    Ok(())
+}
+
+fn print_chip(metadata: &ChipMetadata) -> Result<()> {
+    let mmu_page_sizes = match &metadata.mmu_page_sizes {
+        Some(sizes) => sizes
+            .iter()
+            .map(|size| format!("{}KB", size / 1024))
+            .collect(),
+        None => vec!["64KB".to_string()],
+    };
+
+    println!("{}", metadata.chip);
+    println!("  Chip ID:           {}", metadata.chip_id);
+    println!("  Boot address:      {:#x}", metadata.boot_addr);
+    println!("  Partition table:   {:#x}", metadata.partition_table_addr);
+    println!("  Application:       {:#x}", metadata.app_addr);
+    println!(
+        "  Flash frequencies: {}",
+        names(&metadata.flash_frequencies)?
+    );
+    println!("  Flash sizes:       {}", names(&metadata.flash_sizes)?);
+    println!("  MMU page sizes:    {}", mmu_page_sizes.join(", "));
+    println!("  Build targets:     {}", metadata.build_targets.join(", "));
+    println!("  Features:          {}", metadata.features.join(", "));
+
+    Ok(())
+}
+
+/// The names of the values, as they are given on the command line and in
+/// configuration files
+fn names<T: Serialize>(values: &[T]) -> Result<String> {
+    let names = values
+        .iter()
+        .map(
+            |value| match serde_json::to_value(value).into_diagnostic()? {
+                serde_json::Value::String(name) => Ok(name),
+                other => Ok(other.to_string()),
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(names.join(", "))
+}
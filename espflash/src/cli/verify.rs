@@ -0,0 +1,119 @@
+//! Comparing the contents of flash with a file or an application
+//!
+//! Each region is compared by its MD5 digest, narrowing down a difference to
+//! the first differing byte without reading back the whole region. With
+//! `--elf`, the regions are those `flash` writes for the application, built
+//! with the same options.
+
+use std::path::PathBuf;
+
+use clap::Args;
+use log::info;
+use miette::Result;
+use serde::Serialize;
+
+use crate::{
+    cli::{
+        config::Config, connect, make_flash_data, map_file, parse_uint32, print_board_info,
+        report::Report, ConnectArgs, FlashConfigArgs, ImageArgs,
+    },
+    error::Error,
+    image_format::build_flash_plan,
+};
+
+/// Compare the contents of flash with a file, or with an application
+#[derive(Debug, Args)]
+#[non_exhaustive]
+pub struct VerifyFlashArgs {
+    /// Address of the contents of the file in flash
+    #[arg(
+        value_name = "ADDR",
+        value_parser = parse_uint32,
+        required_unless_present = "elf",
+        requires = "file"
+    )]
+    pub addr: Option<u32>,
+    /// File with the expected contents of flash
+    #[arg(value_name = "FILE")]
+    pub file: Option<PathBuf>,
+    /// ELF application to compare with the image `flash` would write for it,
+    /// including the bootloader and partition table
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["addr", "file"])]
+    pub elf: Option<PathBuf>,
+    /// Connection configuration
+    #[clap(flatten)]
+    pub connect_args: ConnectArgs,
+    /// Flashing configuration, used to build the image with `--elf`
+    #[clap(flatten)]
+    pub flash_config_args: FlashConfigArgs,
+    /// Image configuration, used to build the image with `--elf`
+    #[clap(flatten)]
+    pub image: ImageArgs,
+}
+
+/// Result of comparing a region of flash
+#[derive(Debug, Clone, Serialize)]
+struct RegionReport {
+    address: u32,
+    size: usize,
+    /// Address of the first byte which differs, if any
+    first_difference: Option<u32>,
+}
+
+/// Compare the contents of flash with a file or an application, failing with
+/// the first differing address
+pub fn verify_flash(args: VerifyFlashArgs, config: &Config, report: &mut Report) -> Result<()> {
+    let mut flasher = connect(&args.connect_args, config, false, false)?;
+    print_board_info(&mut flasher, report)?;
+
+    let regions = match (&args.elf, args.addr, &args.file) {
+        (Some(elf), ..) => {
+            let elf_data = map_file(elf)?;
+            let flash_data = make_flash_data(
+                args.image,
+                &args.flash_config_args,
+                config,
+                None,
+                None,
+                report.format(),
+            )?;
+            let chip = flasher.chip();
+            let xtal_freq = chip.into_target().crystal_freq(flasher.connection())?;
+
+            build_flash_plan(&elf_data, chip, flash_data, xtal_freq)?
+        }
+        // Both are required by clap without `--elf`
+        (None, Some(addr), Some(file)) => vec![(addr, map_file(file)?.to_vec())],
+        _ => unreachable!(),
+    };
+
+    let mut reports = Vec::with_capacity(regions.len());
+    for (address, data) in &regions {
+        info!("Verifying {:#x} bytes at {address:#x}", data.len());
+        let first_difference = flasher.verify_flash(*address, data)?;
+        match first_difference {
+            Some(differs) => outputln!(
+                report,
+                "{address:#010x} ({:#x} bytes): differs at {differs:#x}",
+                data.len()
+            ),
+            None => outputln!(report, "{address:#010x} ({:#x} bytes): matches", data.len()),
+        }
+
+        reports.push(RegionReport {
+            address: *address,
+            size: data.len(),
+            first_difference,
+        });
+    }
+
+    report.print(&reports)?;
+
+    match reports.iter().find_map(|region| region.first_difference) {
+        Some(differs) => Err(Error::FlashContentsDiffer(differs).into()),
+        None => {
+            info!("The flash matches the expected contents");
+            Ok(())
+        }
+    }
+}
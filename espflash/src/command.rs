@@ -3,7 +3,7 @@
 use std::{io::Write, mem::size_of, time::Duration};
 
 use bytemuck::{bytes_of, Pod, Zeroable};
-use strum::Display;
+use strum::{Display, FromRepr};
 
 use crate::flasher::{SpiAttachParams, SpiSetParams};
 
@@ -26,7 +26,7 @@ const SYNC_FRAME: [u8; 36] = [
 /// Types of commands that can be sent to a target device
 ///
 /// https://docs.espressif.com/projects/esptool/en/latest/esp32c3/advanced-topics/serial-protocol.html#supported-by-stub-loader-and-rom-loader
-#[derive(Copy, Clone, Debug, Display)]
+#[derive(Copy, Clone, Debug, Display, PartialEq, Eq, FromRepr)]
 #[non_exhaustive]
 #[repr(u8)]
 pub enum CommandType {
@@ -44,6 +44,8 @@ pub enum CommandType {
     // Commands supported by the ESP32s bootloaders
     SpiSetParams = 0x0B,
     SpiAttach = 0x0D,
+    // Only supported by the ESP32's ROM bootloader
+    ReadFlashSlow = 0x0E,
     ChangeBaudrate = 0x0F,
     FlashDeflBegin = 0x10,
     FlashDeflData = 0x11,
@@ -88,9 +90,9 @@ impl CommandType {
             CommandType::FlashBegin | CommandType::FlashDeflBegin | CommandType::EraseRegion => {
                 calc_timeout(ERASE_REGION_TIMEOUT_PER_MB, size)
             }
-            CommandType::FlashData | CommandType::FlashDeflData => {
-                calc_timeout(ERASE_WRITE_TIMEOUT_PER_MB, size)
-            }
+            CommandType::FlashData
+            | CommandType::FlashDeflData
+            | CommandType::FlashEncryptedData => calc_timeout(ERASE_WRITE_TIMEOUT_PER_MB, size),
             _ => self.timeout(),
         }
     }
@@ -106,6 +108,8 @@ pub enum Command<'a> {
         block_size: u32,
         offset: u32,
         supports_encryption: bool,
+        /// Have the ROM loader encrypt the data as it is written
+        encrypted: bool,
     },
     FlashData {
         data: &'a [u8],
@@ -116,6 +120,13 @@ pub enum Command<'a> {
     FlashEnd {
         reboot: bool,
     },
+    /// Write data which the stub encrypts with the key of the device
+    FlashEncryptedData {
+        data: &'a [u8],
+        pad_to: usize,
+        pad_byte: u8,
+        sequence: u32,
+    },
     MemBegin {
         size: u32,
         blocks: u32,
@@ -163,6 +174,8 @@ pub enum Command<'a> {
         block_size: u32,
         offset: u32,
         supports_encryption: bool,
+        /// Have the ROM loader encrypt the data as it is written
+        encrypted: bool,
     },
     FlashDeflData {
         data: &'a [u8],
@@ -188,8 +201,13 @@ pub enum Command<'a> {
         block_size: u32,
         max_in_flight: u32,
     },
+    ReadFlashSlow {
+        offset: u32,
+        size: u32,
+    },
     RunUserCode,
     FlashDetect,
+    GetSecurityInfo,
 }
 
 impl Command<'_> {
@@ -199,6 +217,7 @@ impl Command<'_> {
             Command::FlashBegin { .. } => CommandType::FlashBegin,
             Command::FlashData { .. } => CommandType::FlashData,
             Command::FlashEnd { .. } => CommandType::FlashEnd,
+            Command::FlashEncryptedData { .. } => CommandType::FlashEncryptedData,
             Command::MemBegin { .. } => CommandType::MemBegin,
             Command::MemData { .. } => CommandType::MemData,
             Command::MemEnd { .. } => CommandType::MemEnd,
@@ -216,8 +235,10 @@ impl Command<'_> {
             Command::EraseFlash { .. } => CommandType::EraseFlash,
             Command::EraseRegion { .. } => CommandType::EraseRegion,
             Command::ReadFlash { .. } => CommandType::ReadFlash,
+            Command::ReadFlashSlow { .. } => CommandType::ReadFlashSlow,
             Command::RunUserCode { .. } => CommandType::RunUserCode,
             Command::FlashDetect => CommandType::FlashDetect,
+            Command::GetSecurityInfo => CommandType::GetSecurityInfo,
         }
     }
 
@@ -237,6 +258,7 @@ impl Command<'_> {
                 block_size,
                 offset,
                 supports_encryption,
+                encrypted,
             } => {
                 begin_command(
                     writer,
@@ -245,6 +267,7 @@ impl Command<'_> {
                     block_size,
                     offset,
                     supports_encryption,
+                    encrypted,
                 )?;
             }
             Command::FlashData {
@@ -258,6 +281,14 @@ impl Command<'_> {
             Command::FlashEnd { reboot } => {
                 write_basic(writer, &[u8::from(!reboot)], 0)?;
             }
+            Command::FlashEncryptedData {
+                pad_to,
+                pad_byte,
+                data,
+                sequence,
+            } => {
+                data_command(writer, data, pad_to, pad_byte, sequence)?;
+            }
             Command::MemBegin {
                 size,
                 blocks,
@@ -272,6 +303,7 @@ impl Command<'_> {
                     block_size,
                     offset,
                     supports_encryption,
+                    false,
                 )?;
             }
             Command::MemData {
@@ -352,6 +384,7 @@ impl Command<'_> {
                 block_size,
                 offset,
                 supports_encryption,
+                encrypted,
             } => {
                 begin_command(
                     writer,
@@ -360,6 +393,7 @@ impl Command<'_> {
                     block_size,
                     offset,
                     supports_encryption,
+                    encrypted,
                 )?;
             }
             Command::FlashDeflData {
@@ -414,12 +448,24 @@ impl Command<'_> {
                 writer.write_all(&block_size.to_le_bytes())?;
                 writer.write_all(&(max_in_flight.to_le_bytes()))?;
             }
+            Command::ReadFlashSlow { offset, size } => {
+                // length
+                writer.write_all(&(8u16.to_le_bytes()))?;
+                // checksum
+                writer.write_all(&(0u32.to_le_bytes()))?;
+                // data
+                writer.write_all(&offset.to_le_bytes())?;
+                writer.write_all(&size.to_le_bytes())?;
+            }
             Command::RunUserCode => {
                 write_basic(writer, &[], 0)?;
             }
             Command::FlashDetect => {
                 write_basic(writer, &[], 0)?;
             }
+            Command::GetSecurityInfo => {
+                write_basic(writer, &[], 0)?;
+            }
         };
         Ok(())
     }
@@ -441,6 +487,7 @@ fn begin_command<W: Write>(
     block_size: u32,
     offset: u32,
     supports_encryption: bool,
+    encrypted: bool,
 ) -> std::io::Result<()> {
     #[derive(Zeroable, Pod, Copy, Clone, Debug)]
     #[repr(C)]
@@ -456,7 +503,7 @@ fn begin_command<W: Write>(
         blocks,
         block_size,
         offset,
-        encrypted: 0,
+        encrypted: encrypted as u32,
     };
 
     let bytes = bytes_of(&params);
@@ -0,0 +1,904 @@
+//! A simulated device, for running the flasher without hardware
+//!
+//! A [MockPort] answers the commands of the ROM loader like a chip in download
+//! mode, and keeps the contents of its flash chip in memory. It records the
+//! commands it receives, so that the command sequence of an operation can be
+//! checked, and it is the device flashed by `flash --simulate`.
+//!
+//! Only the ROM loader is simulated: the RAM stub can not run on the simulated
+//! device, so connect with [ConnectOptions::use_stub] disabled, and without
+//! resetting the device. The flash chip answers the SPI commands which read its
+//! ID, status and contents, and those which erase and program it.
+//!
+//! [ConnectOptions::use_stub]: crate::flasher::ConnectOptions::use_stub
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    mem,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use flate2::write::ZlibDecoder;
+use md5::{Digest, Md5};
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits, UsbPortInfo};
+
+use super::encoder::SlipEncoder;
+use crate::{
+    command::CommandType,
+    error::RomErrorKind,
+    flasher::{stubs::CHIP_DETECT_MAGIC_REG_ADDR, FLASH_SECTOR_SIZE},
+    targets::{Chip, SpiRegisters},
+};
+
+const END: u8 = 0xC0;
+const ESC: u8 = 0xDB;
+const ESC_END: u8 = 0xDC;
+const ESC_ESC: u8 = 0xDD;
+
+/// Value of the responses to the `Sync` command
+const SYNC_VALUE: u32 = 0x2012_0707;
+/// Number of responses the ROM loader sends for each `Sync` command
+const SYNC_RESPONSES: usize = 8;
+/// Number of bytes the ESP32 ROM loader reads per `ReadFlashSlow` command
+const READ_FLASH_SLOW_BLOCK_SIZE: usize = 64;
+/// Flag of the security information set when Secure Download Mode is enabled
+const SECURITY_FLAG_SECURE_DOWNLOAD: u32 = 1 << 2;
+
+/// `USR` bit of the SPI command register, which starts a user command
+const SPI_CMD_USR: u32 = 1 << 18;
+/// `USR_MOSI` bit of the SPI user register
+const SPI_USR_MOSI: u32 = 1 << 27;
+/// `USR_MISO` bit of the SPI user register
+const SPI_USR_MISO: u32 = 1 << 28;
+
+/// JEDEC manufacturer ID of the simulated flash chip (Winbond)
+const FLASH_MANUFACTURER: u8 = 0xef;
+/// JEDEC memory type of the simulated flash chip
+const FLASH_MEMORY_TYPE: u8 = 0x40;
+
+// SPI flash commands the simulated flash chip understands
+const FLASH_WRITE_STATUS: u8 = 0x01;
+const FLASH_PAGE_PROGRAM: u8 = 0x02;
+const FLASH_READ: u8 = 0x03;
+const FLASH_READ_STATUS: u8 = 0x05;
+const FLASH_PAGE_PROGRAM_4B: u8 = 0x12;
+const FLASH_READ_4B: u8 = 0x13;
+const FLASH_READ_STATUS_3: u8 = 0x15;
+const FLASH_SECTOR_ERASE: u8 = 0x20;
+const FLASH_SECTOR_ERASE_4B: u8 = 0x21;
+const FLASH_READ_STATUS_2: u8 = 0x35;
+const FLASH_CHIP_ERASE_ALT: u8 = 0x60;
+const FLASH_JEDEC_ID: u8 = 0x9f;
+const FLASH_CHIP_ERASE: u8 = 0xc7;
+
+/// A port connected to a simulated device, see the [module
+/// documentation](self)
+///
+/// Clones of a port are connected to the same device.
+#[derive(Clone)]
+pub struct MockPort {
+    device: Arc<Mutex<MockDevice>>,
+    baud_rate: u32,
+    timeout: Duration,
+}
+
+impl MockPort {
+    /// Connect to a simulated `chip` in download mode, whose flash chip holds
+    /// `flash`
+    ///
+    /// The size of `flash` is the size of the flash chip, and should be a
+    /// power of two.
+    pub fn new(chip: Chip, flash: Vec<u8>) -> Self {
+        let mut registers = HashMap::new();
+        registers.insert(
+            CHIP_DETECT_MAGIC_REG_ADDR,
+            chip.into_target().chip_detect_magic_values()[0],
+        );
+
+        Self {
+            device: Arc::new(Mutex::new(MockDevice {
+                spi: chip.into_target().spi_registers(),
+                registers,
+                flash,
+                frame: Vec::new(),
+                escaped: false,
+                output: VecDeque::new(),
+                commands: Vec::new(),
+                write: None,
+                chip,
+                secure_download_mode: false,
+                reset_after: None,
+            })),
+            baud_rate: 115_200,
+            timeout: Duration::from_secs(3),
+        }
+    }
+
+    /// Set the register at `addr` to `value`, e.g. to simulate the eFuses of a
+    /// device
+    pub fn with_register(self, addr: u32, value: u32) -> Self {
+        self.device().registers.insert(addr, value);
+        self
+    }
+
+    /// Enable Secure Download Mode, in which the ROM loader rejects the
+    /// commands reading or writing memory and registers
+    pub fn with_secure_download_mode(self) -> Self {
+        self.device().secure_download_mode = true;
+        self
+    }
+
+    /// Reset the device once it received `commands` commands, losing the write
+    /// to flash in progress, e.g. to simulate a brownout
+    pub fn with_reset_after(self, commands: usize) -> Self {
+        self.device().reset_after = Some(commands);
+        self
+    }
+
+    /// USB information of the port, which is not a USB device
+    pub fn port_info() -> UsbPortInfo {
+        UsbPortInfo {
+            vid: 0,
+            pid: 0,
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+        }
+    }
+
+    /// The contents of the flash chip
+    pub fn flash(&self) -> Vec<u8> {
+        self.device().flash.clone()
+    }
+
+    /// The commands the device received, in order
+    ///
+    /// Commands with an unknown opcode are recorded as
+    /// [CommandType::Unknown].
+    pub fn commands(&self) -> Vec<CommandType> {
+        self.device().commands.clone()
+    }
+
+    fn device(&self) -> MutexGuard<'_, MockDevice> {
+        // The device is never left in an inconsistent state, so it can still be
+        // used if a thread panicked while holding the lock
+        self.device.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Debug for MockPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let device = self.device();
+        f.debug_struct("MockPort")
+            .field("chip", &device.chip)
+            .field("flash_size", &device.flash.len())
+            .field("baud_rate", &self.baud_rate)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The state of a simulated device
+struct MockDevice {
+    chip: Chip,
+    spi: SpiRegisters,
+    /// Registers which have been written, or have a fixed value
+    registers: HashMap<u32, u32>,
+    flash: Vec<u8>,
+    /// The unescaped contents of the frame being received
+    frame: Vec<u8>,
+    escaped: bool,
+    /// Encoded responses which have not been read yet
+    output: VecDeque<u8>,
+    commands: Vec<CommandType>,
+    /// The write to flash started by the last `FlashBegin` or
+    /// `FlashDeflBegin` command
+    write: Option<FlashWrite>,
+    secure_download_mode: bool,
+    /// Number of commands after which the device resets
+    reset_after: Option<usize>,
+}
+
+struct FlashWrite {
+    /// Offset the next data is written at
+    offset: u32,
+    /// Decompressor of the data of a compressed write
+    decoder: Option<ZlibDecoder<Vec<u8>>>,
+}
+
+impl MockDevice {
+    /// Receive a byte of a SLIP-encoded command
+    fn receive(&mut self, byte: u8) {
+        match byte {
+            END => {
+                if !self.frame.is_empty() {
+                    let frame = mem::take(&mut self.frame);
+                    self.handle(&frame);
+                }
+                self.escaped = false;
+            }
+            ESC => self.escaped = true,
+            _ if self.escaped => {
+                self.escaped = false;
+                self.frame.push(match byte {
+                    ESC_END => END,
+                    ESC_ESC => ESC,
+                    byte => byte,
+                });
+            }
+            _ => self.frame.push(byte),
+        }
+    }
+
+    /// Handle a command packet, queueing the responses to it
+    fn handle(&mut self, packet: &[u8]) {
+        // Packets shorter than the header are ignored, as by the ROM loader
+        let [0, op, ..] = *packet else {
+            return;
+        };
+        let Some(data) = packet.get(8..) else {
+            return;
+        };
+
+        let command = CommandType::from_repr(op).unwrap_or(CommandType::Unknown);
+        self.commands.push(command);
+
+        let responses = if command == CommandType::Sync {
+            SYNC_RESPONSES
+        } else {
+            1
+        };
+        let (value, data, status) = match self.execute(command, data) {
+            Ok((value, data)) => (value, data, [0; 4]),
+            Err(kind) => (0, Vec::new(), [1, kind as u8, 0, 0]),
+        };
+        if self.reset_after == Some(self.commands.len()) {
+            self.write = None;
+        }
+
+        for _ in 0..responses {
+            let mut response = vec![1, op];
+            response.extend_from_slice(&((data.len() + status.len()) as u16).to_le_bytes());
+            response.extend_from_slice(&value.to_le_bytes());
+            response.extend_from_slice(&data);
+            response.extend_from_slice(&status);
+
+            let mut encoder = SlipEncoder::new(&mut self.output).unwrap();
+            encoder.write_all(&response).unwrap();
+            encoder.finish().unwrap();
+        }
+    }
+
+    /// Execute a command, returning the value and data of the response
+    fn execute(
+        &mut self,
+        command: CommandType,
+        data: &[u8],
+    ) -> Result<(u32, Vec<u8>), RomErrorKind> {
+        let word = |index: usize| {
+            data.get(index * 4..index * 4 + 4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                .ok_or(RomErrorKind::BadDataLen)
+        };
+
+        if self.secure_download_mode
+            && matches!(
+                command,
+                CommandType::ReadReg
+                    | CommandType::WriteReg
+                    | CommandType::FlashMd5
+                    | CommandType::ReadFlashSlow
+                    | CommandType::MemBegin
+                    | CommandType::MemData
+                    | CommandType::MemEnd
+            )
+        {
+            return Err(RomErrorKind::InvalidMessage);
+        }
+
+        match command {
+            CommandType::Sync => Ok((SYNC_VALUE, Vec::new())),
+            CommandType::ReadReg => Ok((self.register(word(0)?), Vec::new())),
+            CommandType::WriteReg => {
+                for write in data.chunks(16) {
+                    let [addr, value, mask] = [0, 1, 2].map(|i| {
+                        write
+                            .get(i * 4..i * 4 + 4)
+                            .map_or(0, |bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+                    });
+                    let value = (self.register(addr) & !mask) | (value & mask);
+                    self.registers.insert(addr, value);
+
+                    if addr == self.spi.cmd() && value & SPI_CMD_USR != 0 {
+                        self.spi_transaction();
+                    }
+                }
+                Ok((0, Vec::new()))
+            }
+            CommandType::FlashBegin | CommandType::FlashDeflBegin => {
+                let (size, offset) = (word(0)?, word(3)?);
+                let sector_size = FLASH_SECTOR_SIZE as u32;
+                self.erase(offset, size.div_ceil(sector_size) * sector_size)?;
+                self.write = Some(FlashWrite {
+                    offset,
+                    decoder: (command == CommandType::FlashDeflBegin)
+                        .then(|| ZlibDecoder::new(Vec::new())),
+                });
+                Ok((0, Vec::new()))
+            }
+            CommandType::FlashData | CommandType::FlashDeflData => {
+                let size = word(0)? as usize;
+                let payload = data.get(16..16 + size).ok_or(RomErrorKind::BadDataLen)?;
+                let mut write = self.write.take().ok_or(RomErrorKind::FailedToAct)?;
+
+                let written = match &mut write.decoder {
+                    Some(decoder) => {
+                        decoder
+                            .write_all(payload)
+                            .and_then(|_| decoder.flush())
+                            .map_err(|_| RomErrorKind::DeflateError)?;
+                        mem::take(decoder.get_mut())
+                    }
+                    None => payload.to_vec(),
+                };
+                self.program(write.offset, &written)?;
+                write.offset += written.len() as u32;
+                self.write = Some(write);
+
+                Ok((0, Vec::new()))
+            }
+            CommandType::FlashEnd | CommandType::FlashDeflEnd => {
+                self.write = None;
+                Ok((0, Vec::new()))
+            }
+            CommandType::FlashMd5 => {
+                let (offset, size) = (word(0)?, word(1)?);
+                let digest = Md5::digest(self.region(offset, size)?);
+                let hex = digest
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect::<String>();
+                Ok((0, hex.into_bytes()))
+            }
+            CommandType::ReadFlashSlow if self.chip == Chip::Esp32 => {
+                let (offset, size) = (word(0)?, word(1)?);
+                let len = (size as usize).min(READ_FLASH_SLOW_BLOCK_SIZE);
+                let mut block = self.region(offset, len as u32)?.to_vec();
+                block.resize(READ_FLASH_SLOW_BLOCK_SIZE, 0);
+                Ok((0, block))
+            }
+            CommandType::GetSecurityInfo if self.chip != Chip::Esp32 => {
+                let flags = if self.secure_download_mode {
+                    SECURITY_FLAG_SECURE_DOWNLOAD
+                } else {
+                    0
+                };
+                // The flags, the eFuse flash encryption count and the purposes
+                // of the 7 key blocks, and except on the ESP32-S2 the chip ID
+                // and the API version
+                let mut info = flags.to_le_bytes().to_vec();
+                info.extend_from_slice(&[0; 8]);
+                if self.chip != Chip::Esp32s2 {
+                    let chip_id = self.chip.into_target().params().chip_id as u32;
+                    info.extend_from_slice(&chip_id.to_le_bytes());
+                    info.extend_from_slice(&0u32.to_le_bytes());
+                }
+                Ok((0, info))
+            }
+            CommandType::SpiAttach
+            | CommandType::SpiSetParams
+            | CommandType::ChangeBaudrate
+            | CommandType::MemBegin
+            | CommandType::MemData
+            | CommandType::MemEnd => Ok((0, Vec::new())),
+            _ => Err(RomErrorKind::InvalidMessage),
+        }
+    }
+
+    fn register(&self, addr: u32) -> u32 {
+        self.registers.get(&addr).copied().unwrap_or(0)
+    }
+
+    /// Run the user command set up in the SPI registers on the flash chip
+    fn spi_transaction(&mut self) {
+        let usr = self.register(self.spi.usr());
+        let opcode = self.register(self.spi.usr2()) as u8;
+        let (mosi_bits, miso_bits) = match (self.spi.mosi_length(), self.spi.miso_length()) {
+            (Some(mosi), Some(miso)) => (self.register(mosi) + 1, self.register(miso) + 1),
+            _ => {
+                let usr1 = self.register(self.spi.usr1());
+                (((usr1 >> 17) & 0x1ff) + 1, ((usr1 >> 8) & 0x1ff) + 1)
+            }
+        };
+        let mosi_len = if usr & SPI_USR_MOSI != 0 {
+            mosi_bits as usize / 8
+        } else {
+            0
+        };
+        let miso_len = if usr & SPI_USR_MISO != 0 {
+            miso_bits as usize / 8
+        } else {
+            0
+        };
+
+        let data = (0..mosi_len.div_ceil(4) as u32)
+            .flat_map(|i| self.register(self.spi.w0() + i * 4).to_le_bytes())
+            .take(mosi_len)
+            .collect::<Vec<_>>();
+        let mut response = self.flash_command(opcode, &data, miso_len);
+        response.resize(miso_len.next_multiple_of(4), 0);
+
+        for (i, word) in response.chunks(4).enumerate() {
+            self.registers.insert(
+                self.spi.w0() + i as u32 * 4,
+                u32::from_le_bytes(word.try_into().unwrap()),
+            );
+        }
+    }
+
+    /// Run a command on the flash chip, returning up to `len` bytes it sends
+    /// back
+    ///
+    /// Failed erases and writes are ignored, like a flash chip ignores
+    /// commands outside of its address space.
+    fn flash_command(&mut self, opcode: u8, data: &[u8], len: usize) -> Vec<u8> {
+        let address = |width: usize| {
+            data.get(..width).map_or(0, |bytes| {
+                bytes
+                    .iter()
+                    .fold(0, |addr, byte| (addr << 8) | *byte as u32)
+            })
+        };
+
+        match opcode {
+            FLASH_JEDEC_ID => vec![
+                FLASH_MANUFACTURER,
+                FLASH_MEMORY_TYPE,
+                self.flash.len().ilog2() as u8,
+            ],
+            FLASH_READ | FLASH_READ_4B => {
+                let addr = address(if opcode == FLASH_READ { 3 } else { 4 }) as usize;
+                let start = addr.min(self.flash.len());
+                let end = addr.saturating_add(len).min(self.flash.len());
+                self.flash[start..end].to_vec()
+            }
+            FLASH_PAGE_PROGRAM | FLASH_PAGE_PROGRAM_4B => {
+                let width = if opcode == FLASH_PAGE_PROGRAM { 3 } else { 4 };
+                let _ = self.program(address(width), data.get(width..).unwrap_or_default());
+                Vec::new()
+            }
+            FLASH_SECTOR_ERASE | FLASH_SECTOR_ERASE_4B => {
+                let width = if opcode == FLASH_SECTOR_ERASE { 3 } else { 4 };
+                let _ = self.erase(address(width), FLASH_SECTOR_SIZE as u32);
+                Vec::new()
+            }
+            FLASH_CHIP_ERASE | FLASH_CHIP_ERASE_ALT => {
+                self.flash.fill(0xff);
+                Vec::new()
+            }
+            // The flash chip is never busy, and has no status bits set
+            FLASH_READ_STATUS | FLASH_READ_STATUS_2 | FLASH_READ_STATUS_3 => vec![0; len],
+            FLASH_WRITE_STATUS => Vec::new(),
+            // Anything else, e.g. reading the SFDP, is answered with zeros
+            _ => vec![0; len],
+        }
+    }
+
+    fn region(&mut self, offset: u32, size: u32) -> Result<&mut [u8], RomErrorKind> {
+        let start = offset as usize;
+        let end = start
+            .checked_add(size as usize)
+            .filter(|end| *end <= self.flash.len())
+            .ok_or(RomErrorKind::FailedToAct)?;
+
+        Ok(&mut self.flash[start..end])
+    }
+
+    fn erase(&mut self, offset: u32, size: u32) -> Result<(), RomErrorKind> {
+        self.region(offset, size)?.fill(0xff);
+        Ok(())
+    }
+
+    /// Program `data` at `offset`, which like programming NOR flash can only
+    /// clear bits
+    fn program(&mut self, offset: u32, data: &[u8]) -> Result<(), RomErrorKind> {
+        let region = self
+            .region(offset, data.len() as u32)
+            .map_err(|_| RomErrorKind::FlashWriteError)?;
+        for (byte, data) in region.iter_mut().zip(data) {
+            *byte &= data;
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for MockPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut device = self.device();
+        if device.output.is_empty() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+
+        let len = buf.len().min(device.output.len());
+        for (dst, src) in buf.iter_mut().zip(device.output.drain(..len)) {
+            *dst = src;
+        }
+
+        Ok(len)
+    }
+}
+
+impl Write for MockPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut device = self.device();
+        for byte in buf {
+            device.receive(*byte);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for MockPort {
+    fn name(&self) -> Option<String> {
+        Some(format!("simulated {}", self.device().chip))
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _data_bits: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, _flow_control: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_parity(&mut self, _parity: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, _stop_bits: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, _level: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.device().output.len() as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        if buffer_to_clear != ClearBuffer::Output {
+            self.device().output.clear();
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+
+    use super::MockPort;
+    use crate::{
+        build_flash_plan,
+        command::CommandType,
+        connection::{
+            reset::{ResetAfterOperation, ResetBeforeOperation},
+            Connection,
+        },
+        elf::{ElfFirmwareImage, FirmwareImage},
+        error::Error,
+        flasher::{
+            stubs::CHIP_DETECT_MAGIC_REG_ADDR, ConnectOptions, FlashData, FlashSettings, FlashSize,
+            Flasher,
+        },
+        image_format::ImageFormat,
+        targets::{
+            Chip, Esp32Params, Esp32c3, ReadEFuse, SpiRegisters, Target, XtalFrequency,
+            BROWNOUT_RESET_REASON,
+        },
+    };
+
+    fn connect(port: &MockPort, chip: Chip) -> Flasher {
+        let options = ConnectOptions::default()
+            .with_chip(chip)
+            .with_use_stub(false)
+            .with_before_operation(ResetBeforeOperation::NoReset)
+            .with_after_operation(ResetAfterOperation::NoReset);
+
+        Flasher::connect(port.clone().into(), MockPort::port_info(), options).unwrap()
+    }
+
+    /// Flash the ESP32-C3 application to `port`, checking that the flash plan
+    /// ends up in flash
+    fn flash_app(port: &MockPort) {
+        let elf_data = std::fs::read("resources/apps/esp32c3").unwrap();
+        let flash_data =
+            || FlashData::new(None, None, None, None, FlashSettings::default(), 0, None);
+        let xtal_freq = XtalFrequency::_40Mhz;
+
+        let mut flasher = connect(port, Chip::Esp32c3);
+        let image = ElfFirmwareImage::try_from(&elf_data[..]).unwrap();
+        flasher
+            .load_image_to_flash(&image, flash_data(), None, xtal_freq)
+            .unwrap();
+
+        let flash = port.flash();
+        for (offset, data) in
+            build_flash_plan(&elf_data, Chip::Esp32c3, flash_data(), xtal_freq).unwrap()
+        {
+            let offset = offset as usize;
+            assert_eq!(&flash[offset..offset + data.len()], data);
+        }
+    }
+
+    #[test]
+    fn flashing_writes_the_flash_plan() {
+        let port = MockPort::new(Chip::Esp32c3, vec![0xff; 0x40_0000]);
+        flash_app(&port);
+
+        let commands = port.commands();
+        assert_eq!(commands[0], CommandType::Sync);
+        assert_eq!(commands[1], CommandType::ReadReg);
+        assert!(commands.contains(&CommandType::SpiAttach));
+        assert!(!commands.contains(&CommandType::MemBegin));
+        for command in [
+            CommandType::FlashDeflBegin,
+            CommandType::FlashDeflData,
+            CommandType::FlashDeflEnd,
+            CommandType::FlashMd5,
+        ] {
+            assert!(commands.contains(&command), "{command} was not sent");
+        }
+        assert!(!commands.contains(&CommandType::Unknown));
+    }
+
+    #[test]
+    fn flashing_resumes_after_the_device_was_reset() {
+        let port = MockPort::new(Chip::Esp32c3, vec![0xff; 0x40_0000]);
+        flash_app(&port);
+        let commands = port.commands();
+        let last_data = commands
+            .iter()
+            .rposition(|command| *command == CommandType::FlashDeflData)
+            .unwrap();
+
+        // Reset the device right before the last block of data is sent
+        let port = MockPort::new(Chip::Esp32c3, vec![0xff; 0x40_0000]).with_reset_after(last_data);
+        flash_app(&port);
+
+        let resumed = &port.commands()[last_data..];
+        assert_eq!(resumed[0], CommandType::FlashDeflData);
+        assert!(resumed.contains(&CommandType::Sync));
+        assert!(resumed.contains(&CommandType::SpiAttach));
+        assert!(resumed.contains(&CommandType::FlashDeflBegin));
+    }
+
+    /// A chip which is not supported by the crate, but like the ESP32-C3
+    struct Experimental;
+
+    const EXPERIMENTAL_MAGIC: u32 = 0x1234_5678;
+
+    impl ReadEFuse for Experimental {
+        fn efuse_reg(&self) -> u32 {
+            Esp32c3.efuse_reg()
+        }
+    }
+
+    impl Target for Experimental {
+        fn chip(&self) -> Chip {
+            Chip::Esp32c3
+        }
+
+        fn chip_detect_magic_values(&self) -> &[u32] {
+            &[EXPERIMENTAL_MAGIC]
+        }
+
+        fn chip_features(&self, connection: &mut Connection) -> Result<Vec<&str>, Error> {
+            Esp32c3.chip_features(connection)
+        }
+
+        fn major_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
+            Esp32c3.major_chip_version(connection)
+        }
+
+        fn minor_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
+            Esp32c3.minor_chip_version(connection)
+        }
+
+        fn crystal_freq(&self, connection: &mut Connection) -> Result<XtalFrequency, Error> {
+            Esp32c3.crystal_freq(connection)
+        }
+
+        fn get_esp_idf_image<'a>(
+            &self,
+            image: &'a dyn FirmwareImage<'a>,
+            flash_data: FlashData,
+            chip_revision: Option<(u32, u32)>,
+            xtal_freq: XtalFrequency,
+        ) -> Result<ImageFormat<'a>, Error> {
+            Esp32c3.get_esp_idf_image(image, flash_data, chip_revision, xtal_freq)
+        }
+
+        fn flash_ranges(&self) -> &[Range<u32>] {
+            Esp32c3.flash_ranges()
+        }
+
+        fn params(&self) -> Esp32Params {
+            Esp32c3.params()
+        }
+
+        fn spi_registers(&self) -> SpiRegisters {
+            Esp32c3.spi_registers()
+        }
+
+        fn supported_build_targets(&self) -> &[&str] {
+            Esp32c3.supported_build_targets()
+        }
+    }
+
+    #[test]
+    fn connects_with_a_target_implemented_outside_of_the_crate() {
+        let port = MockPort::new(Chip::Esp32c3, vec![0xff; 0x40_0000])
+            .with_register(CHIP_DETECT_MAGIC_REG_ADDR, EXPERIMENTAL_MAGIC);
+        let options = ConnectOptions::default()
+            .with_target(Experimental)
+            .with_use_stub(false)
+            .with_before_operation(ResetBeforeOperation::NoReset)
+            .with_after_operation(ResetAfterOperation::NoReset);
+        let flasher =
+            Flasher::connect(port.clone().into(), MockPort::port_info(), options).unwrap();
+
+        assert_eq!(flasher.chip(), Chip::Esp32c3);
+        assert_eq!(
+            flasher.target().chip_detect_magic_values(),
+            [EXPERIMENTAL_MAGIC]
+        );
+
+        // The magic value of the chip named by the target is not accepted
+        let port = MockPort::new(Chip::Esp32c3, vec![0xff; 0x40_0000]);
+        let options = ConnectOptions::default()
+            .with_target(Experimental)
+            .with_use_stub(false)
+            .with_before_operation(ResetBeforeOperation::NoReset);
+        assert!(matches!(
+            Flasher::connect(port.into(), MockPort::port_info(), options),
+            Err(Error::ChipDetectError(_))
+        ));
+    }
+
+    #[test]
+    fn detects_the_flash_size() {
+        let port = MockPort::new(Chip::Esp32s3, vec![0xff; 0x80_0000]);
+        let mut flasher = connect(&port, Chip::Esp32s3);
+
+        assert_eq!(flasher.device_info().unwrap().flash_size, FlashSize::_8Mb);
+    }
+
+    #[test]
+    fn preflight_reports_the_reset_reason_captured_while_connecting() {
+        // RTC_CNTL_RESET_STATE_REG and RTC_CNTL_BROWN_OUT_REG of the ESP32-C3
+        let port = MockPort::new(Chip::Esp32c3, vec![0xff; 0x40_0000])
+            .with_register(0x6000_8038, BROWNOUT_RESET_REASON)
+            .with_register(0x6000_80d8, 1 << 31);
+        let mut flasher = connect(&port, Chip::Esp32c3);
+        port.clone().with_register(0x6000_8038, 1);
+
+        let preflight = flasher.preflight().unwrap();
+        assert_eq!(preflight.brownout_reset(), Some(true));
+        assert_eq!(preflight.brownout_detected, Some(true));
+        assert_eq!(preflight.problems().len(), 2);
+        assert!(preflight.unknown().is_empty());
+    }
+
+    #[test]
+    fn erases_regions() {
+        let port = MockPort::new(Chip::Esp32, vec![0; 0x40_0000]);
+        let mut flasher = connect(&port, Chip::Esp32);
+        flasher.erase_region(0x1000, 0x2000).unwrap();
+
+        let flash = port.flash();
+        assert!(flash[..0x1000].iter().all(|b| *b == 0));
+        assert!(flash[0x1000..0x3000].iter().all(|b| *b == 0xff));
+        assert!(flash[0x3000..].iter().all(|b| *b == 0));
+        assert_eq!(port.commands().last(), Some(&CommandType::FlashBegin));
+    }
+
+    #[test]
+    fn erases_the_flash_in_secure_download_mode() {
+        let port = MockPort::new(Chip::Esp32c3, vec![0; 0x40_0000]).with_secure_download_mode();
+        let options = ConnectOptions::default()
+            .with_use_stub(false)
+            .with_before_operation(ResetBeforeOperation::NoReset)
+            .with_after_operation(ResetAfterOperation::NoReset);
+        let mut flasher =
+            Flasher::connect(port.clone().into(), MockPort::port_info(), options).unwrap();
+        assert_eq!(flasher.chip(), Chip::Esp32c3);
+        assert!(flasher.secure_download_mode());
+
+        flasher.erase_flash().unwrap();
+
+        assert!(port.flash().iter().all(|b| *b == 0xff));
+        assert!(!port.commands().contains(&CommandType::WriteReg));
+        assert_eq!(port.commands().last(), Some(&CommandType::FlashBegin));
+    }
+}
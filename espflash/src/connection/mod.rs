@@ -5,13 +5,16 @@
 //! device.
 
 use std::{
+    collections::VecDeque,
     io::{BufWriter, Read, Write},
     iter::zip,
+    str::FromStr,
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use log::{debug, info};
+use log::{debug, info, trace, warn};
+use md5::{Digest, Md5};
 use regex::Regex;
 use serialport::{SerialPort, UsbPortInfo};
 use slip_codec::SlipDecoder;
@@ -22,25 +25,47 @@ use self::{
     encoder::SlipEncoder,
     reset::{
         construct_reset_strategy_sequence, hard_reset, reset_after_flash, ClassicReset,
-        ResetAfterOperation, ResetBeforeOperation, ResetStrategy, UsbJtagSerialReset,
+        CustomReset, ResetAction, ResetAfterOperation, ResetBeforeOperation, ResetHook, ResetStep,
+        ResetStrategy, UsbJtagSerialReset,
     },
 };
 use crate::{
     command::{Command, CommandType},
     connection::reset::soft_reset,
     error::{ConnectionError, Error, ResultExt, RomError, RomErrorKind},
+    flasher::{
+        compat::Compatibility,
+        deflate::{BlockSize, CompressionLevel},
+        load_stub,
+        stubs::FlashStub,
+        SpiAttachParams, SpiSetParams,
+    },
 };
 
+pub mod mock;
+pub mod network;
+mod port;
 pub mod reset;
 
-const MAX_CONNECT_ATTEMPTS: usize = 7;
+/// Default number of attempts to reset and synchronize with a device
+pub const DEFAULT_CONNECT_ATTEMPTS: usize = 7;
+/// Default number of times a write to flash is resumed after a transient error
+pub const DEFAULT_WRITE_RETRIES: u32 = 3;
+/// Number of responses to read for each synchronization command
+const MAX_SYNC_RESPONSES: usize = 7;
 const MAX_SYNC_ATTEMPTS: usize = 5;
+/// How long to wait for a disconnected port to reappear when reconnecting
+const REOPEN_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of bytes the ROM loader of the ESP32 reads per `ReadFlashSlow`
+/// command
+const READ_FLASH_SLOW_BLOCK_SIZE: u32 = 64;
 pub(crate) const USB_SERIAL_JTAG_PID: u16 = 0x1001;
+pub(crate) const USB_OTG_PID: u16 = 0x0002;
+/// PID of the ESP USB Bridge firmware, e.g. on the secondary MCU of a devkit
+pub(crate) const USB_BRIDGE_PID: u16 = 0x1002;
+pub(crate) const ESPRESSIF_USB_VID: u16 = 0x303a;
 
-#[cfg(unix)]
-pub type Port = serialport::TTYPort;
-#[cfg(windows)]
-pub type Port = serialport::COMPort;
+pub use self::port::{NativePort, Port};
 
 #[derive(Debug, Clone)]
 pub enum CommandResponseValue {
@@ -85,6 +110,39 @@ impl TryInto<Vec<u8>> for CommandResponseValue {
     }
 }
 
+/// The kind of port a device is connected through, as told by its USB IDs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display)]
+#[non_exhaustive]
+#[strum(serialize_all = "kebab-case")]
+pub enum PortKind {
+    /// The USB-Serial-JTAG peripheral of the chip
+    UsbSerialJtag,
+    /// The USB-OTG peripheral of the chip
+    UsbOtg,
+    /// The ESP USB Bridge, a separate MCU which passes the UART of the chip,
+    /// and its JTAG interface, through to USB
+    UsbBridge,
+    /// Any other USB-to-UART bridge, or a port which is not a USB device
+    Uart,
+}
+
+impl PortKind {
+    /// The kind of a USB port with the given vendor and product IDs
+    pub fn from_usb_ids(vid: u16, pid: u16) -> Self {
+        match (vid, pid) {
+            (ESPRESSIF_USB_VID, USB_SERIAL_JTAG_PID) => Self::UsbSerialJtag,
+            (ESPRESSIF_USB_VID, USB_OTG_PID) => Self::UsbOtg,
+            (ESPRESSIF_USB_VID, USB_BRIDGE_PID) => Self::UsbBridge,
+            _ => Self::Uart,
+        }
+    }
+
+    /// Whether the chip can be debugged through the same USB device as well
+    pub fn has_jtag(&self) -> bool {
+        matches!(self, Self::UsbSerialJtag | Self::UsbBridge)
+    }
+}
+
 /// A response from a target device following a command
 #[derive(Debug, Clone)]
 pub struct CommandResponse {
@@ -96,6 +154,143 @@ pub struct CommandResponse {
     pub status: u8,
 }
 
+/// How to let the connection settle once the flasher stub has started
+///
+/// The stub re-initializes the USB-Serial-JTAG peripheral of chips like the
+/// ESP32-C3 and ESP32-C6, and the first commands sent while it does so tend to
+/// time out. Settling waits for `delay`, optionally discards anything received
+/// in the meantime, and retries the first command up to `retries` times,
+/// doubling the delay each time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StubSettle {
+    /// Time to wait before sending the first command to the stub
+    pub delay: Duration,
+    /// Discard any data received while waiting
+    pub drain: bool,
+    /// Number of times to retry the first command when it times out
+    pub retries: u32,
+}
+
+impl StubSettle {
+    /// Send the first command immediately, without retrying it
+    pub const NONE: Self = Self {
+        delay: Duration::ZERO,
+        drain: false,
+        retries: 0,
+    };
+
+    /// Default for USB-Serial-JTAG ports
+    pub const USB_SERIAL_JTAG: Self = Self {
+        delay: Duration::from_millis(50),
+        drain: true,
+        retries: 3,
+    };
+}
+
+impl FromStr for StubSettle {
+    type Err = Error;
+
+    /// Parse `off`, or the delay in milliseconds optionally followed by the
+    /// number of retries, e.g. `100,5`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("off") {
+            return Ok(Self::NONE);
+        }
+
+        let invalid = || Error::InvalidStubSettle(s.into());
+        let (delay, retries) = match s.split_once(',') {
+            Some((delay, retries)) => (delay, retries.trim().parse().map_err(|_| invalid())?),
+            None => (s, Self::USB_SERIAL_JTAG.retries),
+        };
+        let delay = delay.trim().parse().map_err(|_| invalid())?;
+
+        Ok(Self {
+            delay: Duration::from_millis(delay),
+            drain: true,
+            retries,
+        })
+    }
+}
+
+/// How to reset a device into its bootloader when connecting to it
+///
+/// Each attempt resets the device with the next strategy of the sequence,
+/// starting over once all of them were tried, and then tries to synchronize
+/// with it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ConnectStrategy {
+    /// Number of attempts, defaults to [DEFAULT_CONNECT_ATTEMPTS]
+    pub attempts: Option<usize>,
+    /// Reset strategies to attempt in order, defaults to the ones suiting the
+    /// serial port and operating system
+    pub reset_sequence: Option<Vec<ResetStep>>,
+    /// Reset sequence to use for every attempt instead of the reset
+    /// strategies, for boards with unusual wiring of the DTR and RTS lines
+    pub custom_reset: Option<CustomReset>,
+    /// External command which resets the device instead of the serial control
+    /// lines, both into download mode and into the application
+    pub reset_hook: Option<ResetHook>,
+}
+
+impl ConnectStrategy {
+    /// Make `attempts` attempts to connect
+    pub fn with_attempts(mut self, attempts: usize) -> Self {
+        self.attempts = Some(attempts);
+        self
+    }
+
+    /// Attempt the reset strategies of `reset_sequence` in order
+    pub fn with_reset_sequence(mut self, reset_sequence: Vec<ResetStep>) -> Self {
+        self.reset_sequence = Some(reset_sequence);
+        self
+    }
+
+    /// Reset with `custom_reset` instead of any reset strategies
+    pub fn with_custom_reset(mut self, custom_reset: CustomReset) -> Self {
+        self.custom_reset = Some(custom_reset);
+        self
+    }
+
+    /// Reset by running `reset_hook`, before connecting and afterwards
+    pub fn with_reset_hook(mut self, reset_hook: ResetHook) -> Self {
+        self.reset_hook = Some(reset_hook);
+        self
+    }
+}
+
+/// Counters of events on a connection, for diagnosing unreliable links
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ConnectionStats {
+    /// Commands sent
+    pub commands: u64,
+    /// Commands which timed out waiting for their response
+    pub timeouts: u64,
+    /// Retries of the first command after the flasher stub started
+    pub settle_retries: u64,
+    /// Bytes discarded while waiting for the flasher stub to settle
+    pub drained_bytes: u64,
+    /// Resynchronizations after a write to flash failed
+    pub resyncs: u64,
+}
+
+/// What the flasher set up on the device after connecting to it, which is set
+/// up again when [Connection::resync] has to reconnect to the device
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Session {
+    /// The flasher stub running on the device
+    pub stub: Option<FlashStub>,
+    /// Size of the blocks the stub was written to RAM in
+    pub max_ram_block_size: usize,
+    /// Parameters flash was attached with
+    pub spi_attach: Option<SpiAttachParams>,
+    /// Parameters of the flash chip sent to the loader
+    pub spi_set_params: Option<SpiSetParams>,
+    /// Baud rate sent to the loader, and the one of the port
+    pub baud: Option<(u32, u32)>,
+}
+
 /// An established connection with a target device
 pub struct Connection {
     serial: Port,
@@ -103,6 +298,18 @@ pub struct Connection {
     decoder: SlipDecoder,
     after_operation: ResetAfterOperation,
     before_operation: ResetBeforeOperation,
+    data_window: usize,
+    write_retries: u32,
+    compression_level: CompressionLevel,
+    block_size: BlockSize,
+    compatibility: Option<Compatibility>,
+    stub_settle: Option<StubSettle>,
+    connect_strategy: ConnectStrategy,
+    stats: ConnectionStats,
+    /// Whether the flasher stub was found running on a device held in download
+    /// mode
+    held_stub: bool,
+    session: Session,
 }
 
 impl Connection {
@@ -118,19 +325,68 @@ impl Connection {
             decoder: SlipDecoder::new(),
             after_operation,
             before_operation,
+            data_window: 1,
+            write_retries: DEFAULT_WRITE_RETRIES,
+            compression_level: CompressionLevel::default(),
+            block_size: BlockSize::default(),
+            compatibility: None,
+            stub_settle: None,
+            connect_strategy: ConnectStrategy::default(),
+            stats: ConnectionStats::default(),
+            held_stub: false,
+            session: Session::default(),
         }
     }
 
+    /// Reset and connect to the device according to `strategy` in
+    /// [Connection::begin]
+    pub fn with_connect_strategy(mut self, strategy: ConnectStrategy) -> Self {
+        self.connect_strategy = strategy;
+        self
+    }
+
     /// Initialize a connection with a device
     pub fn begin(&mut self) -> Result<(), Error> {
-        let port_name = self.serial.name().unwrap_or_default();
-        let reset_sequence = construct_reset_strategy_sequence(
-            &port_name,
-            self.port_info.pid,
-            self.before_operation,
-        );
+        if self.before_operation == ResetBeforeOperation::DownloadModeHold {
+            match self.sync_held() {
+                Ok(stub) => {
+                    info!("Reusing the device held in download mode");
+                    self.held_stub = stub;
+                    return Ok(());
+                }
+                Err(e) => debug!("No device held in download mode ({e}), resetting it"),
+            }
+        }
+
+        let reset_sequence = match &self.connect_strategy {
+            ConnectStrategy {
+                reset_hook: Some(hook),
+                ..
+            } => vec![Box::new(hook.clone()) as Box<dyn ResetStrategy>],
+            ConnectStrategy {
+                custom_reset: Some(custom),
+                ..
+            } => vec![Box::new(custom.clone()) as Box<dyn ResetStrategy>],
+            ConnectStrategy {
+                reset_sequence: Some(steps),
+                ..
+            } if !steps.is_empty() => steps.iter().map(ResetStep::strategy).collect(),
+            _ => {
+                let port_name = self.serial.name().unwrap_or_default();
+                construct_reset_strategy_sequence(
+                    &port_name,
+                    self.port_info.vid,
+                    self.port_info.pid,
+                    self.before_operation,
+                )
+            }
+        };
+        let attempts = self
+            .connect_strategy
+            .attempts
+            .unwrap_or(DEFAULT_CONNECT_ATTEMPTS);
 
-        for (_, reset_strategy) in zip(0..MAX_CONNECT_ATTEMPTS, reset_sequence.iter().cycle()) {
+        for (_, reset_strategy) in zip(0..attempts, reset_sequence.iter().cycle()) {
             match self.connect_attempt(reset_strategy) {
                 Ok(_) => {
                     return Ok(());
@@ -222,7 +478,7 @@ impl Connection {
 
             sleep(Duration::from_millis(10));
 
-            for _ in 0..MAX_CONNECT_ATTEMPTS {
+            for _ in 0..MAX_SYNC_RESPONSES {
                 match connection.read_response()? {
                     Some(response) if response.return_op == CommandType::Sync as u8 => {
                         if response.status == 1 {
@@ -248,8 +504,148 @@ impl Connection {
         Ok(())
     }
 
+    /// Synchronize with a device held in download mode, returning whether the
+    /// flasher stub is running on it
+    ///
+    /// The stub answers the synchronization with a value of 0, while the ROM
+    /// bootloader answers with a non-zero value, repeatedly.
+    fn sync_held(&mut self) -> Result<bool, Error> {
+        self.serial.clear(serialport::ClearBuffer::Input)?;
+        let value = self.with_timeout(CommandType::Sync.timeout(), |connection| {
+            connection.command(Command::Sync)
+        })?;
+
+        sleep(Duration::from_millis(50));
+        self.serial.clear(serialport::ClearBuffer::Input)?;
+
+        Ok(matches!(value, CommandResponseValue::ValueU32(0)))
+    }
+
+    /// Discard any late responses and synchronize with the device again, e.g.
+    /// after a write to flash failed
+    ///
+    /// A device which does not answer is connected to again: its port is
+    /// opened again once it reappears after a USB drop, and the device is
+    /// reset into download mode. Unless the flasher stub is still running,
+    /// the stub, flash parameters and baud rate of the [Session] are then set
+    /// up again, as a ROM loader which answers may have been reset. Returns
+    /// whether the session was set up again, in which case flash has to be
+    /// prepared for writing again.
+    pub(crate) fn resync(&mut self) -> Result<bool, Error> {
+        self.stats.resyncs += 1;
+        self.held_stub = false;
+
+        match self.flush().and_then(|_| self.sync_held()) {
+            Ok(true) => return Ok(false),
+            Ok(false) if self.session.stub.is_some() => {
+                warn!("The device was reset, loading the flasher stub again");
+            }
+            Ok(false) => debug!("The ROM loader may have been reset, setting up flash again"),
+            Err(e) => {
+                if matches!(
+                    e,
+                    Error::Connection(
+                        ConnectionError::Disconnected | ConnectionError::DeviceNotFound
+                    )
+                ) {
+                    warn!("The device was disconnected, waiting for it to reappear");
+                    self.reopen()?;
+                } else {
+                    warn!("The device does not respond ({e}), reconnecting to it");
+                }
+                self.set_baud(115_200)?;
+                self.begin()?;
+            }
+        }
+
+        self.restore_session()?;
+        Ok(true)
+    }
+
+    /// Open the port again once it reappears, e.g. after a USB drop
+    fn reopen(&mut self) -> Result<(), Error> {
+        let name = self
+            .serial
+            .name()
+            .ok_or(Error::Connection(ConnectionError::Disconnected))?;
+        let timeout = self.serial.timeout();
+
+        let start = Instant::now();
+        let mut serial = loop {
+            match Port::open(&name, 115_200) {
+                Ok(serial) => break serial,
+                Err(e) if start.elapsed() > REOPEN_TIMEOUT => return Err(e),
+                Err(_) => sleep(Duration::from_millis(100)),
+            }
+        };
+        serial.set_timeout(timeout)?;
+        self.serial = serial;
+
+        Ok(())
+    }
+
+    /// Set up the [Session] again on a device in download mode
+    fn restore_session(&mut self) -> Result<(), Error> {
+        let session = self.session.clone();
+
+        if let Some(stub) = &session.stub {
+            if !self.held_stub {
+                load_stub(self, stub, session.max_ram_block_size)?;
+            }
+        }
+        let use_stub = session.stub.is_some();
+
+        if let Some(spi_params) = session.spi_attach {
+            self.with_timeout(CommandType::SpiAttach.timeout(), |connection| {
+                connection.command(if use_stub {
+                    Command::SpiAttachStub { spi_params }
+                } else {
+                    Command::SpiAttach { spi_params }
+                })
+            })?;
+        }
+        if let Some(spi_params) = session.spi_set_params {
+            self.with_timeout(CommandType::SpiSetParams.timeout(), |connection| {
+                connection.command(Command::SpiSetParams { spi_params })
+            })?;
+        }
+
+        if let Some((new_baud, speed)) = session.baud {
+            if !self.is_usb_cdc() {
+                let prior_baud = if use_stub { 115_200 } else { 0 };
+                self.with_timeout(CommandType::ChangeBaudrate.timeout(), |connection| {
+                    connection.command(Command::ChangeBaudrate {
+                        new_baud,
+                        prior_baud,
+                    })
+                })?;
+            }
+            self.set_baud(speed)?;
+            sleep(Duration::from_millis(50));
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// What the flasher set up on the device, see [Session]
+    pub(crate) fn session_mut(&mut self) -> &mut Session {
+        &mut self.session
+    }
+
+    /// Whether the flasher stub was still running on the device, which was
+    /// held in download mode by the previous connection
+    pub fn held_stub(&self) -> bool {
+        self.held_stub
+    }
+
     // Reset the device
     pub fn reset(&mut self) -> Result<(), Error> {
+        if let Some(hook) = &self.connect_strategy.reset_hook {
+            let port_name = self.serial.name().unwrap_or_default();
+            return hook.run(ResetAction::Run, &port_name);
+        }
+
         reset_after_flash(&mut self.serial, self.port_info.pid)?;
 
         Ok(())
@@ -260,7 +656,13 @@ impl Connection {
         let pid = self.get_usb_pid()?;
 
         match self.after_operation {
-            ResetAfterOperation::HardReset => hard_reset(&mut self.serial, pid),
+            ResetAfterOperation::HardReset => match &self.connect_strategy.reset_hook {
+                Some(hook) => {
+                    let port_name = self.serial.name().unwrap_or_default();
+                    hook.run(ResetAction::Run, &port_name)
+                }
+                None => hard_reset(&mut self.serial, pid),
+            },
             ResetAfterOperation::NoReset => {
                 info!("Staying in bootloader");
                 soft_reset(self, true, is_stub)?;
@@ -271,6 +673,23 @@ impl Connection {
                 info!("Staying in flasher stub");
                 Ok(())
             }
+            ResetAfterOperation::DownloadModeHold => {
+                info!("Holding the device in download mode");
+
+                // The next connection starts out at 115,200 baud
+                let baud = self.get_baud()?;
+                if baud != 115_200 && !self.is_usb_cdc() {
+                    self.with_timeout(CommandType::ChangeBaudrate.timeout(), |connection| {
+                        connection.command(Command::ChangeBaudrate {
+                            new_baud: 115_200,
+                            prior_baud: if is_stub { baud } else { 0 },
+                        })
+                    })?;
+                    self.set_baud(115_200)?;
+                }
+
+                Ok(())
+            }
         }
     }
 
@@ -402,11 +821,18 @@ impl Connection {
 
     /// Write a command to the serial port
     pub fn write_command(&mut self, command: Command) -> Result<(), Error> {
+        self.serial.clear(serialport::ClearBuffer::Input)?;
+        self.send_command(command)
+    }
+
+    /// Write a command to the serial port, without discarding any responses
+    /// which have not been read yet
+    fn send_command(&mut self, command: Command) -> Result<(), Error> {
         debug!("Writing command: {:?}", command);
+        self.stats.commands += 1;
         let mut binding = Box::new(&mut self.serial);
         let serial = binding.as_mut();
 
-        serial.clear(serialport::ClearBuffer::Input)?;
         let mut writer = BufWriter::new(serial);
         let mut encoder = SlipEncoder::new(&mut writer)?;
         command.write(&mut encoder)?;
@@ -419,14 +845,191 @@ impl Connection {
     pub fn command(&mut self, command: Command) -> Result<CommandResponseValue, Error> {
         let ty = command.command_type();
         self.write_command(command).for_command(ty)?;
+        self.command_response(ty)
+    }
+
+    /// Send commands without waiting for the response to each command before
+    /// sending the next one
+    ///
+    /// Up to `window` commands are in flight at a time, which hides the latency
+    /// of the link. Responses arrive in the order the commands
+    /// were sent, so they are matched to the commands by counting, and
+    /// `acknowledged` is called with the index of each command as its response
+    /// arrives. The first error aborts the remaining commands.
+    pub fn pipelined_commands<'c>(
+        &mut self,
+        commands: impl IntoIterator<Item = Command<'c>>,
+        window: usize,
+        timeout: Duration,
+        mut acknowledged: impl FnMut(usize),
+    ) -> Result<(), Error> {
+        let window = window.max(1);
+        let mut commands = commands.into_iter().enumerate();
+        let mut in_flight = VecDeque::with_capacity(window);
+
+        self.serial.clear(serialport::ClearBuffer::Input)?;
+        self.with_timeout(timeout, |connection| loop {
+            while in_flight.len() < window {
+                let Some((index, command)) = commands.next() else {
+                    break;
+                };
+
+                let ty = command.command_type();
+                connection.send_command(command).for_command(ty)?;
+                in_flight.push_back((index, ty));
+            }
+
+            let Some((index, ty)) = in_flight.pop_front() else {
+                return Ok(());
+            };
+
+            connection.command_response(ty)?;
+            acknowledged(index);
+        })
+    }
+
+    /// Number of data commands which may be in flight at once when writing
+    /// flash, see [Connection::pipelined_commands]
+    pub fn data_window(&self) -> usize {
+        self.data_window
+    }
+
+    /// Set the number of data commands which may be in flight at once
+    ///
+    /// A window of 1 (the default) waits for the response to each command
+    /// before sending the next one.
+    pub fn set_data_window(&mut self, window: usize) {
+        self.data_window = window.max(1);
+    }
+
+    /// Number of times a write to flash is resumed after a transient error,
+    /// e.g. a timeout, before giving up
+    pub fn write_retries(&self) -> u32 {
+        self.write_retries
+    }
+
+    /// Set the number of times a write to flash is resumed after a transient
+    /// error, 0 to fail on the first one
+    pub fn set_write_retries(&mut self, retries: u32) {
+        self.write_retries = retries;
+    }
+
+    /// Compression level of the data written to flash
+    pub fn compression_level(&self) -> CompressionLevel {
+        self.compression_level
+    }
+
+    /// Set the compression level of the data written to flash, by default
+    /// picked to suit the link
+    pub fn set_compression_level(&mut self, level: CompressionLevel) {
+        self.compression_level = level;
+    }
 
+    /// Size of the blocks data is written to flash in
+    pub fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    /// Set the size of the blocks data is written to flash in, by default
+    /// picked to suit the link
+    pub fn set_block_size(&mut self, size: BlockSize) {
+        self.block_size = size;
+    }
+
+    /// The features which may be used with the revision of the connected chip,
+    /// once it was read
+    pub fn compatibility(&self) -> Option<Compatibility> {
+        self.compatibility
+    }
+
+    pub(crate) fn set_compatibility(&mut self, compatibility: Compatibility) {
+        self.compatibility = Some(compatibility);
+    }
+
+    /// How the connection settles once the flasher stub has started
+    ///
+    /// Unless set explicitly, USB-Serial-JTAG ports use
+    /// [StubSettle::USB_SERIAL_JTAG] and all other ports [StubSettle::NONE].
+    pub fn stub_settle(&self) -> StubSettle {
+        self.stub_settle.unwrap_or(if self.is_usb_serial_jtag() {
+            StubSettle::USB_SERIAL_JTAG
+        } else {
+            StubSettle::NONE
+        })
+    }
+
+    /// Set how the connection settles once the flasher stub has started
+    pub fn set_stub_settle(&mut self, settle: StubSettle) {
+        self.stub_settle = Some(settle);
+    }
+
+    /// Counters of the events on this connection so far
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats
+    }
+
+    /// Run the first command after the flasher stub started, letting the
+    /// connection settle according to [Connection::stub_settle]
+    pub(crate) fn settle_after_stub<T>(
+        &mut self,
+        mut f: impl FnMut(&mut Connection) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let settle = self.stub_settle();
+        let mut delay = settle.delay;
+
+        for attempt in 0.. {
+            if !delay.is_zero() {
+                trace!("Waiting {delay:?} for the stub to settle");
+                sleep(delay);
+            }
+
+            if settle.drain {
+                let pending = self.serial.bytes_to_read()?;
+                if pending > 0 {
+                    trace!("Discarding {pending} bytes received while settling");
+                    self.serial.clear(serialport::ClearBuffer::Input)?;
+                    self.stats.drained_bytes += pending as u64;
+                }
+            }
+
+            match f(self) {
+                Err(Error::Connection(ConnectionError::Timeout(_))) if attempt < settle.retries => {
+                    self.stats.settle_retries += 1;
+                    delay = (delay * 2).max(Duration::from_millis(10));
+                    debug!(
+                        "Stub not responding yet, retrying (attempt {}/{})",
+                        attempt + 1,
+                        settle.retries
+                    );
+                }
+                result => {
+                    debug!("Stub settled: {:?}", self.stats);
+                    return result;
+                }
+            }
+        }
+
+        unreachable!()
+    }
+
+    /// Read the response to a command of the given type
+    fn command_response(&mut self, ty: CommandType) -> Result<CommandResponseValue, Error> {
+        let result = self.read_command_response(ty);
+        if let Err(Error::Connection(ConnectionError::Timeout(_))) = result {
+            self.stats.timeouts += 1;
+        }
+
+        result
+    }
+
+    fn read_command_response(&mut self, ty: CommandType) -> Result<CommandResponseValue, Error> {
         for _ in 0..100 {
             match self.read_response().for_command(ty)? {
                 Some(response) if response.return_op == ty as u8 => {
                     return if response.error != 0 {
                         let _error = self.flush();
                         Err(Error::RomError(RomError::new(
-                            command.command_type(),
+                            ty,
                             RomErrorKind::from(response.error),
                         )))
                     } else {
@@ -462,6 +1065,125 @@ impl Connection {
         Ok(())
     }
 
+    /// Replace the bits of `mask` in the register at `addr` with those of
+    /// `value`, leaving the other bits as they are
+    ///
+    /// The register is read and written back, and its new value is returned.
+    pub fn update_reg(&mut self, addr: u32, mask: u32, value: u32) -> Result<u32, Error> {
+        let value = (self.read_reg(addr)? & !mask) | (value & mask);
+        self.write_reg(addr, value, None)?;
+
+        Ok(value)
+    }
+
+    /// Read a region of flash memory, verifying its MD5 digest
+    pub fn read_flash_region(
+        &mut self,
+        offset: u32,
+        size: u32,
+        block_size: u32,
+        max_in_flight: u32,
+    ) -> Result<Vec<u8>, Error> {
+        debug!("Reading 0x{:x}B from 0x{:08x}", size, offset);
+
+        let mut data = Vec::new();
+
+        self.with_timeout(CommandType::ReadFlash.timeout(), |connection| {
+            connection.command(Command::ReadFlash {
+                offset,
+                size,
+                block_size,
+                max_in_flight,
+            })
+        })?;
+
+        while data.len() < size as usize {
+            let response = self.read_response()?;
+            let chunk: Vec<u8> = if let Some(response) = response {
+                response.value.try_into().unwrap()
+            } else {
+                return Err(Error::IncorrectReposnse);
+            };
+
+            data.extend_from_slice(&chunk);
+
+            if data.len() < size as usize && chunk.len() < block_size as usize {
+                return Err(Error::CorruptData(block_size as usize, chunk.len()));
+            }
+
+            self.write_raw(data.len() as u32)?;
+        }
+
+        if data.len() > size as usize {
+            return Err(Error::ReadMoreThanExpected);
+        }
+
+        let response = self.read_response()?;
+        let digest: Vec<u8> = if let Some(response) = response {
+            response.value.try_into().unwrap()
+        } else {
+            return Err(Error::IncorrectReposnse);
+        };
+
+        if digest.len() != 16 {
+            return Err(Error::IncorrectDigestLength(digest.len()));
+        }
+
+        let mut md5_hasher = Md5::new();
+        md5_hasher.update(&data);
+        let checksum_md5 = md5_hasher.finalize();
+
+        if digest != checksum_md5.as_slice() {
+            return Err(Error::DigestMissmatch(
+                digest,
+                checksum_md5.as_slice().to_vec(),
+            ));
+        }
+
+        Ok(data)
+    }
+
+    /// Read a region of flash memory with the ROM loader of the ESP32, which
+    /// reads 64 bytes per command
+    ///
+    /// Unlike [Connection::read_flash_region], the data is not followed by a
+    /// digest, see [FlashReader::read_verified](crate::flasher::FlashReader::read_verified).
+    pub fn read_flash_slow(&mut self, offset: u32, size: u32) -> Result<Vec<u8>, Error> {
+        debug!(
+            "Reading 0x{:x}B from 0x{:08x} with the ROM loader",
+            size, offset
+        );
+
+        let mut data = Vec::with_capacity(size as usize);
+
+        while data.len() < size as usize {
+            let block_offset = offset + data.len() as u32;
+            let response: Vec<u8> = self
+                .with_timeout(CommandType::ReadFlashSlow.timeout(), |connection| {
+                    connection.command(Command::ReadFlashSlow {
+                        offset: block_offset,
+                        size: READ_FLASH_SLOW_BLOCK_SIZE,
+                    })
+                })?
+                .try_into()?;
+
+            // The block follows the header of the response, and is followed by
+            // the status bytes
+            let block_size = READ_FLASH_SLOW_BLOCK_SIZE as usize;
+            let Some(block) = response.get(8..8 + block_size) else {
+                return Err(Error::CorruptData(
+                    block_size,
+                    response.len().saturating_sub(12),
+                ));
+            };
+
+            let len = (size as usize - data.len()).min(block_size);
+            data.extend_from_slice(&block[..len]);
+        }
+
+        Ok(data)
+    }
+
     pub(crate) fn read(&mut self, len: usize) -> Result<Option<Vec<u8>>, Error> {
         let mut tmp = Vec::with_capacity(1024);
         loop {
@@ -487,6 +1209,21 @@ impl Connection {
     pub fn get_usb_pid(&self) -> Result<u16, Error> {
         Ok(self.port_info.pid)
     }
+
+    /// Whether the device is connected through its native USB peripheral
+    /// (USB-Serial-JTAG or USB-OTG) rather than a USB-to-UART bridge
+    ///
+    /// These transports are USB CDC devices, so the configured baud rate has
+    /// no effect on the actual transfer speed.
+    /// Is the port the USB-Serial-JTAG peripheral of the chip?
+    pub fn is_usb_serial_jtag(&self) -> bool {
+        self.port_info.vid == ESPRESSIF_USB_VID && self.port_info.pid == USB_SERIAL_JTAG_PID
+    }
+
+    pub fn is_usb_cdc(&self) -> bool {
+        self.port_info.pid == USB_SERIAL_JTAG_PID
+            || (self.port_info.vid == ESPRESSIF_USB_VID && self.port_info.pid == USB_OTG_PID)
+    }
 }
 
 mod encoder {
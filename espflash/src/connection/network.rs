@@ -0,0 +1,520 @@
+//! Serial ports exposed over the network by a serial server
+//!
+//! Two kinds of servers are supported, selected by the scheme of the URL:
+//!
+//! - `socket://HOST:PORT`: a raw TCP connection to the serial port, as offered
+//!   e.g. by `ser2net` in raw mode. The server does not forward the baud rate
+//!   or control lines, so the device has to be in the bootloader already, or
+//!   be reset into it by the server.
+//! - `rfc2217://HOST:PORT`: a Telnet connection with the COM-PORT-OPTION of
+//!   [RFC 2217], as offered e.g. by `esp_rfc2217_server` and `ser2net` in
+//!   telnet mode, which also forwards the baud rate and the DTR and RTS lines
+//!   used to reset the device.
+//!
+//! [RFC 2217]: https://www.rfc-editor.org/rfc/rfc2217
+
+use std::{
+    cell::Cell,
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use log::debug;
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use crate::error::Error;
+
+/// Telnet "interpret as command" escape
+const IAC: u8 = 255;
+const SE: u8 = 240;
+const SB: u8 = 250;
+const WILL: u8 = 251;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+/// Telnet option for 8-bit binary transmission
+const BINARY: u8 = 0;
+/// Telnet option suppressing go-ahead, for full-duplex transmission
+const SUPPRESS_GO_AHEAD: u8 = 3;
+/// Telnet option of RFC 2217
+const COM_PORT_OPTION: u8 = 44;
+
+// Commands of the COM-PORT-OPTION sent to the server
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+const PURGE_DATA: u8 = 12;
+
+// Values of SET_CONTROL
+const CONTROL_NO_FLOW_CONTROL: u8 = 1;
+const CONTROL_DTR_ON: u8 = 8;
+const CONTROL_DTR_OFF: u8 = 9;
+const CONTROL_RTS_ON: u8 = 11;
+const CONTROL_RTS_OFF: u8 = 12;
+
+/// Value of PURGE_DATA purging the receive buffer of the server
+const PURGE_RECEIVE_BUFFER: u8 = 1;
+
+/// Protocol spoken with the serial server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    /// Data is passed through as is
+    Raw,
+    /// Telnet with the COM-PORT-OPTION
+    Rfc2217,
+}
+
+/// Position of the Telnet decoder in the received data
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum TelnetState {
+    #[default]
+    Data,
+    /// After an IAC
+    Command,
+    /// After IAC WILL, WONT, DO or DONT, expecting the option
+    Negotiation,
+    /// In a subnegotiation, which is ignored
+    Subnegotiation,
+    /// After an IAC in a subnegotiation
+    SubnegotiationCommand,
+}
+
+/// Remove the Telnet commands from the data in `buf`, returning the length of
+/// the remaining data at its start
+fn decode_telnet(state: &mut TelnetState, buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    for i in 0..buf.len() {
+        let byte = buf[i];
+        *state = match (*state, byte) {
+            (TelnetState::Data, IAC) => TelnetState::Command,
+            (TelnetState::Data, _) => {
+                buf[len] = byte;
+                len += 1;
+                TelnetState::Data
+            }
+            // An escaped 0xFF data byte
+            (TelnetState::Command, IAC) => {
+                buf[len] = byte;
+                len += 1;
+                TelnetState::Data
+            }
+            (TelnetState::Command, SB) => TelnetState::Subnegotiation,
+            (TelnetState::Command, WILL..=DONT) => TelnetState::Negotiation,
+            (TelnetState::Command, _) | (TelnetState::Negotiation, _) => TelnetState::Data,
+            (TelnetState::Subnegotiation, IAC) => TelnetState::SubnegotiationCommand,
+            (TelnetState::Subnegotiation, _) => TelnetState::Subnegotiation,
+            (TelnetState::SubnegotiationCommand, SE) => TelnetState::Data,
+            (TelnetState::SubnegotiationCommand, _) => TelnetState::Subnegotiation,
+        };
+    }
+
+    len
+}
+
+/// Escape IAC bytes in data sent over Telnet
+fn encode_telnet(data: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(data.len());
+    for &byte in data {
+        encoded.push(byte);
+        if byte == IAC {
+            encoded.push(IAC);
+        }
+    }
+
+    encoded
+}
+
+/// A serial port exposed over the network by a serial server
+#[derive(Debug)]
+pub struct NetworkPort {
+    url: String,
+    stream: TcpStream,
+    protocol: Protocol,
+    baud_rate: u32,
+    timeout: Duration,
+    telnet_state: Cell<TelnetState>,
+}
+
+impl NetworkPort {
+    /// Is `name` the URL of a network port rather than the path of a serial
+    /// port?
+    pub fn is_url(name: &str) -> bool {
+        name.starts_with("socket://") || name.starts_with("rfc2217://")
+    }
+
+    /// Connect to the serial server at `url`, setting its serial port to
+    /// `baud_rate`
+    pub fn open(url: &str, baud_rate: u32) -> Result<Self, Error> {
+        let (protocol, addr) = if let Some(addr) = url.strip_prefix("socket://") {
+            (Protocol::Raw, addr)
+        } else if let Some(addr) = url.strip_prefix("rfc2217://") {
+            (Protocol::Rfc2217, addr)
+        } else {
+            return Err(Error::InvalidNetworkPort(url.into()));
+        };
+        let addr = addr.trim_end_matches('/');
+        if addr.is_empty() {
+            return Err(Error::InvalidNetworkPort(url.into()));
+        }
+
+        debug!("Connecting to serial server at {addr}");
+        let stream = TcpStream::connect(addr)?;
+        // Commands are small packets whose latency matters far more than their
+        // overhead
+        stream.set_nodelay(true)?;
+
+        let mut port = Self {
+            url: url.into(),
+            stream,
+            protocol,
+            baud_rate,
+            timeout: Duration::from_secs(0),
+            telnet_state: Cell::new(TelnetState::Data),
+        };
+        port.set_timeout(Duration::from_secs(3))?;
+
+        if protocol == Protocol::Rfc2217 {
+            // Negotiate binary, full-duplex transmission and the COM-PORT-OPTION
+            port.send(&[IAC, WILL, BINARY, IAC, DO, BINARY])?;
+            port.send(&[IAC, WILL, SUPPRESS_GO_AHEAD, IAC, DO, SUPPRESS_GO_AHEAD])?;
+            port.send(&[IAC, WILL, COM_PORT_OPTION])?;
+            port.send_com_port_command(SET_BAUDRATE, &baud_rate.to_be_bytes())?;
+            port.send_com_port_command(SET_DATASIZE, &[8])?;
+            port.send_com_port_command(SET_PARITY, &[1])?;
+            port.send_com_port_command(SET_STOPSIZE, &[1])?;
+            port.send_com_port_command(SET_CONTROL, &[CONTROL_NO_FLOW_CONTROL])?;
+        }
+
+        Ok(port)
+    }
+
+    /// Open another connection to the same port, sharing the underlying socket
+    pub fn try_clone_port(&self) -> io::Result<Self> {
+        Ok(Self {
+            url: self.url.clone(),
+            stream: self.stream.try_clone()?,
+            protocol: self.protocol,
+            baud_rate: self.baud_rate,
+            timeout: self.timeout,
+            telnet_state: self.telnet_state.clone(),
+        })
+    }
+
+    fn send(&self, data: &[u8]) -> io::Result<()> {
+        (&self.stream).write_all(data)
+    }
+
+    /// Send a COM-PORT-OPTION command, if the server supports them
+    fn send_com_port_command(&self, command: u8, value: &[u8]) -> io::Result<()> {
+        if self.protocol != Protocol::Rfc2217 {
+            return Ok(());
+        }
+
+        let mut packet = vec![IAC, SB, COM_PORT_OPTION, command];
+        packet.extend(encode_telnet(value));
+        packet.extend([IAC, SE]);
+
+        self.send(&packet)
+    }
+
+    fn unsupported(&self, what: &str) -> serialport::Error {
+        serialport::Error::new(
+            serialport::ErrorKind::Unknown,
+            format!("{what} is not supported by network ports"),
+        )
+    }
+}
+
+impl Read for NetworkPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let len = match self.stream.read(buf) {
+                Ok(0) if !buf.is_empty() => {
+                    return Err(io::Error::new(
+                        ErrorKind::BrokenPipe,
+                        "the serial server closed the connection",
+                    ))
+                }
+                Ok(len) => len,
+                // Sockets report read timeouts as `WouldBlock` on some platforms,
+                // serial ports always as `TimedOut`
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    return Err(ErrorKind::TimedOut.into())
+                }
+                Err(e) => return Err(e),
+            };
+
+            let len = match self.protocol {
+                Protocol::Raw => len,
+                Protocol::Rfc2217 => {
+                    let mut state = self.telnet_state.get();
+                    let len = decode_telnet(&mut state, &mut buf[..len]);
+                    self.telnet_state.set(state);
+                    len
+                }
+            };
+
+            // Only Telnet commands were received, wait for data
+            if len > 0 || buf.is_empty() {
+                return Ok(len);
+            }
+        }
+    }
+}
+
+impl Write for NetworkPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.protocol {
+            Protocol::Raw => self.stream.write(buf),
+            Protocol::Rfc2217 => {
+                self.send(&encode_telnet(buf))?;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl SerialPort for NetworkPort {
+    fn name(&self) -> Option<String> {
+        Some(self.url.clone())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.send_com_port_command(SET_BAUDRATE, &baud_rate.to_be_bytes())?;
+        self.baud_rate = baud_rate;
+
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()> {
+        match data_bits {
+            DataBits::Eight => Ok(()),
+            _ => Err(self.unsupported("Setting the data bits")),
+        }
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        match flow_control {
+            FlowControl::None => Ok(()),
+            _ => Err(self.unsupported("Flow control")),
+        }
+    }
+
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> {
+        match parity {
+            Parity::None => Ok(()),
+            _ => Err(self.unsupported("Setting the parity")),
+        }
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> {
+        match stop_bits {
+            StopBits::One => Ok(()),
+            _ => Err(self.unsupported("Setting the stop bits")),
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        // Sockets do not accept a timeout of zero
+        self.stream
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        self.timeout = timeout;
+
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        let control = if level {
+            CONTROL_RTS_ON
+        } else {
+            CONTROL_RTS_OFF
+        };
+
+        Ok(self.send_com_port_command(SET_CONTROL, &[control])?)
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        let control = if level {
+            CONTROL_DTR_ON
+        } else {
+            CONTROL_DTR_OFF
+        };
+
+        Ok(self.send_com_port_command(SET_CONTROL, &[control])?)
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Err(self.unsupported("Reading CTS"))
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Err(self.unsupported("Reading DSR"))
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Err(self.unsupported("Reading RI"))
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Err(self.unsupported("Reading CD"))
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        if buffer_to_clear == ClearBuffer::Output {
+            return Ok(());
+        }
+
+        self.send_com_port_command(PURGE_DATA, &[PURGE_RECEIVE_BUFFER])?;
+
+        // Discard whatever was already received
+        self.stream.set_nonblocking(true)?;
+        let mut buf = [0; 1024];
+        let result = loop {
+            match (&self.stream).read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        self.telnet_state.set(TelnetState::Data);
+
+        Ok(result?)
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(self.try_clone_port()?))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Err(self.unsupported("Sending a break"))
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Err(self.unsupported("Sending a break"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn telnet_commands_are_removed() {
+        let mut state = TelnetState::default();
+
+        // Data with an escaped 0xFF, a negotiation and a subnegotiation
+        let mut buf = [
+            b'a',
+            IAC,
+            IAC,
+            b'b',
+            IAC,
+            WILL,
+            BINARY,
+            b'c',
+            IAC,
+            SB,
+            COM_PORT_OPTION,
+            101,
+            IAC,
+            SE,
+            b'd',
+        ];
+        let len = decode_telnet(&mut state, &mut buf);
+        assert_eq!(&buf[..len], [b'a', IAC, b'b', b'c', b'd']);
+        assert_eq!(state, TelnetState::Data);
+
+        // A command split across reads
+        let mut buf = [b'e', IAC];
+        assert_eq!(decode_telnet(&mut state, &mut buf), 1);
+        let mut buf = [IAC, b'f'];
+        let len = decode_telnet(&mut state, &mut buf);
+        assert_eq!(&buf[..len], [IAC, b'f']);
+    }
+
+    #[test]
+    fn telnet_data_is_escaped() {
+        assert_eq!(encode_telnet(&[1, IAC, 2]), [1, IAC, IAC, 2]);
+    }
+
+    #[test]
+    fn data_is_passed_through_sockets() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"hello").unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).unwrap();
+            buf
+        });
+
+        let mut port = NetworkPort::open(&format!("socket://{addr}"), 115_200).unwrap();
+        let mut buf = [0; 5];
+        port.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        port.write_all(b"world").unwrap();
+        assert_eq!(&server.join().unwrap(), b"world");
+
+        // The server closed the connection
+        assert_eq!(
+            port.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+    }
+
+    #[test]
+    fn invalid_urls_are_rejected() {
+        assert!(NetworkPort::is_url("rfc2217://localhost:4000"));
+        assert!(!NetworkPort::is_url("/dev/ttyUSB0"));
+
+        assert!(matches!(
+            NetworkPort::open("tcp://localhost:4000", 115_200),
+            Err(Error::InvalidNetworkPort(_))
+        ));
+        assert!(matches!(
+            NetworkPort::open("socket://", 115_200),
+            Err(Error::InvalidNetworkPort(_))
+        ));
+    }
+}
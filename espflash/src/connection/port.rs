@@ -0,0 +1,202 @@
+//! The transport of a connection: a serial port of the host, or one exposed
+//! over the network
+
+use std::{
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use crate::{
+    connection::{mock::MockPort, network::NetworkPort},
+    error::Error,
+};
+
+#[cfg(unix)]
+pub type NativePort = serialport::TTYPort;
+#[cfg(windows)]
+pub type NativePort = serialport::COMPort;
+
+/// A port which a target device is connected to
+#[derive(Debug)]
+pub enum Port {
+    /// A serial port of the host
+    Native(NativePort),
+    /// A serial port exposed over the network by a serial server
+    Network(NetworkPort),
+    /// A simulated device, see [MockPort]
+    Mock(MockPort),
+}
+
+/// Run `$body` with `$port` bound to the port of any variant
+macro_rules! dispatch {
+    ($self:expr, $port:ident => $body:expr) => {
+        match $self {
+            Port::Native($port) => $body,
+            Port::Network($port) => $body,
+            Port::Mock($port) => $body,
+        }
+    };
+}
+
+impl Port {
+    /// Open the serial port at `name`, or connect to the serial server at a
+    /// `socket://HOST:PORT` or `rfc2217://HOST:PORT` URL
+    pub fn open(name: &str, baud_rate: u32) -> Result<Self, Error> {
+        if NetworkPort::is_url(name) {
+            return Ok(Port::Network(NetworkPort::open(name, baud_rate)?));
+        }
+
+        let port = serialport::new(name, baud_rate)
+            .flow_control(FlowControl::None)
+            .open_native()?;
+
+        Ok(Port::Native(port))
+    }
+
+    /// Open another handle to the same port
+    pub fn try_clone_native(&self) -> serialport::Result<Self> {
+        Ok(match self {
+            Port::Native(port) => Port::Native(port.try_clone_native()?),
+            Port::Network(port) => Port::Network(port.try_clone_port()?),
+            Port::Mock(port) => Port::Mock(port.clone()),
+        })
+    }
+}
+
+impl From<NativePort> for Port {
+    fn from(port: NativePort) -> Self {
+        Port::Native(port)
+    }
+}
+
+impl From<NetworkPort> for Port {
+    fn from(port: NetworkPort) -> Self {
+        Port::Network(port)
+    }
+}
+
+impl From<MockPort> for Port {
+    fn from(port: MockPort) -> Self {
+        Port::Mock(port)
+    }
+}
+
+impl Read for Port {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        dispatch!(self, port => port.read(buf))
+    }
+}
+
+impl Write for Port {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        dispatch!(self, port => port.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        dispatch!(self, port => port.flush())
+    }
+}
+
+impl SerialPort for Port {
+    fn name(&self) -> Option<String> {
+        dispatch!(self, port => port.name())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        dispatch!(self, port => port.baud_rate())
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        dispatch!(self, port => port.data_bits())
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        dispatch!(self, port => port.flow_control())
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        dispatch!(self, port => port.parity())
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        dispatch!(self, port => port.stop_bits())
+    }
+
+    fn timeout(&self) -> Duration {
+        dispatch!(self, port => port.timeout())
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        dispatch!(self, port => port.set_baud_rate(baud_rate))
+    }
+
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()> {
+        dispatch!(self, port => port.set_data_bits(data_bits))
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        dispatch!(self, port => port.set_flow_control(flow_control))
+    }
+
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> {
+        dispatch!(self, port => port.set_parity(parity))
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> {
+        dispatch!(self, port => port.set_stop_bits(stop_bits))
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        dispatch!(self, port => port.set_timeout(timeout))
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        dispatch!(self, port => port.write_request_to_send(level))
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        dispatch!(self, port => port.write_data_terminal_ready(level))
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        dispatch!(self, port => port.read_clear_to_send())
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        dispatch!(self, port => port.read_data_set_ready())
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        dispatch!(self, port => port.read_ring_indicator())
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        dispatch!(self, port => port.read_carrier_detect())
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        dispatch!(self, port => port.bytes_to_read())
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        dispatch!(self, port => port.bytes_to_write())
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        dispatch!(self, port => port.clear(buffer_to_clear))
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        dispatch!(self, port => port.try_clone())
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        dispatch!(self, port => port.set_break())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        dispatch!(self, port => port.clear_break())
+    }
+}
@@ -1,10 +1,11 @@
 //! Most of this module is copied from `esptool.py` (https://github.com/espressif/esptool/blob/a8586d02b1305ebc687d31783437a7f4d4dbb70f/esptool/reset.py)
 
+use std::{fmt, process, str::FromStr, thread::sleep, time::Duration};
 #[cfg(unix)]
 use std::{io, os::fd::AsRawFd};
-use std::{thread::sleep, time::Duration};
 
 use log::debug;
+use serde::{Deserialize, Serialize};
 use serialport::SerialPort;
 use strum::{Display, EnumIter, EnumString, VariantNames};
 
@@ -13,7 +14,7 @@ use libc::ioctl;
 
 use crate::{
     command::{Command, CommandType},
-    connection::{Connection, Port, USB_SERIAL_JTAG_PID},
+    connection::{Connection, Port, PortKind, USB_SERIAL_JTAG_PID},
     error::Error,
     flasher::FLASH_WRITE_SIZE,
 };
@@ -22,6 +23,10 @@ use crate::{
 const DEFAULT_RESET_DELAY: u64 = 50; // ms
 /// Amount of time to wait if the default reset delay does not work
 const EXTRA_RESET_DELAY: u64 = 500; // ms
+/// Time the ESP USB Bridge is given to apply each change of the lines
+const USB_BRIDGE_RESET_DELAY: u64 = 100; // ms
+/// Baud rate which requests a restart into download mode, see [usb_touch]
+pub const USB_TOUCH_BAUD: u32 = 1200;
 
 /// Some strategy for resting a target device
 pub trait ResetStrategy {
@@ -46,7 +51,13 @@ pub trait ResetStrategy {
         dtr_level: bool,
         rts_level: bool,
     ) -> Result<(), Error> {
-        let fd = serial_port.as_raw_fd();
+        let Port::Native(native_port) = serial_port else {
+            // Network ports cannot change both lines at once
+            self.set_dtr(serial_port, dtr_level)?;
+            return self.set_rts(serial_port, rts_level);
+        };
+
+        let fd = native_port.as_raw_fd();
         let mut status: i32 = 0;
         match unsafe { ioctl(fd, libc::TIOCMGET, &status) } {
             0 => (),
@@ -89,6 +100,11 @@ impl ClassicReset {
 
         Self { delay }
     }
+
+    /// Hold the boot pin for `delay` milliseconds after the reset
+    pub fn with_delay(delay: u64) -> Self {
+        Self { delay }
+    }
 }
 
 impl ResetStrategy for ClassicReset {
@@ -139,6 +155,11 @@ impl UnixTightReset {
 
         Self { delay }
     }
+
+    /// Hold the boot pin for `delay` milliseconds after the reset
+    pub fn with_delay(delay: u64) -> Self {
+        Self { delay }
+    }
 }
 
 #[cfg(unix)]
@@ -198,6 +219,289 @@ impl ResetStrategy for UsbJtagSerialReset {
     }
 }
 
+/// Reset sequence for the ESP USB Bridge, which drives the EN and BOOT pins of
+/// the chip from the DTR and RTS lines as an auto-reset circuit would
+///
+/// The bridge applies each change of the lines some time after it arrives
+/// over USB, so the states in between would last long enough to let the chip
+/// boot normally. Both lines are therefore changed at once where possible,
+/// and each state is held for longer than with a USB-to-UART bridge.
+#[derive(Debug, Clone, Copy)]
+pub struct UsbBridgeReset {
+    delay: u64,
+}
+
+impl UsbBridgeReset {
+    pub fn new(extra_delay: bool) -> Self {
+        let delay = if extra_delay {
+            EXTRA_RESET_DELAY
+        } else {
+            USB_BRIDGE_RESET_DELAY
+        };
+
+        Self { delay }
+    }
+
+    /// Hold the boot pin for `delay` milliseconds after the reset
+    pub fn with_delay(delay: u64) -> Self {
+        Self { delay }
+    }
+
+    fn set_lines(&self, serial_port: &mut Port, dtr: bool, rts: bool) -> Result<(), Error> {
+        #[cfg(unix)]
+        return self.set_dtr_rts(serial_port, dtr, rts);
+
+        #[cfg(not(unix))]
+        {
+            self.set_dtr(serial_port, dtr)?;
+            self.set_rts(serial_port, rts)
+        }
+    }
+}
+
+impl ResetStrategy for UsbBridgeReset {
+    fn reset(&self, serial_port: &mut Port) -> Result<(), Error> {
+        debug!(
+            "Using UsbBridge reset strategy with delay of {}ms",
+            self.delay
+        );
+
+        self.set_lines(serial_port, false, false)?;
+        sleep(Duration::from_millis(USB_BRIDGE_RESET_DELAY));
+        self.set_lines(serial_port, false, true)?; // IO0 = HIGH, EN = LOW, chip in reset
+
+        sleep(Duration::from_millis(USB_BRIDGE_RESET_DELAY));
+
+        self.set_lines(serial_port, true, false)?; // IO0 = LOW, EN = HIGH, chip out of reset
+
+        sleep(Duration::from_millis(self.delay));
+
+        self.set_lines(serial_port, false, false)?; // IO0 = HIGH, done
+
+        Ok(())
+    }
+}
+
+/// A change of the serial control lines, or a pause, of a [CustomReset]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ResetCommand {
+    /// Set DTR, written as `D0` or `D1`
+    Dtr(bool),
+    /// Set RTS, written as `R0` or `R1`
+    Rts(bool),
+    /// Set DTR and RTS at once where the port allows it, written as e.g.
+    /// `U0,1`
+    DtrRts(bool, bool),
+    /// Wait, written as the number of seconds, e.g. `W0.1`
+    Wait(Duration),
+}
+
+/// Reset sequence of changes of the DTR and RTS lines and pauses, for boards
+/// which wire the lines differently from the usual auto-reset circuit, e.g.
+/// inverted
+///
+/// Written like esptool's `custom_reset_sequence`, as commands separated by
+/// `|`, e.g. `D0|R1|W0.1|D1|R0|W0.05|D0`; see [ResetCommand].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CustomReset {
+    commands: Vec<ResetCommand>,
+}
+
+impl CustomReset {
+    /// Reset by running `commands` in order
+    pub fn new(commands: Vec<ResetCommand>) -> Self {
+        Self { commands }
+    }
+
+    /// The commands run to reset the device
+    pub fn commands(&self) -> &[ResetCommand] {
+        &self.commands
+    }
+}
+
+impl ResetStrategy for CustomReset {
+    fn reset(&self, serial_port: &mut Port) -> Result<(), Error> {
+        debug!("Using Custom reset strategy '{self}'");
+
+        for command in &self.commands {
+            match *command {
+                ResetCommand::Dtr(level) => self.set_dtr(serial_port, level)?,
+                ResetCommand::Rts(level) => self.set_rts(serial_port, level)?,
+                #[cfg(unix)]
+                ResetCommand::DtrRts(dtr, rts) => self.set_dtr_rts(serial_port, dtr, rts)?,
+                #[cfg(not(unix))]
+                ResetCommand::DtrRts(dtr, rts) => {
+                    self.set_dtr(serial_port, dtr)?;
+                    self.set_rts(serial_port, rts)?;
+                }
+                ResetCommand::Wait(duration) => sleep(duration),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for CustomReset {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidCustomReset(s.into());
+        let level = |level: &str| match level.trim() {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            _ => Err(invalid()),
+        };
+
+        let commands = s
+            .split('|')
+            .map(|command| {
+                let command = command.trim();
+                let mut chars = command.chars();
+                let op = chars.next();
+                let arg = chars.as_str();
+                match op.map(|op| op.to_ascii_uppercase()) {
+                    Some('D') => Ok(ResetCommand::Dtr(level(arg)?)),
+                    Some('R') => Ok(ResetCommand::Rts(level(arg)?)),
+                    Some('U') => {
+                        let (dtr, rts) = arg.split_once(',').ok_or_else(invalid)?;
+                        Ok(ResetCommand::DtrRts(level(dtr)?, level(rts)?))
+                    }
+                    Some('W') => arg
+                        .trim()
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                        .map(ResetCommand::Wait)
+                        .ok_or_else(invalid),
+                    _ => Err(invalid()),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { commands })
+    }
+}
+
+impl TryFrom<String> for CustomReset {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<CustomReset> for String {
+    fn from(reset: CustomReset) -> Self {
+        reset.to_string()
+    }
+}
+
+impl fmt::Display for CustomReset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, command) in self.commands.iter().enumerate() {
+            if i > 0 {
+                f.write_str("|")?;
+            }
+            match command {
+                ResetCommand::Dtr(level) => write!(f, "D{}", *level as u8)?,
+                ResetCommand::Rts(level) => write!(f, "R{}", *level as u8)?,
+                ResetCommand::DtrRts(dtr, rts) => write!(f, "U{},{}", *dtr as u8, *rts as u8)?,
+                ResetCommand::Wait(duration) => write!(f, "W{}", duration.as_secs_f64())?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// What a [ResetHook] is asked to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[non_exhaustive]
+#[strum(serialize_all = "kebab-case")]
+pub enum ResetAction {
+    /// Reset the device into download mode, before connecting to it
+    Download,
+    /// Reset the device into its application
+    Run,
+}
+
+/// Reset through an external command, for boards whose reset and boot pins
+/// are not driven by the DTR and RTS lines but e.g. by a GPIO expander
+///
+/// The command is given as the program followed by its arguments. It is run
+/// with the [ResetAction] appended as its last argument, and the name of the
+/// serial port in the `ESPFLASH_PORT` environment variable, and has to return
+/// once the device was reset.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct ResetHook {
+    command: Vec<String>,
+}
+
+impl ResetHook {
+    /// Reset by running `command`, the program followed by its arguments
+    pub fn new(command: Vec<String>) -> Result<Self, Error> {
+        if command.first().is_none_or(|program| program.is_empty()) {
+            return Err(Error::InvalidResetHook);
+        }
+
+        Ok(Self { command })
+    }
+
+    /// The program and arguments which are run
+    pub fn command(&self) -> &[String] {
+        &self.command
+    }
+
+    /// Run the command to perform `action` on the device on the port
+    /// `port_name`
+    pub fn run(&self, action: ResetAction, port_name: &str) -> Result<(), Error> {
+        debug!("Running reset hook {:?} {action}", self.command);
+
+        let status = process::Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .arg(action.to_string())
+            .env("ESPFLASH_PORT", port_name)
+            .status()
+            .map_err(|e| Error::ResetHookFailed(self.command[0].clone(), e.to_string()))?;
+
+        if !status.success() {
+            return Err(Error::ResetHookFailed(
+                self.command[0].clone(),
+                status.to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl ResetStrategy for ResetHook {
+    fn reset(&self, serial_port: &mut Port) -> Result<(), Error> {
+        self.run(
+            ResetAction::Download,
+            &serial_port.name().unwrap_or_default(),
+        )
+    }
+}
+
+impl TryFrom<Vec<String>> for ResetHook {
+    type Error = Error;
+
+    fn try_from(command: Vec<String>) -> Result<Self, Self::Error> {
+        Self::new(command)
+    }
+}
+
+impl From<ResetHook> for Vec<String> {
+    fn from(hook: ResetHook) -> Self {
+        hook.command
+    }
+}
+
 /// Reset the target device
 pub fn reset_after_flash(serial: &mut Port, pid: u16) -> Result<(), serialport::Error> {
     sleep(Duration::from_millis(100));
@@ -260,6 +564,7 @@ pub fn soft_reset(
                     block_size: FLASH_WRITE_SIZE.try_into().unwrap(),
                     offset,
                     supports_encryption: false,
+                    encrypted: false,
                 })
             })?;
             connection.with_timeout(CommandType::FlashEnd.timeout(), |connection| {
@@ -278,6 +583,7 @@ pub fn soft_reset(
                 block_size: FLASH_WRITE_SIZE.try_into().unwrap(),
                 offset,
                 supports_encryption: false,
+                encrypted: false,
             })
         })?;
         connection.with_timeout(CommandType::FlashEnd.timeout(), |connection| {
@@ -293,13 +599,34 @@ pub fn soft_reset(
     Ok(())
 }
 
-/// Construct a sequence of reset strategies based on the OS and chip.
+/// Request a restart into download mode from the firmware running on the port
+/// `port_name`, by opening it at 1200 baud and closing it again
+///
+/// This "1200bps touch" is the convention of Arduino and TinyUSB for boards
+/// whose native USB port is their only connection, e.g. ESP32-S2 and ESP32-S3
+/// boards without BOOT and RESET buttons. The device disconnects, and then
+/// reappears as the port of the ROM bootloader, possibly under a different
+/// name.
+pub fn usb_touch(port_name: &str) -> Result<(), Error> {
+    debug!("Touching {port_name} at {USB_TOUCH_BAUD} baud");
+
+    let mut port = Port::open(port_name, USB_TOUCH_BAUD)?;
+    port.write_data_terminal_ready(true)?;
+    sleep(Duration::from_millis(DEFAULT_RESET_DELAY));
+    port.write_data_terminal_ready(false)?;
+
+    Ok(())
+}
+
+/// Construct a sequence of reset strategies based on the OS and the kind of
+/// port.
 ///
 /// Returns a [Vec] containing one or more reset strategies to be attempted
 /// sequentially.
 #[allow(unused_variables)]
 pub fn construct_reset_strategy_sequence(
     port_name: &str,
+    vid: u16,
     pid: u16,
     mode: ResetBeforeOperation,
 ) -> Vec<Box<dyn ResetStrategy>> {
@@ -308,6 +635,14 @@ pub fn construct_reset_strategy_sequence(
         return vec![Box::new(UsbJtagSerialReset)];
     }
 
+    // ESP USB Bridge
+    if PortKind::from_usb_ids(vid, pid) == PortKind::UsbBridge {
+        return vec![
+            Box::new(UsbBridgeReset::new(false)),
+            Box::new(UsbBridgeReset::new(true)),
+        ];
+    }
+
     // USB-to-Serial bridge
     #[cfg(unix)]
     if cfg!(unix) && !port_name.starts_with("rfc2217:") {
@@ -326,6 +661,98 @@ pub fn construct_reset_strategy_sequence(
     ]
 }
 
+/// The kinds of reset strategies which can be configured in a [ResetStep]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumIter, EnumString, VariantNames)]
+#[non_exhaustive]
+#[strum(serialize_all = "kebab-case")]
+pub enum ResetKind {
+    /// [ClassicReset]
+    Classic,
+    /// [UnixTightReset], or [ClassicReset] on platforms other than UNIX
+    UnixTight,
+    /// [UsbJtagSerialReset]
+    UsbJtagSerial,
+    /// [UsbBridgeReset]
+    UsbBridge,
+}
+
+/// A reset strategy to attempt when connecting, and how long to hold the boot
+/// pin after the reset
+///
+/// Written as the kind of strategy, optionally followed by the delay in
+/// milliseconds, e.g. `classic` or `unix-tight:500`. The USB-JTAG-Serial reset
+/// has fixed timings and takes no delay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ResetStep {
+    /// The reset strategy
+    pub kind: ResetKind,
+    /// Time to hold the boot pin after the reset, in milliseconds; defaults to
+    /// 50ms
+    pub delay: Option<u64>,
+}
+
+impl ResetStep {
+    /// Create the reset strategy of this step
+    pub fn strategy(&self) -> Box<dyn ResetStrategy> {
+        let delay = self.delay.unwrap_or(DEFAULT_RESET_DELAY);
+
+        match self.kind {
+            ResetKind::Classic => Box::new(ClassicReset::with_delay(delay)),
+            #[cfg(unix)]
+            ResetKind::UnixTight => Box::new(UnixTightReset::with_delay(delay)),
+            #[cfg(not(unix))]
+            ResetKind::UnixTight => Box::new(ClassicReset::with_delay(delay)),
+            ResetKind::UsbJtagSerial => Box::new(UsbJtagSerialReset),
+            ResetKind::UsbBridge => Box::new(UsbBridgeReset::with_delay(
+                self.delay.unwrap_or(USB_BRIDGE_RESET_DELAY),
+            )),
+        }
+    }
+}
+
+impl FromStr for ResetStep {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidResetStep(s.into());
+
+        let (kind, delay) = match s.split_once(':') {
+            Some((kind, delay)) => (kind, Some(delay.trim().parse().map_err(|_| invalid())?)),
+            None => (s, None),
+        };
+        let kind = ResetKind::from_str(kind.trim()).map_err(|_| invalid())?;
+        if kind == ResetKind::UsbJtagSerial && delay.is_some() {
+            return Err(invalid());
+        }
+
+        Ok(Self { kind, delay })
+    }
+}
+
+impl TryFrom<String> for ResetStep {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ResetStep> for String {
+    fn from(step: ResetStep) -> Self {
+        step.to_string()
+    }
+}
+
+impl fmt::Display for ResetStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.delay {
+            Some(delay) => write!(f, "{}:{delay}", self.kind),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
 #[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
 #[derive(
     Debug, Default, Clone, Copy, PartialEq, Eq, Display, EnumIter, EnumString, VariantNames,
@@ -345,6 +772,28 @@ pub enum ResetBeforeOperation {
     NoResetNoSync,
     /// Reset sequence for USB-JTAG-Serial peripheral
     UsbReset,
+    /// Reuses a device left in download mode by the previous command without
+    /// resetting it, and skips loading the flasher stub if it is still running.
+    /// Falls back to the default reset if the device does not respond.
+    DownloadModeHold,
+}
+
+/// How to get the device into download mode before connecting
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Display, EnumIter, EnumString, VariantNames,
+)]
+#[non_exhaustive]
+#[strum(serialize_all = "kebab-case")]
+pub enum DownloadModeEntry {
+    /// Reset the device into download mode with the DTR and RTS lines, as
+    /// selected by the [ResetBeforeOperation]
+    #[default]
+    Reset,
+    /// Open the port at 1200 baud and close it again, which makes firmware
+    /// following the Arduino convention (e.g. with TinyUSB) restart into
+    /// download mode; see [usb_touch]
+    UsbTouch,
 }
 
 #[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
@@ -361,4 +810,102 @@ pub enum ResetAfterOperation {
     NoReset,
     /// Leaves the chip in the stub bootloader, no reset is performed.
     NoResetNoStub,
+    /// Leaves the chip in download mode at 115,200 baud, so that the next
+    /// command can reuse it with the download-mode-hold reset before
+    /// connecting.
+    DownloadModeHold,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reset_hook_is_validated() {
+        let hook =
+            ResetHook::try_from(vec!["reset.sh".into(), "--board".into(), "1".into()]).unwrap();
+        assert_eq!(hook.command(), ["reset.sh", "--board", "1"]);
+        assert!(ResetHook::new(vec![]).is_err());
+        assert!(ResetHook::new(vec![String::new()]).is_err());
+
+        assert_eq!(ResetAction::Download.to_string(), "download");
+        assert_eq!(ResetAction::Run.to_string(), "run");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reset_hook_runs_the_command() {
+        let hook = ResetHook::new(vec![
+            "sh".into(),
+            "-c".into(),
+            r#"test "$1" = run && test "$ESPFLASH_PORT" = /dev/ttyUSB0"#.into(),
+            "sh".into(),
+        ])
+        .unwrap();
+        hook.run(ResetAction::Run, "/dev/ttyUSB0").unwrap();
+        assert!(matches!(
+            hook.run(ResetAction::Download, "/dev/ttyUSB0"),
+            Err(Error::ResetHookFailed(..))
+        ));
+    }
+
+    #[test]
+    fn parse_reset_step() {
+        let step: ResetStep = "unix-tight:500".parse().unwrap();
+        assert_eq!(step.kind, ResetKind::UnixTight);
+        assert_eq!(step.delay, Some(500));
+        assert_eq!(step.to_string(), "unix-tight:500");
+
+        let step: ResetStep = "classic".parse().unwrap();
+        assert_eq!(step.kind, ResetKind::Classic);
+        assert_eq!(step.delay, None);
+
+        assert!("usb-jtag-serial".parse::<ResetStep>().is_ok());
+        assert_eq!(
+            "usb-bridge:200".parse::<ResetStep>().unwrap().kind,
+            ResetKind::UsbBridge
+        );
+        assert!("usb-jtag-serial:100".parse::<ResetStep>().is_err());
+        assert!("classic:fast".parse::<ResetStep>().is_err());
+        assert!("hard".parse::<ResetStep>().is_err());
+    }
+
+    #[test]
+    fn parse_custom_reset() {
+        let reset: CustomReset = "D0|R1|W0.1|D1|R0|W0.05|D0".parse().unwrap();
+        assert_eq!(reset.commands().len(), 7);
+        assert_eq!(reset.commands()[1], ResetCommand::Rts(true));
+        assert_eq!(
+            reset.commands()[2],
+            ResetCommand::Wait(Duration::from_millis(100))
+        );
+        assert_eq!(reset.to_string(), "D0|R1|W0.1|D1|R0|W0.05|D0");
+
+        let reset: CustomReset = "u1,0 | w1".parse().unwrap();
+        assert_eq!(
+            reset.commands(),
+            [
+                ResetCommand::DtrRts(true, false),
+                ResetCommand::Wait(Duration::from_secs(1))
+            ]
+        );
+
+        for invalid in ["", "D2", "U1", "W-1", "X0", "D0||R1", "é0"] {
+            assert!(invalid.parse::<CustomReset>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn ports_are_told_apart_by_usb_ids() {
+        assert_eq!(PortKind::from_usb_ids(0x303a, 0x1002), PortKind::UsbBridge);
+        assert!(PortKind::from_usb_ids(0x303a, 0x1002).has_jtag());
+        assert_eq!(PortKind::from_usb_ids(0x303a, 0x0002), PortKind::UsbOtg);
+        assert!(!PortKind::from_usb_ids(0x303a, 0x0002).has_jtag());
+        assert_eq!(PortKind::from_usb_ids(0x10c4, 0x1002), PortKind::Uart);
+        assert_eq!(PortKind::UsbBridge.to_string(), "usb-bridge");
+
+        let bridge =
+            construct_reset_strategy_sequence("", 0x303a, 0x1002, ResetBeforeOperation::default());
+        assert_eq!(bridge.len(), 2);
+    }
 }
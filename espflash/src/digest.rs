@@ -0,0 +1,160 @@
+//! Digests used to compare the contents of flash with the data written to it
+//!
+//! The contents of flash are compared when skipping unchanged segments and
+//! when verifying segments after writing them. By default the MD5 digest is
+//! computed on the device, as both the ROM loader and the flasher stub support
+//! this. Neither loader computes other digests of flash, so SHA-256 and CRC32
+//! are computed on the host from the region read back with [FlashReader],
+//! which falls back to the slower read paths of the ROM loader and does not
+//! need the MD5 command.
+//!
+//! [FlashReader]: crate::flasher::FlashReader
+
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use strum::{Display, EnumIter, VariantNames};
+
+#[cfg(feature = "serialport")]
+use crate::{
+    command::{Command, CommandType},
+    error::Error,
+    flasher::{FlashReader, FLASH_SECTOR_SIZE},
+};
+
+/// Algorithm computing the digest of a region of flash
+pub trait FlashDigest {
+    /// Compute the digest of `data` on the host
+    fn digest(&self, data: &[u8]) -> Vec<u8>;
+
+    #[cfg(feature = "serialport")]
+    /// Compute the digest of a region of flash on the device
+    ///
+    /// By default, the region is read back and its digest computed on the
+    /// host.
+    fn flash_digest(
+        &self,
+        reader: &mut FlashReader<'_>,
+        offset: u32,
+        size: u32,
+    ) -> Result<Vec<u8>, Error> {
+        let data = reader.read(offset, size, FLASH_SECTOR_SIZE as u32, 64)?;
+
+        Ok(self.digest(&data))
+    }
+}
+
+/// MD5, computed by the device itself
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Md5Digest;
+
+impl FlashDigest for Md5Digest {
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        Md5::digest(data).to_vec()
+    }
+
+    #[cfg(feature = "serialport")]
+    fn flash_digest(
+        &self,
+        reader: &mut FlashReader<'_>,
+        offset: u32,
+        size: u32,
+    ) -> Result<Vec<u8>, Error> {
+        let digest: u128 =
+            reader
+                .connection()
+                .with_timeout(CommandType::FlashMd5.timeout(), |connection| {
+                    connection
+                        .command(Command::FlashMd5 { offset, size })?
+                        .try_into()
+                })?;
+
+        Ok(digest.to_be_bytes().to_vec())
+    }
+}
+
+/// SHA-256 of the region read back from the device
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha256Digest;
+
+impl FlashDigest for Sha256Digest {
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        Sha256::digest(data).to_vec()
+    }
+}
+
+/// CRC32 (IEEE) of the region read back from the device
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Crc32Digest;
+
+impl FlashDigest for Crc32Digest {
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        crc32fast::hash(data).to_be_bytes().to_vec()
+    }
+}
+
+/// Selection of the digest algorithm used to compare flash contents
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Display,
+    VariantNames,
+    EnumIter,
+    Serialize,
+    Deserialize,
+)]
+#[non_exhaustive]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum DigestAlgorithm {
+    /// MD5, computed on the device
+    #[default]
+    Md5,
+    /// SHA-256, computed on the host from the data read back
+    Sha256,
+    /// CRC32, computed on the host from the data read back
+    Crc32,
+}
+
+impl DigestAlgorithm {
+    /// The implementation of the algorithm
+    pub fn digest(self) -> &'static dyn FlashDigest {
+        match self {
+            DigestAlgorithm::Md5 => &Md5Digest,
+            DigestAlgorithm::Sha256 => &Sha256Digest,
+            DigestAlgorithm::Crc32 => &Crc32Digest,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DigestAlgorithm;
+
+    #[test]
+    fn host_digests() {
+        let digest = |algorithm: DigestAlgorithm| {
+            algorithm
+                .digest()
+                .digest(b"abc")
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<String>()
+        };
+
+        assert_eq!(
+            digest(DigestAlgorithm::Md5),
+            "900150983cd24fb0d6963f7d28e17f72"
+        );
+        assert_eq!(
+            digest(DigestAlgorithm::Sha256),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(digest(DigestAlgorithm::Crc32), "352441c2");
+    }
+}
@@ -0,0 +1,233 @@
+//! Finding the devices connected to the host
+//!
+//! The serial ports which a device may be connected to are listed along with
+//! the kind of port, as told by their USB IDs. Optionally, each port is probed
+//! by connecting to the ROM loader of the device, which identifies the chip,
+//! its MAC address and flash size without running the flasher stub. The
+//! devices are reset back into their application afterwards.
+//!
+//! ```no_run
+//! use espflash::discovery::Discovery;
+//!
+//! # fn main() -> Result<(), espflash::error::Error> {
+//! for device in Discovery::new().with_probe(true).discover()? {
+//!     match device.probe {
+//!         Some(Ok(info)) => println!("{}: {} {}", device.port_name, info.chip, info.mac_address),
+//!         Some(Err(e)) => println!("{}: {e}", device.port_name),
+//!         None => println!("{}", device.port_name),
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::thread;
+
+use log::debug;
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+
+use crate::{
+    connection::{ConnectStrategy, Port, PortKind},
+    error::Error,
+    flasher::{ConnectOptions, DeviceInfo, Flasher},
+};
+
+#[cfg(all(
+    target_os = "linux",
+    any(test, feature = "sysfs", target_env = "musl", not(feature = "libudev"))
+))]
+mod sysfs;
+
+/// Number of attempts to connect to each port when probing, which keeps
+/// ports without a device from taking long
+const PROBE_CONNECT_ATTEMPTS: usize = 2;
+
+/// The serial ports of the system
+///
+/// On Linux, the ports are found by scanning sysfs instead of asking libudev
+/// when building with the `sysfs` feature, or without the `libudev` feature.
+pub fn available_ports() -> serialport::Result<Vec<SerialPortInfo>> {
+    #[cfg(all(
+        target_os = "linux",
+        any(feature = "sysfs", target_env = "musl", not(feature = "libudev"))
+    ))]
+    return sysfs::available_ports();
+
+    #[cfg(not(all(
+        target_os = "linux",
+        any(feature = "sysfs", target_env = "musl", not(feature = "libudev"))
+    )))]
+    serialport::available_ports()
+}
+
+/// The serial ports which a device may be connected to: USB serial ports, and
+/// with `all_ports` also the ports which are not known to be USB devices
+pub fn candidate_ports(all_ports: bool) -> serialport::Result<Vec<SerialPortInfo>> {
+    Ok(available_ports()?
+        .into_iter()
+        .filter(|port| is_candidate(port, all_ports))
+        .collect())
+}
+
+fn is_candidate(port: &SerialPortInfo, all_ports: bool) -> bool {
+    if all_ports {
+        matches!(
+            &port.port_type,
+            SerialPortType::UsbPort(..) |
+            // Allow PciPort. The user may want to use it.
+            // The port might have been misdetected by the system as PCI.
+            SerialPortType::PciPort |
+            // Good luck.
+            SerialPortType::Unknown
+        )
+    } else {
+        matches!(&port.port_type, SerialPortType::UsbPort(..))
+    }
+}
+
+/// A serial port which a device may be connected to
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct DiscoveredDevice {
+    /// Name of the serial port
+    pub port_name: String,
+    /// USB IDs and descriptions of the port, if it is a USB device
+    pub usb: Option<UsbPortInfo>,
+    /// Kind of the port
+    pub kind: PortKind,
+    /// The device found when probing the port, or why none was found; `None`
+    /// when the port was not probed
+    pub probe: Option<Result<DeviceInfo, Error>>,
+}
+
+/// Settings of finding the connected devices
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Discovery {
+    /// Whether to connect to each port to identify the device
+    pub probe: bool,
+    /// Whether to include ports which are not known to be USB devices
+    pub all_ports: bool,
+    /// Number of attempts to connect to each port when probing
+    pub connect_attempts: Option<usize>,
+}
+
+impl Discovery {
+    /// List the USB serial ports without probing them
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connect to each port to identify the device
+    pub fn with_probe(mut self, probe: bool) -> Self {
+        self.probe = probe;
+        self
+    }
+
+    /// Include ports which are not known to be USB devices
+    pub fn with_all_ports(mut self, all_ports: bool) -> Self {
+        self.all_ports = all_ports;
+        self
+    }
+
+    /// Make `attempts` attempts to connect to each port when probing
+    pub fn with_connect_attempts(mut self, attempts: usize) -> Self {
+        self.connect_attempts = Some(attempts);
+        self
+    }
+
+    /// Find the devices, probing the ports in parallel
+    pub fn discover(&self) -> Result<Vec<DiscoveredDevice>, Error> {
+        let ports = candidate_ports(self.all_ports)?;
+        let attempts = self.connect_attempts.unwrap_or(PROBE_CONNECT_ATTEMPTS);
+
+        let devices = thread::scope(|scope| {
+            let handles = ports
+                .into_iter()
+                .map(|port| {
+                    scope.spawn(move || {
+                        let usb = match port.port_type {
+                            SerialPortType::UsbPort(info) => Some(info),
+                            _ => None,
+                        };
+                        let kind = usb.as_ref().map_or(PortKind::Uart, |info| {
+                            PortKind::from_usb_ids(info.vid, info.pid)
+                        });
+                        let probe = self
+                            .probe
+                            .then(|| probe(&port.port_name, usb.clone(), attempts));
+
+                        DiscoveredDevice {
+                            port_name: port.port_name,
+                            usb,
+                            kind,
+                            probe,
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("probing a port panicked"))
+                .collect()
+        });
+
+        Ok(devices)
+    }
+}
+
+/// List the USB serial ports which a device may be connected to, without
+/// probing them
+pub fn discover_devices() -> Result<Vec<DiscoveredDevice>, Error> {
+    Discovery::new().discover()
+}
+
+/// Identify the device on the port `port_name`, and reset it back into its
+/// application
+fn probe(port_name: &str, usb: Option<UsbPortInfo>, attempts: usize) -> Result<DeviceInfo, Error> {
+    debug!("Probing {port_name}");
+
+    let port = Port::open(port_name, 115_200)?;
+    let port_info = usb.unwrap_or(UsbPortInfo {
+        vid: 0,
+        pid: 0,
+        serial_number: None,
+        manufacturer: None,
+        product: None,
+    });
+    let options = ConnectOptions::default()
+        .with_use_stub(false)
+        .with_connect_strategy(ConnectStrategy::default().with_attempts(attempts));
+    let mut flasher = Flasher::connect(port, port_info, options)?;
+
+    let info = flasher.device_info()?;
+    flasher.connection().reset()?;
+
+    Ok(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ports_are_filtered() {
+        let port = |port_type| SerialPortInfo {
+            port_name: "/dev/ttyX".into(),
+            port_type,
+        };
+        let usb = port(SerialPortType::UsbPort(UsbPortInfo {
+            vid: 0x303a,
+            pid: 0x1001,
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+        }));
+
+        assert!(is_candidate(&usb, false));
+        assert!(!is_candidate(&port(SerialPortType::PciPort), false));
+        assert!(is_candidate(&port(SerialPortType::PciPort), true));
+        assert!(!is_candidate(&port(SerialPortType::BluetoothPort), true));
+    }
+}
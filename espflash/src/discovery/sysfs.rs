@@ -0,0 +1,193 @@
+//! Enumeration of serial ports by scanning sysfs, for Linux builds without
+//! libudev
+//!
+//! The ports are described the same way as by the libudev backend of
+//! `serialport`, so that selecting ports by their USB identifiers, serial
+//! numbers and the known devices works with either backend.
+
+use std::{fs, io, path::Path};
+
+use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+
+/// The serial ports of the system
+// The module is also built for its tests when using libudev
+#[cfg_attr(test, allow(dead_code))]
+pub(super) fn available_ports() -> serialport::Result<Vec<SerialPortInfo>> {
+    Ok(scan(Path::new("/sys/class/tty"), Path::new("/dev"))?)
+}
+
+/// Find the ports in the TTY class directory `sys_tty`, whose device files
+/// are in `dev`
+fn scan(sys_tty: &Path, dev: &Path) -> io::Result<Vec<SerialPortInfo>> {
+    let mut ports = Vec::new();
+
+    for entry in fs::read_dir(sys_tty)? {
+        let entry = entry?;
+        let device = entry.path().join("device");
+        // Virtual terminals and pseudo-terminals have no device
+        let Ok(device) = device.canonicalize() else {
+            continue;
+        };
+
+        let port_name = dev.join(entry.file_name());
+        if !port_name.exists() {
+            continue;
+        }
+        let port_name = port_name.to_string_lossy().into_owned();
+
+        // The 8250 driver registers a number of ports whether or not the
+        // hardware exists, only those which can be opened are real
+        if link_name(&device.join("driver")).as_deref() == Some("serial8250")
+            && serialport::new(&port_name, 9600).open().is_err()
+        {
+            continue;
+        }
+
+        ports.push(SerialPortInfo {
+            port_name,
+            port_type: port_type(&device),
+        });
+    }
+
+    ports.sort_by(|a, b| a.port_name.cmp(&b.port_name));
+
+    Ok(ports)
+}
+
+/// Describe the port provided by `device`
+fn port_type(device: &Path) -> SerialPortType {
+    // Serial converters are children of the USB interface, while CDC-ACM ports
+    // are provided by the interface itself
+    let interface = match link_name(&device.join("subsystem")).as_deref() {
+        Some("usb-serial") => device.parent(),
+        Some("usb") => Some(device),
+        Some("pci") => return SerialPortType::PciPort,
+        _ => None,
+    };
+
+    interface
+        .and_then(Path::parent)
+        .and_then(usb_port_info)
+        .map_or(SerialPortType::Unknown, SerialPortType::UsbPort)
+}
+
+/// Read the descriptors of the USB device at `usb_device`
+fn usb_port_info(usb_device: &Path) -> Option<UsbPortInfo> {
+    let read = |attribute: &str| {
+        fs::read_to_string(usb_device.join(attribute))
+            .ok()
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+    let read_id = |attribute: &str| u16::from_str_radix(&read(attribute)?, 16).ok();
+
+    Some(UsbPortInfo {
+        vid: read_id("idVendor")?,
+        pid: read_id("idProduct")?,
+        serial_number: read("serial"),
+        manufacturer: read("manufacturer"),
+        product: read("product"),
+    })
+}
+
+/// The name of the directory the symbolic link `link` points to
+fn link_name(link: &Path) -> Option<String> {
+    let target = fs::read_link(link).ok()?;
+    Some(target.file_name()?.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::symlink, path::Path};
+
+    use serialport::{SerialPortType, UsbPortInfo};
+
+    use super::scan;
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn link(target: &Path, link: &Path) {
+        fs::create_dir_all(target).unwrap();
+        fs::create_dir_all(link.parent().unwrap()).unwrap();
+        symlink(target, link).unwrap();
+    }
+
+    #[test]
+    fn ports_are_described_like_libudev() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let (sys, tty, dev) = (
+            root.join("sys"),
+            root.join("sys/class/tty"),
+            root.join("dev"),
+        );
+
+        // A CDC-ACM port, e.g. the USB-Serial-JTAG peripheral
+        let acm = sys.join("devices/usb1/1-1");
+        write(&acm.join("idVendor"), "303a\n");
+        write(&acm.join("idProduct"), "1001\n");
+        write(&acm.join("serial"), "F4:12:FA:00:11:22\n");
+        write(&acm.join("product"), "USB JTAG/serial debug unit\n");
+        link(&sys.join("bus/usb"), &acm.join("1-1:1.0/subsystem"));
+        link(&acm.join("1-1:1.0"), &tty.join("ttyACM0/device"));
+        write(&dev.join("ttyACM0"), "");
+
+        // A USB to UART converter without a serial number
+        let converter = sys.join("devices/usb1/1-2");
+        write(&converter.join("idVendor"), "10c4\n");
+        write(&converter.join("idProduct"), "ea60\n");
+        link(
+            &sys.join("bus/usb-serial"),
+            &converter.join("1-2:1.0/ttyUSB0/subsystem"),
+        );
+        link(
+            &converter.join("1-2:1.0/ttyUSB0"),
+            &tty.join("ttyUSB0/device"),
+        );
+        write(&dev.join("ttyUSB0"), "");
+
+        // An 8250 port without hardware, which cannot be opened
+        let platform = sys.join("devices/platform/serial8250");
+        link(
+            &sys.join("bus/platform/drivers/serial8250"),
+            &platform.join("driver"),
+        );
+        link(&platform, &tty.join("ttyS0/device"));
+        write(&dev.join("ttyS0"), "");
+
+        // A virtual terminal
+        fs::create_dir_all(tty.join("tty1")).unwrap();
+        write(&dev.join("tty1"), "");
+
+        let ports = scan(&tty, &dev).unwrap();
+
+        let [acm, converter] = ports.as_slice() else {
+            panic!("unexpected ports: {ports:?}");
+        };
+        assert_eq!(acm.port_name, dev.join("ttyACM0").to_string_lossy());
+        assert_eq!(
+            acm.port_type,
+            SerialPortType::UsbPort(UsbPortInfo {
+                vid: 0x303a,
+                pid: 0x1001,
+                serial_number: Some("F4:12:FA:00:11:22".to_string()),
+                manufacturer: None,
+                product: Some("USB JTAG/serial debug unit".to_string()),
+            })
+        );
+        assert_eq!(converter.port_name, dev.join("ttyUSB0").to_string_lossy());
+        assert_eq!(
+            converter.port_type,
+            SerialPortType::UsbPort(UsbPortInfo {
+                vid: 0x10c4,
+                pid: 0xea60,
+                serial_number: None,
+                manufacturer: None,
+                product: None,
+            })
+        );
+    }
+}
@@ -0,0 +1,457 @@
+//! Batch burning of eFuses
+//!
+//! A set of eFuse fields to burn is described by an [EfuseSpec], which can be
+//! loaded from a TOML or JSON file. Before anything is written to the device,
+//! the spec is checked against the current state of the eFuses, producing an
+//! [EfusePlan]. This allows the plan to be reviewed (e.g. with a dry-run), and
+//! guarantees that all problems which can be detected up front are reported
+//! before the first (irreversible) burn.
+//!
+//! Fields are addressed by their eFuse block, the word within that block, and
+//! the bit range within that word, as documented in the technical reference
+//! manual of each chip.
+//!
+//! Only BLOCK0 of the ESP32-C3 can be burned so far. The other blocks are
+//! protected by a Reed-Solomon code, and other chips have different eFuse
+//! controllers.
+
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{connection::Connection, error::Error, targets::Chip};
+
+/// A set of eFuse fields to burn
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EfuseSpec {
+    /// Fields to burn, in order
+    #[serde(default)]
+    pub burn: Vec<EfuseBurn>,
+}
+
+/// A single eFuse field to burn
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct EfuseBurn {
+    /// Name of the field, used when reporting progress
+    pub name: String,
+    /// eFuse block containing the field
+    pub block: u32,
+    /// Word within the block containing the field
+    pub word: u32,
+    /// Lowest bit of the field within the word
+    #[serde(default)]
+    pub bit: u32,
+    /// Number of bits of the field
+    #[serde(default = "default_width")]
+    pub width: u32,
+    /// Value to burn
+    pub value: u32,
+    /// Bit in the write-disable word (`WR_DIS`, word 0 of block 0) guarding
+    /// this field
+    #[serde(default)]
+    pub wr_dis_bit: Option<u32>,
+    /// Burn the write-disable bit after the field, preventing further changes
+    #[serde(default)]
+    pub write_protect: bool,
+}
+
+fn default_width() -> u32 {
+    1
+}
+
+impl EfuseBurn {
+    /// Mask of the field within its word
+    fn mask(&self) -> u32 {
+        let bits = if self.width >= 32 {
+            u32::MAX
+        } else {
+            (1 << self.width) - 1
+        };
+
+        bits << self.bit
+    }
+
+    fn validate(&self, chip: Chip, controller: &EfuseController) -> Result<(), Error> {
+        let invalid = |reason: &str| Error::InvalidEfuseSpec(format!("{}: {reason}", self.name));
+
+        if self.block != 0 {
+            return Err(invalid(
+                "only block 0 can be burned, as the other blocks are protected by a \
+                 Reed-Solomon code which is not supported yet",
+            ));
+        }
+        let words = controller.block_words[self.block as usize];
+        if self.word >= words {
+            return Err(invalid(&format!(
+                "block {} of the {chip} consists of {words} words",
+                self.block
+            )));
+        }
+        if self.width == 0 || self.bit + self.width > 32 {
+            return Err(invalid("the field must be within a single 32-bit word"));
+        }
+        if self.width < 32 && self.value >> self.width != 0 {
+            return Err(invalid("the value does not fit into the field"));
+        }
+        if self.wr_dis_bit.is_some_and(|bit| bit >= 32) {
+            return Err(invalid("`wr_dis_bit` must be less than 32"));
+        }
+        if self.write_protect && self.wr_dis_bit.is_none() {
+            return Err(invalid("`write_protect` requires `wr_dis_bit` to be set"));
+        }
+
+        Ok(())
+    }
+}
+
+impl EfuseSpec {
+    /// Parse a spec from a TOML document
+    pub fn from_toml(data: &str) -> Result<Self, Error> {
+        toml::from_str(data).map_err(|e| Error::InvalidEfuseSpec(e.to_string()))
+    }
+
+    /// Parse a spec from a JSON document
+    pub fn from_json(data: &str) -> Result<Self, Error> {
+        serde_json::from_str(data).map_err(|e| Error::InvalidEfuseSpec(e.to_string()))
+    }
+
+    /// Load a spec from a `.toml` or `.json` file
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let data = fs::read_to_string(path)
+            .map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&data),
+            Some("json") => Self::from_json(&data),
+            _ => Err(Error::InvalidEfuseSpec(format!(
+                "'{}' must be a .toml or .json file",
+                path.display()
+            ))),
+        }
+    }
+}
+
+/// What needs to be done for a single field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EfuseAction {
+    /// The bits which still have to be burned, within the word
+    Burn(u32),
+    /// The field already has the requested value
+    AlreadyBurned,
+}
+
+/// A validated step of an [EfusePlan]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EfuseStep {
+    /// The requested burn
+    pub burn: EfuseBurn,
+    /// The current value of the field
+    pub current: u32,
+    /// What needs to be done
+    pub action: EfuseAction,
+    /// Whether the write-disable bit still needs to be burned afterwards
+    pub write_protect: bool,
+}
+
+/// The eFuse burns required to apply an [EfuseSpec] to a device
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EfusePlan {
+    pub steps: Vec<EfuseStep>,
+}
+
+impl EfusePlan {
+    /// Check a spec against the current state of the eFuses
+    ///
+    /// `read_word` returns the current value of a word in a block of the eFuses
+    /// of `chip`. Fails if any of the requested fields cannot be burned, as
+    /// bits can only ever be set, or because the field is write-protected.
+    pub fn new(
+        spec: &EfuseSpec,
+        chip: Chip,
+        mut read_word: impl FnMut(u32, u32) -> Result<u32, Error>,
+    ) -> Result<Self, Error> {
+        let conflict = |burn: &EfuseBurn, reason: String| Error::EfuseConflict {
+            name: burn.name.clone(),
+            reason,
+        };
+
+        let controller = EfuseController::for_chip(chip)?;
+
+        let mut steps: Vec<EfuseStep> = Vec::with_capacity(spec.burn.len());
+        for burn in &spec.burn {
+            burn.validate(chip, controller)?;
+
+            if let Some(other) = steps.iter().find(|step| {
+                step.burn.block == burn.block
+                    && step.burn.word == burn.word
+                    && step.burn.mask() & burn.mask() != 0
+            }) {
+                return Err(conflict(
+                    burn,
+                    format!("overlaps with '{}'", other.burn.name),
+                ));
+            }
+
+            let current = (read_word(burn.block, burn.word)? & burn.mask()) >> burn.bit;
+            let wr_dis = read_word(0, 0)?;
+            let protected = burn.wr_dis_bit.is_some_and(|bit| wr_dis & (1 << bit) != 0);
+
+            if current & !burn.value != 0 {
+                return Err(conflict(
+                    burn,
+                    format!(
+                        "the current value {current:#x} has bits set which are not set in {:#x}, \
+                         and eFuse bits cannot be cleared",
+                        burn.value
+                    ),
+                ));
+            }
+
+            let action = if current == burn.value {
+                EfuseAction::AlreadyBurned
+            } else if protected {
+                return Err(conflict(burn, "the field is write-protected".into()));
+            } else {
+                EfuseAction::Burn((burn.value & !current) << burn.bit)
+            };
+
+            steps.push(EfuseStep {
+                burn: burn.clone(),
+                current,
+                action,
+                write_protect: burn.write_protect && !protected,
+            });
+        }
+
+        Ok(Self { steps })
+    }
+}
+
+/// Progress of applying an [EfusePlan], allowing an interrupted run to be
+/// resumed
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct EfuseState {
+    /// Names of the fields which have been burned successfully
+    pub completed: Vec<String>,
+}
+
+impl EfuseState {
+    /// Load the state from a JSON file, starting from scratch if it does not
+    /// exist
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        match fs::read_to_string(path) {
+            Ok(data) => {
+                serde_json::from_str(&data).map_err(|e| Error::InvalidEfuseSpec(e.to_string()))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(Error::FileOpenError(path.display().to_string(), e)),
+        }
+    }
+
+    /// Save the state to a JSON file
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let data = serde_json::to_string_pretty(self).unwrap();
+        crate::output::write(path, data)
+    }
+}
+
+/// Registers of the eFuse controller, relative to [ReadEFuse::efuse_reg]
+///
+/// [ReadEFuse::efuse_reg]: crate::targets::ReadEFuse::efuse_reg
+struct EfuseController {
+    /// Read registers of each block
+    blocks: &'static [u32],
+    /// Number of words of each block
+    block_words: &'static [u32],
+    pgm_data: u32,
+    conf: u32,
+    cmd: u32,
+    dac_conf: u32,
+    wr_tim_conf1: u32,
+    wr_tim_conf2: u32,
+}
+
+const ESP32C3_EFUSE: EfuseController = EfuseController {
+    blocks: &[
+        0x02c, 0x044, 0x05c, 0x07c, 0x09c, 0x0bc, 0x0dc, 0x0fc, 0x11c, 0x13c, 0x15c,
+    ],
+    block_words: &[6, 6, 8, 8, 8, 8, 8, 8, 8, 8, 8],
+    pgm_data: 0x000,
+    conf: 0x1cc,
+    cmd: 0x1d4,
+    dac_conf: 0x1e8,
+    wr_tim_conf1: 0x1f4,
+    wr_tim_conf2: 0x1f8,
+};
+
+const EFUSE_WRITE_OP_CODE: u32 = 0x5a5a;
+const EFUSE_READ_OP_CODE: u32 = 0x5aa5;
+const EFUSE_READ_CMD: u32 = 0x1;
+const EFUSE_PGM_CMD: u32 = 0x2;
+
+impl EfuseController {
+    fn for_chip(chip: Chip) -> Result<&'static Self, Error> {
+        match chip {
+            Chip::Esp32c3 => Ok(&ESP32C3_EFUSE),
+            _ => Err(Error::EfuseUnsupported(chip)),
+        }
+    }
+}
+
+/// Read a word of an eFuse block
+pub fn read_efuse_word(
+    connection: &mut Connection,
+    chip: Chip,
+    block: u32,
+    word: u32,
+) -> Result<u32, Error> {
+    let controller = EfuseController::for_chip(chip)?;
+    let offset = controller
+        .blocks
+        .get(block as usize)
+        .ok_or_else(|| Error::InvalidEfuseSpec(format!("eFuse block {block} does not exist")))?;
+
+    connection.read_reg(chip.into_target().efuse_reg() + offset + word * 4)
+}
+
+/// Burn the given bits of a word in block 0
+///
+/// Only block 0 of the ESP32-C3 is supported, as the other blocks are protected
+/// by a Reed-Solomon code which has to be burned along with the data.
+pub fn burn_efuse_bits(
+    connection: &mut Connection,
+    chip: Chip,
+    word: u32,
+    bits: u32,
+) -> Result<(), Error> {
+    let controller = EfuseController::for_chip(chip)?;
+    let base = chip.into_target().efuse_reg();
+
+    // Programming timings for a 40 MHz APB clock
+    connection.write_reg(base + controller.dac_conf, 0xff << 9, Some(0xff << 9))?;
+    connection.write_reg(base + controller.dac_conf, 0x28, Some(0xff))?;
+    connection.write_reg(
+        base + controller.wr_tim_conf1,
+        0x3000 << 8,
+        Some(0xffff << 8),
+    )?;
+    connection.write_reg(base + controller.wr_tim_conf2, 0x190, Some(0xffff))?;
+
+    for i in 0..8 {
+        let value = if i == word { bits } else { 0 };
+        connection.write_reg(base + controller.pgm_data + i * 4, value, None)?;
+    }
+
+    connection.write_reg(base + controller.conf, EFUSE_WRITE_OP_CODE, None)?;
+    connection.write_reg(base + controller.cmd, EFUSE_PGM_CMD, None)?;
+    wait_efuse_idle(connection, base + controller.cmd)?;
+
+    for i in 0..8 {
+        connection.write_reg(base + controller.pgm_data + i * 4, 0, None)?;
+    }
+
+    // Reload the eFuse values, so that the new value can be read back
+    connection.write_reg(base + controller.conf, EFUSE_READ_OP_CODE, None)?;
+    connection.write_reg(base + controller.cmd, EFUSE_READ_CMD, None)?;
+    wait_efuse_idle(connection, base + controller.cmd)?;
+
+    Ok(())
+}
+
+fn wait_efuse_idle(connection: &mut Connection, cmd: u32) -> Result<(), Error> {
+    for _ in 0..100 {
+        if connection.read_reg(cmd)? & (EFUSE_PGM_CMD | EFUSE_READ_CMD) == 0 {
+            return Ok(());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    Err(Error::EfuseBurnFailed(
+        "timed out waiting for the eFuse controller".into(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> EfuseSpec {
+        EfuseSpec::from_toml(
+            r#"
+            [[burn]]
+            name = "DIS_USB_JTAG"
+            block = 0
+            word = 1
+            bit = 9
+            value = 1
+            wr_dis_bit = 2
+            write_protect = true
+
+            [[burn]]
+            name = "SPI_BOOT_CRYPT_CNT"
+            block = 0
+            word = 2
+            bit = 18
+            width = 3
+            value = 0b011
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn plan_efuse_burns() {
+        let plan = EfusePlan::new(&spec(), Chip::Esp32c3, |_, word| {
+            Ok(if word == 2 { 1 << 18 } else { 0 })
+        })
+        .unwrap();
+
+        assert_eq!(plan.steps[0].action, EfuseAction::Burn(1 << 9));
+        assert!(plan.steps[0].write_protect);
+        assert_eq!(plan.steps[1].current, 0b001);
+        assert_eq!(plan.steps[1].action, EfuseAction::Burn(0b010 << 18));
+    }
+
+    #[test]
+    fn reject_conflicting_burns() {
+        // Bits which are already burned cannot be cleared
+        let result = EfusePlan::new(&spec(), Chip::Esp32c3, |_, word| {
+            Ok(if word == 2 { 4 << 18 } else { 0 })
+        });
+        assert!(matches!(result, Err(Error::EfuseConflict { .. })));
+
+        // The first field is write-protected
+        let result = EfusePlan::new(&spec(), Chip::Esp32c3, |_, word| {
+            Ok(if word == 0 { 1 << 2 } else { 0 })
+        });
+        assert!(matches!(result, Err(Error::EfuseConflict { .. })));
+
+        // The value does not fit into a single bit
+        let spec =
+            EfuseSpec::from_toml("[[burn]]\nname = 'X'\nblock = 0\nword = 0\nvalue = 2").unwrap();
+        assert!(EfusePlan::new(&spec, Chip::Esp32c3, |_, _| Ok(0)).is_err());
+    }
+
+    #[test]
+    fn reject_words_beyond_the_block() {
+        let burn = |word| {
+            EfuseSpec::from_toml(&format!(
+                "[[burn]]\nname = 'X'\nblock = 0\nword = {word}\nvalue = 1"
+            ))
+            .unwrap()
+        };
+
+        assert!(EfusePlan::new(&burn(5), Chip::Esp32c3, |_, _| Ok(0)).is_ok());
+        // BLOCK0 of the ESP32-C3 consists of 6 words
+        assert!(matches!(
+            EfusePlan::new(&burn(6), Chip::Esp32c3, |_, _| Ok(0)),
+            Err(Error::InvalidEfuseSpec(_))
+        ));
+        assert!(matches!(
+            EfusePlan::new(&burn(0), Chip::Esp32, |_, _| Ok(0)),
+            Err(Error::EfuseUnsupported(Chip::Esp32))
+        ));
+    }
+}
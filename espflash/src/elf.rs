@@ -5,7 +5,7 @@ use std::{
     cmp::Ordering,
     fmt::{Debug, Formatter},
     mem::take,
-    ops::AddAssign,
+    ops::{AddAssign, Range},
 };
 
 use xmas_elf::{
@@ -45,16 +45,93 @@ pub trait FirmwareImage<'a> {
                 .filter(move |segment| !chip.into_target().addr_is_flash(segment.addr)),
         )
     }
+
+    /// The application image as it is written to flash, if it has been built
+    /// already
+    ///
+    /// Such an image is written as it is, instead of being built from its
+    /// segments.
+    fn prebuilt(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Memory ranges of the segments which are built into the image but not
+    /// written to flash
+    ///
+    /// The image is built from all segments, so the others stay at their
+    /// offsets, and only the flash sectors holding nothing but these ranges
+    /// are skipped.
+    fn skipped_ranges(&self) -> Vec<Range<u32>> {
+        Vec::new()
+    }
+}
+
+/// Selection of the segments of an ELF file which are written to flash, by
+/// the names of the sections they are built from
+///
+/// The image is still built from all segments, see
+/// [FirmwareImage::skipped_ranges].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SegmentFilter {
+    /// Write all segments
+    #[default]
+    All,
+    /// Only write the segments of the given sections
+    Only(Vec<String>),
+    /// Write all segments except those of the given sections
+    Skip(Vec<String>),
+}
+
+impl SegmentFilter {
+    /// Whether the segment of the section `name` is written
+    pub fn includes(&self, name: &str) -> bool {
+        match self {
+            SegmentFilter::All => true,
+            SegmentFilter::Only(names) => names.iter().any(|n| n == name),
+            SegmentFilter::Skip(names) => !names.iter().any(|n| n == name),
+        }
+    }
+
+    fn names(&self) -> &[String] {
+        match self {
+            SegmentFilter::All => &[],
+            SegmentFilter::Only(names) | SegmentFilter::Skip(names) => names,
+        }
+    }
 }
 
 /// A firmware image built from an ELF file
 pub struct ElfFirmwareImage<'a> {
     elf: ElfFile<'a>,
+    filter: SegmentFilter,
 }
 
 impl<'a> ElfFirmwareImage<'a> {
     pub fn new(elf: ElfFile<'a>) -> Self {
-        Self { elf }
+        Self {
+            elf,
+            filter: SegmentFilter::All,
+        }
+    }
+
+    /// Only write the segments selected by `filter` to flash
+    ///
+    /// Returns an error if `filter` names a section which is not in the ELF
+    /// file, as it is most likely misspelled.
+    pub fn with_segment_filter(mut self, filter: SegmentFilter) -> Result<Self, Error> {
+        if let Some(name) = filter.names().iter().find(|name| {
+            !self
+                .elf
+                .section_iter()
+                .any(|header| header.get_name(&self.elf) == Ok(name.as_str()))
+        }) {
+            return Err(Error::UnknownSegment(name.clone()));
+        }
+
+        self.filter = filter;
+
+        Ok(self)
     }
 }
 
@@ -114,6 +191,25 @@ impl<'a> FirmwareImage<'a> for ElfFirmwareImage<'a> {
                 }),
         )
     }
+
+    fn skipped_ranges(&self) -> Vec<Range<u32>> {
+        self.elf
+            .section_iter()
+            .filter(|header| {
+                header.size() > 0
+                    && header.get_type() == Ok(ShType::ProgBits)
+                    && header.offset() > 0
+                    && header.address() > 0
+                    && !self
+                        .filter
+                        .includes(header.get_name(&self.elf).unwrap_or_default())
+            })
+            .map(|header| {
+                let addr = header.address() as u32;
+                addr..addr + header.size() as u32
+            })
+            .collect()
+    }
 }
 
 #[derive(Eq, Clone, Default)]
@@ -259,3 +355,33 @@ impl<'a> From<CodeSegment<'a>> for RomSegment<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn skipped_ranges(filter: SegmentFilter) -> Result<Vec<Range<u32>>, Error> {
+        let elf_data = std::fs::read("resources/apps/esp32c3").unwrap();
+        let image = ElfFirmwareImage::try_from(&elf_data[..])?.with_segment_filter(filter)?;
+
+        Ok(image.skipped_ranges())
+    }
+
+    #[test]
+    fn segments_are_filtered_by_section() {
+        assert!(skipped_ranges(SegmentFilter::All).unwrap().is_empty());
+
+        let skipped = skipped_ranges(SegmentFilter::Skip(vec![".rodata".into()])).unwrap();
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].start, 0x3c01_0020);
+
+        let only = skipped_ranges(SegmentFilter::Only(vec![".text".into()])).unwrap();
+        assert!(only.contains(&skipped[0]));
+        assert!(!only.iter().any(|range| range.start == 0x4200_0020));
+
+        assert!(matches!(
+            skipped_ranges(SegmentFilter::Skip(vec![".txet".into()])),
+            Err(Error::UnknownSegment(name)) if name == ".txet"
+        ));
+    }
+}
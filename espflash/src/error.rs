@@ -27,6 +27,48 @@ pub enum Error {
     #[diagnostic(code(espflash::app_partition_not_found))]
     AppPartitionNotFound,
 
+    #[error("Invalid monitor rule: {0}")]
+    #[diagnostic(
+        code(espflash::invalid_monitor_rule),
+        help("Rules are given as `--on PATTERN ACTION`, where the action is one of `run:COMMAND`, `mark` or `reset`")
+    )]
+    InvalidMonitorRule(String),
+
+    #[error("Invalid channel route: {0}")]
+    #[diagnostic(
+        code(espflash::invalid_channel_route),
+        help("Channels are routed as `CHANNEL=ROUTE`, where the route is one of `auto`, `defmt`, `serial`, `file:PATH` or `drop`")
+    )]
+    InvalidChannelRoute(String),
+
+    #[error("Partition '{0}' is not an app partition")]
+    #[diagnostic(
+        code(espflash::not_an_app_partition),
+        help("The application can only be written to partitions of type 'app'")
+    )]
+    NotAnAppPartition(String),
+
+    #[error("Cannot read {size:#x} bytes of memory at {addr:#x}")]
+    #[diagnostic(
+        code(espflash::invalid_memory_region),
+        help("The address must be aligned to 4 bytes, and the region must lie within the 32-bit address space")
+    )]
+    InvalidMemoryRegion { addr: u32, size: u32 },
+
+    #[error("Cannot read {size:#x} bytes of flash at {offset:#x}")]
+    #[diagnostic(
+        code(espflash::invalid_flash_region),
+        help("The region must end within the 32-bit address space")
+    )]
+    InvalidFlashRegion { offset: u32, size: u32 },
+
+    #[error("Cannot erase {size:#x} bytes of flash at {offset:#x} with the ROM loader")]
+    #[diagnostic(
+        code(espflash::unaligned_erase_region),
+        help("The ROM loader erases whole sectors, so the offset and size must be multiples of 4096 bytes. Use the flasher stub to erase other regions")
+    )]
+    UnalignedEraseRegion { offset: u32, size: u32 },
+
     #[error("Operation was cancelled by the user")]
     #[diagnostic(code(espflash::cancelled))]
     Cancelled,
@@ -47,13 +89,20 @@ pub enum Error {
     )]
     ChipMismatch(String, String),
 
-    #[error("Chip not argument provided, this is required when using the `--before no-reset-no-sync` option")]
+    #[error("Chip argument not provided, this is required when using the `--before no-reset-no-sync` or `--simulate` options")]
     #[diagnostic(
         code(espflash::chip_not_provided),
         help("Ensure that you provide the `-c/--chip` option with the proper chip")
     )]
     ChipNotProvided,
 
+    #[error("No device is connected with `--chip none`")]
+    #[diagnostic(
+        code(espflash::no_chip),
+        help("Use `--chip none` with `--simulate` to flash a simulated device, or select the chip of the connected device")
+    )]
+    NoChip,
+
     #[error("Corrupt data, expected {0:2x?} bytes but receved {1:2x?} bytes")]
     #[diagnostic(code(espflash::read_flash::corrupt_data))]
     CorruptData(usize, usize),
@@ -79,10 +128,54 @@ pub enum Error {
     )]
     ElfTooBig(u32, u32),
 
+    #[error(
+        "The image header declares {header} of flash, but the connected device only has {detected}"
+    )]
+    #[diagnostic(
+        code(espflash::flash_size_exceeds_detected),
+        help("Use `--flash-size {detected}` (or set `size` in the `[flash]` section of the config file), or omit the flash size to use the detected one")
+    )]
+    FlashSizeExceedsDetected {
+        header: FlashSize,
+        detected: FlashSize,
+    },
+
+    #[error("The partition table ends at {end:#x}, which exceeds the configured flash size of {flash_size}")]
+    #[diagnostic(
+        code(espflash::partition_table_exceeds_flash_size),
+        help("Use `--flash-size {required}` if the device has enough flash, otherwise shrink the partition table")
+    )]
+    PartitionTableExceedsFlashSize {
+        end: u32,
+        flash_size: FlashSize,
+        required: FlashSize,
+    },
+
+    #[error("The serial port {0} was disconnected, e.g. during a suspend of the host, and did not reappear")]
+    #[diagnostic(
+        code(espflash::serial_port_disconnected),
+        help("The device was unplugged, or the host was suspended and the device was not found again afterwards. Reconnect the device and try again")
+    )]
+    SerialPortDisconnected(String),
+
     #[error("Failed to connect to on-device flash")]
     #[diagnostic(code(espflash::flash_connect))]
     FlashConnect,
 
+    #[error("Cannot reach {size:#x} bytes of flash at {offset:#x}, beyond the first 16 MB")]
+    #[diagnostic(
+        code(espflash::flash_address_unreachable),
+        help("The loader of the {chip} wraps around at 16 MB. Beyond it, flash is only read and written without encryption, with SPI flash commands, and not erased")
+    )]
+    FlashAddressUnreachable { chip: Chip, offset: u32, size: u32 },
+
+    #[error("The flash chip appears to be faulty: {0}")]
+    #[diagnostic(
+        code(espflash::flash_fault),
+        help("Check that the flash is supplied with the voltage it is rated for, e.g. that VDD_SDIO is configured for 1.8 V with a 1.8 V flash chip, and that it is wired correctly. A flash chip which still does not respond may be damaged")
+    )]
+    FlashFault(String),
+
     #[error("Expected MD5 digest (16 bytes), received: {0:#x} bytes")]
     #[diagnostic(code(espflash::read_flash::incorrect_digest_length))]
     IncorrectDigestLength(usize),
@@ -94,10 +187,127 @@ pub enum Error {
     #[error("The provided bootloader binary is invalid")]
     InvalidBootloader,
 
+    #[error("The provided direct boot binary is invalid: {0}")]
+    #[diagnostic(
+        code(espflash::invalid_direct_boot),
+        help("Direct boot images must be built for direct boot, e.g. with a linker script placing the magic word at the start of flash")
+    )]
+    InvalidDirectBootBinary(String),
+
+    #[error("The bootloader does not match the target: {0}")]
+    #[diagnostic(
+        code(espflash::bootloader_mismatch),
+        help(
+            "Make sure the path of the bootloader is correct, or pass `--force` to write it anyway"
+        )
+    )]
+    BootloaderMismatch(String),
+
+    #[error("The image was built for the chip with ID {chip_id}, not for the {chip}")]
+    #[diagnostic(
+        code(espflash::image_chip_mismatch),
+        help("Use an image built for the {chip}, or select the chip it was built for")
+    )]
+    ImageChipMismatch { chip_id: u16, chip: Chip },
+
+    #[error("The ELF image was built for the {elf_chip}, not for the {chip}")]
+    #[diagnostic(
+        code(espflash::elf_chip_mismatch),
+        help("Build the application for the {chip}, or select the chip it was built for")
+    )]
+    ElfChipMismatch { elf_chip: String, chip: Chip },
+
+    #[error("The ELF image does not name a supported chip it was built for")]
+    #[diagnostic(
+        code(espflash::chip_not_in_elf),
+        help("Select the chip with the `--chip` option")
+    )]
+    ChipNotInElf,
+
+    #[error("There is no app partition at {0:#x}")]
+    #[diagnostic(
+        code(espflash::no_app_partition_at),
+        help("Use the offset of an app partition, or provide a partition table with one at this offset")
+    )]
+    NoAppPartitionAt(u32),
+
+    #[error("The {image} cannot be written at {offset:#x}: {reason}")]
+    #[diagnostic(
+        code(espflash::invalid_image_offset),
+        help("Choose an offset which fits the partition table and the flash size, `espflash flash-map` shows the layout")
+    )]
+    InvalidImageOffset {
+        image: &'static str,
+        offset: u32,
+        reason: String,
+    },
+
+    #[error("Invalid image: {0}")]
+    #[diagnostic(code(espflash::invalid_image))]
+    InvalidImage(String),
+
+    #[error(
+        "The checksum of the image is {actual:#04x}, but its segments add up to {expected:#04x}"
+    )]
+    #[diagnostic(
+        code(espflash::image_checksum_mismatch),
+        help("The image is corrupted, rebuild it")
+    )]
+    ImageChecksumMismatch { expected: u8, actual: u8 },
+
+    #[error(
+        "The SHA-256 digest appended to the image is {actual}, but the image hashes to {expected}"
+    )]
+    #[diagnostic(
+        code(espflash::image_digest_mismatch),
+        help("The image is corrupted or was modified after it was built, rebuild it")
+    )]
+    ImageDigestMismatch { expected: String, actual: String },
+
+    #[error("The bootloader cannot map the application image with its MMU page size of {mmu_page_size:#x} bytes: {reason}\n{layout}")]
+    #[diagnostic(
+        code(espflash::invalid_image_layout),
+        help("The MMU page size of the bootloader depends on the flash size it was built for. Use `--mmu-page-size` with the page size of the bootloader, or rebuild the bootloader with the page size of the application")
+    )]
+    InvalidImageLayout {
+        mmu_page_size: u32,
+        reason: String,
+        layout: String,
+    },
+
+    #[error("The flash segments at {first:#010x} and {second:#010x} share an MMU page of {mmu_page_size:#x} bytes, but cannot be placed in the same page of flash")]
+    #[diagnostic(
+        code(espflash::flash_segments_share_mmu_page),
+        help("Segments in the same page need at least 8 bytes between them for the header of the second one. Change the linker script to merge the sections, or to align the second one to a page boundary")
+    )]
+    FlashSegmentsShareMmuPage {
+        first: u32,
+        second: u32,
+        mmu_page_size: u32,
+    },
+
     #[error("Specified bootloader path is not a .bin file")]
     #[diagnostic(code(espflash::invalid_bootloader_path))]
     InvalidBootloaderPath,
 
+    #[error("Invalid configuration in {path}: {reason}")]
+    #[diagnostic(
+        code(espflash::invalid_config),
+        help("Check the spelling of the keys and the types of the values; unknown keys are not allowed")
+    )]
+    InvalidConfig { path: String, reason: String },
+
+    #[error("The MMU page size {size:#x} is not supported by the {chip}")]
+    #[diagnostic(
+        code(espflash::invalid_mmu_page_size),
+        help("The supported MMU page sizes are: {valid}")
+    )]
+    InvalidMmuPageSize {
+        chip: Chip,
+        size: u32,
+        valid: String,
+    },
+
     #[error("The flash size '{0}' is invalid")]
     #[diagnostic(
         code(espflash::invalid_flash_size),
@@ -113,6 +323,95 @@ pub enum Error {
     #[diagnostic(code(espflash::invalid_partition_table_path))]
     InvalidPartitionTablePath,
 
+    #[error("Invalid partition size '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_partition_size),
+        help("Give the size in bytes, optionally in hexadecimal or with a `K` or `M` suffix, e.g. `0x6000`, `24K` or `1M`")
+    )]
+    InvalidPartitionSize(String),
+
+    #[error("Invalid partition type '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_partition_type),
+        help("The type is `app`, `data` or a custom type from 0x40 to 0xfe")
+    )]
+    InvalidPartitionType(String),
+
+    #[error("Invalid partition subtype '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_partition_subtype),
+        help("The subtype of this type of partition is one of {1}")
+    )]
+    InvalidPartitionSubtype(String, String),
+
+    #[error("Invalid partition label '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_partition_label),
+        help("The label has to be from 1 to 16 bytes long")
+    )]
+    InvalidPartitionLabel(String),
+
+    #[error("A partition labelled '{0}' already exists in the partition table")]
+    #[diagnostic(
+        code(espflash::partition_exists),
+        help("Choose a different label, or remove the existing partition first")
+    )]
+    PartitionExists(String),
+
+    #[error("The eFuse spec is invalid: {0}")]
+    #[diagnostic(code(espflash::efuse::invalid_spec))]
+    InvalidEfuseSpec(String),
+
+    #[error("The eFuse field '{name}' cannot be burned: {reason}")]
+    #[diagnostic(
+        code(espflash::efuse::conflict),
+        help("Nothing has been burned. Check the spec against the current eFuse values of the device")
+    )]
+    EfuseConflict { name: String, reason: String },
+
+    #[error("Burning eFuses failed: {0}")]
+    #[diagnostic(
+        code(espflash::efuse::burn_failed),
+        help("Fields which were burned successfully are recorded in the state file, and will be skipped when running the same command again")
+    )]
+    EfuseBurnFailed(String),
+
+    #[error("eFuse operations are not supported for the {0} yet")]
+    #[diagnostic(code(espflash::efuse::unsupported_chip))]
+    EfuseUnsupported(Chip),
+
+    #[error("No partition labelled '{0}' was found in the partition table")]
+    #[diagnostic(
+        code(espflash::partition_not_found),
+        help("Check the partition labels in the partition table on the device")
+    )]
+    PartitionNotFound(String),
+
+    #[error("Pre-flight checks failed: {0}")]
+    #[diagnostic(
+        code(espflash::preflight_failed),
+        help("Power the device from a supply which can deliver enough current, e.g. a different USB port or a powered hub")
+    )]
+    PreflightFailed(String),
+
+    #[error("No partition table was found on the device at any of the offsets {0}")]
+    #[diagnostic(
+        code(espflash::partition_table_not_found),
+        help("Provide the offset of the partition table with `--partition-table-offset`")
+    )]
+    PartitionTableNotFound(String),
+
+    #[error("The provided flasher stub is invalid: {0}")]
+    #[diagnostic(
+        code(espflash::invalid_stub),
+        help("Make sure the stub was built for the connected chip, or remove the `--stub-path` option to use the built-in stub")
+    )]
+    InvalidStub(String),
+
+    #[error("Specified stub path is not a .json, .toml or .elf file")]
+    #[diagnostic(code(espflash::invalid_stub_path))]
+    InvalidStubPath,
+
     #[error("No serial ports could be detected")]
     #[diagnostic(
         code(espflash::no_serial),
@@ -120,6 +419,13 @@ pub enum Error {
     )]
     NoSerial,
 
+    #[error("No serial ports could be detected in WSL")]
+    #[diagnostic(
+        code(espflash::no_serial_wsl),
+        help("USB devices must be forwarded from Windows to WSL with usbipd-win. In an administrator PowerShell on the Windows host, run `usbipd list` to find the bus ID of the device, `usbipd bind --busid <BUSID>` once to share it, and `usbipd attach --wsl --busid <BUSID>` to attach it (again after every replug). Alternatively, run espflash on the Windows host directly.")
+    )]
+    NoSerialWsl,
+
     #[error("Read more bytes than expected")]
     #[diagnostic(code(espflash::read_flash::read_more_than_expected))]
     ReadMoreThanExpected,
@@ -131,6 +437,125 @@ pub enum Error {
     )]
     StubRequired,
 
+    #[cfg(feature = "serialport")]
+    #[error("The {0} command is not available in Secure Download Mode")]
+    #[diagnostic(
+        code(espflash::secure_download_mode),
+        help("The ROM loader of the device only accepts the commands needed to write flash while Secure Download Mode is enabled in its eFuses")
+    )]
+    SecureDownloadMode(CommandType),
+
+    #[error("The device on {0} did not restart into download mode after the 1200 baud touch")]
+    #[diagnostic(
+        code(espflash::usb_touch_failed),
+        help("The firmware on the device may not support the 1200 baud touch. Hold the BOOT button while resetting or plugging in the device to enter download mode instead")
+    )]
+    #[cfg(feature = "serialport")]
+    UsbTouchFailed(String),
+
+    #[error("Invalid signing key: {0}")]
+    #[diagnostic(
+        code(espflash::invalid_signing_key),
+        help("Secure Boot V2 signing keys are PEM encoded RSA 3072 or ECDSA P-256 private keys, as generated by `espsecure.py generate_signing_key --version 2`")
+    )]
+    InvalidSigningKey(String),
+
+    #[error("Invalid flash encryption key: {0}")]
+    #[diagnostic(
+        code(espflash::invalid_encryption_key),
+        help("Flash encryption keys are raw binary files of 16, 32 or 64 bytes, as generated by `espsecure.py generate_flash_encryption_key`")
+    )]
+    InvalidEncryptionKey(String),
+
+    #[error("Encrypted data must be written to an address aligned to {alignment} bytes, not {address:#x}")]
+    #[diagnostic(
+        code(espflash::misaligned_encrypted_data),
+        help("Flash encryption works on blocks of {alignment} bytes, place the segment at an aligned address")
+    )]
+    MisalignedEncryptedData { address: u32, alignment: usize },
+
+    #[error("Invalid NVS keys: {0}")]
+    #[diagnostic(
+        code(espflash::invalid_nvs_keys),
+        help("NVS keys are stored as an `nvs_keys` partition image, as generated by `nvs_partition_gen.py generate-key`")
+    )]
+    InvalidNvsKeys(String),
+
+    #[error("Invalid NVS partition image: {0}")]
+    #[diagnostic(
+        code(espflash::invalid_nvs_image),
+        help("Generate the image with `nvs_partition_gen.py generate`, without encrypting it")
+    )]
+    InvalidNvsImage(String),
+
+    #[error("Invalid NVS keys partition: {0}")]
+    #[diagnostic(
+        code(espflash::invalid_nvs_keys_partition),
+        help("Add an encrypted partition for the keys to the partition table, e.g. `nvs_keys, data, nvs_keys, , 0x1000, encrypted`")
+    )]
+    InvalidNvsKeysPartition(String),
+
+    #[error("The NVS keys partition must be written with flash encryption")]
+    #[diagnostic(
+        code(espflash::nvs_keys_not_encrypted),
+        help("Use `--encrypt` or `--encryption-key`, or `--skip-keys` if the device already holds the keys")
+    )]
+    NvsKeysNotEncrypted,
+
+    #[error("Invalid OTA data: {0}")]
+    #[diagnostic(
+        code(espflash::invalid_ota_data),
+        help(
+            "Add an OTA data partition to the partition table, e.g. `otadata, data, ota, , 0x2000`"
+        )
+    )]
+    InvalidOtaData(String),
+
+    #[error("Invalid OTA slot: {0}")]
+    #[diagnostic(
+        code(espflash::invalid_ota_slot),
+        help("Give the index or the label of an OTA app partition, e.g. `1` or `ota_1`, or `factory` to boot the factory app")
+    )]
+    InvalidOtaSlot(String),
+
+    #[error("Invalid MAC address '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_mac_address),
+        help(
+            "MAC addresses are six hexadecimal bytes separated by colons, e.g. `f4:12:fa:00:11:22`"
+        )
+    )]
+    InvalidMacAddress(String),
+
+    #[error("Invalid merge-bin arguments: {0}")]
+    #[diagnostic(
+        code(espflash::invalid_merge_args),
+        help("Give the files to merge as pairs of an address and a path, e.g. `0x0 bootloader.bin 0x8000 partitions.bin`")
+    )]
+    InvalidMergeArgs(String),
+
+    #[error("Invalid Intel HEX file, line {line}: {reason}")]
+    #[diagnostic(
+        code(espflash::invalid_intel_hex),
+        help("Make sure the file is a valid Intel HEX file, or give a raw binary instead")
+    )]
+    InvalidIntelHex { line: usize, reason: String },
+
+    #[error("No address was given to write {0} to")]
+    #[diagnostic(
+        code(espflash::missing_address),
+        help("Give the address before the file, e.g. `espflash write-bin 0x10000 app.bin`, or use an Intel HEX file, which contains its addresses")
+    )]
+    MissingAddress(String),
+
+    #[error("Invalid network port URL: {0}")]
+    #[diagnostic(
+        code(espflash::invalid_network_port),
+        help("Network ports are given as `socket://HOST:PORT` for raw TCP servers, or `rfc2217://HOST:PORT` for RFC 2217 servers")
+    )]
+    #[cfg(feature = "serialport")]
+    InvalidNetworkPort(String),
+
     #[error("The serial port '{0}' could not be found")]
     #[diagnostic(
         code(espflash::serial_not_found),
@@ -138,6 +563,20 @@ pub enum Error {
     )]
     SerialNotFound(String),
 
+    #[error("The serial port selector '{0}' is invalid")]
+    #[diagnostic(
+        code(espflash::invalid_port_selector),
+        help("USB devices are selected with `auto:<VID>:<PID>[:<SERIAL>]`, where the VID and PID are hexadecimal, e.g. `auto:303a:1001`")
+    )]
+    InvalidPortSelector(String),
+
+    #[error("Multiple serial ports match the given USB identifiers: {0}")]
+    #[diagnostic(
+        code(espflash::ambiguous_serial_port),
+        help("Add (part of) the serial number of the device to the selector, e.g. `auto:303a:1001:<SERIAL>`")
+    )]
+    AmbiguousSerialPort(String),
+
     #[error("The {chip} does not support {feature}")]
     #[diagnostic(code(espflash::unsupported_feature))]
     UnsupportedFeature { chip: Chip, feature: String },
@@ -183,6 +622,13 @@ pub enum Error {
     )]
     InvalidElf(#[from] ElfError),
 
+    #[error("The ELF image has no section named `{0}`")]
+    #[diagnostic(
+        code(espflash::unknown_segment),
+        help("Segments are selected by the names of their sections, e.g. `.text` or `.rodata`")
+    )]
+    UnknownSegment(String),
+
     #[error("The bootloader returned an error")]
     #[cfg(feature = "serialport")]
     #[diagnostic(transparent)]
@@ -193,6 +639,95 @@ pub enum Error {
     #[diagnostic(transparent)]
     Defmt(#[from] DefmtError),
 
+    #[error("The SPI flash clock divider {divider} is invalid")]
+    #[diagnostic(
+        code(espflash::invalid_spi_clock_divider),
+        help("The divider must be between 1 and {max}")
+    )]
+    InvalidSpiClockDivider { divider: u32, max: u32 },
+
+    #[error("Invalid stub settle setting '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_stub_settle),
+        help("Use `off`, or the delay in milliseconds optionally followed by the number of retries, e.g. `100,5`")
+    )]
+    InvalidStubSettle(String),
+
+    #[error("Invalid compression level '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_compression_level),
+        help("Use `auto`, or a level from 0 (no compression) to 9 (best compression)")
+    )]
+    InvalidCompressionLevel(String),
+
+    #[error("Invalid flash block size '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_block_size),
+        help("Use `auto`, or a multiple of 4 bytes from 0x100 to 0x4000")
+    )]
+    InvalidBlockSize(String),
+
+    #[error("Invalid reset strategy '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_reset_step),
+        help("Use `classic`, `unix-tight`, `usb-jtag-serial` or `usb-bridge`, optionally followed by the delay in milliseconds to hold the boot pin, e.g. `classic:500`")
+    )]
+    InvalidResetStep(String),
+
+    #[error("Invalid custom reset sequence '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_custom_reset),
+        help("Separate the commands with `|`: `D0`/`D1` and `R0`/`R1` set DTR and RTS, `U0,1` sets both at once and `W0.1` waits for the given number of seconds, e.g. `D0|R1|W0.1|D1|R0|W0.05|D0`")
+    )]
+    InvalidCustomReset(String),
+
+    #[error("The reset hook has no command")]
+    #[diagnostic(
+        code(espflash::invalid_reset_hook),
+        help("Give the program followed by its arguments, e.g. `[\"./reset-board.sh\", \"--expander\", \"0x20\"]`")
+    )]
+    InvalidResetHook,
+
+    #[error("The reset hook `{0}` failed: {1}")]
+    #[diagnostic(
+        code(espflash::reset_hook_failed),
+        help("The hook is run with `download` or `run` as its last argument and the serial port in `ESPFLASH_PORT`, and has to exit successfully once the device was reset")
+    )]
+    ResetHookFailed(String, String),
+
+    #[error("Invalid SFDP of the flash chip, {0}")]
+    #[diagnostic(code(espflash::invalid_sfdp))]
+    InvalidSfdp(String),
+
+    #[error("Invalid monitor recording, {0}")]
+    #[diagnostic(code(espflash::invalid_recording))]
+    InvalidRecording(String),
+
+    #[error("Invalid SPI flash command, {0}")]
+    #[diagnostic(code(espflash::invalid_spi_command))]
+    InvalidSpiCommand(String),
+
+    #[error("Invalid SPI flash connection '{0}'")]
+    #[diagnostic(
+        code(espflash::invalid_spi_connection),
+        help("Use `SPI`, `HSPI`, or the flash GPIOs as `CLK,Q,D,HD,CS`")
+    )]
+    InvalidSpiConnection(String),
+
+    #[error("The flash of this {0} is embedded in the chip package and cannot be attached to other pins")]
+    #[diagnostic(
+        code(espflash::spi_connection_conflict),
+        help("Remove the `--spi-connection` option, the embedded flash is detected automatically")
+    )]
+    SpiConnectionConflict(Chip),
+
+    #[error("The flash differs from the expected contents, first at {0:#x}")]
+    #[diagnostic(
+        code(espflash::flash_contents_differ),
+        help("The region was not written, or was overwritten since. Regions written with flash encryption cannot be compared with their plaintext")
+    )]
+    FlashContentsDiffer(u32),
+
     #[error("Verification of flash content failed")]
     #[diagnostic(code(espflash::verify_failed))]
     VerifyFailed,
@@ -252,6 +787,13 @@ pub enum ConnectionError {
     )]
     DeviceNotFound,
 
+    #[error("The serial port was disconnected")]
+    #[diagnostic(
+        code(espflash::disconnected),
+        help("The device was unplugged or reset, or the host was suspended. Reconnect the device and try again")
+    )]
+    Disconnected,
+
     #[error("Received packet has invalid SLIP framing")]
     #[diagnostic(
         code(espflash::slip_framing),
@@ -298,6 +840,10 @@ pub enum ConnectionError {
 #[cfg(feature = "serialport")]
 impl From<io::Error> for ConnectionError {
     fn from(err: io::Error) -> Self {
+        if is_disconnected(&err) {
+            return ConnectionError::Disconnected;
+        }
+
         from_error_kind(err.kind(), err)
     }
 }
@@ -335,6 +881,14 @@ pub struct TimedOutCommand {
     command: Option<CommandType>,
 }
 
+#[cfg(feature = "serialport")]
+impl TimedOutCommand {
+    /// The command which timed out, if known
+    pub fn command(&self) -> Option<CommandType> {
+        self.command
+    }
+}
+
 #[cfg(feature = "serialport")]
 impl Display for TimedOutCommand {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
@@ -455,6 +1009,11 @@ impl RomError {
     pub fn new(command: CommandType, kind: RomErrorKind) -> RomError {
         RomError { command, kind }
     }
+
+    /// The kind of error the ROM reported
+    pub fn kind(&self) -> RomErrorKind {
+        self.kind
+    }
 }
 
 /// Missing partition error
@@ -522,6 +1081,23 @@ impl<T> ResultExt for Result<T, Error> {
     }
 }
 
+/// Whether an error of a serial port indicates that the device is gone, e.g.
+/// because it was unplugged or the host was suspended
+#[cfg(feature = "serialport")]
+pub(crate) fn is_disconnected(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    const DISCONNECTED: &[i32] = &[libc::EIO, libc::ENXIO, libc::ENODEV];
+    // ERROR_ACCESS_DENIED, ERROR_BAD_COMMAND, ERROR_GEN_FAILURE,
+    // ERROR_OPERATION_ABORTED and ERROR_DEVICE_NOT_CONNECTED
+    #[cfg(windows)]
+    const DISCONNECTED: &[i32] = &[5, 22, 31, 995, 1167];
+
+    err.kind() == io::ErrorKind::BrokenPipe
+        || err
+            .raw_os_error()
+            .is_some_and(|code| DISCONNECTED.contains(&code))
+}
+
 #[cfg(feature = "serialport")]
 #[cfg_attr(docsrs, doc(cfg(feature = "serialport")))]
 fn from_error_kind<E>(kind: io::ErrorKind, err: E) -> ConnectionError
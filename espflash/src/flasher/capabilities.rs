@@ -0,0 +1,220 @@
+//! The commands supported by the loader running on a device
+//!
+//! The ROM loaders of the chips and the flasher stub implement different sets
+//! of commands; a command which the loader does not know is not answered, and
+//! would only fail once it timed out. The [Capabilities] of the loader are
+//! therefore checked before such commands are sent, so that the flasher can
+//! use an alternative, e.g. reading flash with
+//! [CommandType::ReadFlashSlow] from the ROM loader of the ESP32 or erasing
+//! it with [CommandType::FlashBegin], or fail right away.
+
+use crate::{command::CommandType, error::Error, targets::Chip};
+
+/// Commands whose availability is listed by [Capabilities::commands]
+const COMMANDS: &[CommandType] = &[
+    CommandType::FlashBegin,
+    CommandType::FlashData,
+    CommandType::FlashEnd,
+    CommandType::MemBegin,
+    CommandType::MemEnd,
+    CommandType::MemData,
+    CommandType::Sync,
+    CommandType::WriteReg,
+    CommandType::ReadReg,
+    CommandType::SpiSetParams,
+    CommandType::SpiAttach,
+    CommandType::ReadFlashSlow,
+    CommandType::ChangeBaudrate,
+    CommandType::FlashDeflBegin,
+    CommandType::FlashDeflData,
+    CommandType::FlashDeflEnd,
+    CommandType::FlashMd5,
+    CommandType::GetSecurityInfo,
+    CommandType::EraseFlash,
+    CommandType::EraseRegion,
+    CommandType::ReadFlash,
+    CommandType::RunUserCode,
+    CommandType::FlashEncryptedData,
+];
+
+/// Size of the flash which can be addressed with 3-byte addresses
+pub const THREE_BYTE_ADDRESS_LIMIT: u64 = 0x100_0000;
+
+/// The commands supported by the loader running on a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    chip: Chip,
+    stub: bool,
+    secure_download_mode: bool,
+}
+
+impl Capabilities {
+    /// The capabilities of the flasher stub, or of the ROM loader, of `chip`
+    pub fn new(chip: Chip, stub: bool) -> Self {
+        Self {
+            chip,
+            stub,
+            secure_download_mode: false,
+        }
+    }
+
+    /// The capabilities of the ROM loader in Secure Download Mode, which
+    /// rejects the commands reading or writing memory and registers
+    pub fn with_secure_download_mode(mut self, secure_download_mode: bool) -> Self {
+        self.secure_download_mode = secure_download_mode;
+        self
+    }
+
+    /// Whether the flasher stub is running, rather than the ROM loader
+    pub fn stub(&self) -> bool {
+        self.stub
+    }
+
+    /// Whether the ROM loader runs in Secure Download Mode
+    pub fn secure_download_mode(&self) -> bool {
+        self.secure_download_mode
+    }
+
+    /// Whether the loader supports `command`
+    pub fn supports(&self, command: CommandType) -> bool {
+        use CommandType::*;
+
+        if self.secure_download_mode
+            && matches!(
+                command,
+                MemBegin | MemEnd | MemData | WriteReg | ReadReg | ReadFlashSlow | FlashMd5
+            )
+        {
+            return false;
+        }
+
+        match command {
+            FlashBegin | FlashData | FlashEnd | MemBegin | MemEnd | MemData | Sync | WriteReg
+            | ReadReg | SpiSetParams | SpiAttach | ChangeBaudrate | FlashDeflBegin
+            | FlashDeflData | FlashDeflEnd | FlashMd5 => true,
+            // Only the ROM loader of the ESP32 reads flash, 64 bytes at a time
+            ReadFlashSlow => !self.stub && self.chip == Chip::Esp32,
+            // The ROM loader of the ESP32 predates the command, and the stub
+            // does not implement it
+            GetSecurityInfo => !self.stub && self.chip != Chip::Esp32,
+            EraseFlash | EraseRegion | ReadFlash | RunUserCode | FlashEncryptedData => self.stub,
+            Unknown | FlashDetect => false,
+        }
+    }
+
+    /// The commands which the loader supports
+    pub fn commands(&self) -> impl Iterator<Item = CommandType> + '_ {
+        COMMANDS
+            .iter()
+            .copied()
+            .filter(|&command| self.supports(command))
+    }
+
+    /// Whether the loader reaches flash beyond the first 16 MB
+    ///
+    /// The flasher stub of the ESP32-S3 switches the flash chip to 4-byte
+    /// addressing when needed. All other loaders only send 3-byte addresses,
+    /// which wrap around at 16 MB; the flasher reads and writes flash beyond
+    /// with SPI flash commands instead, which take 4-byte addresses.
+    pub fn four_byte_addresses(&self) -> bool {
+        self.stub && self.chip == Chip::Esp32s3
+    }
+
+    /// Fail unless the loader can reach `size` bytes of flash at `offset`
+    pub fn require_flash_region(&self, offset: u32, size: u32) -> Result<(), Error> {
+        if offset as u64 + size as u64 <= THREE_BYTE_ADDRESS_LIMIT || self.four_byte_addresses() {
+            Ok(())
+        } else {
+            Err(Error::FlashAddressUnreachable {
+                chip: self.chip,
+                offset,
+                size,
+            })
+        }
+    }
+
+    /// Fail with the reason `command` cannot be used, unless it is supported
+    ///
+    /// Commands which only the flasher stub supports fail with
+    /// [Error::StubRequired], all others with [Error::UnsupportedFeature]. In
+    /// Secure Download Mode, where the stub cannot be loaded, commands which
+    /// the loaders support otherwise fail with [Error::SecureDownloadMode].
+    pub fn require(&self, command: CommandType) -> Result<(), Error> {
+        let otherwise_supported = Self::new(self.chip, self.stub).supports(command)
+            || Self::new(self.chip, true).supports(command);
+        if self.supports(command) {
+            Ok(())
+        } else if self.secure_download_mode && otherwise_supported {
+            Err(Error::SecureDownloadMode(command))
+        } else if !self.stub && Self::new(self.chip, true).supports(command) {
+            Err(Error::StubRequired)
+        } else {
+            Err(Error::UnsupportedFeature {
+                chip: self.chip,
+                feature: format!("the {command} command"),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rom_loaders_fall_back_or_require_the_stub() {
+        let esp32 = Capabilities::new(Chip::Esp32, false);
+        assert!(!esp32.supports(CommandType::ReadFlash));
+        assert!(esp32.supports(CommandType::ReadFlashSlow));
+        assert!(matches!(
+            esp32.require(CommandType::GetSecurityInfo),
+            Err(Error::UnsupportedFeature { .. })
+        ));
+
+        let esp32c3 = Capabilities::new(Chip::Esp32c3, false);
+        assert!(!esp32c3.supports(CommandType::ReadFlashSlow));
+        assert!(esp32c3.supports(CommandType::GetSecurityInfo));
+        assert!(matches!(
+            esp32c3.require(CommandType::EraseRegion),
+            Err(Error::StubRequired)
+        ));
+
+        let stub = Capabilities::new(Chip::Esp32c3, true);
+        assert!(stub.require(CommandType::ReadFlash).is_ok());
+        assert!(!stub.commands().any(|c| c == CommandType::ReadFlashSlow));
+    }
+
+    #[test]
+    fn secure_download_mode_rejects_register_access() {
+        let sdm = Capabilities::new(Chip::Esp32c3, false).with_secure_download_mode(true);
+        assert!(sdm.supports(CommandType::FlashBegin));
+        assert!(sdm.supports(CommandType::GetSecurityInfo));
+        assert!(!sdm.supports(CommandType::WriteReg));
+        assert!(matches!(
+            sdm.require(CommandType::ReadReg),
+            Err(Error::SecureDownloadMode(CommandType::ReadReg))
+        ));
+        assert!(matches!(
+            sdm.require(CommandType::EraseFlash),
+            Err(Error::SecureDownloadMode(CommandType::EraseFlash))
+        ));
+    }
+
+    #[test]
+    fn flash_beyond_16mb_needs_four_byte_addresses() {
+        let s3_stub = Capabilities::new(Chip::Esp32s3, true);
+        assert!(s3_stub.require_flash_region(0x1800000, 0x1000).is_ok());
+        assert!(s3_stub.require_flash_region(0x1fff000, 0x1000).is_ok());
+
+        let s3_rom = Capabilities::new(Chip::Esp32s3, false);
+        assert!(s3_rom.require_flash_region(0xfff000, 0x1000).is_ok());
+        assert!(matches!(
+            s3_rom.require_flash_region(0xfff000, 0x2000),
+            Err(Error::FlashAddressUnreachable { .. })
+        ));
+
+        let c3_stub = Capabilities::new(Chip::Esp32c3, true);
+        assert!(c3_stub.require_flash_region(0x1000000, 0x1000).is_err());
+        assert!(c3_stub.require_flash_region(u32::MAX, u32::MAX).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! Compatibility of the flasher with the revisions of each chip
+//!
+//! Stubs and fast paths are validated against the silicon revisions available
+//! when they are written, and some of them misbehave on very old or very new
+//! revisions. The table of quirks lists the features to avoid on each range of
+//! revisions, and the flasher falls back to the slower path, logging why.
+
+use std::{fmt, ops::RangeInclusive};
+
+use crate::targets::Chip;
+
+/// Revision of a chip, as its major and minor version
+pub type Revision = (u32, u32);
+
+/// Features which are avoided on some revisions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Feature {
+    /// Keeping several data blocks in flight when writing flash
+    PipelinedWrites,
+    /// Writing flash in blocks larger than the chip's default
+    LargeBlocks,
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::PipelinedWrites => "pipelined flash writes",
+            Self::LargeBlocks => "large flash write blocks",
+        })
+    }
+}
+
+/// A feature to avoid on some revisions of a chip
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Quirk {
+    chip: Chip,
+    revisions: RangeInclusive<Revision>,
+    feature: Feature,
+    reason: &'static str,
+}
+
+/// Why the fast write paths are avoided on the first revision of the ESP32
+const ESP32_ECO0: &str = "the fast write paths of the stub are not validated on ECO0 silicon";
+
+const QUIRKS: &[Quirk] = &[
+    Quirk {
+        chip: Chip::Esp32,
+        revisions: (0, 0)..=(0, 0),
+        feature: Feature::PipelinedWrites,
+        reason: ESP32_ECO0,
+    },
+    Quirk {
+        chip: Chip::Esp32,
+        revisions: (0, 0)..=(0, 0),
+        feature: Feature::LargeBlocks,
+        reason: ESP32_ECO0,
+    },
+];
+
+/// The features the flasher may use with a chip of a given revision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compatibility {
+    chip: Chip,
+    /// Revision of the chip, if it is known
+    revision: Option<Revision>,
+}
+
+impl Compatibility {
+    /// Compatibility with `chip` of the given revision, allowing every
+    /// feature when the revision is unknown
+    pub fn new(chip: Chip, revision: Option<Revision>) -> Self {
+        Self { chip, revision }
+    }
+
+    /// Revision of the chip, if it is known
+    pub fn revision(&self) -> Option<Revision> {
+        self.revision
+    }
+
+    /// Why `feature` is avoided, if it is
+    pub fn avoided(&self, feature: Feature) -> Option<&'static str> {
+        self.quirks()
+            .find(|quirk| quirk.feature == feature)
+            .map(|quirk| quirk.reason)
+    }
+
+    /// Whether the flasher may use `feature`
+    pub fn allows(&self, feature: Feature) -> bool {
+        self.avoided(feature).is_none()
+    }
+
+    /// The avoided features, along with why
+    pub fn avoided_features(&self) -> impl Iterator<Item = (Feature, &'static str)> + '_ {
+        self.quirks().map(|quirk| (quirk.feature, quirk.reason))
+    }
+
+    fn quirks(&self) -> impl Iterator<Item = &'static Quirk> + '_ {
+        QUIRKS.iter().filter(move |quirk| {
+            quirk.chip == self.chip
+                && self
+                    .revision
+                    .is_some_and(|revision| quirk.revisions.contains(&revision))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn features_are_avoided_on_listed_revisions() {
+        let eco0 = Compatibility::new(Chip::Esp32, Some((0, 0)));
+        assert!(!eco0.allows(Feature::PipelinedWrites));
+        assert!(!eco0.allows(Feature::LargeBlocks));
+        assert_eq!(eco0.avoided_features().count(), 2);
+
+        assert!(Compatibility::new(Chip::Esp32, Some((3, 0))).allows(Feature::PipelinedWrites));
+        assert!(Compatibility::new(Chip::Esp32c3, Some((0, 0))).allows(Feature::LargeBlocks));
+        // Unknown revisions allow everything
+        assert!(Compatibility::new(Chip::Esp32, None).allows(Feature::PipelinedWrites));
+    }
+}
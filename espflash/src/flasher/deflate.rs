@@ -0,0 +1,265 @@
+//! Compression and block size of the data written to flash
+//!
+//! Data written to flash is compressed on the host and sent in blocks, which
+//! the device inflates as they arrive. Unless they are given explicitly, the
+//! compression level and block size are picked to suit the link: larger blocks
+//! save round trips on fast links when using the flasher stub, and the best
+//! compression only pays off while the link, rather than the host, is the
+//! bottleneck. The ROM loader always gets the block size it is known to accept.
+
+use std::{str::FromStr, time::Duration};
+
+use flate2::Compression;
+
+use crate::error::Error;
+
+/// Largest block accepted by the flasher stub
+pub const STUB_MAX_BLOCK_SIZE: usize = 0x4000;
+/// Smallest block size which can be set
+const MIN_BLOCK_SIZE: usize = 0x100;
+/// Longest time it should take to send an automatically sized block, which
+/// bounds the time lost when a block has to be sent again
+const MAX_BLOCK_TIME: Duration = Duration::from_millis(100);
+/// Baud rate above which compressing with the best level takes longer than
+/// sending the few bytes it saves
+const FAST_LINK_BAUD: u32 = 460_800;
+/// Compression level for native USB, which is fast enough for compressing to
+/// take about as long as sending the data
+const USB_COMPRESSION_LEVEL: u32 = 3;
+
+/// Compression level of the data written to flash
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CompressionLevel {
+    /// Pick the level to suit the link
+    #[default]
+    Auto,
+    /// Compress with the given level, from 0 (none) to 9 (best)
+    Level(u32),
+}
+
+impl FromStr for CompressionLevel {
+    type Err = Error;
+
+    /// Parse `auto`, or a level from 0 to 9
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(Self::Auto);
+        }
+
+        match s.trim().parse() {
+            Ok(level @ 0..=9) => Ok(Self::Level(level)),
+            _ => Err(Error::InvalidCompressionLevel(s.into())),
+        }
+    }
+}
+
+/// Size of the blocks the compressed data is sent in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BlockSize {
+    /// Pick the size to suit the link
+    #[default]
+    Auto,
+    /// Send blocks of the given number of bytes
+    Bytes(usize),
+}
+
+impl FromStr for BlockSize {
+    type Err = Error;
+
+    /// Parse `auto`, or the size in bytes, which may be given in hexadecimal
+    /// with a `0x` prefix
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(Self::Auto);
+        }
+
+        let s = s.trim();
+        let size = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => s.parse(),
+        };
+
+        match size {
+            Ok(size) if (MIN_BLOCK_SIZE..=STUB_MAX_BLOCK_SIZE).contains(&size) && size % 4 == 0 => {
+                Ok(Self::Bytes(size))
+            }
+            _ => Err(Error::InvalidBlockSize(s.into())),
+        }
+    }
+}
+
+/// The link data is written over, which the automatic settings depend on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Link {
+    /// Whether the flasher stub is running on the device
+    pub stub: bool,
+    /// Baud rate of the serial port, `None` for native USB where it has no
+    /// effect on the speed
+    pub baud: Option<u32>,
+    /// Block size of the chip's loader, which the ROM loader is limited to
+    pub default_block_size: usize,
+    /// Whether blocks larger than the default may be sent to the chip
+    pub large_blocks: bool,
+}
+
+impl CompressionLevel {
+    /// The level to compress with over `link`
+    pub(crate) fn resolve(self, link: &Link) -> Compression {
+        match self {
+            Self::Level(level) => Compression::new(level),
+            Self::Auto if !link.stub => Compression::best(),
+            Self::Auto => match link.baud {
+                Some(baud) if baud <= FAST_LINK_BAUD => Compression::best(),
+                Some(_) => Compression::default(),
+                None => Compression::new(USB_COMPRESSION_LEVEL),
+            },
+        }
+    }
+}
+
+impl BlockSize {
+    /// The size of the blocks to send over `link`
+    pub(crate) fn resolve(self, link: &Link) -> usize {
+        match self {
+            Self::Bytes(size) => size,
+            Self::Auto if !link.stub || !link.large_blocks => link.default_block_size,
+            Self::Auto => match link.baud {
+                // A serial byte takes 10 bits, including start and stop bits
+                Some(baud) => {
+                    let bytes = (baud as u128 / 10 * MAX_BLOCK_TIME.as_millis() / 1000) as usize;
+                    let bytes = match bytes.checked_next_power_of_two() {
+                        Some(size) if size > bytes => size / 2,
+                        _ => bytes,
+                    };
+
+                    bytes.clamp(link.default_block_size, STUB_MAX_BLOCK_SIZE)
+                }
+                None => STUB_MAX_BLOCK_SIZE,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, time::Instant};
+
+    use flate2::write::ZlibEncoder;
+
+    use super::*;
+
+    const DEFAULT_BLOCK_SIZE: usize = 0x400;
+
+    fn link(stub: bool, baud: Option<u32>) -> Link {
+        Link {
+            stub,
+            baud,
+            default_block_size: DEFAULT_BLOCK_SIZE,
+            large_blocks: true,
+        }
+    }
+
+    #[test]
+    fn settings_are_parsed() {
+        assert_eq!(
+            "auto".parse::<CompressionLevel>().unwrap(),
+            CompressionLevel::Auto
+        );
+        assert_eq!(
+            "6".parse::<CompressionLevel>().unwrap(),
+            CompressionLevel::Level(6)
+        );
+        assert!("10".parse::<CompressionLevel>().is_err());
+
+        assert_eq!("AUTO".parse::<BlockSize>().unwrap(), BlockSize::Auto);
+        assert_eq!(
+            "0x1000".parse::<BlockSize>().unwrap(),
+            BlockSize::Bytes(0x1000)
+        );
+        assert_eq!("2048".parse::<BlockSize>().unwrap(), BlockSize::Bytes(2048));
+        assert!("0x8000".parse::<BlockSize>().is_err());
+        assert!("1001".parse::<BlockSize>().is_err());
+    }
+
+    #[test]
+    fn automatic_settings_suit_the_link() {
+        let rom = link(false, Some(921_600));
+        assert_eq!(BlockSize::Auto.resolve(&rom), DEFAULT_BLOCK_SIZE);
+        assert_eq!(CompressionLevel::Auto.resolve(&rom), Compression::best());
+
+        let sizes = [
+            (115_200, 0x400),
+            (460_800, 0x1000),
+            (921_600, 0x2000),
+            (2_000_000, 0x4000),
+        ];
+        for (baud, size) in sizes {
+            assert_eq!(BlockSize::Auto.resolve(&link(true, Some(baud))), size);
+        }
+        assert_eq!(
+            BlockSize::Auto.resolve(&link(true, None)),
+            STUB_MAX_BLOCK_SIZE
+        );
+        let limited = Link {
+            large_blocks: false,
+            ..link(true, None)
+        };
+        assert_eq!(BlockSize::Auto.resolve(&limited), DEFAULT_BLOCK_SIZE);
+
+        let slow = link(true, Some(115_200));
+        assert_eq!(CompressionLevel::Auto.resolve(&slow), Compression::best());
+        let fast = link(true, Some(921_600));
+        assert_eq!(
+            CompressionLevel::Auto.resolve(&fast),
+            Compression::default()
+        );
+        let usb = link(true, None);
+        assert_eq!(CompressionLevel::Auto.resolve(&usb), Compression::new(3));
+
+        // Explicit settings are used as they are
+        assert_eq!(BlockSize::Bytes(0x800).resolve(&rom), 0x800);
+        assert_eq!(
+            CompressionLevel::Level(1).resolve(&usb),
+            Compression::fast()
+        );
+    }
+
+    /// Estimate the time to write an application with each compression level,
+    /// for links of several speeds
+    ///
+    /// Run with `cargo test --release -p espflash --lib benchmark -- --ignored
+    /// --nocapture`, as compressing is much slower in debug builds.
+    #[test]
+    #[ignore = "benchmark"]
+    fn benchmark_compression_levels() {
+        let data = std::fs::read("resources/apps/esp32").unwrap();
+        // Bytes per second of each link, roughly for USB-Serial-JTAG
+        let links = [
+            ("115200", 11_520.0),
+            ("921600", 92_160.0),
+            ("2000000", 200_000.0),
+            ("USB", 1_000_000.0),
+        ];
+
+        println!(
+            "level  compressed  compress  {}",
+            links.map(|l| l.0).join("  ")
+        );
+        for level in [1, 3, 6, 9] {
+            let start = Instant::now();
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level));
+            encoder.write_all(&data).unwrap();
+            let compressed = encoder.finish().unwrap().len();
+            let compress_time = start.elapsed().as_secs_f64();
+
+            let times =
+                links.map(|(_, rate)| format!("{:.2}s", compress_time + compressed as f64 / rate));
+            println!(
+                "{level:>5}  {compressed:>10}  {compress_time:>7.3}s  {}",
+                times.join("  ")
+            );
+        }
+    }
+}
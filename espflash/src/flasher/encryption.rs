@@ -0,0 +1,268 @@
+//! Flash encryption of the data written to a device
+//!
+//! Devices with flash encryption enabled either encrypt the data themselves as
+//! it is written, or are given data which was encrypted on the host with the
+//! key burned to their eFuses. Host-side encryption matches
+//! `espsecure.py encrypt_flash_data` for the chips using XTS-AES, and needs the
+//! `security` feature.
+
+#[cfg(feature = "security")]
+use std::{fmt, fs, path::Path};
+
+#[cfg(feature = "security")]
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128, Aes256,
+};
+#[cfg(feature = "security")]
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "security")]
+use crate::{error::Error, targets::Chip};
+
+/// Size of the data unit of XTS-AES flash encryption, which shares a tweak
+#[cfg(feature = "security")]
+const XTS_DATA_UNIT: usize = 0x80;
+/// Size of an AES block
+#[cfg(feature = "security")]
+const AES_BLOCK: usize = 16;
+
+/// How the data written to flash is encrypted
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum FlashEncryption {
+    /// The device encrypts the data with the key in its eFuses as it is written
+    Device,
+    /// The data is encrypted on the host, and written as it is
+    #[cfg(feature = "security")]
+    Host(FlashEncryptionKey),
+}
+
+/// XTS-AES flash encryption key, as burned to the eFuses of a device
+#[cfg(feature = "security")]
+#[derive(Clone)]
+pub struct FlashEncryptionKey {
+    /// Data key followed by the tweak key, of 16 or 32 bytes each
+    key: Vec<u8>,
+}
+
+#[cfg(feature = "security")]
+impl fmt::Debug for FlashEncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print the key itself
+        f.debug_struct("FlashEncryptionKey")
+            .field("bits", &(self.key.len() * 4))
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "security")]
+impl FlashEncryptionKey {
+    /// Use a raw key of 32 bytes for XTS-AES-128 or 64 bytes for XTS-AES-256
+    ///
+    /// A key of 16 bytes is extended with SHA-256, as done by the ESP32-C2 for
+    /// keys derived from 128 eFuse bits.
+    pub fn new(key: &[u8]) -> Result<Self, Error> {
+        let key = match key.len() {
+            16 => Sha256::digest(key).to_vec(),
+            32 | 64 => key.to_vec(),
+            len => {
+                return Err(Error::InvalidEncryptionKey(format!(
+                    "the key is {len} bytes long, instead of 16, 32 or 64 bytes"
+                )))
+            }
+        };
+
+        Ok(Self { key })
+    }
+
+    /// Load a raw key from a binary file, as generated by
+    /// `espsecure.py generate_flash_encryption_key`
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let key =
+            fs::read(path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+        Self::new(&key)
+    }
+
+    /// Encrypt `data` to be written to `address` in the flash of `chip`
+    ///
+    /// The data is padded with `0xFF` to a multiple of the AES block size.
+    pub fn encrypt(&self, chip: Chip, address: u32, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if chip == Chip::Esp32 {
+            return Err(Error::UnsupportedFeature {
+                chip,
+                feature: "host-side flash encryption, let the device encrypt the data".into(),
+            });
+        }
+        if address as usize % AES_BLOCK != 0 {
+            return Err(Error::MisalignedEncryptedData {
+                address,
+                alignment: AES_BLOCK,
+            });
+        }
+
+        let (data_key, tweak_key) = self.key.split_at(self.key.len() / 2);
+        match data_key.len() {
+            16 => Ok(encrypt_xts::<Aes128>(data_key, tweak_key, address, data)),
+            _ => Ok(encrypt_xts::<Aes256>(data_key, tweak_key, address, data)),
+        }
+    }
+}
+
+/// Encrypt `data` at `address` with XTS-AES, in the byte order of the flash
+/// encryption hardware
+#[cfg(feature = "security")]
+fn encrypt_xts<C: BlockEncrypt + KeyInit>(
+    data_key: &[u8],
+    tweak_key: &[u8],
+    address: u32,
+    data: &[u8],
+) -> Vec<u8> {
+    let data_cipher = C::new_from_slice(data_key).unwrap();
+    let tweak_cipher = C::new_from_slice(tweak_key).unwrap();
+
+    // Data units are aligned to their size, so pad the data on both sides
+    let pad_left = address as usize % XTS_DATA_UNIT;
+    let len = data.len().next_multiple_of(AES_BLOCK);
+    let mut buffer = vec![0; pad_left];
+    buffer.extend_from_slice(data);
+    buffer.resize(pad_left + len, 0xff);
+    buffer.resize(buffer.len().next_multiple_of(XTS_DATA_UNIT), 0);
+
+    let first_unit = address as usize - pad_left;
+    for (i, unit) in buffer.chunks_exact_mut(XTS_DATA_UNIT).enumerate() {
+        let unit_address = (first_unit + i * XTS_DATA_UNIT) as u32;
+        let mut tweak = GenericArray::default();
+        tweak[..4].copy_from_slice(&unit_address.to_le_bytes());
+        tweak_cipher.encrypt_block(&mut tweak);
+
+        // The hardware processes the data unit in reverse byte order
+        unit.reverse();
+        for block in unit.chunks_exact_mut(AES_BLOCK) {
+            let block = GenericArray::from_mut_slice(block);
+            xor(block, &tweak);
+            data_cipher.encrypt_block(block);
+            xor(block, &tweak);
+            multiply_by_alpha(&mut tweak);
+        }
+        unit.reverse();
+    }
+
+    buffer.drain(..pad_left);
+    buffer.truncate(len);
+
+    buffer
+}
+
+/// Encrypt a data unit in place with standard XTS-AES, whose tweak is `sector`
+/// in little-endian byte order, as done by NVS encryption
+///
+/// The unit must be a multiple of the AES block size long.
+#[cfg(feature = "security")]
+pub(crate) fn encrypt_xts_unit<C: BlockEncrypt + KeyInit>(
+    data_key: &[u8],
+    tweak_key: &[u8],
+    sector: u32,
+    unit: &mut [u8],
+) {
+    let data_cipher = C::new_from_slice(data_key).unwrap();
+    let tweak_cipher = C::new_from_slice(tweak_key).unwrap();
+
+    let mut tweak = GenericArray::default();
+    tweak[..4].copy_from_slice(&sector.to_le_bytes());
+    tweak_cipher.encrypt_block(&mut tweak);
+
+    for block in unit.chunks_exact_mut(AES_BLOCK) {
+        let block = GenericArray::from_mut_slice(block);
+        xor(block, &tweak);
+        data_cipher.encrypt_block(block);
+        xor(block, &tweak);
+        multiply_by_alpha(&mut tweak);
+    }
+}
+
+#[cfg(feature = "security")]
+fn xor(block: &mut [u8], tweak: &[u8]) {
+    for (b, t) in block.iter_mut().zip(tweak) {
+        *b ^= t;
+    }
+}
+
+/// Multiply the tweak by the primitive element of GF(2^128)
+#[cfg(feature = "security")]
+fn multiply_by_alpha(tweak: &mut [u8]) {
+    let carry = tweak[AES_BLOCK - 1] >> 7;
+    for i in (1..AES_BLOCK).rev() {
+        tweak[i] = (tweak[i] << 1) | (tweak[i - 1] >> 7);
+    }
+    tweak[0] = (tweak[0] << 1) ^ (0x87 * carry);
+}
+
+#[cfg(all(test, feature = "security"))]
+mod tests {
+    use super::*;
+
+    /// SHA-256 of the encrypted data, to compare with `espsecure.py`
+    fn encrypted_digest(key: &[u8], address: u32, data: &[u8]) -> String {
+        let key = FlashEncryptionKey::new(key).unwrap();
+        let encrypted = key.encrypt(Chip::Esp32c3, address, data).unwrap();
+        assert_eq!(encrypted.len(), data.len());
+
+        Sha256::digest(encrypted)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    #[test]
+    fn encryption_matches_espsecure() {
+        let data: Vec<u8> = (0..256u32).map(|i| (i * 7) as u8).collect();
+        let key: Vec<u8> = (0..64).collect();
+
+        assert_eq!(
+            encrypted_digest(&key[..32], 0x1000, &data),
+            "54eb461fadc39d372a593be3a0deaa8fc79600535b84b6f8cd1c07629d591dd4"
+        );
+        assert_eq!(
+            encrypted_digest(&key, 0x1000, &data),
+            "0254592b5b9ebb66614417ab66128255c5ed403aae24bf72cf032bf79dee997d"
+        );
+        // Data which does not start at a data unit
+        assert_eq!(
+            encrypted_digest(&key[..32], 0x1010, &data[..32]),
+            "f061e5b57265eb40d1d0b5a29482c05e03a1940dfffae08836f95ee24f355a3c"
+        );
+    }
+
+    #[test]
+    fn short_keys_are_extended() {
+        let key: Vec<u8> = (0..16).collect();
+        assert_eq!(
+            FlashEncryptionKey::new(&key).unwrap().key,
+            FlashEncryptionKey::new(&Sha256::digest(&key)).unwrap().key
+        );
+        assert!(FlashEncryptionKey::new(&[0; 24]).is_err());
+    }
+
+    #[test]
+    fn misaligned_addresses_are_rejected() {
+        let key = FlashEncryptionKey::new(&[1; 32]).unwrap();
+        assert!(matches!(
+            key.encrypt(Chip::Esp32c3, 0x1008, &[0; 16]),
+            Err(Error::MisalignedEncryptedData {
+                address: 0x1008,
+                alignment: 16
+            })
+        ));
+    }
+
+    #[test]
+    fn esp32_is_not_supported() {
+        let key = FlashEncryptionKey::new(&[1; 32]).unwrap();
+        assert!(matches!(
+            key.encrypt(Chip::Esp32, 0x1000, &[0; 16]),
+            Err(Error::UnsupportedFeature { .. })
+        ));
+    }
+}
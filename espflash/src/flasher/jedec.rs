@@ -0,0 +1,227 @@
+//! Identification of flash chips by their JEDEC ID
+//!
+//! The JEDEC ID read with the `0x9F` command consists of the manufacturer ID,
+//! followed by a device ID of the memory type and the capacity. The capacity is
+//! all that is needed to detect the flash size; the vendor and model are looked
+//! up in a table of the chips commonly found on modules and boards, so that the
+//! bill of materials can be checked. Several vendors share the manufacturer
+//! ID `0x20`, which is therefore resolved by the device ID.
+
+use std::fmt;
+
+/// Vendors, by their JEDEC manufacturer ID
+const VENDORS: &[(u8, &str)] = &[
+    (0x0b, "XTX"),
+    (0x1c, "EON"),
+    (0x1f, "Adesto"),
+    (0x20, "XMC"),
+    (0x5e, "Zbit"),
+    (0x68, "Boya"),
+    (0x85, "Puya"),
+    (0x9d, "ISSI"),
+    (0xa1, "Fudan"),
+    (0xc2, "Macronix"),
+    (0xc8, "GigaDevice"),
+    (0xef, "Winbond"),
+];
+
+/// A flash chip model, identified by its manufacturer and device IDs
+struct Part {
+    manufacturer_id: u8,
+    device_id: u16,
+    /// Vendor of the part, when it differs from the vendor of the
+    /// manufacturer ID
+    vendor: Option<&'static str>,
+    model: &'static str,
+}
+
+const fn part(manufacturer_id: u8, device_id: u16, model: &'static str) -> Part {
+    Part {
+        manufacturer_id,
+        device_id,
+        vendor: None,
+        model,
+    }
+}
+
+const PARTS: &[Part] = &[
+    // GigaDevice
+    part(0xc8, 0x4014, "GD25Q80"),
+    part(0xc8, 0x4015, "GD25Q16"),
+    part(0xc8, 0x4016, "GD25Q32"),
+    part(0xc8, 0x4017, "GD25Q64"),
+    part(0xc8, 0x4018, "GD25Q128"),
+    part(0xc8, 0x4019, "GD25Q256"),
+    part(0xc8, 0x6016, "GD25LQ32"),
+    part(0xc8, 0x6017, "GD25LQ64"),
+    part(0xc8, 0x6018, "GD25LQ128"),
+    // Winbond
+    part(0xef, 0x4014, "W25Q80"),
+    part(0xef, 0x4015, "W25Q16"),
+    part(0xef, 0x4016, "W25Q32"),
+    part(0xef, 0x4017, "W25Q64"),
+    part(0xef, 0x4018, "W25Q128"),
+    part(0xef, 0x4019, "W25Q256"),
+    part(0xef, 0x6016, "W25Q32FW"),
+    part(0xef, 0x6017, "W25Q64FW"),
+    part(0xef, 0x6018, "W25Q128FW"),
+    part(0xef, 0x7018, "W25Q128JV-M"),
+    // XMC
+    part(0x20, 0x4016, "XM25QH32B"),
+    part(0x20, 0x4017, "XM25QH64C"),
+    part(0x20, 0x4018, "XM25QH128C"),
+    part(0x20, 0x3816, "XM25QU32C"),
+    part(0x20, 0x3817, "XM25QU64C"),
+    part(0x20, 0x3818, "XM25QU128C"),
+    // Micron, which shares the manufacturer ID with XMC
+    Part {
+        vendor: Some("Micron"),
+        ..part(0x20, 0xba18, "N25Q128A")
+    },
+    Part {
+        vendor: Some("Micron"),
+        ..part(0x20, 0xba19, "MT25QL256A")
+    },
+    // Macronix
+    part(0xc2, 0x2015, "MX25L1606E"),
+    part(0xc2, 0x2016, "MX25L3233F"),
+    part(0xc2, 0x2017, "MX25L6433F"),
+    part(0xc2, 0x2018, "MX25L12835F"),
+    part(0xc2, 0x2019, "MX25L25645G"),
+    part(0xc2, 0x2536, "MX25U3232F"),
+    part(0xc2, 0x2537, "MX25U6432F"),
+    part(0xc2, 0x2538, "MX25U12832F"),
+    // Boya
+    part(0x68, 0x4016, "BY25Q32"),
+    part(0x68, 0x4017, "BY25Q64"),
+    part(0x68, 0x4018, "BY25Q128"),
+    // ISSI
+    part(0x9d, 0x6016, "IS25LP032"),
+    part(0x9d, 0x6017, "IS25LP064"),
+    part(0x9d, 0x6018, "IS25LP128"),
+    part(0x9d, 0x7016, "IS25WP032"),
+    part(0x9d, 0x7017, "IS25WP064"),
+    // Others
+    part(0x0b, 0x4016, "XT25F32B"),
+    part(0x0b, 0x4017, "XT25F64B"),
+    part(0x1c, 0x3016, "EN25Q32B"),
+    part(0x1c, 0x7017, "EN25QH64A"),
+    part(0x1c, 0x7018, "EN25QH128A"),
+    part(0x5e, 0x4016, "ZB25VQ32"),
+    part(0x5e, 0x4017, "ZB25VQ64"),
+    part(0x85, 0x6016, "P25Q32H"),
+    part(0xa1, 0x4016, "FM25Q32"),
+];
+
+/// A flash chip, as identified by its JEDEC ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FlashChipInfo {
+    /// JEDEC manufacturer ID
+    pub manufacturer_id: u8,
+    /// Device ID, the memory type followed by the capacity
+    pub device_id: u16,
+    /// Vendor of the chip, if it is known
+    pub vendor: Option<&'static str>,
+    /// Model of the chip, if it is known
+    pub model: Option<&'static str>,
+}
+
+impl FlashChipInfo {
+    /// Identify a flash chip by the response to the `0x9F` command, with the
+    /// manufacturer ID in the least significant byte
+    pub fn from_jedec_id(id: u32) -> Self {
+        let [manufacturer_id, memory_type, capacity, _] = id.to_le_bytes();
+        let device_id = u16::from_be_bytes([memory_type, capacity]);
+
+        let part = PARTS
+            .iter()
+            .find(|part| part.manufacturer_id == manufacturer_id && part.device_id == device_id);
+        let vendor = part.and_then(|part| part.vendor).or_else(|| {
+            VENDORS
+                .iter()
+                .find(|(id, _)| *id == manufacturer_id)
+                .map(|(_, vendor)| *vendor)
+        });
+
+        Self {
+            manufacturer_id,
+            device_id,
+            vendor,
+            model: part.map(|part| part.model),
+        }
+    }
+
+    /// Whether the flash chip responded to the command at all
+    ///
+    /// A chip which does not drive its data line, e.g. because it is not
+    /// supplied with the right voltage, reads as all ones, or as all zeros when
+    /// the line is pulled low.
+    pub fn responded(&self) -> bool {
+        !matches!(
+            (self.manufacturer_id, self.device_id),
+            (0x00, 0x0000) | (0xff, 0xffff)
+        )
+    }
+
+    /// The JEDEC ID, as it is usually written, e.g. `c84016`
+    pub fn jedec_id(&self) -> String {
+        format!("{:02x}{:04x}", self.manufacturer_id, self.device_id)
+    }
+
+    /// Size of the flash in bytes, as encoded in the capacity byte of the
+    /// device ID, if it is plausible
+    pub fn size(&self) -> Option<u64> {
+        let capacity = self.device_id as u8;
+        (0x10..=0x22).contains(&capacity).then(|| 1 << capacity)
+    }
+}
+
+impl fmt::Display for FlashChipInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.vendor, self.model) {
+            (Some(vendor), Some(model)) => write!(f, "{vendor} {model}")?,
+            (Some(vendor), None) => write!(f, "{vendor} (unknown model)")?,
+            _ => f.write_str("Unknown")?,
+        }
+
+        write!(f, " (JEDEC ID {})", self.jedec_id())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chips_are_identified() {
+        let gd25q32 = FlashChipInfo::from_jedec_id(0x1640c8);
+        assert_eq!(gd25q32.vendor, Some("GigaDevice"));
+        assert_eq!(gd25q32.model, Some("GD25Q32"));
+        assert_eq!(gd25q32.size(), Some(4 * 1024 * 1024));
+        assert_eq!(gd25q32.to_string(), "GigaDevice GD25Q32 (JEDEC ID c84016)");
+
+        // The manufacturer ID of XMC and Micron is resolved by the device ID
+        assert_eq!(FlashChipInfo::from_jedec_id(0x184020).vendor, Some("XMC"));
+        assert_eq!(
+            FlashChipInfo::from_jedec_id(0x18ba20).vendor,
+            Some("Micron")
+        );
+
+        let unknown_model = FlashChipInfo::from_jedec_id(0x1799ef);
+        assert_eq!(unknown_model.model, None);
+        assert_eq!(
+            unknown_model.to_string(),
+            "Winbond (unknown model) (JEDEC ID ef9917)"
+        );
+        assert_eq!(
+            FlashChipInfo::from_jedec_id(0xffffff).to_string(),
+            "Unknown (JEDEC ID ffffff)"
+        );
+        assert_eq!(FlashChipInfo::from_jedec_id(0xffffff).size(), None);
+
+        assert!(gd25q32.responded());
+        assert!(!FlashChipInfo::from_jedec_id(0xffffff).responded());
+        assert!(!FlashChipInfo::from_jedec_id(0).responded());
+    }
+}
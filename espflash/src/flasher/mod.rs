@@ -7,7 +7,14 @@
 use std::{fs, path::Path, str::FromStr};
 
 #[cfg(feature = "serialport")]
-use std::{borrow::Cow, io::Write, path::PathBuf, thread::sleep, time::Duration};
+use std::{
+    borrow::Cow,
+    io::Write,
+    path::PathBuf,
+    sync::Arc,
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
 use esp_idf_part::PartitionTable;
 
@@ -21,9 +28,16 @@ use serialport::UsbPortInfo;
 use strum::IntoEnumIterator;
 use strum::{Display, EnumIter, VariantNames};
 
+#[cfg(feature = "serialport")]
+use self::{capabilities::Capabilities, compat::Compatibility, encryption::FlashEncryption};
+use self::{jedec::FlashChipInfo, sfdp::BasicFlashParameters};
+#[cfg(feature = "security")]
+use crate::image_format::signing::SigningKey;
 use crate::{
+    elf::SegmentFilter,
     error::Error,
-    targets::{Chip, XtalFrequency},
+    image_format::ImageFormatKind,
+    targets::{Chip, XtalFrequency, BROWNOUT_RESET_REASON},
 };
 
 #[cfg(feature = "serialport")]
@@ -31,23 +45,43 @@ use crate::{
     command::{Command, CommandType},
     connection::{
         reset::{ResetAfterOperation, ResetBeforeOperation},
-        Connection, Port,
+        ConnectStrategy, Connection, Port, StubSettle,
     },
+    digest::DigestAlgorithm,
     elf::{ElfFirmwareImage, FirmwareImage, RomSegment},
-    error::{ConnectionError, ResultExt},
+    error::{ConnectionError, ResultExt, TimedOutCommand},
+    flasher::sfdp::Sfdp,
     flasher::stubs::{
         FlashStub, CHIP_DETECT_MAGIC_REG_ADDR, DEFAULT_TIMEOUT, EXPECTED_STUB_HANDSHAKE,
     },
+    image_format::read_image_len,
+    output::{self, partial_path, OutputFile},
+    targets::{
+        flash_target::{FlashTarget, RamTarget, RateTracker},
+        SpiRegisters, Target,
+    },
 };
 
 #[cfg(feature = "serialport")]
-pub use crate::targets::flash_target::ProgressCallbacks;
+pub use crate::targets::flash_target::{ProgressCallbacks, SegmentProgress, TransferRate};
 
 #[cfg(feature = "serialport")]
 pub(crate) use stubs::{FLASH_SECTOR_SIZE, FLASH_WRITE_SIZE};
 
 #[cfg(feature = "serialport")]
-pub(crate) mod stubs;
+pub mod capabilities;
+#[cfg(feature = "serialport")]
+pub mod compat;
+#[cfg(feature = "serialport")]
+pub mod deflate;
+pub mod encryption;
+pub mod jedec;
+#[cfg(feature = "security")]
+pub mod nvs;
+pub mod ota;
+pub mod sfdp;
+#[cfg(feature = "serialport")]
+pub mod stubs;
 
 /// Supported flash frequencies
 ///
@@ -266,6 +300,7 @@ impl FromStr for FlashSize {
 
 /// Flash settings to use when flashing a device
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 #[non_exhaustive]
 pub struct FlashSettings {
     pub mode: Option<FlashMode>,
@@ -293,43 +328,42 @@ impl FlashSettings {
 }
 
 /// Builder interface to create [`FlashData`] objects.
-pub struct FlashDataBuilder<'a> {
-    bootloader_path: Option<&'a Path>,
-    partition_table_path: Option<&'a Path>,
+#[derive(Default)]
+pub struct FlashDataBuilder {
+    bootloader: Option<Vec<u8>>,
+    partition_table: Option<PartitionTable>,
     partition_table_offset: Option<u32>,
+    bootloader_offset: Option<u32>,
+    app_offset: Option<u32>,
     target_app_partition: Option<String>,
     flash_settings: FlashSettings,
     min_chip_rev: u16,
+    mmu_page_size: Option<u32>,
+    extra_app_partitions: ExtraAppPartitions,
+    app_only: bool,
+    segment_filter: SegmentFilter,
+    #[cfg(feature = "security")]
+    signing_key: Option<SigningKey>,
+    force_bootloader: bool,
+    image_format: ImageFormatKind,
 }
 
-impl Default for FlashDataBuilder<'_> {
-    fn default() -> Self {
-        Self {
-            bootloader_path: Default::default(),
-            partition_table_path: Default::default(),
-            partition_table_offset: Default::default(),
-            target_app_partition: Default::default(),
-            flash_settings: FlashSettings::default(),
-            min_chip_rev: Default::default(),
-        }
-    }
-}
-
-impl<'a> FlashDataBuilder<'a> {
+impl FlashDataBuilder {
     /// Creates a new [`FlashDataBuilder`] object.
     pub fn new() -> Self {
         Self::default()
     }
 
-    /// Sets the bootloader path.
-    pub fn with_bootloader(mut self, bootloader_path: &'a Path) -> Self {
-        self.bootloader_path = Some(bootloader_path);
+    /// Sets the bootloader image.
+    pub fn with_bootloader(mut self, bootloader: Vec<u8>) -> Self {
+        self.bootloader = Some(bootloader);
         self
     }
 
-    /// Sets the partition table path.
-    pub fn with_partition_table(mut self, partition_table_path: &'a Path) -> Self {
-        self.partition_table_path = Some(partition_table_path);
+    /// Sets the partition table, see [parse_partition_table] to load it from
+    /// a file.
+    pub fn with_partition_table(mut self, partition_table: PartitionTable) -> Self {
+        self.partition_table = Some(partition_table);
         self
     }
 
@@ -339,6 +373,20 @@ impl<'a> FlashDataBuilder<'a> {
         self
     }
 
+    /// Sets the offset to write the bootloader at, instead of the default of
+    /// the chip.
+    pub fn with_bootloader_offset(mut self, bootloader_offset: u32) -> Self {
+        self.bootloader_offset = Some(bootloader_offset);
+        self
+    }
+
+    /// Sets the offset to write the application image at, instead of the
+    /// offset of the target app partition.
+    pub fn with_app_offset(mut self, app_offset: u32) -> Self {
+        self.app_offset = Some(app_offset);
+        self
+    }
+
     /// Sets the label of the target app partition.
     pub fn with_target_app_partition(mut self, target_app_partition: String) -> Self {
         self.target_app_partition = Some(target_app_partition);
@@ -357,19 +405,94 @@ impl<'a> FlashDataBuilder<'a> {
         self
     }
 
+    /// Sets the MMU page size to align the application image to.
+    pub fn with_mmu_page_size(mut self, mmu_page_size: u32) -> Self {
+        self.mmu_page_size = Some(mmu_page_size);
+        self
+    }
+
+    /// Sets the app partitions to write a copy of the application image to.
+    pub fn with_extra_app_partitions(mut self, extra_app_partitions: ExtraAppPartitions) -> Self {
+        self.extra_app_partitions = extra_app_partitions;
+        self
+    }
+
+    /// Sets whether only the application image is written.
+    pub fn with_app_only(mut self, app_only: bool) -> Self {
+        self.app_only = app_only;
+        self
+    }
+
+    /// Sets which segments of an ELF file are written.
+    pub fn with_segment_filter(mut self, segment_filter: SegmentFilter) -> Self {
+        self.segment_filter = segment_filter;
+        self
+    }
+
+    /// Sets the key to sign the application image with for Secure Boot V2.
+    #[cfg(feature = "security")]
+    pub fn with_signing_key(mut self, signing_key: SigningKey) -> Self {
+        self.signing_key = Some(signing_key);
+        self
+    }
+
+    /// Sets whether the bootloader is written even if it was built for a
+    /// different chip or flash mode.
+    pub fn with_force_bootloader(mut self, force_bootloader: bool) -> Self {
+        self.force_bootloader = force_bootloader;
+        self
+    }
+
+    /// Sets the format of the image, with or without a bootloader.
+    pub fn with_image_format(mut self, image_format: ImageFormatKind) -> Self {
+        self.image_format = image_format;
+        self
+    }
+
     /// Builds a [`FlashData`] object.
-    pub fn build(self) -> Result<FlashData, Error> {
-        FlashData::new(
-            self.bootloader_path,
-            self.partition_table_path,
+    pub fn build(self) -> FlashData {
+        let mut flash_data = FlashData::new(
+            self.bootloader,
+            self.partition_table,
             self.partition_table_offset,
             self.target_app_partition,
             self.flash_settings,
             self.min_chip_rev,
-        )
+            self.mmu_page_size,
+        );
+        flash_data.bootloader_offset = self.bootloader_offset;
+        flash_data.app_offset = self.app_offset;
+        flash_data.extra_app_partitions = self.extra_app_partitions;
+        flash_data.app_only = self.app_only;
+        flash_data.segment_filter = self.segment_filter;
+        #[cfg(feature = "security")]
+        {
+            flash_data.signing_key = self.signing_key;
+        }
+        flash_data.force_bootloader = self.force_bootloader;
+        flash_data.image_format = self.image_format;
+
+        flash_data
     }
 }
 
+/// App partitions to write a copy of the application image to, in addition to
+/// the target app partition
+///
+/// Writing the application to e.g. both the `factory` and `ota_0` partitions
+/// provides a fallback image before the first over-the-air update.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ExtraAppPartitions {
+    /// Only write the target app partition
+    #[default]
+    None,
+    /// The app partitions with the given labels
+    Labels(Vec<String>),
+    /// All app partitions in the partition table
+    All,
+}
+
 /// Flash data and configuration
 #[derive(Debug, Clone)]
 #[non_exhaustive]
@@ -377,47 +500,62 @@ pub struct FlashData {
     pub bootloader: Option<Vec<u8>>,
     pub partition_table: Option<PartitionTable>,
     pub partition_table_offset: Option<u32>,
+    /// Offset to write the bootloader at, the default of the chip if `None`
+    pub bootloader_offset: Option<u32>,
+    /// Offset to write the application image at, instead of the offset of the
+    /// target app partition
+    pub app_offset: Option<u32>,
     pub target_app_partition: Option<String>,
     pub flash_settings: FlashSettings,
     pub min_chip_rev: u16,
+    pub mmu_page_size: Option<u32>,
+    pub extra_app_partitions: ExtraAppPartitions,
+    /// Only write the application image, leaving the bootloader and partition
+    /// table on the device untouched
+    pub app_only: bool,
+    /// Segments of an ELF file which are written, all of them by default
+    pub segment_filter: SegmentFilter,
+    /// Key to sign the application image with for Secure Boot V2
+    #[cfg(feature = "security")]
+    pub signing_key: Option<SigningKey>,
+    /// Write the bootloader even if its image header shows that it was built
+    /// for a different chip or flash mode
+    pub force_bootloader: bool,
+    /// Format of the image, which only includes the bootloader and partition
+    /// table for [ImageFormatKind::EspIdf]
+    pub image_format: ImageFormatKind,
 }
 
 impl FlashData {
+    /// Flash data with the given bootloader image and partition table, or the
+    /// defaults of the chip where they are `None`
     pub fn new(
-        bootloader: Option<&Path>,
-        partition_table: Option<&Path>,
+        bootloader: Option<Vec<u8>>,
+        partition_table: Option<PartitionTable>,
         partition_table_offset: Option<u32>,
         target_app_partition: Option<String>,
         flash_settings: FlashSettings,
         min_chip_rev: u16,
-    ) -> Result<Self, Error> {
-        // If the '--bootloader' option is provided, load the binary file at the
-        // specified path.
-        let bootloader = if let Some(path) = bootloader {
-            let data = fs::canonicalize(path)
-                .and_then(fs::read)
-                .map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
-
-            Some(data)
-        } else {
-            None
-        };
-
-        // If the '-T' option is provided, load the partition table from
-        // the CSV or binary file at the specified path.
-        let partition_table = match partition_table {
-            Some(path) => Some(parse_partition_table(path)?),
-            None => None,
-        };
-
-        Ok(FlashData {
+        mmu_page_size: Option<u32>,
+    ) -> Self {
+        FlashData {
             bootloader,
             partition_table,
             partition_table_offset,
+            bootloader_offset: None,
+            app_offset: None,
             target_app_partition,
             flash_settings,
             min_chip_rev,
-        })
+            mmu_page_size,
+            extra_app_partitions: ExtraAppPartitions::None,
+            app_only: false,
+            segment_filter: SegmentFilter::All,
+            #[cfg(feature = "security")]
+            signing_key: None,
+            force_bootloader: false,
+            image_format: ImageFormatKind::EspIdf,
+        }
     }
 }
 
@@ -453,6 +591,22 @@ impl SpiSetParams {
         }
     }
 
+    /// Use the block, sector and page sizes the flash chip reports in its
+    /// Basic Flash Parameter Table, where it specifies them
+    pub fn with_sfdp(mut self, basic: &BasicFlashParameters) -> Self {
+        if let Some(block) = basic.block_erase() {
+            self.block_size = block.size;
+        }
+        if let Some(sector) = basic.sector_erase() {
+            self.sector_size = sector.size;
+        }
+        if let Some(page_size) = basic.page_size {
+            self.page_size = page_size;
+        }
+
+        self
+    }
+
     /// Encode the parameters into a byte array
     pub fn encode(&self) -> Vec<u8> {
         let mut encoded: Vec<u8> = Vec::new();
@@ -467,7 +621,7 @@ impl SpiSetParams {
 }
 
 /// Parameters for attaching to a target devices SPI flash
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(C)]
 pub struct SpiAttachParams {
     clk: u8,
@@ -488,6 +642,22 @@ impl SpiAttachParams {
         }
     }
 
+    /// Flash connected to the HSPI pins, as selected by the ROM loader
+    pub const fn hspi() -> Self {
+        SpiAttachParams {
+            clk: 1,
+            q: 0,
+            d: 0,
+            hd: 0,
+            cs: 0,
+        }
+    }
+
+    /// Flash connected to custom GPIOs
+    pub const fn pins(clk: u8, q: u8, d: u8, hd: u8, cs: u8) -> Self {
+        SpiAttachParams { clk, q, d, hd, cs }
+    }
+
     // Default SPI parameters for ESP32-PICO-D4
     pub const fn esp32_pico_d4() -> Self {
         SpiAttachParams {
@@ -517,6 +687,29 @@ impl SpiAttachParams {
     }
 }
 
+impl FromStr for SpiAttachParams {
+    type Err = Error;
+
+    /// Parse `SPI`, `HSPI` or a list of GPIOs in the order `CLK,Q,D,HD,CS`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "SPI" => return Ok(Self::default()),
+            "HSPI" => return Ok(Self::hspi()),
+            _ => {}
+        }
+
+        let pins = s
+            .split(',')
+            .map(|pin| pin.trim().parse::<u8>().ok().filter(|pin| *pin < 64))
+            .collect::<Option<Vec<_>>>();
+
+        match pins.as_deref() {
+            Some(&[clk, q, d, hd, cs]) => Ok(Self::pins(clk, q, d, hd, cs)),
+            _ => Err(Error::InvalidSpiConnection(s.into())),
+        }
+    }
+}
+
 /// Information about the connected device
 #[derive(Debug, Clone)]
 pub struct DeviceInfo {
@@ -528,12 +721,72 @@ pub struct DeviceInfo {
     pub crystal_frequency: XtalFrequency,
     /// The total available flash size
     pub flash_size: FlashSize,
+    /// Whether the flash is embedded in the chip package, if this can be
+    /// determined from the eFuses
+    pub embedded_flash: Option<bool>,
+    /// The flash chip, if it could be identified
+    pub flash_chip: Option<FlashChipInfo>,
     /// Device features
     pub features: Vec<String>,
     /// MAC address
     pub mac_address: String,
 }
 
+/// Results of the checks of the device before flashing, see
+/// [Flasher::preflight]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PreflightReport {
+    /// Reason of the last reset, if the chip reports it
+    pub reset_reason: Option<u32>,
+    /// Whether the supply voltage is below the threshold of the brownout
+    /// detector, if the chip reports it
+    pub brownout_detected: Option<bool>,
+}
+
+impl PreflightReport {
+    /// Did the device last reset because its supply voltage dropped?
+    ///
+    /// Returns `None` when the chip does not report the reset reason.
+    pub fn brownout_reset(&self) -> Option<bool> {
+        self.reset_reason
+            .map(|reason| reason == BROWNOUT_RESET_REASON)
+    }
+
+    /// Problems which make flashing likely to fail, as messages for the user
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.brownout_reset() == Some(true) {
+            problems.push(
+                "The device was reset by its brownout detector, its power supply is unstable"
+                    .to_string(),
+            );
+        }
+        if self.brownout_detected == Some(true) {
+            problems.push(
+                "The supply voltage of the device is below the threshold of its brownout detector"
+                    .to_string(),
+            );
+        }
+
+        problems
+    }
+
+    /// Checks which could not be run as the chip does not report their
+    /// status, as messages for the user
+    pub fn unknown(&self) -> Vec<&'static str> {
+        let mut unknown = Vec::new();
+        if self.reset_reason.is_none() {
+            unknown.push("reset reason");
+        }
+        if self.brownout_detected.is_none() {
+            unknown.push("brownout detector");
+        }
+
+        unknown
+    }
+}
+
 /// Parse a [PartitionTable] from the provided path
 pub fn parse_partition_table(path: &Path) -> Result<PartitionTable, Error> {
     let data = fs::read(path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
@@ -541,11 +794,274 @@ pub fn parse_partition_table(path: &Path) -> Result<PartitionTable, Error> {
     Ok(PartitionTable::try_from(data)?)
 }
 
+/// Maximum size of a partition table, including its MD5 digest
+pub(crate) const MAX_PARTITION_TABLE_SIZE: u32 = 0xC00;
+
+#[cfg(feature = "serialport")]
+/// Offsets of the partition table commonly configured in ESP-IDF
+pub(crate) const PARTITION_TABLE_OFFSETS: [u32; 3] = [0x8000, 0x9000, 0xD000];
+
+#[cfg(feature = "serialport")]
+/// Magic bytes at the start of every partition table entry
+pub(crate) const PARTITION_ENTRY_MAGIC: [u8; 2] = [0xAA, 0x50];
+
 #[cfg(feature = "serialport")]
 /// List of SPI parameters to try while detecting flash size
 pub(crate) const TRY_SPI_PARAMS: [SpiAttachParams; 2] =
     [SpiAttachParams::default(), SpiAttachParams::esp32_pico_d4()];
 
+#[cfg(feature = "serialport")]
+/// Size of the chunks flash is read in when saving it to a file
+const READ_CHUNK_SIZE: u32 = 0x4_0000;
+
+#[cfg(feature = "serialport")]
+/// Size of the regions flash is erased in when reporting progress, each
+/// taking around a second to erase
+const ERASE_CHUNK_SIZE: u32 = 0x4_0000;
+
+#[cfg(feature = "serialport")]
+/// Size of the region which is read back to find the first differing byte
+/// when verifying flash
+const VERIFY_READ_SIZE: usize = 0x100;
+
+#[cfg(feature = "serialport")]
+/// Maximum number of bytes sent with an SPI flash command
+const SPI_COMMAND_MAX_DATA: usize = 64;
+
+#[cfg(feature = "serialport")]
+/// SPI flash commands reading status registers 1 to 3
+const SPI_READ_STATUS: [u8; 3] = [0x05, 0x35, 0x15];
+
+#[cfg(feature = "serialport")]
+/// SPI flash commands writing status registers 1 to 3
+const SPI_WRITE_STATUS: [u8; 3] = [0x01, 0x31, 0x11];
+
+#[cfg(feature = "serialport")]
+/// Bit of status register 1 which is set while the flash is busy writing or
+/// erasing
+const SPI_STATUS_WRITE_IN_PROGRESS: u32 = 1 << 0;
+
+#[cfg(feature = "serialport")]
+/// SPI flash command enabling writes to the flash or its status registers
+const SPI_WRITE_ENABLE: u8 = 0x06;
+
+#[cfg(feature = "serialport")]
+/// SPI flash command erasing the entire flash
+const SPI_CHIP_ERASE: u8 = 0xc7;
+
+#[cfg(feature = "serialport")]
+/// SPI flash command reading data, with a 24-bit address
+const SPI_READ: u8 = 0x03;
+
+#[cfg(feature = "serialport")]
+/// SPI flash command reading data, with a 32-bit address
+const SPI_READ_4B: u8 = 0x13;
+
+#[cfg(feature = "serialport")]
+/// SPI flash command programming up to a page of data, with a 32-bit address
+const SPI_PAGE_PROGRAM_4B: u8 = 0x12;
+
+#[cfg(feature = "serialport")]
+/// SPI flash command erasing a 4 kB sector, with a 32-bit address
+const SPI_SECTOR_ERASE_4B: u8 = 0x21;
+
+#[cfg(feature = "serialport")]
+/// Size of the pages of the flash chip, which a program command cannot cross
+const SPI_PAGE_SIZE: u32 = 0x100;
+
+#[cfg(feature = "serialport")]
+/// SPI flash command reading the Serial Flash Discoverable Parameters
+const SPI_READ_SFDP: u8 = 0x5a;
+
+#[cfg(feature = "serialport")]
+/// Flag of the security information set when Secure Download Mode is enabled
+const SECURITY_FLAG_SECURE_DOWNLOAD: u32 = 1 << 2;
+
+#[cfg(feature = "serialport")]
+/// Progress of reading flash to a file, recorded next to the partial output
+/// while the read is in progress
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReadProgress {
+    /// Offset of the region being read
+    pub offset: u32,
+    /// Size of the region being read
+    pub size: u32,
+    /// Number of bytes read and written to the partial output so far
+    pub completed: u32,
+}
+
+#[cfg(feature = "serialport")]
+impl ReadProgress {
+    /// Path the progress of reading to `output` is recorded at
+    pub fn path(output: &Path) -> PathBuf {
+        let mut path = partial_path(output).into_os_string();
+        path.push(".json");
+
+        path.into()
+    }
+
+    /// Load the progress of an interrupted read to `output`, if there is one
+    pub fn load(output: &Path) -> Option<Self> {
+        let data = fs::read_to_string(Self::path(output)).ok()?;
+
+        serde_json::from_str(&data).ok()
+    }
+
+    fn save(&self, output: &Path) -> Result<(), Error> {
+        output::write(Self::path(output), serde_json::to_string(self).unwrap())
+    }
+}
+
+/// Statistics of the data a [Flasher] wrote to flash
+#[cfg(feature = "serialport")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FlashStats {
+    /// Bytes of data written to flash, including unchanged segments which
+    /// were skipped
+    pub bytes: u64,
+    /// Time spent writing flash
+    pub duration: Duration,
+    /// Number of times a segment failed verification and was written again
+    pub verify_failures: u64,
+}
+
+#[cfg(feature = "serialport")]
+impl FlashStats {
+    /// Average throughput in bytes per second, if any data was written
+    pub fn throughput(&self) -> Option<f64> {
+        let seconds = self.duration.as_secs_f64();
+        (self.bytes > 0 && seconds > 0.0).then(|| self.bytes as f64 / seconds)
+    }
+}
+
+/// Options for connecting to a device with [Flasher::connect]
+///
+/// By default, the device is reset into download mode, the chip is detected
+/// and the built-in flasher stub is run at 115,200 baud, verifying written
+/// data and skipping segments already in flash.
+#[cfg(feature = "serialport")]
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ConnectOptions {
+    /// Baud rate to switch to once connected, 115,200 is kept if `None`
+    pub speed: Option<u32>,
+    /// Run a flasher stub instead of using the ROM loader
+    pub use_stub: bool,
+    /// Flasher stub to run instead of the built-in one
+    pub stub: Option<FlashStub>,
+    /// Verify the data written to flash
+    pub verify: bool,
+    /// Skip segments whose contents are already in flash
+    pub skip: bool,
+    /// Chip expected on the port, detected if `None`
+    pub chip: Option<Chip>,
+    /// Implementation of the chip on the port, instead of the one of the
+    /// detected [Chip]
+    pub target: Option<Arc<dyn Target>>,
+    /// Reset to perform after the operation
+    pub after_operation: ResetAfterOperation,
+    /// Reset to perform before connecting
+    pub before_operation: ResetBeforeOperation,
+    /// Waiting for the flasher stub to settle, the default for the port if
+    /// `None`
+    pub stub_settle: Option<StubSettle>,
+    /// How to reset the device into download mode
+    pub connect_strategy: ConnectStrategy,
+}
+
+#[cfg(feature = "serialport")]
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            speed: None,
+            use_stub: true,
+            stub: None,
+            verify: true,
+            skip: true,
+            chip: None,
+            target: None,
+            after_operation: ResetAfterOperation::default(),
+            before_operation: ResetBeforeOperation::default(),
+            stub_settle: None,
+            connect_strategy: ConnectStrategy::default(),
+        }
+    }
+}
+
+#[cfg(feature = "serialport")]
+impl ConnectOptions {
+    /// Switch to the baud rate `speed` once connected
+    pub fn with_speed(mut self, speed: u32) -> Self {
+        self.speed = Some(speed);
+        self
+    }
+
+    /// Run a flasher stub, or use the ROM loader if `use_stub` is `false`
+    pub fn with_use_stub(mut self, use_stub: bool) -> Self {
+        self.use_stub = use_stub;
+        self
+    }
+
+    /// Run `stub` instead of the built-in flasher stub
+    pub fn with_stub(mut self, stub: FlashStub) -> Self {
+        self.stub = Some(stub);
+        self
+    }
+
+    /// Verify the data written to flash, or not
+    pub fn with_verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Skip segments whose contents are already in flash, or not
+    pub fn with_skip(mut self, skip: bool) -> Self {
+        self.skip = skip;
+        self
+    }
+
+    /// Expect `chip` on the port, failing if another chip is detected
+    pub fn with_chip(mut self, chip: Chip) -> Self {
+        self.chip = Some(chip);
+        self
+    }
+
+    /// Use `target` as the implementation of the chip on the port, e.g. one
+    /// implemented outside of this crate
+    ///
+    /// The chip is detected by the magic values of `target`, and is the chip
+    /// named by [Target::chip] otherwise.
+    pub fn with_target(mut self, target: impl Target + 'static) -> Self {
+        self.target = Some(Arc::new(target));
+        self
+    }
+
+    /// Reset the device with `after_operation` after the operation
+    pub fn with_after_operation(mut self, after_operation: ResetAfterOperation) -> Self {
+        self.after_operation = after_operation;
+        self
+    }
+
+    /// Reset the device with `before_operation` before connecting
+    pub fn with_before_operation(mut self, before_operation: ResetBeforeOperation) -> Self {
+        self.before_operation = before_operation;
+        self
+    }
+
+    /// Wait for the flasher stub to settle as given by `stub_settle`
+    pub fn with_stub_settle(mut self, stub_settle: StubSettle) -> Self {
+        self.stub_settle = Some(stub_settle);
+        self
+    }
+
+    /// Reset the device into download mode with `connect_strategy`
+    pub fn with_connect_strategy(mut self, connect_strategy: ConnectStrategy) -> Self {
+        self.connect_strategy = connect_strategy;
+        self
+    }
+}
+
 #[cfg(feature = "serialport")]
 /// Connect to and flash a target device
 pub struct Flasher {
@@ -553,42 +1069,102 @@ pub struct Flasher {
     connection: Connection,
     /// Chip ID
     chip: Chip,
+    /// Implementation of the chip
+    target: Arc<dyn Target>,
     /// Flash size, loaded from SPI flash
     flash_size: FlashSize,
+    /// Flash size reported by the SPI flash, if it could be recognized
+    detected_flash_size: Option<FlashSize>,
+    /// Flash chip identified by its JEDEC ID
+    flash_chip: Option<FlashChipInfo>,
     /// Configuration for SPI attached flash (0 to use fused values)
     spi_params: SpiAttachParams,
     /// Indicate RAM stub loader is in use
     use_stub: bool,
+    /// Indicate the ROM loader runs in Secure Download Mode
+    secure_download_mode: bool,
     /// Indicate verifying flash contents after flashing
     verify: bool,
     /// Indicate skipping of already flashed regions
     skip: bool,
+    /// Digest comparing the contents of flash when skipping and verifying
+    digest: DigestAlgorithm,
+    /// SPI flash clock divider pinned by the user, disabling the automatic
+    /// step-down when verification fails
+    spi_clock_divider: Option<u32>,
+    /// Time it took to load the RAM stub loader
+    stub_load_time: Option<Duration>,
+    /// Reason of the last reset, captured while connecting
+    reset_reason: Option<u32>,
+    /// Name telling the device apart from others, see [Flasher::set_device_name]
+    device_name: Option<String>,
+    /// Serial Flash Discoverable Parameters of the flash chip, if it has them,
+    /// or `None` if they have not been read yet
+    sfdp: Option<Option<Sfdp>>,
+    /// Encryption of the data written to flash
+    encryption: Option<FlashEncryption>,
+    /// Statistics of the data written to flash
+    flash_stats: FlashStats,
 }
 
 #[cfg(feature = "serialport")]
 impl Flasher {
-    #[allow(clippy::too_many_arguments)]
+    /// Connect to the device on `serial`, as described by `options`
     pub fn connect(
         serial: Port,
         port_info: UsbPortInfo,
-        speed: Option<u32>,
-        use_stub: bool,
-        verify: bool,
-        skip: bool,
-        chip: Option<Chip>,
-        after_operation: ResetAfterOperation,
-        before_operation: ResetBeforeOperation,
+        options: ConnectOptions,
     ) -> Result<Self, Error> {
+        let ConnectOptions {
+            speed,
+            use_stub,
+            stub,
+            verify,
+            skip,
+            chip,
+            target,
+            after_operation,
+            before_operation,
+            stub_settle,
+            connect_strategy,
+        } = options;
+
         // Establish a connection to the device using the default baud rate of 115,200
         // and timeout of 3 seconds.
-        let mut connection = Connection::new(serial, port_info, after_operation, before_operation);
+        let mut connection = Connection::new(serial, port_info, after_operation, before_operation)
+            .with_connect_strategy(connect_strategy);
+        if let Some(settle) = stub_settle {
+            connection.set_stub_settle(settle);
+        }
         connection.begin()?;
         connection.set_timeout(DEFAULT_TIMEOUT)?;
 
+        let mut secure_download_mode = false;
         let detected_chip = if before_operation != ResetBeforeOperation::NoResetNoSync {
             // Detect which chip we are connected to.
-            let magic = connection.read_reg(CHIP_DETECT_MAGIC_REG_ADDR)?;
-            let detected_chip = Chip::from_magic(magic)?;
+            let detected_chip = match connection.read_reg(CHIP_DETECT_MAGIC_REG_ADDR) {
+                Ok(magic) => match &target {
+                    Some(target) if target.has_magic_value(magic) => target.chip(),
+                    Some(_) => return Err(Error::ChipDetectError(magic)),
+                    None => Chip::from_magic(magic)?,
+                },
+                Err(e) => {
+                    // The ROM loader rejects reading registers in Secure Download
+                    // Mode, but reports the ID of the chip with its security info
+                    let info = match SecurityInfo::read(&mut connection) {
+                        Ok(info) if info.secure_download_mode() => info,
+                        _ => return Err(e),
+                    };
+                    warn!("The device is in Secure Download Mode, only flash can be written");
+                    secure_download_mode = true;
+
+                    match (&target, info.chip().or(chip)) {
+                        (Some(target), _) => target.chip(),
+                        (None, Some(chip)) => chip,
+                        (None, None) => return Err(Error::ChipNotProvided),
+                    }
+                }
+            };
             if let Some(chip) = chip {
                 if chip != detected_chip {
                     return Err(Error::ChipMismatch(
@@ -598,8 +1174,10 @@ impl Flasher {
                 }
             }
             detected_chip
-        } else if before_operation == ResetBeforeOperation::NoResetNoSync && chip.is_some() {
-            chip.unwrap()
+        } else if let Some(target) = &target {
+            target.chip()
+        } else if let Some(chip) = chip {
+            chip
         } else {
             return Err(Error::ChipNotProvided);
         };
@@ -607,21 +1185,68 @@ impl Flasher {
         let mut flasher = Flasher {
             connection,
             chip: detected_chip,
+            target: target.unwrap_or_else(|| detected_chip.into_target().into()),
             flash_size: FlashSize::_4Mb,
+            detected_flash_size: None,
+            flash_chip: None,
             spi_params: SpiAttachParams::default(),
             use_stub,
+            secure_download_mode,
             verify,
             skip,
+            digest: DigestAlgorithm::default(),
+            spi_clock_divider: None,
+            stub_load_time: None,
+            reset_reason: None,
+            device_name: None,
+            sfdp: None,
+            encryption: None,
+            flash_stats: FlashStats::default(),
         };
 
         if before_operation == ResetBeforeOperation::NoResetNoSync {
             return Ok(flasher);
         }
 
-        // Load flash stub if enabled
-        if use_stub {
+        // Without access to the registers, neither the revision of the chip nor
+        // the flash chip can be read, and the stub cannot be loaded
+        if secure_download_mode {
+            if use_stub {
+                info!("The flasher stub cannot be loaded in Secure Download Mode, using the ROM loader");
+            }
+            flasher.use_stub = false;
+            flasher.enable_flash(SpiAttachParams::default())?;
+            warn!("Could not detect flash size in Secure Download Mode, defaulting to 4MB");
+            return Ok(flasher);
+        }
+
+        // Capture the reason of the last reset before anything else runs on the
+        // device, which may reset it again, e.g. a brownout while loading the stub
+        flasher.reset_reason = match flasher.target.reset_reason(&mut flasher.connection) {
+            Ok(reset_reason) => reset_reason,
+            Err(e) => {
+                debug!("Failed to read the reset reason: {e}");
+                None
+            }
+        };
+
+        flasher.check_compatibility();
+
+        // Load flash stub if enabled, unless it is still running on a device held
+        // in download mode
+        if use_stub && flasher.connection.held_stub() {
+            info!("Flasher stub is already running");
+            // Remember the stub to load it again when reconnecting to the device
+            let stub = flasher.select_stub(stub)?;
+            let max_ram_block_size = flasher.target.max_ram_block_size(&mut flasher.connection)?;
+            let session = flasher.connection.session_mut();
+            session.stub = Some(stub);
+            session.max_ram_block_size = max_ram_block_size;
+        } else if use_stub {
             info!("Using flash stub");
-            flasher.load_stub()?;
+            let start = Instant::now();
+            flasher.load_stub(stub)?;
+            flasher.stub_load_time = Some(start.elapsed());
         }
 
         flasher.spi_autodetect()?;
@@ -630,7 +1255,9 @@ impl Flasher {
         // size, we can set the baud rate of the connection to the configured value.
         if let Some(baud) = speed {
             if baud > 115_200 {
-                warn!("Setting baud rate higher than 115,200 can cause issues");
+                if !flasher.connection.is_usb_cdc() {
+                    warn!("Setting baud rate higher than 115,200 can cause issues");
+                }
                 flasher.change_baud(baud)?;
             }
         }
@@ -638,78 +1265,243 @@ impl Flasher {
         Ok(flasher)
     }
 
-    pub fn set_flash_size(&mut self, flash_size: FlashSize) {
-        self.flash_size = flash_size;
+    /// Time it took to load the RAM stub loader, if it is in use
+    pub fn stub_load_time(&self) -> Option<Duration> {
+        self.stub_load_time
     }
 
-    pub fn disable_watchdog(&mut self) -> Result<(), Error> {
-        let mut target = self
-            .chip
-            .flash_target(self.spi_params, self.use_stub, false, false);
-        target.begin(&mut self.connection).flashing()?;
-        Ok(())
+    /// The commands supported by the loader running on the device, i.e. the
+    /// flasher stub or the ROM loader
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::new(self.chip, self.use_stub)
+            .with_secure_download_mode(self.secure_download_mode)
     }
 
-    /// Load flash stub
-    fn load_stub(&mut self) -> Result<(), Error> {
-        debug!("Loading flash stub for chip: {:?}", self.chip);
+    /// Whether the ROM loader runs in Secure Download Mode, which only allows
+    /// writing and erasing flash
+    pub fn secure_download_mode(&self) -> bool {
+        self.secure_download_mode
+    }
 
-        // Load flash stub
-        let stub = FlashStub::get(self.chip);
+    /// Name the device, e.g. with [DeviceAliases::name], to tell the output of
+    /// operations on several devices apart
+    ///
+    /// [DeviceAliases::name]: crate::identity::DeviceAliases::name
+    pub fn set_device_name(&mut self, name: Option<String>) {
+        self.device_name = name;
+    }
 
-        let mut ram_target = self.chip.ram_target(
-            Some(stub.entry()),
-            self.chip
-                .into_target()
-                .max_ram_block_size(&mut self.connection)?,
-        );
-        ram_target.begin(&mut self.connection).flashing()?;
+    /// The name of the device, if it was named with [Flasher::set_device_name]
+    pub fn device_name(&self) -> Option<&str> {
+        self.device_name.as_deref()
+    }
 
-        let (text_addr, text) = stub.text();
-        debug!("Write {} byte stub text", text.len());
+    /// Statistics of the data written to flash since connecting
+    pub fn flash_stats(&self) -> FlashStats {
+        self.flash_stats
+    }
 
-        ram_target
-            .write_segment(
-                &mut self.connection,
-                RomSegment {
-                    addr: text_addr,
-                    data: Cow::Borrowed(&text),
-                },
-                &mut None,
-            )
-            .flashing()?;
+    pub fn set_flash_size(&mut self, flash_size: FlashSize) {
+        self.flash_size = flash_size;
+    }
 
-        let (data_addr, data) = stub.data();
-        debug!("Write {} byte stub data", data.len());
+    /// Read the divider currently applied to the SPI flash clock
+    pub fn spi_clock_divider(&mut self) -> Result<u32, Error> {
+        let spi_registers = self.target.spi_registers();
+        let value = self.connection.read_reg(spi_registers.clock())?;
 
-        ram_target
-            .write_segment(
-                &mut self.connection,
-                RomSegment {
-                    addr: data_addr,
-                    data: Cow::Borrowed(&data),
-                },
-                &mut None,
-            )
-            .flashing()?;
+        Ok(spi_registers.decode_clock_divider(value))
+    }
 
-        debug!("Finish stub write");
-        ram_target.finish(&mut self.connection, true).flashing()?;
+    /// Set the divider of the SPI flash clock
+    ///
+    /// Lowering the clock can help with marginal hardware, e.g. long wires or
+    /// breadboards. The divider is applied again whenever the flash is
+    /// attached, and disables the automatic step-down of the clock when writes
+    /// fail verification.
+    pub fn set_spi_clock_divider(&mut self, divider: u32) -> Result<(), Error> {
+        self.write_spi_clock_divider(divider)?;
+        self.spi_clock_divider = Some(divider);
 
-        debug!("Stub written!");
+        Ok(())
+    }
 
-        match self.connection.read(EXPECTED_STUB_HANDSHAKE.len())? {
-            Some(resp) if resp == EXPECTED_STUB_HANDSHAKE.as_bytes() => Ok(()),
-            _ => Err(Error::Connection(ConnectionError::InvalidStubHandshake)),
-        }?;
+    /// Attach to flash connected to the given pins, instead of the ones
+    /// found while connecting
+    ///
+    /// Flash embedded in the chip package is always wired to the same pins, so
+    /// selecting a different connection for it is an error.
+    pub fn set_spi_connection(&mut self, spi_params: SpiAttachParams) -> Result<(), Error> {
+        if spi_params == self.spi_params {
+            return Ok(());
+        }
 
-        // Re-detect chip to check stub is up
-        let magic = self.connection.read_reg(CHIP_DETECT_MAGIC_REG_ADDR)?;
-        let chip = Chip::from_magic(magic)?;
-        debug!("Re-detected chip: {:?}", chip);
+        if self.target.embedded_flash(&mut self.connection)? == Some(true) {
+            return Err(Error::SpiConnectionConflict(self.chip));
+        }
 
-        Ok(())
-    }
+        debug!("Attaching flash with: {:?}", spi_params);
+        if let Err(_e) = self.enable_flash(spi_params) {
+            debug!("Flash enable failed");
+        }
+
+        match self.flash_detect()? {
+            Some(flash_size) => {
+                self.flash_size = flash_size;
+                self.spi_params = spi_params;
+                self.connection.session_mut().spi_attach = Some(spi_params);
+
+                self.check_flash_status()
+            }
+            None => Err(self.flash_connect_error()),
+        }
+    }
+
+    /// Set the digest used to compare the contents of flash with the data
+    /// written, when skipping unchanged segments and verifying them
+    pub fn set_digest(&mut self, digest: DigestAlgorithm) {
+        self.digest = digest;
+    }
+
+    /// Set how the data written to flash is encrypted, for devices with flash
+    /// encryption enabled
+    ///
+    /// Skipping unchanged segments and verifying them is not possible when the
+    /// device encrypts the data, as their digest is computed over the encrypted
+    /// contents of the flash.
+    pub fn set_encryption(&mut self, encryption: Option<FlashEncryption>) {
+        self.encryption = encryption;
+    }
+
+    fn write_spi_clock_divider(&mut self, divider: u32) -> Result<(), Error> {
+        let spi_registers = self.target.spi_registers();
+        let value =
+            spi_registers
+                .encode_clock_divider(divider)
+                .ok_or(Error::InvalidSpiClockDivider {
+                    divider,
+                    max: spi_registers.max_clock_divider(),
+                })?;
+
+        debug!("Setting SPI flash clock divider to {divider}");
+        self.connection
+            .write_reg(spi_registers.clock(), value, None)
+    }
+
+    /// Write a segment, lowering the SPI flash clock and trying again when
+    /// verification fails, unless a divider was set explicitly
+    fn write_segment_with_retry(
+        &mut self,
+        target: &mut dyn FlashTarget,
+        segment: RomSegment<'_>,
+        progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        loop {
+            let start = Instant::now();
+            let result = target
+                .write_segment(&mut self.connection, segment.borrow(), progress)
+                .flashing();
+            self.flash_stats.duration += start.elapsed();
+
+            match result {
+                Ok(()) => {
+                    self.flash_stats.bytes += segment.data.len() as u64;
+                    return Ok(());
+                }
+                Err(Error::VerifyFailed) => {
+                    self.flash_stats.verify_failures += 1;
+                    if self.spi_clock_divider.is_some() {
+                        return Err(Error::VerifyFailed);
+                    }
+
+                    let current = self.spi_clock_divider()?;
+                    let next = current * 2;
+                    let max = self.target.spi_registers().max_clock_divider();
+                    if next > max {
+                        return Err(Error::VerifyFailed);
+                    }
+
+                    warn!(
+                        "Verification failed, lowering the SPI flash clock (divider {current} -> {next}) and retrying"
+                    );
+                    self.write_spi_clock_divider(next)?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Apply the SPI flash clock divider set by the user, as attaching the
+    /// flash may have reset it
+    fn apply_spi_clock_divider(&mut self) -> Result<(), Error> {
+        match self.spi_clock_divider {
+            Some(divider) => self.write_spi_clock_divider(divider),
+            None => Ok(()),
+        }
+    }
+
+    pub fn disable_watchdog(&mut self) -> Result<(), Error> {
+        let mut target = self.chip.flash_target(
+            self.spi_params,
+            self.use_stub,
+            false,
+            false,
+            self.digest,
+            None,
+        );
+        target.begin(&mut self.connection).flashing()?;
+        Ok(())
+    }
+
+    /// Read the revision of the chip, to avoid the features which do not work
+    /// with it
+    fn check_compatibility(&mut self) {
+        let revision = match self.target.chip_revision(&mut self.connection) {
+            Ok(revision) => Some(revision),
+            Err(e) => {
+                debug!("Failed to read the chip revision: {e}");
+                None
+            }
+        };
+
+        let compatibility = Compatibility::new(self.chip, revision);
+        for (feature, reason) in compatibility.avoided_features() {
+            let (major, minor) = revision.unwrap_or_default();
+            info!(
+                "Avoiding {feature} on the {} revision v{major}.{minor}: {reason}",
+                self.chip
+            );
+        }
+        self.connection.set_compatibility(compatibility);
+    }
+
+    /// The custom flash stub, if it suits the chip, or else the built-in stub
+    fn select_stub(&self, stub: Option<FlashStub>) -> Result<FlashStub, Error> {
+        Ok(match stub {
+            Some(stub) => {
+                stub.validate(self.chip)?;
+                info!("Using custom flash stub");
+                stub
+            }
+            None => FlashStub::get(self.chip),
+        })
+    }
+
+    /// Load flash stub, falling back to the built-in stub for the chip
+    fn load_stub(&mut self, stub: Option<FlashStub>) -> Result<(), Error> {
+        debug!("Loading flash stub for chip: {:?}", self.chip);
+
+        let stub = self.select_stub(stub)?;
+        let max_ram_block_size = self.target.max_ram_block_size(&mut self.connection)?;
+        load_stub(&mut self.connection, &stub, max_ram_block_size)?;
+
+        // Remember the stub to load it again when reconnecting to the device
+        let session = self.connection.session_mut();
+        session.stub = Some(stub);
+        session.max_ram_block_size = max_ram_block_size;
+
+        Ok(())
+    }
 
     fn spi_autodetect(&mut self) -> Result<(), Error> {
         // Loop over all available SPI parameters until we find one that successfully
@@ -725,13 +1517,34 @@ impl Flasher {
 
             if let Some(flash_size) = self.flash_detect()? {
                 debug!("Flash detect OK!");
+                self.check_flash_status()?;
 
                 // Flash detection was successful, so save the flash size and SPI parameters and
                 // return.
                 self.flash_size = flash_size;
                 self.spi_params = spi_params;
+                self.connection.session_mut().spi_attach = Some(spi_params);
+
+                // Reading the SFDP takes a few SPI commands, which are slow on the
+                // ROM loader, so it is only read here when the flash size is
+                // unknown otherwise.
+                if self.detected_flash_size.is_none() {
+                    let sfdp_flash_size = self.sfdp().and_then(|sfdp| {
+                        FlashSize::iter().find(|size| u64::from(size.size()) == sfdp.basic.size)
+                    });
+                    if let Some(size) = sfdp_flash_size {
+                        debug!("Using the flash size from SFDP: {size}");
+                        self.flash_size = size;
+                        self.detected_flash_size = Some(size);
+                    } else {
+                        warn!("Could not detect flash size, defaulting to 4MB");
+                    }
+                }
 
-                let spi_set_params = SpiSetParams::default(self.flash_size.size());
+                let mut spi_set_params = SpiSetParams::default(self.flash_size.size());
+                if let Some(Some(sfdp)) = &self.sfdp {
+                    spi_set_params = spi_set_params.with_sfdp(&sfdp.basic);
+                }
                 self.connection.with_timeout(
                     CommandType::SpiSetParams.timeout(),
                     |connection| {
@@ -740,6 +1553,7 @@ impl Flasher {
                         })
                     },
                 )?;
+                self.connection.session_mut().spi_set_params = Some(spi_set_params);
 
                 return Ok(());
             }
@@ -750,27 +1564,77 @@ impl Flasher {
         debug!("SPI flash autodetection failed");
 
         // None of the SPI parameters were successful.
-        Err(Error::FlashConnect)
+        Err(self.flash_connect_error())
+    }
+
+    /// The error for a flash chip which could not be detected, diagnosing a
+    /// hardware fault when the chip did not respond at all
+    fn flash_connect_error(&self) -> Error {
+        match self.flash_chip {
+            Some(flash_chip) if !flash_chip.responded() => Error::FlashFault(format!(
+                "the flash chip returned a JEDEC ID of {}",
+                flash_chip.jedec_id()
+            )),
+            _ => Error::FlashConnect,
+        }
+    }
+
+    /// Check the status of the flash chip, failing when it stays busy, which
+    /// would otherwise only show as timeouts when writing
+    ///
+    /// A write protected flash chip can still be read, so it is only warned
+    /// about.
+    fn check_flash_status(&mut self) -> Result<(), Error> {
+        // Block protect bits BP0 to BP4
+        const BLOCK_PROTECT: u32 = 0b0111_1100;
+
+        let status = match self.read_flash_status(1) {
+            Ok(status) => status,
+            Err(e) => {
+                debug!("Failed to read the flash status: {e}");
+                return Ok(());
+            }
+        };
+        debug!("Flash status register 1: {status:#04x}");
+
+        if status & SPI_STATUS_WRITE_IN_PROGRESS != 0 && self.wait_flash_idle().is_err() {
+            return Err(Error::FlashFault(format!(
+                "the flash chip stays busy, its status register 1 reads {status:#04x}"
+            )));
+        }
+        if status & BLOCK_PROTECT != 0 {
+            warn!(
+                "The flash is write protected (status register 1 reads {status:#04x}), writing to it will fail"
+            );
+        }
+
+        Ok(())
     }
 
     fn flash_detect(&mut self) -> Result<Option<FlashSize>, Error> {
         const FLASH_RETRY: u8 = 0xFF;
 
-        let flash_id = self.spi_command(CommandType::FlashDetect, &[], 24)?;
+        let flash_id = self.spi_command(CommandType::FlashDetect as u8, &[], 24)?;
         let size_id = (flash_id >> 16) as u8;
+        let flash_chip = FlashChipInfo::from_jedec_id(flash_id);
+        self.flash_chip = Some(flash_chip);
 
         // This value indicates that an alternate detection method should be tried.
-        if size_id == FLASH_RETRY {
+        if size_id == FLASH_RETRY || !flash_chip.responded() {
+            debug!("No response from the flash chip: {flash_chip}");
             return Ok(None);
         }
+        debug!("Flash chip: {flash_chip}");
 
         let flash_size = match FlashSize::from_detected(size_id) {
-            Ok(size) => size,
+            Ok(size) => {
+                self.detected_flash_size = Some(size);
+                size
+            }
             Err(_) => {
-                warn!(
-                    "Could not detect flash size (FlashID=0x{:02X}, SizeID=0x{:02X}), defaulting to 4MB",
-                    flash_id,
-                    size_id
+                debug!(
+                    "Unknown flash size (FlashID=0x{:02X}, SizeID=0x{:02X})",
+                    flash_id, size_id
                 );
                 FlashSize::default()
             }
@@ -792,92 +1656,117 @@ impl Flasher {
         Ok(())
     }
 
-    fn spi_command(
+    /// Run a command on the SPI flash chip
+    ///
+    /// Sends the `opcode`, followed by up to 64 bytes of `data`, and then reads
+    /// `read_bits` bits of the response, at most 32. The first byte of the
+    /// response is in the least significant byte of the returned value.
+    ///
+    /// Commands which take dummy cycles before their response can send them as
+    /// zeroes at the end of `data`. Commands which modify the flash or its
+    /// status registers need to be preceded by a write enable (`0x06`)
+    /// command.
+    pub fn spi_command(&mut self, opcode: u8, data: &[u8], read_bits: u32) -> Result<u32, Error> {
+        if data.len() > SPI_COMMAND_MAX_DATA {
+            return Err(Error::InvalidSpiCommand(format!(
+                "{} bytes of data is more than the maximum of {SPI_COMMAND_MAX_DATA}",
+                data.len()
+            )));
+        }
+        if read_bits > 32 {
+            return Err(Error::InvalidSpiCommand(format!(
+                "reading {read_bits} bits is more than the maximum of 32"
+            )));
+        }
+
+        Ok(self.spi_transaction(opcode, data, read_bits)?[0])
+    }
+
+    /// Run a command on the SPI flash chip, reading up to 64 bytes of the
+    /// response
+    fn spi_read(&mut self, opcode: u8, data: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+        let words = self.spi_transaction(opcode, data, len as u32 * 8)?;
+        let mut response: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        response.truncate(len);
+
+        Ok(response)
+    }
+
+    /// Run a command on the SPI flash chip, returning the words of the
+    /// response, or the first data word when there is no response
+    fn spi_transaction(
         &mut self,
-        command: CommandType,
+        opcode: u8,
         data: &[u8],
         read_bits: u32,
-    ) -> Result<u32, Error> {
-        assert!(read_bits < 32);
-        assert!(data.len() < 64);
-
-        let spi_registers = self.chip.into_target().spi_registers();
-
-        let old_spi_usr = self.connection.read_reg(spi_registers.usr())?;
-        let old_spi_usr2 = self.connection.read_reg(spi_registers.usr2())?;
+    ) -> Result<Vec<u32>, Error> {
+        let spi_registers = self.target.spi_registers();
+        spi_transaction(
+            &mut self.connection,
+            &spi_registers,
+            opcode,
+            data,
+            read_bits,
+        )
+    }
 
-        let mut flags = 1 << 31;
-        if !data.is_empty() {
-            flags |= 1 << 27;
-        }
-        if read_bits > 0 {
-            flags |= 1 << 28;
+    /// Read the first `count` status registers of the SPI flash chip, at most
+    /// 3
+    ///
+    /// Status register 1 is in the least significant byte of the returned
+    /// value.
+    pub fn read_flash_status(&mut self, count: usize) -> Result<u32, Error> {
+        let mut status = 0;
+        for (i, opcode) in SPI_READ_STATUS.iter().take(count).enumerate() {
+            status |= self.spi_command(*opcode, &[], 8)? << (8 * i);
         }
 
-        self.connection
-            .write_reg(spi_registers.usr(), flags, None)?;
-        self.connection
-            .write_reg(spi_registers.usr2(), 7 << 28 | command as u32, None)?;
+        Ok(status)
+    }
 
-        if let (Some(mosi_data_length), Some(miso_data_length)) =
-            (spi_registers.mosi_length(), spi_registers.miso_length())
-        {
-            if !data.is_empty() {
-                self.connection
-                    .write_reg(mosi_data_length, data.len() as u32 * 8 - 1, None)?;
-            }
-            if read_bits > 0 {
-                self.connection
-                    .write_reg(miso_data_length, read_bits - 1, None)?;
-            }
-        } else {
-            let mosi_mask = if data.is_empty() {
-                0
-            } else {
-                data.len() as u32 * 8 - 1
-            };
-            let miso_mask = if read_bits == 0 { 0 } else { read_bits - 1 };
-            self.connection.write_reg(
-                spi_registers.usr1(),
-                miso_mask << 8 | mosi_mask << 17,
-                None,
-            )?;
+    /// Write the first `count` status registers of the SPI flash chip, at most
+    /// 3, waiting for each write to complete
+    ///
+    /// Status register 1 is written from the least significant byte of
+    /// `status`. The status registers are written to their non-volatile bits,
+    /// so take care not to set protection bits inadvertently.
+    pub fn write_flash_status(&mut self, status: u32, count: usize) -> Result<(), Error> {
+        for (i, opcode) in SPI_WRITE_STATUS.iter().take(count).enumerate() {
+            self.spi_command(SPI_WRITE_ENABLE, &[], 0)?;
+            self.spi_command(*opcode, &[(status >> (8 * i)) as u8], 0)?;
+            self.wait_flash_idle()?;
         }
 
-        if data.is_empty() {
-            self.connection.write_reg(spi_registers.w0(), 0, None)?;
-        } else {
-            for (i, bytes) in data.chunks(4).enumerate() {
-                let mut data_bytes = [0; 4];
-                data_bytes[0..bytes.len()].copy_from_slice(bytes);
-                let data = u32::from_le_bytes(data_bytes);
-                self.connection
-                    .write_reg(spi_registers.w0() + i as u32, data, None)?;
-            }
+        Ok(())
+    }
+
+    /// Read `len` bytes of the Serial Flash Discoverable Parameters (SFDP) of
+    /// the SPI flash chip, starting at `addr`
+    pub fn read_sfdp(&mut self, addr: u32, len: usize) -> Result<Vec<u8>, Error> {
+        let mut sfdp = Vec::with_capacity(len);
+        for offset in (0..len).step_by(SPI_COMMAND_MAX_DATA) {
+            let chunk_len = (len - offset).min(SPI_COMMAND_MAX_DATA);
+            let [_, addr @ ..] = (addr + offset as u32).to_be_bytes();
+            // The address is followed by 8 dummy cycles
+            let chunk = self.spi_read(SPI_READ_SFDP, &[addr[0], addr[1], addr[2], 0], chunk_len)?;
+            sfdp.extend_from_slice(&chunk);
         }
 
-        self.connection
-            .write_reg(spi_registers.cmd(), 1 << 18, None)?;
+        Ok(sfdp)
+    }
 
-        let mut i = 0;
-        loop {
-            sleep(Duration::from_millis(1));
-            if self.connection.read_reg(spi_registers.usr())? & (1 << 18) == 0 {
-                break;
-            }
-            i += 1;
-            if i > 10 {
-                return Err(Error::Connection(ConnectionError::Timeout(command.into())));
+    /// Wait for the SPI flash chip to finish writing or erasing
+    pub fn wait_flash_idle(&mut self) -> Result<(), Error> {
+        for _ in 0..100 {
+            if self.read_flash_status(1)? & SPI_STATUS_WRITE_IN_PROGRESS == 0 {
+                return Ok(());
             }
+            sleep(Duration::from_millis(10));
         }
 
-        let result = self.connection.read_reg(spi_registers.w0())?;
-        self.connection
-            .write_reg(spi_registers.usr(), old_spi_usr, None)?;
-        self.connection
-            .write_reg(spi_registers.usr2(), old_spi_usr2, None)?;
-
-        Ok(result)
+        Err(Error::Connection(ConnectionError::Timeout(
+            TimedOutCommand::default(),
+        )))
     }
 
     /// The active serial connection being used by the flasher
@@ -885,15 +1774,41 @@ impl Flasher {
         &mut self.connection
     }
 
+    /// The Serial Flash Discoverable Parameters of the flash chip, if it has
+    /// them
+    ///
+    /// They are read from the flash chip the first time they are needed.
+    pub fn sfdp(&mut self) -> Option<&Sfdp> {
+        if self.sfdp.is_none() {
+            let sfdp = Sfdp::read(|addr, len| self.read_sfdp(addr, len)).unwrap_or_else(|e| {
+                debug!("Failed to read SFDP: {e}");
+                None
+            });
+            self.sfdp = Some(sfdp);
+        }
+
+        self.sfdp.as_ref().and_then(Option::as_ref)
+    }
+
+    /// The flash chip, as identified by its JEDEC ID
+    pub fn flash_chip(&self) -> Option<FlashChipInfo> {
+        self.flash_chip
+    }
+
     /// The chip type that the flasher is connected to
     pub fn chip(&self) -> Chip {
         self.chip
     }
 
+    /// The implementation of the chip, see [ConnectOptions::with_target]
+    pub fn target(&self) -> &dyn Target {
+        &*self.target
+    }
+
     /// Read and print any information we can about the connected device
     pub fn device_info(&mut self) -> Result<DeviceInfo, Error> {
         let chip = self.chip();
-        let target = chip.into_target();
+        let target = self.target.clone();
 
         let revision = Some(target.chip_revision(self.connection())?);
         let crystal_frequency = target.crystal_freq(self.connection())?;
@@ -903,12 +1818,15 @@ impl Flasher {
             .map(|s| s.to_string())
             .collect::<Vec<_>>();
         let mac_address = target.mac_address(self.connection())?;
+        let embedded_flash = target.embedded_flash(self.connection())?;
 
         let info = DeviceInfo {
             chip,
             revision,
             crystal_frequency,
             flash_size: self.flash_size,
+            embedded_flash,
+            flash_chip: self.flash_chip,
             features,
             mac_address,
         };
@@ -916,24 +1834,52 @@ impl Flasher {
         Ok(info)
     }
 
+    /// Check the device for conditions which make flashing likely to fail
+    ///
+    /// An unstable power supply is a common cause of failures in the middle of
+    /// flashing. It is detected by the reset reason captured while connecting,
+    /// before the flasher stub is loaded, and by the current output of the
+    /// brownout detector. Unless the device was connected to without resetting
+    /// it, e.g. with [ResetBeforeOperation::NoReset], the reset reason records
+    /// brownouts while the device started the bootloader.
+    pub fn preflight(&mut self) -> Result<PreflightReport, Error> {
+        let brownout_detected = self.target.brownout_detected(&mut self.connection)?;
+        debug!(
+            "Reset reason: {:?}, brownout detected: {brownout_detected:?}",
+            self.reset_reason
+        );
+
+        Ok(PreflightReport {
+            reset_reason: self.reset_reason,
+            brownout_detected,
+        })
+    }
+
     /// Load an ELF image to RAM and execute it
     ///
     /// Note that this will not touch the flash on the device
     pub fn load_elf_to_ram(
         &mut self,
         elf_data: &[u8],
-        mut progress: Option<&mut dyn ProgressCallbacks>,
+        progress: Option<&mut dyn ProgressCallbacks>,
     ) -> Result<(), Error> {
         let image = ElfFirmwareImage::try_from(elf_data)?;
         if image.rom_segments(self.chip).next().is_some() {
             return Err(Error::ElfNotRamLoadable);
         }
 
+        let sizes = image
+            .ram_segments(self.chip)
+            .map(|segment| (segment.addr, segment.data().len()))
+            .collect();
+        let mut tracker = progress.map(|progress| RateTracker::new(progress, sizes));
+        let mut progress = tracker
+            .as_mut()
+            .map(|tracker| tracker as &mut dyn ProgressCallbacks);
+
         let mut target = self.chip.ram_target(
             Some(image.entry()),
-            self.chip
-                .into_target()
-                .max_ram_block_size(&mut self.connection)?,
+            self.target.max_ram_block_size(&mut self.connection)?,
         );
         target.begin(&mut self.connection).flashing()?;
 
@@ -951,37 +1897,85 @@ impl Flasher {
         &mut self,
         elf_data: &[u8],
         flash_data: FlashData,
-        mut progress: Option<&mut dyn ProgressCallbacks>,
+        progress: Option<&mut dyn ProgressCallbacks>,
         xtal_freq: XtalFrequency,
     ) -> Result<(), Error> {
-        let image = ElfFirmwareImage::try_from(elf_data)?;
+        let image = ElfFirmwareImage::try_from(elf_data)?
+            .with_segment_filter(flash_data.segment_filter.clone())?;
 
-        let mut target =
-            self.chip
-                .flash_target(self.spi_params, self.use_stub, self.verify, self.skip);
-        target.begin(&mut self.connection).flashing()?;
+        self.load_image_to_flash(&image, flash_data, progress, xtal_freq)
+    }
+
+    /// Load a firmware image, e.g. a prebuilt [AppImage], to flash along with
+    /// the bootloader and partition table
+    ///
+    /// [AppImage]: crate::image_format::AppImage
+    pub fn load_image_to_flash<'a>(
+        &mut self,
+        image: &'a dyn FirmwareImage<'a>,
+        mut flash_data: FlashData,
+        progress: Option<&mut dyn ProgressCallbacks>,
+        xtal_freq: XtalFrequency,
+    ) -> Result<(), Error> {
+        let app_only = flash_data.app_only;
+
+        self.check_flash_size(&flash_data.flash_settings)?;
+        // Without an explicit flash size, the header declares the detected one
+        if flash_data.flash_settings.size.is_none() {
+            flash_data.flash_settings.size = self.detected_flash_size;
+        }
 
-        let chip_revision = Some(
-            self.chip
-                .into_target()
-                .chip_revision(&mut self.connection)?,
+        let mut target = self.chip.flash_target(
+            self.spi_params,
+            self.use_stub,
+            self.verify,
+            self.skip,
+            self.digest,
+            self.encryption.clone(),
         );
+        target.begin(&mut self.connection).flashing()?;
+        self.apply_spi_clock_divider()?;
 
-        let image = self.chip.into_target().get_flash_image(
-            &image,
-            flash_data,
-            chip_revision,
-            xtal_freq,
-        )?;
+        let chip_revision = Some(self.target.chip_revision(&mut self.connection)?);
+
+        let image = self
+            .target
+            .get_flash_image(image, flash_data, chip_revision, xtal_freq)?;
 
         // When the `cli` feature is enabled, display the image size information.
+        // The output format of the command is not known here, so it goes to
+        // stderr like the logs, keeping stdout free for the results.
         #[cfg(feature = "cli")]
-        crate::cli::display_image_size(image.app_size(), image.part_size());
+        crate::cli::display_image_size(
+            crate::cli::report::OutputFormat::Json,
+            image.app_size(),
+            image.part_size(),
+        );
 
-        for segment in image.flash_segments() {
-            target
-                .write_segment(&mut self.connection, segment, &mut progress)
-                .flashing()?;
+        let segments = || {
+            if app_only {
+                image.app_segments()
+            } else {
+                image.flash_segments()
+            }
+        };
+
+        let (segments, beyond) = self.split_unreachable(segments())?;
+        for segment in &beyond {
+            self.write_flash_with_spi_commands(segment.addr, &segment.data)?;
+        }
+
+        let sizes = segments
+            .iter()
+            .map(|segment| (segment.addr, segment.data.len()))
+            .collect();
+        let mut tracker = progress.map(|progress| RateTracker::new(progress, sizes));
+        let mut progress = tracker
+            .as_mut()
+            .map(|tracker| tracker as &mut dyn ProgressCallbacks);
+
+        for segment in segments {
+            self.write_segment_with_retry(target.as_mut(), segment, &mut progress)?;
         }
 
         target.finish(&mut self.connection, true).flashing()?;
@@ -989,6 +1983,20 @@ impl Flasher {
         Ok(())
     }
 
+    /// Ensure the flash size explicitly requested for the image header does
+    /// not exceed the size reported by the device's flash chip
+    fn check_flash_size(&self, flash_settings: &FlashSettings) -> Result<(), Error> {
+        let (Some(header), Some(detected)) = (flash_settings.size, self.detected_flash_size) else {
+            return Ok(());
+        };
+
+        if header.size() > detected.size() {
+            return Err(Error::FlashSizeExceedsDetected { header, detected });
+        }
+
+        Ok(())
+    }
+
     /// Load an bin image to flash at a specific address
     pub fn write_bin_to_flash(
         &mut self,
@@ -1011,20 +2019,128 @@ impl Flasher {
     pub fn write_bins_to_flash(
         &mut self,
         segments: &[RomSegment],
-        mut progress: Option<&mut dyn ProgressCallbacks>,
+        progress: Option<&mut dyn ProgressCallbacks>,
     ) -> Result<(), Error> {
-        let mut target = self
-            .chip
-            .flash_target(self.spi_params, self.use_stub, false, false);
+        let (segments, beyond) = self.split_unreachable(segments.iter().map(RomSegment::borrow))?;
+
+        let sizes = segments
+            .iter()
+            .map(|segment| (segment.addr, segment.data.len()))
+            .collect();
+        let mut tracker = progress.map(|progress| RateTracker::new(progress, sizes));
+        let mut progress = tracker
+            .as_mut()
+            .map(|tracker| tracker as &mut dyn ProgressCallbacks);
+
+        for segment in &beyond {
+            self.write_flash_with_spi_commands(segment.addr, &segment.data)?;
+        }
+
+        let mut target = self.chip.flash_target(
+            self.spi_params,
+            self.use_stub,
+            false,
+            false,
+            self.digest,
+            self.encryption.clone(),
+        );
         target.begin(&mut self.connection).flashing()?;
+        self.apply_spi_clock_divider()?;
         for segment in segments {
-            target.write_segment(&mut self.connection, segment.borrow(), &mut progress)?;
+            self.write_segment_with_retry(target.as_mut(), segment, &mut progress)?;
         }
         target.finish(&mut self.connection, true).flashing()?;
 
         Ok(())
     }
 
+    /// Split `segments` into the parts which the loader reaches, and the parts
+    /// beyond the first 16 MB which are written with SPI flash commands instead
+    ///
+    /// Encrypted segments can only be written by the loader, so they fail if
+    /// it does not reach them.
+    fn split_unreachable<'a>(
+        &self,
+        segments: impl IntoIterator<Item = RomSegment<'a>>,
+    ) -> Result<(Vec<RomSegment<'a>>, Vec<RomSegment<'a>>), Error> {
+        let capabilities = self.capabilities();
+        let mut reachable = Vec::new();
+        let mut beyond = Vec::new();
+
+        for segment in segments {
+            let size = segment.data.len() as u32;
+            if capabilities
+                .require_flash_region(segment.addr, size)
+                .is_ok()
+            {
+                reachable.push(segment);
+            } else if self.encryption.is_none() && segment.addr.checked_add(size).is_some() {
+                let (low, high) =
+                    split_segment(segment, capabilities::THREE_BYTE_ADDRESS_LIMIT as u32);
+                reachable.extend(low);
+                beyond.extend(high);
+            } else {
+                return Err(Error::FlashAddressUnreachable {
+                    chip: self.chip,
+                    offset: segment.addr,
+                    size,
+                });
+            }
+        }
+
+        Ok((reachable, beyond))
+    }
+
+    /// Write `data` to flash at `addr` with program commands sent to the flash
+    /// chip, using 4-byte addresses
+    ///
+    /// This reaches flash beyond the first 16 MB, which the loaders other than
+    /// the flasher stub of the ESP32-S3 cannot address, but is slow, as each
+    /// command programs at most 60 bytes. The sectors containing the data are
+    /// erased first, and the data is read back if verification is enabled.
+    fn write_flash_with_spi_commands(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        info!(
+            "Writing 0x{:x}B to 0x{:08x} beyond the first 16 MB with SPI flash commands",
+            data.len(),
+            addr
+        );
+
+        let sector_size = FLASH_SECTOR_SIZE as u32;
+        let end = addr + data.len() as u32;
+        for sector in (addr - addr % sector_size..end).step_by(FLASH_SECTOR_SIZE) {
+            self.spi_command(SPI_WRITE_ENABLE, &[], 0)?;
+            self.spi_command(SPI_SECTOR_ERASE_4B, &sector.to_be_bytes(), 0)?;
+            self.wait_flash_idle()?;
+        }
+
+        let start = Instant::now();
+        let mut written = 0;
+        while written < data.len() {
+            let chunk_addr = addr + written as u32;
+            let len = (data.len() - written)
+                .min(SPI_COMMAND_MAX_DATA - 4)
+                .min((SPI_PAGE_SIZE - chunk_addr % SPI_PAGE_SIZE) as usize);
+            let command = [&chunk_addr.to_be_bytes()[..], &data[written..][..len]].concat();
+
+            self.spi_command(SPI_WRITE_ENABLE, &[], 0)?;
+            self.spi_command(SPI_PAGE_PROGRAM_4B, &command, 0)?;
+            self.wait_flash_idle()?;
+            written += len;
+        }
+        self.flash_stats.duration += start.elapsed();
+        self.flash_stats.bytes += data.len() as u64;
+
+        if self.verify
+            && self.read_flash_region(addr, data.len() as u32, FLASH_SECTOR_SIZE as u32, 64)?
+                != data
+        {
+            self.flash_stats.verify_failures += 1;
+            return Err(Error::VerifyFailed);
+        }
+
+        Ok(())
+    }
+
     /// Get MD5 of region
     pub fn checksum_md5(&mut self, addr: u32, length: u32) -> Result<u128, Error> {
         self.connection
@@ -1038,15 +2154,83 @@ impl Flasher {
             })
     }
 
+    /// Compare the flash at `addr` with `data`, returning the address of the
+    /// first byte which differs, if any
+    ///
+    /// The flash is compared by the MD5 digests of ever smaller regions, so
+    /// only the small region containing the difference is read back.
+    pub fn verify_flash(&mut self, addr: u32, data: &[u8]) -> Result<Option<u32>, Error> {
+        let offset = first_difference(
+            self,
+            data,
+            |flasher, offset, expected| {
+                let digest = flasher.checksum_md5(addr + offset, expected.len() as u32)?;
+                Ok(digest == u128::from_be_bytes(Md5::digest(expected).into()))
+            },
+            |flasher, offset, len| {
+                flasher.read_flash_region(addr + offset, len, FLASH_SECTOR_SIZE as u32, 64)
+            },
+        )?;
+
+        Ok(offset.map(|offset| addr + offset))
+    }
+
+    /// Read `size` bytes of memory starting at `addr`, e.g. RAM or peripheral
+    /// registers, one word at a time
+    pub fn read_mem(
+        &mut self,
+        addr: u32,
+        size: u32,
+        mut progress: Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<Vec<u8>, Error> {
+        if addr % 4 != 0 || addr as u64 + size as u64 > 1 << 32 {
+            return Err(Error::InvalidMemoryRegion { addr, size });
+        }
+
+        let words = size.div_ceil(4);
+        let mut data = Vec::with_capacity(words as usize * 4);
+
+        if let Some(cb) = progress.as_mut() {
+            cb.init(addr, size as usize);
+        }
+        for i in 0..words {
+            if progress.as_mut().is_some_and(|cb| cb.cancelled()) {
+                return Err(Error::Cancelled);
+            }
+
+            let word = self.connection.read_reg(addr + i * 4)?;
+            data.extend_from_slice(&word.to_le_bytes());
+
+            if let Some(cb) = progress.as_mut() {
+                cb.update(data.len().min(size as usize));
+            }
+        }
+        if let Some(cb) = progress.as_mut() {
+            cb.finish();
+        }
+
+        data.truncate(size as usize);
+        Ok(data)
+    }
+
     pub fn change_baud(&mut self, speed: u32) -> Result<(), Error> {
         debug!("Change baud to: {}", speed);
 
+        // USB CDC transports are not limited by a UART clock, so there is nothing
+        // to reconfigure on the device side. Only update the host port.
+        if self.connection.is_usb_cdc() {
+            debug!("USB CDC transport detected, skipping device baud rate change");
+            self.connection.set_baud(speed)?;
+            self.connection.session_mut().baud = Some((speed, speed));
+            return Ok(());
+        }
+
         let prior_baud = match self.use_stub {
             true => self.connection.get_baud()?,
             false => 0,
         };
 
-        let target = self.chip.into_target();
+        let target = self.target.clone();
         let xtal_freq = target.crystal_freq(&mut self.connection)?;
 
         // Probably this is just a temporary solution until the next chip revision.
@@ -1066,6 +2250,7 @@ impl Flasher {
                 })
             })?;
         self.connection.set_baud(speed)?;
+        self.connection.session_mut().baud = Some((new_baud, speed));
         sleep(Duration::from_secs_f32(0.05));
         self.connection.flush()?;
 
@@ -1080,7 +2265,17 @@ impl Flasher {
         self.connection.get_usb_pid()
     }
 
+    /// Erase a region of flash
+    ///
+    /// Without the flasher stub, the region is erased by beginning a flash
+    /// write of its size, which the ROM loader erases before any data is
+    /// sent. This also works in Secure Download Mode, but only erases whole
+    /// sectors, so the region must be aligned to sectors.
     pub fn erase_region(&mut self, offset: u32, size: u32) -> Result<(), Error> {
+        self.capabilities().require_flash_region(offset, size)?;
+        if !self.capabilities().supports(CommandType::EraseRegion) {
+            return self.erase_region_with_rom(offset, size);
+        }
         debug!("Erasing region of 0x{:x}B at 0x{:08x}", size, offset);
 
         self.connection.with_timeout(
@@ -1092,7 +2287,21 @@ impl Flasher {
         Ok(())
     }
 
+    /// Erase the entire flash
+    ///
+    /// Without the flasher stub, the flash chip is sent the chip erase command
+    /// directly. In Secure Download Mode, which rejects access to the SPI
+    /// registers, the flash is instead erased by beginning a flash write of
+    /// its whole size.
     pub fn erase_flash(&mut self) -> Result<(), Error> {
+        if !self.capabilities().supports(CommandType::EraseFlash) {
+            if !self.capabilities().supports(CommandType::WriteReg) {
+                let size = self.flash_size.size();
+                self.capabilities().require_flash_region(0, size)?;
+                return self.erase_region_with_rom(0, size);
+            }
+            return self.erase_flash_with_spi_commands();
+        }
         debug!("Erasing the entire flash");
 
         self.connection
@@ -1105,78 +2314,165 @@ impl Flasher {
         Ok(())
     }
 
-    pub fn read_flash(
+    /// Erase the entire flash region by region, reporting the progress
+    ///
+    /// Erasing large flash chips takes minutes, which a single erase command
+    /// gives no feedback on. The erase stops with [Error::Cancelled] between
+    /// two regions once [ProgressCallbacks::cancelled] returns `true`. When
+    /// the size of the flash could not be detected, the whole flash is erased
+    /// at once instead.
+    pub fn erase_flash_with_progress(
         &mut self,
-        offset: u32,
-        size: u32,
-        block_size: u32,
-        max_in_flight: u32,
-        file_path: PathBuf,
+        mut progress: Option<&mut dyn ProgressCallbacks>,
     ) -> Result<(), Error> {
-        debug!("Reading 0x{:x}B from 0x{:08x}", size, offset);
-
-        let mut data = Vec::new();
+        let Some(flash_size) = self.detected_flash_size else {
+            warn!("The flash size could not be detected, erasing the flash without progress");
+            return self.erase_flash();
+        };
+        let size = flash_size.size();
+        if self.capabilities().require_flash_region(0, size).is_err() {
+            warn!("The loader cannot address all of the flash, erasing it without progress");
+            return self.erase_flash();
+        }
+        debug!("Erasing the entire flash of 0x{:x}B in regions", size);
 
-        let mut file = fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(&file_path)?;
+        if let Some(cb) = progress.as_mut() {
+            cb.init(0, size.div_ceil(ERASE_CHUNK_SIZE) as usize)
+        }
 
-        self.connection
-            .with_timeout(CommandType::ReadFlash.timeout(), |connection| {
-                connection.command(Command::ReadFlash {
-                    offset,
-                    size,
-                    block_size,
-                    max_in_flight,
-                })
-            })?;
+        for (i, offset) in (0..size).step_by(ERASE_CHUNK_SIZE as usize).enumerate() {
+            if progress.as_mut().is_some_and(|cb| cb.cancelled()) {
+                return Err(Error::Cancelled);
+            }
 
-        while data.len() < size as usize {
-            let response = self.connection.read_response()?;
-            let chunk: Vec<u8> = if let Some(response) = response {
-                response.value.try_into().unwrap()
+            let len = ERASE_CHUNK_SIZE.min(size - offset);
+            if self.capabilities().supports(CommandType::EraseRegion) {
+                self.connection.with_timeout(
+                    CommandType::EraseRegion.timeout_for_size(len),
+                    |connection| connection.command(Command::EraseRegion { offset, size: len }),
+                )?;
             } else {
-                return Err(Error::IncorrectReposnse);
-            };
-
-            data.extend_from_slice(&chunk);
+                self.erase_region_with_rom(offset, len)?;
+            }
 
-            if data.len() < size as usize && chunk.len() < block_size as usize {
-                return Err(Error::CorruptData(block_size as usize, chunk.len()));
+            if let Some(cb) = progress.as_mut() {
+                cb.update(i + 1)
             }
+        }
 
-            self.connection.write_raw(data.len() as u32)?;
+        if let Some(cb) = progress.as_mut() {
+            cb.finish()
         }
 
-        if data.len() > size as usize {
-            return Err(Error::ReadMoreThanExpected);
+        Ok(())
+    }
+
+    /// Erase a region of flash with the ROM loader, by beginning a flash write
+    /// without sending any data
+    fn erase_region_with_rom(&mut self, offset: u32, size: u32) -> Result<(), Error> {
+        let sector_size = FLASH_SECTOR_SIZE as u32;
+        if offset % sector_size != 0 || size % sector_size != 0 {
+            return Err(Error::UnalignedEraseRegion { offset, size });
         }
+        debug!(
+            "Erasing region of 0x{:x}B at 0x{:08x} with the ROM loader",
+            size, offset
+        );
 
-        let response = self.connection.read_response()?;
-        let digest: Vec<u8> = if let Some(response) = response {
-            response.value.try_into().unwrap()
+        // No data is written, and the registers telling the size of the
+        // blocks apart cannot be read in Secure Download Mode
+        let block_size = if self.secure_download_mode {
+            FLASH_WRITE_SIZE as u32
         } else {
-            return Err(Error::IncorrectReposnse);
+            self.target.flash_write_size(&mut self.connection)? as u32
         };
+        self.connection.with_timeout(
+            CommandType::FlashBegin.timeout_for_size(size),
+            |connection| {
+                connection.command(Command::FlashBegin {
+                    size,
+                    blocks: 0,
+                    block_size,
+                    offset,
+                    supports_encryption: self.chip != Chip::Esp32,
+                    encrypted: false,
+                })
+            },
+        )?;
+
+        Ok(())
+    }
 
-        if digest.len() != 16 {
-            return Err(Error::IncorrectDigestLength(digest.len()));
+    /// Erase the entire flash with the chip erase command of the flash chip,
+    /// waiting for it to finish
+    fn erase_flash_with_spi_commands(&mut self) -> Result<(), Error> {
+        debug!("Erasing the entire flash with SPI flash commands");
+
+        self.spi_command(SPI_WRITE_ENABLE, &[], 0)?;
+        self.spi_command(SPI_CHIP_ERASE, &[], 0)?;
+
+        let start = Instant::now();
+        while self.read_flash_status(1)? & SPI_STATUS_WRITE_IN_PROGRESS != 0 {
+            if start.elapsed() > CommandType::EraseFlash.timeout() {
+                return Err(Error::Connection(ConnectionError::Timeout(
+                    TimedOutCommand::default(),
+                )));
+            }
+            sleep(Duration::from_millis(100));
         }
 
-        let mut md5_hasher = Md5::new();
-        md5_hasher.update(&data);
-        let checksum_md5 = md5_hasher.finalize();
+        Ok(())
+    }
 
-        if digest != checksum_md5.as_slice() {
-            return Err(Error::DigestMissmatch(
-                digest,
-                checksum_md5.as_slice().to_vec(),
-            ));
+    pub fn read_flash(
+        &mut self,
+        offset: u32,
+        size: u32,
+        block_size: u32,
+        max_in_flight: u32,
+        file_path: PathBuf,
+    ) -> Result<(), Error> {
+        let mut file = OutputFile::create(&file_path)?;
+        let mut progress = ReadProgress {
+            offset,
+            size,
+            completed: 0,
+        };
+
+        // The region is read in chunks, so that an interrupted read leaves behind
+        // the data read so far along with a record of how far it got
+        while progress.completed < size {
+            let len = (size - progress.completed).min(READ_CHUNK_SIZE);
+            let data = match self.read_flash_region(
+                offset + progress.completed,
+                len,
+                block_size,
+                max_in_flight,
+            ) {
+                Ok(data) => data,
+                Err(e) if progress.completed > 0 => {
+                    let partial_path = file.keep_partial()?;
+                    warn!(
+                        "Read failed after 0x{:x} bytes, the data read so far was kept in '{}'",
+                        progress.completed,
+                        partial_path.display()
+                    );
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            };
+
+            file.write_all(&data)?;
+            progress.completed += len;
+
+            if progress.completed < size {
+                file.flush()?;
+                progress.save(&file_path)?;
+            }
         }
 
-        file.write_all(&data)?;
+        file.persist()?;
+        fs::remove_file(ReadProgress::path(&file_path)).ok();
 
         info!(
             "Flash content successfully read and written to '{}'!",
@@ -1186,8 +2482,87 @@ impl Flasher {
         Ok(())
     }
 
+    /// Read a region of flash memory, verifying its MD5 digest
+    ///
+    /// Without the flasher stub, the ROM loader of the ESP32 reads the region
+    /// with [CommandType::ReadFlashSlow] instead, and the ROM loaders of the
+    /// other chips send read commands to the flash chip through the SPI
+    /// registers, 64 bytes at a time, see [FlashReader].
+    pub fn read_flash_region(
+        &mut self,
+        offset: u32,
+        size: u32,
+        block_size: u32,
+        max_in_flight: u32,
+    ) -> Result<Vec<u8>, Error> {
+        FlashReader::new(&mut self.connection, self.chip, self.use_stub).read_verified(
+            offset,
+            size,
+            block_size,
+            max_in_flight,
+        )
+    }
+
+    /// Read the partition table stored on the device at the given offset
+    pub fn read_partition_table(&mut self, offset: u32) -> Result<PartitionTable, Error> {
+        let data = self.read_flash_region(
+            offset,
+            MAX_PARTITION_TABLE_SIZE,
+            FLASH_SECTOR_SIZE as u32,
+            64,
+        )?;
+
+        Ok(PartitionTable::try_from_bytes(data)?)
+    }
+
+    /// Find the partition table stored on the device, returning its offset
+    /// along with the table
+    ///
+    /// The offset of the partition table is configurable in ESP-IDF, so the
+    /// commonly used offsets are checked for a partition table entry, as well
+    /// as the first sector following the bootloader.
+    pub fn find_partition_table(&mut self) -> Result<(u32, PartitionTable), Error> {
+        let mut offsets = PARTITION_TABLE_OFFSETS.to_vec();
+        match self.bootloader_end() {
+            Ok(end) if !offsets.contains(&end) => offsets.push(end),
+            Ok(_) => {}
+            Err(e) => debug!("Failed to find the end of the bootloader: {e}"),
+        }
+
+        for &offset in &offsets {
+            let magic = self.read_flash_region(
+                offset,
+                PARTITION_ENTRY_MAGIC.len() as u32,
+                FLASH_SECTOR_SIZE as u32,
+                64,
+            )?;
+            if magic == PARTITION_ENTRY_MAGIC {
+                debug!("Found the partition table at {offset:#x}");
+                return Ok((offset, self.read_partition_table(offset)?));
+            }
+        }
+
+        Err(Error::PartitionTableNotFound(
+            offsets
+                .iter()
+                .map(|offset| format!("{offset:#x}"))
+                .collect::<Vec<_>>()
+                .join(", "),
+        ))
+    }
+
+    /// The offset of the first flash sector following the bootloader
+    fn bootloader_end(&mut self) -> Result<u32, Error> {
+        let boot_addr = self.chip.metadata().boot_addr;
+        let len = read_image_len(|offset, len| {
+            self.read_flash_region(boot_addr + offset, len, FLASH_SECTOR_SIZE as u32, 64)
+        })?;
+
+        Ok((boot_addr + len).next_multiple_of(FLASH_SECTOR_SIZE as u32))
+    }
+
     pub fn verify_minimum_revision(&mut self, minimum: u16) -> Result<(), Error> {
-        let (major, minor) = self.chip.into_target().chip_revision(self.connection())?;
+        let (major, minor) = self.target.chip_revision(&mut self.connection)?;
         let revision = (major * 100 + minor) as u16;
         if revision < minimum {
             return Err(Error::UnsupportedChipRevision {
@@ -1201,3 +2576,494 @@ impl Flasher {
         Ok(())
     }
 }
+
+#[cfg(feature = "serialport")]
+/// Reads flash through the loader running on a device, with the fastest read
+/// path it supports
+///
+/// The flasher stub streams the region with [CommandType::ReadFlash], followed
+/// by its MD5 digest. Without the stub, the ROM loader of the ESP32 reads it
+/// with [CommandType::ReadFlashSlow], and the ROM loaders of the other chips
+/// send read commands to the flash chip through the SPI registers, 64 bytes at
+/// a time. Neither is possible in Secure Download Mode. The read commands use
+/// 4-byte addresses beyond the first 16 MB, so unlike the loaders they reach
+/// all of a large flash chip, and are used for regions the loader does not
+/// reach.
+pub struct FlashReader<'a> {
+    connection: &'a mut Connection,
+    chip: Chip,
+    use_stub: bool,
+}
+
+#[cfg(feature = "serialport")]
+impl<'a> FlashReader<'a> {
+    pub fn new(connection: &'a mut Connection, chip: Chip, use_stub: bool) -> Self {
+        Self {
+            connection,
+            chip,
+            use_stub,
+        }
+    }
+
+    /// The connection to the loader
+    pub fn connection(&mut self) -> &mut Connection {
+        self.connection
+    }
+
+    /// Read a region of flash memory
+    ///
+    /// Only the data streamed by the flasher stub is checked against an MD5
+    /// digest, the slower read paths do not need the loader to support
+    /// [CommandType::FlashMd5]. `block_size` and `max_in_flight` only apply to
+    /// the flasher stub.
+    pub fn read(
+        &mut self,
+        offset: u32,
+        size: u32,
+        block_size: u32,
+        max_in_flight: u32,
+    ) -> Result<Vec<u8>, Error> {
+        let capabilities = Capabilities::new(self.chip, self.use_stub);
+        let reachable = capabilities.require_flash_region(offset, size).is_ok();
+        if !reachable && offset.checked_add(size).is_none() {
+            return Err(Error::FlashAddressUnreachable {
+                chip: self.chip,
+                offset,
+                size,
+            });
+        }
+        if !reachable
+            || !capabilities.supports(CommandType::ReadFlash)
+                && !capabilities.supports(CommandType::ReadFlashSlow)
+        {
+            return self.read_with_spi_commands(offset, size);
+        }
+
+        if !capabilities.supports(CommandType::ReadFlash) {
+            return self.connection.read_flash_slow(offset, size);
+        }
+
+        self.connection
+            .read_flash_region(offset, size, block_size, max_in_flight)
+    }
+
+    /// Read a region of flash memory, verifying its MD5 digest
+    ///
+    /// The digest of the slower read paths is computed by the loader, where it
+    /// reaches the region.
+    pub fn read_verified(
+        &mut self,
+        offset: u32,
+        size: u32,
+        block_size: u32,
+        max_in_flight: u32,
+    ) -> Result<Vec<u8>, Error> {
+        let data = self.read(offset, size, block_size, max_in_flight)?;
+
+        // The loader digests flash with 3-byte addresses, which would wrap
+        // around at 16 MB
+        let capabilities = Capabilities::new(self.chip, self.use_stub);
+        if capabilities.supports(CommandType::ReadFlash)
+            || capabilities.require_flash_region(offset, size).is_err()
+        {
+            return Ok(data);
+        }
+
+        let digest: u128 =
+            self.connection
+                .with_timeout(CommandType::FlashMd5.timeout(), |connection| {
+                    connection
+                        .command(crate::command::Command::FlashMd5 { offset, size })?
+                        .try_into()
+                })?;
+        let checksum_md5 = u128::from_be_bytes(Md5::digest(&data).into());
+        if digest != checksum_md5 {
+            return Err(Error::DigestMissmatch(
+                digest.to_be_bytes().to_vec(),
+                checksum_md5.to_be_bytes().to_vec(),
+            ));
+        }
+
+        Ok(data)
+    }
+
+    /// Read a region of flash memory with read commands sent to the flash chip
+    fn read_with_spi_commands(&mut self, offset: u32, size: u32) -> Result<Vec<u8>, Error> {
+        debug!(
+            "Reading 0x{:x}B from 0x{:08x} with SPI flash commands",
+            size, offset
+        );
+
+        let mut data = Vec::with_capacity(size as usize);
+        while data.len() < size as usize {
+            let addr = offset + data.len() as u32;
+            let len = (size as usize - data.len()).min(SPI_COMMAND_MAX_DATA);
+            let (opcode, addr) = spi_read_command(addr, len as u32);
+            let words = spi_transaction(
+                self.connection,
+                &self.chip.into_target().spi_registers(),
+                opcode,
+                &addr,
+                len as u32 * 8,
+            )?;
+            data.extend(words.iter().flat_map(|word| word.to_le_bytes()).take(len));
+        }
+
+        Ok(data)
+    }
+}
+
+#[cfg(feature = "serialport")]
+/// Load `stub` into the RAM of the device and start it, writing RAM in blocks
+/// of up to `max_ram_block_size` bytes
+pub(crate) fn load_stub(
+    connection: &mut Connection,
+    stub: &FlashStub,
+    max_ram_block_size: usize,
+) -> Result<(), Error> {
+    let mut ram_target = RamTarget::new(Some(stub.entry()), max_ram_block_size);
+    ram_target.begin(connection).flashing()?;
+
+    let (text_addr, text) = stub.text();
+    debug!("Write {} byte stub text", text.len());
+
+    ram_target
+        .write_segment(
+            connection,
+            RomSegment {
+                addr: text_addr,
+                data: Cow::Borrowed(&text),
+            },
+            &mut None,
+        )
+        .flashing()?;
+
+    let (data_addr, data) = stub.data();
+    debug!("Write {} byte stub data", data.len());
+
+    ram_target
+        .write_segment(
+            connection,
+            RomSegment {
+                addr: data_addr,
+                data: Cow::Borrowed(&data),
+            },
+            &mut None,
+        )
+        .flashing()?;
+
+    debug!("Finish stub write");
+    ram_target.finish(connection, true).flashing()?;
+
+    debug!("Stub written!");
+
+    match connection.read(EXPECTED_STUB_HANDSHAKE.len())? {
+        Some(resp) if resp == EXPECTED_STUB_HANDSHAKE.as_bytes() => Ok(()),
+        _ => Err(Error::Connection(ConnectionError::InvalidStubHandshake)),
+    }?;
+
+    // Re-detect chip to check stub is up
+    let magic = connection
+        .settle_after_stub(|connection| connection.read_reg(CHIP_DETECT_MAGIC_REG_ADDR))?;
+    let chip = Chip::from_magic(magic)?;
+    debug!("Re-detected chip: {:?}", chip);
+
+    Ok(())
+}
+
+#[cfg(feature = "serialport")]
+/// Security information reported by the ROM loader
+struct SecurityInfo {
+    flags: u32,
+    /// ID of the chip, which the ROM loader of the ESP32-S2 does not report
+    chip_id: Option<u32>,
+}
+
+#[cfg(feature = "serialport")]
+impl SecurityInfo {
+    fn read(connection: &mut Connection) -> Result<Self, Error> {
+        let response: Vec<u8> = connection
+            .with_timeout(CommandType::GetSecurityInfo.timeout(), |connection| {
+                connection.command(Command::GetSecurityInfo)
+            })?
+            .try_into()?;
+
+        // The response is the header of 8 bytes, the security information and
+        // 4 status bytes
+        let info = response
+            .get(8..response.len().saturating_sub(4))
+            .filter(|info| info.len() >= 12)
+            .ok_or(Error::InternalError)?;
+        let word = |offset: usize| {
+            info.get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+
+        Ok(Self {
+            flags: word(0).unwrap(),
+            chip_id: word(12),
+        })
+    }
+
+    fn secure_download_mode(&self) -> bool {
+        self.flags & SECURITY_FLAG_SECURE_DOWNLOAD != 0
+    }
+
+    /// The chip with the reported ID
+    fn chip(&self) -> Option<Chip> {
+        let chip_id = self.chip_id?;
+        Chip::iter().find(|chip| u32::from(chip.into_target().params().chip_id) == chip_id)
+    }
+}
+
+#[cfg(feature = "serialport")]
+/// Run a command on the SPI flash chip, returning the words of the response,
+/// or the first data word when there is no response
+fn spi_transaction(
+    connection: &mut Connection,
+    spi_registers: &SpiRegisters,
+    opcode: u8,
+    data: &[u8],
+    read_bits: u32,
+) -> Result<Vec<u32>, Error> {
+    let old_spi_usr = connection.read_reg(spi_registers.usr())?;
+    let old_spi_usr2 = connection.read_reg(spi_registers.usr2())?;
+
+    let mut flags = 1 << 31;
+    if !data.is_empty() {
+        flags |= 1 << 27;
+    }
+    if read_bits > 0 {
+        flags |= 1 << 28;
+    }
+
+    connection.write_reg(spi_registers.usr(), flags, None)?;
+    connection.write_reg(spi_registers.usr2(), 7 << 28 | opcode as u32, None)?;
+
+    if let (Some(mosi_data_length), Some(miso_data_length)) =
+        (spi_registers.mosi_length(), spi_registers.miso_length())
+    {
+        if !data.is_empty() {
+            connection.write_reg(mosi_data_length, data.len() as u32 * 8 - 1, None)?;
+        }
+        if read_bits > 0 {
+            connection.write_reg(miso_data_length, read_bits - 1, None)?;
+        }
+    } else {
+        let mosi_mask = if data.is_empty() {
+            0
+        } else {
+            data.len() as u32 * 8 - 1
+        };
+        let miso_mask = if read_bits == 0 { 0 } else { read_bits - 1 };
+        connection.write_reg(spi_registers.usr1(), miso_mask << 8 | mosi_mask << 17, None)?;
+    }
+
+    if data.is_empty() {
+        connection.write_reg(spi_registers.w0(), 0, None)?;
+    } else {
+        for (i, bytes) in data.chunks(4).enumerate() {
+            let mut data_bytes = [0; 4];
+            data_bytes[0..bytes.len()].copy_from_slice(bytes);
+            let data = u32::from_le_bytes(data_bytes);
+            connection.write_reg(spi_registers.w0() + i as u32 * 4, data, None)?;
+        }
+    }
+
+    connection.write_reg(spi_registers.cmd(), 1 << 18, None)?;
+
+    let mut i = 0;
+    loop {
+        sleep(Duration::from_millis(1));
+        if connection.read_reg(spi_registers.usr())? & (1 << 18) == 0 {
+            break;
+        }
+        i += 1;
+        if i > 10 {
+            return Err(Error::Connection(ConnectionError::Timeout(
+                TimedOutCommand::default(),
+            )));
+        }
+    }
+
+    let result = (0..read_bits.div_ceil(32).max(1))
+        .map(|i| connection.read_reg(spi_registers.w0() + i * 4))
+        .collect::<Result<Vec<_>, _>>()?;
+    connection.write_reg(spi_registers.usr(), old_spi_usr, None)?;
+    connection.write_reg(spi_registers.usr2(), old_spi_usr2, None)?;
+
+    Ok(result)
+}
+
+#[cfg(feature = "serialport")]
+/// The offset of the first byte of `expected` which differs, found by
+/// bisecting with `matches`, which compares a region at an offset with its
+/// expected contents, and reading the last region with `read`
+fn first_difference<T>(
+    flash: &mut T,
+    expected: &[u8],
+    matches: impl Fn(&mut T, u32, &[u8]) -> Result<bool, Error>,
+    read: impl Fn(&mut T, u32, u32) -> Result<Vec<u8>, Error>,
+) -> Result<Option<u32>, Error> {
+    if expected.is_empty() || matches(flash, 0, expected)? {
+        return Ok(None);
+    }
+
+    // The region from `start` to `end` differs, and so does its first half
+    // unless the second half does
+    let (mut start, mut end) = (0, expected.len());
+    while end - start > VERIFY_READ_SIZE {
+        let mid = start + (end - start) / 2;
+        if matches(flash, start as u32, &expected[start..mid])? {
+            start = mid;
+        } else {
+            end = mid;
+        }
+    }
+
+    let actual = read(flash, start as u32, (end - start) as u32)?;
+    let offset = expected[start..end]
+        .iter()
+        .zip(&actual)
+        .position(|(expected, actual)| expected != actual)
+        .unwrap_or(actual.len().min(end - start));
+
+    Ok(Some((start + offset) as u32))
+}
+
+#[cfg(feature = "serialport")]
+/// Split `segment` into the parts before and from the flash address `at`
+fn split_segment(
+    segment: RomSegment<'_>,
+    at: u32,
+) -> (Option<RomSegment<'_>>, Option<RomSegment<'_>>) {
+    if segment.addr as u64 + segment.data.len() as u64 <= at as u64 {
+        return (Some(segment), None);
+    }
+    if segment.addr >= at {
+        return (None, Some(segment));
+    }
+
+    let mid = (at - segment.addr) as usize;
+    let (low, high) = match segment.data {
+        Cow::Borrowed(data) => {
+            let (low, high) = data.split_at(mid);
+            (Cow::Borrowed(low), Cow::Borrowed(high))
+        }
+        Cow::Owned(mut data) => {
+            let high = data.split_off(mid);
+            (Cow::Owned(data), Cow::Owned(high))
+        }
+    };
+
+    (
+        Some(RomSegment {
+            addr: segment.addr,
+            data: low,
+        }),
+        Some(RomSegment {
+            addr: at,
+            data: high,
+        }),
+    )
+}
+
+#[cfg(feature = "serialport")]
+/// The SPI flash command reading `len` bytes at `addr`, along with its address
+/// bytes, using a 4-byte address when the read goes beyond the first 16 MB
+fn spi_read_command(addr: u32, len: u32) -> (u8, Vec<u8>) {
+    if addr as u64 + len as u64 > capabilities::THREE_BYTE_ADDRESS_LIMIT {
+        (SPI_READ_4B, addr.to_be_bytes().to_vec())
+    } else {
+        (SPI_READ, addr.to_be_bytes()[1..].to_vec())
+    }
+}
+
+#[cfg(all(test, feature = "serialport"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_beyond_16mb_use_4_byte_addresses() {
+        assert_eq!(
+            spi_read_command(0x00ab_cdef, 64),
+            (SPI_READ, vec![0xab, 0xcd, 0xef])
+        );
+        assert_eq!(
+            spi_read_command(0x00ff_ffc0, 64),
+            (SPI_READ, vec![0xff, 0xff, 0xc0])
+        );
+        assert_eq!(
+            spi_read_command(0x00ff_ffe0, 64),
+            (SPI_READ_4B, vec![0x00, 0xff, 0xff, 0xe0])
+        );
+        assert_eq!(
+            spi_read_command(0x0123_4560, 64),
+            (SPI_READ_4B, vec![0x01, 0x23, 0x45, 0x60])
+        );
+    }
+
+    #[test]
+    fn segments_are_split_at_16mb() {
+        let limit = capabilities::THREE_BYTE_ADDRESS_LIMIT as u32;
+        let segment = |addr| RomSegment {
+            addr,
+            data: Cow::Owned(vec![0xaa; 0x2000]),
+        };
+
+        let (low, high) = split_segment(segment(0x00ff_f000), limit);
+        let (low, high) = (low.unwrap(), high.unwrap());
+        assert_eq!((low.addr, low.data.len()), (0x00ff_f000, 0x1000));
+        assert_eq!((high.addr, high.data.len()), (0x0100_0000, 0x1000));
+
+        assert!(matches!(
+            split_segment(segment(0x00ff_e000), limit),
+            (Some(_), None)
+        ));
+        assert!(matches!(
+            split_segment(segment(0x0180_0000), limit),
+            (
+                None,
+                Some(RomSegment {
+                    addr: 0x0180_0000,
+                    ..
+                })
+            )
+        ));
+    }
+
+    #[test]
+    fn first_difference_is_found_by_bisecting() {
+        let expected: Vec<u8> = (0..0x10000).map(|i| i as u8).collect();
+
+        for difference in [0, 0x1234, 0x8000, 0xffff] {
+            let mut flash = expected.clone();
+            flash[difference] ^= 0xff;
+
+            // The flash, along with the number of regions compared
+            let mut state = (flash, 0);
+            let offset = first_difference(
+                &mut state,
+                &expected,
+                |(flash, digests), offset, expected| {
+                    *digests += 1;
+                    let offset = offset as usize;
+                    Ok(flash[offset..offset + expected.len()] == *expected)
+                },
+                |(flash, _), offset, len| Ok(flash[offset as usize..][..len as usize].to_vec()),
+            )
+            .unwrap();
+
+            assert_eq!(offset, Some(difference as u32));
+            assert!(state.1 <= 10);
+        }
+
+        let same = first_difference(
+            &mut (),
+            &expected,
+            |_, _, _| Ok(true),
+            |_, _, _| unreachable!(),
+        );
+        assert_eq!(same.unwrap(), None);
+    }
+}
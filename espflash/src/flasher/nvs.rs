@@ -0,0 +1,223 @@
+//! NVS encryption keys and encrypted NVS partitions
+//!
+//! With NVS encryption, the entries of an NVS partition are encrypted with
+//! XTS-AES-256 using a pair of keys stored in an `nvs_keys` partition. The
+//! keys partition itself is protected by flash encryption, whereas the NVS
+//! partition must not be flash encrypted. This matches the `generate-key` and
+//! `encrypt` commands of `nvs_partition_gen.py`.
+
+use std::{fmt, fs, path::Path};
+
+use aes::Aes256;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::{error::Error, flasher::encryption::encrypt_xts_unit};
+
+/// Size of each of the NVS keys
+pub const NVS_KEY_SIZE: usize = 32;
+/// Size of the image of an `nvs_keys` partition
+pub const NVS_KEYS_PARTITION_SIZE: usize = 0x1000;
+/// Size of an NVS page
+const PAGE_SIZE: usize = 0x1000;
+/// Size of an entry of an NVS page, which is encrypted on its own
+const ENTRY_SIZE: usize = 32;
+/// Offset of the entry state bitmap in a page
+const BITMAP_OFFSET: usize = 32;
+/// Offset of the first entry in a page
+const FIRST_ENTRY_OFFSET: usize = 64;
+/// Number of entries in a page
+const ENTRIES_PER_PAGE: usize = 126;
+/// State of a page which was never written
+const PAGE_UNINITIALIZED: u32 = 0xffff_ffff;
+/// State of an entry which was never written
+const ENTRY_EMPTY: u8 = 0b11;
+
+/// Keys encrypting the entries of NVS partitions
+#[derive(Clone, PartialEq, Eq)]
+pub struct NvsKeys {
+    /// Key encrypting the data
+    eky: [u8; NVS_KEY_SIZE],
+    /// Key encrypting the tweak
+    tky: [u8; NVS_KEY_SIZE],
+}
+
+impl fmt::Debug for NvsKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print the keys themselves
+        f.debug_struct("NvsKeys").finish_non_exhaustive()
+    }
+}
+
+impl NvsKeys {
+    /// Use the given data and tweak keys
+    pub fn new(eky: [u8; NVS_KEY_SIZE], tky: [u8; NVS_KEY_SIZE]) -> Self {
+        Self { eky, tky }
+    }
+
+    /// Generate random keys
+    pub fn generate() -> Result<Self, Error> {
+        let mut keys = [0; 2 * NVS_KEY_SIZE];
+        SystemRandom::new()
+            .fill(&mut keys)
+            .map_err(|_| Error::InvalidNvsKeys("failed to generate random keys".into()))?;
+
+        Ok(Self::from_bytes(&keys))
+    }
+
+    /// Read the keys from the image of an `nvs_keys` partition
+    pub fn from_partition(data: &[u8]) -> Result<Self, Error> {
+        let Some(keys) = data.get(..2 * NVS_KEY_SIZE + 4) else {
+            return Err(Error::InvalidNvsKeys(format!(
+                "the partition image is {} bytes long, too short to hold the keys",
+                data.len()
+            )));
+        };
+
+        let (keys, crc) = keys.split_at(2 * NVS_KEY_SIZE);
+        if crc != checksum(keys).to_le_bytes() {
+            return Err(Error::InvalidNvsKeys(
+                "the checksum of the keys does not match, the partition may be encrypted".into(),
+            ));
+        }
+
+        Ok(Self::from_bytes(keys))
+    }
+
+    /// Load the keys from the image of an `nvs_keys` partition in a file
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let data =
+            fs::read(path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+        Self::from_partition(&data)
+    }
+
+    /// The image of an `nvs_keys` partition holding the keys, which is to be
+    /// written with flash encryption
+    pub fn to_partition(&self) -> Vec<u8> {
+        let mut data = vec![0xff; NVS_KEYS_PARTITION_SIZE];
+        data[..NVS_KEY_SIZE].copy_from_slice(&self.eky);
+        data[NVS_KEY_SIZE..2 * NVS_KEY_SIZE].copy_from_slice(&self.tky);
+        let crc = checksum(&data[..2 * NVS_KEY_SIZE]);
+        data[2 * NVS_KEY_SIZE..][..4].copy_from_slice(&crc.to_le_bytes());
+
+        data
+    }
+
+    /// Encrypt the image of an NVS partition, as generated without encryption
+    ///
+    /// Each entry which was written is encrypted, using its offset in the
+    /// partition as the tweak. Page headers, entry state bitmaps and empty
+    /// entries are left as they are.
+    pub fn encrypt(&self, nvs: &[u8]) -> Result<Vec<u8>, Error> {
+        if nvs.is_empty() || nvs.len() % PAGE_SIZE != 0 {
+            return Err(Error::InvalidNvsImage(format!(
+                "the image is {} bytes long, which is not a multiple of the page size of {PAGE_SIZE} bytes",
+                nvs.len()
+            )));
+        }
+
+        let mut encrypted = nvs.to_vec();
+        for (page_index, page) in encrypted.chunks_exact_mut(PAGE_SIZE).enumerate() {
+            let state = u32::from_le_bytes(page[..4].try_into().unwrap());
+            if state == PAGE_UNINITIALIZED {
+                continue;
+            }
+
+            let bitmap: [u8; FIRST_ENTRY_OFFSET - BITMAP_OFFSET] =
+                page[BITMAP_OFFSET..FIRST_ENTRY_OFFSET].try_into().unwrap();
+            let entries = page[FIRST_ENTRY_OFFSET..].chunks_exact_mut(ENTRY_SIZE);
+
+            for (index, entry) in entries.take(ENTRIES_PER_PAGE).enumerate() {
+                let entry_state = (bitmap[index / 4] >> (index % 4 * 2)) & 0b11;
+                if entry_state == ENTRY_EMPTY {
+                    continue;
+                }
+
+                let offset = page_index * PAGE_SIZE + FIRST_ENTRY_OFFSET + index * ENTRY_SIZE;
+                encrypt_xts_unit::<Aes256>(&self.eky, &self.tky, offset as u32, entry);
+            }
+        }
+
+        Ok(encrypted)
+    }
+
+    fn from_bytes(keys: &[u8]) -> Self {
+        let (eky, tky) = keys.split_at(NVS_KEY_SIZE);
+
+        Self {
+            eky: eky.try_into().unwrap(),
+            tky: tky.try_into().unwrap(),
+        }
+    }
+}
+
+/// CRC-32 of the keys, as checked by `nvs_flash_read_security_cfg`
+fn checksum(keys: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(0xffff_ffff);
+    hasher.update(keys);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    fn keys() -> NvsKeys {
+        let keys: Vec<u8> = (0..64).collect();
+        NvsKeys::from_bytes(&keys)
+    }
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn keys_partition_round_trips() {
+        let partition = keys().to_partition();
+
+        assert_eq!(partition.len(), NVS_KEYS_PARTITION_SIZE);
+        assert_eq!(hex(&partition[64..68]), "45527c9a");
+        assert!(partition[68..].iter().all(|&b| b == 0xff));
+        assert_eq!(NvsKeys::from_partition(&partition).unwrap(), keys());
+
+        let mut corrupted = partition.clone();
+        corrupted[0] ^= 1;
+        assert!(NvsKeys::from_partition(&corrupted).is_err());
+        assert!(NvsKeys::from_partition(&partition[..64]).is_err());
+    }
+
+    #[test]
+    fn encryption_matches_nvs_partition_gen() {
+        // An active page with the first two entries written, followed by an
+        // uninitialized one
+        let mut nvs = vec![0xff; 2 * PAGE_SIZE];
+        nvs[..4].copy_from_slice(&0xffff_fffe_u32.to_le_bytes());
+        nvs[BITMAP_OFFSET] = 0b1111_1010;
+        for (i, b) in nvs[FIRST_ENTRY_OFFSET..][..2 * ENTRY_SIZE]
+            .iter_mut()
+            .enumerate()
+        {
+            *b = i as u8;
+        }
+
+        let encrypted = keys().encrypt(&nvs).unwrap();
+
+        assert_eq!(
+            hex(&encrypted[FIRST_ENTRY_OFFSET..][..ENTRY_SIZE]),
+            "7635fec7e95803eb7a706849d6014f4ac533b30d1b284960557fe8d6b0dd9ebb"
+        );
+        assert_eq!(encrypted[..FIRST_ENTRY_OFFSET], nvs[..FIRST_ENTRY_OFFSET]);
+        assert_eq!(
+            encrypted[FIRST_ENTRY_OFFSET + 2 * ENTRY_SIZE..],
+            nvs[FIRST_ENTRY_OFFSET + 2 * ENTRY_SIZE..]
+        );
+        assert_eq!(
+            hex(&Sha256::digest(&encrypted)),
+            "9e17f1f2c773a99c656449d47bd4d4f921414fbc32cafcf010a6d5a4b5b4dd51"
+        );
+
+        assert!(keys().encrypt(&nvs[..100]).is_err());
+    }
+}
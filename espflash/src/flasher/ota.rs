@@ -0,0 +1,336 @@
+//! OTA data partitions
+//!
+//! The `otadata` partition selects which of the OTA app partitions the
+//! second-stage bootloader boots. It consists of two flash sectors, each
+//! holding an entry with a sequence number and the state of the app. The valid
+//! entry with the highest sequence number selects the app partition, which is
+//! `ota_N` with `N = (seq - 1) % count` for `count` OTA app partitions. Without
+//! a valid entry, the factory app is booted. This matches the behaviour of
+//! `otatool.py`.
+
+use std::fmt;
+
+use esp_idf_part::{AppType, DataType, Partition, PartitionTable, SubType, Type};
+use serde::Serialize;
+
+use crate::error::Error;
+
+/// Size of an `otadata` partition
+pub const OTA_DATA_PARTITION_SIZE: usize = 2 * SECTOR_SIZE;
+/// Size of each of the two sectors holding an entry
+const SECTOR_SIZE: usize = 0x1000;
+/// Size of an entry, `esp_ota_select_entry_t`
+const ENTRY_SIZE: usize = 32;
+/// Offset of the label in an entry
+const LABEL_OFFSET: usize = 4;
+/// Offset of the state in an entry
+const STATE_OFFSET: usize = 24;
+/// Offset of the checksum in an entry
+const CRC_OFFSET: usize = 28;
+/// Sequence number of an entry which was never written
+const SEQ_UNINITIALIZED: u32 = 0xffff_ffff;
+
+/// State of the app selected by an OTA data entry, `esp_ota_img_states_t`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OtaState {
+    /// The app was newly written, and is to be verified on its first boot
+    New,
+    /// The app is booted for the first time, and was not verified yet
+    PendingVerify,
+    /// The app was marked as working
+    Valid,
+    /// The app was marked as not working, and is not booted
+    Invalid,
+    /// The app was not marked as working before being rebooted, and is not
+    /// booted
+    Aborted,
+    /// The state is not used, as app rollback is disabled
+    Undefined,
+    /// A value unknown to ESP-IDF
+    Unknown(u32),
+}
+
+impl OtaState {
+    fn from_raw(raw: u32) -> Self {
+        match raw {
+            0 => Self::New,
+            1 => Self::PendingVerify,
+            2 => Self::Valid,
+            3 => Self::Invalid,
+            4 => Self::Aborted,
+            0xffff_ffff => Self::Undefined,
+            raw => Self::Unknown(raw),
+        }
+    }
+
+    fn to_raw(self) -> u32 {
+        match self {
+            Self::New => 0,
+            Self::PendingVerify => 1,
+            Self::Valid => 2,
+            Self::Invalid => 3,
+            Self::Aborted => 4,
+            Self::Undefined => 0xffff_ffff,
+            Self::Unknown(raw) => raw,
+        }
+    }
+}
+
+impl fmt::Display for OtaState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::New => write!(f, "new"),
+            Self::PendingVerify => write!(f, "pending verify"),
+            Self::Valid => write!(f, "valid"),
+            Self::Invalid => write!(f, "invalid"),
+            Self::Aborted => write!(f, "aborted"),
+            Self::Undefined => write!(f, "undefined"),
+            Self::Unknown(raw) => write!(f, "unknown ({raw:#x})"),
+        }
+    }
+}
+
+/// An entry of the OTA data, as stored in one of its sectors
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OtaEntry {
+    /// Sequence number, selecting the app partition
+    pub seq: u32,
+    /// State of the selected app
+    pub state: OtaState,
+    /// Whether the checksum of the sequence number matches
+    pub crc_valid: bool,
+}
+
+impl OtaEntry {
+    /// Whether the entry was never written
+    pub fn is_empty(&self) -> bool {
+        self.seq == SEQ_UNINITIALIZED
+    }
+
+    /// Whether the bootloader considers the entry, as it was written and its
+    /// app was not rejected
+    pub fn is_valid(&self) -> bool {
+        !self.is_empty()
+            && self.crc_valid
+            && !matches!(self.state, OtaState::Invalid | OtaState::Aborted)
+    }
+
+    /// Index of the OTA app partition selected by the entry
+    pub fn slot(&self, slot_count: usize) -> usize {
+        (self.seq.wrapping_sub(1) as usize) % slot_count
+    }
+
+    fn parse(data: &[u8]) -> Self {
+        let word = |offset: usize| u32::from_le_bytes(data[offset..][..4].try_into().unwrap());
+        let seq = word(0);
+
+        Self {
+            seq,
+            state: OtaState::from_raw(word(STATE_OFFSET)),
+            crc_valid: word(CRC_OFFSET) == checksum(seq),
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; ENTRY_SIZE] {
+        let mut data = [0xff; ENTRY_SIZE];
+        data[..LABEL_OFFSET].copy_from_slice(&self.seq.to_le_bytes());
+        data[STATE_OFFSET..CRC_OFFSET].copy_from_slice(&self.state.to_raw().to_le_bytes());
+        data[CRC_OFFSET..].copy_from_slice(&checksum(self.seq).to_le_bytes());
+
+        data
+    }
+}
+
+/// Contents of an `otadata` partition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtaData {
+    data: Vec<u8>,
+}
+
+impl OtaData {
+    /// Read the OTA data from the image of an `otadata` partition
+    pub fn from_partition(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < OTA_DATA_PARTITION_SIZE {
+            return Err(Error::InvalidOtaData(format!(
+                "the partition image is {} bytes long, too short to hold both sectors",
+                data.len()
+            )));
+        }
+
+        Ok(Self {
+            data: data[..OTA_DATA_PARTITION_SIZE].to_vec(),
+        })
+    }
+
+    /// The image of the `otadata` partition
+    pub fn to_partition(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The entries stored in both sectors
+    pub fn entries(&self) -> [OtaEntry; 2] {
+        [0, 1].map(|sector| OtaEntry::parse(&self.data[sector * SECTOR_SIZE..][..ENTRY_SIZE]))
+    }
+
+    /// Index of the sector holding the entry selecting the app, if any is
+    /// valid
+    pub fn active_sector(&self) -> Option<usize> {
+        let [first, second] = self.entries();
+        match (first.is_valid(), second.is_valid()) {
+            (true, true) if second.seq > first.seq => Some(1),
+            (true, _) => Some(0),
+            (false, true) => Some(1),
+            (false, false) => None,
+        }
+    }
+
+    /// Index of the OTA app partition which is booted, or `None` if the
+    /// factory app is booted instead
+    pub fn active_slot(&self, slot_count: usize) -> Option<usize> {
+        let sector = self.active_sector()?;
+        (slot_count > 0).then(|| self.entries()[sector].slot(slot_count))
+    }
+
+    /// Select the OTA app partition to boot, with the index `slot` of
+    /// `slot_count` partitions
+    ///
+    /// Like `esp_ota_set_boot_partition`, an entry with the next sequence
+    /// number selecting the slot is written to the sector which does not hold
+    /// the active entry, so that the previous selection is kept when the write
+    /// is interrupted.
+    pub fn set_boot_slot(&mut self, slot: usize, slot_count: usize) -> Result<(), Error> {
+        if slot >= slot_count {
+            return Err(Error::InvalidOtaSlot(format!(
+                "slot {slot} does not exist, there are {slot_count} OTA app partitions"
+            )));
+        }
+
+        let (sector, seq) = match self.active_sector() {
+            Some(active) => {
+                // The smallest sequence number above the current one which
+                // selects the slot
+                let current = self.entries()[active].seq;
+                let (slot, count) = (slot as u32 + 1, slot_count as u32);
+                let cycles = match current.checked_sub(slot) {
+                    Some(above) => above / count + 1,
+                    None => 0,
+                };
+                (1 - active, slot + cycles * count)
+            }
+            None => (0, slot as u32 + 1),
+        };
+
+        let entry = OtaEntry {
+            seq,
+            state: OtaState::Undefined,
+            crc_valid: true,
+        };
+        let sector = &mut self.data[sector * SECTOR_SIZE..][..SECTOR_SIZE];
+        sector.fill(0xff);
+        sector[..ENTRY_SIZE].copy_from_slice(&entry.to_bytes());
+
+        Ok(())
+    }
+
+    /// Boot the factory app, by erasing both entries
+    pub fn set_boot_factory(&mut self) {
+        self.data.fill(0xff);
+    }
+}
+
+/// The `otadata` partition of a partition table
+pub fn ota_data_partition(table: &PartitionTable) -> Result<&Partition, Error> {
+    table
+        .find_by_subtype(Type::Data, SubType::Data(DataType::Ota))
+        .ok_or_else(|| {
+            Error::InvalidOtaData("the partition table has no `otadata` partition".into())
+        })
+}
+
+/// The OTA app partitions of a partition table, ordered by their index
+///
+/// Like the bootloader, partitions are counted from `ota_0` up to the first
+/// missing index.
+pub fn ota_app_partitions(table: &PartitionTable) -> Vec<&Partition> {
+    (0x10..=0x1f)
+        .map_while(AppType::from_repr)
+        .map_while(|ty| table.find_by_subtype(Type::App, SubType::App(ty)))
+        .collect()
+}
+
+/// CRC-32 of the sequence number, as checked by `bootloader_common_ota_select_crc`
+fn checksum(seq: u32) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(0xffff_ffff);
+    hasher.update(&seq.to_le_bytes());
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ota_data(entries: &[(usize, u32, u32)]) -> OtaData {
+        let mut data = vec![0xff; OTA_DATA_PARTITION_SIZE];
+        for &(sector, seq, state) in entries {
+            let entry = OtaEntry {
+                seq,
+                state: OtaState::from_raw(state),
+                crc_valid: true,
+            };
+            data[sector * SECTOR_SIZE..][..ENTRY_SIZE].copy_from_slice(&entry.to_bytes());
+        }
+
+        OtaData::from_partition(&data).unwrap()
+    }
+
+    #[test]
+    fn checksum_matches_otatool() {
+        assert_eq!(checksum(1), 0x4743_989a);
+        assert_eq!(checksum(2), 0x55f6_3774);
+    }
+
+    #[test]
+    fn highest_valid_entry_is_active() {
+        let empty = ota_data(&[]);
+        assert_eq!(empty.active_slot(2), None);
+
+        let data = ota_data(&[(0, 3, 0xffff_ffff), (1, 4, 2)]);
+        assert_eq!(data.active_sector(), Some(1));
+        assert_eq!(data.active_slot(2), Some(1));
+
+        // A rejected app falls back to the other entry
+        let data = ota_data(&[(0, 3, 2), (1, 4, 4)]);
+        assert_eq!(data.active_slot(2), Some(0));
+
+        let mut corrupted = ota_data(&[(0, 1, 2)]).to_partition().to_vec();
+        corrupted[CRC_OFFSET] ^= 1;
+        assert_eq!(
+            OtaData::from_partition(&corrupted).unwrap().active_slot(2),
+            None
+        );
+        assert!(OtaData::from_partition(&corrupted[..SECTOR_SIZE]).is_err());
+    }
+
+    #[test]
+    fn boot_slot_is_selected() {
+        let mut data = ota_data(&[]);
+        data.set_boot_slot(1, 2).unwrap();
+        assert_eq!(data.entries()[0].seq, 2);
+        assert_eq!(data.active_slot(2), Some(1));
+
+        // The other sector is written, with the next sequence number
+        data.set_boot_slot(0, 2).unwrap();
+        assert_eq!(data.entries()[1].seq, 3);
+        assert_eq!(data.active_slot(2), Some(0));
+
+        data.set_boot_slot(0, 2).unwrap();
+        assert_eq!(data.entries()[0].seq, 5);
+        assert_eq!(data.active_slot(2), Some(0));
+
+        assert!(data.set_boot_slot(2, 2).is_err());
+
+        data.set_boot_factory();
+        assert_eq!(data.active_slot(2), None);
+    }
+}
@@ -0,0 +1,275 @@
+//! Serial Flash Discoverable Parameters (SFDP)
+//!
+//! Flash chips describe their geometry and the commands they support in a set
+//! of parameter tables, as standardized by JEDEC in JESD216. This module parses
+//! the SFDP header, the parameter headers, and the mandatory Basic Flash
+//! Parameter Table (BFPT).
+
+use std::fmt;
+
+use crate::error::Error;
+
+/// Signature at the start of the SFDP header, "SFDP"
+const SFDP_SIGNATURE: [u8; 4] = *b"SFDP";
+/// Size of the SFDP header and of each parameter header
+const HEADER_LEN: usize = 8;
+/// ID of the Basic Flash Parameter Table
+const BASIC_TABLE_ID: u16 = 0xff00;
+/// Number of DWORDs of the Basic Flash Parameter Table which are parsed
+const BASIC_TABLE_DWORDS: usize = 11;
+
+/// Header of an SFDP parameter table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParameterHeader {
+    /// ID of the table, `0xff00` for the Basic Flash Parameter Table
+    pub id: u16,
+    /// Revision of the table, as (major, minor)
+    pub revision: (u8, u8),
+    /// Length of the table in DWORDs
+    pub length: u8,
+    /// Address of the table in the SFDP address space
+    pub pointer: u32,
+}
+
+/// Number of address bytes the flash chip accepts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressBytes {
+    /// 3-byte addressing only
+    Three,
+    /// 3-byte addressing, and 4-byte addressing once enabled
+    ThreeOrFour,
+    /// 4-byte addressing only
+    Four,
+}
+
+impl fmt::Display for AddressBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressBytes::Three => write!(f, "3-byte"),
+            AddressBytes::ThreeOrFour => write!(f, "3-byte or 4-byte"),
+            AddressBytes::Four => write!(f, "4-byte"),
+        }
+    }
+}
+
+/// An erase command supported by the flash chip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EraseType {
+    /// Size of the erased region in bytes
+    pub size: u32,
+    /// Opcode of the command
+    pub opcode: u8,
+}
+
+/// Contents of the Basic Flash Parameter Table
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BasicFlashParameters {
+    /// Size of the flash chip in bytes
+    pub size: u64,
+    /// Supported addressing
+    pub address_bytes: AddressBytes,
+    /// Supported erase commands, from the smallest to the largest region
+    pub erase_types: Vec<EraseType>,
+    /// Size of a program page in bytes, if the table specifies it
+    pub page_size: Option<u32>,
+}
+
+impl BasicFlashParameters {
+    /// Parse the table from its little endian DWORDs
+    fn parse(dwords: &[u32]) -> Result<Self, Error> {
+        let invalid = |reason: &str| Error::InvalidSfdp(reason.into());
+
+        if dwords.len() < 9 {
+            return Err(invalid("the basic flash parameter table is too short"));
+        }
+
+        let address_bytes = match (dwords[0] >> 17) & 0b11 {
+            0b00 => AddressBytes::Three,
+            0b01 => AddressBytes::ThreeOrFour,
+            0b10 => AddressBytes::Four,
+            _ => return Err(invalid("reserved address bytes")),
+        };
+
+        let density = dwords[1];
+        let bits = if density & (1 << 31) == 0 {
+            u64::from(density) + 1
+        } else {
+            let exponent = density & !(1 << 31);
+            1u64.checked_shl(exponent)
+                .ok_or_else(|| invalid("flash density out of range"))?
+        };
+
+        let mut erase_types = [dwords[7], dwords[8]]
+            .iter()
+            .flat_map(|dword| [*dword as u16, (*dword >> 16) as u16])
+            .filter(|erase_type| erase_type & 0xff != 0)
+            .map(|erase_type| EraseType {
+                size: 1 << (erase_type & 0x1f),
+                opcode: (erase_type >> 8) as u8,
+            })
+            .collect::<Vec<_>>();
+        erase_types.sort_by_key(|erase_type| erase_type.size);
+
+        let page_size = dwords.get(10).map(|dword| 1 << ((dword >> 4) & 0xf));
+
+        Ok(Self {
+            size: bits / 8,
+            address_bytes,
+            erase_types,
+            page_size,
+        })
+    }
+
+    /// The erase command for the smallest region, usually a 4KB sector
+    pub fn sector_erase(&self) -> Option<EraseType> {
+        self.erase_types.first().copied()
+    }
+
+    /// The erase command for the largest region of at most 64KB, usually a
+    /// block
+    pub fn block_erase(&self) -> Option<EraseType> {
+        self.erase_types
+            .iter()
+            .rev()
+            .find(|erase_type| erase_type.size <= 64 * 1024)
+            .copied()
+    }
+}
+
+/// Parsed SFDP of a flash chip
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Sfdp {
+    /// SFDP revision, as (major, minor)
+    pub revision: (u8, u8),
+    /// Headers of all parameter tables
+    pub parameter_headers: Vec<ParameterHeader>,
+    /// The Basic Flash Parameter Table
+    pub basic: BasicFlashParameters,
+}
+
+impl Sfdp {
+    /// Read and parse the SFDP with `read`, which reads the given number of
+    /// bytes at an address in the SFDP address space
+    ///
+    /// Returns `None` if the flash chip does not support SFDP.
+    pub fn read(
+        mut read: impl FnMut(u32, usize) -> Result<Vec<u8>, Error>,
+    ) -> Result<Option<Self>, Error> {
+        let header = read(0, HEADER_LEN)?;
+        if header.len() < HEADER_LEN || header[..4] != SFDP_SIGNATURE {
+            return Ok(None);
+        }
+
+        let revision = (header[5], header[4]);
+        let count = usize::from(header[6]) + 1;
+
+        let parameter_headers = read(HEADER_LEN as u32, count * HEADER_LEN)?
+            .chunks_exact(HEADER_LEN)
+            .map(|header| ParameterHeader {
+                id: u16::from_le_bytes([header[0], header[7]]),
+                revision: (header[2], header[1]),
+                length: header[3],
+                pointer: u32::from_le_bytes([header[4], header[5], header[6], 0]),
+            })
+            .collect::<Vec<_>>();
+
+        let basic_header = parameter_headers
+            .iter()
+            .find(|header| header.id == BASIC_TABLE_ID)
+            .ok_or_else(|| Error::InvalidSfdp("no basic flash parameter table".into()))?;
+
+        let len = usize::from(basic_header.length).min(BASIC_TABLE_DWORDS);
+        let dwords = read(basic_header.pointer, len * 4)?
+            .chunks_exact(4)
+            .map(|dword| u32::from_le_bytes(dword.try_into().unwrap()))
+            .collect::<Vec<_>>();
+
+        Ok(Some(Self {
+            revision,
+            basic: BasicFlashParameters::parse(&dwords)?,
+            parameter_headers,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// SFDP of a Winbond W25Q32JV, with the Basic Flash Parameter Table moved
+    /// right after its header
+    const W25Q32_SFDP: &[u8] = &[
+        0x53, 0x46, 0x44, 0x50, 0x05, 0x01, 0x00, 0xff, // SFDP header
+        0x00, 0x05, 0x01, 0x10, 0x10, 0x00, 0x00, 0xff, // BFPT header
+        0xe5, 0x20, 0xf9, 0xff, 0xff, 0xff, 0xff, 0x01, // BFPT DWORD 1-2
+        0x44, 0xeb, 0x08, 0x6b, 0x08, 0x3b, 0x42, 0xbb, // BFPT DWORD 3-4
+        0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, // BFPT DWORD 5-6
+        0xff, 0xff, 0x40, 0xeb, 0x0c, 0x20, 0x0f, 0x52, // BFPT DWORD 7-8
+        0x10, 0xd8, 0x00, 0x00, 0x36, 0x02, 0xa6, 0x00, // BFPT DWORD 9-10
+        0x82, 0xea, 0x14, 0xc4, 0xe9, 0x63, 0x76, 0x33, // BFPT DWORD 11-12
+        0x7a, 0x75, 0x7a, 0x75, 0xf7, 0xa2, 0xd5, 0x5c, // BFPT DWORD 13-14
+        0x19, 0xf7, 0x4d, 0xff, 0xe9, 0x30, 0xf8, 0x80, // BFPT DWORD 15-16
+    ];
+
+    fn read(sfdp: &[u8]) -> Result<Option<Sfdp>, Error> {
+        Sfdp::read(|addr, len| {
+            let addr = addr as usize;
+            Ok(sfdp
+                .get(addr..addr + len)
+                .unwrap_or(&[0xff; 256][..len])
+                .to_vec())
+        })
+    }
+
+    #[test]
+    fn short_header_is_not_sfdp() {
+        assert_eq!(Sfdp::read(|_, _| Ok(b"SFD".to_vec())).unwrap(), None);
+    }
+
+    #[test]
+    fn parse_basic_flash_parameters() {
+        let sfdp = read(W25Q32_SFDP).unwrap().unwrap();
+
+        assert_eq!(sfdp.revision, (1, 5));
+        assert_eq!(
+            sfdp.parameter_headers,
+            [ParameterHeader {
+                id: 0xff00,
+                revision: (1, 5),
+                length: 16,
+                pointer: 0x10,
+            }]
+        );
+
+        let basic = sfdp.basic;
+        assert_eq!(basic.size, 4 * 1024 * 1024);
+        assert_eq!(basic.address_bytes, AddressBytes::Three);
+        assert_eq!(basic.page_size, Some(256));
+        assert_eq!(
+            basic.erase_types,
+            [
+                EraseType {
+                    size: 4 * 1024,
+                    opcode: 0x20
+                },
+                EraseType {
+                    size: 32 * 1024,
+                    opcode: 0x52
+                },
+                EraseType {
+                    size: 64 * 1024,
+                    opcode: 0xd8
+                },
+            ]
+        );
+        assert_eq!(basic.sector_erase().unwrap().size, 4 * 1024);
+        assert_eq!(basic.block_erase().unwrap().size, 64 * 1024);
+    }
+
+    #[test]
+    fn missing_sfdp() {
+        assert_eq!(read(&[]).unwrap(), None);
+    }
+}
@@ -1,11 +1,23 @@
-use std::time::Duration;
+use std::{fs, path::Path, time::Duration};
 
 use base64::{engine::general_purpose, Engine as _};
 use serde::{Deserialize, Serialize};
+use xmas_elf::{
+    sections::{SectionData, ShType, SHF_ALLOC, SHF_EXECINSTR},
+    ElfFile,
+};
 
-use crate::targets::Chip;
+use crate::{
+    error::{ElfError, Error},
+    targets::Chip,
+};
 
-/// Flash stub object (deserialized from TOML, converted from JSON as used by `esptool.py`)
+/// Flash stub object
+///
+/// The built-in stubs are deserialized from TOML (converted from the JSON used
+/// by `esptool.py`), and can be selected by name with [FlashStub::named].
+/// Custom stubs can additionally be loaded from `esptool.py` JSON files or
+/// directly from the ELF files published by the stub flasher project.
 #[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub struct FlashStub {
     /// Entry point (address)
@@ -18,6 +30,9 @@ pub struct FlashStub {
     data: String,
     /// Start of data section address
     data_start: u32,
+    /// Chip a built-in stub is built for
+    #[serde(skip)]
+    chip: Option<Chip>,
 }
 
 pub(crate) const CHIP_DETECT_MAGIC_REG_ADDR: u32 = 0x40001000;
@@ -27,6 +42,10 @@ pub(crate) const EXPECTED_STUB_HANDSHAKE: &str = "OHAI";
 pub(crate) const FLASH_SECTOR_SIZE: usize = 0x1000;
 pub(crate) const FLASH_WRITE_SIZE: usize = 0x400;
 
+/// Size of the largest internal RAM of the chips (768 KB on the ESP32-P4),
+/// which the sections of a stub have to fit in
+const STUB_RAM_SIZE: usize = 0xC_0000;
+
 // Include stub objects in binary
 const STUB_32: &str = include_str!("../../resources/stubs/stub_flasher_32.toml");
 const STUB_32C2: &str = include_str!("../../resources/stubs/stub_flasher_32c2.toml");
@@ -37,23 +56,192 @@ const STUB_32P4: &str = include_str!("../../resources/stubs/stub_flasher_32p4.to
 const STUB_32S2: &str = include_str!("../../resources/stubs/stub_flasher_32s2.toml");
 const STUB_32S3: &str = include_str!("../../resources/stubs/stub_flasher_32s3.toml");
 
+/// A built-in stub
+struct BuiltinStub {
+    chip: Chip,
+    /// Name selecting the stub with [FlashStub::named]
+    name: &'static str,
+    toml: &'static str,
+}
+
+/// The built-in stubs, one for each chip
+const STUBS: &[BuiltinStub] = &[
+    BuiltinStub {
+        chip: Chip::Esp32,
+        name: "esp32",
+        toml: STUB_32,
+    },
+    BuiltinStub {
+        chip: Chip::Esp32c2,
+        name: "esp32c2",
+        toml: STUB_32C2,
+    },
+    BuiltinStub {
+        chip: Chip::Esp32c3,
+        name: "esp32c3",
+        toml: STUB_32C3,
+    },
+    BuiltinStub {
+        chip: Chip::Esp32c6,
+        name: "esp32c6",
+        toml: STUB_32C6,
+    },
+    BuiltinStub {
+        chip: Chip::Esp32h2,
+        name: "esp32h2",
+        toml: STUB_32H2,
+    },
+    BuiltinStub {
+        chip: Chip::Esp32p4,
+        name: "esp32p4",
+        toml: STUB_32P4,
+    },
+    BuiltinStub {
+        chip: Chip::Esp32s2,
+        name: "esp32s2",
+        toml: STUB_32S2,
+    },
+    BuiltinStub {
+        chip: Chip::Esp32s3,
+        name: "esp32s3",
+        toml: STUB_32S3,
+    },
+];
+
+impl BuiltinStub {
+    fn load(&self) -> FlashStub {
+        FlashStub {
+            chip: Some(self.chip),
+            ..toml::from_str(self.toml).unwrap()
+        }
+    }
+}
+
 impl FlashStub {
     /// Fetch flash stub for the provided chip
     pub fn get(chip: Chip) -> FlashStub {
-        let s = match chip {
-            Chip::Esp32 => STUB_32,
-            Chip::Esp32c2 => STUB_32C2,
-            Chip::Esp32c3 => STUB_32C3,
-            Chip::Esp32c6 => STUB_32C6,
-            Chip::Esp32h2 => STUB_32H2,
-            Chip::Esp32p4 => STUB_32P4,
-            Chip::Esp32s2 => STUB_32S2,
-            Chip::Esp32s3 => STUB_32S3,
+        STUBS.iter().find(|s| s.chip == chip).unwrap().load()
+    }
+
+    /// Names of the built-in stubs, which can be selected with
+    /// [FlashStub::named]
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        STUBS.iter().map(|s| s.name)
+    }
+
+    /// Fetch the built-in flash stub named `name`
+    ///
+    /// The stub remembers the chip it is built for, so that
+    /// [FlashStub::validate] rejects it for any other chip.
+    pub fn named(name: &str) -> Result<FlashStub, Error> {
+        STUBS
+            .iter()
+            .find(|s| s.name == name)
+            .map(BuiltinStub::load)
+            .ok_or_else(|| Error::InvalidStub(format!("there is no built-in stub named {name}")))
+    }
+
+    /// Parse a flash stub in the JSON format used by `esptool.py`
+    pub fn from_json(s: &str) -> Result<FlashStub, Error> {
+        serde_json::from_str(s).map_err(|e| Error::InvalidStub(e.to_string()))
+    }
+
+    /// Parse a flash stub in the TOML format used by the built-in stubs
+    pub fn from_toml(s: &str) -> Result<FlashStub, Error> {
+        toml::from_str(s).map_err(|e| Error::InvalidStub(e.to_string()))
+    }
+
+    /// Build a flash stub from a stub flasher ELF file
+    ///
+    /// Executable sections are combined into the stub's text, all other
+    /// allocated sections into its data. Gaps between sections are zero-filled.
+    pub fn from_elf(elf_data: &[u8]) -> Result<FlashStub, Error> {
+        let elf = ElfFile::new(elf_data).map_err(ElfError::from)?;
+
+        let mut text = Vec::new();
+        let mut data = Vec::new();
+        for header in elf.section_iter() {
+            if header.size() == 0
+                || header.address() == 0
+                || header.get_type() != Ok(ShType::ProgBits)
+                || header.flags() & SHF_ALLOC == 0
+            {
+                continue;
+            }
+
+            let bytes = match header.get_data(&elf) {
+                Ok(SectionData::Undefined(bytes)) => bytes,
+                _ => continue,
+            };
+
+            let section = (header.address() as u32, bytes);
+            if header.flags() & SHF_EXECINSTR != 0 {
+                text.push(section);
+            } else {
+                data.push(section);
+            }
+        }
+
+        let (text_start, text) = merge_sections(text)?;
+        let (data_start, data) = merge_sections(data)?;
+
+        Ok(FlashStub {
+            entry: elf.header.pt2.entry_point() as u32,
+            text: general_purpose::STANDARD.encode(text),
+            text_start,
+            data: general_purpose::STANDARD.encode(data),
+            data_start,
+            chip: None,
+        })
+    }
+
+    /// Load a flash stub from a file, selecting the format by its extension
+    pub fn load(path: &Path) -> Result<FlashStub, Error> {
+        let open_error = |e| Error::FileOpenError(path.display().to_string(), e);
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Self::from_json(&fs::read_to_string(path).map_err(open_error)?),
+            Some("toml") => Self::from_toml(&fs::read_to_string(path).map_err(open_error)?),
+            Some("elf") => Self::from_elf(&fs::read(path).map_err(open_error)?),
+            _ => Err(Error::InvalidStubPath),
+        }
+    }
+
+    /// Check that the stub can be loaded into the RAM of the provided chip
+    pub fn validate(&self, chip: Chip) -> Result<(), Error> {
+        if let Some(stub_chip) = self.chip.filter(|c| *c != chip) {
+            return Err(Error::InvalidStub(format!(
+                "the built-in stub is for the {stub_chip}, not the {chip}"
+            )));
+        }
+
+        let decode = |name: &str, s: &str| {
+            general_purpose::STANDARD
+                .decode(s)
+                .map_err(|e| Error::InvalidStub(format!("{name} is not valid base64: {e}")))
         };
 
-        let stub: FlashStub = toml::from_str(s).unwrap();
+        let text = decode("text", &self.text)?;
+        decode("data", &self.data)?;
+
+        let text_end = self.text_start as u64 + text.len() as u64;
+        if !(self.text_start as u64..text_end).contains(&(self.entry as u64)) {
+            return Err(Error::InvalidStub(format!(
+                "entry point {:#010x} is not within the text section",
+                self.entry
+            )));
+        }
+
+        let target = chip.into_target();
+        for (name, addr) in [("text", self.text_start), ("data", self.data_start)] {
+            if target.addr_is_flash(addr) {
+                return Err(Error::InvalidStub(format!(
+                    "{name} section at {addr:#010x} is mapped to flash on the {chip}"
+                )));
+            }
+        }
 
-        stub
+        Ok(())
     }
 
     /// Fetch stub entry point
@@ -74,11 +262,40 @@ impl FlashStub {
     }
 }
 
+/// Concatenate sections into a single contiguous blob, returning its start
+/// address
+fn merge_sections(mut sections: Vec<(u32, &[u8])>) -> Result<(u32, Vec<u8>), Error> {
+    sections.sort_by_key(|(addr, _)| *addr);
+
+    let Some(&(start, _)) = sections.first() else {
+        return Ok((0, Vec::new()));
+    };
+
+    let mut merged = Vec::new();
+    for (addr, bytes) in sections {
+        let offset = (addr - start) as usize;
+        if offset < merged.len() {
+            return Err(Error::InvalidStub(format!(
+                "overlapping sections at {addr:#010x}"
+            )));
+        }
+        if offset + bytes.len() > STUB_RAM_SIZE {
+            return Err(Error::InvalidStub(format!(
+                "section at {addr:#010x} is more than {STUB_RAM_SIZE:#x} bytes past {start:#010x}"
+            )));
+        }
+        merged.resize(offset, 0);
+        merged.extend_from_slice(bytes);
+    }
+
+    Ok((start, merged))
+}
+
 #[cfg(test)]
 mod tests {
     use strum::IntoEnumIterator;
 
-    use super::FlashStub;
+    use super::{merge_sections, FlashStub};
     use crate::targets::Chip;
 
     #[test]
@@ -90,6 +307,43 @@ mod tests {
             // Data decoded from b64
             let _ = s.text();
             let _ = s.data();
+
+            s.validate(c).unwrap();
         }
     }
+
+    #[test]
+    fn parse_esptool_json_stub() {
+        let stub = FlashStub::get(Chip::Esp32c3);
+        let json = format!(
+            r#"{{"entry": {}, "text": "{}", "text_start": {}, "data": "{}", "data_start": {}, "bss_start": 0}}"#,
+            stub.entry, stub.text, stub.text_start, stub.data, stub.data_start
+        );
+
+        let parsed = FlashStub::from_json(&json).unwrap();
+        assert_eq!(parsed, FlashStub { chip: None, ..stub });
+        assert!(parsed.validate(Chip::Esp32c3).is_ok());
+        assert!(parsed.validate(Chip::Esp32).is_err());
+    }
+
+    #[test]
+    fn named_stubs_are_validated_for_their_chip() {
+        assert!(FlashStub::names().any(|name| name == "esp32c6"));
+
+        let stub = FlashStub::named("esp32c6").unwrap();
+        assert!(stub.validate(Chip::Esp32c6).is_ok());
+        assert!(stub.validate(Chip::Esp32h2).is_err());
+        assert!(FlashStub::named("esp8266").is_err());
+    }
+
+    #[test]
+    fn merge_sections_pads_gaps() {
+        let (start, merged) =
+            merge_sections(vec![(0x1008, &[3, 4][..]), (0x1000, &[1, 2][..])]).unwrap();
+        assert_eq!(start, 0x1000);
+        assert_eq!(merged, [1, 2, 0, 0, 0, 0, 0, 0, 3, 4]);
+
+        assert!(merge_sections(vec![(0x1000, &[1, 2][..]), (0x1001, &[3][..])]).is_err());
+        assert!(merge_sections(vec![(0x1000, &[1][..]), (0x8000_0000, &[2][..])]).is_err());
+    }
 }
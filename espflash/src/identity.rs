@@ -0,0 +1,153 @@
+//! Short names identifying devices
+//!
+//! When operating on several devices at once, their output is told apart by a
+//! short name: the alias assigned to the MAC address of the device, or the
+//! last three bytes of the MAC address otherwise. Orchestration layers can use
+//! [DeviceAliases] to name devices the same way as the command-line tools.
+
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::Error;
+
+/// MAC address of a device, as printed by `board-info`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddress([u8; 6]);
+
+impl MacAddress {
+    pub fn new(bytes: [u8; 6]) -> Self {
+        Self(bytes)
+    }
+
+    /// The bytes of the address
+    pub fn bytes(&self) -> [u8; 6] {
+        self.0
+    }
+
+    /// The last three bytes of the address, e.g. `a1b2c3`, which are unique
+    /// among the devices of a vendor
+    pub fn short_id(&self) -> String {
+        self.0[3..].iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+impl FromStr for MacAddress {
+    type Err = Error;
+
+    /// Parse an address of six hexadecimal bytes, separated by colons or
+    /// dashes
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidMacAddress(s.to_string());
+
+        let parts = s.split([':', '-']).collect::<Vec<_>>();
+        let parts: [&str; 6] = parts.try_into().map_err(|_| invalid())?;
+
+        let mut bytes = [0; 6];
+        for (byte, part) in bytes.iter_mut().zip(parts) {
+            if part.len() != 2 {
+                return Err(invalid());
+            }
+            *byte = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+
+        Ok(Self(bytes))
+    }
+}
+
+impl Display for MacAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+impl Serialize for MacAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MacAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Aliases assigned to devices by their MAC address
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct DeviceAliases(BTreeMap<MacAddress, String>);
+
+impl DeviceAliases {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Assign `alias` to the device with the MAC address `mac`
+    pub fn insert(&mut self, mac: MacAddress, alias: impl Into<String>) {
+        self.0.insert(mac, alias.into());
+    }
+
+    /// The alias of the device with the MAC address `mac`, if it has one
+    pub fn alias(&self, mac: &MacAddress) -> Option<&str> {
+        self.0.get(mac).map(String::as_str)
+    }
+
+    /// The name of the device with the MAC address `mac`: its alias, or the
+    /// short ID of its MAC address
+    pub fn name(&self, mac: &MacAddress) -> String {
+        match self.alias(mac) {
+            Some(alias) => alias.to_string(),
+            None => mac.short_id(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The devices and their aliases, ordered by MAC address
+    pub fn iter(&self) -> impl Iterator<Item = (&MacAddress, &str)> {
+        self.0.iter().map(|(mac, alias)| (mac, alias.as_str()))
+    }
+}
+
+impl FromIterator<(MacAddress, String)> for DeviceAliases {
+    fn from_iter<I: IntoIterator<Item = (MacAddress, String)>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mac_addresses_round_trip() {
+        let mac: MacAddress = "F4:12:fa:00:11:22".parse().unwrap();
+        assert_eq!(mac.bytes(), [0xf4, 0x12, 0xfa, 0x00, 0x11, 0x22]);
+        assert_eq!(mac.to_string(), "f4:12:fa:00:11:22");
+        assert_eq!("f4-12-fa-00-11-22".parse::<MacAddress>().unwrap(), mac);
+
+        for invalid in ["f4:12:fa:00:11", "f4:12:fa:00:11:2", "f4:12:fa:00:11:2g"] {
+            assert!(invalid.parse::<MacAddress>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn devices_are_named_by_alias_or_short_id() {
+        let bench: MacAddress = "f4:12:fa:00:11:22".parse().unwrap();
+        let other: MacAddress = "f4:12:fa:a1:b2:c3".parse().unwrap();
+
+        let aliases = DeviceAliases::from_iter([(bench, "bench-1".to_string())]);
+        assert_eq!(aliases.name(&bench), "bench-1");
+        assert_eq!(aliases.name(&other), "a1b2c3");
+    }
+}
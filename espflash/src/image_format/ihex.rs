@@ -0,0 +1,279 @@
+//! Intel HEX files, for tools and programmers which take those instead of raw
+//! binaries
+
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+};
+
+use crate::{elf::RomSegment, error::Error};
+
+/// Number of data bytes in each data record
+const RECORD_DATA_LEN: usize = 16;
+
+const DATA_RECORD: u8 = 0x00;
+const END_OF_FILE_RECORD: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS_RECORD: u8 = 0x02;
+const START_SEGMENT_ADDRESS_RECORD: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS_RECORD: u8 = 0x04;
+const START_LINEAR_ADDRESS_RECORD: u8 = 0x05;
+
+/// Whether `data` looks like an Intel HEX file rather than a raw binary
+pub fn is_ihex(data: &[u8]) -> bool {
+    let data = data.trim_ascii_start();
+    data.first() == Some(&b':')
+        && data
+            .iter()
+            .take_while(|b| !b.is_ascii_whitespace())
+            .skip(1)
+            .all(u8::is_ascii_hexdigit)
+}
+
+/// Parse an Intel HEX file into the contiguous regions of data it contains,
+/// ordered by their address
+///
+/// Start address records are ignored, as the entry point of an application is
+/// taken from its image header instead.
+pub fn parse_ihex(text: &str) -> Result<Vec<RomSegment<'static>>, Error> {
+    let mut regions: Vec<(u32, Vec<u8>, usize)> = Vec::new();
+    let mut base_address = 0u32;
+    let mut end_of_file = false;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid = |reason: &str| Error::InvalidIntelHex {
+            line: index + 1,
+            reason: reason.into(),
+        };
+
+        if end_of_file {
+            return Err(invalid("record after the end of file record"));
+        }
+
+        let record = line
+            .strip_prefix(':')
+            .ok_or_else(|| invalid("records must start with ':'"))?;
+        if record.len() % 2 != 0 || !record.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid("records must consist of pairs of hex digits"));
+        }
+        let bytes = (0..record.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&record[i..i + 2], 16).unwrap())
+            .collect::<Vec<_>>();
+
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(invalid("the record length does not match its byte count"));
+        }
+        if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(invalid("invalid checksum"));
+        }
+
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let data = &bytes[4..bytes.len() - 1];
+
+        match bytes[3] {
+            DATA_RECORD => {
+                let addr = base_address
+                    .checked_add(offset)
+                    .filter(|addr| addr.checked_add(data.len() as u32).is_some())
+                    .ok_or_else(|| invalid("the data exceeds the 32-bit address space"))?;
+                match regions.last_mut() {
+                    Some((start, region, _)) if *start + region.len() as u32 == addr => {
+                        region.extend_from_slice(data)
+                    }
+                    _ => regions.push((addr, data.to_vec(), index + 1)),
+                }
+            }
+            END_OF_FILE_RECORD => end_of_file = true,
+            EXTENDED_SEGMENT_ADDRESS_RECORD if data.len() == 2 => {
+                base_address = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4;
+            }
+            EXTENDED_LINEAR_ADDRESS_RECORD if data.len() == 2 => {
+                base_address = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16;
+            }
+            START_SEGMENT_ADDRESS_RECORD | START_LINEAR_ADDRESS_RECORD if data.len() == 4 => {}
+            EXTENDED_SEGMENT_ADDRESS_RECORD
+            | EXTENDED_LINEAR_ADDRESS_RECORD
+            | START_SEGMENT_ADDRESS_RECORD
+            | START_LINEAR_ADDRESS_RECORD => {
+                return Err(invalid("unexpected length of an address record"));
+            }
+            kind => return Err(invalid(&format!("unknown record type {kind:02X}"))),
+        }
+    }
+
+    if !end_of_file {
+        return Err(Error::InvalidIntelHex {
+            line: text.lines().count(),
+            reason: "missing end of file record".into(),
+        });
+    }
+
+    // Records may appear in any order, so sort the regions and merge those
+    // which turn out to be adjacent, rejecting any overlaps
+    regions.sort_by_key(|(addr, ..)| *addr);
+    let mut merged: Vec<(u32, Vec<u8>)> = Vec::with_capacity(regions.len());
+    for (addr, data, line) in regions {
+        match merged.last_mut() {
+            Some((start, region)) if *start as u64 + region.len() as u64 > addr as u64 => {
+                return Err(Error::InvalidIntelHex {
+                    line,
+                    reason: format!("the data at {addr:#x} overlaps with other records"),
+                });
+            }
+            Some((start, region)) if *start + region.len() as u32 == addr => {
+                region.extend_from_slice(&data)
+            }
+            _ => merged.push((addr, data)),
+        }
+    }
+
+    Ok(merged
+        .into_iter()
+        .map(|(addr, data)| RomSegment {
+            addr,
+            data: Cow::Owned(data),
+        })
+        .collect())
+}
+
+/// Write `segments` as an Intel HEX file
+///
+/// The gaps between the segments are left out of the file, rather than being
+/// filled.
+pub fn write_ihex(writer: &mut impl Write, segments: &[RomSegment<'_>]) -> io::Result<()> {
+    let mut upper_address = 0;
+
+    for segment in segments {
+        let mut addr = segment.addr;
+        let mut data = segment.data.as_ref();
+
+        while !data.is_empty() {
+            if addr >> 16 != upper_address {
+                upper_address = addr >> 16;
+                write_record(
+                    writer,
+                    EXTENDED_LINEAR_ADDRESS_RECORD,
+                    0,
+                    &(upper_address as u16).to_be_bytes(),
+                )?;
+            }
+
+            // Records must not cross into the next 64 KiB of the address space
+            let to_boundary = 0x1_0000 - (addr as usize & 0xffff);
+            let len = data.len().min(RECORD_DATA_LEN).min(to_boundary);
+            let (record, rest) = data.split_at(len);
+            write_record(writer, DATA_RECORD, addr as u16, record)?;
+
+            addr += len as u32;
+            data = rest;
+        }
+    }
+
+    write_record(writer, END_OF_FILE_RECORD, 0, &[])
+}
+
+fn write_record(writer: &mut impl Write, kind: u8, addr: u16, data: &[u8]) -> io::Result<()> {
+    let [addr_high, addr_low] = addr.to_be_bytes();
+    let mut record = vec![data.len() as u8, addr_high, addr_low, kind];
+    record.extend_from_slice(data);
+
+    let checksum = record
+        .iter()
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+        .wrapping_neg();
+    record.push(checksum);
+
+    write!(writer, ":")?;
+    for byte in record {
+        write!(writer, "{byte:02X}")?;
+    }
+    writeln!(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_split_at_64k_boundaries() {
+        let segments = [
+            RomSegment {
+                addr: 0x0,
+                data: Cow::Borrowed(&[1, 2, 3]),
+            },
+            RomSegment {
+                addr: 0xfffe,
+                data: Cow::Borrowed(&[4, 5, 6, 7]),
+            },
+        ];
+
+        let mut hex = Vec::new();
+        write_ihex(&mut hex, &segments).unwrap();
+
+        assert_eq!(
+            String::from_utf8(hex).unwrap(),
+            ":03000000010203F7\n\
+             :02FFFE000405F8\n\
+             :020000040001F9\n\
+             :020000000607F1\n\
+             :00000001FF\n"
+        );
+    }
+
+    #[test]
+    fn parsing_reverses_writing() {
+        let segments = [
+            RomSegment {
+                addr: 0x0,
+                data: Cow::Owned((0..40).collect()),
+            },
+            RomSegment {
+                addr: 0x1_fff0,
+                data: Cow::Owned((0..100).collect()),
+            },
+        ];
+
+        let mut hex = Vec::new();
+        write_ihex(&mut hex, &segments).unwrap();
+        assert!(is_ihex(&hex));
+
+        let parsed = parse_ihex(std::str::from_utf8(&hex).unwrap()).unwrap();
+        assert_eq!(parsed.len(), 2);
+        for (parsed, segment) in parsed.iter().zip(&segments) {
+            assert_eq!(parsed.addr, segment.addr);
+            assert_eq!(parsed.data, segment.data);
+        }
+    }
+
+    #[test]
+    fn segment_addresses_and_unordered_records_are_supported() {
+        let parsed = parse_ihex(
+            ":020000021000EC\n\
+             :02000200CCDD53\n\
+             :02000000AABB99\n\
+             :0400000300001000E9\n\
+             :00000001FF\n",
+        )
+        .unwrap();
+
+        assert_eq!(parsed.len(), 1);
+        assert_eq!(parsed[0].addr, 0x1_0000);
+        assert_eq!(parsed[0].data.as_ref(), &[0xaa, 0xbb, 0xcc, 0xdd]);
+    }
+
+    #[test]
+    fn invalid_files_are_rejected() {
+        assert!(matches!(
+            parse_ihex(":02000000AABB98\n:00000001FF\n"),
+            Err(Error::InvalidIntelHex { line: 1, .. })
+        ));
+        assert!(parse_ihex(":02000000AABB99\n").is_err());
+        assert!(parse_ihex(":02000000AABB99\n:01000100CC32\n:00000001FF\n").is_err());
+        assert!(!is_ihex(b"\xe9\x03\x02\x20"));
+    }
+}
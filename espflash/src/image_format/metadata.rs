@@ -0,0 +1,288 @@
+//! Metadata embedded in ELF files by esp-hal and ESP-IDF
+//!
+//! The metadata is read from the ELF file alone, so that build pipelines can
+//! validate their artifacts without a device. It consists of:
+//!
+//! - the metadata notes of esp-hal, symbols in the `.espressif.metadata`
+//!   section whose names are the keys and whose contents are the values, e.g.
+//!   `build_info.CHIP_NAME`
+//! - the application description (`esp_app_desc_t`) of ESP-IDF, also emitted by
+//!   the `esp_app_desc!` macro of esp-bootloader-esp-idf
+//! - whether the application logs with defmt
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use xmas_elf::{
+    sections::{SectionData, SectionHeader},
+    symbol_table::Entry,
+    ElfFile,
+};
+
+use crate::{
+    error::{ElfError, Error},
+    targets::Chip,
+};
+
+/// Section of the metadata notes of esp-hal
+const METADATA_SECTION: &str = ".espressif.metadata";
+/// Sections the application description is placed in by ESP-IDF and esp-hal
+const APP_DESC_SECTIONS: [&str; 2] = [".flash.appdesc", ".rodata_desc"];
+/// Magic word at the start of the application description
+const APP_DESC_MAGIC: u32 = 0xabcd_5432;
+/// Size of the application description, up to the ELF file's SHA-256
+const APP_DESC_LEN: usize = 0xb0;
+
+/// Application description of ESP-IDF (`esp_app_desc_t`)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct AppDescriptor {
+    /// Version for the anti-rollback feature of the bootloader
+    pub secure_version: u32,
+    /// Version of the application
+    pub version: String,
+    /// Name of the project
+    pub project_name: String,
+    /// Time of the build
+    pub time: String,
+    /// Date of the build
+    pub date: String,
+    /// Version of ESP-IDF, or of the crate providing the description
+    pub idf_version: String,
+    /// SHA-256 of the ELF file, if it was filled in after linking
+    #[serde(serialize_with = "serialize_hex")]
+    pub elf_sha256: [u8; 32],
+}
+
+impl AppDescriptor {
+    /// Parse the description at the start of `bytes`, if it has the magic word
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let desc = bytes.get(..APP_DESC_LEN)?;
+        let word = |offset: usize| u32::from_le_bytes(desc[offset..][..4].try_into().unwrap());
+        if word(0x0) != APP_DESC_MAGIC {
+            return None;
+        }
+
+        let field = |range: std::ops::Range<usize>| {
+            let bytes = &desc[range];
+            let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+            String::from_utf8_lossy(&bytes[..end]).into_owned()
+        };
+
+        Some(Self {
+            secure_version: word(0x4),
+            version: field(0x10..0x30),
+            project_name: field(0x30..0x50),
+            time: field(0x50..0x60),
+            date: field(0x60..0x70),
+            idf_version: field(0x70..0x90),
+            elf_sha256: desc[0x90..0xb0].try_into().unwrap(),
+        })
+    }
+}
+
+/// Metadata of an application, read from its ELF file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct Metadata {
+    /// Metadata notes of esp-hal, by their key
+    #[serde(serialize_with = "serialize_notes")]
+    notes: BTreeMap<String, Vec<u8>>,
+    /// Application description of ESP-IDF
+    app_descriptor: Option<AppDescriptor>,
+    /// The ELF file contains defmt data
+    defmt: bool,
+}
+
+impl Metadata {
+    /// Read the metadata of the ELF file `elf_data`
+    ///
+    /// ELF files without any metadata result in empty [Metadata].
+    pub fn from_bytes(elf_data: &[u8]) -> Result<Self, Error> {
+        let elf = ElfFile::new(elf_data).map_err(ElfError::from)?;
+
+        let mut metadata = Metadata {
+            defmt: elf.find_section_by_name(".defmt").is_some(),
+            ..Default::default()
+        };
+
+        metadata.app_descriptor = APP_DESC_SECTIONS
+            .iter()
+            .filter_map(|name| elf.find_section_by_name(name))
+            .filter_map(|header| section_data(&elf, &header))
+            .find_map(AppDescriptor::from_bytes);
+
+        let Some((index, section)) = elf
+            .section_iter()
+            .enumerate()
+            .find(|(_, header)| header.get_name(&elf) == Ok(METADATA_SECTION))
+        else {
+            return Ok(metadata);
+        };
+        let data = section_data(&elf, &section).unwrap_or_default();
+
+        let symbols = match elf
+            .find_section_by_name(".symtab")
+            .map(|header| header.get_data(&elf))
+        {
+            Some(Ok(SectionData::SymbolTable32(symbols))) => symbols,
+            _ => return Ok(metadata),
+        };
+
+        for symbol in symbols
+            .iter()
+            .filter(|symbol| symbol.shndx() as usize == index)
+        {
+            let (Ok(name), Some(offset)) = (
+                symbol.get_name(&elf),
+                symbol.value().checked_sub(section.address()),
+            ) else {
+                continue;
+            };
+            if let Some(value) = data.get(offset as usize..(offset + symbol.size()) as usize) {
+                metadata.notes.insert(name.to_string(), value.to_vec());
+            }
+        }
+
+        Ok(metadata)
+    }
+
+    /// The value of the metadata note `key`
+    pub fn note(&self, key: &str) -> Option<&[u8]> {
+        self.notes.get(key).map(Vec::as_slice)
+    }
+
+    /// The value of the metadata note `key`, if it is text
+    pub fn note_str(&self, key: &str) -> Option<&str> {
+        std::str::from_utf8(self.note(key)?).ok()
+    }
+
+    /// All metadata notes, ordered by their key
+    pub fn notes(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.notes
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_slice()))
+    }
+
+    /// Name of the chip the application was built for, e.g. `esp32c3`
+    pub fn chip_name(&self) -> Option<&str> {
+        self.note_str("build_info.CHIP_NAME")
+    }
+
+    /// The chip the application was built for, if it is known
+    pub fn chip(&self) -> Option<Chip> {
+        self.chip_name()?.parse().ok()
+    }
+
+    /// Log format the application uses, e.g. `defmt`
+    pub fn log_format(&self) -> Option<&str> {
+        self.note_str("espflash.LOG_FORMAT")
+    }
+
+    /// Application description of ESP-IDF
+    pub fn app_descriptor(&self) -> Option<&AppDescriptor> {
+        self.app_descriptor.as_ref()
+    }
+
+    /// Whether the ELF file contains defmt data
+    pub fn has_defmt(&self) -> bool {
+        self.defmt
+    }
+
+    /// Check that the application was built for `chip`, if the metadata names
+    /// a chip
+    pub fn check_chip(&self, chip: Chip) -> Result<(), Error> {
+        match self.chip_name() {
+            Some(name) if self.chip() != Some(chip) => Err(Error::ElfChipMismatch {
+                elf_chip: name.to_string(),
+                chip,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The chip to use for the application: `chip` if it is given, which must
+    /// match the one named by the metadata, otherwise the chip named by the
+    /// metadata
+    pub fn select_chip(&self, chip: Option<Chip>) -> Result<Chip, Error> {
+        match chip {
+            Some(chip) => self.check_chip(chip).map(|_| chip),
+            None => self.chip().ok_or(Error::ChipNotInElf),
+        }
+    }
+}
+
+/// The contents of the section `header`
+fn section_data<'a>(elf: &ElfFile<'a>, header: &SectionHeader<'a>) -> Option<&'a [u8]> {
+    match header.get_data(elf) {
+        Ok(SectionData::Undefined(data)) => Some(data),
+        _ => None,
+    }
+}
+
+fn serialize_hex<S: serde::Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    let hex = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    serializer.serialize_str(&hex)
+}
+
+/// Serialize the notes as text where possible
+fn serialize_notes<S: serde::Serializer>(
+    notes: &BTreeMap<String, Vec<u8>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(
+        notes
+            .iter()
+            .map(|(key, value)| (key, String::from_utf8_lossy(value))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_is_read_from_the_elf_file() {
+        let elf = std::fs::read("tests/resources/metadata.elf").unwrap();
+        let metadata = Metadata::from_bytes(&elf).unwrap();
+
+        assert_eq!(metadata.chip_name(), Some("esp32c3"));
+        assert_eq!(metadata.chip(), Some(Chip::Esp32c3));
+        assert_eq!(metadata.log_format(), Some("defmt"));
+        assert!(metadata.has_defmt());
+        assert_eq!(metadata.notes().count(), 2);
+
+        let desc = metadata.app_descriptor().unwrap();
+        assert_eq!(desc.project_name, "blink");
+        assert_eq!(desc.version, "1.2.3");
+        assert_eq!(desc.secure_version, 3);
+        assert_eq!(desc.date, "Oct 16 2026");
+        assert_eq!(desc.idf_version, "v5.3.1");
+        assert_eq!(desc.elf_sha256[31], 31);
+
+        assert!(metadata.check_chip(Chip::Esp32c3).is_ok());
+        assert!(matches!(
+            metadata.check_chip(Chip::Esp32c6),
+            Err(Error::ElfChipMismatch { .. })
+        ));
+        assert_eq!(metadata.select_chip(None).unwrap(), Chip::Esp32c3);
+        assert!(metadata.select_chip(Some(Chip::Esp32c6)).is_err());
+    }
+
+    #[test]
+    fn elf_files_without_metadata() {
+        let elf = std::fs::read("tests/resources/esp32_hal_blinky").unwrap();
+        let metadata = Metadata::from_bytes(&elf).unwrap();
+
+        assert_eq!(metadata, Metadata::default());
+        assert!(metadata.check_chip(Chip::Esp32c6).is_ok());
+        assert_eq!(
+            metadata.select_chip(Some(Chip::Esp32c6)).unwrap(),
+            Chip::Esp32c6
+        );
+        assert!(matches!(
+            metadata.select_chip(None),
+            Err(Error::ChipNotInElf)
+        ));
+    }
+}
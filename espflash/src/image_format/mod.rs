@@ -0,0 +1,2169 @@
+//! ESP-IDF application binary image format
+
+pub mod ihex;
+pub mod metadata;
+#[cfg(feature = "security")]
+pub mod signing;
+pub mod uf2;
+
+use std::{borrow::Cow, io::Write, iter::once, mem::size_of, ops::Range};
+
+use bytemuck::{bytes_of, from_bytes, Pod, Zeroable};
+use esp_idf_part::{Partition, PartitionTable, Type};
+use log::debug;
+use sha2::{Digest, Sha256};
+use strum::IntoEnumIterator;
+
+use self::metadata::AppDescriptor;
+#[cfg(feature = "security")]
+use self::signing::sign_image;
+use crate::{
+    elf::{CodeSegment, ElfFirmwareImage, FirmwareImage, RomSegment},
+    error::Error,
+    flasher::{
+        ExtraAppPartitions, FlashData, FlashFrequency, FlashMode, FlashSettings, FlashSize,
+        MAX_PARTITION_TABLE_SIZE,
+    },
+    targets::{Chip, Esp32Params, XtalFrequency},
+};
+
+const ESP_CHECKSUM_MAGIC: u8 = 0xef;
+/// First byte of every application and bootloader image
+pub const ESP_MAGIC: u8 = 0xE9;
+const IROM_ALIGN: u32 = 0x10000;
+/// The bootloader and the partition table start at the start of a flash sector
+const SECTOR_ALIGN: u32 = 0x1000;
+const SEG_HEADER_LEN: u32 = 8;
+const WP_PIN_DISABLED: u8 = 0xEE;
+const DIGEST_LEN: usize = 32;
+/// Magic word the ROM checks for twice at flash offset 0 to direct boot
+const DIRECT_BOOT_MAGIC: &[u8] = &[0x1d, 0x04, 0xdb, 0xae, 0x1d, 0x04, 0xdb, 0xae];
+
+/// Supported binary image formats
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ImageFormatKind {
+    /// Application image booted by the second-stage bootloader of ESP-IDF
+    #[default]
+    EspIdf,
+    /// Image without a bootloader, booted directly by the ROM of the chip
+    DirectBoot,
+}
+
+/// Firmware header used by the ESP-IDF bootloader.
+///
+/// ## Header documentation:
+/// * [Header](https://docs.espressif.com/projects/esptool/en/latest/esp32c3/advanced-topics/firmware-image-format.html#file-header)
+/// * [Extended header](https://docs.espressif.com/projects/esptool/en/latest/esp32c3/advanced-topics/firmware-image-format.html#extended-file-header)
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+#[doc(alias = "esp_image_header_t")]
+struct ImageHeader {
+    magic: u8,
+    segment_count: u8,
+    /// Flash read mode (esp_image_spi_mode_t)
+    flash_mode: u8,
+    /// ..4 bits are flash chip size (esp_image_flash_size_t)
+    /// 4.. bits are flash frequency (esp_image_spi_freq_t)
+    #[doc(alias = "spi_size")]
+    #[doc(alias = "spi_speed")]
+    flash_config: u8,
+    entry: u32,
+
+    // extended header part
+    wp_pin: u8,
+    clk_q_drv: u8,
+    d_cs_drv: u8,
+    gd_wp_drv: u8,
+    chip_id: u16,
+    min_rev: u8,
+    /// Minimum chip revision supported by image, in format: major * 100 + minor
+    min_chip_rev_full: u16,
+    /// Maximal chip revision supported by image, in format: major * 100 + minor
+    max_chip_rev_full: u16,
+    reserved: [u8; 4],
+    append_digest: u8,
+}
+
+impl Default for ImageHeader {
+    fn default() -> Self {
+        Self {
+            magic: ESP_MAGIC,
+            segment_count: 3,
+            flash_mode: FlashMode::default() as _,
+            flash_config: ((FlashSize::default() as u8) << 4) | FlashFrequency::default() as u8,
+            entry: 0,
+            wp_pin: WP_PIN_DISABLED,
+            clk_q_drv: 0,
+            d_cs_drv: 0,
+            gd_wp_drv: 0,
+            chip_id: Default::default(),
+            min_rev: 0,
+            min_chip_rev_full: 0,
+            max_chip_rev_full: u16::MAX,
+            reserved: Default::default(),
+            append_digest: 1,
+        }
+    }
+}
+
+impl ImageHeader {
+    /// Updates flash size and speed filed.
+    pub fn write_flash_config(
+        &mut self,
+        size: FlashSize,
+        freq: FlashFrequency,
+        chip: Chip,
+    ) -> Result<(), Error> {
+        let flash_size = size.encode_flash_size()?;
+        let flash_speed = freq.encode_flash_frequency(chip)?;
+
+        // bit field
+        self.flash_config = (flash_size << 4) | flash_speed;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Pod, Zeroable)]
+#[repr(C, packed)]
+struct SegmentHeader {
+    addr: u32,
+    length: u32,
+}
+
+/// Image format for ESP32 family chips using the second-stage bootloader from
+/// ESP-IDF
+pub struct IdfBootloaderFormat<'a> {
+    params: Esp32Params,
+    bootloader: Cow<'a, [u8]>,
+    partition_table: PartitionTable,
+    flash_segment: RomSegment<'a>,
+    /// Offsets of the app partitions a copy of the application is written to
+    copy_offsets: Vec<u32>,
+    /// Sectors of the application image which are not written, see
+    /// [FirmwareImage::skipped_ranges]
+    skipped: Vec<Range<u32>>,
+    app_size: u32,
+    part_size: u32,
+    partition_table_offset: u32,
+}
+
+impl<'a> IdfBootloaderFormat<'a> {
+    pub fn new(
+        image: &'a dyn FirmwareImage<'a>,
+        chip: Chip,
+        mut params: Esp32Params,
+        flash_data: FlashData,
+    ) -> Result<Self, Error> {
+        let FlashData {
+            bootloader,
+            partition_table,
+            partition_table_offset,
+            bootloader_offset,
+            app_offset,
+            target_app_partition,
+            flash_settings,
+            min_chip_rev: min_rev_full,
+            mmu_page_size,
+            extra_app_partitions,
+            force_bootloader,
+            ..
+        } = flash_data;
+        let mmu_page_size = check_mmu_page_size(chip, mmu_page_size)?;
+
+        let partition_table = partition_table.unwrap_or_else(|| {
+            params.default_partition_table(flash_settings.size.map(|v| v.size()))
+        });
+        check_partition_table_fits(&partition_table, flash_settings.size.unwrap_or_default())?;
+
+        let custom_bootloader = bootloader.is_some();
+        let mut bootloader = if let Some(bytes) = bootloader {
+            Cow::Owned(bytes)
+        } else {
+            Cow::Borrowed(params.default_bootloader)
+        };
+
+        // fetch the generated header from the bootloader
+        if bootloader.len() < size_of::<ImageHeader>() {
+            return Err(Error::InvalidBootloader);
+        }
+        let mut header: ImageHeader = *from_bytes(&bootloader[0..size_of::<ImageHeader>()]);
+        if header.magic != ESP_MAGIC {
+            return Err(Error::InvalidBootloader);
+        }
+        if custom_bootloader && !force_bootloader {
+            check_bootloader_header(&header, chip, &params, &flash_settings)?;
+        }
+        // The header is rewritten below, so the page size is derived from the
+        // flash size the bootloader was built for first
+        let bootloader_page_size = if force_bootloader {
+            mmu_page_size
+        } else {
+            bootloader_mmu_page_size(chip, &header)
+        };
+
+        // update the header if a user has specified any custom arguments
+        if let Some(mode) = flash_settings.mode {
+            header.flash_mode = mode as u8;
+        }
+
+        header.write_flash_config(
+            flash_settings.size.unwrap_or_default(),
+            flash_settings.freq.unwrap_or(params.flash_freq),
+            chip,
+        )?;
+
+        bootloader.to_mut().splice(
+            0..size_of::<ImageHeader>(),
+            bytes_of(&header).iter().copied(),
+        );
+
+        // re-calculate hash of the bootloader - needed since we modified the header.
+        // Anything following the digest, such as a secure boot signature block, is
+        // left as it is.
+        let integrity = check_image_layout(&bootloader).map_err(|_| Error::InvalidBootloader)?;
+        if integrity.digest.is_some() {
+            let digest_start = integrity.len - DIGEST_LEN;
+            let hash = Sha256::digest(&bootloader[..digest_start]);
+            bootloader.to_mut()[digest_start..integrity.len].copy_from_slice(&hash);
+        }
+
+        let mut placements = Vec::new();
+        let data = if let Some(app) = image.prebuilt() {
+            let app_header: &ImageHeader = from_bytes(&app[..size_of::<ImageHeader>()]);
+            if app_header.chip_id != params.chip_id {
+                return Err(Error::ImageChipMismatch {
+                    chip_id: app_header.chip_id,
+                    chip,
+                });
+            }
+
+            app.to_vec()
+        } else {
+            // write the header of the app
+            // use the same settings as the bootloader
+            // just update the entry point
+            header.entry = image.entry();
+
+            header.wp_pin = WP_PIN_DISABLED;
+            header.chip_id = params.chip_id;
+            header.min_chip_rev_full = min_rev_full;
+            header.append_digest = 1;
+
+            let mut data = bytes_of(&header).to_vec();
+
+            let flash_segments: Vec<_> =
+                merge_adjacent_segments(image.rom_segments(chip).collect());
+            let mut ram_segments: Vec<_> =
+                merge_adjacent_segments(image.ram_segments(chip).collect());
+
+            let mut checksum = ESP_CHECKSUM_MAGIC;
+            let mut segment_count = 0;
+            let mut previous = None;
+
+            for segment in flash_segments {
+                loop {
+                    let pad_len = get_segment_padding(data.len(), &segment, mmu_page_size);
+                    if pad_len > 0 {
+                        if pad_len > SEG_HEADER_LEN {
+                            if let Some(ram_segment) = ram_segments.first_mut() {
+                                // save up to `pad_len` from the ram segment, any remaining bits in the
+                                // ram segments will be saved later
+                                let pad_segment = ram_segment.split_off(pad_len as usize);
+                                placements.push(FlashPlacement::of(&pad_segment, &data));
+                                checksum = save_segment(&mut data, &pad_segment, checksum)?;
+                                if ram_segment.data().is_empty() {
+                                    ram_segments.remove(0);
+                                }
+                                segment_count += 1;
+                                continue;
+                            }
+                        }
+
+                        let pad_header = SegmentHeader {
+                            addr: 0,
+                            length: pad_len,
+                        };
+                        data.write_all(bytes_of(&pad_header))?;
+
+                        for _ in 0..pad_len {
+                            data.write_all(&[0])?;
+                        }
+
+                        segment_count += 1;
+                    } else {
+                        break;
+                    }
+                }
+
+                let placed = FlashPlacement::of(&segment, &data);
+                check_shared_page(previous.as_ref(), &placed, mmu_page_size)?;
+                placements.push(placed.clone());
+
+                checksum = save_flash_segment(&mut data, segment, checksum, mmu_page_size)?;
+                segment_count += 1;
+                previous = Some(FlashPlacement {
+                    end: placed.addr + (data.len() as u32 - placed.offset),
+                    ..placed
+                });
+            }
+
+            for segment in ram_segments {
+                placements.push(FlashPlacement::of(&segment, &data));
+                checksum = save_segment(&mut data, &segment, checksum)?;
+                segment_count += 1;
+            }
+
+            let padding = 15 - (data.len() % 16);
+            let padding = &[0u8; 16][0..padding];
+            data.write_all(padding)?;
+
+            data.write_all(&[checksum])?;
+
+            // since we added some dummy segments, we need to patch the segment count
+            data[1] = segment_count as u8;
+
+            let mut hasher = Sha256::new();
+            hasher.update(&data);
+            let hash = hasher.finalize();
+            data.write_all(&hash)?;
+
+            data
+        };
+
+        // Secure Boot V2 verifies the signature sector following the image
+        #[cfg(feature = "security")]
+        let data = match flash_data.signing_key {
+            Some(key) => sign_image(&data, &key, chip)?,
+            None => data,
+        };
+
+        let partition_at_app_offset = app_offset.and_then(|offset| {
+            partition_table
+                .partitions()
+                .iter()
+                .find(|p| p.offset() == offset && p.ty() == Type::App)
+        });
+
+        let target_app_partition: &Partition =
+        // An app partition at the requested offset is the target
+        if let Some(partition) = partition_at_app_offset {
+            partition
+        // Use the target app partition if provided
+        } else if let Some(target_partition) = target_app_partition {
+            partition_table
+                .find(&target_partition)
+                .ok_or(Error::AppPartitionNotFound)?
+        } else {
+
+            // The default partition table contains the "factory" partition, and if a user
+            // provides a partition table via command-line then the validation step confirms
+            // that at least one "app" partition is present. We prefer the "factory"
+            // partition, and use any available "app" partitions if not present.
+
+            partition_table
+                .find("factory")
+                .or_else(|| partition_table.find_by_type(Type::App))
+                .ok_or(Error::AppPartitionNotFound)?
+        };
+
+        // If the user did not specify a partition offset, we need to assume that the
+        // partition offset is (first partition offset) - 0x1000, since this is
+        // the most common case.
+        let partition_table_offset = partition_table_offset.unwrap_or_else(|| {
+            let partitions = partition_table.partitions();
+            let first_partition = partitions
+                .iter()
+                .min_by(|a, b| a.offset().cmp(&b.offset()))
+                .unwrap();
+            first_partition.offset() - 0x1000
+        });
+
+        if let Some(offset) = bootloader_offset {
+            params.boot_addr = offset;
+        }
+        let (app_offset, part_size) = check_image_offsets(
+            &params,
+            bootloader.len() as u32,
+            &partition_table,
+            partition_table_offset,
+            app_offset,
+            mmu_page_size,
+            flash_settings.size.unwrap_or_default(),
+        )?
+        .unwrap_or((target_app_partition.offset(), target_app_partition.size()));
+
+        let app_size = data.len() as u32;
+
+        // The size of the application must not exceed the size of the target app
+        // partition.
+        if app_size as f32 / part_size as f32 > 1.0 {
+            return Err(Error::ElfTooBig(app_size, part_size));
+        }
+
+        let copy_offsets = extra_app_partitions_offsets(
+            &partition_table,
+            target_app_partition,
+            &extra_app_partitions,
+            app_size,
+        )?;
+
+        for offset in once(app_offset).chain(copy_offsets.iter().copied()) {
+            verify_layout(chip, &data, offset, part_size, bootloader_page_size)?;
+        }
+
+        let skipped = skipped_sectors(&image.skipped_ranges(), &placements);
+
+        let flash_segment = RomSegment {
+            addr: app_offset,
+            data: Cow::Owned(data),
+        };
+
+        Ok(Self {
+            params,
+            bootloader,
+            partition_table,
+            flash_segment,
+            copy_offsets,
+            skipped,
+            app_size,
+            part_size,
+            partition_table_offset,
+        })
+    }
+
+    /// Segments to write for a full flash: the bootloader, the partition table
+    /// and the application image, in that order
+    ///
+    /// If extra app partitions were requested, the application image is written
+    /// to each of them as well, ordered by their offset.
+    pub fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        let bootloader_segment = RomSegment {
+            addr: self.params.boot_addr,
+            data: Cow::Borrowed(&self.bootloader),
+        };
+
+        let partition_table_segment = RomSegment {
+            addr: self.partition_table_offset,
+            data: Cow::Owned(self.partition_table.to_bin().unwrap()),
+        };
+
+        Box::new(
+            once(bootloader_segment)
+                .chain(once(partition_table_segment))
+                .chain(self.app_segments()),
+        )
+    }
+
+    /// Segments to write for the application only: the application image at
+    /// the target app partition and any extra app partitions, ordered by their
+    /// offset
+    ///
+    /// Sectors holding only segments excluded by a
+    /// [SegmentFilter](crate::elf::SegmentFilter) are left out.
+    pub fn app_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        let mut app_offsets = self.copy_offsets.clone();
+        app_offsets.push(self.flash_segment.addr);
+        app_offsets.sort_unstable();
+
+        Box::new(
+            app_offsets
+                .into_iter()
+                .flat_map(|addr| written_parts(addr, &self.flash_segment.data, &self.skipped)),
+        )
+    }
+
+    /// Segments to write for an over-the-air update, which is only the
+    /// application image placed at the offset of the target app partition
+    ///
+    /// The whole image is returned, regardless of any skipped segments.
+    pub fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        Box::new(once(self.flash_segment.borrow()))
+    }
+
+    /// Size of the application image in bytes
+    pub fn app_size(&self) -> u32 {
+        self.app_size
+    }
+
+    /// Size of the target app partition in bytes
+    pub fn part_size(&self) -> Option<u32> {
+        Some(self.part_size)
+    }
+}
+
+/// Image format for RISC-V chips whose ROM boots the application directly,
+/// without a second-stage bootloader
+///
+/// The flash-mapped segments are written at their offset in the mapped
+/// address range, starting with the direct boot magic word at offset 0.
+pub struct DirectBootFormat<'a> {
+    segment: RomSegment<'a>,
+    /// Sectors of the image which are not written, see
+    /// [FirmwareImage::skipped_ranges]
+    skipped: Vec<Range<u32>>,
+}
+
+impl<'a> DirectBootFormat<'a> {
+    pub fn new(image: &'a dyn FirmwareImage<'a>, chip: Chip) -> Result<Self, Error> {
+        let target = chip.into_target();
+
+        let flash_range = |addr: u32| {
+            target
+                .flash_ranges()
+                .iter()
+                .find(|range| range.contains(&addr))
+        };
+
+        let mut segments = image
+            .segments_with_load_addresses()
+            .map(|mut segment| {
+                let range = flash_range(segment.addr).ok_or_else(|| {
+                    Error::InvalidDirectBootBinary(format!(
+                        "the segment at {:#010x} is not loaded from flash, which the ROM \
+                         does not do when direct booting",
+                        segment.addr
+                    ))
+                })?;
+                segment.addr -= range.start;
+                Ok(segment)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        segments.sort();
+
+        for pair in segments.windows(2) {
+            if pair[0].addr + pair[0].size() > pair[1].addr {
+                return Err(Error::InvalidDirectBootBinary(format!(
+                    "the segments at flash offsets {:#x} and {:#x} overlap",
+                    pair[0].addr, pair[1].addr
+                )));
+            }
+        }
+
+        let mut segment = segments
+            .iter()
+            .fold(CodeSegment::default(), |mut image, segment| {
+                image += segment;
+                image
+            });
+        segment.pad_align(4);
+
+        if !segment.data().starts_with(DIRECT_BOOT_MAGIC) {
+            return Err(Error::InvalidDirectBootBinary(
+                "the image does not start with the direct boot magic word".into(),
+            ));
+        }
+
+        // The image is placed as it is mapped, so it is its own placement
+        let placements = [FlashPlacement {
+            addr: 0,
+            offset: 0,
+            end: segment.size(),
+        }];
+        let skipped_ranges = image
+            .skipped_ranges()
+            .into_iter()
+            .filter_map(|range| {
+                let start = flash_range(range.start)?.start;
+                Some(range.start - start..range.end - start)
+            })
+            .collect::<Vec<_>>();
+        let skipped = skipped_sectors(&skipped_ranges, &placements);
+
+        Ok(Self {
+            segment: segment.into(),
+            skipped,
+        })
+    }
+
+    /// Segments to write for a full flash, which is only the image at flash
+    /// offset 0, leaving out the sectors holding only skipped segments
+    pub fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        Box::new(written_parts(
+            self.segment.addr,
+            &self.segment.data,
+            &self.skipped,
+        ))
+    }
+
+    /// Segments to write for the application only, the same as
+    /// [DirectBootFormat::flash_segments] as there is no bootloader
+    pub fn app_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        self.flash_segments()
+    }
+
+    /// Segments to save as the application image, the whole image regardless
+    /// of any skipped segments
+    pub fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        Box::new(once(self.segment.borrow()))
+    }
+
+    /// Size of the image in bytes
+    pub fn app_size(&self) -> u32 {
+        self.segment.data.len() as u32
+    }
+
+    /// There are no partitions when direct booting
+    pub fn part_size(&self) -> Option<u32> {
+        None
+    }
+}
+
+/// An image built for flashing, in one of the [ImageFormatKind]s
+pub enum ImageFormat<'a> {
+    EspIdf(IdfBootloaderFormat<'a>),
+    DirectBoot(DirectBootFormat<'a>),
+}
+
+impl<'a> ImageFormat<'a> {
+    /// Segments to write for a full flash
+    pub fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        match self {
+            ImageFormat::EspIdf(image) => image.flash_segments(),
+            ImageFormat::DirectBoot(image) => image.flash_segments(),
+        }
+    }
+
+    /// Segments to write for the application only
+    pub fn app_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        match self {
+            ImageFormat::EspIdf(image) => image.app_segments(),
+            ImageFormat::DirectBoot(image) => image.app_segments(),
+        }
+    }
+
+    /// Segments to write for an over-the-air update
+    pub fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
+    where
+        'a: 'b,
+    {
+        match self {
+            ImageFormat::EspIdf(image) => image.ota_segments(),
+            ImageFormat::DirectBoot(image) => image.ota_segments(),
+        }
+    }
+
+    /// Size of the application image in bytes
+    pub fn app_size(&self) -> u32 {
+        match self {
+            ImageFormat::EspIdf(image) => image.app_size(),
+            ImageFormat::DirectBoot(image) => image.app_size(),
+        }
+    }
+
+    /// Size of the target app partition in bytes, if there is one
+    pub fn part_size(&self) -> Option<u32> {
+        match self {
+            ImageFormat::EspIdf(image) => image.part_size(),
+            ImageFormat::DirectBoot(image) => image.part_size(),
+        }
+    }
+}
+
+/// Check that a bootloader was built for `chip` and the requested flash mode
+///
+/// The flash settings in the header are rewritten before writing the
+/// bootloader, but the code of the bootloader only enables the quad flash modes
+/// it was configured for.
+fn check_bootloader_header(
+    header: &ImageHeader,
+    chip: Chip,
+    params: &Esp32Params,
+    flash_settings: &FlashSettings,
+) -> Result<(), Error> {
+    if header.chip_id != params.chip_id {
+        let built_for = Chip::iter()
+            .find(|chip| chip.into_target().params().chip_id == header.chip_id)
+            .map_or_else(
+                || format!("the chip with ID {}", { header.chip_id }),
+                |chip| format!("the {chip}"),
+            );
+
+        return Err(Error::BootloaderMismatch(format!(
+            "the bootloader was built for {built_for}, but the target is the {chip}"
+        )));
+    }
+
+    if let Some(mode) = flash_settings.mode {
+        if header.flash_mode != mode as u8 {
+            let built_mode = [
+                FlashMode::Qio,
+                FlashMode::Qout,
+                FlashMode::Dio,
+                FlashMode::Dout,
+            ]
+            .into_iter()
+            .find(|m| *m as u8 == header.flash_mode)
+            .map_or_else(
+                || format!("the flash mode {}", header.flash_mode),
+                |m| format!("{m:?}").to_uppercase(),
+            );
+
+            return Err(Error::BootloaderMismatch(format!(
+                "the bootloader was built for {built_mode}, but {} was requested",
+                format!("{mode:?}").to_uppercase()
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Integrity information of an application or bootloader image
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ImageIntegrity {
+    /// Number of segments in the image
+    pub segment_count: u8,
+    /// Length of the image, up to and including the appended digest
+    pub len: usize,
+    /// Checksum of the segment data
+    pub checksum: u8,
+    /// SHA-256 digest appended to the image, if the header requests one
+    pub digest: Option<[u8; DIGEST_LEN]>,
+}
+
+/// Check the checksum and the appended SHA-256 digest of an application or
+/// bootloader image, as the ROM and the bootloader do before booting it
+///
+/// Data following the image, e.g. padding or a signature block, is ignored.
+pub fn check_image(data: &[u8]) -> Result<ImageIntegrity, Error> {
+    let integrity = check_image_layout(data)?;
+
+    let checksum_pos = integrity.len - integrity.digest.map_or(0, |_| DIGEST_LEN) - 1;
+    if data[checksum_pos] != integrity.checksum {
+        return Err(Error::ImageChecksumMismatch {
+            expected: integrity.checksum,
+            actual: data[checksum_pos],
+        });
+    }
+
+    if let Some(digest) = integrity.digest {
+        let actual = Sha256::digest(&data[..=checksum_pos]);
+        if actual.as_slice() != digest {
+            let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{b:02x}")).collect();
+            return Err(Error::ImageDigestMismatch {
+                expected: hex(&actual),
+                actual: hex(&digest),
+            });
+        }
+    }
+
+    Ok(integrity)
+}
+
+/// An application image which has already been built, e.g. by ESP-IDF
+///
+/// The image is flashed as it is, together with the bootloader and partition
+/// table, instead of being built from an ELF file.
+pub struct AppImage<'a> {
+    data: &'a [u8],
+    entry: u32,
+    segments: Vec<(u32, Range<usize>)>,
+}
+
+impl<'a> AppImage<'a> {
+    /// Parse an application image, checking its checksum and appended digest
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        check_image(data)?;
+
+        let header: &ImageHeader = from_bytes(&data[..size_of::<ImageHeader>()]);
+        let mut pos = size_of::<ImageHeader>();
+        let mut segments = Vec::new();
+        for _ in 0..header.segment_count {
+            let segment: &SegmentHeader = from_bytes(&data[pos..pos + size_of::<SegmentHeader>()]);
+            pos += size_of::<SegmentHeader>();
+
+            let length = segment.length as usize;
+            segments.push((segment.addr, pos..pos + length));
+            pos += length;
+        }
+
+        Ok(Self {
+            data,
+            entry: header.entry,
+            segments,
+        })
+    }
+}
+
+impl<'a> FirmwareImage<'a> for AppImage<'a> {
+    fn entry(&self) -> u32 {
+        self.entry
+    }
+
+    fn segments(&'a self) -> Box<dyn Iterator<Item = CodeSegment<'a>> + 'a> {
+        Box::new(
+            self.segments
+                .iter()
+                .map(|(addr, range)| CodeSegment::new(*addr, &self.data[range.clone()])),
+        )
+    }
+
+    fn segments_with_load_addresses(&'a self) -> Box<dyn Iterator<Item = CodeSegment<'a>> + 'a> {
+        self.segments()
+    }
+
+    fn prebuilt(&self) -> Option<&[u8]> {
+        Some(self.data)
+    }
+}
+
+/// A segment of an application or bootloader image
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ImageSegment {
+    /// Address the segment is loaded or mapped to
+    pub addr: u32,
+    /// Length of the segment data
+    pub len: u32,
+    /// Offset of the segment data in the image
+    pub offset: usize,
+}
+
+/// The header fields, segments and integrity of an application or bootloader
+/// image, like `esptool.py image_info --version 2` prints them
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ImageInfo {
+    /// Chip ID in the extended header
+    pub chip_id: u16,
+    /// The chip with the ID, if it is supported
+    pub chip: Option<Chip>,
+    /// Entry point
+    pub entry: u32,
+    /// Flash mode, if the value is known
+    pub flash_mode: Option<FlashMode>,
+    /// Flash size, if the value is known
+    pub flash_size: Option<FlashSize>,
+    /// Flash frequency, if the value is known for the chip
+    pub flash_frequency: Option<FlashFrequency>,
+    /// Write protect pin, `0xee` if it is disabled
+    pub wp_pin: u8,
+    /// Minimum chip revision, in the format `major * 100 + minor`
+    pub min_chip_rev: u16,
+    /// Maximum chip revision, in the format `major * 100 + minor`
+    pub max_chip_rev: u16,
+    /// Segments of the image
+    pub segments: Vec<ImageSegment>,
+    /// Checksum stored in the image
+    pub checksum: u8,
+    /// Checksum of the segment data
+    pub calculated_checksum: u8,
+    /// SHA-256 digest appended to the image, if the header requests one
+    pub digest: Option<[u8; DIGEST_LEN]>,
+    /// SHA-256 digest of the image up to the appended digest
+    pub calculated_digest: [u8; DIGEST_LEN],
+    /// Length of the image, up to and including the appended digest
+    pub len: usize,
+    /// Application description, at the start of the first segment of an
+    /// application image
+    pub app_descriptor: Option<AppDescriptor>,
+}
+
+impl ImageInfo {
+    /// Parse an application or bootloader image, without failing when its
+    /// checksum or digest does not match, see [check_image]
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let integrity = check_image_layout(data)?;
+        let header: &ImageHeader = from_bytes(&data[..size_of::<ImageHeader>()]);
+
+        let mut pos = size_of::<ImageHeader>();
+        let mut segments = Vec::new();
+        for _ in 0..header.segment_count {
+            let segment: &SegmentHeader = from_bytes(&data[pos..pos + size_of::<SegmentHeader>()]);
+            pos += size_of::<SegmentHeader>();
+
+            segments.push(ImageSegment {
+                addr: segment.addr,
+                len: segment.length,
+                offset: pos,
+            });
+            pos += segment.length as usize;
+        }
+
+        let digest_start = integrity.len - integrity.digest.map_or(0, |_| DIGEST_LEN);
+        let chip = Chip::iter().find(|chip| chip.into_target().params().chip_id == header.chip_id);
+        let flash_frequency = chip.and_then(|chip| {
+            chip.into_target()
+                .flash_frequency_encodings()
+                .into_iter()
+                .find(|(_, encoded)| *encoded == header.flash_config & 0xf)
+                .map(|(frequency, _)| frequency)
+        });
+
+        Ok(Self {
+            chip_id: header.chip_id,
+            chip,
+            entry: header.entry,
+            flash_mode: [
+                FlashMode::Qio,
+                FlashMode::Qout,
+                FlashMode::Dio,
+                FlashMode::Dout,
+            ]
+            .into_iter()
+            .find(|mode| *mode as u8 == header.flash_mode),
+            flash_size: FlashSize::iter()
+                .find(|size| size.encode_flash_size().ok() == Some(header.flash_config >> 4)),
+            flash_frequency,
+            wp_pin: header.wp_pin,
+            min_chip_rev: header.min_chip_rev_full,
+            max_chip_rev: header.max_chip_rev_full,
+            app_descriptor: segments
+                .first()
+                .and_then(|segment| AppDescriptor::from_bytes(&data[segment.offset..])),
+            segments,
+            checksum: data[digest_start - 1],
+            calculated_checksum: integrity.checksum,
+            digest: integrity.digest,
+            calculated_digest: Sha256::digest(&data[..digest_start]).into(),
+            len: integrity.len,
+        })
+    }
+
+    /// Whether the stored checksum matches the segment data
+    pub fn checksum_valid(&self) -> bool {
+        self.checksum == self.calculated_checksum
+    }
+
+    /// Whether the appended digest matches the image, if there is one
+    pub fn digest_valid(&self) -> Option<bool> {
+        self.digest.map(|digest| digest == self.calculated_digest)
+    }
+}
+
+/// Find the length of an image, including its checksum and appended digest,
+/// by only reading its headers
+///
+/// `read` reads the given number of bytes at an offset into the image, so that
+/// the length of an image on a device can be found without reading all of it.
+pub fn read_image_len(
+    mut read: impl FnMut(u32, u32) -> Result<Vec<u8>, Error>,
+) -> Result<u32, Error> {
+    let invalid = |reason: &str| Error::InvalidImage(reason.to_string());
+
+    let header: ImageHeader = *from_bytes(&read(0, size_of::<ImageHeader>() as u32)?);
+    if header.magic != ESP_MAGIC {
+        return Err(invalid("the image does not start with the magic byte 0xE9"));
+    }
+
+    let out_of_range = || invalid("a segment length is out of range");
+
+    let mut pos = size_of::<ImageHeader>() as u32;
+    for _ in 0..header.segment_count {
+        let segment_header: SegmentHeader = *from_bytes(&read(pos, SEG_HEADER_LEN)?);
+        pos = pos
+            .checked_add(SEG_HEADER_LEN)
+            .and_then(|pos| pos.checked_add(segment_header.length))
+            .ok_or_else(out_of_range)?;
+    }
+
+    // The checksum byte follows the segments, padded to 16 bytes
+    let mut len = (pos | 15).checked_add(1).ok_or_else(out_of_range)?;
+    if header.append_digest == 1 {
+        len = len
+            .checked_add(DIGEST_LEN as u32)
+            .ok_or_else(out_of_range)?;
+    }
+
+    Ok(len)
+}
+
+/// Walk the segments of an image, computing its checksum and finding its end
+/// without validating the stored checksum or digest
+fn check_image_layout(data: &[u8]) -> Result<ImageIntegrity, Error> {
+    let invalid = |reason: &str| Error::InvalidImage(reason.to_string());
+
+    let header: &ImageHeader = data
+        .get(..size_of::<ImageHeader>())
+        .map(from_bytes)
+        .ok_or_else(|| invalid("the image is shorter than its header"))?;
+    if header.magic != ESP_MAGIC {
+        return Err(invalid("the image does not start with the magic byte 0xE9"));
+    }
+
+    let mut pos = size_of::<ImageHeader>();
+    let mut checksum = ESP_CHECKSUM_MAGIC;
+    for _ in 0..header.segment_count {
+        let segment_header: &SegmentHeader = data
+            .get(pos..pos + size_of::<SegmentHeader>())
+            .map(from_bytes)
+            .ok_or_else(|| invalid("a segment header is truncated"))?;
+        pos += size_of::<SegmentHeader>();
+
+        let length = segment_header.length as usize;
+        let segment = data
+            .get(pos..pos + length)
+            .ok_or_else(|| invalid("a segment is truncated"))?;
+        checksum = update_checksum(segment, checksum);
+        pos += length;
+    }
+
+    // The checksum is the last byte of the 16 byte block following the segments
+    let checksum_pos = pos + 15 - (pos % 16);
+    let mut len = checksum_pos + 1;
+    if data.len() < len {
+        return Err(invalid("the checksum is missing"));
+    }
+
+    let digest = if header.append_digest == 1 {
+        let digest = data
+            .get(len..len + DIGEST_LEN)
+            .ok_or_else(|| invalid("the appended SHA-256 digest is truncated"))?;
+        len += DIGEST_LEN;
+
+        Some(digest.try_into().unwrap())
+    } else {
+        None
+    };
+
+    Ok(ImageIntegrity {
+        segment_count: header.segment_count,
+        len,
+        checksum,
+        digest,
+    })
+}
+
+/// Resolve the offsets of the app partitions to write a copy of the application
+/// to, excluding the target app partition itself
+fn extra_app_partitions_offsets(
+    partition_table: &PartitionTable,
+    target_app_partition: &Partition,
+    extra_app_partitions: &ExtraAppPartitions,
+    app_size: u32,
+) -> Result<Vec<u32>, Error> {
+    let partitions = match extra_app_partitions {
+        ExtraAppPartitions::None => return Ok(Vec::new()),
+        ExtraAppPartitions::Labels(labels) => labels
+            .iter()
+            .map(|label| {
+                let partition = partition_table
+                    .find(label)
+                    .ok_or_else(|| Error::PartitionNotFound(label.clone()))?;
+                if partition.ty() != Type::App {
+                    return Err(Error::NotAnAppPartition(label.clone()));
+                }
+
+                Ok(partition)
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        ExtraAppPartitions::All => partition_table
+            .partitions()
+            .iter()
+            .filter(|partition| partition.ty() == Type::App)
+            .collect(),
+    };
+
+    let mut offsets = Vec::new();
+    for partition in partitions {
+        if partition.offset() == target_app_partition.offset()
+            || offsets.contains(&partition.offset())
+        {
+            continue;
+        }
+
+        if app_size > partition.size() {
+            return Err(Error::ElfTooBig(app_size, partition.size()));
+        }
+
+        offsets.push(partition.offset());
+    }
+
+    Ok(offsets)
+}
+
+/// Build the list of `(address, bytes)` pairs that would be written to flash
+/// for the given ELF file
+///
+/// This performs the same conversion as flashing an ELF with [Flasher], without
+/// requiring a connection to a device. Since the chip revision is not known,
+/// no revision specific adjustments are made to the image.
+///
+/// [Flasher]: crate::flasher::Flasher
+pub fn build_flash_plan(
+    elf_data: &[u8],
+    chip: Chip,
+    flash_data: FlashData,
+    xtal_freq: XtalFrequency,
+) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+    let image = ElfFirmwareImage::try_from(elf_data)?
+        .with_segment_filter(flash_data.segment_filter.clone())?;
+
+    build_image_flash_plan(&image, chip, flash_data, xtal_freq)
+}
+
+/// Build the list of `(address, bytes)` pairs that would be written to flash
+/// for the given firmware image, e.g. an [AppImage]
+pub fn build_image_flash_plan<'a>(
+    image: &'a dyn FirmwareImage<'a>,
+    chip: Chip,
+    flash_data: FlashData,
+    xtal_freq: XtalFrequency,
+) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+    let app_only = flash_data.app_only;
+    let image = chip
+        .into_target()
+        .get_flash_image(image, flash_data, None, xtal_freq)?;
+
+    let segments = if app_only {
+        image.app_segments()
+    } else {
+        image.flash_segments()
+    };
+    let plan = segments
+        .map(|segment| (segment.addr, segment.data.into_owned()))
+        .collect();
+
+    Ok(plan)
+}
+
+/// Label of the app partition at `offset`, in the partition table of
+/// `flash_data` or the default partition table of the chip
+pub fn app_partition_at(chip: Chip, flash_data: &FlashData, offset: u32) -> Result<String, Error> {
+    let default_table;
+    let partition_table = match &flash_data.partition_table {
+        Some(partition_table) => partition_table,
+        None => {
+            default_table = chip
+                .into_target()
+                .params()
+                .default_partition_table(flash_data.flash_settings.size.map(|size| size.size()));
+            &default_table
+        }
+    };
+
+    partition_table
+        .partitions()
+        .iter()
+        .find(|partition| partition.ty() == Type::App && partition.offset() == offset)
+        .map(|partition| partition.name())
+        .ok_or(Error::NoAppPartitionAt(offset))
+}
+
+/// Ensure that every partition lies within the flash size written to the
+/// bootloader header
+pub(crate) fn check_partition_table_fits(
+    partition_table: &PartitionTable,
+    flash_size: FlashSize,
+) -> Result<(), Error> {
+    let Some(end) = partition_table
+        .partitions()
+        .iter()
+        .map(|p| p.offset() + p.size())
+        .max()
+    else {
+        return Ok(());
+    };
+
+    if end > flash_size.size() {
+        let required = FlashSize::iter()
+            .find(|size| size.size() >= end)
+            .unwrap_or(FlashSize::_256Mb);
+        return Err(Error::PartitionTableExceedsFlashSize {
+            end,
+            flash_size,
+            required,
+        });
+    }
+
+    Ok(())
+}
+
+/// Check that the bootloader, the partition table and the application image do
+/// not overlap each other or the partitions, and fit into the flash
+///
+/// Returns the offset to write the application image at, and the space
+/// available to it there, if an offset was requested instead of the target app
+/// partition.
+fn check_image_offsets(
+    params: &Esp32Params,
+    bootloader_len: u32,
+    partition_table: &PartitionTable,
+    partition_table_offset: u32,
+    app_offset: Option<u32>,
+    mmu_page_size: u32,
+    flash_size: FlashSize,
+) -> Result<Option<(u32, u32)>, Error> {
+    let flash_end = flash_size.size();
+    let partitions_start = partition_table
+        .partitions()
+        .iter()
+        .map(|p| p.offset())
+        .min()
+        .unwrap_or(flash_end);
+
+    let bootloader_error = |reason: String| Error::InvalidImageOffset {
+        image: "bootloader",
+        offset: params.boot_addr,
+        reason,
+    };
+    let bootloader_end = params.boot_addr.saturating_add(bootloader_len);
+    if params.boot_addr % SECTOR_ALIGN != 0 {
+        return Err(bootloader_error(format!(
+            "the offset is not aligned to a flash sector ({SECTOR_ALIGN:#x} bytes)"
+        )));
+    }
+    if params.boot_addr < partition_table_offset && bootloader_end > partition_table_offset {
+        return Err(bootloader_error(format!(
+            "the bootloader ends at {bootloader_end:#x}, after the start of the partition table at {partition_table_offset:#x}"
+        )));
+    }
+    if bootloader_end > partitions_start {
+        return Err(bootloader_error(format!(
+            "the bootloader ends at {bootloader_end:#x}, after the start of the first partition at {partitions_start:#x}"
+        )));
+    }
+
+    let partition_table_end = partition_table_offset.saturating_add(MAX_PARTITION_TABLE_SIZE);
+    if partition_table_offset % SECTOR_ALIGN != 0 {
+        return Err(Error::InvalidImageOffset {
+            image: "partition table",
+            offset: partition_table_offset,
+            reason: format!(
+                "the offset is not aligned to a flash sector ({SECTOR_ALIGN:#x} bytes)"
+            ),
+        });
+    }
+    if partition_table_end > partitions_start {
+        return Err(Error::InvalidImageOffset {
+            image: "partition table",
+            offset: partition_table_offset,
+            reason: format!(
+                "the partition table ends at {partition_table_end:#x}, after the start of the first partition at {partitions_start:#x}"
+            ),
+        });
+    }
+
+    let Some(offset) = app_offset else {
+        return Ok(None);
+    };
+    let app_error = |reason: String| Error::InvalidImageOffset {
+        image: "application image",
+        offset,
+        reason,
+    };
+
+    if offset % mmu_page_size != 0 {
+        return Err(app_error(format!(
+            "the offset is not aligned to the MMU page size of {mmu_page_size:#x} bytes"
+        )));
+    }
+    if offset < partition_table_end.max(bootloader_end) {
+        return Err(app_error(
+            "the offset is within the bootloader or the partition table".into(),
+        ));
+    }
+    if offset >= flash_end {
+        return Err(app_error(format!(
+            "the offset is beyond the end of the flash of {flash_size}"
+        )));
+    }
+
+    let available = match partition_table
+        .partitions()
+        .iter()
+        .find(|p| (p.offset()..p.offset() + p.size()).contains(&offset))
+    {
+        Some(partition) if partition.ty() != Type::App => {
+            return Err(app_error(format!(
+                "the offset is within the {} partition '{}'",
+                partition.ty(),
+                partition.name()
+            )));
+        }
+        Some(partition) => partition.offset() + partition.size() - offset,
+        // Up to the next partition, or the end of the flash
+        None => {
+            partition_table
+                .partitions()
+                .iter()
+                .map(|p| p.offset())
+                .filter(|&start| start > offset)
+                .min()
+                .unwrap_or(flash_end)
+                .min(flash_end)
+                - offset
+        }
+    };
+
+    Ok(Some((offset, available)))
+}
+
+/// Validate the requested MMU page size for the given chip, returning the page
+/// size to align flash segments to
+///
+/// Chips without a configurable MMU page size always use [IROM_ALIGN].
+fn check_mmu_page_size(chip: Chip, mmu_page_size: Option<u32>) -> Result<u32, Error> {
+    let valid = chip.valid_mmu_page_sizes().unwrap_or(&[IROM_ALIGN]);
+
+    match mmu_page_size {
+        None => Ok(IROM_ALIGN),
+        Some(size) if valid.contains(&size) => Ok(size),
+        Some(size) => Err(Error::InvalidMmuPageSize {
+            chip,
+            size,
+            valid: valid
+                .iter()
+                .map(|size| format!("{}KB", size / 1024))
+                .collect::<Vec<_>>()
+                .join(", "),
+        }),
+    }
+}
+
+/// The MMU page size the bootloader was built with
+///
+/// ESP-IDF only allows smaller pages than [IROM_ALIGN] with small flash chips,
+/// so the page size is derived from the flash size in the header of the
+/// bootloader, like the `MMU_PAGE_SIZE` option does.
+fn bootloader_mmu_page_size(chip: Chip, header: &ImageHeader) -> u32 {
+    let flash_size = FlashSize::iter()
+        .find(|size| size.encode_flash_size().ok() == Some(header.flash_config >> 4));
+    let page_size = match flash_size {
+        Some(FlashSize::_1Mb) => 0x8000,
+        _ => IROM_ALIGN,
+    };
+
+    match chip.valid_mmu_page_sizes() {
+        Some(valid) if valid.contains(&page_size) => page_size,
+        _ => IROM_ALIGN,
+    }
+}
+
+/// Check that the bootloader can map the flash segments of the application
+/// image written at `app_offset`, in pages of `mmu_page_size` bytes
+///
+/// The bootloader maps each segment whose address is in flash by whole pages,
+/// so its offset in flash must be at the same offset within a page as its
+/// address. On failure, the mapping of each segment is listed.
+fn verify_layout(
+    chip: Chip,
+    data: &[u8],
+    app_offset: u32,
+    part_size: u32,
+    mmu_page_size: u32,
+) -> Result<(), Error> {
+    let target = chip.into_target();
+    let info = ImageInfo::parse(data)?;
+
+    let mut reason = None;
+    let mut layout = String::new();
+    for (index, segment) in info.segments.iter().enumerate() {
+        if !target.addr_is_flash(segment.addr) {
+            continue;
+        }
+
+        let flash_offset = app_offset + segment.offset as u32;
+        let flash_end = flash_offset + segment.len;
+        let page_offset = segment.addr % mmu_page_size;
+        let aligned = flash_offset % mmu_page_size == page_offset;
+        layout += &format!(
+            "  segment {index}: {:#010x}..{:#010x} mapped from flash {flash_offset:#x}..{flash_end:#x}, at {:#x} into a page of flash and {page_offset:#x} of memory{}\n",
+            segment.addr,
+            segment.addr + segment.len,
+            flash_offset % mmu_page_size,
+            if aligned { "" } else { " (misaligned)" }
+        );
+
+        if reason.is_some() {
+            continue;
+        }
+        if app_offset % mmu_page_size != 0 {
+            reason = Some(format!(
+                "the application image at {app_offset:#x} does not start at a page boundary"
+            ));
+        } else if !aligned {
+            reason = Some(format!(
+                "segment {index} is not at the same offset within a page in flash and in memory"
+            ));
+        } else if flash_end > app_offset + part_size {
+            reason = Some(format!(
+                "segment {index} ends beyond the app partition of {part_size:#x} bytes"
+            ));
+        }
+    }
+
+    match reason {
+        Some(reason) => Err(Error::InvalidImageLayout {
+            mmu_page_size,
+            reason,
+            layout: layout.trim_end().to_string(),
+        }),
+        None => {
+            debug!(
+                "Flash mapping of the application image at {app_offset:#x}:\n{}",
+                layout.trim_end()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Actual alignment (in data bytes) required for a segment header: positioned
+/// so that after we write the next 8 byte header, file_offset % align ==
+/// segment.addr % align, where `align` is the MMU page size
+///
+/// (this is because the segment's vaddr may not be page aligned, more likely is
+/// aligned align+0x18 to account for the binary file header)
+fn get_segment_padding(offset: usize, segment: &CodeSegment, align: u32) -> u32 {
+    let align_past = (segment.addr - SEG_HEADER_LEN) % align;
+    let pad_len = ((align - ((offset as u32) % align)) + align_past) % align;
+
+    if pad_len == 0 || pad_len == align {
+        0
+    } else if pad_len > SEG_HEADER_LEN {
+        pad_len - SEG_HEADER_LEN
+    } else {
+        pad_len + align - SEG_HEADER_LEN
+    }
+}
+
+/// Where a segment is placed in the application image
+#[derive(Clone)]
+struct FlashPlacement {
+    /// Address of the segment in memory
+    addr: u32,
+    /// Offset of the data of the segment in the image
+    offset: u32,
+    /// End of the segment in memory, including its padding
+    end: u32,
+}
+
+impl FlashPlacement {
+    /// Placement of a segment which is saved next, after `data`
+    fn of(segment: &CodeSegment, data: &[u8]) -> Self {
+        Self {
+            addr: segment.addr,
+            offset: data.len() as u32 + SEG_HEADER_LEN,
+            end: segment.addr + segment.size(),
+        }
+    }
+}
+
+/// Offsets of the sectors of an image holding nothing but the memory ranges
+/// in `skipped`, given where the segments are placed in the image
+fn skipped_sectors(skipped: &[Range<u32>], placements: &[FlashPlacement]) -> Vec<Range<u32>> {
+    let mut sectors = skipped
+        .iter()
+        .flat_map(|range| {
+            placements.iter().filter_map(move |placed| {
+                let start = range.start.max(placed.addr);
+                let end = range.end.min(placed.end);
+                if start >= end {
+                    return None;
+                }
+
+                let start = (placed.offset + (start - placed.addr)).next_multiple_of(SECTOR_ALIGN);
+                let end = (placed.offset + (end - placed.addr)) / SECTOR_ALIGN * SECTOR_ALIGN;
+                (start < end).then_some(start..end)
+            })
+        })
+        .collect::<Vec<_>>();
+    sectors.sort_by_key(|range| range.start);
+
+    let mut merged: Vec<Range<u32>> = Vec::with_capacity(sectors.len());
+    for range in sectors {
+        match merged.last_mut() {
+            Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    merged
+}
+
+/// The parts of an image written at `addr`, leaving out the `skipped` ranges
+/// of sorted offsets in it
+fn written_parts<'b>(
+    addr: u32,
+    data: &'b [u8],
+    skipped: &'b [Range<u32>],
+) -> impl Iterator<Item = RomSegment<'b>> + 'b {
+    let starts = once(0).chain(skipped.iter().map(|range| range.end as usize));
+    let ends = skipped
+        .iter()
+        .map(|range| range.start as usize)
+        .chain(once(data.len()));
+
+    starts
+        .zip(ends)
+        .filter(|(start, end)| start < end)
+        .map(move |(start, end)| RomSegment {
+            addr: addr + start as u32,
+            data: Cow::Borrowed(&data[start..end]),
+        })
+}
+
+/// Check that the bootloader can map a flash segment along with the previous
+/// one
+///
+/// Both are mapped by whole MMU pages, so when the segment starts in the page
+/// in which the previous one ends, both must be in the same page of flash, at
+/// the same distance as in memory. Padding cannot achieve that if there is no
+/// space for the header of the segment between them.
+fn check_shared_page(
+    previous: Option<&FlashPlacement>,
+    segment: &FlashPlacement,
+    align: u32,
+) -> Result<(), Error> {
+    let Some(previous) = previous else {
+        return Ok(());
+    };
+
+    let shares_page = (previous.end - 1) / align == segment.addr / align;
+    if shares_page && segment.offset - previous.offset != segment.addr - previous.addr {
+        return Err(Error::FlashSegmentsShareMmuPage {
+            first: previous.addr,
+            second: segment.addr,
+            mmu_page_size: align,
+        });
+    }
+
+    Ok(())
+}
+
+/// Merge adjacent segments into one.
+fn merge_adjacent_segments(mut segments: Vec<CodeSegment>) -> Vec<CodeSegment> {
+    segments.sort();
+
+    let mut merged: Vec<CodeSegment> = Vec::with_capacity(segments.len());
+    for segment in segments {
+        match merged.last_mut() {
+            Some(last) if last.addr + last.size() == segment.addr => {
+                *last += segment.data();
+            }
+            _ => {
+                merged.push(segment);
+            }
+        }
+    }
+
+    merged
+}
+
+/// Save a segment to the data buffer.
+fn save_flash_segment(
+    data: &mut Vec<u8>,
+    mut segment: CodeSegment,
+    checksum: u8,
+    align: u32,
+) -> Result<u8, Error> {
+    let end_pos = (data.len() + segment.data().len()) as u32 + SEG_HEADER_LEN;
+    let segment_reminder = end_pos % align;
+
+    if segment_reminder < 0x24 {
+        // Work around a bug in ESP-IDF 2nd stage bootloader, that it didn't map the
+        // last MMU page, if an IROM/DROM segment was < 0x24 bytes over the page
+        // boundary.
+        static PADDING: [u8; 0x24] = [0; 0x24];
+
+        segment += &PADDING[0..(0x24 - segment_reminder as usize)];
+    }
+
+    let checksum = save_segment(data, &segment, checksum)?;
+
+    Ok(checksum)
+}
+
+/// Stores a segment header and the segment data in the data buffer.
+fn save_segment(data: &mut Vec<u8>, segment: &CodeSegment, checksum: u8) -> Result<u8, Error> {
+    let padding = (4 - segment.size() % 4) % 4;
+    let header = SegmentHeader {
+        addr: segment.addr,
+        length: segment.size() + padding,
+    };
+
+    data.write_all(bytes_of(&header))?;
+    data.write_all(segment.data())?;
+
+    let padding = &[0u8; 4][0..padding as usize];
+    data.write_all(padding)?;
+
+    Ok(update_checksum(segment.data(), checksum))
+}
+
+/// Update the checksum with the given data
+fn update_checksum(data: &[u8], mut checksum: u8) -> u8 {
+    for byte in data {
+        checksum ^= *byte;
+    }
+
+    checksum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{elf::SegmentFilter, flasher::FlashDataBuilder};
+
+    #[test]
+    fn test_flash_config_write() {
+        let mut header = ImageHeader::default();
+        header
+            .write_flash_config(FlashSize::_4Mb, FlashFrequency::_40Mhz, Chip::Esp32c3)
+            .unwrap();
+        assert_eq!(header.flash_config, 0x20);
+
+        header
+            .write_flash_config(FlashSize::_32Mb, FlashFrequency::_80Mhz, Chip::Esp32s3)
+            .unwrap();
+        assert_eq!(header.flash_config, 0x5F);
+    }
+
+    #[test]
+    fn test_partition_table_fits() {
+        let table = PartitionTable::try_from_str(
+            "nvs,data,nvs,0x9000,0x6000,\nfactory,app,factory,0x10000,0x5f0000,",
+        )
+        .unwrap();
+
+        assert!(check_partition_table_fits(&table, FlashSize::_8Mb).is_ok());
+        assert!(matches!(
+            check_partition_table_fits(&table, FlashSize::_4Mb),
+            Err(Error::PartitionTableExceedsFlashSize {
+                end: 0x600000,
+                required: FlashSize::_8Mb,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn image_len_from_headers() {
+        let bootloader = include_bytes!("../../resources/bootloaders/esp32c3-bootloader.bin");
+
+        let len = read_image_len(|offset, len| {
+            let offset = offset as usize;
+            Ok(bootloader[offset..offset + len as usize].to_vec())
+        })
+        .unwrap();
+        assert_eq!(len as usize, check_image(bootloader).unwrap().len);
+
+        assert!(matches!(
+            read_image_len(|_, len| Ok(vec![0xff; len as usize])),
+            Err(Error::InvalidImage(_))
+        ));
+
+        // A single segment ending right before `u32::MAX`
+        let header = ImageHeader {
+            segment_count: 1,
+            append_digest: 1,
+            ..ImageHeader::default()
+        };
+        let segment = SegmentHeader {
+            addr: 0,
+            length: u32::MAX - size_of::<ImageHeader>() as u32 - SEG_HEADER_LEN - 1,
+        };
+        assert!(matches!(
+            read_image_len(|offset, _| Ok(if offset == 0 {
+                bytes_of(&header).to_vec()
+            } else {
+                bytes_of(&segment).to_vec()
+            })),
+            Err(Error::InvalidImage(_))
+        ));
+    }
+
+    #[test]
+    fn test_build_flash_plan() {
+        // Copy the data, as parsing the ELF requires it to be aligned
+        let elf = include_bytes!("../../tests/resources/esp32_hal_blinky").to_vec();
+        let flash_data = FlashData::new(None, None, None, None, FlashSettings::default(), 0, None);
+
+        let plan = build_flash_plan(&elf, Chip::Esp32, flash_data, XtalFrequency::_40Mhz).unwrap();
+        let addrs = plan.iter().map(|(addr, _)| *addr).collect::<Vec<_>>();
+        assert_eq!(addrs, [0x1000, 0x8000, 0x10000]);
+        assert_eq!(plan[2].1[0], ESP_MAGIC);
+    }
+
+    #[test]
+    fn test_skipped_segments_keep_their_offsets() {
+        let elf = std::fs::read("resources/apps/esp32c3").unwrap();
+        let plan = |segment_filter| {
+            let mut flash_data =
+                FlashData::new(None, None, None, None, FlashSettings::default(), 0, None);
+            flash_data.app_only = true;
+            flash_data.segment_filter = segment_filter;
+
+            build_flash_plan(&elf, Chip::Esp32c3, flash_data, XtalFrequency::_40Mhz).unwrap()
+        };
+
+        let all = plan(SegmentFilter::All);
+        let [(app_offset, app)] = &all[..] else {
+            panic!("the application is written at once");
+        };
+
+        // `.text` is placed at 0x10020 in the image, the sectors from 0x11000
+        // to 0x14000 hold nothing else
+        let skipped = plan(SegmentFilter::Skip(vec![".text".into()]));
+        let offsets = skipped
+            .iter()
+            .map(|(addr, data)| (addr - app_offset, data.len() as u32))
+            .collect::<Vec<_>>();
+        assert_eq!(offsets.len(), 2);
+        assert_eq!(offsets[0], (0, 0x11000));
+        assert_eq!(offsets[1].0, 0x14000);
+
+        // The remaining segments are written at the same offsets as without
+        // the filter
+        for (addr, data) in &skipped {
+            let start = (addr - app_offset) as usize;
+            assert_eq!(&app[start..start + data.len()], &data[..]);
+        }
+        let (last, data) = skipped.last().unwrap();
+        assert_eq!((last - app_offset) as usize + data.len(), app.len());
+    }
+
+    #[test]
+    fn test_in_memory_flash_data() {
+        let elf = include_bytes!("../../tests/resources/esp32_hal_blinky").to_vec();
+        let bootloader = include_bytes!("../../resources/bootloaders/esp32-bootloader.bin");
+        let table = PartitionTable::try_from_str(
+            "nvs,data,nvs,0x9000,0x6000,\n\
+             factory,app,factory,0x20000,0x100000,",
+        )
+        .unwrap();
+        let flash_data = FlashDataBuilder::new()
+            .with_bootloader(bootloader.to_vec())
+            .with_partition_table(table)
+            .build();
+
+        let plan = build_flash_plan(&elf, Chip::Esp32, flash_data, XtalFrequency::_40Mhz).unwrap();
+        let addrs = plan.iter().map(|(addr, _)| *addr).collect::<Vec<_>>();
+        assert_eq!(addrs, [0x1000, 0x8000, 0x20000]);
+    }
+
+    #[test]
+    fn mismatched_bootloaders_are_rejected() {
+        let elf = include_bytes!("../../tests/resources/esp32_hal_blinky").to_vec();
+        let plan = |bootloader: &[u8], mode, force| {
+            let flash_data = FlashDataBuilder::new()
+                .with_bootloader(bootloader.to_vec())
+                .with_flash_settings(FlashSettings::new(mode, None, None))
+                .with_force_bootloader(force)
+                .build();
+            build_flash_plan(&elf, Chip::Esp32, flash_data, XtalFrequency::_40Mhz)
+        };
+
+        let esp32c3 = include_bytes!("../../resources/bootloaders/esp32c3-bootloader.bin");
+        assert!(matches!(
+            plan(esp32c3, None, false),
+            Err(Error::BootloaderMismatch(_))
+        ));
+        assert!(plan(esp32c3, None, true).is_ok());
+
+        // The bundled bootloader was built for DIO
+        let esp32 = include_bytes!("../../resources/bootloaders/esp32-bootloader.bin");
+        assert!(plan(esp32, Some(FlashMode::Dio), false).is_ok());
+        assert!(matches!(
+            plan(esp32, Some(FlashMode::Qio), false),
+            Err(Error::BootloaderMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_image() {
+        let elf = include_bytes!("../../tests/resources/esp32_hal_blinky").to_vec();
+        let flash_data = FlashData::new(None, None, None, None, FlashSettings::default(), 0, None);
+        let plan = build_flash_plan(&elf, Chip::Esp32, flash_data, XtalFrequency::_40Mhz).unwrap();
+
+        // Both the bootloader, whose header was rewritten, and the application
+        // carry a valid digest
+        for (_, image) in [&plan[0], &plan[2]] {
+            let integrity = check_image(image).unwrap();
+            assert_eq!(integrity.len, image.len());
+            assert!(integrity.digest.is_some());
+        }
+
+        let mut app = plan[2].1.clone();
+        let last = app.len() - 1;
+        app[last] ^= 1;
+        assert!(matches!(
+            check_image(&app),
+            Err(Error::ImageDigestMismatch { .. })
+        ));
+
+        let mut app = plan[2].1.clone();
+        app[size_of::<ImageHeader>() + size_of::<SegmentHeader>()] ^= 1;
+        assert!(matches!(
+            check_image(&app),
+            Err(Error::ImageChecksumMismatch { .. })
+        ));
+
+        assert!(matches!(
+            check_image(&plan[2].1[..0x40]),
+            Err(Error::InvalidImage(_))
+        ));
+    }
+
+    #[test]
+    fn test_bundled_bootloaders_are_valid() {
+        for entry in std::fs::read_dir("resources/bootloaders").unwrap() {
+            let path = entry.unwrap().path();
+            let bootloader = std::fs::read(&path).unwrap();
+
+            assert!(check_image(&bootloader).is_ok(), "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn test_app_image() {
+        let elf = include_bytes!("../../tests/resources/esp32_hal_blinky").to_vec();
+        let flash_data = FlashData::new(None, None, None, None, FlashSettings::default(), 0, None);
+        let plan =
+            build_flash_plan(&elf, Chip::Esp32, flash_data.clone(), XtalFrequency::_40Mhz).unwrap();
+
+        // Flashing the application image built from the ELF file results in the
+        // same flash contents
+        let app = AppImage::parse(&plan[2].1).unwrap();
+        let app_plan =
+            build_image_flash_plan(&app, Chip::Esp32, flash_data.clone(), XtalFrequency::_40Mhz)
+                .unwrap();
+        assert_eq!(app_plan, plan);
+
+        assert!(matches!(
+            build_image_flash_plan(
+                &app,
+                Chip::Esp32c3,
+                flash_data.clone(),
+                XtalFrequency::_40Mhz
+            ),
+            Err(Error::ImageChipMismatch { chip_id: 0, .. })
+        ));
+
+        assert_eq!(
+            app_partition_at(Chip::Esp32, &flash_data, 0x10000).unwrap(),
+            "factory"
+        );
+        assert!(matches!(
+            app_partition_at(Chip::Esp32, &flash_data, 0x9000),
+            Err(Error::NoAppPartitionAt(0x9000))
+        ));
+    }
+
+    #[test]
+    fn test_image_info() {
+        let elf = include_bytes!("../../tests/resources/esp32_hal_blinky").to_vec();
+        let flash_data = FlashData::new(None, None, None, None, FlashSettings::default(), 0, None);
+        let plan = build_flash_plan(&elf, Chip::Esp32, flash_data, XtalFrequency::_40Mhz).unwrap();
+
+        let bootloader = ImageInfo::parse(&plan[0].1).unwrap();
+        assert_eq!(bootloader.chip, Some(Chip::Esp32));
+        assert_eq!(bootloader.flash_size, Some(FlashSize::_4Mb));
+        assert_eq!(bootloader.flash_frequency, Some(FlashFrequency::_40Mhz));
+        assert!(bootloader.app_descriptor.is_none());
+
+        let mut app = plan[2].1.clone();
+        let info = ImageInfo::parse(&app).unwrap();
+        let integrity = check_image(&app).unwrap();
+        assert_eq!(info.segments.len(), integrity.segment_count as usize);
+        assert_eq!(info.len, integrity.len);
+        assert!(info.checksum_valid());
+        assert_eq!(info.digest_valid(), Some(true));
+        assert_eq!(
+            info.segments[0].offset,
+            size_of::<ImageHeader>() + size_of::<SegmentHeader>()
+        );
+
+        // A corrupted image is still described, but does not validate
+        let last = info.segments.last().unwrap();
+        app[last.offset] ^= 1;
+        let info = ImageInfo::parse(&app).unwrap();
+        assert!(!info.checksum_valid());
+        assert_eq!(info.digest_valid(), Some(false));
+    }
+
+    #[test]
+    fn test_mmu_page_size() {
+        assert_eq!(check_mmu_page_size(Chip::Esp32, None).unwrap(), IROM_ALIGN);
+        assert_eq!(
+            check_mmu_page_size(Chip::Esp32c6, Some(0x8000)).unwrap(),
+            0x8000
+        );
+        assert!(matches!(
+            check_mmu_page_size(Chip::Esp32, Some(0x8000)),
+            Err(Error::InvalidMmuPageSize { .. })
+        ));
+        assert!(matches!(
+            check_mmu_page_size(Chip::Esp32c2, Some(0x2000)),
+            Err(Error::InvalidMmuPageSize { .. })
+        ));
+
+        // After the padding segment and the segment header, the data is placed at
+        // the same offset within the (smaller) page as its virtual address
+        let segment = CodeSegment::new(0x4200_8020, &[0; 4]);
+        let pad = get_segment_padding(0x20, &segment, 0x8000);
+        assert_eq!(
+            (0x20 + SEG_HEADER_LEN + pad + SEG_HEADER_LEN) % 0x8000,
+            0x20
+        );
+    }
+
+    #[test]
+    fn test_image_layout() {
+        let elf = include_bytes!("../../tests/resources/esp32_hal_blinky").to_vec();
+        let flash_data = FlashData::new(None, None, None, None, FlashSettings::default(), 0, None);
+        let plan = build_flash_plan(&elf, Chip::Esp32, flash_data, XtalFrequency::_40Mhz).unwrap();
+        let app = &plan[2].1;
+        let app_size = app.len() as u32;
+
+        assert!(verify_layout(Chip::Esp32, app, 0x10000, app_size, IROM_ALIGN).is_ok());
+        assert!(matches!(
+            verify_layout(Chip::Esp32, app, 0x18000, app_size, IROM_ALIGN),
+            Err(Error::InvalidImageLayout { layout, .. }) if layout.contains("(misaligned)")
+        ));
+        assert!(matches!(
+            verify_layout(Chip::Esp32, app, 0x10000, 0x1000, IROM_ALIGN),
+            Err(Error::InvalidImageLayout { .. })
+        ));
+    }
+
+    /// Firmware image made of `(address, data)` segments
+    struct Segments(Vec<(u32, Vec<u8>)>);
+
+    impl<'a> FirmwareImage<'a> for Segments {
+        fn entry(&self) -> u32 {
+            0x4200_0008
+        }
+
+        fn segments(&'a self) -> Box<dyn Iterator<Item = CodeSegment<'a>> + 'a> {
+            Box::new(
+                self.0
+                    .iter()
+                    .map(|(addr, data)| CodeSegment::new(*addr, data)),
+            )
+        }
+
+        fn segments_with_load_addresses(
+            &'a self,
+        ) -> Box<dyn Iterator<Item = CodeSegment<'a>> + 'a> {
+            self.segments()
+        }
+    }
+
+    #[test]
+    fn test_direct_boot() {
+        let code = [DIRECT_BOOT_MAGIC, &[0x13, 0, 0, 0]].concat();
+        let image = Segments(vec![
+            (0x3c00_0010, vec![0xaa; 4]),
+            (0x4200_0000, code.clone()),
+        ]);
+        let image = DirectBootFormat::new(&image, Chip::Esp32c3).unwrap();
+        let segments = image.flash_segments().collect::<Vec<_>>();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].addr, 0);
+        assert_eq!(&segments[0].data[..12], &code[..]);
+        assert_eq!(&segments[0].data[16..], &[0xaa; 4]);
+        assert_eq!(image.app_size(), 20);
+        assert_eq!(image.part_size(), None);
+
+        let image = Segments(vec![(0x4200_0000, vec![0; 12])]);
+        assert!(matches!(
+            DirectBootFormat::new(&image, Chip::Esp32c3),
+            Err(Error::InvalidDirectBootBinary(_))
+        ));
+        let image = Segments(vec![(0x4200_0000, code.clone()), (0x3fc8_0000, code)]);
+        assert!(matches!(
+            DirectBootFormat::new(&image, Chip::Esp32c3),
+            Err(Error::InvalidDirectBootBinary(_))
+        ));
+    }
+
+    #[test]
+    fn test_flash_segments_sharing_a_page() {
+        let flash_image = |segments: Vec<(u32, Vec<u8>)>| {
+            let image = Segments(segments);
+            let flash_data = FlashDataBuilder::new().with_mmu_page_size(0x8000).build();
+            Chip::Esp32c6
+                .into_target()
+                .get_flash_image(&image, flash_data, None, XtalFrequency::_40Mhz)
+                .map(|_| ())
+        };
+
+        // There is space for the header of the second segment between them
+        assert!(flash_image(vec![
+            (0x4200_0020, vec![0; 0x100]),
+            (0x4200_0140, vec![0; 0x10]),
+        ])
+        .is_ok());
+
+        // There is not, so it would be placed in the next page of flash
+        assert!(matches!(
+            flash_image(vec![
+                (0x4200_0020, vec![0; 0x100]),
+                (0x4200_0124, vec![0; 0x10]),
+            ]),
+            Err(Error::FlashSegmentsShareMmuPage {
+                first: 0x4200_0020,
+                second: 0x4200_0124,
+                mmu_page_size: 0x8000,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_direct_boot_unsupported() {
+        let image = Segments(vec![(0x400d_0000, DIRECT_BOOT_MAGIC.to_vec())]);
+        let unsupported = Chip::iter()
+            .filter(|chip| !chip.into_target().supports_direct_boot())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            unsupported,
+            [Chip::Esp32, Chip::Esp32p4, Chip::Esp32s2, Chip::Esp32s3]
+        );
+
+        for chip in unsupported {
+            let flash_data = FlashDataBuilder::new()
+                .with_image_format(ImageFormatKind::DirectBoot)
+                .build();
+            assert!(matches!(
+                chip.into_target().get_flash_image(
+                    &image,
+                    flash_data,
+                    None,
+                    XtalFrequency::_40Mhz
+                ),
+                Err(Error::UnsupportedFeature { chip: c, .. }) if c == chip
+            ));
+        }
+    }
+
+    #[test]
+    fn test_bootloader_mmu_page_size() {
+        let header = |chip: Chip| -> ImageHeader {
+            *from_bytes(&chip.into_target().params().default_bootloader[..size_of::<ImageHeader>()])
+        };
+        assert_eq!(
+            bootloader_mmu_page_size(Chip::Esp32c2, &header(Chip::Esp32c2)),
+            IROM_ALIGN
+        );
+
+        // ESP-IDF uses 32 KB pages with 1 MB of flash
+        let mut small = header(Chip::Esp32c2);
+        small.flash_config &= 0x0f;
+        assert_eq!(bootloader_mmu_page_size(Chip::Esp32c2, &small), 0x8000);
+        let mut small = header(Chip::Esp32);
+        small.flash_config &= 0x0f;
+        assert_eq!(bootloader_mmu_page_size(Chip::Esp32, &small), IROM_ALIGN);
+    }
+
+    #[test]
+    fn test_extra_app_partitions() {
+        let table = PartitionTable::try_from_str(
+            "nvs,data,nvs,0x9000,0x4000,\n\
+             otadata,data,ota,0xd000,0x2000,\n\
+             factory,app,factory,0x10000,0x100000,\n\
+             ota_0,app,ota_0,0x110000,0x100000,\n\
+             ota_1,app,ota_1,0x210000,0x80000,",
+        )
+        .unwrap();
+        let factory = table.find("factory").unwrap();
+
+        let offsets = |extra: ExtraAppPartitions, app_size: u32| {
+            extra_app_partitions_offsets(&table, factory, &extra, app_size)
+        };
+
+        assert!(offsets(ExtraAppPartitions::None, 0x1000)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            offsets(ExtraAppPartitions::Labels(vec!["ota_0".into()]), 0x1000).unwrap(),
+            [0x110000]
+        );
+        assert_eq!(
+            offsets(ExtraAppPartitions::All, 0x1000).unwrap(),
+            [0x110000, 0x210000]
+        );
+        assert!(matches!(
+            offsets(ExtraAppPartitions::Labels(vec!["nvs".into()]), 0x1000),
+            Err(Error::NotAnAppPartition(_))
+        ));
+        assert!(matches!(
+            offsets(ExtraAppPartitions::Labels(vec!["ota_2".into()]), 0x1000),
+            Err(Error::PartitionNotFound(_))
+        ));
+        assert!(matches!(
+            offsets(ExtraAppPartitions::All, 0x90000),
+            Err(Error::ElfTooBig(0x90000, 0x80000))
+        ));
+    }
+
+    #[test]
+    fn test_image_offsets() {
+        let table = PartitionTable::try_from_str(
+            "nvs,data,nvs,0x9000,0x4000,\n\
+             factory,app,factory,0x10000,0x100000,\n\
+             storage,data,spiffs,0x200000,0x10000,",
+        )
+        .unwrap();
+        let params = Chip::Esp32c3.into_target().params();
+
+        let check = |boot_addr, bootloader_len, partition_table_offset, app_offset| {
+            let params = Esp32Params {
+                boot_addr,
+                ..params
+            };
+            check_image_offsets(
+                &params,
+                bootloader_len,
+                &table,
+                partition_table_offset,
+                app_offset,
+                IROM_ALIGN,
+                FlashSize::_4Mb,
+            )
+        };
+        let invalid =
+            |result: Result<_, Error>| matches!(result, Err(Error::InvalidImageOffset { .. }));
+
+        assert_eq!(check(0x0, 0x5000, 0x8000, None).unwrap(), None);
+        // Within and after the app partition, and outside of all partitions
+        assert_eq!(
+            check(0x0, 0x5000, 0x8000, Some(0x20000)).unwrap(),
+            Some((0x20000, 0xf0000))
+        );
+        assert_eq!(
+            check(0x0, 0x5000, 0x8000, Some(0x110000)).unwrap(),
+            Some((0x110000, 0xf0000))
+        );
+        assert_eq!(
+            check(0x0, 0x5000, 0x8000, Some(0x210000)).unwrap(),
+            Some((0x210000, 0x1f0000))
+        );
+
+        assert!(invalid(check(0x800, 0x5000, 0x8000, None)));
+        assert!(invalid(check(0x0, 0x9000, 0x8000, None)));
+        assert!(invalid(check(0x0, 0x5000, 0x8800, None)));
+        assert!(invalid(check(0x0, 0x5000, 0x9000, None)));
+        assert!(invalid(check(0x0, 0x5000, 0x8000, Some(0x18000))));
+        assert!(invalid(check(0x0, 0x5000, 0x8000, Some(0x200000))));
+        assert!(invalid(check(0x0, 0x5000, 0x8000, Some(0x400000))));
+    }
+}
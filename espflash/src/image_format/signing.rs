@@ -0,0 +1,437 @@
+//! Secure Boot V2 signing of application images
+//!
+//! Images are signed the same way as by `espsecure.py sign_data --version 2`:
+//! the image is padded with `0xFF` to a multiple of the sector size, and is
+//! followed by a sector holding the signature block. Keys are read from PEM
+//! files, as generated by `espsecure.py generate_signing_key` or OpenSSL.
+
+use std::{fmt, fs, path::Path, sync::Arc};
+
+use ring::{
+    rand::SystemRandom,
+    rsa::PublicKeyComponents,
+    signature::{
+        EcdsaKeyPair, KeyPair, RsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING, RSA_PSS_SHA256,
+    },
+};
+use sha2::{Digest, Sha256};
+use strum::Display;
+
+use crate::{error::Error, targets::Chip};
+
+/// Size of a flash sector, which images and signature blocks are aligned to
+pub const SECTOR_SIZE: usize = 0x1000;
+/// Size of a signature block
+pub const SIG_BLOCK_LEN: usize = 1216;
+
+const SIG_BLOCK_MAGIC: u8 = 0xe7;
+const SIG_BLOCK_VERSION_RSA: u8 = 0x02;
+const SIG_BLOCK_VERSION_ECDSA: u8 = 0x03;
+/// Length of the part of a signature block which its CRC32 covers
+const SIG_BLOCK_CRC_LEN: usize = 1196;
+const RSA_KEY_LEN: usize = 384;
+const ECDSA_CURVE_P256: u8 = 2;
+
+/// `AlgorithmIdentifier` of an ECDSA P-256 key, to wrap SEC1 keys in PKCS#8
+const P256_ALGORITHM_ID: &[u8] = &[
+    0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48,
+    0xce, 0x3d, 0x03, 0x01, 0x07,
+];
+
+/// Signature scheme of a [SigningKey]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+#[non_exhaustive]
+pub enum SigningScheme {
+    /// RSA-PSS with a 3072 bit key and SHA-256
+    #[strum(serialize = "RSA-PSS 3072")]
+    Rsa3072,
+    /// ECDSA on the P-256 curve with SHA-256
+    #[strum(serialize = "ECDSA P-256")]
+    EcdsaP256,
+}
+
+impl SigningScheme {
+    /// Does the ROM bootloader of `chip` verify signatures of this scheme?
+    pub fn is_supported_by(self, chip: Chip) -> bool {
+        match self {
+            SigningScheme::Rsa3072 => chip != Chip::Esp32c2,
+            SigningScheme::EcdsaP256 => matches!(
+                chip,
+                Chip::Esp32c2 | Chip::Esp32c6 | Chip::Esp32h2 | Chip::Esp32p4
+            ),
+        }
+    }
+}
+
+enum KeyPairKind {
+    Rsa(RsaKeyPair),
+    Ecdsa(EcdsaKeyPair),
+}
+
+/// Private key to sign images for Secure Boot V2 with
+#[derive(Clone)]
+pub struct SigningKey {
+    key_pair: Arc<KeyPairKind>,
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print the private key
+        f.debug_struct("SigningKey")
+            .field("scheme", &self.scheme())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SigningKey {
+    /// Load a PEM encoded RSA 3072 or ECDSA P-256 private key from a file
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let pem =
+            fs::read(path).map_err(|e| Error::FileOpenError(path.display().to_string(), e))?;
+
+        Self::from_pem(&pem)
+    }
+
+    /// Parse a PEM encoded RSA 3072 or ECDSA P-256 private key, in PKCS#1,
+    /// SEC1 or PKCS#8 format
+    pub fn from_pem(pem: &[u8]) -> Result<Self, Error> {
+        let invalid = |reason: &str| Error::InvalidSigningKey(reason.to_string());
+
+        let (label, der) =
+            pem_rfc7468::decode_vec(pem).map_err(|_| invalid("the key is not PEM encoded"))?;
+
+        let key_pair = match label {
+            "RSA PRIVATE KEY" => RsaKeyPair::from_der(&der).map(KeyPairKind::Rsa),
+            "EC PRIVATE KEY" => ecdsa_key_pair(&sec1_to_pkcs8(&der)),
+            "PRIVATE KEY" => RsaKeyPair::from_pkcs8(&der)
+                .map(KeyPairKind::Rsa)
+                .or_else(|_| ecdsa_key_pair(&der)),
+            "ENCRYPTED PRIVATE KEY" => return Err(invalid("encrypted keys are not supported")),
+            _ => return Err(invalid("the PEM file does not contain a private key")),
+        }
+        .map_err(|e| invalid(&format!("the key was rejected: {e}")))?;
+
+        if let KeyPairKind::Rsa(key_pair) = &key_pair {
+            if key_pair.public().modulus_len() != RSA_KEY_LEN {
+                return Err(invalid("RSA keys must have a length of 3072 bits"));
+            }
+        }
+
+        Ok(Self {
+            key_pair: Arc::new(key_pair),
+        })
+    }
+
+    /// Signature scheme of the key
+    pub fn scheme(&self) -> SigningScheme {
+        match *self.key_pair {
+            KeyPairKind::Rsa(_) => SigningScheme::Rsa3072,
+            KeyPairKind::Ecdsa(_) => SigningScheme::EcdsaP256,
+        }
+    }
+
+    /// Build the signature block of `data`, which must already be padded to
+    /// the sector size
+    fn signature_block(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let signing_failed = |_| Error::InvalidSigningKey("the signature failed".to_string());
+        let rng = SystemRandom::new();
+
+        let mut block = Vec::with_capacity(SIG_BLOCK_LEN);
+        block.push(SIG_BLOCK_MAGIC);
+
+        match &*self.key_pair {
+            KeyPairKind::Rsa(key_pair) => {
+                block.extend_from_slice(&[SIG_BLOCK_VERSION_RSA, 0, 0]);
+                block.extend_from_slice(&Sha256::digest(data));
+
+                // The ROM verifies the signature with Montgomery multiplication,
+                // and takes its parameters from the signature block
+                let public: PublicKeyComponents<Vec<u8>> = key_pair.public().into();
+                let modulus = to_le_limbs(&public.n);
+                let exponent = public.e.iter().fold(0u32, |e, &b| (e << 8) | b as u32);
+                block.extend(modulus.iter().flat_map(|limb| limb.to_le_bytes()));
+                block.extend_from_slice(&exponent.to_le_bytes());
+                block.extend(
+                    montgomery_rinv(&modulus)
+                        .iter()
+                        .flat_map(|limb| limb.to_le_bytes()),
+                );
+                block.extend_from_slice(&montgomery_m_prime(modulus[0]).to_le_bytes());
+
+                let mut signature = vec![0; key_pair.public().modulus_len()];
+                key_pair
+                    .sign(&RSA_PSS_SHA256, &rng, data, &mut signature)
+                    .map_err(signing_failed)?;
+                block.extend(signature.iter().rev());
+            }
+            KeyPairKind::Ecdsa(key_pair) => {
+                block.extend_from_slice(&[SIG_BLOCK_VERSION_ECDSA, 0, 0]);
+                block.extend_from_slice(&Sha256::digest(data));
+                block.push(ECDSA_CURVE_P256);
+
+                // The uncompressed point is 0x04 followed by X and Y
+                let point = &key_pair.public_key().as_ref()[1..];
+                let signature = key_pair.sign(&rng, data).map_err(signing_failed)?;
+                for value in point.chunks(32).chain(signature.as_ref().chunks(32)) {
+                    block.extend(value.iter().rev());
+                }
+                block.resize(SIG_BLOCK_CRC_LEN, 0);
+            }
+        }
+
+        let crc = crc32fast::hash(&block);
+        block.extend_from_slice(&crc.to_le_bytes());
+        block.resize(SIG_BLOCK_LEN, 0);
+
+        Ok(block)
+    }
+}
+
+/// Sign an application or bootloader image for Secure Boot V2 on `chip`
+///
+/// Returns the image padded to the sector size, followed by the sector holding
+/// the signature block.
+pub fn sign_image(image: &[u8], key: &SigningKey, chip: Chip) -> Result<Vec<u8>, Error> {
+    let scheme = key.scheme();
+    if !scheme.is_supported_by(chip) {
+        return Err(Error::UnsupportedFeature {
+            chip,
+            feature: format!("Secure Boot V2 with {scheme} signatures"),
+        });
+    }
+    if is_signed(image) {
+        return Err(Error::InvalidImage(
+            "the image is already signed".to_string(),
+        ));
+    }
+
+    let mut data = image.to_vec();
+    data.resize(data.len().next_multiple_of(SECTOR_SIZE), 0xff);
+
+    let block = key.signature_block(&data)?;
+    data.extend_from_slice(&block);
+    data.resize(data.len() + SECTOR_SIZE - SIG_BLOCK_LEN, 0xff);
+
+    Ok(data)
+}
+
+/// Does the image end with a sector holding a signature block?
+fn is_signed(image: &[u8]) -> bool {
+    image.len() >= 2 * SECTOR_SIZE
+        && image.len() % SECTOR_SIZE == 0
+        && matches!(
+            image[image.len() - SECTOR_SIZE..][..2],
+            [
+                SIG_BLOCK_MAGIC,
+                SIG_BLOCK_VERSION_RSA | SIG_BLOCK_VERSION_ECDSA
+            ]
+        )
+}
+
+fn ecdsa_key_pair(pkcs8: &[u8]) -> Result<KeyPairKind, ring::error::KeyRejected> {
+    EcdsaKeyPair::from_pkcs8(
+        &ECDSA_P256_SHA256_FIXED_SIGNING,
+        pkcs8,
+        &SystemRandom::new(),
+    )
+    .map(KeyPairKind::Ecdsa)
+}
+
+/// Wrap a SEC1 `ECPrivateKey` in a PKCS#8 `PrivateKeyInfo` for a P-256 key
+fn sec1_to_pkcs8(sec1: &[u8]) -> Vec<u8> {
+    fn push_tlv(out: &mut Vec<u8>, tag: u8, value: &[u8]) {
+        out.push(tag);
+        match value.len() {
+            len @ 0..=0x7f => out.push(len as u8),
+            len @ 0x80..=0xff => out.extend_from_slice(&[0x81, len as u8]),
+            len => out.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+        }
+        out.extend_from_slice(value);
+    }
+
+    let mut info = vec![0x02, 0x01, 0x00];
+    info.extend_from_slice(P256_ALGORITHM_ID);
+    push_tlv(&mut info, 0x04, sec1);
+
+    let mut pkcs8 = Vec::new();
+    push_tlv(&mut pkcs8, 0x30, &info);
+
+    pkcs8
+}
+
+/// Convert a big-endian number to little-endian 32-bit limbs, filling the
+/// length of an RSA key
+fn to_le_limbs(be: &[u8]) -> Vec<u32> {
+    let mut le: Vec<u8> = be.iter().rev().copied().collect();
+    le.resize(RSA_KEY_LEN, 0);
+
+    le.chunks(4)
+        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+        .collect()
+}
+
+/// `R^2 mod n` for the Montgomery radix `R = 2^bits` of the modulus `n`
+fn montgomery_rinv(modulus: &[u32]) -> Vec<u32> {
+    let bits = modulus.len() * 32;
+    let mut r = vec![0u32; modulus.len()];
+    r[0] = 1;
+
+    // Double 2 * bits times, reducing after each step
+    for _ in 0..2 * bits {
+        let mut carry = 0;
+        for limb in r.iter_mut() {
+            let next = *limb >> 31;
+            *limb = (*limb << 1) | carry;
+            carry = next;
+        }
+
+        let ge = carry == 1
+            || r.iter()
+                .rev()
+                .zip(modulus.iter().rev())
+                .find(|(a, b)| a != b)
+                .is_none_or(|(a, b)| a > b);
+        if ge {
+            let mut borrow = false;
+            for (limb, &m) in r.iter_mut().zip(modulus) {
+                let (value, b1) = limb.overflowing_sub(m);
+                let (value, b2) = value.overflowing_sub(borrow as u32);
+                *limb = value;
+                borrow = b1 || b2;
+            }
+        }
+    }
+
+    r
+}
+
+/// `-n^-1 mod 2^32` for the least significant limb `n0` of an odd modulus
+fn montgomery_m_prime(n0: u32) -> u32 {
+    // Newton's iteration doubles the number of correct bits every step
+    let mut inverse = 1u32;
+    for _ in 0..5 {
+        inverse = inverse.wrapping_mul(2u32.wrapping_sub(n0.wrapping_mul(inverse)));
+    }
+
+    inverse.wrapping_neg()
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED, RSA_PSS_2048_8192_SHA256};
+
+    use super::*;
+
+    const RSA_KEY: &[u8] = include_bytes!("../../tests/resources/secure_boot_rsa.pem");
+    const ECDSA_KEY: &[u8] = include_bytes!("../../tests/resources/secure_boot_ecdsa.pem");
+
+    /// Sign an image, check the layout of the result, and return the signed
+    /// data and the signature block
+    fn sign(key: &SigningKey) -> (Vec<u8>, Vec<u8>) {
+        let image = vec![0xe9; 5000];
+        let signed = sign_image(&image, key, Chip::Esp32c6).unwrap();
+
+        assert_eq!(signed.len(), 3 * SECTOR_SIZE);
+        assert_eq!(&signed[..5000], image.as_slice());
+        assert!(signed[5000..2 * SECTOR_SIZE].iter().all(|&b| b == 0xff));
+        assert!(signed[2 * SECTOR_SIZE + SIG_BLOCK_LEN..]
+            .iter()
+            .all(|&b| b == 0xff));
+
+        let (data, block) = signed.split_at(2 * SECTOR_SIZE);
+        let block = &block[..SIG_BLOCK_LEN];
+        assert_eq!(block[0], SIG_BLOCK_MAGIC);
+        assert_eq!(&block[4..36], Sha256::digest(data).as_slice());
+        assert_eq!(
+            block[SIG_BLOCK_CRC_LEN..][..4],
+            crc32fast::hash(&block[..SIG_BLOCK_CRC_LEN]).to_le_bytes()
+        );
+
+        assert!(matches!(
+            sign_image(&signed, key, Chip::Esp32c6),
+            Err(Error::InvalidImage(_))
+        ));
+
+        (data.to_vec(), block.to_vec())
+    }
+
+    /// Convert a little-endian number from a signature block to big-endian
+    fn be(le: &[u8]) -> Vec<u8> {
+        le.iter().rev().copied().collect()
+    }
+
+    #[test]
+    fn rsa_signature_block() {
+        let key = SigningKey::from_pem(RSA_KEY).unwrap();
+        assert_eq!(key.scheme(), SigningScheme::Rsa3072);
+
+        let (data, block) = sign(&key);
+        assert_eq!(block[1], SIG_BLOCK_VERSION_RSA);
+
+        let n = &block[36..420];
+        let e = &block[420..424];
+        let signature = &block[812..1196];
+        let e: Vec<u8> = be(e).into_iter().skip_while(|&b| b == 0).collect();
+        let public = PublicKeyComponents { n: be(n), e };
+        public
+            .verify(&RSA_PSS_2048_8192_SHA256, &data, &be(signature))
+            .unwrap();
+    }
+
+    #[test]
+    fn ecdsa_signature_block() {
+        let key = SigningKey::from_pem(ECDSA_KEY).unwrap();
+        assert_eq!(key.scheme(), SigningScheme::EcdsaP256);
+
+        let (data, block) = sign(&key);
+        assert_eq!(block[1], SIG_BLOCK_VERSION_ECDSA);
+        assert_eq!(block[36], ECDSA_CURVE_P256);
+
+        let point = [&[0x04][..], &be(&block[37..69]), &be(&block[69..101])].concat();
+        let signature = [be(&block[101..133]), be(&block[133..165])].concat();
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+            .verify(&data, &signature)
+            .unwrap();
+    }
+
+    #[test]
+    fn reject_schemes_unsupported_by_the_chip() {
+        let image = vec![0xe9; 5000];
+        let ecdsa = SigningKey::from_pem(ECDSA_KEY).unwrap();
+        let rsa = SigningKey::from_pem(RSA_KEY).unwrap();
+
+        for chip in [Chip::Esp32, Chip::Esp32c3, Chip::Esp32s2, Chip::Esp32s3] {
+            assert!(matches!(
+                sign_image(&image, &ecdsa, chip),
+                Err(Error::UnsupportedFeature { .. })
+            ));
+        }
+        assert!(matches!(
+            sign_image(&image, &rsa, Chip::Esp32c2),
+            Err(Error::UnsupportedFeature { .. })
+        ));
+        assert!(sign_image(&image, &ecdsa, Chip::Esp32c2).is_ok());
+        assert!(sign_image(&image, &rsa, Chip::Esp32h2).is_ok());
+    }
+
+    #[test]
+    fn montgomery_parameters() {
+        // For n = 2^3072 - 1, R = 2^3072 = 1 mod n and -n^-1 = 1 mod 2^32
+        let modulus = vec![u32::MAX; RSA_KEY_LEN / 4];
+        let mut one = vec![0; RSA_KEY_LEN / 4];
+        one[0] = 1;
+        assert_eq!(montgomery_rinv(&modulus), one);
+        assert_eq!(montgomery_m_prime(u32::MAX), 1);
+
+        let n0: u32 = 0x1234_5677;
+        assert_eq!(n0.wrapping_mul(montgomery_m_prime(n0)), u32::MAX);
+    }
+
+    #[test]
+    fn reject_invalid_keys() {
+        assert!(matches!(
+            SigningKey::from_pem(b"not a key"),
+            Err(Error::InvalidSigningKey(_))
+        ));
+    }
+}
@@ -0,0 +1,113 @@
+//! UF2 files, as taken by the TinyUF2 bootloader, e.g. by dropping them onto
+//! the USB mass storage device it provides
+//!
+//! See <https://github.com/microsoft/uf2> for the format.
+
+use std::io::{self, Write};
+
+use crate::{elf::RomSegment, targets::Chip};
+
+const UF2_MAGIC_START0: u32 = 0x0A32_4655;
+const UF2_MAGIC_START1: u32 = 0x9E5D_5157;
+const UF2_MAGIC_END: u32 = 0x0AB1_6F30;
+
+/// The family ID field of the block is set
+const UF2_FLAG_FAMILY_ID: u32 = 0x0000_2000;
+
+/// Number of data bytes in each block
+const UF2_PAYLOAD_SIZE: usize = 256;
+
+/// Size of the data field of each block
+const UF2_DATA_SIZE: usize = 476;
+
+/// Write `segments` as a UF2 file for `chip`
+///
+/// The addresses of the blocks are offsets in flash, as expected by TinyUF2.
+pub fn write_uf2(
+    writer: &mut impl Write,
+    segments: &[RomSegment<'_>],
+    chip: Chip,
+) -> io::Result<()> {
+    let num_blocks: usize = segments
+        .iter()
+        .map(|segment| segment.data.len().div_ceil(UF2_PAYLOAD_SIZE))
+        .sum();
+
+    let family_id = chip.into_target().uf2_family_id();
+    let blocks = segments.iter().flat_map(|segment| {
+        segment
+            .data
+            .chunks(UF2_PAYLOAD_SIZE)
+            .zip((segment.addr..).step_by(UF2_PAYLOAD_SIZE))
+    });
+
+    for (block_no, (payload, addr)) in blocks.enumerate() {
+        let header = [
+            UF2_MAGIC_START0,
+            UF2_MAGIC_START1,
+            UF2_FLAG_FAMILY_ID,
+            addr,
+            payload.len() as u32,
+            block_no as u32,
+            num_blocks as u32,
+            family_id,
+        ];
+        for word in header {
+            writer.write_all(&word.to_le_bytes())?;
+        }
+
+        let mut data = [0; UF2_DATA_SIZE];
+        data[..payload.len()].copy_from_slice(payload);
+        writer.write_all(&data)?;
+        writer.write_all(&UF2_MAGIC_END.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    fn word(block: &[u8], index: usize) -> u32 {
+        u32::from_le_bytes(block[index * 4..][..4].try_into().unwrap())
+    }
+
+    #[test]
+    fn segments_are_split_into_blocks() {
+        let segments = [
+            RomSegment {
+                addr: 0x0,
+                data: Cow::Owned(vec![0xaa; 300]),
+            },
+            RomSegment {
+                addr: 0x8000,
+                data: Cow::Owned(vec![0xbb; 16]),
+            },
+        ];
+
+        let mut uf2 = Vec::new();
+        write_uf2(&mut uf2, &segments, Chip::Esp32s3).unwrap();
+
+        let blocks = uf2.chunks(512).collect::<Vec<_>>();
+        assert_eq!(uf2.len(), 3 * 512);
+
+        // target address, payload size and block number of each block
+        let fields = blocks
+            .iter()
+            .map(|block| (word(block, 3), word(block, 4), word(block, 5)))
+            .collect::<Vec<_>>();
+        assert_eq!(fields, [(0x0, 256, 0), (0x100, 44, 1), (0x8000, 16, 2)]);
+
+        for block in &blocks {
+            assert_eq!(word(block, 0), UF2_MAGIC_START0);
+            assert_eq!(word(block, 6), 3);
+            assert_eq!(word(block, 7), 0xc47e_5767);
+            assert_eq!(word(block, 127), UF2_MAGIC_END);
+        }
+        assert_eq!(&blocks[1][32..32 + 44], &[0xaa; 44]);
+        assert_eq!(blocks[1][32 + 44], 0);
+    }
+}
@@ -22,11 +22,18 @@
 //! provide SemVer guarantees. You likely will not need any of these types or functions
 //! in your application so there's no use pulling in the extra dependencies.
 //!
+//! The [prelude] exports the stable subset of the library needed to flash
+//! applications, read flash and get information about a device, and shows how
+//! to do so.
+//!
 //! [espflash]: https://crates.io/crates/espflash
 //! [cargo-binstall]: https://github.com/cargo-bins/cargo-binstall
 
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
+#[cfg(feature = "blocking-pool")]
+#[cfg_attr(docsrs, doc(cfg(feature = "blocking-pool")))]
+pub mod blocking_pool;
 #[cfg(feature = "cli")]
 #[cfg_attr(docsrs, doc(cfg(feature = "cli")))]
 pub mod cli;
@@ -35,24 +42,139 @@ pub mod command;
 #[cfg(feature = "serialport")]
 #[cfg_attr(docsrs, doc(cfg(feature = "serialport")))]
 pub mod connection;
+pub mod digest;
+#[cfg(feature = "serialport")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serialport")))]
+pub mod discovery;
+#[cfg(feature = "serialport")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serialport")))]
+pub mod efuse;
 pub mod elf;
 pub mod error;
 pub mod flasher;
+pub mod identity;
 pub mod image_format;
+#[cfg(feature = "metrics")]
+#[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+pub mod metrics;
+#[cfg(feature = "serialport")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serialport")))]
+pub mod monitor;
+pub mod output;
+pub mod prelude;
 pub mod targets;
 
+pub use image_format::{build_flash_plan, build_image_flash_plan};
+
 /// Logging utilities
 #[cfg(feature = "cli")]
 #[cfg_attr(docsrs, doc(cfg(feature = "cli")))]
 pub mod logging {
+    use std::sync::Mutex;
+
     use env_logger::Env;
-    use log::LevelFilter;
+    use log::{warn, Level, LevelFilter, Log, Metadata, Record};
+
+    /// Warnings which were logged, with the number of times each was repeated
+    static WARNINGS: Mutex<Vec<(String, usize)>> = Mutex::new(Vec::new());
+
+    /// Logger which prints each distinct warning only once, so that warnings
+    /// repeated e.g. for every block written do not flood the output
+    struct DedupLogger {
+        inner: env_logger::Logger,
+    }
+
+    impl Log for DedupLogger {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            self.inner.enabled(metadata)
+        }
+
+        fn log(&self, record: &Record<'_>) {
+            if record.level() == Level::Warn
+                && self.inner.matches(record)
+                && is_repeated(&record.args().to_string())
+            {
+                return;
+            }
+
+            self.inner.log(record);
+        }
+
+        fn flush(&self) {
+            self.inner.flush();
+        }
+    }
+
+    /// Record a warning, returning whether it was logged before
+    fn is_repeated(message: &str) -> bool {
+        let mut warnings = WARNINGS.lock().unwrap();
+        match warnings.iter_mut().find(|(logged, _)| logged == message) {
+            Some((_, repeats)) => {
+                *repeats += 1;
+                true
+            }
+            None => {
+                warnings.push((message.to_string(), 0));
+                false
+            }
+        }
+    }
 
     /// Initialize the logger with the given [LevelFilter]
+    ///
+    /// Repeated warnings are suppressed, see [print_warning_summary].
     pub fn initialize_logger(filter: LevelFilter) {
-        env_logger::Builder::from_env(Env::default().default_filter_or(filter.as_str()))
-            .format_target(false)
-            .init();
+        let inner =
+            env_logger::Builder::from_env(Env::default().default_filter_or(filter.as_str()))
+                .format_target(false)
+                .build();
+        let max_level = inner.filter();
+
+        log::set_boxed_logger(Box::new(DedupLogger { inner }))
+            .expect("the logger must only be initialized once");
+        log::set_max_level(max_level);
+    }
+
+    /// Print how often the warnings which were suppressed were repeated
+    pub fn print_warning_summary() {
+        let repeated = WARNINGS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, repeats)| *repeats > 0)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for (message, repeats) in repeated {
+            let times = if repeats == 1 { "time" } else { "times" };
+            warn!("The warning '{message}' was repeated {repeats} more {times}");
+        }
+    }
+
+    /// Log level for the number of times the verbose flag was given
+    pub fn log_level(verbose: u8) -> LevelFilter {
+        match verbose {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::is_repeated;
+
+        #[test]
+        fn warnings_are_deduplicated() {
+            assert!(!is_repeated("first"));
+            assert!(!is_repeated("second"));
+            assert!(is_repeated("first"));
+            assert!(is_repeated("first"));
+
+            let warnings = super::WARNINGS.lock().unwrap();
+            assert_eq!(warnings[0], ("first".to_string(), 2));
+            assert_eq!(warnings[1], ("second".to_string(), 0));
+        }
     }
 }
 
@@ -0,0 +1,218 @@
+//! Reporting flashing statistics as StatsD metrics
+//!
+//! Provisioning lines flashing many devices can monitor the health of their
+//! stations by collecting these metrics, e.g. with Telegraf, the Datadog agent
+//! or the Prometheus StatsD exporter. Metrics are only sent when a StatsD server
+//! is configured with environment variables:
+//!
+//! - `ESPFLASH_STATSD_ADDR`: address of the server, e.g. `127.0.0.1:8125`
+//! - `ESPFLASH_STATSD_PREFIX`: prefix of the metric names, `espflash` by
+//!   default
+//! - `ESPFLASH_STATSD_TAGS`: comma separated tags in the DogStatsD format, e.g.
+//!   `station:3,line:a`; the chip type is added to them. Without this
+//!   variable, no tags are sent.
+//!
+//! The metrics are sent over UDP, so reporting them never delays or fails
+//! flashing.
+
+use std::{
+    env, io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+};
+
+use log::debug;
+
+use crate::{
+    connection::ConnectionStats,
+    flasher::{FlashStats, Flasher},
+    targets::Chip,
+};
+
+/// Report the outcome of flashing with `flasher` to the StatsD server
+/// configured in the environment, if any
+pub fn report_flash(flasher: &mut Flasher, success: bool) {
+    if let Some(sink) = StatsdSink::from_env() {
+        let connection_stats = flasher.connection().stats();
+        sink.report_flash(
+            flasher.chip(),
+            success,
+            &flasher.flash_stats(),
+            &connection_stats,
+        );
+    }
+}
+
+/// A StatsD server to send metrics to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsdSink {
+    addr: String,
+    prefix: String,
+    tags: Option<Vec<String>>,
+}
+
+impl StatsdSink {
+    /// The server configured in the environment, if any
+    pub fn from_env() -> Option<Self> {
+        let addr = env::var("ESPFLASH_STATSD_ADDR").ok()?;
+        let prefix = env::var("ESPFLASH_STATSD_PREFIX").unwrap_or_else(|_| "espflash".into());
+        let tags = env::var("ESPFLASH_STATSD_TAGS").ok().map(|tags| {
+            tags.split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(String::from)
+                .collect()
+        });
+
+        Some(Self { addr, prefix, tags })
+    }
+
+    /// Report the outcome and statistics of flashing a device
+    pub fn report_flash(
+        &self,
+        chip: Chip,
+        success: bool,
+        flash_stats: &FlashStats,
+        connection_stats: &ConnectionStats,
+    ) {
+        let lines = self.flash_lines(chip, success, flash_stats, connection_stats);
+
+        if let Err(e) = self.send(lines.join("\n").as_bytes()) {
+            debug!("Failed to send metrics to {}: {e}", self.addr);
+        }
+    }
+
+    /// Send `payload` from a socket of the same address family as the server
+    fn send(&self, payload: &[u8]) -> io::Result<usize> {
+        let addr = self
+            .addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found"))?;
+        let local = match addr {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+
+        UdpSocket::bind(local)?.send_to(payload, addr)
+    }
+
+    fn flash_lines(
+        &self,
+        chip: Chip,
+        success: bool,
+        flash_stats: &FlashStats,
+        connection_stats: &ConnectionStats,
+    ) -> Vec<String> {
+        let outcome = if success { "success" } else { "failure" };
+        let mut metrics = vec![
+            (format!("flash.{outcome}"), "1|c".to_string()),
+            (
+                "flash.duration".into(),
+                format!("{}|ms", flash_stats.duration.as_millis()),
+            ),
+            ("flash.bytes".into(), format!("{}|c", flash_stats.bytes)),
+            (
+                "flash.verify_failures".into(),
+                format!("{}|c", flash_stats.verify_failures),
+            ),
+            (
+                "connection.timeouts".into(),
+                format!("{}|c", connection_stats.timeouts),
+            ),
+            (
+                "connection.retries".into(),
+                format!("{}|c", connection_stats.settle_retries),
+            ),
+            (
+                "connection.resyncs".into(),
+                format!("{}|c", connection_stats.resyncs),
+            ),
+        ];
+        if let Some(throughput) = flash_stats.throughput() {
+            metrics.push(("flash.throughput".into(), format!("{throughput:.0}|g")));
+        }
+
+        let tags = self.tags.as_ref().map(|tags| {
+            let mut tags = tags.clone();
+            tags.push(format!("chip:{chip}"));
+            format!("|#{}", tags.join(","))
+        });
+
+        metrics
+            .into_iter()
+            .map(|(name, value)| {
+                format!(
+                    "{}.{name}:{value}{}",
+                    self.prefix,
+                    tags.as_deref().unwrap_or_default()
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn flash_metrics() {
+        let mut sink = StatsdSink {
+            addr: "127.0.0.1:8125".into(),
+            prefix: "espflash".into(),
+            tags: None,
+        };
+        let flash_stats = FlashStats {
+            bytes: 1000,
+            duration: Duration::from_millis(500),
+            verify_failures: 1,
+        };
+
+        let connection_stats = ConnectionStats {
+            resyncs: 2,
+            ..ConnectionStats::default()
+        };
+
+        let lines = sink.flash_lines(Chip::Esp32c3, true, &flash_stats, &connection_stats);
+        assert_eq!(lines[0], "espflash.flash.success:1|c");
+        assert_eq!(lines[1], "espflash.flash.duration:500|ms");
+        assert!(lines.contains(&"espflash.flash.verify_failures:1|c".to_string()));
+        assert!(lines.contains(&"espflash.flash.throughput:2000|g".to_string()));
+        assert!(lines.contains(&"espflash.connection.resyncs:2|c".to_string()));
+
+        sink.tags = Some(vec!["station:3".into()]);
+        let lines = sink.flash_lines(
+            Chip::Esp32c3,
+            false,
+            &FlashStats::default(),
+            &ConnectionStats::default(),
+        );
+        assert_eq!(
+            lines[0],
+            "espflash.flash.failure:1|c|#station:3,chip:esp32c3"
+        );
+        assert_eq!(lines.len(), 7);
+    }
+
+    #[test]
+    fn metrics_are_sent_to_ipv4_and_ipv6_servers() {
+        for server in ["127.0.0.1:0", "[::1]:0"] {
+            // IPv6 may be disabled on the host
+            let Ok(server) = UdpSocket::bind(server) else {
+                continue;
+            };
+            let sink = StatsdSink {
+                addr: server.local_addr().unwrap().to_string(),
+                prefix: "espflash".into(),
+                tags: None,
+            };
+
+            sink.send(b"espflash.flash.success:1|c").unwrap();
+            let mut buf = [0; 64];
+            let len = server.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"espflash.flash.success:1|c");
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Decoding of defmt frames with the defmt table of an ELF file
+
+use defmt_decoder::Table;
+use miette::{bail, Context, Diagnostic, Result};
+use thiserror::Error;
+
+use crate::monitor::{DefmtDecoder, FRAME_END};
+
+#[derive(Clone, Copy, Debug, Diagnostic, Error)]
+#[error("Could not set up defmt logger")]
+pub enum DefmtError {
+    #[error("No elf data available")]
+    #[diagnostic(code(espflash::monitor::defmt::no_elf))]
+    NoElf,
+
+    #[error("No defmt data was found in the elf file")]
+    #[diagnostic(code(espflash::monitor::defmt::no_defmt))]
+    NoDefmtData,
+
+    #[error("Failed to parse defmt data")]
+    #[diagnostic(code(espflash::monitor::defmt::parse_failed))]
+    TableParseFailed,
+
+    #[error("Unsupported defmt encoding: {0:?}. Only rzcobs is supported.")]
+    #[diagnostic(code(espflash::monitor::defmt::unsupported_encoding))]
+    UnsupportedEncoding(defmt_decoder::Encoding),
+}
+
+/// Load the defmt table of the ELF file of an application
+pub fn load_table(elf: &[u8]) -> Result<Table> {
+    let table = match Table::parse(elf) {
+        Ok(Some(table)) => table,
+        Ok(None) => bail!(DefmtError::NoDefmtData),
+        Err(e) => return Err(DefmtError::TableParseFailed).with_context(|| e),
+    };
+
+    let encoding = table.encoding();
+
+    // We only support rzcobs encoding because it is the only way to multiplex
+    // a defmt stream and an ASCII log stream over the same serial port.
+    if encoding == defmt_decoder::Encoding::Rzcobs {
+        Ok(table)
+    } else {
+        bail!(DefmtError::UnsupportedEncoding(encoding))
+    }
+}
+
+/// Decode a frame, without the framing of esp-println, optionally with the
+/// colors of the log levels
+pub(crate) fn decode(table: &Table, frame: &[u8], colored: bool) -> Option<String> {
+    let mut decoder = table.new_stream_decoder();
+    decoder.received(frame);
+    // small reliance on rzcobs internals: we need to feed the terminating zero
+    decoder.received(FRAME_END);
+
+    decoder
+        .decode()
+        .ok()
+        .map(|frame| frame.display(colored).to_string())
+}
+
+impl DefmtDecoder for Table {
+    fn decode(&mut self, frame: &[u8]) -> Option<String> {
+        decode(self, frame, false)
+    }
+}
@@ -0,0 +1,615 @@
+//! Reading and decoding the output of a running application
+//!
+//! The [Monitor] reads the serial port of a device and turns its output into
+//! [Event]s, so that tools embedding espflash can present the output in their
+//! own way instead of the terminal UI of the command-line application.
+//!
+//! Output is split into lines of text and defmt frames, as framed by
+//! esp-println. The frames are decoded by a [DefmtDecoder], and addresses in
+//! lines of text (e.g. of a backtrace) are looked up by an [AddressResolver],
+//! if these are given. With the `decoders` feature, both can be loaded from
+//! the ELF file of the application with [Monitor::with_elf].
+//!
+//! The monitor of the command-line application decodes the output with the
+//! same framing, line splitting, address lookups and decoders, but shows it on
+//! the terminal as soon as it is read instead of line by line.
+//!
+//! ```no_run
+//! use espflash::{connection::Port, monitor::{Event, Monitor}};
+//!
+//! # fn main() -> Result<(), espflash::error::Error> {
+//! let mut monitor = Monitor::new(Port::open("/dev/ttyUSB0", 115_200)?)?;
+//! loop {
+//!     match monitor.poll_event()? {
+//!         Some(Event::Line(line)) => println!("{line}"),
+//!         Some(Event::Address(address)) => println!("  {address}"),
+//!         _ => {}
+//!     }
+//! }
+//! # }
+//! ```
+
+use std::{
+    collections::VecDeque,
+    fmt::{self, Display, Formatter},
+    io::{ErrorKind, Read, Write},
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+use serialport::SerialPort;
+
+use crate::{connection::Port, error::Error};
+
+#[cfg(feature = "decoders")]
+#[cfg_attr(docsrs, doc(cfg(feature = "decoders")))]
+pub mod defmt;
+#[cfg(feature = "decoders")]
+#[cfg_attr(docsrs, doc(cfg(feature = "decoders")))]
+pub mod symbols;
+
+/// Start of a defmt frame, as framed by esp-println
+pub(crate) const FRAME_START: &[u8] = &[0xFF, 0x00];
+/// End of a defmt frame, the terminating zero of rzcobs
+pub(crate) const FRAME_END: &[u8] = &[0x00];
+
+/// How long [Monitor::poll_event] waits for output
+const POLL_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Something the device sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// A complete line of text, without its line ending
+    Line(String),
+    /// A defmt frame, with the message it was decoded to, if it could be
+    Defmt {
+        frame: Vec<u8>,
+        message: Option<String>,
+    },
+    /// An address in the previous line, with the function it belongs to
+    Address(ResolvedAddress),
+}
+
+/// The function, and the location in its source, at an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedAddress {
+    pub address: u64,
+    pub function: String,
+    /// File name and line number, if known
+    pub location: Option<(String, u32)>,
+}
+
+impl Display for ResolvedAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#010x} - {}", self.address, self.function)?;
+        match &self.location {
+            Some((file, line)) => write!(f, " at {file}:{line}"),
+            None => write!(f, " at ??:??"),
+        }
+    }
+}
+
+/// Decodes defmt frames into their messages
+pub trait DefmtDecoder: Send {
+    /// Decode a frame, without the framing of esp-println
+    fn decode(&mut self, frame: &[u8]) -> Option<String>;
+}
+
+/// Looks up the function at an address
+pub trait AddressResolver: Send {
+    fn resolve(&mut self, address: u64) -> Option<ResolvedAddress>;
+}
+
+/// A part of the output, separated by the framing of esp-println
+#[derive(Debug, PartialEq)]
+pub(crate) enum FrameKind<'a> {
+    Defmt(&'a [u8]),
+    Raw(&'a [u8]),
+}
+
+/// Splits output into defmt frames and the raw data between them
+pub(crate) struct FrameDelimiter {
+    buffer: Vec<u8>,
+    in_frame: bool,
+}
+
+impl FrameDelimiter {
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            in_frame: false,
+        }
+    }
+
+    fn search(haystack: &[u8], look_for_end: bool) -> Option<(&[u8], usize)> {
+        let needle = if look_for_end { FRAME_END } else { FRAME_START };
+        let start = if look_for_end {
+            // skip leading zeros
+            haystack.iter().position(|&b| b != 0)?
+        } else {
+            0
+        };
+
+        let end = haystack[start..]
+            .windows(needle.len())
+            .position(|window| window == needle)?;
+
+        Some((&haystack[start..][..end], start + end + needle.len()))
+    }
+
+    pub fn feed(&mut self, buffer: &[u8], mut process: impl FnMut(FrameKind<'_>)) {
+        self.buffer.extend_from_slice(buffer);
+
+        while let Some((frame, consumed)) = Self::search(&self.buffer, self.in_frame) {
+            if self.in_frame {
+                process(FrameKind::Defmt(frame));
+            } else if !frame.is_empty() {
+                process(FrameKind::Raw(frame));
+            }
+            self.in_frame = !self.in_frame;
+
+            self.buffer.drain(..consumed);
+        }
+
+        if !self.in_frame {
+            // If we have a 0xFF byte at the end, we should assume it's the start of a new frame.
+            let consume = if self.buffer.ends_with(&[0xFF]) {
+                &self.buffer[..self.buffer.len() - 1]
+            } else {
+                self.buffer.as_slice()
+            };
+
+            if !consume.is_empty() {
+                process(FrameKind::Raw(consume));
+                self.buffer.drain(..consume.len());
+            }
+        }
+    }
+}
+
+/// A part of the output which does not span line endings
+#[derive(Debug, PartialEq)]
+pub(crate) struct LinePart<'a> {
+    /// The text of the part, without its line ending
+    pub text: &'a [u8],
+    /// The whole line, without its line ending, if the part completes it
+    pub line: Option<Vec<u8>>,
+}
+
+/// Joins output which is received in parts into complete lines
+#[derive(Debug, Default)]
+pub(crate) struct LineBuffer {
+    /// Start of the line which is not complete yet
+    fragment: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// Split `bytes` at its line endings
+    pub fn split<'a>(&mut self, mut bytes: &'a [u8]) -> Vec<LinePart<'a>> {
+        fn trim_cr(line: &[u8]) -> &[u8] {
+            line.strip_suffix(b"\r").unwrap_or(line)
+        }
+
+        let mut parts = Vec::new();
+        while let Some(end) = bytes.iter().position(|&byte| byte == b'\n') {
+            self.fragment.extend_from_slice(&bytes[..end]);
+            let mut line = std::mem::take(&mut self.fragment);
+            line.truncate(trim_cr(&line).len());
+
+            parts.push(LinePart {
+                text: trim_cr(&bytes[..end]),
+                line: Some(line),
+            });
+            bytes = &bytes[end + 1..];
+        }
+
+        if !bytes.is_empty() {
+            self.fragment.extend_from_slice(bytes);
+            parts.push(LinePart {
+                text: bytes,
+                line: None,
+            });
+        }
+
+        parts
+    }
+}
+
+/// The addresses in a line of output, e.g. of a backtrace, along with the text
+/// they were found as
+pub(crate) fn addresses(line: &str) -> impl Iterator<Item = (&str, u64)> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+
+    PATTERN
+        .get_or_init(|| Regex::new(r"0x[[:xdigit:]]{8}").unwrap())
+        .find_iter(line)
+        .filter_map(|matched| {
+            let text = matched.as_str();
+            u64::from_str_radix(&text[2..], 16)
+                .ok()
+                .map(|address| (text, address))
+        })
+}
+
+/// Reads the output of a device, and decodes it into [Event]s
+pub struct Monitor {
+    serial: Port,
+    delimiter: FrameDelimiter,
+    lines: LineBuffer,
+    events: VecDeque<Event>,
+    defmt: Option<Box<dyn DefmtDecoder>>,
+    resolver: Option<Box<dyn AddressResolver>>,
+}
+
+impl Monitor {
+    /// Monitor the output of the device connected to `serial`
+    ///
+    /// The device is not reset, see
+    /// [reset_after_flash](crate::connection::reset::reset_after_flash) to
+    /// start the application from the beginning.
+    pub fn new(mut serial: Port) -> Result<Self, Error> {
+        serial.set_timeout(POLL_TIMEOUT)?;
+
+        Ok(Self {
+            serial,
+            delimiter: FrameDelimiter::new(),
+            lines: LineBuffer::default(),
+            events: VecDeque::new(),
+            defmt: None,
+            resolver: None,
+        })
+    }
+
+    /// Decode defmt frames with `decoder`
+    pub fn with_defmt_decoder(mut self, decoder: impl DefmtDecoder + 'static) -> Self {
+        self.defmt = Some(Box::new(decoder));
+        self
+    }
+
+    /// Look up the addresses in the output with `resolver`
+    pub fn with_address_resolver(mut self, resolver: impl AddressResolver + 'static) -> Self {
+        self.resolver = Some(Box::new(resolver));
+        self
+    }
+
+    /// Decode defmt frames and look up addresses with the ELF file of the
+    /// application
+    ///
+    /// Frames are not decoded if the ELF file contains no defmt data.
+    #[cfg(feature = "decoders")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "decoders")))]
+    pub fn with_elf(mut self, elf: &[u8]) -> Self {
+        if let Ok(table) = defmt::load_table(elf) {
+            self.defmt = Some(Box::new(table));
+        }
+        if let Ok(symbols) = symbols::Symbols::try_from(elf) {
+            self.resolver = Some(Box::new(symbols));
+        }
+
+        self
+    }
+
+    /// The serial port of the device, e.g. to reset it
+    pub fn serial(&mut self) -> &mut Port {
+        &mut self.serial
+    }
+
+    /// Stop monitoring, returning the serial port
+    pub fn into_serial(self) -> Port {
+        self.serial
+    }
+
+    /// Send `data` to the device
+    pub fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.serial.write_all(data)?;
+        self.serial.flush()?;
+
+        Ok(())
+    }
+
+    /// The next event, or `None` if the device sent nothing which completes one
+    /// within a short time
+    pub fn poll_event(&mut self) -> Result<Option<Event>, Error> {
+        if self.events.is_empty() {
+            let mut buffer = [0; 1024];
+            match self.serial.read(&mut buffer) {
+                Ok(0) => {}
+                Ok(len) => self.feed(&buffer[..len]),
+                // Sockets of network ports time out with `WouldBlock`
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(self.events.pop_front())
+    }
+
+    /// Wait up to `timeout` for the next line of text, skipping other events
+    ///
+    /// Returns `None` if no line was completed in time.
+    pub fn read_line(&mut self, timeout: Duration) -> Result<Option<String>, Error> {
+        let start = Instant::now();
+        loop {
+            match self.poll_event()? {
+                Some(Event::Line(line)) => return Ok(Some(line)),
+                Some(_) => {}
+                None if start.elapsed() >= timeout => return Ok(None),
+                None => {}
+            }
+        }
+    }
+
+    /// Decode output of the device which was read elsewhere, e.g. from a
+    /// recording, into events
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let mut parts = Vec::new();
+        self.delimiter.feed(bytes, |frame| match frame {
+            FrameKind::Defmt(frame) => parts.push((true, frame.to_vec())),
+            FrameKind::Raw(bytes) => parts.push((false, bytes.to_vec())),
+        });
+
+        for (is_frame, part) in parts {
+            if is_frame {
+                self.push_frame(&part);
+            } else {
+                self.push_text(&part);
+            }
+        }
+    }
+
+    fn push_text(&mut self, bytes: &[u8]) {
+        for part in self.lines.split(bytes) {
+            let Some(line) = part.line else {
+                continue;
+            };
+            let line = String::from_utf8_lossy(&line).into_owned();
+
+            let addresses = addresses(&line)
+                .map(|(_, address)| address)
+                .collect::<Vec<_>>();
+            self.events.push_back(Event::Line(line));
+
+            if let Some(resolver) = &mut self.resolver {
+                for address in addresses {
+                    if let Some(resolved) = resolver.resolve(address) {
+                        self.events.push_back(Event::Address(resolved));
+                    }
+                }
+            }
+        }
+    }
+
+    fn push_frame(&mut self, frame: &[u8]) {
+        let message = self
+            .defmt
+            .as_mut()
+            .and_then(|decoder| decoder.decode(frame));
+
+        self.events.push_back(Event::Defmt {
+            frame: frame.to_vec(),
+            message,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    struct Upper;
+
+    impl DefmtDecoder for Upper {
+        fn decode(&mut self, frame: &[u8]) -> Option<String> {
+            Some(String::from_utf8_lossy(frame).to_uppercase())
+        }
+    }
+
+    struct Known;
+
+    impl AddressResolver for Known {
+        fn resolve(&mut self, address: u64) -> Option<ResolvedAddress> {
+            (address == 0x4008_0000).then(|| ResolvedAddress {
+                address,
+                function: "main".into(),
+                location: None,
+            })
+        }
+    }
+
+    #[test]
+    fn output_is_decoded_into_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("socket://{}", listener.local_addr().unwrap());
+        let mut monitor = Monitor::new(Port::open(&url, 115_200).unwrap())
+            .unwrap()
+            .with_defmt_decoder(Upper)
+            .with_address_resolver(Known);
+        let (mut device, _) = listener.accept().unwrap();
+
+        device.write_all(b"boot\r\npc 0x40080000 0x400").unwrap();
+        device.write_all(b"90000\n\xFF\x00frame\x00part").unwrap();
+
+        let mut events = Vec::new();
+        let start = Instant::now();
+        while events.len() < 4 && start.elapsed() < Duration::from_secs(5) {
+            events.extend(monitor.poll_event().unwrap());
+        }
+
+        assert_eq!(
+            events,
+            [
+                Event::Line("boot".into()),
+                Event::Line("pc 0x40080000 0x40090000".into()),
+                Event::Address(ResolvedAddress {
+                    address: 0x4008_0000,
+                    function: "main".into(),
+                    location: None,
+                }),
+                Event::Defmt {
+                    frame: b"frame".to_vec(),
+                    message: Some("FRAME".into()),
+                },
+            ]
+        );
+
+        // The incomplete line is only returned once it ends
+        assert_eq!(monitor.read_line(Duration::from_millis(50)).unwrap(), None);
+        device.write_all(b"ial\n").unwrap();
+        assert_eq!(
+            monitor
+                .read_line(Duration::from_secs(5))
+                .unwrap()
+                .as_deref(),
+            Some("partial")
+        );
+    }
+
+    #[test]
+    fn lines_are_joined_across_parts() {
+        let mut lines = LineBuffer::default();
+
+        assert_eq!(
+            lines.split(b"one\r\ntw"),
+            [
+                LinePart {
+                    text: b"one",
+                    line: Some(b"one".to_vec()),
+                },
+                LinePart {
+                    text: b"tw",
+                    line: None,
+                },
+            ]
+        );
+        assert_eq!(
+            lines.split(b"o\r\n"),
+            [LinePart {
+                text: b"o",
+                line: Some(b"two".to_vec()),
+            }]
+        );
+        assert!(lines.split(b"").is_empty());
+    }
+
+    #[test]
+    fn framing_prints_raw_data_by_default() {
+        let mut parser = FrameDelimiter::new();
+
+        let mut asserted = 0;
+        parser.feed(b"hello", |frame| {
+            assert_eq!(frame, FrameKind::Raw(b"hello"));
+            asserted += 1;
+        });
+        assert_eq!(asserted, 1);
+    }
+
+    #[test]
+    fn start_byte_on_end_is_not_part_of_the_raw_sequence() {
+        let mut parser = FrameDelimiter::new();
+
+        let mut asserted = 0;
+        parser.feed(b"hello\xFF", |frame| {
+            assert_eq!(frame, FrameKind::Raw(b"hello"));
+            asserted += 1;
+        });
+        assert_eq!(asserted, 1);
+    }
+
+    #[test]
+    fn frame_start_on_end_is_not_part_of_the_raw_sequence() {
+        let mut parser = FrameDelimiter::new();
+
+        let mut asserted = 0;
+        parser.feed(b"hello\xFF\x00", |frame| {
+            assert_eq!(frame, FrameKind::Raw(b"hello"));
+            asserted += 1;
+        });
+        assert_eq!(asserted, 1);
+    }
+
+    #[test]
+    fn process_data_after_frame() {
+        let mut parser = FrameDelimiter::new();
+
+        let mut asserted = 0;
+        parser.feed(b"\xFF\x00frame data\x00hello", |frame| {
+            match asserted {
+                0 => assert_eq!(frame, FrameKind::Defmt(b"frame data")),
+                1 => assert_eq!(frame, FrameKind::Raw(b"hello")),
+                _ => panic!("Too many frames"),
+            }
+            asserted += 1;
+        });
+        assert_eq!(asserted, 2);
+    }
+
+    #[test]
+    fn can_concatenate_partial_defmt_frames() {
+        let mut parser = FrameDelimiter::new();
+
+        let mut asserted = 0;
+        parser.feed(b"\xFF\x00frame", |_| {
+            panic!("Should not have a frame yet");
+        });
+        parser.feed(b" data\x00\xFF", |frame| {
+            assert_eq!(frame, FrameKind::Defmt(b"frame data"));
+            asserted += 1;
+        });
+        parser.feed(b"\x00second frame", |_| {
+            panic!("Should not have a frame yet");
+        });
+        parser.feed(b"\x00last part", |frame| {
+            match asserted {
+                1 => assert_eq!(frame, FrameKind::Defmt(b"second frame")),
+                2 => assert_eq!(frame, FrameKind::Raw(b"last part")),
+                _ => panic!("Too many frames"),
+            }
+            asserted += 1;
+        });
+        assert_eq!(asserted, 3);
+    }
+
+    #[test]
+    fn defmt_frames_back_to_back() {
+        let mut parser = FrameDelimiter::new();
+
+        let mut asserted = 0;
+        parser.feed(b"\xFF\x00frame data1\x00\xFF\x00frame data2\x00", |frame| {
+            match asserted {
+                0 => assert_eq!(frame, FrameKind::Defmt(b"frame data1")),
+                1 => assert_eq!(frame, FrameKind::Defmt(b"frame data2")),
+                _ => panic!("Too many frames"),
+            }
+            asserted += 1;
+        });
+        assert_eq!(asserted, 2);
+    }
+
+    #[test]
+    fn output_includes_ff_and_0_bytes() {
+        let mut parser = FrameDelimiter::new();
+
+        let mut asserted = 0;
+        parser.feed(
+            b"some message\xFF with parts of\0 a defmt \0\xFF frame delimiter",
+            |frame| {
+                assert_eq!(
+                    frame,
+                    FrameKind::Raw(
+                        b"some message\xFF with parts of\0 a defmt \0\xFF frame delimiter"
+                    )
+                );
+                asserted += 1;
+            },
+        );
+        assert_eq!(asserted, 1);
+    }
+}
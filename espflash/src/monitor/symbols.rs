@@ -0,0 +1,229 @@
+//! Looking up the functions at addresses with the debug information of an ELF
+//! file
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    error::Error,
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
+
+use addr2line::{
+    gimli::{EndianRcSlice, RunTimeEndian},
+    object::{read::File, Object, ObjectSegment, ObjectSymbol},
+    Context, LookupResult,
+};
+use log::debug;
+
+use crate::monitor::{AddressResolver, ResolvedAddress};
+
+/// The function name and source location resolved for an address
+#[derive(Debug, Clone, Default)]
+struct Frame {
+    name: Option<String>,
+    location: Option<(String, u32)>,
+}
+
+/// Wrapper around addr2line that allows to look up function names and
+/// locations from a given address.
+///
+/// Loading the DWARF information of a large ELF file can take a while, so it
+/// happens on a worker thread which answers the lookups. This way the monitor
+/// can start showing output immediately, and only waits for the debug info
+/// once an address actually needs to be resolved. Results are cached, as the
+/// same addresses tend to show up over and over again (e.g. in backtraces).
+pub struct Symbols {
+    requests: Sender<u64>,
+    responses: Receiver<Frame>,
+    cache: RefCell<HashMap<u64, Frame>>,
+}
+
+impl Symbols {
+    /// Load the debug information of the ELF file in `bytes`
+    pub fn try_from(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        // Make sure the file can be parsed before starting the worker, so that
+        // invalid files are still reported to the caller.
+        File::parse(bytes)?;
+
+        let elf = bytes.to_vec();
+        let (requests, worker_requests) = channel::<u64>();
+        let (worker_responses, responses) = channel();
+
+        thread::Builder::new()
+            .name("symbols".into())
+            .spawn(move || {
+                let resolver = match Resolver::try_from(&elf) {
+                    Ok(resolver) => Some(resolver),
+                    Err(e) => {
+                        debug!("Failed to load debug information: {e}");
+                        None
+                    }
+                };
+
+                for addr in worker_requests {
+                    let frame = resolver
+                        .as_ref()
+                        .map(|resolver| resolver.resolve(addr))
+                        .unwrap_or_default();
+
+                    if worker_responses.send(frame).is_err() {
+                        break;
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            requests,
+            responses,
+            cache: RefCell::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the name of the function at the given address, if one can be found.
+    pub fn get_name(&self, addr: u64) -> Option<String> {
+        self.frame(addr).name
+    }
+
+    /// Returns the file name and line number of the function at the given address, if one can be.
+    pub fn get_location(&self, addr: u64) -> Option<(String, u32)> {
+        self.frame(addr).location
+    }
+
+    /// Returns the function at the given address, with its location if known
+    pub fn lookup(&self, address: u64) -> Option<ResolvedAddress> {
+        let Frame { name, location } = self.frame(address);
+
+        Some(ResolvedAddress {
+            address,
+            function: name?,
+            location,
+        })
+    }
+
+    /// Look up an address, asking the worker thread if it is not cached yet
+    fn frame(&self, addr: u64) -> Frame {
+        if let Some(frame) = self.cache.borrow().get(&addr) {
+            return frame.clone();
+        }
+
+        let frame = if self.requests.send(addr).is_ok() {
+            self.responses.recv().unwrap_or_default()
+        } else {
+            Frame::default()
+        };
+
+        self.cache.borrow_mut().insert(addr, frame.clone());
+
+        frame
+    }
+}
+
+impl AddressResolver for Symbols {
+    fn resolve(&mut self, address: u64) -> Option<ResolvedAddress> {
+        self.lookup(address)
+    }
+}
+
+/// Performs the actual lookups, living on the worker thread
+struct Resolver<'sym> {
+    file: File<'sym, &'sym [u8]>,
+    ctx: Context<EndianRcSlice<RunTimeEndian>>,
+}
+
+impl<'sym> Resolver<'sym> {
+    fn try_from(bytes: &'sym [u8]) -> Result<Self, Box<dyn Error>> {
+        let file = File::parse(bytes)?;
+        let ctx = Context::new(&file)?;
+
+        Ok(Self { file, ctx })
+    }
+
+    fn resolve(&self, addr: u64) -> Frame {
+        // no need to try an address not contained in any segment
+        if !self.file.segments().any(|segment| {
+            (segment.address()..(segment.address() + segment.size())).contains(&addr)
+        }) {
+            return Frame::default();
+        }
+
+        Frame {
+            name: self.get_name(addr),
+            location: self.get_location(addr),
+        }
+    }
+
+    fn get_name(&self, addr: u64) -> Option<String> {
+        // The basic steps here are:
+        //   1. find which frame `addr` is in
+        //   2. look up and demangle the function name
+        //   3. if no function name is found, try to look it up in the object file
+        //      directly
+        //   4. return a demangled function name, if one was found
+        let mut frames = match self.ctx.find_frames(addr) {
+            LookupResult::Output(result) => result.ok()?,
+            LookupResult::Load { .. } => unimplemented!(),
+        };
+
+        frames
+            .next()
+            .ok()
+            .flatten()
+            .and_then(|frame| {
+                frame
+                    .function
+                    .and_then(|name| name.demangle().map(|s| s.into_owned()).ok())
+            })
+            .or_else(|| {
+                // Don't use `symbol_map().get(addr)` - it's documentation says "Get the symbol before the given address." which might be totally wrong
+                let symbol = self.file.symbols().find(|symbol| {
+                    (symbol.address()..=(symbol.address() + symbol.size())).contains(&addr)
+                });
+
+                if let Some(symbol) = symbol {
+                    match symbol.name() {
+                        Ok(name) if !name.is_empty() => Some(
+                            addr2line::demangle_auto(std::borrow::Cow::Borrowed(name), None)
+                                .to_string(),
+                        ),
+                        _ => None,
+                    }
+                } else {
+                    None
+                }
+            })
+    }
+
+    fn get_location(&self, addr: u64) -> Option<(String, u32)> {
+        // Find the location which `addr` is in. If we can dedetermine a file name and
+        // line number for this function we will return them both in a tuple.
+        self.ctx.find_location(addr).ok()?.map(|location| {
+            let file = location.file.map(|f| f.to_string());
+            let line = location.line;
+
+            match (file, line) {
+                (Some(file), Some(line)) => Some((file, line)),
+                _ => None,
+            }
+        })?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use addr2line::object::{read::File, Object};
+
+    use super::Symbols;
+
+    #[test]
+    fn resolves_entry_point() {
+        let elf = include_bytes!("../../tests/resources/esp32_hal_blinky");
+        let entry = File::parse(&elf[..]).unwrap().entry();
+
+        let symbols = Symbols::try_from(elf).unwrap();
+        assert!(symbols.get_name(entry).is_some());
+        // Cached lookups return the same result
+        assert_eq!(symbols.get_name(entry), symbols.get_name(entry));
+        assert!(symbols.get_name(0).is_none());
+    }
+}
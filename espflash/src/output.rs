@@ -0,0 +1,150 @@
+//! Output files which only appear once they are complete
+//!
+//! Files are written to a temporary `<name>.partial` file next to their
+//! destination, which is renamed to the destination once everything has been
+//! written. An interrupted operation therefore never leaves behind a truncated
+//! file under the name the user asked for.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::error::Error;
+
+/// Extension appended to the name of a file while it is being written
+pub const PARTIAL_EXTENSION: &str = "partial";
+
+/// A file which is moved into place once it has been written completely
+///
+/// Dropping an `OutputFile` without calling [OutputFile::persist] removes the
+/// temporary file, unless it was kept with [OutputFile::keep_partial].
+#[derive(Debug)]
+pub struct OutputFile {
+    path: PathBuf,
+    partial_path: PathBuf,
+    file: Option<BufWriter<File>>,
+}
+
+impl OutputFile {
+    /// Start writing a file which will be saved to `path`
+    pub fn create(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let partial_path = partial_path(&path);
+
+        let file = File::create(&partial_path)
+            .map_err(|e| Error::FileOpenError(partial_path.display().to_string(), e))?;
+
+        Ok(Self {
+            path,
+            partial_path,
+            file: Some(BufWriter::new(file)),
+        })
+    }
+
+    /// Path the file is saved to once it is complete
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Path of the temporary file being written
+    pub fn partial_path(&self) -> &Path {
+        &self.partial_path
+    }
+
+    /// Flush the file to disk and move it to its destination
+    pub fn persist(mut self) -> Result<(), Error> {
+        let file = self.file.take().expect("file is only taken once");
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&self.partial_path, &self.path)?;
+
+        Ok(())
+    }
+
+    /// Flush the file and leave it at its temporary path, e.g. to resume an
+    /// interrupted read later
+    pub fn keep_partial(mut self) -> Result<PathBuf, Error> {
+        let mut file = self.file.take().expect("file is only taken once");
+        file.flush()?;
+
+        Ok(std::mem::take(&mut self.partial_path))
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            fs::remove_file(&self.partial_path).ok();
+        }
+    }
+}
+
+/// Write `data` to `path`, replacing any existing file only once everything
+/// has been written
+pub fn write(path: impl Into<PathBuf>, data: impl AsRef<[u8]>) -> Result<(), Error> {
+    let mut file = OutputFile::create(path)?;
+    file.write_all(data.as_ref())?;
+    file.persist()
+}
+
+/// Temporary path used while writing the file at `path`
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".");
+    name.push(PARTIAL_EXTENSION);
+
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write, path::Path};
+
+    use super::{partial_path, OutputFile};
+
+    #[test]
+    fn output_appears_when_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.bin");
+
+        assert_eq!(
+            partial_path(Path::new("out/dump.bin")),
+            Path::new("out/dump.bin.partial")
+        );
+
+        // Dropped before completion: nothing is left behind
+        let mut file = OutputFile::create(&path).unwrap();
+        file.write_all(b"incomplete").unwrap();
+        drop(file);
+        assert!(!path.exists());
+        assert!(!partial_path(&path).exists());
+
+        let mut file = OutputFile::create(&path).unwrap();
+        file.write_all(b"complete").unwrap();
+        assert!(!path.exists());
+        file.persist().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"complete");
+        assert!(!partial_path(&path).exists());
+
+        let mut file = OutputFile::create(&path).unwrap();
+        file.write_all(b"partial").unwrap();
+        let kept = file.keep_partial().unwrap();
+        assert_eq!(fs::read(&kept).unwrap(), b"partial");
+        assert_eq!(fs::read(&path).unwrap(), b"complete");
+    }
+}
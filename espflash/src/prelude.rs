@@ -0,0 +1,112 @@
+//! The types and functions needed for the common uses of the library
+//!
+//! The prelude is the stable subset of the library: its items are only
+//! changed incompatibly in major releases, while the rest of the library may
+//! be reorganized as it grows. Import it with a glob:
+//!
+//! ```
+//! use espflash::prelude::*;
+//! ```
+
+#![cfg_attr(
+    feature = "serialport",
+    doc = r#"
+## Connecting to a device
+
+A [Flasher] connects to the device on a serial port, resets it into the
+download mode and runs the flasher stub on it, as configured by
+[ConnectOptions]. The USB IDs of the port select the reset sequence; they
+are zero for ports which are not USB devices.
+
+```no_run
+use espflash::prelude::*;
+
+# fn main() -> Result<(), Error> {
+let port = Port::open("/dev/ttyUSB0", 115_200)?;
+let port_info = UsbPortInfo {
+    vid: 0x10c4,
+    pid: 0xea60,
+    serial_number: None,
+    manufacturer: None,
+    product: None,
+};
+
+let options = ConnectOptions::default().with_speed(921_600);
+let mut flasher = Flasher::connect(port, port_info, options)?;
+# Ok(())
+# }
+```
+
+## Flashing an application
+
+The application is written along with the bootloader and partition table
+for its chip, which [FlashDataBuilder] replaces when they are given.
+
+```no_run
+# use espflash::prelude::*;
+# fn flash(mut flasher: Flasher) -> Result<(), Error> {
+let elf_data = std::fs::read("target/riscv32imc-unknown-none-elf/release/app")?;
+let flash_data = FlashDataBuilder::new()
+    .with_flash_settings(FlashSettings::new(None, Some(FlashSize::_4Mb), None))
+    .build();
+let xtal_freq = flasher
+    .chip()
+    .into_target()
+    .crystal_freq(flasher.connection())?;
+
+flasher.load_elf_to_flash(&elf_data, flash_data, None, xtal_freq)?;
+# Ok(())
+# }
+```
+
+## Reading flash
+
+```no_run
+# use espflash::prelude::*;
+# fn read(mut flasher: Flasher) -> Result<(), Error> {
+// The first 64 kB, in blocks of 4 kB with up to 64 blocks in flight
+let data = flasher.read_flash_region(0x0, 0x10000, 0x1000, 64)?;
+std::fs::write("flash.bin", data)?;
+# Ok(())
+# }
+```
+
+## Information about the device
+
+```no_run
+# use espflash::prelude::*;
+# fn info(mut flasher: Flasher) -> Result<(), Error> {
+let info = flasher.device_info()?;
+println!(
+    "{} with {} of flash, MAC address {}",
+    info.chip, info.flash_size, info.mac_address
+);
+# Ok(())
+# }
+```
+"#
+)]
+
+#[cfg(feature = "serialport")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serialport")))]
+pub use serialport::UsbPortInfo;
+
+#[cfg(feature = "serialport")]
+#[cfg_attr(docsrs, doc(cfg(feature = "serialport")))]
+pub use crate::{
+    connection::{
+        reset::{ResetAfterOperation, ResetBeforeOperation},
+        ConnectStrategy, Connection, Port,
+    },
+    flasher::{ConnectOptions, Flasher, ProgressCallbacks, SegmentProgress, TransferRate},
+};
+pub use crate::{
+    elf::{ElfFirmwareImage, FirmwareImage, RomSegment},
+    error::Error,
+    flasher::{
+        parse_partition_table, DeviceInfo, FlashData, FlashDataBuilder, FlashFrequency, FlashMode,
+        FlashSettings, FlashSize,
+    },
+    image_format::{build_flash_plan, metadata::Metadata, AppImage},
+    targets::{Chip, XtalFrequency},
+};
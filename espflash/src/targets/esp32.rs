@@ -6,7 +6,7 @@ use crate::{
     elf::FirmwareImage,
     error::Error,
     flasher::{FlashData, FlashFrequency},
-    image_format::IdfBootloaderFormat,
+    image_format::{IdfBootloaderFormat, ImageFormat},
     targets::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target, XtalFrequency},
 };
 
@@ -17,6 +17,15 @@ const FLASH_RANGES: &[Range<u32>] = &[
     0x3f40_0000..0x3f80_0000, // DROM
 ];
 
+const PARAMS: Esp32Params = Esp32Params::new(
+    0x1000,
+    0x1_0000,
+    0x3f_0000,
+    0,
+    FlashFrequency::_40Mhz,
+    include_bytes!("../../resources/bootloaders/esp32-bootloader.bin"),
+);
+
 // UART0_BASE_REG + 0x14
 #[cfg(feature = "serialport")]
 const UART_CLKDIV_REG: u32 = 0x3ff4_0014;
@@ -29,11 +38,6 @@ const XTAL_CLK_DIVIDER: u32 = 1;
 pub struct Esp32;
 
 impl Esp32 {
-    /// Check if the magic value contains the specified value
-    pub fn has_magic_value(value: u32) -> bool {
-        CHIP_DETECT_MAGIC_VALUES.contains(&value)
-    }
-
     #[cfg(feature = "serialport")]
     /// Return the package version based on the eFuses
     fn package_version(&self, connection: &mut Connection) -> Result<u32, Error> {
@@ -53,8 +57,20 @@ impl ReadEFuse for Esp32 {
 }
 
 impl Target for Esp32 {
-    fn addr_is_flash(&self, addr: u32) -> bool {
-        FLASH_RANGES.iter().any(|range| range.contains(&addr))
+    fn chip(&self) -> Chip {
+        Chip::Esp32
+    }
+
+    fn chip_detect_magic_values(&self) -> &[u32] {
+        CHIP_DETECT_MAGIC_VALUES
+    }
+
+    fn uf2_family_id(&self) -> u32 {
+        0x1c5f_21b0
+    }
+
+    fn family_features(&self) -> &'static [&'static str] {
+        &["Wi-Fi", "Bluetooth Classic", "BLE"]
     }
 
     #[cfg(feature = "serialport")]
@@ -116,6 +132,13 @@ impl Target for Esp32 {
         Ok(features)
     }
 
+    #[cfg(feature = "serialport")]
+    fn embedded_flash(&self, connection: &mut Connection) -> Result<Option<bool>, Error> {
+        Ok(Some(
+            [2, 4, 5, 6].contains(&self.package_version(connection)?),
+        ))
+    }
+
     #[cfg(feature = "serialport")]
     fn major_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
         let apb_ctl_date = connection.read_reg(0x3FF6_607C)?;
@@ -152,17 +175,25 @@ impl Target for Esp32 {
         Ok(norm_xtal)
     }
 
-    fn get_flash_image<'a>(
+    fn reset_reason_reg(&self) -> Option<(u32, u32)> {
+        // RTC_CNTL_RESET_STATE_REG
+        Some((0x3ff4_8034, 0x3f))
+    }
+
+    fn brownout_status_reg(&self) -> Option<(u32, u32)> {
+        // RTC_CNTL_BROWN_OUT_REG, RTC_CNTL_BROWN_OUT_DET
+        Some((0x3ff4_80d4, 1 << 31))
+    }
+
+    fn get_esp_idf_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
         flash_data: FlashData,
         _chip_revision: Option<(u32, u32)>,
         xtal_freq: XtalFrequency,
-    ) -> Result<IdfBootloaderFormat<'a>, Error> {
+    ) -> Result<ImageFormat<'a>, Error> {
         let booloader: &'static [u8] = match xtal_freq {
-            XtalFrequency::_40Mhz => {
-                include_bytes!("../../resources/bootloaders/esp32-bootloader.bin")
-            }
+            XtalFrequency::_40Mhz => PARAMS.default_bootloader,
             XtalFrequency::_26Mhz => {
                 include_bytes!("../../resources/bootloaders/esp32_26-bootloader.bin")
             }
@@ -174,26 +205,12 @@ impl Target for Esp32 {
             }
         };
 
-        let params = Esp32Params::new(
-            0x1000,
-            0x1_0000,
-            0x3f_0000,
-            0,
-            FlashFrequency::_40Mhz,
-            booloader,
-        );
-
-        IdfBootloaderFormat::new(
-            image,
-            Chip::Esp32,
-            flash_data.min_chip_rev,
-            params,
-            flash_data.partition_table,
-            flash_data.partition_table_offset,
-            flash_data.target_app_partition,
-            flash_data.bootloader,
-            flash_data.flash_settings,
-        )
+        let params = Esp32Params {
+            default_bootloader: booloader,
+            ..PARAMS
+        };
+
+        IdfBootloaderFormat::new(image, Chip::Esp32, params, flash_data).map(ImageFormat::EspIdf)
     }
 
     #[cfg(feature = "serialport")]
@@ -208,6 +225,14 @@ impl Target for Esp32 {
         Ok(bytes_to_mac_addr(bytes))
     }
 
+    fn flash_ranges(&self) -> &[Range<u32>] {
+        FLASH_RANGES
+    }
+
+    fn params(&self) -> Esp32Params {
+        PARAMS
+    }
+
     fn spi_registers(&self) -> SpiRegisters {
         SpiRegisters {
             base: 0x3ff4_2000,
@@ -217,6 +242,8 @@ impl Target for Esp32 {
             w0_offset: 0x80,
             mosi_length_offset: Some(0x28),
             miso_length_offset: Some(0x2c),
+            clock_offset: 0x18,
+            clock_field_width: 6,
         }
     }
 
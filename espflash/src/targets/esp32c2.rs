@@ -8,7 +8,7 @@ use crate::{
     elf::FirmwareImage,
     error::Error,
     flasher::{FlashData, FlashFrequency},
-    image_format::IdfBootloaderFormat,
+    image_format::{IdfBootloaderFormat, ImageFormat},
     targets::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target, XtalFrequency},
 };
 
@@ -22,6 +22,15 @@ const FLASH_RANGES: &[Range<u32>] = &[
     0x3c00_0000..0x3c40_0000, // DROM
 ];
 
+const PARAMS: Esp32Params = Esp32Params::new(
+    0x0,
+    0x1_0000,
+    0x1f_0000,
+    12,
+    FlashFrequency::_30Mhz,
+    include_bytes!("../../resources/bootloaders/esp32c2-bootloader.bin"),
+);
+
 // UART0_BASE_REG + 0x14
 #[cfg(feature = "serialport")]
 const UART_CLKDIV_REG: u32 = 0x6000_0014;
@@ -34,13 +43,6 @@ const XTAL_CLK_DIVIDER: u32 = 1;
 /// ESP32-C2 Target
 pub struct Esp32c2;
 
-impl Esp32c2 {
-    /// Check if the magic value contains the specified value
-    pub fn has_magic_value(value: u32) -> bool {
-        CHIP_DETECT_MAGIC_VALUES.contains(&value)
-    }
-}
-
 impl ReadEFuse for Esp32c2 {
     fn efuse_reg(&self) -> u32 {
         0x6000_8800
@@ -48,8 +50,20 @@ impl ReadEFuse for Esp32c2 {
 }
 
 impl Target for Esp32c2 {
-    fn addr_is_flash(&self, addr: u32) -> bool {
-        FLASH_RANGES.iter().any(|range| range.contains(&addr))
+    fn chip(&self) -> Chip {
+        Chip::Esp32c2
+    }
+
+    fn chip_detect_magic_values(&self) -> &[u32] {
+        CHIP_DETECT_MAGIC_VALUES
+    }
+
+    fn uf2_family_id(&self) -> u32 {
+        0x2b88_d29c
+    }
+
+    fn family_features(&self) -> &'static [&'static str] {
+        &["Wi-Fi", "BLE"]
     }
 
     #[cfg(feature = "serialport")]
@@ -80,6 +94,11 @@ impl Target for Esp32c2 {
         Ok(norm_xtal)
     }
 
+    fn reset_reason_reg(&self) -> Option<(u32, u32)> {
+        // RTC_CNTL_RESET_STATE_REG
+        Some((0x6000_8038, 0x3f))
+    }
+
     fn flash_frequency_encodings(&self) -> HashMap<FlashFrequency, u8> {
         use FlashFrequency::*;
 
@@ -88,17 +107,21 @@ impl Target for Esp32c2 {
         HashMap::from(encodings)
     }
 
-    fn get_flash_image<'a>(
+    fn supports_direct_boot(&self) -> bool {
+        true
+    }
+
+    fn get_esp_idf_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
         flash_data: FlashData,
         _chip_revision: Option<(u32, u32)>,
         xtal_freq: XtalFrequency,
-    ) -> Result<IdfBootloaderFormat<'a>, Error> {
+    ) -> Result<ImageFormat<'a>, Error> {
         let booloader: &'static [u8] = match xtal_freq {
             XtalFrequency::_40Mhz => {
                 debug!("Using 40MHz bootloader");
-                include_bytes!("../../resources/bootloaders/esp32c2-bootloader.bin")
+                PARAMS.default_bootloader
             }
             XtalFrequency::_26Mhz => {
                 debug!("Using 26MHz bootloader");
@@ -112,26 +135,12 @@ impl Target for Esp32c2 {
             }
         };
 
-        let params = Esp32Params::new(
-            0x0,
-            0x1_0000,
-            0x1f_0000,
-            12,
-            FlashFrequency::_30Mhz,
-            booloader,
-        );
-
-        IdfBootloaderFormat::new(
-            image,
-            Chip::Esp32c2,
-            flash_data.min_chip_rev,
-            params,
-            flash_data.partition_table,
-            flash_data.partition_table_offset,
-            flash_data.target_app_partition,
-            flash_data.bootloader,
-            flash_data.flash_settings,
-        )
+        let params = Esp32Params {
+            default_bootloader: booloader,
+            ..PARAMS
+        };
+
+        IdfBootloaderFormat::new(image, Chip::Esp32c2, params, flash_data).map(ImageFormat::EspIdf)
     }
 
     #[cfg(feature = "serialport")]
@@ -147,6 +156,18 @@ impl Target for Esp32c2 {
         Ok(bytes_to_mac_addr(bytes))
     }
 
+    fn flash_ranges(&self) -> &[Range<u32>] {
+        FLASH_RANGES
+    }
+
+    fn valid_mmu_page_sizes(&self) -> Option<&'static [u32]> {
+        Some(&[0x4000, 0x8000, 0x10000])
+    }
+
+    fn params(&self) -> Esp32Params {
+        PARAMS
+    }
+
     fn spi_registers(&self) -> SpiRegisters {
         SpiRegisters {
             base: 0x6000_2000,
@@ -156,6 +177,8 @@ impl Target for Esp32c2 {
             w0_offset: 0x58,
             mosi_length_offset: Some(0x24),
             miso_length_offset: Some(0x28),
+            clock_offset: 0x14,
+            clock_field_width: 8,
         }
     }
 
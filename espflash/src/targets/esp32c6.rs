@@ -6,7 +6,7 @@ use crate::{
     elf::FirmwareImage,
     error::Error,
     flasher::{FlashData, FlashFrequency},
-    image_format::IdfBootloaderFormat,
+    image_format::{IdfBootloaderFormat, ImageFormat},
     targets::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target, XtalFrequency},
 };
 
@@ -29,13 +29,6 @@ const PARAMS: Esp32Params = Esp32Params::new(
 /// ESP32-C6 Target
 pub struct Esp32c6;
 
-impl Esp32c6 {
-    /// Check if the magic value contains the specified value
-    pub fn has_magic_value(value: u32) -> bool {
-        CHIP_DETECT_MAGIC_VALUES.contains(&value)
-    }
-}
-
 impl ReadEFuse for Esp32c6 {
     fn efuse_reg(&self) -> u32 {
         0x600B_0800
@@ -43,8 +36,20 @@ impl ReadEFuse for Esp32c6 {
 }
 
 impl Target for Esp32c6 {
-    fn addr_is_flash(&self, addr: u32) -> bool {
-        FLASH_RANGES.iter().any(|range| range.contains(&addr))
+    fn chip(&self) -> Chip {
+        Chip::Esp32c6
+    }
+
+    fn chip_detect_magic_values(&self) -> &[u32] {
+        CHIP_DETECT_MAGIC_VALUES
+    }
+
+    fn uf2_family_id(&self) -> u32 {
+        0x540d_df62
+    }
+
+    fn family_features(&self) -> &'static [&'static str] {
+        &["Wi-Fi 6", "BLE", "IEEE 802.15.4", "USB-Serial-JTAG"]
     }
 
     #[cfg(feature = "serialport")]
@@ -71,13 +76,22 @@ impl Target for Esp32c6 {
         Ok(XtalFrequency::_40Mhz)
     }
 
-    fn get_flash_image<'a>(
+    fn reset_reason_reg(&self) -> Option<(u32, u32)> {
+        // LP_CLKRST_RESET_CAUSE_REG
+        Some((0x600b_0410, 0x1f))
+    }
+
+    fn supports_direct_boot(&self) -> bool {
+        true
+    }
+
+    fn get_esp_idf_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
         flash_data: FlashData,
         _chip_revision: Option<(u32, u32)>,
         xtal_freq: XtalFrequency,
-    ) -> Result<IdfBootloaderFormat<'a>, Error> {
+    ) -> Result<ImageFormat<'a>, Error> {
         if xtal_freq != XtalFrequency::_40Mhz {
             return Err(Error::UnsupportedFeature {
                 chip: Chip::Esp32c6,
@@ -85,17 +99,19 @@ impl Target for Esp32c6 {
             });
         }
 
-        IdfBootloaderFormat::new(
-            image,
-            Chip::Esp32c6,
-            flash_data.min_chip_rev,
-            PARAMS,
-            flash_data.partition_table,
-            flash_data.partition_table_offset,
-            flash_data.target_app_partition,
-            flash_data.bootloader,
-            flash_data.flash_settings,
-        )
+        IdfBootloaderFormat::new(image, Chip::Esp32c6, PARAMS, flash_data).map(ImageFormat::EspIdf)
+    }
+
+    fn flash_ranges(&self) -> &[Range<u32>] {
+        FLASH_RANGES
+    }
+
+    fn valid_mmu_page_sizes(&self) -> Option<&'static [u32]> {
+        Some(&[0x2000, 0x4000, 0x8000, 0x10000])
+    }
+
+    fn params(&self) -> Esp32Params {
+        PARAMS
     }
 
     fn spi_registers(&self) -> SpiRegisters {
@@ -107,6 +123,8 @@ impl Target for Esp32c6 {
             w0_offset: 0x58,
             mosi_length_offset: Some(0x24),
             miso_length_offset: Some(0x28),
+            clock_offset: 0x14,
+            clock_field_width: 8,
         }
     }
 
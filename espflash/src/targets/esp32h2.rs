@@ -6,7 +6,7 @@ use crate::{
     elf::FirmwareImage,
     error::Error,
     flasher::{FlashData, FlashFrequency},
-    image_format::IdfBootloaderFormat,
+    image_format::{IdfBootloaderFormat, ImageFormat},
     targets::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target, XtalFrequency},
 };
 
@@ -29,12 +29,6 @@ const PARAMS: Esp32Params = Esp32Params::new(
 /// ESP32-H2 Target
 pub struct Esp32h2;
 
-impl Esp32h2 {
-    pub fn has_magic_value(value: u32) -> bool {
-        CHIP_DETECT_MAGIC_VALUES.contains(&value)
-    }
-}
-
 impl ReadEFuse for Esp32h2 {
     fn efuse_reg(&self) -> u32 {
         0x600B_0800
@@ -42,8 +36,20 @@ impl ReadEFuse for Esp32h2 {
 }
 
 impl Target for Esp32h2 {
-    fn addr_is_flash(&self, addr: u32) -> bool {
-        FLASH_RANGES.iter().any(|range| range.contains(&addr))
+    fn chip(&self) -> Chip {
+        Chip::Esp32h2
+    }
+
+    fn chip_detect_magic_values(&self) -> &[u32] {
+        CHIP_DETECT_MAGIC_VALUES
+    }
+
+    fn uf2_family_id(&self) -> u32 {
+        0x3327_26f6
+    }
+
+    fn family_features(&self) -> &'static [&'static str] {
+        &["BLE", "IEEE 802.15.4", "USB-Serial-JTAG"]
     }
 
     #[cfg(feature = "serialport")]
@@ -70,6 +76,11 @@ impl Target for Esp32h2 {
         Ok(XtalFrequency::_32Mhz)
     }
 
+    fn reset_reason_reg(&self) -> Option<(u32, u32)> {
+        // LP_CLKRST_RESET_CAUSE_REG
+        Some((0x600b_0410, 0x1f))
+    }
+
     fn flash_frequency_encodings(&self) -> HashMap<FlashFrequency, u8> {
         use FlashFrequency::*;
 
@@ -78,13 +89,17 @@ impl Target for Esp32h2 {
         HashMap::from(encodings)
     }
 
-    fn get_flash_image<'a>(
+    fn supports_direct_boot(&self) -> bool {
+        true
+    }
+
+    fn get_esp_idf_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
         flash_data: FlashData,
         _chip_revision: Option<(u32, u32)>,
         xtal_freq: XtalFrequency,
-    ) -> Result<IdfBootloaderFormat<'a>, Error> {
+    ) -> Result<ImageFormat<'a>, Error> {
         if xtal_freq != XtalFrequency::_32Mhz {
             return Err(Error::UnsupportedFeature {
                 chip: Chip::Esp32h2,
@@ -92,17 +107,23 @@ impl Target for Esp32h2 {
             });
         }
 
-        IdfBootloaderFormat::new(
-            image,
-            Chip::Esp32h2,
-            flash_data.min_chip_rev,
-            PARAMS,
-            flash_data.partition_table,
-            flash_data.partition_table_offset,
-            flash_data.target_app_partition,
-            flash_data.bootloader,
-            flash_data.flash_settings,
-        )
+        IdfBootloaderFormat::new(image, Chip::Esp32h2, PARAMS, flash_data).map(ImageFormat::EspIdf)
+    }
+
+    fn flash_ranges(&self) -> &[Range<u32>] {
+        FLASH_RANGES
+    }
+
+    fn default_xtal_frequency(&self) -> XtalFrequency {
+        XtalFrequency::_32Mhz
+    }
+
+    fn valid_mmu_page_sizes(&self) -> Option<&'static [u32]> {
+        Some(&[0x2000, 0x4000, 0x8000, 0x10000])
+    }
+
+    fn params(&self) -> Esp32Params {
+        PARAMS
     }
 
     fn spi_registers(&self) -> SpiRegisters {
@@ -114,6 +135,8 @@ impl Target for Esp32h2 {
             w0_offset: 0x58,
             mosi_length_offset: Some(0x24),
             miso_length_offset: Some(0x28),
+            clock_offset: 0x14,
+            clock_field_width: 8,
         }
     }
 
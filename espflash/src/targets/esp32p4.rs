@@ -6,7 +6,7 @@ use crate::{
     elf::FirmwareImage,
     error::Error,
     flasher::{FlashData, FlashFrequency},
-    image_format::IdfBootloaderFormat,
+    image_format::{IdfBootloaderFormat, ImageFormat},
     targets::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target, XtalFrequency},
 };
 
@@ -29,12 +29,6 @@ const PARAMS: Esp32Params = Esp32Params::new(
 /// ESP32-P4 Target
 pub struct Esp32p4;
 
-impl Esp32p4 {
-    pub fn has_magic_value(value: u32) -> bool {
-        CHIP_DETECT_MAGIC_VALUES.contains(&value)
-    }
-}
-
 impl ReadEFuse for Esp32p4 {
     fn efuse_reg(&self) -> u32 {
         0x5012_D000
@@ -42,8 +36,20 @@ impl ReadEFuse for Esp32p4 {
 }
 
 impl Target for Esp32p4 {
-    fn addr_is_flash(&self, addr: u32) -> bool {
-        FLASH_RANGES.iter().any(|range| range.contains(&addr))
+    fn chip(&self) -> Chip {
+        Chip::Esp32p4
+    }
+
+    fn chip_detect_magic_values(&self) -> &[u32] {
+        CHIP_DETECT_MAGIC_VALUES
+    }
+
+    fn uf2_family_id(&self) -> u32 {
+        0x3d30_8e94
+    }
+
+    fn family_features(&self) -> &'static [&'static str] {
+        &["USB-Serial-JTAG", "USB OTG"]
     }
 
     #[cfg(feature = "serialport")]
@@ -67,13 +73,13 @@ impl Target for Esp32p4 {
         Ok(XtalFrequency::_40Mhz)
     }
 
-    fn get_flash_image<'a>(
+    fn get_esp_idf_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
         flash_data: FlashData,
         _chip_revision: Option<(u32, u32)>,
         xtal_freq: XtalFrequency,
-    ) -> Result<IdfBootloaderFormat<'a>, Error> {
+    ) -> Result<ImageFormat<'a>, Error> {
         if xtal_freq != XtalFrequency::_40Mhz {
             return Err(Error::UnsupportedFeature {
                 chip: Chip::Esp32p4,
@@ -81,17 +87,15 @@ impl Target for Esp32p4 {
             });
         }
 
-        IdfBootloaderFormat::new(
-            image,
-            Chip::Esp32p4,
-            flash_data.min_chip_rev,
-            PARAMS,
-            flash_data.partition_table,
-            flash_data.partition_table_offset,
-            flash_data.target_app_partition,
-            flash_data.bootloader,
-            flash_data.flash_settings,
-        )
+        IdfBootloaderFormat::new(image, Chip::Esp32p4, PARAMS, flash_data).map(ImageFormat::EspIdf)
+    }
+
+    fn flash_ranges(&self) -> &[Range<u32>] {
+        FLASH_RANGES
+    }
+
+    fn params(&self) -> Esp32Params {
+        PARAMS
     }
 
     fn spi_registers(&self) -> SpiRegisters {
@@ -103,6 +107,8 @@ impl Target for Esp32p4 {
             w0_offset: 0x58,
             mosi_length_offset: Some(0x24),
             miso_length_offset: Some(0x28),
+            clock_offset: 0x14,
+            clock_field_width: 8,
         }
     }
 
@@ -6,7 +6,7 @@ use crate::{
     elf::FirmwareImage,
     error::Error,
     flasher::{FlashData, FlashFrequency},
-    image_format::IdfBootloaderFormat,
+    image_format::{IdfBootloaderFormat, ImageFormat},
     targets::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target, XtalFrequency},
 };
 
@@ -68,11 +68,6 @@ impl Esp32s2 {
 
         Ok(psram_version)
     }
-
-    /// Check if the magic value contains the specified value
-    pub fn has_magic_value(value: u32) -> bool {
-        CHIP_DETECT_MAGIC_VALUES.contains(&value)
-    }
 }
 
 impl ReadEFuse for Esp32s2 {
@@ -82,8 +77,20 @@ impl ReadEFuse for Esp32s2 {
 }
 
 impl Target for Esp32s2 {
-    fn addr_is_flash(&self, addr: u32) -> bool {
-        FLASH_RANGES.iter().any(|range| range.contains(&addr))
+    fn chip(&self) -> Chip {
+        Chip::Esp32s2
+    }
+
+    fn chip_detect_magic_values(&self) -> &[u32] {
+        CHIP_DETECT_MAGIC_VALUES
+    }
+
+    fn uf2_family_id(&self) -> u32 {
+        0xbfdd_4eee
+    }
+
+    fn family_features(&self) -> &'static [&'static str] {
+        &["Wi-Fi", "USB OTG"]
     }
 
     #[cfg(feature = "serialport")]
@@ -117,6 +124,11 @@ impl Target for Esp32s2 {
         Ok(features)
     }
 
+    #[cfg(feature = "serialport")]
+    fn embedded_flash(&self, connection: &mut Connection) -> Result<Option<bool>, Error> {
+        Ok(Some(self.get_flash_version(connection)? != 0))
+    }
+
     #[cfg(feature = "serialport")]
     fn major_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
         Ok(self.read_efuse(connection, 20)? >> 18 & 0x3)
@@ -136,6 +148,16 @@ impl Target for Esp32s2 {
         Ok(XtalFrequency::_40Mhz)
     }
 
+    fn reset_reason_reg(&self) -> Option<(u32, u32)> {
+        // RTC_CNTL_RESET_STATE_REG
+        Some((0x3f40_8038, 0x3f))
+    }
+
+    fn brownout_status_reg(&self) -> Option<(u32, u32)> {
+        // RTC_CNTL_BROWN_OUT_REG, RTC_CNTL_BROWN_OUT_DET
+        Some((0x3f40_80d8, 1 << 31))
+    }
+
     #[cfg(feature = "serialport")]
     fn flash_write_size(&self, connection: &mut Connection) -> Result<usize, Error> {
         Ok(if self.connection_is_usb_otg(connection)? {
@@ -145,13 +167,13 @@ impl Target for Esp32s2 {
         })
     }
 
-    fn get_flash_image<'a>(
+    fn get_esp_idf_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
         flash_data: FlashData,
         _chip_revision: Option<(u32, u32)>,
         xtal_freq: XtalFrequency,
-    ) -> Result<IdfBootloaderFormat<'a>, Error> {
+    ) -> Result<ImageFormat<'a>, Error> {
         if xtal_freq != XtalFrequency::_40Mhz {
             return Err(Error::UnsupportedFeature {
                 chip: Chip::Esp32s2,
@@ -159,17 +181,7 @@ impl Target for Esp32s2 {
             });
         }
 
-        IdfBootloaderFormat::new(
-            image,
-            Chip::Esp32s2,
-            flash_data.min_chip_rev,
-            PARAMS,
-            flash_data.partition_table,
-            flash_data.partition_table_offset,
-            flash_data.target_app_partition,
-            flash_data.bootloader,
-            flash_data.flash_settings,
-        )
+        IdfBootloaderFormat::new(image, Chip::Esp32s2, PARAMS, flash_data).map(ImageFormat::EspIdf)
     }
 
     #[cfg(feature = "serialport")]
@@ -181,6 +193,14 @@ impl Target for Esp32s2 {
         })
     }
 
+    fn flash_ranges(&self) -> &[Range<u32>] {
+        FLASH_RANGES
+    }
+
+    fn params(&self) -> Esp32Params {
+        PARAMS
+    }
+
     fn spi_registers(&self) -> SpiRegisters {
         SpiRegisters {
             base: 0x3f40_2000,
@@ -190,6 +210,8 @@ impl Target for Esp32s2 {
             w0_offset: 0x58,
             mosi_length_offset: Some(0x24),
             miso_length_offset: Some(0x28),
+            clock_offset: 0x14,
+            clock_field_width: 8,
         }
     }
 
@@ -6,7 +6,7 @@ use crate::{
     elf::FirmwareImage,
     error::Error,
     flasher::{FlashData, FlashFrequency},
-    image_format::IdfBootloaderFormat,
+    image_format::{IdfBootloaderFormat, ImageFormat},
     targets::{Chip, Esp32Params, ReadEFuse, SpiRegisters, Target, XtalFrequency},
 };
 
@@ -41,11 +41,6 @@ impl Esp32s3 {
     fn blk_version_minor(&self, connection: &mut Connection) -> Result<u32, Error> {
         Ok(self.read_efuse(connection, 20)? >> 24 & 0x7)
     }
-
-    /// Check if the magic value contains the specified value
-    pub fn has_magic_value(value: u32) -> bool {
-        CHIP_DETECT_MAGIC_VALUES.contains(&value)
-    }
 }
 
 impl ReadEFuse for Esp32s3 {
@@ -55,8 +50,20 @@ impl ReadEFuse for Esp32s3 {
 }
 
 impl Target for Esp32s3 {
-    fn addr_is_flash(&self, addr: u32) -> bool {
-        FLASH_RANGES.iter().any(|range| range.contains(&addr))
+    fn chip(&self) -> Chip {
+        Chip::Esp32s3
+    }
+
+    fn chip_detect_magic_values(&self) -> &[u32] {
+        CHIP_DETECT_MAGIC_VALUES
+    }
+
+    fn uf2_family_id(&self) -> u32 {
+        0xc47e_5767
+    }
+
+    fn family_features(&self) -> &'static [&'static str] {
+        &["Wi-Fi", "BLE", "USB-Serial-JTAG", "USB OTG"]
     }
 
     #[cfg(feature = "serialport")]
@@ -64,6 +71,13 @@ impl Target for Esp32s3 {
         Ok(vec!["WiFi", "BLE"])
     }
 
+    #[cfg(feature = "serialport")]
+    fn embedded_flash(&self, connection: &mut Connection) -> Result<Option<bool>, Error> {
+        let flash_cap = self.read_efuse(connection, 20)? >> 27 & 0x7;
+
+        Ok(Some(flash_cap != 0))
+    }
+
     #[cfg(feature = "serialport")]
     fn major_chip_version(&self, connection: &mut Connection) -> Result<u32, Error> {
         let major = self.read_efuse(connection, 22)? >> 24 & 0x3;
@@ -95,13 +109,23 @@ impl Target for Esp32s3 {
         Ok(XtalFrequency::_40Mhz)
     }
 
-    fn get_flash_image<'a>(
+    fn reset_reason_reg(&self) -> Option<(u32, u32)> {
+        // RTC_CNTL_RESET_STATE_REG
+        Some((0x6000_8038, 0x3f))
+    }
+
+    fn brownout_status_reg(&self) -> Option<(u32, u32)> {
+        // RTC_CNTL_BROWN_OUT_REG, RTC_CNTL_BROWN_OUT_DET
+        Some((0x6000_80e8, 1 << 31))
+    }
+
+    fn get_esp_idf_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
         flash_data: FlashData,
         _chip_revision: Option<(u32, u32)>,
         xtal_freq: XtalFrequency,
-    ) -> Result<IdfBootloaderFormat<'a>, Error> {
+    ) -> Result<ImageFormat<'a>, Error> {
         if xtal_freq != XtalFrequency::_40Mhz {
             return Err(Error::UnsupportedFeature {
                 chip: Chip::Esp32s3,
@@ -109,17 +133,15 @@ impl Target for Esp32s3 {
             });
         }
 
-        IdfBootloaderFormat::new(
-            image,
-            Chip::Esp32s3,
-            flash_data.min_chip_rev,
-            PARAMS,
-            flash_data.partition_table,
-            flash_data.partition_table_offset,
-            flash_data.target_app_partition,
-            flash_data.bootloader,
-            flash_data.flash_settings,
-        )
+        IdfBootloaderFormat::new(image, Chip::Esp32s3, PARAMS, flash_data).map(ImageFormat::EspIdf)
+    }
+
+    fn flash_ranges(&self) -> &[Range<u32>] {
+        FLASH_RANGES
+    }
+
+    fn params(&self) -> Esp32Params {
+        PARAMS
     }
 
     fn spi_registers(&self) -> SpiRegisters {
@@ -131,6 +153,8 @@ impl Target for Esp32s3 {
             w0_offset: 0x58,
             mosi_length_offset: Some(0x24),
             miso_length_offset: Some(0x28),
+            clock_offset: 0x14,
+            clock_field_width: 8,
         }
     }
 
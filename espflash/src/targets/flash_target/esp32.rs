@@ -1,23 +1,21 @@
 use std::io::Write;
 
-use flate2::{
-    write::{ZlibDecoder, ZlibEncoder},
-    Compression,
-};
-use log::info;
-use md5::{Digest, Md5};
+use flate2::write::{ZlibDecoder, ZlibEncoder};
+use log::{debug, info, warn};
 
 #[cfg(feature = "serialport")]
 use crate::{
     command::{Command, CommandType},
     connection::{Connection, USB_SERIAL_JTAG_PID},
-    flasher::ProgressCallbacks,
+    error::{ConnectionError, RomErrorKind},
+    flasher::{compat::Feature, deflate::Link, FlashReader, ProgressCallbacks},
     targets::FlashTarget,
 };
 use crate::{
+    digest::DigestAlgorithm,
     elf::RomSegment,
     error::Error,
-    flasher::{SpiAttachParams, FLASH_SECTOR_SIZE},
+    flasher::{encryption::FlashEncryption, SpiAttachParams, FLASH_SECTOR_SIZE},
     targets::Chip,
 };
 
@@ -28,7 +26,12 @@ pub struct Esp32Target {
     use_stub: bool,
     verify: bool,
     skip: bool,
+    digest: DigestAlgorithm,
+    encryption: Option<FlashEncryption>,
     need_deflate_end: bool,
+    /// Whether data is written compressed, until the loader turns out not to
+    /// support it
+    deflate: bool,
 }
 
 impl Esp32Target {
@@ -38,6 +41,8 @@ impl Esp32Target {
         use_stub: bool,
         verify: bool,
         skip: bool,
+        digest: DigestAlgorithm,
+        encryption: Option<FlashEncryption>,
     ) -> Self {
         Esp32Target {
             chip,
@@ -45,11 +50,166 @@ impl Esp32Target {
             use_stub,
             verify,
             skip,
+            digest,
+            encryption,
             need_deflate_end: false,
+            deflate: true,
         }
     }
 }
 
+#[cfg(feature = "serialport")]
+impl Esp32Target {
+    /// Write `data` compressed to flash at `addr`, returning the error along
+    /// with the number of bytes of `data` the device acknowledged if it fails
+    fn write_deflated(
+        &mut self,
+        connection: &mut Connection,
+        addr: u32,
+        data: &[u8],
+        window: usize,
+        progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), (Error, usize)> {
+        let link = Link {
+            stub: self.use_stub,
+            baud: if connection.is_usb_cdc() {
+                None
+            } else {
+                Some(connection.get_baud().map_err(|e| (e, 0))?)
+            },
+            default_block_size: self
+                .chip
+                .into_target()
+                .flash_write_size(connection)
+                .map_err(|e| (e, 0))?,
+            large_blocks: allows(connection, Feature::LargeBlocks),
+        };
+        let level = connection.compression_level().resolve(&link);
+        let flash_write_size = connection.block_size().resolve(&link);
+        debug!(
+            "Writing with compression level {} in blocks of {flash_write_size} bytes",
+            level.level()
+        );
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), level);
+        encoder.write_all(data).map_err(|e| (e.into(), 0))?;
+        let compressed = encoder.finish().map_err(|e| (e.into(), 0))?;
+        let block_count = compressed.len().div_ceil(flash_write_size);
+        let erase_count = data.len().div_ceil(FLASH_SECTOR_SIZE);
+
+        // round up to sector size
+        let erase_size = (erase_count * FLASH_SECTOR_SIZE) as u32;
+
+        let chunks = compressed.chunks(flash_write_size);
+        let num_chunks = chunks.len();
+
+        // decode the chunks to see how much data the device will have to save, only
+        // counting the decoded bytes rather than keeping a copy of the data, and
+        // how much of the data was sent once each block is acknowledged
+        let mut decoder = ZlibDecoder::new(ByteCounter::default());
+        let mut largest_block = 0;
+        let mut decoded_ends = Vec::with_capacity(num_chunks);
+
+        for block in chunks.clone() {
+            let decoded_size = decoder.get_ref().0;
+            decoder
+                .write_all(block)
+                .and_then(|_| decoder.flush())
+                .map_err(|e| (e.into(), 0))?;
+            largest_block = largest_block.max(decoder.get_ref().0 - decoded_size);
+            decoded_ends.push(decoder.get_ref().0);
+        }
+
+        let timeout = CommandType::FlashDeflData.timeout_for_size(largest_block as u32);
+
+        connection
+            .with_timeout(
+                CommandType::FlashDeflBegin.timeout_for_size(erase_size),
+                |connection| {
+                    connection.command(Command::FlashDeflBegin {
+                        size: data.len() as u32,
+                        blocks: block_count as u32,
+                        block_size: flash_write_size as u32,
+                        offset: addr,
+                        supports_encryption: self.chip != Chip::Esp32 && !self.use_stub,
+                        encrypted: false,
+                    })?;
+                    Ok(())
+                },
+            )
+            .map_err(|e| {
+                if !self.use_stub && rejects_command(&e) {
+                    self.deflate = false;
+                }
+                (e, 0)
+            })?;
+        self.need_deflate_end = true;
+
+        if let Some(cb) = progress.as_mut() {
+            cb.init(addr, num_chunks);
+            cb.compressed_size(compressed.len());
+        }
+
+        let commands = chunks.enumerate().map(|(i, block)| Command::FlashDeflData {
+            sequence: i as u32,
+            pad_to: 0,
+            pad_byte: 0xff,
+            data: block,
+        });
+
+        let mut acknowledged = 0;
+        connection
+            .pipelined_commands(commands, window, timeout, |i| {
+                acknowledged = decoded_ends[i];
+                if let Some(cb) = progress.as_mut() {
+                    cb.update(i + 1)
+                }
+            })
+            .map_err(|e| (e, acknowledged))
+    }
+}
+
+/// Whether `feature` may be used with the revision of the connected chip
+#[cfg(feature = "serialport")]
+fn allows(connection: &Connection, feature: Feature) -> bool {
+    connection
+        .compatibility()
+        .is_none_or(|compatibility| compatibility.allows(feature))
+}
+
+/// Whether the loader rejected a command, which a ROM loader that does not
+/// implement it answers with an invalid message error
+///
+/// A timeout is not a rejection, as erasing a large region may simply take
+/// longer than expected.
+#[cfg(feature = "serialport")]
+fn rejects_command(error: &Error) -> bool {
+    matches!(error, Error::RomError(e) if matches!(e.kind(), RomErrorKind::InvalidMessage))
+}
+
+/// Whether writing to flash may succeed when retried after an error, e.g. a
+/// response which was corrupted or arrived late, or a device which dropped off
+/// USB and has to be reconnected
+#[cfg(feature = "serialport")]
+fn is_transient(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::Connection(
+            ConnectionError::Timeout(_)
+                | ConnectionError::FramingError
+                | ConnectionError::OverSizedPacket
+                | ConnectionError::Disconnected
+                | ConnectionError::DeviceNotFound
+        ) | Error::Flashing(
+            ConnectionError::Timeout(_)
+                | ConnectionError::FramingError
+                | ConnectionError::OverSizedPacket
+                | ConnectionError::Disconnected
+                | ConnectionError::DeviceNotFound
+        ) | Error::RomError(_)
+    )
+}
+
 #[cfg(feature = "serialport")]
 impl FlashTarget for Esp32Target {
     fn begin(&mut self, connection: &mut Connection) -> Result<(), Error> {
@@ -140,89 +300,98 @@ impl FlashTarget for Esp32Target {
     ) -> Result<(), Error> {
         let addr = segment.addr;
 
-        let mut md5_hasher = Md5::new();
-        md5_hasher.update(&segment.data);
-        let checksum_md5 = md5_hasher.finalize();
-
-        if self.skip {
-            let flash_checksum_md5: u128 =
-                connection.with_timeout(CommandType::FlashMd5.timeout(), |connection| {
-                    connection
-                        .command(crate::command::Command::FlashMd5 {
-                            offset: addr,
-                            size: segment.data.len() as u32,
-                        })?
-                        .try_into()
-                })?;
-
-            if checksum_md5.as_slice() == flash_checksum_md5.to_be_bytes() {
-                info!(
-                    "Segment at address '0x{:x}' has not changed, skipping write",
-                    addr
-                );
-                return Ok(());
+        let segment = match &self.encryption {
+            #[cfg(feature = "security")]
+            Some(FlashEncryption::Host(key)) => RomSegment {
+                addr,
+                data: key.encrypt(self.chip, addr, &segment.data)?.into(),
+            },
+            Some(FlashEncryption::Device) => {
+                return self.write_encrypted_segment(connection, segment, progress)
             }
-        }
-
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
-        encoder.write_all(&segment.data)?;
-        let compressed = encoder.finish()?;
-
-        let target = self.chip.into_target();
-        let flash_write_size = target.flash_write_size(connection)?;
-        let block_count = compressed.len().div_ceil(flash_write_size);
-        let erase_count = segment.data.len().div_ceil(FLASH_SECTOR_SIZE);
+            None => segment,
+        };
 
-        // round up to sector size
-        let erase_size = (erase_count * FLASH_SECTOR_SIZE) as u32;
+        let size = segment.data.len() as u32;
+        let digest = self.digest.digest();
+        let checksum = digest.digest(&segment.data);
 
-        connection.with_timeout(
-            CommandType::FlashDeflBegin.timeout_for_size(erase_size),
-            |connection| {
-                connection.command(Command::FlashDeflBegin {
-                    size: segment.data.len() as u32,
-                    blocks: block_count as u32,
-                    block_size: flash_write_size as u32,
-                    offset: addr,
-                    supports_encryption: self.chip != Chip::Esp32 && !self.use_stub,
-                })?;
-                Ok(())
-            },
-        )?;
-        self.need_deflate_end = true;
+        if self.skip
+            && checksum
+                == digest.flash_digest(
+                    &mut FlashReader::new(connection, self.chip, self.use_stub),
+                    addr,
+                    size,
+                )?
+        {
+            info!(
+                "Segment at address '0x{:x}' has not changed, skipping write",
+                addr
+            );
+            return Ok(());
+        }
 
-        let chunks = compressed.chunks(flash_write_size);
-        let num_chunks = chunks.len();
+        // Only the stub buffers data commands, the ROM loader needs to receive them
+        // one at a time
+        let mut window = if self.use_stub && allows(connection, Feature::PipelinedWrites) {
+            connection.data_window()
+        } else {
+            1
+        };
+        let mut retries = 0;
+        // Bytes at the start of the segment which are known to be in flash
+        let mut written = 0;
 
-        if let Some(cb) = progress.as_mut() {
-            cb.init(addr, num_chunks)
-        }
+        loop {
+            let data = &segment.data[written..];
+            let part_addr = addr + written as u32;
 
-        // decode the chunks to see how much data the device will have to save
-        let mut decoder = ZlibDecoder::new(Vec::new());
-        let mut decoded_size = 0;
+            let deflate = self.deflate;
+            let result = if deflate {
+                self.write_deflated(connection, part_addr, data, window, progress)
+            } else {
+                self.write_blocks(connection, part_addr, data, false, progress)
+            };
+            let Err((e, acknowledged)) = result else {
+                break;
+            };
 
-        for (i, block) in chunks.enumerate() {
-            decoder.write_all(block)?;
-            decoder.flush()?;
-            let size = decoder.get_ref().len() - decoded_size;
-            decoded_size = decoder.get_ref().len();
+            if deflate && !self.deflate {
+                warn!(
+                    "The ROM loader does not accept compressed data ({e}), writing it uncompressed"
+                );
+            } else if window > 1 {
+                // Send one block at a time from now on
+                warn!("Writing with {window} blocks in flight failed ({e}), retrying one block at a time");
+                window = 1;
+                connection.set_data_window(1);
+            } else if retries < connection.write_retries() && is_transient(&e) {
+                retries += 1;
+                warn!(
+                    "Writing flash at 0x{part_addr:x} failed ({e}), retrying ({retries}/{})",
+                    connection.write_retries()
+                );
+            } else {
+                return Err(e);
+            }
 
-            connection.with_timeout(
-                CommandType::FlashDeflData.timeout_for_size(size as u32),
-                |connection| {
-                    connection.command(Command::FlashDeflData {
-                        sequence: i as u32,
-                        pad_to: 0,
-                        pad_byte: 0xff,
-                        data: block,
-                    })?;
-                    Ok(())
-                },
-            )?;
+            // A device which was reconnected needs flash to be set up again
+            if connection.resync()? {
+                self.begin(connection)?;
+            }
 
-            if let Some(cb) = progress.as_mut() {
-                cb.update(i + 1)
+            // Resume after the sectors the device acknowledged, as long as they
+            // really made it to flash
+            let resume = acknowledged / FLASH_SECTOR_SIZE * FLASH_SECTOR_SIZE;
+            if resume > 0
+                && digest.digest(&data[..resume])
+                    == digest.flash_digest(
+                        &mut FlashReader::new(connection, self.chip, self.use_stub),
+                        part_addr,
+                        resume as u32,
+                    )?
+            {
+                written += resume;
             }
         }
 
@@ -230,20 +399,15 @@ impl FlashTarget for Esp32Target {
             cb.finish()
         }
 
-        if self.verify {
-            let flash_checksum_md5: u128 =
-                connection.with_timeout(CommandType::FlashMd5.timeout(), |connection| {
-                    connection
-                        .command(crate::command::Command::FlashMd5 {
-                            offset: addr,
-                            size: segment.data.len() as u32,
-                        })?
-                        .try_into()
-                })?;
-
-            if checksum_md5.as_slice() != flash_checksum_md5.to_be_bytes() {
-                return Err(Error::VerifyFailed);
-            }
+        if self.verify
+            && checksum
+                != digest.flash_digest(
+                    &mut FlashReader::new(connection, self.chip, self.use_stub),
+                    addr,
+                    size,
+                )?
+        {
+            return Err(Error::VerifyFailed);
         }
 
         Ok(())
@@ -263,3 +427,138 @@ impl FlashTarget for Esp32Target {
         Ok(())
     }
 }
+
+#[cfg(feature = "serialport")]
+impl Esp32Target {
+    /// Write a segment which the device encrypts as it is written
+    ///
+    /// The stub takes the encrypted writes with a dedicated command, while the
+    /// ROM loader is told to encrypt the data when the write begins. Neither
+    /// accepts compressed data. The contents of the flash can not be compared
+    /// with the segment afterwards, so skipping and verifying are not possible.
+    fn write_encrypted_segment(
+        &mut self,
+        connection: &mut Connection,
+        segment: RomSegment,
+        progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        if !self.use_stub && self.chip == Chip::Esp32 {
+            return Err(Error::UnsupportedFeature {
+                chip: self.chip,
+                feature: "encrypted writes with the ROM loader, use the flasher stub".into(),
+            });
+        }
+        if self.skip || self.verify {
+            warn!("Skipping unchanged segments and verifying them is not possible when the device encrypts the data");
+        }
+
+        self.write_blocks(connection, segment.addr, &segment.data, true, progress)
+            .map_err(|(e, _)| e)?;
+
+        if let Some(cb) = progress.as_mut() {
+            cb.finish()
+        }
+
+        Ok(())
+    }
+
+    /// Write `data` uncompressed to flash at `addr`, which the device encrypts
+    /// if `encrypted` is set, returning the error along with the number of
+    /// bytes of `data` the device acknowledged if it fails
+    fn write_blocks(
+        &self,
+        connection: &mut Connection,
+        addr: u32,
+        data: &[u8],
+        encrypted: bool,
+        progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), (Error, usize)> {
+        let target = self.chip.into_target();
+        let flash_write_size = target.flash_write_size(connection).map_err(|e| (e, 0))?;
+        let chunks = data.chunks(flash_write_size);
+        let num_chunks = chunks.len();
+        let erase_size = data.len().next_multiple_of(FLASH_SECTOR_SIZE) as u32;
+
+        connection
+            .with_timeout(
+                CommandType::FlashBegin.timeout_for_size(erase_size),
+                |connection| {
+                    connection.command(Command::FlashBegin {
+                        size: data.len() as u32,
+                        blocks: num_chunks as u32,
+                        block_size: flash_write_size as u32,
+                        offset: addr,
+                        supports_encryption: self.chip != Chip::Esp32 && !self.use_stub,
+                        encrypted: encrypted && !self.use_stub,
+                    })
+                },
+            )
+            .map_err(|e| (e, 0))?;
+
+        if let Some(cb) = progress.as_mut() {
+            cb.init(addr, num_chunks)
+        }
+
+        let timeout = CommandType::FlashData.timeout_for_size(flash_write_size as u32);
+        for (i, block) in chunks.enumerate() {
+            let sequence = i as u32;
+            let command = if encrypted && self.use_stub {
+                Command::FlashEncryptedData {
+                    data: block,
+                    pad_to: flash_write_size,
+                    pad_byte: 0xff,
+                    sequence,
+                }
+            } else {
+                Command::FlashData {
+                    data: block,
+                    pad_to: flash_write_size,
+                    pad_byte: 0xff,
+                    sequence,
+                }
+            };
+            connection
+                .with_timeout(timeout, |connection| connection.command(command))
+                .map_err(|e| (e, (i * flash_write_size).min(data.len())))?;
+
+            if let Some(cb) = progress.as_mut() {
+                cb.update(i + 1)
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writer which discards the data written to it, only counting its length
+#[derive(Default)]
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "serialport"))]
+mod tests {
+    use super::*;
+    use crate::{command::CommandType, error::RomError};
+
+    #[test]
+    fn only_invalid_message_errors_reject_commands() {
+        let invalid = RomError::new(CommandType::FlashDeflBegin, RomErrorKind::InvalidMessage);
+        assert!(rejects_command(&Error::RomError(invalid)));
+
+        let failed = RomError::new(CommandType::FlashDeflBegin, RomErrorKind::FailedToAct);
+        assert!(!rejects_command(&Error::RomError(failed)));
+
+        let timeout = ConnectionError::Timeout(CommandType::FlashDeflBegin.into());
+        assert!(!rejects_command(&Error::Connection(timeout)));
+    }
+}
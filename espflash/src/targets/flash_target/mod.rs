@@ -1,3 +1,5 @@
+use std::time::{Duration, Instant};
+
 pub(crate) use self::ram::MAX_RAM_BLOCK_SIZE;
 pub use self::{esp32::Esp32Target, ram::RamTarget};
 use crate::{connection::Connection, elf::RomSegment, error::Error};
@@ -6,6 +8,16 @@ mod esp32;
 mod ram;
 
 /// Progress update callbacks
+///
+/// Progress is reported in blocks, or other units of work, with [init],
+/// [update] and [finish]. Writes to flash and RAM are additionally reported in
+/// bytes, along with their [TransferRate]. All but the block-level callbacks
+/// have default implementations, so that implementations only interested in
+/// some of the reports need not change.
+///
+/// [init]: Self::init
+/// [update]: Self::update
+/// [finish]: Self::finish
 pub trait ProgressCallbacks {
     /// Initialize some progress report
     fn init(&mut self, addr: u32, total: usize);
@@ -13,6 +25,206 @@ pub trait ProgressCallbacks {
     fn update(&mut self, current: usize);
     /// Finish some progress report
     fn finish(&mut self);
+    /// Report the size of the data sent for the current segment, called after
+    /// [init](Self::init) when the data is compressed
+    ///
+    /// The default implementation ignores the size.
+    fn compressed_size(&mut self, _size: usize) {}
+    /// Report the transfer rate, called after each update once it is known
+    ///
+    /// The default implementation ignores the transfer rate.
+    fn rate(&mut self, _rate: TransferRate) {}
+    /// Report the progress of the segment being written in bytes, called after
+    /// each update
+    ///
+    /// The default implementation ignores the progress.
+    fn bytes(&mut self, _progress: SegmentProgress) {}
+    /// Report the size and duration of a segment once it is written, called
+    /// before [finish](Self::finish)
+    ///
+    /// The default implementation ignores the segment.
+    fn segment_finished(&mut self, _progress: SegmentProgress) {}
+    /// Whether a long-running operation should stop, checked at the points
+    /// where it can be stopped safely
+    ///
+    /// The default implementation never stops the operation.
+    fn cancelled(&mut self) -> bool {
+        false
+    }
+}
+
+/// Throughput and estimated time remaining of an ongoing write
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct TransferRate {
+    /// Throughput of the current segment, in bytes per second
+    pub segment_bytes_per_sec: f64,
+    /// Estimated time until the current segment is written
+    pub segment_eta: Duration,
+    /// Throughput of all segments written so far, in bytes per second
+    pub overall_bytes_per_sec: f64,
+    /// Estimated time until all segments are written
+    pub overall_eta: Duration,
+}
+
+/// Progress of writing a segment, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SegmentProgress {
+    /// Address of the segment
+    pub addr: u32,
+    /// Size of the segment
+    pub size: usize,
+    /// Bytes of the segment written so far
+    pub written: usize,
+    /// Size of the data sent for the segment after compression, if it is
+    /// compressed; when a failed write was resumed, only that of the data
+    /// sent since
+    pub compressed_size: Option<usize>,
+    /// Time spent writing the segment so far
+    pub elapsed: Duration,
+}
+
+/// Wraps some [ProgressCallbacks] to additionally report the progress in bytes
+/// and the [TransferRate]
+///
+/// The flash targets only report progress in blocks, which this converts to
+/// bytes using the sizes of the segments being written. Segments are expected
+/// to be written in order, however some of them may be skipped, and a failed
+/// write may be resumed part way through a segment.
+pub(crate) struct RateTracker<'a> {
+    inner: &'a mut dyn ProgressCallbacks,
+    /// Address and size of each segment to be written
+    segments: Vec<(u32, usize)>,
+    current: Option<Current>,
+    segment_start: Instant,
+    start: Option<Instant>,
+    /// Bytes written in the already finished segments
+    written: usize,
+}
+
+/// The segment currently being written
+#[derive(Debug, Clone, Copy)]
+struct Current {
+    index: usize,
+    /// Bytes of the segment written before the current part, when resumed
+    offset: usize,
+    /// Number of blocks of the current part
+    blocks: usize,
+    compressed_size: Option<usize>,
+}
+
+impl<'a> RateTracker<'a> {
+    pub fn new(inner: &'a mut dyn ProgressCallbacks, segments: Vec<(u32, usize)>) -> Self {
+        Self {
+            inner,
+            segments,
+            current: None,
+            segment_start: Instant::now(),
+            start: None,
+            written: 0,
+        }
+    }
+
+    fn progress(&self, current: &Current, written: usize) -> SegmentProgress {
+        let (addr, size) = self.segments[current.index];
+
+        SegmentProgress {
+            addr,
+            size,
+            written,
+            compressed_size: current.compressed_size,
+            elapsed: self.segment_start.elapsed(),
+        }
+    }
+}
+
+impl ProgressCallbacks for RateTracker<'_> {
+    fn init(&mut self, addr: u32, total: usize) {
+        // A resumed write begins within the segment
+        let index = self.segments.iter().position(|&(segment_addr, size)| {
+            addr == segment_addr || (segment_addr..segment_addr + size as u32).contains(&addr)
+        });
+        let resumed = index.is_some() && index == self.current.map(|current| current.index);
+
+        self.current = index.map(|index| Current {
+            index,
+            offset: (addr - self.segments[index].0) as usize,
+            blocks: total,
+            compressed_size: None,
+        });
+        if !resumed {
+            self.segment_start = Instant::now();
+        }
+        self.start.get_or_insert(self.segment_start);
+
+        self.inner.init(addr, total);
+    }
+
+    fn compressed_size(&mut self, size: usize) {
+        if let Some(current) = self.current.as_mut() {
+            current.compressed_size = Some(size);
+        }
+
+        self.inner.compressed_size(size);
+    }
+
+    fn update(&mut self, blocks_written: usize) {
+        self.inner.update(blocks_written);
+
+        let (Some(current), Some(start)) = (self.current, self.start) else {
+            return;
+        };
+        if current.blocks == 0 {
+            return;
+        }
+
+        let size = self.segments[current.index].1;
+        let part_size = size - current.offset;
+        let segment_written =
+            current.offset + part_size * blocks_written.min(current.blocks) / current.blocks;
+        let segment_elapsed = self.segment_start.elapsed().as_secs_f64();
+        let overall_written = self.written + segment_written;
+        let overall_elapsed = start.elapsed().as_secs_f64();
+
+        self.inner.bytes(self.progress(&current, segment_written));
+
+        if segment_written == 0 || segment_elapsed == 0.0 || overall_elapsed == 0.0 {
+            return;
+        }
+
+        let segment_rate = segment_written as f64 / segment_elapsed;
+        let overall_rate = overall_written as f64 / overall_elapsed;
+
+        let segment_remaining = size - segment_written;
+        let later_segments: usize = self.segments[current.index + 1..]
+            .iter()
+            .map(|(_, s)| s)
+            .sum();
+
+        self.inner.rate(TransferRate {
+            segment_bytes_per_sec: segment_rate,
+            segment_eta: Duration::from_secs_f64(segment_remaining as f64 / segment_rate),
+            overall_bytes_per_sec: overall_rate,
+            overall_eta: Duration::from_secs_f64(
+                (segment_remaining + later_segments) as f64 / overall_rate,
+            ),
+        });
+    }
+
+    fn finish(&mut self) {
+        if let Some(current) = self.current.take() {
+            let size = self.segments[current.index].1;
+            self.written += size;
+            self.inner.segment_finished(self.progress(&current, size));
+        }
+
+        self.inner.finish();
+    }
+
+    fn cancelled(&mut self) -> bool {
+        self.inner.cancelled()
+    }
 }
 
 /// Operations for interacting with a flash target
@@ -31,3 +243,85 @@ pub trait FlashTarget {
     /// Complete the flashing operation
     fn finish(&mut self, connection: &mut Connection, reboot: bool) -> Result<(), Error>;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{thread::sleep, time::Duration};
+
+    use super::{ProgressCallbacks, RateTracker, SegmentProgress, TransferRate};
+
+    #[derive(Default)]
+    struct Recorder {
+        rates: Vec<TransferRate>,
+        bytes: Vec<usize>,
+        finished: Vec<SegmentProgress>,
+    }
+
+    impl ProgressCallbacks for Recorder {
+        fn init(&mut self, _addr: u32, _total: usize) {}
+        fn update(&mut self, _current: usize) {}
+        fn finish(&mut self) {}
+        fn rate(&mut self, rate: TransferRate) {
+            self.rates.push(rate);
+        }
+        fn bytes(&mut self, progress: SegmentProgress) {
+            self.bytes.push(progress.written);
+        }
+        fn segment_finished(&mut self, progress: SegmentProgress) {
+            self.finished.push(progress);
+        }
+    }
+
+    #[test]
+    fn rate_tracker_handles_skipped_segments() {
+        let mut recorder = Recorder::default();
+        let mut tracker = RateTracker::new(
+            &mut recorder,
+            vec![(0x0, 1000), (0x1000, 2000), (0x2000, 0)],
+        );
+
+        // The first segment is skipped, so only the second one is written
+        tracker.init(0x1000, 4);
+        sleep(Duration::from_millis(5));
+        tracker.update(2);
+        tracker.update(4);
+        tracker.finish();
+
+        assert_eq!(recorder.rates.len(), 2);
+        let rate = recorder.rates[0];
+        assert!(rate.segment_bytes_per_sec > 0.0);
+        assert_eq!(recorder.rates[1].segment_eta, Duration::ZERO);
+        assert_eq!(recorder.rates[1].overall_eta, Duration::ZERO);
+        assert_eq!(recorder.bytes, [1000, 2000]);
+        assert_eq!(recorder.finished.len(), 1);
+        assert_eq!(recorder.finished[0].addr, 0x1000);
+        assert_eq!(recorder.finished[0].written, 2000);
+    }
+
+    #[test]
+    fn rate_tracker_handles_resumed_writes() {
+        let mut recorder = Recorder::default();
+        let mut tracker = RateTracker::new(&mut recorder, vec![(0x0, 0x4000), (0x4000, 0x1000)]);
+
+        tracker.init(0x0, 4);
+        tracker.compressed_size(0x800);
+        sleep(Duration::from_millis(5));
+        tracker.update(1);
+        // The write failed after the first sector and resumes from there
+        tracker.init(0x1000, 3);
+        tracker.compressed_size(0x600);
+        tracker.update(3);
+        tracker.finish();
+
+        assert_eq!(recorder.bytes, [0x1000, 0x4000]);
+        assert_eq!(recorder.rates.len(), 2);
+        assert_eq!(recorder.rates[1].segment_eta, Duration::ZERO);
+        // The second segment is still to be written
+        assert!(recorder.rates[1].overall_eta > Duration::ZERO);
+
+        let finished = recorder.finished[0];
+        assert_eq!(finished.size, 0x4000);
+        assert_eq!(finished.compressed_size, Some(0x600));
+        assert!(finished.elapsed >= Duration::from_millis(5));
+    }
+}
@@ -4,21 +4,22 @@
 //! possible to write an application to and boot from RAM, where a bootloader is
 //! obviously not required either.
 
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, ops::Range};
 
 use esp_idf_part::{AppType, DataType, Partition, PartitionTable, SubType, Type};
 use serde::{Deserialize, Serialize};
-use strum::{Display, EnumIter, EnumString, VariantNames};
+use strum::{Display, EnumIter, EnumString, IntoEnumIterator, VariantNames};
 
 use crate::{
     elf::FirmwareImage,
     error::Error,
-    flasher::{FlashData, FlashFrequency},
-    image_format::IdfBootloaderFormat,
-    targets::{
-        esp32::Esp32, esp32c2::Esp32c2, esp32c3::Esp32c3, esp32c6::Esp32c6, esp32h2::Esp32h2,
-        esp32p4::Esp32p4, esp32s2::Esp32s2, esp32s3::Esp32s3,
-    },
+    flasher::{FlashData, FlashFrequency, FlashSize},
+    image_format::{DirectBootFormat, ImageFormat, ImageFormatKind},
+};
+
+pub use self::{
+    esp32::Esp32, esp32c2::Esp32c2, esp32c3::Esp32c3, esp32c6::Esp32c6, esp32h2::Esp32h2,
+    esp32p4::Esp32p4, esp32s2::Esp32s2, esp32s3::Esp32s3,
 };
 
 #[cfg(feature = "serialport")]
@@ -27,7 +28,8 @@ pub use self::flash_target::{Esp32Target, RamTarget};
 #[cfg(feature = "serialport")]
 use crate::{
     connection::Connection,
-    flasher::{SpiAttachParams, FLASH_WRITE_SIZE},
+    digest::DigestAlgorithm,
+    flasher::{encryption::FlashEncryption, SpiAttachParams, FLASH_WRITE_SIZE},
     targets::flash_target::{FlashTarget, MAX_RAM_BLOCK_SIZE},
 };
 
@@ -57,12 +59,15 @@ pub(crate) mod flash_target;
 #[repr(u32)]
 pub enum XtalFrequency {
     #[strum(serialize = "26 MHz")]
+    #[serde(rename = "26MHz", alias = "_26Mhz")]
     /// 26 MHz
     _26Mhz,
     #[strum(serialize = "32 MHz")]
+    #[serde(rename = "32MHz", alias = "_32Mhz")]
     /// 32 MHz
     _32Mhz,
     #[strum(serialize = "40 MHz")]
+    #[serde(rename = "40MHz", alias = "_40Mhz")]
     /// 40 MHz
     #[default]
     _40Mhz,
@@ -70,20 +75,14 @@ pub enum XtalFrequency {
 
 impl XtalFrequency {
     pub fn default(chip: Chip) -> Self {
-        match chip {
-            Chip::Esp32 => Self::_40Mhz,
-            Chip::Esp32c2 => Self::_40Mhz,
-            Chip::Esp32c3 => Self::_40Mhz,
-            Chip::Esp32c6 => Self::_40Mhz,
-            Chip::Esp32h2 => Self::_32Mhz,
-            Chip::Esp32p4 => Self::_40Mhz,
-            Chip::Esp32s2 => Self::_40Mhz,
-            Chip::Esp32s3 => Self::_40Mhz,
-        }
+        chip.into_target().default_xtal_frequency()
     }
 }
 
 /// All supported devices
+///
+/// Each variant dispatches to the [Target] implementation of the chip, see
+/// [Chip::into_target].
 #[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Display, EnumIter, EnumString, VariantNames)]
 #[non_exhaustive]
@@ -109,27 +108,12 @@ pub enum Chip {
 
 impl Chip {
     pub fn from_magic(magic: u32) -> Result<Self, Error> {
-        if Esp32::has_magic_value(magic) {
-            Ok(Chip::Esp32)
-        } else if Esp32c2::has_magic_value(magic) {
-            Ok(Chip::Esp32c2)
-        } else if Esp32c3::has_magic_value(magic) {
-            Ok(Chip::Esp32c3)
-        } else if Esp32c6::has_magic_value(magic) {
-            Ok(Chip::Esp32c6)
-        } else if Esp32h2::has_magic_value(magic) {
-            Ok(Chip::Esp32h2)
-        } else if Esp32p4::has_magic_value(magic) {
-            Ok(Chip::Esp32p4)
-        } else if Esp32s2::has_magic_value(magic) {
-            Ok(Chip::Esp32s2)
-        } else if Esp32s3::has_magic_value(magic) {
-            Ok(Chip::Esp32s3)
-        } else {
-            Err(Error::ChipDetectError(magic))
-        }
+        Chip::iter()
+            .find(|chip| chip.into_target().has_magic_value(magic))
+            .ok_or(Error::ChipDetectError(magic))
     }
 
+    /// The implementation of [Target] for the chip
     pub fn into_target(&self) -> Box<dyn Target> {
         match self {
             Chip::Esp32 => Box::new(Esp32),
@@ -143,6 +127,65 @@ impl Chip {
         }
     }
 
+    /// MMU page sizes supported by the chip, for chips where it is
+    /// configurable
+    ///
+    /// Chips which return [None] always use a page size of 64 KB.
+    pub fn valid_mmu_page_sizes(self) -> Option<&'static [u32]> {
+        self.into_target().valid_mmu_page_sizes()
+    }
+
+    /// Constants describing the chip, as used by espflash
+    pub fn metadata(self) -> ChipMetadata {
+        let target = self.into_target();
+        let params = target.params();
+        let spi = target.spi_registers();
+
+        let mut flash_frequencies = target
+            .flash_frequency_encodings()
+            .into_keys()
+            .collect::<Vec<_>>();
+        flash_frequencies.sort_by_key(|frequency| *frequency as u8);
+
+        ChipMetadata {
+            chip: self,
+            chip_id: params.chip_id,
+            efuse_base: target.efuse_reg(),
+            spi_registers: SpiRegisterAddresses {
+                cmd: spi.cmd(),
+                usr: spi.usr(),
+                usr1: spi.usr1(),
+                usr2: spi.usr2(),
+                w0: spi.w0(),
+                mosi_length: spi.mosi_length(),
+                miso_length: spi.miso_length(),
+                clock: spi.clock(),
+            },
+            flash_ranges: target.flash_ranges().to_vec(),
+            boot_addr: params.boot_addr,
+            partition_table_addr: params.partition_addr,
+            app_addr: params.app_addr,
+            app_size: params.app_size,
+            default_flash_frequency: params.flash_freq,
+            flash_frequencies,
+            flash_sizes: FlashSize::iter()
+                .filter(|size| size.encode_flash_size().is_ok())
+                .collect(),
+            default_xtal_frequency: XtalFrequency::default(self),
+            mmu_page_sizes: self.valid_mmu_page_sizes().map(<[u32]>::to_vec),
+            build_targets: target
+                .supported_build_targets()
+                .iter()
+                .map(|target| target.to_string())
+                .collect(),
+            features: target
+                .family_features()
+                .iter()
+                .map(|f| f.to_string())
+                .collect(),
+        }
+    }
+
     #[cfg(feature = "serialport")]
     pub fn flash_target(
         &self,
@@ -150,8 +193,12 @@ impl Chip {
         use_stub: bool,
         verify: bool,
         skip: bool,
+        digest: DigestAlgorithm,
+        encryption: Option<FlashEncryption>,
     ) -> Box<dyn FlashTarget> {
-        Box::new(Esp32Target::new(*self, spi_params, use_stub, verify, skip))
+        Box::new(Esp32Target::new(
+            *self, spi_params, use_stub, verify, skip, digest, encryption,
+        ))
     }
 
     #[cfg(feature = "serialport")]
@@ -241,15 +288,76 @@ impl Esp32Params {
     }
 }
 
+/// Constants describing a chip, see [Chip::metadata]
+#[derive(Debug, Clone, Serialize)]
+#[non_exhaustive]
+pub struct ChipMetadata {
+    #[serde(serialize_with = "serialize_display")]
+    pub chip: Chip,
+    pub chip_id: u16,
+    /// Base address of the eFuse registers
+    pub efuse_base: u32,
+    pub spi_registers: SpiRegisterAddresses,
+    /// Address ranges which are mapped to flash
+    pub flash_ranges: Vec<Range<u32>>,
+    /// Flash offset of the second stage bootloader
+    pub boot_addr: u32,
+    /// Default flash offset of the partition table
+    pub partition_table_addr: u32,
+    /// Flash offset of the application in the default partition table
+    pub app_addr: u32,
+    /// Size of the application partition in the default partition table
+    pub app_size: u32,
+    pub default_flash_frequency: FlashFrequency,
+    /// Flash frequencies the bootloader of the chip can be configured with
+    pub flash_frequencies: Vec<FlashFrequency>,
+    /// Flash sizes the bootloader of the chip can be configured with
+    pub flash_sizes: Vec<FlashSize>,
+    pub default_xtal_frequency: XtalFrequency,
+    /// Configurable MMU page sizes, see [Chip::valid_mmu_page_sizes]
+    pub mmu_page_sizes: Option<Vec<u32>>,
+    pub build_targets: Vec<String>,
+    /// See [Target::family_features]
+    pub features: Vec<String>,
+}
+
+/// Absolute addresses of the SPI registers used by espflash
+#[derive(Debug, Clone, Copy, Serialize)]
+#[non_exhaustive]
+pub struct SpiRegisterAddresses {
+    pub cmd: u32,
+    pub usr: u32,
+    pub usr1: u32,
+    pub usr2: u32,
+    pub w0: u32,
+    pub mosi_length: Option<u32>,
+    pub miso_length: Option<u32>,
+    pub clock: u32,
+}
+
+fn serialize_display<S, T>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+    T: std::fmt::Display,
+{
+    serializer.collect_str(value)
+}
+
 /// SPI register addresses
 pub struct SpiRegisters {
-    base: u32,
-    usr_offset: u32,
-    usr1_offset: u32,
-    usr2_offset: u32,
-    w0_offset: u32,
-    mosi_length_offset: Option<u32>,
-    miso_length_offset: Option<u32>,
+    /// Base address of the SPI peripheral, which is also the command register
+    pub base: u32,
+    pub usr_offset: u32,
+    pub usr1_offset: u32,
+    pub usr2_offset: u32,
+    pub w0_offset: u32,
+    /// Offset of the MOSI length register, on chips which have one
+    pub mosi_length_offset: Option<u32>,
+    /// Offset of the MISO length register, on chips which have one
+    pub miso_length_offset: Option<u32>,
+    pub clock_offset: u32,
+    /// Width of the `CLKCNT_N/H/L` fields of the clock register
+    pub clock_field_width: u32,
 }
 
 impl SpiRegisters {
@@ -280,6 +388,52 @@ impl SpiRegisters {
     pub fn miso_length(&self) -> Option<u32> {
         self.miso_length_offset.map(|offset| self.base + offset)
     }
+
+    pub fn clock(&self) -> u32 {
+        self.base + self.clock_offset
+    }
+
+    /// Largest clock divider which can be encoded in the clock register
+    pub fn max_clock_divider(&self) -> u32 {
+        1 << self.clock_field_width
+    }
+
+    /// Encode a clock divider as a value of the clock register
+    pub fn encode_clock_divider(&self, divider: u32) -> Option<u32> {
+        if divider == 0 || divider > self.max_clock_divider() {
+            return None;
+        }
+
+        if divider == 1 {
+            // Use the system clock directly
+            return Some(1 << 31);
+        }
+
+        let width = self.clock_field_width;
+        let n = divider - 1;
+        let h = (divider / 2) - 1;
+        let l = n;
+
+        Some((n << (2 * width)) | (h << width) | l)
+    }
+
+    /// Decode the clock divider from a value of the clock register
+    pub fn decode_clock_divider(&self, value: u32) -> u32 {
+        if value & (1 << 31) != 0 {
+            return 1;
+        }
+
+        let width = self.clock_field_width;
+        let n = (value >> (2 * width)) & ((1 << width) - 1);
+        // Only the original ESP32 has a pre-divider, above the counter fields
+        let pre = if width == 6 {
+            (value >> (3 * width)) & 0x1fff
+        } else {
+            0
+        };
+
+        (pre + 1) * (n + 1)
+    }
 }
 
 /// Enable the reading of eFuses for a target
@@ -295,15 +449,123 @@ pub trait ReadEFuse {
     }
 }
 
+/// Reset reason of all chips after the supply voltage dropped below the
+/// threshold of the brownout detector
+pub const BROWNOUT_RESET_REASON: u32 = 15;
+
 /// Operations for interacting with supported target devices
-pub trait Target: ReadEFuse {
+///
+/// Every chip has an implementation of this trait in its own module, and
+/// [Chip] dispatches to them with [Chip::into_target].
+///
+/// The trait can also be implemented outside of this crate, e.g. to bring up a
+/// chip before it is added to [Chip], and used to connect to it with
+/// [ConnectOptions::with_target]. The flasher then uses the implementation for
+/// everything the trait covers, while the chip it names with [Target::chip]
+/// selects the flasher stub and the chip-specific behaviour outside of the
+/// trait.
+///
+/// [ConnectOptions::with_target]: crate::flasher::ConnectOptions::with_target
+pub trait Target: ReadEFuse + Send + Sync {
+    /// The chip this is the implementation for, or the supported chip an
+    /// implementation outside of this crate is derived from
+    fn chip(&self) -> Chip;
+
+    /// Values of the chip detection magic register which identify the chip
+    fn chip_detect_magic_values(&self) -> &[u32];
+
+    /// Family ID identifying the chip in UF2 files
+    ///
+    /// Implementations outside of this crate use the ID of [Target::chip].
+    fn uf2_family_id(&self) -> u32 {
+        self.chip().into_target().uf2_family_id()
+    }
+
+    /// Peripherals which every chip of the family has, as opposed to the
+    /// features read from the eFuses of a device
+    ///
+    /// Implementations outside of this crate use the peripherals of
+    /// [Target::chip].
+    fn family_features(&self) -> &'static [&'static str] {
+        self.chip().into_target().family_features()
+    }
+
+    /// Does the chip detection magic register value `value` identify the
+    /// chip?
+    fn has_magic_value(&self, value: u32) -> bool {
+        self.chip_detect_magic_values().contains(&value)
+    }
+
+    /// Address ranges which are mapped to flash
+    fn flash_ranges(&self) -> &[Range<u32>];
+
     /// Is the provided address `addr` in flash?
-    fn addr_is_flash(&self, addr: u32) -> bool;
+    fn addr_is_flash(&self, addr: u32) -> bool {
+        self.flash_ranges()
+            .iter()
+            .any(|range| range.contains(&addr))
+    }
+
+    /// Default device-specific parameters
+    fn params(&self) -> Esp32Params;
+
+    /// Crystal frequency assumed when it is not given or read from the device
+    fn default_xtal_frequency(&self) -> XtalFrequency {
+        XtalFrequency::_40Mhz
+    }
+
+    /// MMU page sizes supported by the chip, for chips where it is
+    /// configurable
+    fn valid_mmu_page_sizes(&self) -> Option<&'static [u32]> {
+        None
+    }
 
     #[cfg(feature = "serialport")]
     /// Enumerate the chip's features, read from eFuse
     fn chip_features(&self, connection: &mut Connection) -> Result<Vec<&str>, Error>;
 
+    #[cfg(feature = "serialport")]
+    /// Is the flash embedded in the chip package?
+    ///
+    /// Returns `None` when the package cannot be determined for this chip.
+    fn embedded_flash(&self, _connection: &mut Connection) -> Result<Option<bool>, Error> {
+        Ok(None)
+    }
+
+    /// Address of the register holding the reason of the last reset, and the
+    /// mask of the reason in it
+    ///
+    /// Returns `None` when the reset reason cannot be read on this chip.
+    fn reset_reason_reg(&self) -> Option<(u32, u32)> {
+        None
+    }
+
+    #[cfg(feature = "serialport")]
+    /// Reason of the last reset of the chip, e.g. [BROWNOUT_RESET_REASON]
+    fn reset_reason(&self, connection: &mut Connection) -> Result<Option<u32>, Error> {
+        self.reset_reason_reg()
+            .map(|(reg, mask)| Ok(connection.read_reg(reg)? & mask))
+            .transpose()
+    }
+
+    /// Address of the register holding the output of the brownout detector,
+    /// and the mask of the bit which is set while the supply voltage is below
+    /// its threshold
+    ///
+    /// Returns `None` when the brownout detector cannot be read on this chip.
+    fn brownout_status_reg(&self) -> Option<(u32, u32)> {
+        None
+    }
+
+    #[cfg(feature = "serialport")]
+    /// Is the supply voltage currently below the threshold of the brownout
+    /// detector?
+    fn brownout_detected(&self, connection: &mut Connection) -> Result<Option<bool>, Error> {
+        self.brownout_status_reg()
+            .map(|(reg, mask)| Ok(connection.read_reg(reg)? & mask != 0))
+            .transpose()
+    }
+
     #[cfg(feature = "serialport")]
     /// Determine the chip's revision number
     fn chip_revision(&self, connection: &mut Connection) -> Result<(u32, u32), Error> {
@@ -338,14 +600,44 @@ pub trait Target: ReadEFuse {
         Ok(FLASH_WRITE_SIZE)
     }
 
-    /// Build an image from the provided data for flashing
+    /// Can the ROM of the chip boot an image without a bootloader, see
+    /// [ImageFormatKind::DirectBoot]?
+    fn supports_direct_boot(&self) -> bool {
+        false
+    }
+
+    /// Build an image from the provided data for flashing, in the format
+    /// selected by `flash_data`
     fn get_flash_image<'a>(
         &self,
         image: &'a dyn FirmwareImage<'a>,
         flash_data: FlashData,
         chip_revision: Option<(u32, u32)>,
         xtal_freq: XtalFrequency,
-    ) -> Result<IdfBootloaderFormat<'a>, Error>;
+    ) -> Result<ImageFormat<'a>, Error> {
+        match flash_data.image_format {
+            ImageFormatKind::EspIdf => {
+                self.get_esp_idf_image(image, flash_data, chip_revision, xtal_freq)
+            }
+            ImageFormatKind::DirectBoot if self.supports_direct_boot() => {
+                DirectBootFormat::new(image, self.chip()).map(ImageFormat::DirectBoot)
+            }
+            ImageFormatKind::DirectBoot => Err(Error::UnsupportedFeature {
+                chip: self.chip(),
+                feature: "the direct boot image format".into(),
+            }),
+        }
+    }
+
+    /// Build an image booted by the ESP-IDF bootloader from the provided data
+    /// for flashing
+    fn get_esp_idf_image<'a>(
+        &self,
+        image: &'a dyn FirmwareImage<'a>,
+        flash_data: FlashData,
+        chip_revision: Option<(u32, u32)>,
+        xtal_freq: XtalFrequency,
+    ) -> Result<ImageFormat<'a>, Error>;
 
     #[cfg(feature = "serialport")]
     /// What is the MAC address?
@@ -378,6 +670,12 @@ pub trait Target: ReadEFuse {
     }
 }
 
+impl fmt::Debug for dyn Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Target").field(&self.chip()).finish()
+    }
+}
+
 #[cfg(feature = "serialport")]
 fn bytes_to_mac_addr(bytes: &[u8]) -> String {
     bytes
@@ -386,3 +684,67 @@ fn bytes_to_mac_addr(bytes: &[u8]) -> String {
         .collect::<Vec<_>>()
         .join(":")
 }
+
+#[cfg(test)]
+mod tests {
+    use serde::{de::value::StrDeserializer, Deserialize};
+    use strum::IntoEnumIterator;
+
+    use super::{Chip, SpiRegisters, XtalFrequency};
+
+    fn spi_registers(chip: Chip) -> SpiRegisters {
+        chip.into_target().spi_registers()
+    }
+
+    #[test]
+    fn spi_clock_divider_round_trip() {
+        for chip in [Chip::Esp32, Chip::Esp32c3] {
+            let registers = spi_registers(chip);
+            for divider in [1, 2, 3, 4, 16, registers.max_clock_divider()] {
+                let value = registers.encode_clock_divider(divider).unwrap();
+                assert_eq!(registers.decode_clock_divider(value), divider);
+            }
+
+            assert!(registers.encode_clock_divider(0).is_none());
+            assert!(registers
+                .encode_clock_divider(registers.max_clock_divider() + 1)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn metadata_is_consistent() {
+        for chip in Chip::iter() {
+            let metadata = chip.metadata();
+            let target = chip.into_target();
+
+            assert_eq!(metadata.chip, chip);
+            assert_eq!(target.chip(), chip);
+            for magic in target.chip_detect_magic_values() {
+                assert_eq!(Chip::from_magic(*magic).unwrap(), chip);
+            }
+            assert!(metadata.boot_addr < metadata.partition_table_addr);
+            assert!(metadata.partition_table_addr < metadata.app_addr);
+            assert!(metadata
+                .flash_frequencies
+                .contains(&metadata.default_flash_frequency));
+            assert!(!metadata.features.is_empty());
+            for range in &metadata.flash_ranges {
+                assert!(target.addr_is_flash(range.start));
+                assert!(target.addr_is_flash(range.end - 1));
+            }
+        }
+    }
+
+    #[test]
+    fn xtal_frequencies_accept_the_old_spellings() {
+        let parse = |s: &str| {
+            XtalFrequency::deserialize(StrDeserializer::<serde::de::value::Error>::new(s)).unwrap()
+        };
+
+        assert_eq!(parse("26MHz"), XtalFrequency::_26Mhz);
+        assert_eq!(parse("_26Mhz"), XtalFrequency::_26Mhz);
+        assert_eq!(parse("40MHz"), XtalFrequency::_40Mhz);
+        assert_eq!(parse("_40Mhz"), XtalFrequency::_40Mhz);
+    }
+}
@@ -0,0 +1,138 @@
+//! JUnit XML report of the test results
+
+use std::{fmt::Write, time::Duration};
+
+/// Result of a scenario run against a device
+#[derive(Debug, Clone)]
+pub struct TestCase {
+    pub name: String,
+    pub time: Duration,
+    /// Output of the commands run by the scenario
+    pub output: String,
+    /// Reason the scenario failed, if it did
+    pub failure: Option<String>,
+}
+
+/// Results of the scenarios run against a device
+#[derive(Debug, Clone)]
+pub struct TestSuite {
+    pub name: String,
+    pub cases: Vec<TestCase>,
+}
+
+impl TestSuite {
+    pub fn failures(&self) -> usize {
+        self.cases.iter().filter(|c| c.failure.is_some()).count()
+    }
+
+    fn time(&self) -> Duration {
+        self.cases.iter().map(|c| c.time).sum()
+    }
+}
+
+/// Render the results as JUnit XML
+pub fn render(suites: &[TestSuite]) -> String {
+    let tests: usize = suites.iter().map(|s| s.cases.len()).sum();
+    let failures: usize = suites.iter().map(TestSuite::failures).sum();
+    let time: Duration = suites.iter().map(TestSuite::time).sum();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(
+        xml,
+        "<testsuites name=\"espflash\" tests=\"{tests}\" failures=\"{failures}\" time=\"{:.3}\">",
+        time.as_secs_f64()
+    )
+    .unwrap();
+
+    for suite in suites {
+        writeln!(
+            xml,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">",
+            escape(&suite.name),
+            suite.cases.len(),
+            suite.failures(),
+            suite.time().as_secs_f64()
+        )
+        .unwrap();
+
+        for case in &suite.cases {
+            writeln!(
+                xml,
+                "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\">",
+                escape(&case.name),
+                escape(&suite.name),
+                case.time.as_secs_f64()
+            )
+            .unwrap();
+            if let Some(failure) = &case.failure {
+                writeln!(xml, "      <failure message=\"{}\"/>", escape(failure)).unwrap();
+            }
+            writeln!(
+                xml,
+                "      <system-out>{}</system-out>",
+                escape(&case.output)
+            )
+            .unwrap();
+            xml.push_str("    </testcase>\n");
+        }
+
+        xml.push_str("  </testsuite>\n");
+    }
+
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+/// Escape text for an attribute or the content of an element, dropping the
+/// control characters XML 1.0 cannot represent
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_report() {
+        let suites = [TestSuite {
+            name: "esp32c3".into(),
+            cases: vec![
+                TestCase {
+                    name: "board-info".into(),
+                    time: Duration::from_millis(1500),
+                    output: "Chip type: esp32c3".into(),
+                    failure: None,
+                },
+                TestCase {
+                    name: "flash".into(),
+                    time: Duration::from_millis(500),
+                    output: "\x1b[31mError\x1b[0m <port>".into(),
+                    failure: Some("expected \"Flashing has completed!\"".into()),
+                },
+            ],
+        }];
+
+        let xml = render(&suites);
+
+        assert!(xml
+            .contains("<testsuites name=\"espflash\" tests=\"2\" failures=\"1\" time=\"2.000\">"));
+        assert!(xml.contains("<testsuite name=\"esp32c3\" tests=\"2\" failures=\"1\""));
+        assert!(xml.contains("<testcase name=\"board-info\" classname=\"esp32c3\" time=\"1.500\">"));
+        assert!(xml.contains("<failure message=\"expected &quot;Flashing has completed!&quot;\"/>"));
+        assert!(xml.contains("<system-out>[31mError[0m &lt;port&gt;</system-out>"));
+    }
+}
@@ -0,0 +1,150 @@
+//! Development tasks of the espflash workspace, run with `cargo xtask`
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{self, Command, ExitCode},
+    thread,
+};
+
+use clap::{Args, Parser};
+
+use crate::{matrix::Matrix, scenario::Runner};
+
+mod junit;
+mod matrix;
+mod scenario;
+
+#[derive(Debug, Parser)]
+enum Cli {
+    /// Run the hardware-in-the-loop tests against the attached devices
+    ///
+    /// The devices are described by a TOML matrix, see `xtask/hil.toml`. The
+    /// scenarios of each device run one after the other, different devices
+    /// are tested in parallel.
+    RunTests(RunTestsArgs),
+}
+
+#[derive(Debug, Args)]
+struct RunTestsArgs {
+    /// Hardware matrix, relative to the workspace root
+    #[arg(long, default_value = "xtask/hil.toml")]
+    matrix: PathBuf,
+    /// Only test the devices with these names
+    #[arg(long = "device", value_name = "NAME")]
+    devices: Vec<String>,
+    /// Use this espflash binary instead of building it
+    #[arg(long, value_name = "FILE")]
+    espflash: Option<PathBuf>,
+    /// Write the results as JUnit XML to this file
+    #[arg(long, value_name = "FILE")]
+    junit: Option<PathBuf>,
+}
+
+fn main() -> ExitCode {
+    let result = match Cli::parse() {
+        Cli::RunTests(args) => run_tests(args),
+    };
+
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// The workspace root, the current directory when xtask is not run by cargo,
+/// e.g. on a test runner
+fn workspace_root() -> Result<PathBuf, String> {
+    match std::env::var_os("CARGO_MANIFEST_DIR") {
+        Some(dir) => Ok(Path::new(&dir).parent().unwrap().to_path_buf()),
+        None => std::env::current_dir().map_err(|e| format!("no current directory: {e}")),
+    }
+}
+
+fn run_tests(args: RunTestsArgs) -> Result<ExitCode, String> {
+    let root = workspace_root()?;
+    let mut matrix = Matrix::load(&root.join(&args.matrix))?;
+    matrix.select(&args.devices)?;
+
+    let espflash = match args.espflash {
+        Some(espflash) => espflash,
+        None => build_espflash(&root, &matrix.features)?,
+    };
+
+    let work_dir = root.join("target").join("hil");
+    let runner = Runner {
+        espflash: &espflash,
+        root: &root,
+        work_dir: &work_dir,
+    };
+
+    let suites = thread::scope(|s| {
+        let handles = matrix
+            .devices
+            .iter()
+            .map(|device| s.spawn(|| runner.run(device)))
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    for suite in &suites {
+        for case in &suite.cases {
+            if let Some(failure) = &case.failure {
+                eprintln!(
+                    "\n--- {}: {} failed: {failure}\n{}",
+                    suite.name, case.name, case.output
+                );
+            }
+        }
+    }
+
+    if let Some(path) = args.junit {
+        fs::write(&path, junit::render(&suites))
+            .map_err(|e| format!("failed to write {}: {e}", path.display()))?;
+    }
+
+    let failures: usize = suites.iter().map(junit::TestSuite::failures).sum();
+    let tests: usize = suites.iter().map(|s| s.cases.len()).sum();
+    println!("\n{} passed, {failures} failed", tests - failures);
+
+    Ok(if failures == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn build_espflash(root: &Path, features: &[String]) -> Result<PathBuf, String> {
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let mut command = Command::new(cargo);
+    command.current_dir(root).args([
+        "build",
+        "--release",
+        "--package",
+        "espflash",
+        "--bin",
+        "espflash",
+    ]);
+    if !features.is_empty() {
+        command.args(["--features", &features.join(",")]);
+    }
+
+    let status = command
+        .status()
+        .map_err(|e| format!("failed to build espflash: {e}"))?;
+    if !status.success() {
+        process::exit(status.code().unwrap_or(1));
+    }
+
+    Ok(root
+        .join("target")
+        .join("release")
+        .join(format!("espflash{}", std::env::consts::EXE_SUFFIX)))
+}
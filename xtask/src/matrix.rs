@@ -0,0 +1,185 @@
+//! The hardware matrix, describing the devices attached to a test runner
+
+use std::{fmt, fs, path::Path};
+
+use serde::Deserialize;
+
+/// A scenario, run against a device with the espflash binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scenario {
+    /// `board-info` reports the chip
+    BoardInfo,
+    /// `flash` writes the application
+    Flash,
+    /// `monitor` shows the output of the application
+    Monitor,
+    /// `erase-flash` empties the flash, checked with `read-flash`
+    EraseRead,
+    /// `save-image --merge` output is written with `write-bin` and monitored
+    SaveImage,
+}
+
+impl Scenario {
+    /// All scenarios, in the order they are run
+    pub const ALL: [Scenario; 5] = [
+        Scenario::BoardInfo,
+        Scenario::Flash,
+        Scenario::Monitor,
+        Scenario::EraseRead,
+        Scenario::SaveImage,
+    ];
+}
+
+impl fmt::Display for Scenario {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Scenario::BoardInfo => "board-info",
+            Scenario::Flash => "flash",
+            Scenario::Monitor => "monitor",
+            Scenario::EraseRead => "erase-read",
+            Scenario::SaveImage => "save-image",
+        })
+    }
+}
+
+/// A device attached to the test runner
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Device {
+    /// Name of the device, used to select it and in the test report
+    pub name: String,
+    /// Serial port the device is connected to
+    pub port: String,
+    /// Chip of the device, as accepted by `--chip`
+    pub chip: String,
+    /// ELF file of the application printing `Hello world!`, relative to the
+    /// workspace root
+    pub app: String,
+    /// Extra arguments for `save-image`, e.g. the crystal frequency
+    #[serde(default)]
+    pub image_args: Vec<String>,
+    /// Scenarios to run, all of them when empty
+    #[serde(default)]
+    pub scenarios: Vec<Scenario>,
+}
+
+impl Device {
+    /// Scenarios to run against the device
+    pub fn scenarios(&self) -> &[Scenario] {
+        if self.scenarios.is_empty() {
+            &Scenario::ALL
+        } else {
+            &self.scenarios
+        }
+    }
+}
+
+/// The hardware matrix
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Matrix {
+    /// Features to build espflash with
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Devices attached to the test runner
+    #[serde(default, rename = "device")]
+    pub devices: Vec<Device>,
+}
+
+impl Matrix {
+    /// Load the matrix from a TOML file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+
+        Self::parse(&text).map_err(|e| format!("failed to parse {}: {e}", path.display()))
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let matrix: Matrix = toml::from_str(text).map_err(|e| e.to_string())?;
+
+        for (i, device) in matrix.devices.iter().enumerate() {
+            if matrix.devices[..i].iter().any(|d| d.name == device.name) {
+                return Err(format!("device `{}` is listed twice", device.name));
+            }
+        }
+
+        Ok(matrix)
+    }
+
+    /// Keep only the devices with one of the given names, all of them when
+    /// no name is given
+    pub fn select(&mut self, names: &[String]) -> Result<(), String> {
+        if let Some(name) = names
+            .iter()
+            .find(|name| !self.devices.iter().any(|d| &d.name == *name))
+        {
+            return Err(format!("no device `{name}` in the matrix"));
+        }
+
+        if !names.is_empty() {
+            self.devices.retain(|d| names.contains(&d.name));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MATRIX: &str = r#"
+        features = ["libudev"]
+
+        [[device]]
+        name = "esp32c2-26mhz"
+        port = "/dev/serial_ports/esp32c2"
+        chip = "esp32c2"
+        app = "espflash/resources/apps/esp32c2"
+        image_args = ["-x", "26mhz"]
+
+        [[device]]
+        name = "esp32c3"
+        port = "/dev/serial_ports/esp32c3"
+        chip = "esp32c3"
+        app = "espflash/resources/apps/esp32c3"
+        scenarios = ["board-info", "erase-read"]
+    "#;
+
+    #[test]
+    fn parse_matrix() {
+        let matrix = Matrix::parse(MATRIX).unwrap();
+
+        assert_eq!(matrix.features, ["libudev"]);
+        assert_eq!(matrix.devices.len(), 2);
+        assert_eq!(matrix.devices[0].image_args, ["-x", "26mhz"]);
+        assert_eq!(matrix.devices[0].scenarios(), Scenario::ALL);
+        assert_eq!(
+            matrix.devices[1].scenarios(),
+            [Scenario::BoardInfo, Scenario::EraseRead]
+        );
+    }
+
+    #[test]
+    fn reject_invalid_matrices() {
+        let duplicate = format!(
+            "{MATRIX}\n[[device]]\nname = \"esp32c3\"\nport = \"p\"\nchip = \"esp32c3\"\napp = \"a\""
+        );
+        assert!(Matrix::parse(&duplicate).is_err());
+
+        let unknown = MATRIX.replace("erase-read", "erase");
+        assert!(Matrix::parse(&unknown).is_err());
+    }
+
+    #[test]
+    fn select_devices() {
+        let mut matrix = Matrix::parse(MATRIX).unwrap();
+        assert!(matrix.select(&["esp32".into()]).is_err());
+
+        matrix.select(&["esp32c3".into()]).unwrap();
+        assert_eq!(matrix.devices.len(), 1);
+        assert_eq!(matrix.devices[0].name, "esp32c3");
+    }
+}
@@ -0,0 +1,242 @@
+//! Scenarios run against the attached devices with the espflash binary
+
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    junit::{TestCase, TestSuite},
+    matrix::{Device, Scenario},
+};
+
+/// How long the output of the application is monitored
+const MONITOR_TIME: Duration = Duration::from_secs(5);
+
+/// Output printed by the test applications
+const HELLO: &str = "Hello world!";
+
+/// Runs the scenarios against a device
+pub struct Runner<'a> {
+    /// The espflash binary
+    pub espflash: &'a Path,
+    /// The workspace root, applications are relative to it
+    pub root: &'a Path,
+    /// Directory for the files written by the scenarios
+    pub work_dir: &'a Path,
+}
+
+impl Runner<'_> {
+    /// Run the scenarios of a device one after the other, as they share its
+    /// serial port
+    pub fn run(&self, device: &Device) -> TestSuite {
+        let work_dir = self.work_dir.join(&device.name);
+        let cases = device
+            .scenarios()
+            .iter()
+            .map(|&scenario| {
+                let started = Instant::now();
+                let mut output = String::new();
+                let result = fs::create_dir_all(&work_dir)
+                    .map_err(|e| format!("failed to create {}: {e}", work_dir.display()))
+                    .and_then(|_| self.scenario(scenario, device, &work_dir, &mut output));
+
+                let case = TestCase {
+                    name: scenario.to_string(),
+                    time: started.elapsed(),
+                    output,
+                    failure: result.err(),
+                };
+                println!(
+                    "{}: {scenario} {}",
+                    device.name,
+                    if case.failure.is_some() {
+                        "FAILED"
+                    } else {
+                        "ok"
+                    }
+                );
+                case
+            })
+            .collect();
+
+        TestSuite {
+            name: device.name.clone(),
+            cases,
+        }
+    }
+
+    fn scenario(
+        &self,
+        scenario: Scenario,
+        device: &Device,
+        work_dir: &Path,
+        output: &mut String,
+    ) -> Result<(), String> {
+        let app = self.root.join(&device.app);
+
+        match scenario {
+            Scenario::BoardInfo => {
+                self.espflash(device, ["board-info"], output)?;
+                expect(output, &device.chip)
+            }
+            Scenario::Flash => {
+                self.espflash(device, [arg("flash"), app], output)?;
+                expect(output, "Flashing has completed!")
+            }
+            Scenario::Monitor => self.monitor(device, output),
+            Scenario::EraseRead => {
+                self.espflash(device, ["erase-flash"], output)?;
+                expect(output, "Flash has been erased!")?;
+
+                let content = work_dir.join("flash_content.bin");
+                self.espflash(
+                    device,
+                    [arg("read-flash"), arg("0"), arg("0x200"), content.clone()],
+                    output,
+                )?;
+                expect(output, "Flash content successfully read")?;
+
+                let content = fs::read(&content)
+                    .map_err(|e| format!("failed to read {}: {e}", content.display()))?;
+                match content.iter().position(|&b| b != 0xff) {
+                    Some(offset) => Err(format!("flash is not empty at offset {offset:#x}")),
+                    None => Ok(()),
+                }
+            }
+            Scenario::SaveImage => {
+                let image = work_dir.join("app.bin");
+                let mut args = vec![
+                    arg("save-image"),
+                    arg("--merge"),
+                    arg("--chip"),
+                    arg(&device.chip),
+                ];
+                args.extend(device.image_args.iter().map(arg));
+                args.extend([app, image.clone()]);
+                self.espflash(device, args, output)?;
+                expect(output, "Image successfully saved!")?;
+
+                self.espflash(device, [arg("write-bin"), arg("0x0"), image], output)?;
+                expect(output, "Binary successfully written to flash!")?;
+
+                self.monitor(device, output)
+            }
+        }
+    }
+
+    fn command(&self, device: &Device) -> Command {
+        let mut command = Command::new(self.espflash);
+        command
+            .env("ESPFLASH_PORT", &device.port)
+            .current_dir(self.root)
+            .stdin(Stdio::null());
+        command
+    }
+
+    /// Run espflash to completion, appending its output, and fail if it does
+    fn espflash<I, S>(&self, device: &Device, args: I, output: &mut String) -> Result<(), String>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<std::ffi::OsStr>,
+    {
+        let mut command = self.command(device);
+        command.args(args);
+        output.push_str(&format!("$ {command:?}\n"));
+
+        let result = command
+            .output()
+            .map_err(|e| format!("failed to run {}: {e}", self.espflash.display()))?;
+        output.push_str(&String::from_utf8_lossy(&result.stdout));
+        output.push_str(&String::from_utf8_lossy(&result.stderr));
+
+        if result.status.success() {
+            Ok(())
+        } else {
+            Err(format!("espflash exited with {}", result.status))
+        }
+    }
+
+    /// Monitor the device for a while and expect the output of the
+    /// application
+    fn monitor(&self, device: &Device, output: &mut String) -> Result<(), String> {
+        let mut command = self.command(device);
+        command
+            .args(["monitor", "--non-interactive"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        output.push_str(&format!("$ {command:?}\n"));
+
+        let mut child = command
+            .spawn()
+            .map_err(|e| format!("failed to run {}: {e}", self.espflash.display()))?;
+
+        let (tx, rx) = mpsc::channel();
+        let readers = [
+            child
+                .stdout
+                .take()
+                .map(|s| Box::new(s) as Box<dyn Read + Send>),
+            child
+                .stderr
+                .take()
+                .map(|s| Box::new(s) as Box<dyn Read + Send>),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|mut stream| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let mut buffer = [0; 1024];
+                while let Ok(n @ 1..) = stream.read(&mut buffer) {
+                    if tx.send(buffer[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+        drop(tx);
+
+        // Stop as soon as the application greets us, or when the time is up
+        let deadline = Instant::now() + MONITOR_TIME;
+        let mut received = Vec::new();
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match rx.recv_timeout(left) {
+                Ok(bytes) => received.extend(bytes),
+                Err(_) => break,
+            }
+            if String::from_utf8_lossy(&received).contains(HELLO) {
+                break;
+            }
+        }
+
+        child.kill().ok();
+        child.wait().ok();
+        for reader in readers {
+            reader.join().ok();
+        }
+        received.extend(rx.try_iter().flatten());
+
+        let received = String::from_utf8_lossy(&received);
+        output.push_str(&received);
+        expect(&received, HELLO)
+    }
+}
+
+fn arg(arg: impl AsRef<str>) -> PathBuf {
+    PathBuf::from(arg.as_ref())
+}
+
+fn expect(output: &str, expected: &str) -> Result<(), String> {
+    if output.contains(expected) {
+        Ok(())
+    } else {
+        Err(format!("expected {expected:?} in the output"))
+    }
+}